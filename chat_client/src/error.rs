@@ -0,0 +1,23 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    // the server answered with an error
+    #[error("{status}: {message}")]
+    Api { status: u16, message: String },
+    // finish signing in with the code of the second factor and this token
+    #[error("two factor authentication required")]
+    TwoFactorRequired { challenge_token: String },
+    // the message ran a slash command, which answered only the sender
+    #[error("command reply: {0}")]
+    CommandReply(String),
+    #[error("not signed in")]
+    Unauthenticated,
+    // the server speaks a newer event protocol, update the client
+    #[error("unsupported event protocol version {0}")]
+    UnsupportedVersion(u32),
+    #[error("invalid event: {0}")]
+    InvalidEvent(#[from] serde_json::Error),
+}
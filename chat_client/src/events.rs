@@ -0,0 +1,117 @@
+use chat_core::{AppEvent, EventFrame, PROTOCOL_VERSION};
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use reqwest::Response;
+
+use crate::ClientError;
+
+// the events of /events, see ChatClient::events
+pub type EventStream = BoxStream<'static, Result<AppEvent, ClientError>>;
+
+pub(crate) fn stream(res: Response) -> EventStream {
+    // after a failed read the response is dropped, which ends the stream
+    let chunks = stream::unfold(Some(res), |res| async move {
+        let mut res = res?;
+        match res.chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(res))),
+            Ok(None) => None,
+            Err(e) => Some((Err(ClientError::from(e)), None)),
+        }
+    });
+    parse(chunks).boxed()
+}
+
+// server sent events out of the body as it arrives, an event may be split across chunks.
+// Comments (keep alives), events without data and events newer than the client are skipped.
+fn parse<S, B>(chunks: S) -> impl Stream<Item = Result<AppEvent, ClientError>>
+where
+    S: Stream<Item = Result<B, ClientError>> + Send + 'static,
+    B: AsRef<[u8]>,
+{
+    stream::unfold((chunks.boxed(), Vec::new()), |(mut chunks, mut buf)| async move {
+        loop {
+            if let Some(end) = buf.windows(2).position(|w| w == b"\n\n") {
+                let frame: Vec<u8> = buf.drain(..end + 2).collect();
+                let Some(data) = frame_data(&String::from_utf8_lossy(&frame)) else {
+                    continue;
+                };
+                match read_event(&data) {
+                    Ok(Some(event)) => return Some((Ok(event), (chunks, buf))),
+                    Ok(None) => continue,
+                    Err(e) => return Some((Err(e), (chunks, buf))),
+                }
+            }
+            match chunks.next().await {
+                Some(Ok(chunk)) => buf.extend(chunk.as_ref().iter().filter(|b| **b != b'\r')),
+                Some(Err(e)) => return Some((Err(e), (chunks, buf))),
+                None => return None,
+            }
+        }
+    })
+}
+
+// none for events added after the client's version of the protocol
+fn read_event(data: &str) -> Result<Option<AppEvent>, ClientError> {
+    // a newer version may have changed the fields, so it is checked first
+    let frame: serde_json::Value = serde_json::from_str(data)?;
+    let version = frame["version"].as_u64().unwrap_or(1);
+    if version > PROTOCOL_VERSION as u64 {
+        return Err(ClientError::UnsupportedVersion(version as u32));
+    }
+    match serde_json::from_value::<EventFrame>(frame)?.event {
+        AppEvent::Unknown => Ok(None),
+        event => Ok(Some(event)),
+    }
+}
+
+// the data lines of an event, joined by newlines
+fn frame_data(frame: &str) -> Option<String> {
+    let lines: Vec<&str> = frame
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn parse_should_join_split_events() {
+        let chunks = vec![
+            ":\n\n",
+            "data: {\"version\":1,\"event\":\"chat_updated\",\"ws_id\":1,",
+            "\"chat_id\":2}\n\ndata: {\"version\":1,\"event\":\"chat_created\",\"ws_id\":1,\"chat_id\":3}\r\n\r\n",
+            "data: {\"version\":1,\"event\":\"reaction_added\",\"chat_id\":3}\n\n",
+            "data: {\"version\":1,\"event\":\"typing_started\",\"ws_id\":1,\"chat_id\":3,\"user_id\":2}\n\n",
+        ];
+        let chunks = stream::iter(chunks.into_iter().map(Ok::<_, ClientError>));
+        let events: Vec<AppEvent> = block_on(parse(chunks).map(|e| e.expect("event")).collect());
+        assert_eq!(
+            events,
+            vec![
+                AppEvent::ChatUpdated { ws_id: 1, chat_id: 2 },
+                AppEvent::ChatCreated { ws_id: 1, chat_id: 3 },
+                AppEvent::TypingStarted { ws_id: 1, chat_id: 3, user_id: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_should_refuse_newer_versions() {
+        let chunks = stream::iter([Ok::<_, ClientError>("data: {\"version\":2,\"event\":\"chat_updated\"}\n\n")]);
+        let events: Vec<_> = block_on(parse(chunks).collect());
+        assert!(matches!(events[..], [Err(ClientError::UnsupportedVersion(2))]));
+    }
+
+    #[test]
+    fn frame_data_should_skip_comments() {
+        assert_eq!(frame_data(": keep alive\n"), None);
+        assert_eq!(frame_data("event: x\ndata: a\ndata:b\n"), Some("a\nb".to_string()));
+    }
+}
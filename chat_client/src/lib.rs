@@ -0,0 +1,157 @@
+// a client of the chat api, for bots, tools and tests
+mod error;
+mod events;
+
+use chat_core::{
+    AuthOutput, ChangeBatch, ChatSummary, CreateMessage, ErrorOutput, EventBatch, ListMessages, MarkChatRead, Message,
+    Paginated, PollEvents, SigninUser, SyncChanges,
+};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+
+pub use chat_core;
+pub use error::ClientError;
+pub use events::EventStream;
+
+// the api version the client is written against, pinned rather than following /api
+const API_PREFIX: &str = "/api/v2";
+
+#[derive(Debug, Clone)]
+pub struct ChatClient {
+    http: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+}
+
+// signin answers with the tokens, or with a challenge when the user has a second factor
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SigninOutput {
+    Tokens(AuthOutput),
+    Challenge { challenge_token: String },
+}
+
+// what a slash command answered when it posted nothing
+#[derive(Debug, Deserialize)]
+struct CommandReply {
+    #[serde(default)]
+    text: String,
+}
+
+impl ChatClient {
+    // base_url is where the server is, e.g. https://chat.acme.org
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: None,
+        }
+    }
+
+    // an access token or bot token from elsewhere, instead of signing in
+    pub fn with_token(self, token: impl Into<String>) -> Self {
+        Self { token: Some(token.into()), ..self }
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    // requests after this are made as the user
+    pub async fn signin(&mut self, email: &str, password: &str) -> Result<AuthOutput, ClientError> {
+        let req = self.http.post(self.url("/signin")).json(&SigninUser::new(email, password));
+        match parse(req.send().await?).await? {
+            SigninOutput::Tokens(output) => {
+                self.token = Some(output.token.clone());
+                Ok(output)
+            }
+            SigninOutput::Challenge { challenge_token } => Err(ClientError::TwoFactorRequired { challenge_token }),
+        }
+    }
+
+    // the user's chats, most recently active first
+    pub async fn list_chats(&self) -> Result<Vec<ChatSummary>, ClientError> {
+        let page: Paginated<ChatSummary> = parse(self.get("/chats")?.send().await?).await?;
+        Ok(page.items)
+    }
+
+    // newest first unless input.sort says otherwise, pass the seq of the last one as last_seq
+    // for the page after
+    pub async fn list_messages(&self, chat_id: i64, input: &ListMessages) -> Result<Paginated<Message>, ClientError> {
+        let req = self.get(&format!("/chats/{}/messages", chat_id))?.query(input);
+        parse(req.send().await?).await
+    }
+
+    // a message starting with a slash command may get a reply only the user sees instead
+    pub async fn send_message(&self, chat_id: i64, input: &CreateMessage) -> Result<Message, ClientError> {
+        let req = self.post(&format!("/chats/{}", chat_id))?.json(input);
+        let res = check(req.send().await?).await?;
+        if res.status() == StatusCode::ACCEPTED {
+            let reply: CommandReply = res.json().await?;
+            return Err(ClientError::CommandReply(reply.text));
+        }
+        Ok(res.json().await?)
+    }
+
+    pub async fn mark_read(&self, chat_id: i64, seq: i64) -> Result<(), ClientError> {
+        let req = self.post(&format!("/chats/{}/read", chat_id))?.json(&MarkChatRead { seq });
+        check(req.send().await?).await?;
+        Ok(())
+    }
+
+    // shows the chat's members that the user is typing, call again every few seconds while they are
+    pub async fn start_typing(&self, chat_id: i64) -> Result<(), ClientError> {
+        check(self.post(&format!("/chats/{}/typing", chat_id))?.send().await?).await?;
+        Ok(())
+    }
+
+    // events of the user's chats and workspace as they happen. The stream ends when the server closes it,
+    // e.g. when the client falls behind, reconnect and reload what may have been missed.
+    pub async fn events(&self) -> Result<EventStream, ClientError> {
+        let res = check(self.get("/events")?.send().await?).await?;
+        Ok(events::stream(res))
+    }
+
+    // events after the cursor of the previous batch, for where event streams get cut. Start
+    // without a cursor, then poll again with the cursor of each batch.
+    pub async fn poll_events(&self, input: &PollEvents) -> Result<EventBatch, ClientError> {
+        parse(self.get("/events/poll")?.query(input).send().await?).await
+    }
+
+    // what changed in the user's chats after since, to catch up after being offline. Sync
+    // without since before loading the chats, then with the seq of each batch until has_more
+    // is false. Reload everything on reset.
+    pub async fn sync(&self, input: &SyncChanges) -> Result<ChangeBatch, ClientError> {
+        parse(self.get("/sync")?.query(input).send().await?).await
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}{}", self.base_url, API_PREFIX, path)
+    }
+
+    fn get(&self, path: &str) -> Result<RequestBuilder, ClientError> {
+        Ok(self.http.get(self.url(path)).bearer_auth(self.token.as_deref().ok_or(ClientError::Unauthenticated)?))
+    }
+
+    fn post(&self, path: &str) -> Result<RequestBuilder, ClientError> {
+        Ok(self.http.post(self.url(path)).bearer_auth(self.token.as_deref().ok_or(ClientError::Unauthenticated)?))
+    }
+}
+
+// the response if it was a success, else the error the server gave
+async fn check(res: Response) -> Result<Response, ClientError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let body = res.text().await?;
+    let message = match serde_json::from_str::<ErrorOutput>(&body) {
+        Ok(output) => output.error,
+        Err(_) => body,
+    };
+    Err(ClientError::Api { status: status.as_u16(), message })
+}
+
+async fn parse<T: DeserializeOwned>(res: Response) -> Result<T, ClientError> {
+    Ok(check(res).await?.json().await?)
+}
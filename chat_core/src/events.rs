@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+
+// bumped when an event changes in a way old clients would misread. New events don't bump it,
+// clients read those as AppEvent::Unknown.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// what changed, not the new state; notify payloads are capped at 8000 bytes, so subscribers
+// load anything else they need
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AppEvent {
+    ChatCreated { ws_id: i64, chat_id: i64 },
+    // renamed, or members joined or left
+    ChatUpdated { ws_id: i64, chat_id: i64 },
+    NewMessage { ws_id: i64, chat_id: i64, message_id: i64, sender_id: i64, seq: i64 },
+    // sent again every few seconds while the user keeps typing, clients stop showing it after
+    // a while without one
+    TypingStarted { ws_id: i64, chat_id: i64, user_id: i64 },
+    // online while the user has an event stream open, for everyone in the workspace
+    PresenceChanged { ws_id: i64, user_id: i64, online: bool },
+    // an event added in a later version of the protocol
+    #[serde(other)]
+    Unknown,
+}
+
+// an event as it goes over the bus and to clients
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EventFrame {
+    // frames queued before versions existed have none
+    #[serde(default = "first_version")]
+    pub version: u32,
+    #[serde(flatten)]
+    pub event: AppEvent,
+}
+
+// the query of /events/poll
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PollEvents {
+    // the cursor of the previous batch, none for the first poll which only gives a cursor
+    #[serde(default)]
+    pub cursor: Option<i64>,
+    // seconds to wait for an event when there is none yet, capped by the server
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+// events after the cursor, oldest first, none when the wait ran out
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EventBatch {
+    pub events: Vec<EventFrame>,
+    // for the next poll
+    pub cursor: i64,
+    // events since the cursor are no longer kept, reload what the events would have said
+    #[serde(default)]
+    pub reset: bool,
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+impl AppEvent {
+    // the chat whose members get the event, none for workspace wide ones
+    pub fn chat_id(&self) -> Option<i64> {
+        match self {
+            Self::ChatCreated { chat_id, .. }
+            | Self::ChatUpdated { chat_id, .. }
+            | Self::NewMessage { chat_id, .. }
+            | Self::TypingStarted { chat_id, .. } => Some(*chat_id),
+            Self::PresenceChanged { .. } | Self::Unknown => None,
+        }
+    }
+
+    pub fn ws_id(&self) -> Option<i64> {
+        match self {
+            Self::ChatCreated { ws_id, .. }
+            | Self::ChatUpdated { ws_id, .. }
+            | Self::NewMessage { ws_id, .. }
+            | Self::TypingStarted { ws_id, .. }
+            | Self::PresenceChanged { ws_id, .. } => Some(*ws_id),
+            Self::Unknown => None,
+        }
+    }
+}
+
+impl From<AppEvent> for EventFrame {
+    fn from(event: AppEvent) -> Self {
+        Self { version: PROTOCOL_VERSION, event }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    // every event as it is on the wire, a change here breaks deployed clients
+    fn wire_events() -> Vec<(AppEvent, Value)> {
+        vec![
+            (
+                AppEvent::ChatCreated { ws_id: 1, chat_id: 2 },
+                json!({ "version": 1, "event": "chat_created", "ws_id": 1, "chat_id": 2 }),
+            ),
+            (
+                AppEvent::ChatUpdated { ws_id: 1, chat_id: 2 },
+                json!({ "version": 1, "event": "chat_updated", "ws_id": 1, "chat_id": 2 }),
+            ),
+            (
+                AppEvent::NewMessage { ws_id: 1, chat_id: 2, message_id: 3, sender_id: 4, seq: 5 },
+                json!({
+                    "version": 1, "event": "new_message", "ws_id": 1, "chat_id": 2,
+                    "message_id": 3, "sender_id": 4, "seq": 5
+                }),
+            ),
+            (
+                AppEvent::TypingStarted { ws_id: 1, chat_id: 2, user_id: 3 },
+                json!({ "version": 1, "event": "typing_started", "ws_id": 1, "chat_id": 2, "user_id": 3 }),
+            ),
+            (
+                AppEvent::PresenceChanged { ws_id: 1, user_id: 3, online: true },
+                json!({ "version": 1, "event": "presence_changed", "ws_id": 1, "user_id": 3, "online": true }),
+            ),
+            (AppEvent::Unknown, json!({ "version": 1, "event": "unknown" })),
+        ]
+    }
+
+    #[test]
+    fn frames_should_round_trip_every_event() {
+        for (event, wire) in wire_events() {
+            let frame = EventFrame::from(event.clone());
+            assert_eq!(serde_json::to_value(&frame).unwrap(), wire, "{:?}", event);
+            let read: EventFrame = serde_json::from_value(wire).unwrap();
+            assert_eq!(read.event, event);
+        }
+    }
+
+    #[test]
+    fn frames_should_read_older_and_newer_payloads() {
+        // queued before the version field
+        let frame: EventFrame = serde_json::from_str(r#"{"event":"chat_created","ws_id":1,"chat_id":2}"#).unwrap();
+        assert_eq!(frame, EventFrame { version: 1, event: AppEvent::ChatCreated { ws_id: 1, chat_id: 2 } });
+
+        let frame: EventFrame = serde_json::from_str(r#"{"version":1,"event":"reaction_added","chat_id":2}"#).unwrap();
+        assert_eq!(frame.event, AppEvent::Unknown);
+
+        let ret = serde_json::from_str::<EventFrame>(r#"{"version":1,"event":"new_message","ws_id":1}"#);
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn events_should_name_their_audience() {
+        assert_eq!(AppEvent::TypingStarted { ws_id: 1, chat_id: 2, user_id: 3 }.chat_id(), Some(2));
+        let presence = AppEvent::PresenceChanged { ws_id: 1, user_id: 3, online: false };
+        assert_eq!((presence.chat_id(), presence.ws_id()), (None, Some(1)));
+        assert_eq!(AppEvent::Unknown.ws_id(), None);
+    }
+}
@@ -0,0 +1,10 @@
+// what the server and its clients exchange, the api's requests and responses and its events
+mod events;
+mod models;
+mod pagination;
+mod sync;
+
+pub use events::{AppEvent, EventBatch, EventFrame, PollEvents, PROTOCOL_VERSION};
+pub use models::*;
+pub use pagination::Paginated;
+pub use sync::{ChangeBatch, ChatMembers, ReadState, SyncChanges};
@@ -0,0 +1,263 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LIST_LIMIT: u64 = 50;
+const MAX_LIST_LIMIT: u64 = 200;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(type_name = "chat_type", rename_all = "snake_case"))]
+pub enum ChatType {
+    Single,
+    Group,
+    PrivateChannel,
+    PublicChannel,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(type_name = "message_kind", rename_all = "snake_case"))]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    User,
+    // generated by the server for chat events, sender is the user who caused it
+    System,
+    // posted by an integration, an incoming webhook or a slash command, sender is the user who
+    // created the webhook or ran the command
+    Integration,
+    // sent by a bot, sender is the bot's user
+    Bot,
+}
+
+// a row of the chat list, read from the columns kept up to date on chats
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct ChatSummary {
+    pub id: i64,
+    pub name: Option<String>,
+    pub r#type: ChatType,
+    pub member_count: i64,
+    // newest top level message, thread replies don't show here
+    pub last_message_id: Option<i64>,
+    pub last_message_at: Option<DateTime<Utc>>,
+    // the first 140 characters
+    pub last_message_preview: Option<String>,
+    pub last_sender_id: Option<i64>,
+    pub unread: i64,
+}
+
+// a message as the api answers with it. The server has its own with the queries on it, a test
+// there keeps the two in step.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Message {
+    pub id: i64,
+    pub chat_id: i64,
+    pub sender_id: i64,
+    // per chat monotonically increasing, clients should order by this rather than created_at
+    pub seq: i64,
+    pub kind: MessageKind,
+    // root message id for thread replies
+    pub thread_id: Option<i64>,
+    pub content: String,
+    pub images: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    // when the client composed it, may predate created_at for messages written offline
+    pub client_created_at: Option<DateTime<Utc>>,
+    // shown instead of the sender's name and avatar, for integration messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateMessage {
+    pub content: String,
+    #[serde(default)]
+    pub images: Vec<String>,
+    #[serde(default)]
+    pub client_created_at: Option<DateTime<Utc>>,
+    // root message id when replying in a thread
+    #[serde(default)]
+    pub thread_id: Option<i64>,
+    // id in the originating system (email gateway, bridge...), a message with an external id
+    // already seen in the chat is not created again, the existing one is returned instead
+    #[serde(default)]
+    pub external_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListMessages {
+    // only return messages after this seq in the sort order, smaller for newest first
+    #[serde(default)]
+    pub last_seq: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+    // list replies of this thread instead of top level messages
+    #[serde(default)]
+    pub thread_id: Option<i64>,
+    // only messages of this user
+    #[serde(default)]
+    pub sender: Option<i64>,
+    #[serde(default)]
+    pub kind: Option<MessageKind>,
+    // created before, exclusive
+    #[serde(default)]
+    pub before: Option<DateTime<Utc>>,
+    // created at or after
+    #[serde(default)]
+    pub after: Option<DateTime<Utc>>,
+    // what the messages must all have, has=attachment,mention
+    #[serde(default, with = "has_list", skip_serializing_if = "Vec::is_empty")]
+    pub has: Vec<MessageHas>,
+    #[serde(default)]
+    pub sort: MessageSort,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageHas {
+    // images
+    Attachment,
+    Link,
+    // of any user
+    Mention,
+}
+
+// seq is the order messages were created in, created_at and seq sort the same
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum MessageSort {
+    #[default]
+    #[serde(rename = "-created_at", alias = "-seq")]
+    Newest,
+    #[serde(rename = "created_at", alias = "seq")]
+    Oldest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarkChatRead {
+    // everything up to and including this seq has been seen
+    pub seq: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigninUser {
+    pub email: String,
+    pub password: String,
+    // keeps the session alive for the workspace's remember me max age
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthOutput {
+    pub token: String,
+    // left out when it is set as a cookie instead
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub refresh_token: String,
+    // lifetime of the access token in seconds
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorOutput {
+    pub error: String,
+}
+
+impl CreateMessage {
+    pub fn new(content: &str) -> Self {
+        Self {
+            content: content.to_string(),
+            images: vec![],
+            client_created_at: None,
+            thread_id: None,
+            external_id: None,
+        }
+    }
+}
+
+impl ListMessages {
+    pub fn limit(&self) -> u64 {
+        self.limit.unwrap_or(DEFAULT_LIST_LIMIT).min(MAX_LIST_LIMIT)
+    }
+}
+
+impl MessageHas {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Attachment => "attachment",
+            Self::Link => "link",
+            Self::Mention => "mention",
+        }
+    }
+}
+
+// a list in one query parameter, comma separated
+mod has_list {
+    use serde::{
+        de::{value, Error, IntoDeserializer},
+        Deserialize, Deserializer, Serializer,
+    };
+
+    use super::MessageHas;
+
+    pub fn serialize<S: Serializer>(has: &[MessageHas], serializer: S) -> Result<S::Ok, S::Error> {
+        let names: Vec<&str> = has.iter().map(MessageHas::as_str).collect();
+        serializer.serialize_str(&names.join(","))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<MessageHas>, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                MessageHas::deserialize(name.into_deserializer()).map_err(|e: value::Error| D::Error::custom(e))
+            })
+            .collect()
+    }
+}
+
+impl SigninUser {
+    pub fn new(email: &str, password: &str) -> Self {
+        Self {
+            email: email.to_string(),
+            password: password.to_string(),
+            remember_me: false,
+        }
+    }
+}
+
+impl ErrorOutput {
+    pub fn new(error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enums_should_keep_their_wire_names() {
+        assert_eq!(serde_json::to_string(&ChatType::PublicChannel).unwrap(), "\"PublicChannel\"");
+        assert_eq!(serde_json::to_string(&MessageKind::Integration).unwrap(), "\"integration\"");
+        let input: ListMessages = serde_json::from_str(r#"{"limit": 1000}"#).unwrap();
+        assert_eq!(input.limit(), MAX_LIST_LIMIT);
+    }
+
+    #[test]
+    fn list_messages_should_read_filters_and_sort() {
+        let input: ListMessages =
+            serde_json::from_str(r#"{"sender": 2, "has": "attachment, mention", "sort": "seq"}"#).unwrap();
+        assert_eq!(input.sender, Some(2));
+        assert_eq!(input.has, vec![MessageHas::Attachment, MessageHas::Mention]);
+        assert_eq!(input.sort, MessageSort::Oldest);
+        assert_eq!(serde_json::to_value(&input).unwrap()["has"], "attachment,mention");
+        assert_eq!(ListMessages::default().sort, MessageSort::Newest);
+
+        assert!(serde_json::from_str::<ListMessages>(r#"{"has": "attachment,video"}"#).is_err());
+        assert!(serde_json::from_str::<ListMessages>(r#"{"sort": "-sender"}"#).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+
+// a page of a listing
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    // passed back as cursor for the next page, none on the last one
+    pub next_cursor: Option<String>,
+    // only for listings where counting is cheap
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<i64>,
+}
+
+impl<T> Paginated<T> {
+    // everything there is, in one page
+    pub fn all(items: Vec<T>) -> Self {
+        let total = Some(items.len() as i64);
+        Self { items, next_cursor: None, total }
+    }
+
+    // a full page may have a next one, starting after the cursor of its last item
+    pub fn page(items: Vec<T>, limit: u64, cursor: impl FnOnce(&T) -> String) -> Self {
+        let next_cursor = match items.last() {
+            Some(last) if items.len() as u64 >= limit => Some(cursor(last)),
+            _ => None,
+        };
+        Self { items, next_cursor, total: None }
+    }
+
+    pub fn with_total(self, total: i64) -> Self {
+        Self { total: Some(total), ..self }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_should_only_have_a_cursor_when_full() {
+        let page = Paginated::page(vec![5, 4, 3], 3, |n| n.to_string());
+        assert_eq!(page.next_cursor.as_deref(), Some("3"));
+        let page = Paginated::page(vec![2, 1], 3, |n| n.to_string());
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(Paginated::all(vec![1, 2]).total, Some(2));
+    }
+}
@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Message;
+
+// the query of /sync
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncChanges {
+    // the seq of the previous batch, none for the first sync which only gives a seq. Take it
+    // before loading the chats and messages, so nothing changed in between is missed.
+    #[serde(default)]
+    pub since: Option<i64>,
+}
+
+// what changed after since, as it is now. A message created and then edited is in messages
+// once, with its edit. Generic for the server, which answers with its own message.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChangeBatch<M = Message> {
+    // created or edited, replace any copy with the same id
+    pub messages: Vec<M>,
+    pub deleted_messages: Vec<i64>,
+    // chats the user is in whose members changed, including ones they joined
+    pub chats: Vec<ChatMembers>,
+    // chats the user left, was removed from, or that were deleted
+    pub left_chats: Vec<i64>,
+    // the user's own, read on this or another device
+    pub read_states: Vec<ReadState>,
+    // for the next sync
+    pub seq: i64,
+    // more changes after seq, sync again right away
+    #[serde(default)]
+    pub has_more: bool,
+    // changes since are no longer kept, reload the chats and messages
+    #[serde(default)]
+    pub reset: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct ChatMembers {
+    pub chat_id: i64,
+    pub members: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct ReadState {
+    pub chat_id: i64,
+    // everything up to and including this seq has been seen
+    pub last_read_seq: i64,
+}
@@ -0,0 +1,90 @@
+// cargo bench -p chat_server
+//
+// the chat list bench runs against server.db_url, prepared with `chat_server migrate` and
+// `chat_server seed`. It is skipped when the database can't be reached.
+use std::hint::black_box;
+
+use chat_server::{
+    hash_password, verify_password, AppConfig, Chat, DecodingKey, EncodingKey, Grant, Message, MessageKind, TokenId,
+    User,
+};
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, Criterion};
+use sqlx::PgPool;
+use tokio::runtime::Runtime;
+
+fn config() -> AppConfig {
+    AppConfig::load().expect("load app.yml")
+}
+
+fn passwords(c: &mut Criterion) {
+    let hashing = config().auth.password_hashing;
+    let hash = hash_password("hunter42 and more", &hashing).expect("hash password");
+    let mut group = c.benchmark_group("password");
+    // argon2 at the configured cost takes tens of milliseconds per call
+    group.sample_size(10);
+    group.bench_function("hash", |b| b.iter(|| hash_password(black_box("hunter42 and more"), &hashing)));
+    group.bench_function("verify", |b| b.iter(|| verify_password(black_box("hunter42 and more"), &hash)));
+    group.finish();
+}
+
+fn jwt(c: &mut Criterion) {
+    let config = config();
+    let ek = EncodingKey::load(&config.auth.sk, config.auth.kid.as_deref()).expect("load sk");
+    let dk = DecodingKey::from_config(&config.auth).expect("load pk");
+    let user = User {
+        id: 1,
+        ws_id: 1,
+        fullname: "Tyr Chen".to_string(),
+        email: "tchen@acme.org".to_string(),
+        password_hash: None,
+        created_at: Utc::now(),
+    };
+    let grant = Grant::default();
+    let id = TokenId::generate();
+    let token = ek.sign_with_id(user.clone(), &grant, &id).expect("sign");
+    c.bench_function("jwt/sign", |b| b.iter(|| ek.sign_with_id(black_box(user.clone()), &grant, &id)));
+    c.bench_function("jwt/verify", |b| b.iter(|| dk.verify(black_box(&token))));
+}
+
+fn messages(c: &mut Criterion) {
+    // a page of messages as list_message_handler returns it
+    let page: Vec<Message> = (1..=50)
+        .map(|seq| Message {
+            id: seq,
+            chat_id: 1,
+            sender_id: seq % 5 + 1,
+            seq,
+            kind: MessageKind::User,
+            thread_id: None,
+            content: "Hello, world! ".repeat(8),
+            images: vec![],
+            created_at: Utc::now(),
+            client_created_at: None,
+            display_name: None,
+            icon_url: None,
+        })
+        .collect();
+    let json = serde_json::to_vec(&page).expect("serialize");
+    c.bench_function("messages/serialize", |b| b.iter(|| serde_json::to_vec(black_box(&page))));
+    c.bench_function("messages/deserialize", |b| {
+        b.iter(|| serde_json::from_slice::<Vec<Message>>(black_box(&json)))
+    });
+}
+
+fn chat_list(c: &mut Criterion) {
+    let rt = Runtime::new().expect("tokio runtime");
+    let pool = match rt.block_on(PgPool::connect(&config().server.db_url)) {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("skipping chat_list, cannot connect to the database: {}", e);
+            return;
+        }
+    };
+    c.bench_function("chat_list/list_for_member", |b| {
+        b.to_async(&rt).iter(|| Chat::list_for_member(1, 1, &pool))
+    });
+}
+
+criterion_group!(benches, passwords, jwt, messages, chat_list);
+criterion_main!(benches);
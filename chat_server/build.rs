@@ -0,0 +1,9 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // a protoc that builds everywhere, rather than whatever is installed
+    let mut config = prost_build::Config::new();
+    config.protoc_executable(protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos_with_config(config, &["proto/chat.proto"], &["proto"])?;
+    Ok(())
+}
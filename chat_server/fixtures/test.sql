@@ -1,8 +1,8 @@
 -- insert 3 workspaces
-INSERT INTO workspaces(name, owner_id)
-  VALUES ('acme', 0),
-('foo', 0),
-('bar', 0);
+INSERT INTO workspaces(name, slug, owner_id)
+  VALUES ('acme', 'acme', 0),
+('foo', 'foo', 0),
+('bar', 'bar', 0);
 
 -- insert 5 users, all with hashed password '123456'
 INSERT INTO users(ws_id, email, fullname, password_hash)
@@ -14,11 +14,29 @@ INSERT INTO users(ws_id, email, fullname, password_hash)
 
 -- insert 4 chats
 -- insert public/private channel
-INSERT INTO chats(ws_id, name, type, members)
-  VALUES (1, 'general', 'public_channel', '{1,2,3,4,5}'),
-(1, 'private', 'private_channel', '{1,2,3}');
+INSERT INTO chats(ws_id, name, type, members, owner_id)
+  VALUES (1, 'general', 'public_channel', '{1,2,3,4,5}', 1),
+(1, 'private', 'private_channel', '{1,2,3}', 1);
 
 -- insert unnamed chat
-INSERT INTO chats(ws_id, type, members)
-  VALUES (1, 'single', '{1,2}'),
-(1, 'group', '{1,3,4}');
+INSERT INTO chats(ws_id, type, members, owner_id)
+  VALUES (1, 'single', '{1,2}', 1),
+(1, 'group', '{1,3,4}', 1);
+
+-- insert 10 messages into general
+INSERT INTO messages(chat_id, sender_id, seq, content, images)
+  VALUES (1, 1, 1, 'Hello, world!', '{}'),
+(1, 2, 2, 'Hi, there!', '{}'),
+(1, 3, 3, 'How are you?', '{}'),
+(1, 4, 4, 'I am fine, thank you!', '{}'),
+(1, 5, 5, 'Good to hear that!', '{}'),
+(1, 1, 6, 'Hello, world!', '{}'),
+(1, 2, 7, 'Hi, there!', '{}'),
+(1, 3, 8, 'How are you?', '{}'),
+(1, 1, 9, 'Hello, world!', '{}'),
+(1, 1, 10, 'Hello, world!', '{}');
+
+UPDATE chats SET last_seq = 10 WHERE id = 1;
+
+-- fixture users have verified their email
+UPDATE users SET email_verified_at = created_at;
@@ -0,0 +1,154 @@
+use std::fmt::Write;
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use sqlx::PgPool;
+use tracing::warn;
+
+use crate::{AuditAction, AuditLog, CreateAuditLog, User};
+
+// bumped on every update, not a change anyone made
+const IGNORED_FIELDS: &[&str] = &["created_at", "updated_at"];
+
+// audit failures are logged rather than surfaced, the audited action has already happened
+pub(crate) async fn record(
+    pool: &PgPool,
+    actor: &User,
+    action: AuditAction,
+    target_id: Option<i64>,
+    details: Value,
+) {
+    let input = CreateAuditLog {
+        ws_id: actor.ws_id,
+        actor_id: actor.id,
+        action,
+        target_id,
+        details,
+    };
+    if let Err(e) = AuditLog::create(&input, pool).await {
+        warn!("record audit log {:?} failed: {}", input, e);
+    }
+}
+
+// records the fields an admin changed in `setting`, with their old values so that the change
+// can be undone. None stands for settings that did not exist before or were removed.
+// Write only fields (secrets) are not serialized, so they never end up in the log.
+pub(crate) async fn record_change<T: Serialize>(
+    pool: &PgPool,
+    actor: &User,
+    setting: &str,
+    before: Option<&T>,
+    after: Option<&T>,
+) {
+    let to_value = |v: Option<&T>| v.and_then(|v| serde_json::to_value(v).ok()).unwrap_or(Value::Null);
+    let changes = diff(&to_value(before), &to_value(after));
+    if changes.is_empty() {
+        return;
+    }
+    let details = json!({ "setting": setting, "changes": changes });
+    record(pool, actor, AuditAction::SettingsChanged, None, details).await;
+}
+
+// changed leaf fields, nested objects are flattened to dotted paths
+fn diff(before: &Value, after: &Value) -> Vec<Value> {
+    let mut changes = vec![];
+    diff_into("", before, after, &mut changes);
+    changes
+}
+
+fn diff_into(path: &str, before: &Value, after: &Value, changes: &mut Vec<Value>) {
+    let is_map = |v: &Value| v.is_object() || v.is_null();
+    if (before.is_object() || after.is_object()) && is_map(before) && is_map(after) {
+        let empty = Map::new();
+        let before = before.as_object().unwrap_or(&empty);
+        let after = after.as_object().unwrap_or(&empty);
+        let mut keys: Vec<&String> = before.keys().chain(after.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        for key in keys.into_iter().filter(|k| !IGNORED_FIELDS.contains(&k.as_str())) {
+            let field = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+            let before = before.get(key).unwrap_or(&Value::Null);
+            let after = after.get(key).unwrap_or(&Value::Null);
+            diff_into(&field, before, after, changes);
+        }
+    } else if before != after {
+        changes.push(json!({ "field": path, "before": before, "after": after }));
+    }
+}
+
+// one line per changed field, e.g. `session_policy.max_age: 2592000 -> 604800`
+pub(crate) fn describe(log: &AuditLog) -> String {
+    let mut out = format!(
+        "#{} {} by user {} at {}\n",
+        log.id,
+        serde_json::to_value(log.action).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default(),
+        log.actor_id,
+        log.created_at.format("%Y-%m-%d %H:%M:%S UTC"),
+    );
+    let Some(changes) = log.details["changes"].as_array() else {
+        let _ = writeln!(out, "{}", log.details);
+        return out;
+    };
+    let setting = log.details["setting"].as_str().unwrap_or("settings");
+    for change in changes {
+        let _ = writeln!(
+            out,
+            "{}.{}: {} -> {}",
+            setting,
+            change["field"].as_str().unwrap_or_default(),
+            display(&change["before"]),
+            display(&change["after"]),
+        );
+    }
+    out
+}
+
+fn display(value: &Value) -> String {
+    match value {
+        Value::Null => "(unset)".to_string(),
+        v => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn diff_should_list_changed_fields_with_old_values() {
+        let before = json!({ "mode": "off", "terms": [], "smtp": { "host": "a", "port": 25 }, "updated_at": 1 });
+        let after = json!({ "mode": "mask", "terms": ["heck"], "smtp": { "host": "a", "port": 587 }, "updated_at": 2 });
+        let changes = diff(&before, &after);
+        assert_eq!(
+            changes,
+            vec![
+                json!({ "field": "mode", "before": "off", "after": "mask" }),
+                json!({ "field": "smtp.port", "before": 25, "after": 587 }),
+                json!({ "field": "terms", "before": [], "after": ["heck"] }),
+            ]
+        );
+        assert!(diff(&before, &before).is_empty());
+        // removed settings list every field as unset
+        assert_eq!(diff(&json!({ "issuer": "x" }), &Value::Null), vec![json!({ "field": "issuer", "before": "x", "after": null })]);
+    }
+
+    #[test]
+    fn describe_should_render_one_line_per_change() {
+        let log = AuditLog {
+            id: 7,
+            ws_id: 1,
+            actor_id: 1,
+            action: AuditAction::SettingsChanged,
+            target_id: None,
+            details: json!({
+                "setting": "session_policy",
+                "changes": [{ "field": "max_age", "before": 2592000, "after": 604800 }],
+            }),
+            created_at: Utc::now(),
+        };
+        let text = describe(&log);
+        assert!(text.starts_with("#7 settings_changed by user 1 at "));
+        assert!(text.ends_with("session_policy.max_age: 2592000 -> 604800\n"));
+    }
+}
@@ -0,0 +1,189 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::{OriginalUri, Request},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tower::ServiceExt;
+
+use crate::{
+    error::ErrorOutput,
+    middlewares::{set_request_limits, unversioned, ClientIp},
+    ApiVersion, AppError, AppState,
+};
+
+const MAX_BATCH_REQUESTS: usize = 20;
+// responses of a sub-request are buffered, larger ones fail that item
+const MAX_BATCH_RESPONSE_BYTES: usize = 4 * 1024 * 1024;
+// the headers of the batch request a sub-request gets, the credentials and what comes with them
+const FORWARDED_HEADERS: &[&str] = &["authorization", "cookie", "x-csrf-token", "accept-language", "user-agent"];
+
+// one request of a batch, the path is the one it would have on its own, e.g. /api/chats
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchRequest {
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub path: String,
+    #[serde(default)]
+    pub body: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BatchResponse {
+    pub status: u16,
+    // the json the request answered with, other bodies as text, none when it was empty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+}
+
+// the authenticated api routes sub-requests are served by, under /api and behind the same
+// rate and request limits as on their own. Public routes like /signin can't be batched.
+#[derive(Clone)]
+pub(crate) struct BatchApi(Router);
+
+impl BatchApi {
+    pub(crate) fn new(api: Router<AppState>, state: AppState) -> Self {
+        let api = Router::new().nest("/api", api).with_state(state.clone());
+        Self(set_request_limits(api, state))
+    }
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+// the requests in order, each as if it had been sent on its own with the credentials of the
+// batch. A failed request is a result like any other and doesn't stop the ones after it.
+pub(crate) async fn run(
+    api: &BatchApi,
+    headers: &HeaderMap,
+    version: ApiVersion,
+    ip: Option<ClientIp>,
+    requests: Vec<BatchRequest>,
+) -> Result<Vec<BatchResponse>, AppError> {
+    if requests.is_empty() || requests.len() > MAX_BATCH_REQUESTS {
+        return Err(AppError::InvalidInput(format!(
+            "a batch has 1 to {} requests, got {}",
+            MAX_BATCH_REQUESTS,
+            requests.len()
+        )));
+    }
+    let mut responses = Vec::with_capacity(requests.len());
+    for request in requests {
+        let res = match sub_request(request, headers, version, ip.clone()) {
+            Ok(req) => api.0.clone().oneshot(req).await.unwrap_or_else(|e| match e {}),
+            Err(e) => e.into_response(),
+        };
+        responses.push(BatchResponse::read(res).await);
+    }
+    Ok(responses)
+}
+
+fn sub_request(
+    request: BatchRequest,
+    headers: &HeaderMap,
+    version: ApiVersion,
+    ip: Option<ClientIp>,
+) -> Result<Request, AppError> {
+    let method = Method::from_bytes(request.method.to_ascii_uppercase().as_bytes())
+        .map_err(|_| AppError::InvalidInput(format!("invalid method: {}", request.method)))?;
+    // the version is the batch's, a versioned path is served as the unversioned one
+    let full: Uri = unversioned(&request.path)
+        .parse()
+        .map_err(|_| AppError::InvalidInput(format!("invalid path: {}", request.path)))?;
+    match full.path().strip_prefix("/api") {
+        Some(rest) if rest.starts_with('/') && rest != "/batch" => {}
+        _ => return Err(AppError::NotFound(format!("batch path {}", request.path))),
+    }
+
+    let mut builder = Request::builder().method(method).uri(full.clone());
+    for name in FORWARDED_HEADERS {
+        if let Some(value) = headers.get(*name) {
+            builder = builder.header(*name, value);
+        }
+    }
+    let body = match request.body {
+        Some(body) => {
+            builder = builder.header(header::CONTENT_TYPE, "application/json");
+            Body::from(body.to_string())
+        }
+        None => Body::empty(),
+    };
+    let mut req = builder
+        .body(body)
+        .map_err(|e| AppError::InvalidInput(format!("invalid request: {}", e)))?;
+    // links in responses point at the path the client knows
+    req.extensions_mut().insert(OriginalUri(full));
+    req.extensions_mut().insert(version);
+    if let Some(ip) = ip {
+        req.extensions_mut().insert(ip);
+    }
+    Ok(req)
+}
+
+impl BatchResponse {
+    async fn read(res: Response) -> Self {
+        let status = res.status().as_u16();
+        match to_bytes(res.into_body(), MAX_BATCH_RESPONSE_BYTES).await {
+            Ok(bytes) if bytes.is_empty() => Self { status, body: None },
+            Ok(bytes) => {
+                let body = serde_json::from_slice(&bytes)
+                    .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+                Self { status, body: Some(body) }
+            }
+            Err(_) => Self {
+                status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                body: serde_json::to_value(ErrorOutput::new("the response is too large for a batch")).ok(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handlers::*, middlewares::verify_token, AppConfig, AppState, User};
+    use anyhow::Result;
+    use axum::{
+        middleware::from_fn_with_state,
+        routing::{get, post},
+    };
+
+    #[tokio::test]
+    async fn batch_should_run_each_request() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let user = User::find_by_email("tchen@acme.org", &state.pool).await?.expect("user should exist");
+        let token = state.ek.sign(user)?;
+        let api = Router::new()
+            .route("/chats", get(list_chat_handler))
+            .route("/chats/{id}", get(get_chat_handler))
+            .route("/chats/{id}/read", post(mark_chat_read_handler))
+            .layer(from_fn_with_state(state.clone(), verify_token));
+        let api = BatchApi::new(api, state);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse()?);
+        let requests = vec![
+            BatchRequest { method: "GET".to_string(), path: "/api/chats".to_string(), body: None },
+            BatchRequest { method: "GET".to_string(), path: "/api/chats/999".to_string(), body: None },
+            BatchRequest { method: "POST".to_string(), path: "/api/batch".to_string(), body: None },
+            BatchRequest { method: "POST".to_string(), path: "/api/signin".to_string(), body: None },
+            BatchRequest {
+                method: "POST".to_string(),
+                path: "/api/chats/1/read".to_string(),
+                body: Some(serde_json::json!({ "seq": 1 })),
+            },
+        ];
+        let ret = run(&api, &headers, ApiVersion(2), None, requests).await?;
+        let statuses: Vec<u16> = ret.iter().map(|r| r.status).collect();
+        assert_eq!(statuses, vec![200, 404, 404, 404, 204]);
+        assert!(ret[0].body.as_ref().is_some_and(|b| b["items"].is_array()));
+        assert_eq!(ret[4].body, None);
+
+        let ret = run(&api, &headers, ApiVersion(2), None, vec![]).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use std::io::{self, BufRead, Write};
+
+use anyhow::{bail, Context, Result};
+use sqlx::{postgres::PgPoolOptions, PgPool};
+
+use crate::{config::AuthConfig, db::MIGRATOR, AppConfig, CreateUser, User, WorkspaceRole};
+
+// `chat_server migrate`, applies the migrations this build has that the database doesn't
+pub async fn migrate(config: &AppConfig) -> Result<()> {
+    let pool = connect(config).await?;
+    MIGRATOR.run(&pool).await?;
+    let latest = MIGRATOR.iter().map(|m| m.version).max().unwrap_or_default();
+    println!("database is at migration {}", latest);
+    Ok(())
+}
+
+// `chat_server seed`, loads the demo workspaces, users and chats used in development. Every
+// user's password is 123456, so never on a database real users sign in to.
+pub async fn seed(config: &AppConfig) -> Result<()> {
+    let pool = connect(config).await?;
+    let users = load_demo_data(&pool).await?;
+    println!("seeded {} users, sign in as tchen@acme.org with password 123456", users);
+    Ok(())
+}
+
+// `chat_server create-admin <email> <fullname> <workspace>`, with the password read from
+// CHAT_ADMIN_PASSWORD or stdin. The workspace is created if it doesn't exist yet.
+pub async fn create_admin(config: &AppConfig, args: &[String]) -> Result<()> {
+    let [email, fullname, workspace] = args else {
+        bail!("usage: chat_server create-admin <email> <fullname> <workspace>");
+    };
+    let password = match std::env::var("CHAT_ADMIN_PASSWORD") {
+        Ok(password) => password,
+        Err(_) => read_password()?,
+    };
+    let input = CreateUser {
+        fullname: fullname.clone(),
+        email: email.clone(),
+        workspace: workspace.clone(),
+        password,
+    };
+    let pool = connect(config).await?;
+    let user = add_admin(&input, &config.auth, &pool).await?;
+    println!("created admin {} ({}) in workspace {}", user.email, user.id, workspace);
+    Ok(())
+}
+
+async fn connect(config: &AppConfig) -> Result<PgPool> {
+    PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&config.server.db_url)
+        .await
+        .context("connect to db failed")
+}
+
+async fn load_demo_data(pool: &PgPool) -> Result<i64> {
+    let mut tx = pool.begin().await?;
+    // the demo data refers to rows by id, so it only fits an empty database. User 0 is the
+    // placeholder owner added by the migrations.
+    let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE id > 0").fetch_one(&mut *tx).await?;
+    if users > 0 {
+        bail!("database already has {} users, seed only fills an empty one", users);
+    }
+    for sql in include_str!("../fixtures/test.sql").split(';') {
+        if sql.trim().is_empty() {
+            continue;
+        }
+        sqlx::query(sql).execute(&mut *tx).await?;
+    }
+    let users = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE id > 0").fetch_one(&mut *tx).await?;
+    tx.commit().await?;
+    Ok(users)
+}
+
+// created by the operator, so the email counts as verified
+async fn add_admin(input: &CreateUser, auth: &AuthConfig, pool: &PgPool) -> Result<User> {
+    let user = User::create(input, &auth.password_policy, &auth.password_hashing, pool).await?;
+    if !user.is_workspace_admin(pool).await? {
+        user.set_role(user.id as u64, WorkspaceRole::Admin, pool).await?;
+    }
+    sqlx::query("UPDATE users SET email_verified_at = NOW() WHERE id = $1")
+        .bind(user.id)
+        .execute(pool)
+        .await?;
+    Ok(user)
+}
+
+fn read_password() -> Result<String> {
+    eprint!("password: ");
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    let password = line.trim_end_matches(['\r', '\n']).to_string();
+    if password.is_empty() {
+        bail!("no password given");
+    }
+    Ok(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+
+    #[tokio::test]
+    async fn add_admin_should_promote_in_existing_workspace() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateUser {
+            fullname: "Ops Admin".to_string(),
+            email: "ops@acme.org".to_string(),
+            workspace: "acme".to_string(),
+            password: "correct horse battery".to_string(),
+        };
+        // acme has no owner in the fixtures, so the first one takes it over
+        add_admin(&input, &config.auth, &pool).await?;
+        let input = CreateUser { email: "ops2@acme.org".to_string(), ..input };
+        let user = add_admin(&input, &config.auth, &pool).await?;
+        assert_eq!(user.ws_id, 1);
+        assert!(user.is_workspace_admin(&pool).await?);
+        assert!(user.is_email_verified(&pool).await?);
+
+        // fixtures are loaded already
+        assert!(load_demo_data(&pool).await.is_err());
+        Ok(())
+    }
+}
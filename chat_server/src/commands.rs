@@ -0,0 +1,257 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    models::{is_command_name, IntegrationSender},
+    webhooks::{sign, SIGNATURE_HEADER},
+    AppError, AppState, Chat, CreateMessage, Message, SlashCommand, User,
+};
+
+// the user is waiting on the reply
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+const BUILTINS: &[(&str, &str)] = &[
+    ("me", "/me <action>, say you are doing something"),
+    ("shrug", "/shrug [message], append ¯\\_(ツ)_/¯"),
+    ("help", "/help, list the commands"),
+];
+
+// what sending a message ended up doing
+#[derive(Debug)]
+pub(crate) enum CommandReply {
+    Message(Message),
+    // only for the sender, it isn't stored or sent to the other members
+    Ephemeral(String),
+}
+
+#[derive(Debug, Serialize)]
+struct CommandCall<'a> {
+    command: &'a str,
+    text: &'a str,
+    user_id: i64,
+    user_name: &'a str,
+    chat_id: u64,
+    ws_id: i64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CommandResponse {
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    response_type: ResponseType,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ResponseType {
+    #[default]
+    Ephemeral,
+    InChannel,
+}
+
+// the command name and its arguments, None when the content isn't a command. A leading //
+// is how to send a message that starts with a slash.
+pub(crate) fn parse(content: &str) -> Option<(&str, &str)> {
+    let rest = content.strip_prefix('/')?;
+    let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    is_command_name(name).then(|| (name, args.trim()))
+}
+
+// posts the message, or runs the command it starts with. A /word that isn't a command is
+// posted as it is.
+pub(crate) async fn send(input: &CreateMessage, chat_id: u64, user: &User, state: &AppState) -> Result<CommandReply, AppError> {
+    let Some((name, args)) = parse(&input.content) else {
+        let message = match input.content.strip_prefix("//") {
+            Some(content) => {
+                let input = CreateMessage { content: format!("/{}", content), ..input.clone() };
+                Message::create(&input, chat_id, user.id as _, &state.pool).await?
+            }
+            None => Message::create(input, chat_id, user.id as _, &state.pool).await?,
+        };
+        return Ok(CommandReply::Message(message));
+    };
+    if !Chat::is_member(chat_id, user.id as _, &state.pool).await? {
+        return Err(AppError::CreateMessageError(format!(
+            "User {} is not a member of chat {}",
+            user.id, chat_id
+        )));
+    }
+
+    let post = |content: String| CreateMessage { content, ..input.clone() };
+    match name {
+        "me" if args.is_empty() => Ok(CommandReply::Ephemeral("usage: /me <action>".to_string())),
+        "me" => {
+            let message = Message::create(&post(format!("_{}_", args)), chat_id, user.id as _, &state.pool).await?;
+            Ok(CommandReply::Message(message))
+        }
+        "shrug" => {
+            let content = format!("{} ¯\\_(ツ)_/¯", args).trim_start().to_string();
+            let message = Message::create(&post(content), chat_id, user.id as _, &state.pool).await?;
+            Ok(CommandReply::Message(message))
+        }
+        "help" => help(user, state).await,
+        _ => match SlashCommand::find_by_name(user.ws_id as _, name, &state.pool).await? {
+            Some(command) => run(&command, args, &post, chat_id, user, state).await,
+            None => {
+                let message = Message::create(input, chat_id, user.id as _, &state.pool).await?;
+                Ok(CommandReply::Message(message))
+            }
+        },
+    }
+}
+
+async fn run(
+    command: &SlashCommand,
+    args: &str,
+    post: &impl Fn(String) -> CreateMessage,
+    chat_id: u64,
+    user: &User,
+    state: &AppState,
+) -> Result<CommandReply, AppError> {
+    let name = &command.name;
+    let res = match call(command, args, chat_id, user, state).await {
+        Ok(res) => res,
+        Err(e) => {
+            warn!("slash command /{} of workspace {} failed: {}", name, command.ws_id, e);
+            return Ok(CommandReply::Ephemeral(format!("/{} didn't respond, try again later", name)));
+        }
+    };
+    if res.response_type == ResponseType::Ephemeral || res.text.is_empty() {
+        return Ok(CommandReply::Ephemeral(res.text));
+    }
+    // shown as the command, sent on behalf of whoever ran it
+    let sender = IntegrationSender {
+        display_name: format!("/{}", name),
+        icon_url: None,
+    };
+    let message = Message::create_integration(&post(res.text), chat_id, user.id as _, &sender, &state.pool).await?;
+    Ok(CommandReply::Message(message))
+}
+
+async fn help(user: &User, state: &AppState) -> Result<CommandReply, AppError> {
+    let mut lines: Vec<String> = BUILTINS.iter().map(|(_, usage)| usage.to_string()).collect();
+    for command in SlashCommand::list(user.ws_id as _, &state.pool).await? {
+        if command.description.is_empty() {
+            lines.push(format!("/{}", command.name));
+        } else {
+            lines.push(format!("/{}, {}", command.name, command.description));
+        }
+    }
+    Ok(CommandReply::Ephemeral(lines.join("\n")))
+}
+
+// signed like webhook deliveries, with the command's own secret. A body that isn't json is
+// taken as ephemeral text.
+async fn call(command: &SlashCommand, args: &str, chat_id: u64, user: &User, state: &AppState) -> anyhow::Result<CommandResponse> {
+    let body = serde_json::to_vec(&CommandCall {
+        command: &command.name,
+        text: args,
+        user_id: user.id,
+        user_name: &user.fullname,
+        chat_id,
+        ws_id: command.ws_id,
+    })?;
+    let request = state
+        .http
+        .post(&command.url)
+        .timeout(COMMAND_TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, sign(command.secret.as_deref().unwrap_or_default(), &body))
+        .body(body);
+    let res = state.http.send_public(request).await?.error_for_status()?;
+    let body = res.bytes().await?;
+    if body.is_empty() {
+        return Ok(CommandResponse::default());
+    }
+    Ok(serde_json::from_slice(&body).unwrap_or_else(|_| CommandResponse {
+        text: String::from_utf8_lossy(&body).into_owned(),
+        ..Default::default()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppConfig;
+    use anyhow::Result;
+    use axum::{body::Bytes, http::HeaderMap, routing::post, Json, Router};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parse_should_only_take_command_names() {
+        assert_eq!(parse("/me waves"), Some(("me", "waves")));
+        assert_eq!(parse("/shrug"), Some(("shrug", "")));
+        assert_eq!(parse("/deploy  api prod "), Some(("deploy", "api prod")));
+        assert_eq!(parse("/usr/bin is full"), None);
+        assert_eq!(parse("//me"), None);
+        assert_eq!(parse("hello /me"), None);
+    }
+
+    #[tokio::test]
+    async fn builtin_commands_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let user = User::find_by_id(1, &state.pool).await?.expect("user");
+
+        let CommandReply::Message(msg) = send(&CreateMessage::new("/me waves"), 1, &user, &state).await? else {
+            panic!("expected a message");
+        };
+        assert_eq!(msg.content, "_waves_");
+        let CommandReply::Message(msg) = send(&CreateMessage::new("/shrug ok"), 1, &user, &state).await? else {
+            panic!("expected a message");
+        };
+        assert_eq!(msg.content, "ok ¯\\_(ツ)_/¯");
+        let CommandReply::Message(msg) = send(&CreateMessage::new("//me"), 1, &user, &state).await? else {
+            panic!("expected a message");
+        };
+        assert_eq!(msg.content, "/me");
+        // not a command, sent as it is
+        let CommandReply::Message(msg) = send(&CreateMessage::new("/nope"), 1, &user, &state).await? else {
+            panic!("expected a message");
+        };
+        assert_eq!(msg.content, "/nope");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn external_command_should_post_in_channel() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        // the receiver of the test is local
+        config.outbound.allow_private_targets = true;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let receiver = Router::new().route(
+            "/deploy",
+            post(|headers: HeaderMap, body: Bytes| async move {
+                let call: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+                Json(serde_json::json!({
+                    "response_type": "in_channel",
+                    "text": format!("deploying {} ({})", call["text"].as_str().unwrap_or_default(), headers.contains_key(SIGNATURE_HEADER)),
+                }))
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/deploy", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+        let input = crate::CreateSlashCommand {
+            name: "deploy".to_string(),
+            url: "https://ops.acme.org/deploy".to_string(),
+            description: String::new(),
+        };
+        let command = SlashCommand::create(1, &input, 1, &state.pool).await?;
+        // only public https urls can be registered
+        sqlx::query("UPDATE slash_commands SET url = $2 WHERE id = $1")
+            .bind(command.id)
+            .bind(url)
+            .execute(&state.pool)
+            .await?;
+        let user = User::find_by_id(1, &state.pool).await?.expect("user");
+        let CommandReply::Message(msg) = send(&CreateMessage::new("/deploy api"), 1, &user, &state).await? else {
+            panic!("expected a message");
+        };
+        assert_eq!(msg.content, "deploying api (true)");
+        assert_eq!(msg.display_name.as_deref(), Some("/deploy"));
+        Ok(())
+    }
+}
@@ -1,38 +1,1005 @@
-use std::{fs::File, env};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{bail, Result};
+use figment::{
+    providers::{Env, Format, Yaml},
+    Figment,
+};
 use serde::{Deserialize, Serialize};
+use tracing::level_filters::LevelFilter;
+
+use crate::features::FeatureFlags;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub mail: MailConfig,
+    #[serde(default)]
+    pub webauthn: WebauthnConfig,
+    // social login providers by name, google or github
+    #[serde(default)]
+    pub oauth: HashMap<String, OAuthProviderConfig>,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub features: FeatureFlags,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    #[serde(default)]
+    pub security_headers: SecurityHeadersConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub outbound: OutboundConfig,
+    #[serde(default)]
+    pub request_limits: RequestLimitsConfig,
+    #[serde(default)]
+    pub events: EventBusConfig,
+    // off unless set
+    #[serde(default)]
+    pub grpc: Option<GrpcConfig>,
+    // /metrics is not served unless set
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+}
+
+// the scraper sends the token as a bearer token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    pub token: String,
+}
+
+// the grpc service for other services in the deployment, see proto/chat.proto
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrpcConfig {
+    // only reachable from this host unless set, e.g. to 0.0.0.0
+    #[serde(default = "default_grpc_host")]
+    pub host: String,
+    pub port: u16,
+    // always tls, tokens are bearer secrets
+    pub tls: TlsConfig,
+    // who may call, each only into its own workspace
+    pub clients: Vec<GrpcClient>,
+}
+
+// a caller's secret, sent as a bearer token. A workspace can have more than one so they can be
+// rotated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrpcClient {
+    pub ws_id: u64,
+    pub token: String,
+}
+
+// tokens are long random secrets, anything shorter is likely a placeholder
+const MIN_GRPC_TOKEN_LEN: usize = 32;
+const MIN_METRICS_TOKEN_LEN: usize = 32;
+
+fn default_grpc_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+impl GrpcConfig {
+    fn validate(&self) -> Result<()> {
+        if self.clients.is_empty() {
+            bail!("grpc: at least one client is needed");
+        }
+        let mut tokens = HashSet::new();
+        for client in &self.clients {
+            if client.token.len() < MIN_GRPC_TOKEN_LEN {
+                bail!("grpc: the token of workspace {} must be at least {} characters", client.ws_id, MIN_GRPC_TOKEN_LEN);
+            }
+            if !tokens.insert(client.token.as_str()) {
+                bail!("grpc: a token is used more than once");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MetricsConfig {
+    fn validate(&self) -> Result<()> {
+        if self.token.len() < MIN_METRICS_TOKEN_LEN {
+            bail!("metrics: the token must be at least {} characters", MIN_METRICS_TOKEN_LEN);
+        }
+        Ok(())
+    }
+}
+
+// how events reach every instance, see events::listen. Instances behind one load balancer must
+// all use the same backend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EventBusConfig {
+    pub backend: EventBackend,
+    // required by the redis backend, e.g. redis://localhost:6379
+    pub redis_url: Option<String>,
+    // how far back clients polling /api/events/poll can catch up from
+    pub log_retention_hours: u64,
+    // how long a client can be offline and still catch up with /api/sync rather than reload
+    pub sync_retention_days: u64,
+}
+
+impl Default for EventBusConfig {
+    fn default() -> Self {
+        Self {
+            backend: EventBackend::default(),
+            redis_url: None,
+            log_retention_hours: 24,
+            sync_retention_days: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventBackend {
+    // LISTEN/NOTIFY on the primary, nothing else to run
+    #[default]
+    Postgres,
+    // pub/sub, when the primary can't spare a connection per instance or notify is unavailable,
+    // e.g. behind a transaction pooler
+    Redis,
+}
+
+impl EventBusConfig {
+    fn validate(&self) -> Result<()> {
+        if self.backend == EventBackend::Redis && self.redis_url.is_none() {
+            bail!("events: the redis backend needs redis_url");
+        }
+        Ok(())
+    }
+}
+
+// how long a handler may take and how large a request body may be
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestLimitsConfig {
+    // for routes without a rule of their own
+    pub timeout_secs: u64,
+    pub body_bytes: usize,
+    // requests handled at once, more are refused with 503. None means no cap.
+    pub max_in_flight: Option<usize>,
+    // the first matching rule applies
+    pub routes: Vec<RouteRequestLimit>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteRequestLimit {
+    // path prefix, {name} matches any one segment, e.g. /api/chats/{id}
+    pub path: String,
+    // any method when not set
+    #[serde(default)]
+    pub method: Option<String>,
+    // the defaults when not set
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub body_bytes: Option<usize>,
+}
+
+impl RouteRequestLimit {
+    fn new(method: &str, path: &str, timeout_secs: Option<u64>, body_bytes: Option<usize>) -> Self {
+        Self {
+            path: path.to_string(),
+            method: Some(method.to_string()),
+            timeout_secs,
+            body_bytes,
+        }
+    }
+}
+
+impl Default for RequestLimitsConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 30,
+            body_bytes: 256 * 1024,
+            max_in_flight: Some(1024),
+            routes: vec![
+                // long messages are stored in chunks
+                RouteRequestLimit::new("POST", "/api/chats/{id}", None, Some(1024 * 1024)),
+                RouteRequestLimit::new("GET", "/api/workspace/members/export", Some(120), None),
+                RouteRequestLimit::new("POST", "/api/workspace/clone", Some(120), None),
+            ],
+        }
+    }
+}
+
+// calls to third parties, e.g. webhooks and identity providers, see utils::HttpClient
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboundConfig {
+    pub connect_timeout_ms: u64,
+    // for the whole request, unless the destination has its own
+    pub timeout_ms: u64,
+    // only requests that are safe to repeat are retried: GET and HEAD on any failure, others
+    // only when the connection couldn't be made
+    pub retries: u32,
+    // doubled after every retry
+    pub retry_backoff_ms: u64,
+    // consecutive failures after which requests to the endpoint, the url without its query,
+    // fail fast for open_secs
+    pub failure_threshold: u32,
+    pub open_secs: u64,
+    // by host name, e.g. hooks.slack.com
+    pub hosts: HashMap<String, OutboundHostConfig>,
+    // lets webhooks and slash commands reach this machine and its network, for development
+    pub allow_private_targets: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboundHostConfig {
+    pub timeout_ms: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_ms: 3000,
+            timeout_ms: 10_000,
+            retries: 2,
+            retry_backoff_ms: 200,
+            failure_threshold: 5,
+            open_secs: 30,
+            hosts: HashMap::new(),
+            allow_private_targets: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    // smaller responses are sent as they are
+    pub min_size_bytes: u16,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_size_bytes: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    pub level: LogLevel,
+    // requests taking longer are logged as warnings, none turns it off
+    pub slow_request_ms: Option<u64>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            level: LogLevel::default(),
+            slow_request_ms: Some(1000),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+// the part of the config applied without a restart when the file changes, see reload::watch
+#[derive(Debug, Clone)]
+pub(crate) struct LiveConfig {
+    pub rate_limit: RateLimitConfig,
+    pub features: FeatureFlags,
+    pub log_level: LogLevel,
+}
+
+impl From<&AppConfig> for LiveConfig {
+    fn from(config: &AppConfig) -> Self {
+        Self {
+            rate_limit: config.rate_limit.clone(),
+            features: config.features.clone(),
+            log_level: config.log.level,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    // human readable, for development
+    #[default]
+    Text,
+    // one object per line with the request span's fields, for log collectors
+    Json,
+}
+
+// set on every response that doesn't set them itself, an empty value leaves the header out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityHeadersConfig {
+    pub content_security_policy: String,
+    pub content_type_options: String,
+    pub referrer_policy: String,
+    // browsers ignore it over plain http, so it is harmless in development
+    pub strict_transport_security: String,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            content_security_policy: "default-src 'self'; frame-ancestors 'none'; base-uri 'self'; form-action 'self'"
+                .to_string(),
+            content_type_options: "nosniff".to_string(),
+            referrer_policy: "strict-origin-when-cross-origin".to_string(),
+            strict_transport_security: "max-age=31536000; includeSubDomains".to_string(),
+        }
+    }
+}
+
+impl SecurityHeadersConfig {
+    pub fn headers(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("content-security-policy", &self.content_security_policy),
+            ("x-content-type-options", &self.content_type_options),
+            ("referrer-policy", &self.referrer_policy),
+            ("strict-transport-security", &self.strict_transport_security),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name, value.trim()))
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (name, value) in self.headers() {
+            if value.parse::<axum::http::HeaderValue>().is_err() {
+                bail!("security_headers: invalid value for {}: {}", name, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+// browser clients served from other origins, none are allowed by default
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    // e.g. https://app.acme.org, or * for any origin
+    pub allowed_origins: Vec<String>,
+    // lets browsers send cookies and authorization headers, can't be used with *
+    pub allow_credentials: bool,
+    // how long browsers may cache a preflight response
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|o| o == "*")
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.allow_credentials && self.allows_any_origin() {
+            bail!("cors: allow_credentials can't be used with any origin (*)");
+        }
+        for origin in self.allowed_origins.iter().filter(|o| *o != "*") {
+            let valid = (origin.starts_with("https://") || origin.starts_with("http://"))
+                && !origin.ends_with('/')
+                && origin.parse::<axum::http::HeaderValue>().is_ok();
+            if !valid {
+                bail!("cors: invalid origin {}, expected scheme://host[:port]", origin);
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthConfig {
     pub sk: String,
     pub pk: String,
+    // key id put in tokens signed with sk, derived from pk when not set
+    #[serde(default)]
+    pub kid: Option<String>,
+    // public keys rotated out, still accepted for tokens issued before the rotation
+    #[serde(default)]
+    pub previous_keys: Vec<PreviousKey>,
+    #[serde(default)]
+    pub email_verification: EmailVerification,
+    // header set by the edge proxy with the client's country, e.g. cf-ipcountry
+    #[serde(default)]
+    pub country_header: Option<String>,
+    #[serde(default)]
+    pub signin_throttle: SigninThrottleConfig,
+    #[serde(default)]
+    pub password_policy: PasswordPolicy,
+    #[serde(default)]
+    pub password_hashing: PasswordHashing,
+    // members of these workspaces sign in with their directory password, checked against the
+    // workspace's ldap server instead of a password stored here
+    #[serde(default)]
+    pub ldap: Vec<LdapConfig>,
+    #[serde(default)]
+    pub cookie_session: CookieSessionConfig,
+}
+
+// for browser apps, the access token is also set as an HttpOnly cookie at signin so that it never
+// has to be kept in js. Requests authenticated by the cookie alone must echo the csrf cookie in
+// csrf_header for anything but GET, HEAD and OPTIONS.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CookieSessionConfig {
+    pub enabled: bool,
+    pub cookie_name: String,
+    // readable by js, unlike the token cookie
+    pub csrf_cookie_name: String,
+    pub csrf_header: String,
+    // HttpOnly as well, the refresh token is left out of the response body
+    pub refresh_cookie_name: String,
+    // lax or strict, none only works together with secure
+    pub same_site: String,
+    // can only be turned off for plain http on localhost in development
+    pub secure: bool,
+}
+
+impl Default for CookieSessionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cookie_name: "chat_token".to_string(),
+            csrf_cookie_name: "chat_csrf".to_string(),
+            csrf_header: "x-csrf-token".to_string(),
+            refresh_cookie_name: "chat_refresh".to_string(),
+            same_site: "Lax".to_string(),
+            secure: true,
+        }
+    }
+}
+
+impl CookieSessionConfig {
+    // base_url is where the app is served, see mail.base_url
+    fn validate(&self, base_url: &str) -> Result<()> {
+        if self.enabled && !self.secure && !is_localhost_url(base_url) {
+            bail!("cookie_session: secure can only be off when the app is served from localhost, not {}", base_url);
+        }
+        let same_site = self.same_site.to_ascii_lowercase();
+        if !["lax", "strict", "none"].contains(&same_site.as_str()) {
+            bail!("cookie_session: invalid same_site {}, expected lax, strict or none", self.same_site);
+        }
+        if same_site == "none" && !self.secure {
+            bail!("cookie_session: same_site none requires secure");
+        }
+        if axum::http::HeaderName::try_from(self.csrf_header.as_str()).is_err() {
+            bail!("cookie_session: invalid csrf_header {}", self.csrf_header);
+        }
+        Ok(())
+    }
+}
+
+fn is_localhost_url(url: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(url) else {
+        return false;
+    };
+    url.scheme() == "http" && matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LdapConfig {
+    // slug of the workspace the server signs members in for
+    pub workspace: String,
+    // ldaps://host:636, or ldap://host:389 together with starttls. Passwords are never sent
+    // over a plain connection.
+    pub url: String,
+    #[serde(default)]
+    pub starttls: bool,
+    // service account users are looked up with, an anonymous bind when empty
+    #[serde(default)]
+    pub bind_dn: String,
+    #[serde(default)]
+    pub bind_password: String,
+    pub base_dn: String,
+    // the attribute holding the email members sign in with, userPrincipalName for active directory
+    #[serde(default = "default_ldap_email_attr")]
+    pub email_attr: String,
+    #[serde(default = "default_ldap_object_class")]
+    pub object_class: String,
+    #[serde(default = "default_ldap_name_attr")]
+    pub name_attr: String,
+    // emails at these domains without an account become members at their first signin
+    #[serde(default)]
+    pub domains: Vec<String>,
+    #[serde(default = "default_ldap_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_ldap_email_attr() -> String {
+    "mail".to_string()
+}
+
+fn default_ldap_object_class() -> String {
+    "person".to_string()
+}
+
+fn default_ldap_name_attr() -> String {
+    "cn".to_string()
+}
+
+fn default_ldap_timeout_secs() -> u64 {
+    5
+}
+
+impl LdapConfig {
+    fn validate(&self) -> Result<()> {
+        if !self.url.starts_with("ldap://") && !self.url.starts_with("ldaps://") {
+            bail!("ldap: invalid url {} for {}, expected ldap:// or ldaps://", self.url, self.workspace);
+        }
+        if self.url.starts_with("ldap://") && !self.starttls {
+            bail!("ldap: {} for {} needs starttls, or use ldaps://", self.url, self.workspace);
+        }
+        if self.base_dn.trim().is_empty() {
+            bail!("ldap: base_dn is required for {}", self.workspace);
+        }
+        Ok(())
+    }
+}
+
+// argon2id cost, stored hashes made with lower costs are upgraded at the next signin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordHashing {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordHashing {
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviousKey {
+    pub pk: String,
+    // the kid the key was configured with, derived from pk when not set
+    #[serde(default)]
+    pub kid: Option<String>,
+}
+
+// checked whenever a password is set, at signup and on reset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    // how many of lowercase, uppercase, digits and symbols must be used
+    pub min_classes: u8,
+    // the lowest guess score allowed, 0 (too guessable) to 4 (very unguessable). The score is a
+    // built in estimate, see utils::password, not zxcvbn; only its scale is the same.
+    #[serde(alias = "min_score")]
+    pub min_guess_score: Option<u8>,
+    pub deny_common: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            min_classes: 2,
+            min_guess_score: None,
+            deny_common: true,
+        }
+    }
+}
+
+// failed signins after which further attempts have to wait, twice as long after every
+// further failure. Counted per email, and per ip to slow down guessing across accounts.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SigninThrottleConfig {
+    pub email_failures: u32,
+    pub ip_failures: u32,
+    // failures older than this are forgotten
+    pub window_secs: u32,
+    pub base_delay_secs: u32,
+    pub max_delay_secs: u32,
+}
+
+impl Default for SigninThrottleConfig {
+    fn default() -> Self {
+        Self {
+            email_failures: 5,
+            ip_failures: 20,
+            window_secs: 900,
+            base_delay_secs: 30,
+            max_delay_secs: 900,
+        }
+    }
+}
+
+// what unverified users are kept from doing
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailVerification {
+    // creating chats, sending messages and inviting others
+    #[default]
+    RestrictActions,
+    // the above, and signing in at all
+    BlockSignin,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MailConfig {
+    pub from: String,
+    // prefix for links in emails
+    pub base_url: String,
+    // how long a mention or direct message may stay unread before it is emailed
+    #[serde(default = "default_notify_delay_secs")]
+    pub notify_delay_secs: u64,
+    // lets notification emails be answered by replying to them, off unless set
+    #[serde(default)]
+    pub reply: Option<MailReplyConfig>,
+}
+
+// replies go to reply+<signed token>@domain, mail for the domain has to be posted to /mail/inbound
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailReplyConfig {
+    pub domain: String,
+    // signs the reply addresses
+    pub secret: String,
+    // bearer token the inbound mail service calls /mail/inbound with
+    pub inbound_token: String,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            from: "noreply@localhost".to_string(),
+            base_url: "http://localhost:6688".to_string(),
+            notify_delay_secs: default_notify_delay_secs(),
+            reply: None,
+        }
+    }
+}
+
+fn default_notify_delay_secs() -> u64 {
+    120
+}
+
+impl MailReplyConfig {
+    fn validate(&self) -> Result<()> {
+        if self.domain.is_empty() || self.domain.contains('@') {
+            bail!("mail.reply: domain must be a domain, e.g. reply.acme.org");
+        }
+        if self.secret.len() < 16 || self.inbound_token.len() < 16 {
+            bail!("mail.reply: secret and inbound_token must be at least 16 characters");
+        }
+        Ok(())
+    }
+}
+
+// relying party passkeys are bound to, must match the domain the web client runs on
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnConfig {
+    pub rp_id: String,
+    pub rp_name: String,
+    pub origin: String,
+}
+
+impl Default for WebauthnConfig {
+    fn default() -> Self {
+        Self {
+            rp_id: "localhost".to_string(),
+            rp_name: "Chat".to_string(),
+            origin: "http://localhost:6688".to_string(),
+        }
+    }
+}
+
+// the endpoints default to the provider's public ones
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OAuthProviderConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub auth_url: Option<String>,
+    #[serde(default)]
+    pub token_url: Option<String>,
+    #[serde(default)]
+    pub api_url: Option<String>,
+}
+
+// token buckets keyed by the signed in user, or the client's ip for anonymous requests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // for routes without a rule of their own
+    pub requests_per_minute: u32,
+    // bucket size, defaults to a minute's worth of requests
+    pub burst: Option<u32>,
+    // percent of the bucket used after which responses carry a warning
+    pub warn_percent: u32,
+    // header the edge proxy appends the client's ip to, e.g. x-forwarded-for. It is only read
+    // for requests from trusted_proxies, the peer address is used otherwise as the header could
+    // be forged.
+    pub ip_header: Option<String>,
+    // addresses or CIDR ranges of the proxies in front of the server
+    pub trusted_proxies: Vec<String>,
+    // the first matching rule applies
+    pub routes: Vec<RouteRateLimit>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteRateLimit {
+    // path prefix, {name} matches any one segment, e.g. /api/chats/{id}/messages
+    pub path: String,
+    // any method when not set
+    #[serde(default)]
+    pub method: Option<String>,
+    pub requests_per_minute: u32,
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+impl RouteRateLimit {
+    fn new(method: &str, path: &str, requests_per_minute: u32) -> Self {
+        Self {
+            path: path.to_string(),
+            method: Some(method.to_string()),
+            requests_per_minute,
+            burst: None,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: 600,
+            burst: None,
+            warn_percent: 80,
+            ip_header: None,
+            trusted_proxies: vec![],
+            routes: vec![
+                RouteRateLimit::new("POST", "/api/signin", 10),
+                RouteRateLimit::new("POST", "/api/signup", 5),
+                RouteRateLimit::new("POST", "/api/password/reset", 5),
+                RouteRateLimit::new("POST", "/api/verify/resend", 5),
+                RouteRateLimit::new("GET", "/api/chats/{id}/messages", 1200),
+            ],
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
+    // checked by /readyz when set, any response short of a server error counts as reachable
+    #[serde(default)]
+    pub object_storage_url: Option<String>,
+    // after a shutdown signal, how long requests in flight and background jobs get to finish
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    #[serde(default)]
+    pub db_pool: DbPoolConfig,
+    // read only copies of db_url, connected with the same pool settings
+    #[serde(default)]
+    pub replica_urls: Vec<String>,
+    #[serde(default)]
+    pub message_partitions: MessagePartitionConfig,
+    // terminate tls here rather than in a proxy in front, with http/2. Renewed certificates are
+    // picked up within a minute, no restart needed
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    // pem, the server certificate followed by its chain
+    pub cert_path: String,
+    // pem, pkcs8, pkcs1 or sec1
+    pub key_path: String,
+}
+
+// messages are partitioned by month, see the messages_partitioning migration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MessagePartitionConfig {
+    // partitions are created this many months in advance
+    pub months_ahead: u32,
+    // months older than this are taken out of messages, none keeps everything
+    pub retention_months: Option<u32>,
+    // drop expired months instead of leaving them detached for archiving
+    pub drop_expired: bool,
+}
+
+impl Default for MessagePartitionConfig {
+    fn default() -> Self {
+        Self {
+            months_ahead: 2,
+            retention_months: None,
+            drop_expired: false,
+        }
+    }
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DbPoolConfig {
+    pub max_connections: u32,
+    // kept open even when idle, so a burst after a quiet period doesn't wait on new connections
+    pub min_connections: u32,
+    // how long a request waits for a free connection before failing
+    pub acquire_timeout_secs: u64,
+    // idle connections above min_connections are closed after this, none means never
+    pub idle_timeout_secs: Option<u64>,
+    // set as the session's statement_timeout, postgres cancels statements running longer
+    pub statement_timeout_ms: Option<u64>,
+    // statements taking longer are logged as warnings with their sql, none turns it off
+    pub slow_statement_ms: Option<u64>,
+    // attempts after the first to reach a database that isn't up yet at startup, waiting
+    // connect_backoff_ms before the first and doubling up to connect_backoff_max_ms
+    pub connect_retries: u32,
+    pub connect_backoff_ms: u64,
+    pub connect_backoff_max_ms: u64,
+}
+
+impl Default for DbPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout_secs: 30,
+            idle_timeout_secs: Some(600),
+            statement_timeout_ms: None,
+            slow_statement_ms: Some(1000),
+            connect_retries: 10,
+            connect_backoff_ms: 500,
+            connect_backoff_max_ms: 30_000,
+        }
+    }
+}
+
+impl DbPoolConfig {
+    fn validate(&self) -> Result<()> {
+        if self.max_connections == 0 {
+            bail!("server.db_pool.max_connections must be at least 1");
+        }
+        if self.min_connections > self.max_connections {
+            bail!("server.db_pool.min_connections must not exceed max_connections");
+        }
+        if self.connect_backoff_ms > self.connect_backoff_max_ms {
+            bail!("server.db_pool.connect_backoff_ms must not exceed connect_backoff_max_ms");
+        }
+        Ok(())
+    }
 }
 
+const ENV_PREFIX: &str = "CHAT__";
+
 impl AppConfig {
     pub fn load() -> Result<Self> {
-        let ret= match(
-            File::open("app.yml"),
-            File::open("/etc/config/app.yml"),
-            env::var("CHAT_CONFIG"),
-        ) {
-            (Ok(reader), _, _) => serde_yaml::from_reader(reader),
-            (_, Ok(reader), _) => serde_yaml::from_reader(reader),
-            (_, _, Ok(path)) => serde_yaml::from_reader(File::open(path)?),
-            _ => bail!("Failed to load config"),
-        };
-        Ok(ret?)
-    }
-}
\ No newline at end of file
+        Self::load_from(&Self::path()?)
+    }
+
+    // the first of app.yml, /etc/config/app.yml or $CHAT_CONFIG that exists
+    pub(crate) fn path() -> Result<PathBuf> {
+        for path in ["app.yml", "/etc/config/app.yml"] {
+            if Path::new(path).is_file() {
+                return Ok(path.into());
+            }
+        }
+        match env::var("CHAT_CONFIG") {
+            Ok(path) => Ok(path.into()),
+            Err(_) => bail!("Failed to load config"),
+        }
+    }
+
+    // env vars override the file, e.g. CHAT__SERVER__PORT=8080 for server.port or
+    // CHAT__FEATURES__NEW_PAGINATION__ENABLED=false
+    pub(crate) fn load_from(path: &Path) -> Result<Self> {
+        Self::load_with_env(path, ENV_PREFIX)
+    }
+
+    // tests use their own prefix, the environment is shared by the whole test binary
+    fn load_with_env(path: &Path, env_prefix: &str) -> Result<Self> {
+        if !path.is_file() {
+            bail!("Failed to load config, {} not found", path.display());
+        }
+        let config: Self = Figment::from(Yaml::file(path))
+            .merge(Env::prefixed(env_prefix).split("__"))
+            .extract()?;
+        config.server.db_pool.validate()?;
+        config.cors.validate()?;
+        config.security_headers.validate()?;
+        config.auth.cookie_session.validate(&config.mail.base_url)?;
+        config.events.validate()?;
+        if let Some(grpc) = &config.grpc {
+            grpc.validate()?;
+        }
+        if let Some(metrics) = &config.metrics {
+            metrics.validate()?;
+        }
+        if let Some(reply) = &config.mail.reply {
+            reply.validate()?;
+        }
+        for ldap in &config.auth.ldap {
+            ldap.validate()?;
+        }
+        Ok(config)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_vars_should_override_the_file() -> Result<()> {
+        let path = Path::new("app.yml");
+        let file = AppConfig::load_with_env(path, "CHAT_TEST_OVERRIDE__")?;
+        assert_eq!(file.server.port, 6688);
+        // SAFETY: no other test reads or writes variables with this prefix
+        unsafe {
+            env::set_var("CHAT_TEST_OVERRIDE__SERVER__PORT", "8080");
+            env::set_var("CHAT_TEST_OVERRIDE__SERVER__DB_POOL__MAX_CONNECTIONS", "3");
+        }
+        let config = AppConfig::load_with_env(path, "CHAT_TEST_OVERRIDE__")?;
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.db_pool.max_connections, 3);
+        // the rest still comes from the file
+        assert_eq!(config.server.db_url, file.server.db_url);
+        assert_eq!(config.server.db_pool.min_connections, file.server.db_pool.min_connections);
+
+        // overrides are validated like the file is
+        unsafe { env::set_var("CHAT_TEST_INVALID__SERVER__DB_POOL__CONNECT_BACKOFF_MS", "60000") };
+        assert!(AppConfig::load_with_env(path, "CHAT_TEST_INVALID__").is_err());
+        Ok(())
+    }
+}
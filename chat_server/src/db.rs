@@ -0,0 +1,164 @@
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sqlx::{
+    migrate::Migrator,
+    postgres::{PgConnectOptions, PgPoolOptions},
+    ConnectOptions, PgPool,
+};
+use tracing::warn;
+
+use crate::{
+    config::{DbPoolConfig, ServerConfig},
+    doctor::redact,
+    AppConfig,
+};
+
+pub(crate) static MIGRATOR: Migrator = sqlx::migrate!("../migrations");
+
+// sends writes to the primary and spreads reads that can tolerate replication lag over the
+// replicas, round robin. Without replicas everything goes to the primary.
+#[derive(Debug)]
+pub(crate) struct DbRouter {
+    primary: PgPool,
+    replicas: Vec<PgPool>,
+    next: AtomicUsize,
+}
+
+impl DbRouter {
+    pub(crate) fn new(primary: PgPool, replicas: Vec<PgPool>) -> Self {
+        Self {
+            primary,
+            replicas,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) async fn connect(server: &ServerConfig) -> Result<Self, sqlx::Error> {
+        let primary = connect_with_retry(&server.db_url, &server.db_pool).await?;
+        let mut replicas = Vec::with_capacity(server.replica_urls.len());
+        for url in &server.replica_urls {
+            replicas.push(connect_with_retry(url, &server.db_pool).await?);
+        }
+        Ok(Self::new(primary, replicas))
+    }
+
+    pub(crate) fn primary(&self) -> &PgPool {
+        &self.primary
+    }
+
+    // only for queries that don't have to see the caller's own writes right away, e.g. listing
+    // messages or members
+    pub(crate) fn reader(&self) -> &PgPool {
+        if self.replicas.is_empty() {
+            return &self.primary;
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.replicas.len();
+        &self.replicas[i]
+    }
+
+    pub(crate) async fn close(&self) {
+        for replica in &self.replicas {
+            replica.close().await;
+        }
+        self.primary.close().await;
+    }
+}
+
+// blocks until the primary accepts connections, or server.db_pool.connect_retries run out
+pub async fn wait_for_database(config: &AppConfig) -> Result<(), sqlx::Error> {
+    let pool = connect_with_retry(&config.server.db_url, &config.server.db_pool).await?;
+    pool.close().await;
+    Ok(())
+}
+
+// the database may come up after the server, e.g. when an orchestrator restarts both. Errors
+// that waiting won't fix, such as a wrong password, are returned right away.
+async fn connect_with_retry(url: &str, tuning: &DbPoolConfig) -> Result<PgPool, sqlx::Error> {
+    let mut attempt = 0;
+    loop {
+        match connect(url, tuning).await {
+            Err(e) if attempt < tuning.connect_retries && is_transient(&e) => {
+                let delay = backoff(attempt, tuning, OsRng.next_u64());
+                warn!("connect to {} failed: {}, retrying in {}ms", redact(url), e, delay.as_millis());
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            ret => return ret,
+        }
+    }
+}
+
+fn is_transient(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        // cannot_connect_now while postgres starts up or recovers, too_many_connections
+        sqlx::Error::Database(e) => matches!(e.code().as_deref(), Some("57P03" | "53300")),
+        _ => false,
+    }
+}
+
+// exponential, with the upper half jittered so that instances restarted together don't
+// reconnect in lockstep
+fn backoff(attempt: u32, tuning: &DbPoolConfig, random: u64) -> Duration {
+    let ms = tuning
+        .connect_backoff_ms
+        .saturating_mul(1 << attempt.min(32))
+        .min(tuning.connect_backoff_max_ms);
+    Duration::from_millis(ms - ms / 2 + random % (ms / 2 + 1))
+}
+
+async fn connect(url: &str, tuning: &DbPoolConfig) -> Result<PgPool, sqlx::Error> {
+    let mut options = PgConnectOptions::from_str(url)?;
+    if let Some(ms) = tuning.statement_timeout_ms {
+        options = options.options([("statement_timeout", ms.to_string())]);
+    }
+    // the sql has placeholders, bound values are never logged
+    options = match tuning.slow_statement_ms {
+        Some(ms) => options.log_slow_statements(log::LevelFilter::Warn, Duration::from_millis(ms)),
+        None => options.log_slow_statements(log::LevelFilter::Off, Duration::MAX),
+    };
+    PgPoolOptions::new()
+        .max_connections(tuning.max_connections)
+        .min_connections(tuning.min_connections)
+        .acquire_timeout(Duration::from_secs(tuning.acquire_timeout_secs))
+        .idle_timeout(tuning.idle_timeout_secs.map(Duration::from_secs))
+        .connect_with(options)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[test]
+    fn backoff_should_double_up_to_max() {
+        let tuning = DbPoolConfig::default();
+        assert_eq!(backoff(0, &tuning, 0), Duration::from_millis(250));
+        assert_eq!(backoff(0, &tuning, u64::MAX), Duration::from_millis(250 + u64::MAX % 251));
+        assert_eq!(backoff(2, &tuning, 0), Duration::from_millis(1000));
+        assert_eq!(backoff(10, &tuning, 0), Duration::from_millis(15_000));
+        assert!(backoff(40, &tuning, u64::MAX) <= Duration::from_millis(30_000));
+    }
+
+    #[tokio::test]
+    async fn reader_should_rotate_over_replicas() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let db = DbRouter::new(pool.clone(), vec![]);
+        let n: i32 = sqlx::query_scalar("SELECT 1").fetch_one(db.reader()).await?;
+        assert_eq!(n, 1);
+
+        let replicas = vec![pool.clone(), pool.clone()];
+        let db = DbRouter::new(pool, replicas);
+        assert!(std::ptr::eq(db.reader(), &db.replicas[0]));
+        assert!(std::ptr::eq(db.reader(), &db.replicas[1]));
+        assert!(std::ptr::eq(db.reader(), &db.replicas[0]));
+        Ok(())
+    }
+}
@@ -0,0 +1,471 @@
+use core::fmt;
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::{db::MIGRATOR, scope::Grant, utils::{DecodingKey, EncodingKey, HttpClient, TokenId}, AppConfig, AppState, User};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// beyond this, expiry of access tokens and TOTP codes is off noticeably
+const CLOCK_SKEW_WARN_SECS: i64 = 5;
+const CLOCK_SKEW_FAIL_SECS: i64 = 60;
+// every table the server reads and writes
+const TABLE_PRIVILEGES: &[&str] = &["SELECT", "INSERT", "UPDATE", "DELETE"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    // what was found, and for warnings and failures what to do about it
+    pub message: String,
+}
+
+// the result of checking the config and its environment before serving requests, so that a
+// broken setup is reported up front rather than failing requests later
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DoctorReport {
+    pub checks: Vec<Check>,
+}
+
+impl Check {
+    fn new(name: &'static str, status: CheckStatus, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl DoctorReport {
+    pub async fn run(config: &AppConfig) -> Self {
+        let mut report = Self::default();
+        report.checks.push(check_keys(config));
+        report.checks.push(check_mail_config(config));
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .acquire_timeout(CONNECT_TIMEOUT)
+            .connect(&config.server.db_url)
+            .await;
+        let pool = match pool {
+            Ok(pool) => pool,
+            Err(e) => {
+                let msg = format!(
+                    "cannot connect to {}: {}. Check server.db_url and that postgres is up",
+                    redact(&config.server.db_url),
+                    e
+                );
+                report.checks.push(Check::new("database", CheckStatus::Fail, msg));
+                return report;
+            }
+        };
+        report.checks.push(Check::new("database", CheckStatus::Ok, "connected"));
+        for check in [
+            check_migrations(&pool).await,
+            check_privileges(&pool).await,
+            check_clock(&pool).await,
+            check_smtp(&pool).await,
+        ] {
+            report.checks.push(
+                check.unwrap_or_else(|(name, e)| Check::new(name, CheckStatus::Fail, format!("check failed: {}", e))),
+            );
+        }
+        pool.close().await;
+        report
+    }
+
+    // the subset of checks that can change while serving, run on the server's own pool for /readyz
+    pub(crate) async fn readiness(state: &AppState) -> Self {
+        let mut report = Self::default();
+        let database = timeout(CONNECT_TIMEOUT, sqlx::query("SELECT 1").execute(&state.pool)).await;
+        match database {
+            Ok(Ok(_)) => report.checks.push(Check::new("database", CheckStatus::Ok, "connected")),
+            Ok(Err(e)) => report.checks.push(Check::new("database", CheckStatus::Fail, e.to_string())),
+            Err(_) => report.checks.push(Check::new("database", CheckStatus::Fail, "timed out")),
+        }
+        if report.is_healthy() {
+            let check = check_migrations(&state.pool).await;
+            report.checks.push(
+                check.unwrap_or_else(|(name, e)| Check::new(name, CheckStatus::Fail, format!("check failed: {}", e))),
+            );
+        }
+        if let Some(url) = &state.config.server.object_storage_url {
+            report.checks.push(check_object_storage(&state.http, url).await);
+        }
+        report
+    }
+
+    // for /readyz, which anyone can call: messages can carry database errors and addresses, so
+    // only the names and statuses are kept
+    pub(crate) fn summary(&self) -> Self {
+        let checks = self
+            .checks
+            .iter()
+            .map(|c| {
+                let message = match c.status {
+                    CheckStatus::Ok => "ok",
+                    CheckStatus::Warn => "degraded",
+                    CheckStatus::Fail => "unavailable",
+                };
+                Check::new(c.name, c.status, message)
+            })
+            .collect();
+        Self { checks }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.checks.iter().all(|c| c.status != CheckStatus::Fail)
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let status = match check.status {
+                CheckStatus::Ok => "ok",
+                CheckStatus::Warn => "warn",
+                CheckStatus::Fail => "FAIL",
+            };
+            writeln!(f, "[{:>4}] {}: {}", status, check.name, check.message)?;
+        }
+        Ok(())
+    }
+}
+
+type CheckResult = Result<Check, (&'static str, sqlx::Error)>;
+
+// a token signed with the private key has to verify with the configured public key
+fn check_keys(config: &AppConfig) -> Check {
+    let ret = EncodingKey::load(&config.auth.sk, config.auth.kid.as_deref()).and_then(|ek| {
+        let dk = DecodingKey::from_config(&config.auth)?;
+        let user = User {
+            id: 0,
+            ws_id: 0,
+            fullname: "doctor".to_string(),
+            email: "doctor@localhost".to_string(),
+            password_hash: None,
+            created_at: Utc::now(),
+        };
+        // never handed out, so it doesn't need a session
+        let token = ek.sign_with_id(user, &Grant::default(), &TokenId::generate())?;
+        dk.verify(&token)
+    });
+    match ret {
+        Ok(_) => Check::new("keys", CheckStatus::Ok, "auth.sk and auth.pk are a matching key pair"),
+        Err(e) => Check::new(
+            "keys",
+            CheckStatus::Fail,
+            format!(
+                "auth.sk and auth.pk do not form a valid key pair: {}. Generate a new pair with {}",
+                e, "openssl genpkey -algorithm ed25519"
+            ),
+        ),
+    }
+}
+
+fn check_mail_config(config: &AppConfig) -> Check {
+    let mail = &config.mail;
+    if !mail.from.contains('@') {
+        return Check::new(
+            "mail",
+            CheckStatus::Fail,
+            format!("mail.from is not an email address: {}", mail.from),
+        );
+    }
+    if !(mail.base_url.starts_with("https://") || mail.base_url.starts_with("http://")) {
+        return Check::new(
+            "mail",
+            CheckStatus::Fail,
+            format!("mail.base_url must be an http(s) url: {}", mail.base_url),
+        );
+    }
+    if mail.base_url.contains("localhost") {
+        let msg = format!(
+            "links in emails point to {}, set mail.base_url to the public url",
+            mail.base_url
+        );
+        return Check::new("mail", CheckStatus::Warn, msg);
+    }
+    Check::new("mail", CheckStatus::Ok, format!("sending as {}", mail.from))
+}
+
+// migrations in this build that were not applied, failed, or were edited after being applied
+async fn check_migrations(pool: &PgPool) -> CheckResult {
+    let name = "migrations";
+    let exists: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(pool)
+        .await
+        .map_err(|e| (name, e))?;
+    if !exists {
+        return Ok(Check::new(
+            name,
+            CheckStatus::Fail,
+            "no migrations applied, run chat_server migrate",
+        ));
+    }
+    let applied: Vec<(i64, Vec<u8>, bool)> = sqlx::query_as("SELECT version, checksum, success FROM _sqlx_migrations")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| (name, e))?;
+    let applied: HashMap<i64, (Vec<u8>, bool)> = applied.into_iter().map(|(v, c, s)| (v, (c, s))).collect();
+    let mut pending = vec![];
+    let mut problems = vec![];
+    for migration in MIGRATOR.iter().filter(|m| m.migration_type.is_up_migration()) {
+        match applied.get(&migration.version) {
+            None => pending.push(migration.version.to_string()),
+            Some((_, false)) => problems.push(format!("{} failed", migration.version)),
+            Some((checksum, _)) if checksum[..] != migration.checksum[..] => {
+                problems.push(format!("{} was changed after it was applied", migration.version))
+            }
+            Some(_) => {}
+        }
+    }
+    if !problems.is_empty() {
+        return Ok(Check::new(
+            name,
+            CheckStatus::Fail,
+            format!("{}, fix the database by hand", problems.join(", ")),
+        ));
+    }
+    if !pending.is_empty() {
+        let msg = format!(
+            "{} pending: {}, run chat_server migrate",
+            pending.len(),
+            pending.join(", ")
+        );
+        return Ok(Check::new(name, CheckStatus::Fail, msg));
+    }
+    Ok(Check::new(name, CheckStatus::Ok, format!("{} applied", applied.len())))
+}
+
+async fn check_object_storage(http: &HttpClient, url: &str) -> Check {
+    let name = "object_storage";
+    match http.send(http.head(url).timeout(CONNECT_TIMEOUT)).await {
+        Ok(res) if !res.status().is_server_error() => Check::new(name, CheckStatus::Ok, "reachable"),
+        Ok(res) => Check::new(name, CheckStatus::Fail, format!("{} answered {}", redact(url), res.status())),
+        Err(e) => Check::new(name, CheckStatus::Fail, format!("cannot reach {}: {}", redact(url), e)),
+    }
+}
+
+async fn check_privileges(pool: &PgPool) -> CheckResult {
+    let name = "privileges";
+    // has_table_privilege is true if any of a list of privileges is held, so each is checked
+    let missing: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT t.tablename::text FROM pg_tables t
+        WHERE t.schemaname = 'public' AND EXISTS (
+            SELECT 1 FROM unnest($1::text[]) p WHERE NOT has_table_privilege(quote_ident(t.tablename), p)
+        )
+        ORDER BY 1
+        "#,
+    )
+    .bind(TABLE_PRIVILEGES)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| (name, e))?;
+    if !missing.is_empty() {
+        let privileges = TABLE_PRIVILEGES.join(", ");
+        let msg = format!(
+            "missing privileges on {}, GRANT {} ON ALL TABLES IN SCHEMA public TO the database user",
+            missing.join(", "),
+            privileges
+        );
+        return Ok(Check::new(name, CheckStatus::Fail, msg));
+    }
+    // a read only replica accepts all of the above and then rejects every write
+    let read_only: bool =
+        sqlx::query_scalar("SELECT pg_is_in_recovery() OR current_setting('transaction_read_only') = 'on'")
+            .fetch_one(pool)
+            .await
+            .map_err(|e| (name, e))?;
+    if read_only {
+        return Ok(Check::new(
+            name,
+            CheckStatus::Fail,
+            "the database is read only, point server.db_url at the primary",
+        ));
+    }
+    Ok(Check::new(name, CheckStatus::Ok, "tables are readable and writable"))
+}
+
+// compared to the database's clock, the one other clock we can reach
+async fn check_clock(pool: &PgPool) -> CheckResult {
+    let name = "clock";
+    let before = Utc::now();
+    let db_now: DateTime<Utc> = sqlx::query_scalar("SELECT NOW()")
+        .fetch_one(pool)
+        .await
+        .map_err(|e| (name, e))?;
+    let local = before + (Utc::now() - before) / 2;
+    Ok(clock_check(local, db_now))
+}
+
+fn clock_check(local: DateTime<Utc>, reference: DateTime<Utc>) -> Check {
+    let skew = (local - reference).num_seconds();
+    let msg = format!("{}s off from the database clock", skew);
+    match skew.abs() {
+        s if s > CLOCK_SKEW_FAIL_SECS => {
+            Check::new("clock", CheckStatus::Fail, format!("{}, sync the clocks with ntp", msg))
+        }
+        s if s > CLOCK_SKEW_WARN_SECS => {
+            Check::new("clock", CheckStatus::Warn, format!("{}, consider running ntp", msg))
+        }
+        _ => Check::new("clock", CheckStatus::Ok, msg),
+    }
+}
+
+// the smtp servers of workspaces that send through their own, emails to them queue up otherwise
+async fn check_smtp(pool: &PgPool) -> CheckResult {
+    let name = "smtp";
+    let servers: Vec<(String, i32)> = sqlx::query_as(
+        "SELECT DISTINCT smtp_host, smtp_port FROM workspace_mail WHERE verified_at IS NOT NULL ORDER BY 1, 2",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| (name, e))?;
+    if servers.is_empty() {
+        return Ok(Check::new(
+            name,
+            CheckStatus::Ok,
+            "no workspace smtp servers configured",
+        ));
+    }
+    let mut unreachable = vec![];
+    for (host, port) in &servers {
+        let addr = format!("{}:{}", host, port);
+        match timeout(CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => unreachable.push(format!("{} ({})", addr, e)),
+            Err(_) => unreachable.push(format!("{} (timed out)", addr)),
+        }
+    }
+    if !unreachable.is_empty() {
+        let msg = format!(
+            "cannot reach {}, check the firewall or the workspaces' mail settings",
+            unreachable.join(", ")
+        );
+        return Ok(Check::new(name, CheckStatus::Warn, msg));
+    }
+    Ok(Check::new(
+        name,
+        CheckStatus::Ok,
+        format!("{} servers reachable", servers.len()),
+    ))
+}
+
+// the password is left out of reports
+pub(crate) fn redact(url: &str) -> String {
+    match (url.find("://"), url.rfind('@')) {
+        (Some(scheme), Some(at)) if at > scheme => {
+            let userinfo = &url[scheme + 3..at];
+            let user = userinfo.split(':').next().unwrap_or_default();
+            format!("{}{}:***{}", &url[..scheme + 3], user, &url[at..])
+        }
+        _ => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+    use sqlx_db_tester::TestPg;
+
+    #[test]
+    fn clock_check_should_grade_skew() {
+        let now = Utc::now();
+        assert_eq!(clock_check(now, now).status, CheckStatus::Ok);
+        assert_eq!(
+            clock_check(now + chrono::Duration::seconds(10), now).status,
+            CheckStatus::Warn
+        );
+        assert_eq!(
+            clock_check(now, now + chrono::Duration::seconds(120)).status,
+            CheckStatus::Fail
+        );
+    }
+
+    #[test]
+    fn summary_should_leave_out_messages() {
+        let report = DoctorReport {
+            checks: vec![
+                Check::new("database", CheckStatus::Fail, "connection refused by 10.0.0.5:5432"),
+                Check::new("object_storage", CheckStatus::Ok, "reachable"),
+            ],
+        };
+        let summary = report.summary();
+        assert_eq!(summary.checks[0], Check::new("database", CheckStatus::Fail, "unavailable"));
+        assert_eq!(summary.checks[1], Check::new("object_storage", CheckStatus::Ok, "ok"));
+        assert!(!summary.is_healthy());
+    }
+
+    #[test]
+    fn redact_should_hide_password() {
+        assert_eq!(
+            redact("postgres://postgres:secret@db:5432/chat"),
+            "postgres://postgres:***@db:5432/chat"
+        );
+        assert_eq!(redact("postgres://db/chat"), "postgres://db/chat");
+    }
+
+    #[tokio::test]
+    async fn doctor_should_report_migration_state() -> Result<()> {
+        let (tdb, pool): (TestPg, _) = get_test_pool(None).await;
+        let mut config = AppConfig::load()?;
+        config.server.db_url = tdb.url();
+        let report = DoctorReport::run(&config).await;
+        assert!(report.is_healthy(), "{}", report);
+        assert_eq!(
+            report.checks.iter().map(|c| c.name).collect::<Vec<_>>(),
+            ["keys", "mail", "database", "migrations", "privileges", "clock", "smtp"]
+        );
+
+        let latest = MIGRATOR.iter().map(|m| m.version).max().expect("migrations");
+        sqlx::query("DELETE FROM _sqlx_migrations WHERE version = $1")
+            .bind(latest)
+            .execute(&pool)
+            .await?;
+        let report = DoctorReport::run(&config).await;
+        let check = report
+            .checks
+            .iter()
+            .find(|c| c.name == "migrations")
+            .expect("migrations check");
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.message.contains(&latest.to_string()), "{}", check.message);
+        assert!(!report.is_healthy());
+
+        config.auth.pk = config.auth.pk.replace("fM+l", "fM+m");
+        let report = DoctorReport::run(&config).await;
+        assert_eq!(report.checks[0].status, CheckStatus::Fail);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn readiness_should_fail_on_pending_migrations() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let report = DoctorReport::readiness(&state).await;
+        assert!(report.is_healthy(), "{}", report);
+        assert_eq!(report.checks.iter().map(|c| c.name).collect::<Vec<_>>(), ["database", "migrations"]);
+
+        let latest = MIGRATOR.iter().map(|m| m.version).max().expect("migrations");
+        sqlx::query("DELETE FROM _sqlx_migrations WHERE version = $1")
+            .bind(latest)
+            .execute(&state.pool)
+            .await?;
+        assert!(!DoctorReport::readiness(&state).await.is_healthy());
+        Ok(())
+    }
+}
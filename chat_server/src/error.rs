@@ -1,11 +1,7 @@
-use axum::{http::{Response, StatusCode}, response::IntoResponse, Json};
-use serde::{Deserialize, Serialize};
+use axum::{http::{header::RETRY_AFTER, HeaderValue, Response, StatusCode}, response::IntoResponse, Json};
 use thiserror::Error;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct ErrorOutput {
-    pub error: String,
-}
+pub use chat_core::ErrorOutput;
 
 #[derive(Error, Debug)]
 pub enum AppError {
@@ -13,8 +9,47 @@ pub enum AppError {
     EmailAlreadyExists(String),
     #[error("create chat error: {0}")]
     CreateChatError(String),
+    #[error("workspace name already taken: {0}")]
+    WorkspaceNameTaken(String),
+    #[error("update workspace error: {0}")]
+    UpdateWorkspaceError(String),
+    #[error("chat name already taken: {0}")]
+    ChatNameTaken(String),
+    #[error("update chat error: {0}")]
+    UpdateChatError(String),
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("create message error: {0}")]
+    CreateMessageError(String),
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("weak password: {0}")]
+    WeakPassword(String),
+    // the client address, outside the workspace's ip allowlist
+    #[error("ip_not_allowed: {0} is not in the workspace ip allowlist")]
+    IpNotAllowed(String),
     #[error("Not found: {0}")]
     NotFound(String),
+    // a directory or identity provider credentials are checked against
+    #[error("auth backend unavailable: {0}")]
+    AuthBackendUnavailable(String),
+    // seconds until the next signin, or email an admin asks for, is accepted
+    #[error("too many attempts, retry in {0}s")]
+    TooManyAttempts(u64),
+    // the route's limit in seconds
+    #[error("request timed out after {0}s")]
+    RequestTimeout(u64),
+    // the route's limit in bytes
+    #[error("request body is larger than {0} bytes")]
+    PayloadTooLarge(usize),
+    #[error("server is overloaded, retry later")]
+    Overloaded,
+    // the requested version, with the supported ones
+    #[error("unsupported api version: {0}")]
+    UnsupportedApiVersion(String),
+    // the content type that was sent
+    #[error("unsupported media type: {0}")]
+    UnsupportedMediaType(String),
     #[error("sql error: {0}")]
     SqlxError(#[from] sqlx::Error),
     #[error("password hash error: {0}")]
@@ -25,14 +60,6 @@ pub enum AppError {
     HttpHeaderError(#[from] axum::http::header::InvalidHeaderValue),
 }
 
-impl ErrorOutput {
-    pub fn new(error: impl Into<String>) -> Self {
-        Self {
-            error: error.into(),
-        }
-    }
-}
-
 impl IntoResponse for AppError {
     fn into_response(self) -> Response<axum::body::Body> {
         let status = match &self {
@@ -42,8 +69,34 @@ impl IntoResponse for AppError {
             Self::HttpHeaderError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             Self::EmailAlreadyExists(_) => StatusCode::CONFLICT,
             Self::CreateChatError(_) => StatusCode::BAD_REQUEST,
+            Self::WorkspaceNameTaken(_) => StatusCode::CONFLICT,
+            Self::UpdateWorkspaceError(_) => StatusCode::BAD_REQUEST,
+            Self::ChatNameTaken(_) => StatusCode::CONFLICT,
+            Self::UpdateChatError(_) => StatusCode::BAD_REQUEST,
+            Self::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            Self::CreateMessageError(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            Self::WeakPassword(_) => StatusCode::BAD_REQUEST,
+            Self::IpNotAllowed(_) => StatusCode::FORBIDDEN,
             Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::AuthBackendUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::RequestTimeout(_) => StatusCode::REQUEST_TIMEOUT,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            Self::UnsupportedApiVersion(_) => StatusCode::NOT_ACCEPTABLE,
+            Self::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
         };
-        (status, Json(ErrorOutput::new(self.to_string()))).into_response()
+        let mut res = (status, Json(ErrorOutput::new(self.to_string()))).into_response();
+        match self {
+            Self::TooManyAttempts(secs) => {
+                res.headers_mut().insert(RETRY_AFTER, HeaderValue::from(secs));
+            }
+            Self::Overloaded => {
+                res.headers_mut().insert(RETRY_AFTER, HeaderValue::from(1));
+            }
+            _ => {}
+        }
+        res
     }
 }
@@ -0,0 +1,284 @@
+use std::time::Duration;
+
+use anyhow::bail;
+use futures::StreamExt;
+use redis::aio::MultiplexedConnection;
+use sqlx::{postgres::PgListener, types::Json, Postgres, Transaction};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+pub use chat_core::{AppEvent, EventBatch, EventFrame, PollEvents, PROTOCOL_VERSION};
+
+use crate::{
+    config::{EventBackend, EventBusConfig},
+    sync, AppError, AppState,
+};
+
+pub(crate) const EVENTS_CHANNEL: &str = "chat_events";
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const OUTBOX_POLL_INTERVAL: Duration = Duration::from_millis(200);
+const OUTBOX_BATCH: usize = 100;
+const LOG_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// pg_try_advisory_xact_lock key held by the instance publishing the outbox
+const OUTBOX_LOCK_KEY: i64 = 0x6f7574626f78;
+
+// where publish_outbox sends events and listen receives them, on every instance
+#[derive(Debug, Clone)]
+pub(crate) enum EventBus {
+    Postgres,
+    Redis(redis::Client),
+}
+
+impl EventBus {
+    pub(crate) fn new(config: &EventBusConfig) -> Result<Self, redis::RedisError> {
+        match config.backend {
+            EventBackend::Postgres => Ok(Self::Postgres),
+            EventBackend::Redis => Ok(Self::Redis(redis::Client::open(config.redis_url.as_deref().unwrap_or_default())?)),
+        }
+    }
+}
+
+// queues the event in the outbox, it is published only if the transaction commits
+pub(crate) async fn record(tx: &mut Transaction<'_, Postgres>, event: &AppEvent) -> Result<(), AppError> {
+    sqlx::query("INSERT INTO event_outbox (event) VALUES ($1)")
+        .bind(Json(EventFrame::from(event.clone())))
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+// publishes queued events to every instance's listener until stop is cancelled
+pub(crate) async fn relay_outbox(state: AppState, stop: CancellationToken) {
+    // kept between batches, dropped after a failure
+    let mut redis = None;
+    loop {
+        let delay = match publish_outbox(&state, &mut redis).await {
+            // more may be waiting
+            Ok(n) if n == OUTBOX_BATCH => continue,
+            Ok(_) => OUTBOX_POLL_INTERVAL,
+            Err(e) => {
+                warn!("publish outbox failed: {}", e);
+                RECONNECT_DELAY
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stop.cancelled() => return,
+        }
+    }
+}
+
+// at least once, events are removed from the outbox in the transaction that publishes them
+async fn publish_outbox(state: &AppState, redis: &mut Option<MultiplexedConnection>) -> anyhow::Result<usize> {
+    let mut tx = state.pool.begin().await?;
+    // one relay at a time across instances, so events go out in the order they were queued
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock($1)")
+        .bind(OUTBOX_LOCK_KEY)
+        .fetch_one(&mut *tx)
+        .await?;
+    if !locked {
+        return Ok(0);
+    }
+    sync::stamp(&mut tx).await?;
+    let mut events: Vec<(i64, String)> = sqlx::query_as(
+        r#"
+        DELETE FROM event_outbox
+        WHERE id IN (SELECT id FROM event_outbox ORDER BY id LIMIT $1)
+        RETURNING id, event::text
+        "#,
+    )
+    .bind(OUTBOX_BATCH as i64)
+    .fetch_all(&mut *tx)
+    .await?;
+    if events.is_empty() {
+        tx.commit().await?;
+        return Ok(0);
+    }
+    events.sort_unstable_by_key(|(id, _)| *id);
+    // in publish order, one relay at a time so a poller never sees a later id before an earlier one
+    let payloads: Vec<&str> = events.iter().map(|(_, event)| event.as_str()).collect();
+    sqlx::query(
+        r#"
+        INSERT INTO event_log (ws_id, chat_id, event)
+        SELECT (e->>'ws_id')::bigint, (e->>'chat_id')::bigint, e
+        FROM unnest($1::jsonb[]) WITH ORDINALITY AS t(e, n)
+        WHERE e ? 'ws_id'
+        ORDER BY n
+        "#,
+    )
+    .bind(&payloads)
+    .execute(&mut *tx)
+    .await?;
+    match &state.bus {
+        EventBus::Postgres => {
+            for (_, event) in &events {
+                sqlx::query("SELECT pg_notify($1, $2)")
+                    .bind(EVENTS_CHANNEL)
+                    .bind(event)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        // sent before the commit, if that fails they go out again rather than not at all
+        EventBus::Redis(client) => {
+            let conn = match redis {
+                Some(conn) => conn,
+                None => redis.insert(client.get_multiplexed_async_connection().await?),
+            };
+            let mut pipe = redis::pipe();
+            for (_, event) in &events {
+                pipe.publish(EVENTS_CHANNEL, event).ignore();
+            }
+            if let Err(e) = pipe.query_async::<()>(conn).await {
+                *redis = None;
+                return Err(e.into());
+            }
+        }
+    }
+    tx.commit().await?;
+    Ok(events.len())
+}
+
+// relays notifications from every instance, this one included, to AppState::subscribe_events
+// until stop is cancelled. Events sent while the connection is down are missed.
+pub(crate) async fn listen(state: AppState, stop: CancellationToken) {
+    loop {
+        tokio::select! {
+            ret = relay(&state) => {
+                if let Err(e) = ret {
+                    warn!("event listener failed: {}, reconnecting", e);
+                }
+            }
+            _ = stop.cancelled() => return,
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = stop.cancelled() => return,
+        }
+    }
+}
+
+// drops logged events older than events.log_retention_hours and sync changes older than
+// events.sync_retention_days, hourly until stop is cancelled
+pub(crate) async fn prune_log(state: AppState, stop: CancellationToken) {
+    loop {
+        let hours = state.config.events.log_retention_hours.min(i32::MAX as u64) as i32;
+        let ret = sqlx::query("DELETE FROM event_log WHERE created_at < now() - make_interval(hours => $1)")
+            .bind(hours)
+            .execute(&state.pool)
+            .await;
+        if let Err(e) = ret {
+            warn!("prune event log failed: {}", e);
+        }
+        let days = state.config.events.sync_retention_days.min(i32::MAX as u64) as i32;
+        let ret = sqlx::query("DELETE FROM sync_changes WHERE created_at < now() - make_interval(days => $1)")
+            .bind(days)
+            .execute(&state.pool)
+            .await;
+        if let Err(e) = ret {
+            warn!("prune sync changes failed: {}", e);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(LOG_PRUNE_INTERVAL) => {}
+            _ = stop.cancelled() => return,
+        }
+    }
+}
+
+// a new chat changes the workspace's chat count, no matter which instance cached its stats
+pub(crate) async fn invalidate_stats(state: AppState, stop: CancellationToken) {
+    let mut events = state.subscribe_events();
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = stop.cancelled() => return,
+        };
+        let cache = &state.stats_cache;
+        match event {
+            Ok(AppEvent::ChatCreated { ws_id, .. }) => {
+                cache.write().expect("stats cache poisoned").remove(&ws_id);
+            }
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(_)) => cache.write().expect("stats cache poisoned").clear(),
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn relay(state: &AppState) -> anyhow::Result<()> {
+    match &state.bus {
+        EventBus::Postgres => {
+            let mut listener = PgListener::connect_with(&state.pool).await?;
+            listener.listen(EVENTS_CHANNEL).await?;
+            loop {
+                let notification = listener.recv().await?;
+                forward(state, notification.payload());
+            }
+        }
+        EventBus::Redis(client) => {
+            let mut pubsub = client.get_async_pubsub().await?;
+            pubsub.subscribe(EVENTS_CHANNEL).await?;
+            let mut messages = pubsub.on_message();
+            while let Some(msg) = messages.next().await {
+                forward(state, &msg.get_payload::<String>()?);
+            }
+            bail!("redis closed the subscription")
+        }
+    }
+}
+
+fn forward(state: &AppState, payload: &str) {
+    match serde_json::from_str::<EventFrame>(payload) {
+        // from an instance running a newer version, during a rolling deploy
+        Ok(frame) if frame.version > PROTOCOL_VERSION || frame.event == AppEvent::Unknown => {
+            warn!("ignore event this version can't read: {}", payload)
+        }
+        // fails only when nobody is subscribed
+        Ok(frame) => {
+            let _ = state.events.send(frame.event);
+        }
+        Err(e) => warn!("ignore malformed event {}: {}", payload, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppConfig, CreateMessage, Message};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn listener_should_relay_committed_events() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let mut events = state.subscribe_events();
+        let stop = CancellationToken::new();
+        tokio::spawn(listen(state.clone(), stop.clone()));
+        tokio::spawn(relay_outbox(state.clone(), stop.clone()));
+        // give the listener time to LISTEN
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let msg = Message::create(&CreateMessage::new("hello"), 1, 1, &state.pool).await?;
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+        assert_eq!(
+            event,
+            AppEvent::NewMessage { ws_id: 1, chat_id: 1, message_id: msg.id, sender_id: 1, seq: msg.seq }
+        );
+        stop.cancel();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outbox_should_keep_events_until_published() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let mut tx = state.pool.begin().await?;
+        record(&mut tx, &AppEvent::ChatUpdated { ws_id: 1, chat_id: 1 }).await?;
+        tx.rollback().await?;
+        Message::create(&CreateMessage::new("hello"), 1, 1, &state.pool).await?;
+        Message::create(&CreateMessage::new("again"), 1, 1, &state.pool).await?;
+
+        assert_eq!(publish_outbox(&state, &mut None).await?, 2);
+        assert_eq!(publish_outbox(&state, &mut None).await?, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FeatureFlag {
+    #[serde(default)]
+    pub enabled: bool,
+    // percentage of users (0-100) the flag is on for once enabled
+    #[serde(default = "full_rollout")]
+    pub rollout: u8,
+    // always on for these users regardless of rollout, e.g. internal testers
+    #[serde(default)]
+    pub users: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FeatureFlags(HashMap<String, FeatureFlag>);
+
+fn full_rollout() -> u8 {
+    100
+}
+
+impl FeatureFlag {
+    pub fn is_enabled_for(&self, name: &str, user_id: i64) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if self.users.contains(&user_id) {
+            return true;
+        }
+        bucket(name, user_id) < self.rollout.min(100)
+    }
+}
+
+impl FeatureFlags {
+    pub fn is_enabled(&self, name: &str, user_id: i64) -> bool {
+        self.0
+            .get(name)
+            .is_some_and(|flag| flag.is_enabled_for(name, user_id))
+    }
+
+    pub fn enabled_for(&self, user_id: i64) -> Vec<&str> {
+        let mut names: Vec<_> = self
+            .0
+            .iter()
+            .filter(|(name, flag)| flag.is_enabled_for(name, user_id))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+// stable bucket in 0..100 for a user, salted with the flag name so that the same 5% of
+// users are not the guinea pigs for every flag. FNV-1a keeps it identical across builds.
+fn bucket(name: &str, user_id: i64) -> u8 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in name.as_bytes().iter().chain(b":").chain(&user_id.to_le_bytes()) {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    (hash % 100) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flag(enabled: bool, rollout: u8, users: &[i64]) -> FeatureFlag {
+        FeatureFlag {
+            enabled,
+            rollout,
+            users: users.to_vec(),
+        }
+    }
+
+    #[test]
+    fn bucket_should_be_stable_and_in_range() {
+        for id in 0..1000 {
+            let b = bucket("new_pagination", id);
+            assert!(b < 100);
+            assert_eq!(b, bucket("new_pagination", id));
+        }
+    }
+
+    #[test]
+    fn rollout_should_enable_roughly_the_given_percentage() {
+        let f = flag(true, 5, &[]);
+        let enabled = (0..10_000).filter(|id| f.is_enabled_for("new_realtime", *id)).count();
+        assert!((350..650).contains(&enabled), "enabled for {} users", enabled);
+    }
+
+    #[test]
+    fn feature_flags_should_respect_enabled_and_allowlist() {
+        let flags = FeatureFlags(HashMap::from([
+            ("off".to_string(), flag(false, 100, &[1])),
+            ("all".to_string(), flag(true, 100, &[])),
+            ("none".to_string(), flag(true, 0, &[7])),
+        ]));
+        assert!(!flags.is_enabled("off", 1));
+        assert!(flags.is_enabled("all", 42));
+        assert!(!flags.is_enabled("none", 42));
+        assert!(flags.is_enabled("none", 7));
+        assert!(!flags.is_enabled("missing", 7));
+        assert_eq!(flags.enabled_for(7), vec!["all", "none"]);
+    }
+}
@@ -0,0 +1,208 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::{AppError, Chat, FeedEntry};
+
+// longer first lines are cut in entry titles
+const TITLE_CHARS: usize = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FeedFormat {
+    Atom,
+    Rss,
+}
+
+impl FeedFormat {
+    // the chat id and format of a feed file name, e.g. 1.atom or 1.rss
+    pub(crate) fn parse(file: &str) -> Result<(u64, Self), AppError> {
+        let not_found = || AppError::NotFound(format!("feed not found: {}", file));
+        let (id, ext) = file.rsplit_once('.').ok_or_else(not_found)?;
+        let format = match ext {
+            "atom" => Self::Atom,
+            "rss" => Self::Rss,
+            _ => return Err(not_found()),
+        };
+        Ok((id.parse().map_err(|_| not_found())?, format))
+    }
+
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Self::Atom => "application/atom+xml; charset=utf-8",
+            Self::Rss => "application/rss+xml; charset=utf-8",
+        }
+    }
+
+    // chat_url is the chat in the web app, self_url the feed without its token
+    pub(crate) fn render(self, chat: &Chat, entries: &[FeedEntry], chat_url: &str, self_url: &str) -> String {
+        match self {
+            Self::Atom => atom(chat, entries, chat_url, self_url),
+            Self::Rss => rss(chat, entries, chat_url, self_url),
+        }
+    }
+}
+
+fn atom(chat: &Chat, entries: &[FeedEntry], chat_url: &str, self_url: &str) -> String {
+    let updated = entries.first().map(|e| e.created_at).unwrap_or(chat.created_at);
+    let mut xml = format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+            "<feed xmlns=\"http://www.w3.org/2005/Atom\">\n",
+            "  <title>{}</title>\n",
+            "  <id>{}</id>\n",
+            "  <link rel=\"alternate\" href=\"{}\"/>\n",
+            "  <link rel=\"self\" href=\"{}\"/>\n",
+            "  <updated>{}</updated>\n",
+        ),
+        escape(&title(chat)),
+        escape(chat_url),
+        escape(chat_url),
+        escape(self_url),
+        rfc3339(updated),
+    );
+    for entry in entries {
+        let link = format!("{}?message={}", chat_url, entry.id);
+        xml.push_str(&format!(
+            concat!(
+                "  <entry>\n",
+                "    <title>{}</title>\n",
+                "    <id>{}</id>\n",
+                "    <link href=\"{}\"/>\n",
+                "    <author><name>{}</name></author>\n",
+                "    <updated>{}</updated>\n",
+                "    <content type=\"text\">{}</content>\n",
+                "  </entry>\n",
+            ),
+            escape(&entry_title(entry)),
+            escape(&link),
+            escape(&link),
+            escape(&entry.author),
+            rfc3339(entry.created_at),
+            escape(&entry.content),
+        ));
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn rss(chat: &Chat, entries: &[FeedEntry], chat_url: &str, self_url: &str) -> String {
+    let updated = entries.first().map(|e| e.created_at).unwrap_or(chat.created_at);
+    let mut xml = format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+            "<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n",
+            "<channel>\n",
+            "  <title>{}</title>\n",
+            "  <link>{}</link>\n",
+            "  <description>{}</description>\n",
+            "  <atom:link rel=\"self\" type=\"application/rss+xml\" href=\"{}\"/>\n",
+            "  <lastBuildDate>{}</lastBuildDate>\n",
+        ),
+        escape(&title(chat)),
+        escape(chat_url),
+        escape(&format!("Messages in {}", title(chat))),
+        escape(self_url),
+        updated.to_rfc2822(),
+    );
+    for entry in entries {
+        let link = format!("{}?message={}", chat_url, entry.id);
+        xml.push_str(&format!(
+            concat!(
+                "  <item>\n",
+                "    <title>{}</title>\n",
+                "    <link>{}</link>\n",
+                "    <guid isPermaLink=\"false\">{}</guid>\n",
+                "    <pubDate>{}</pubDate>\n",
+                "    <description>{}</description>\n",
+                "  </item>\n",
+            ),
+            escape(&entry_title(entry)),
+            escape(&link),
+            escape(&link),
+            entry.created_at.to_rfc2822(),
+            escape(&entry.content),
+        ));
+    }
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+fn title(chat: &Chat) -> String {
+    match &chat.name {
+        Some(name) => format!("#{}", name),
+        None => format!("chat {}", chat.id),
+    }
+}
+
+// the author and the first line of the message
+fn entry_title(entry: &FeedEntry) -> String {
+    let line = entry.content.lines().next().unwrap_or_default();
+    let mut title: String = line.chars().take(TITLE_CHARS).collect();
+    if line.chars().count() > TITLE_CHARS {
+        title.push('…');
+    }
+    format!("{}: {}", entry.author, title)
+}
+
+fn rfc3339(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// for both text and attribute values, and without the characters xml 1.0 doesn't allow
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if (c as u32) < 0x20 => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChatType;
+
+    fn chat() -> Chat {
+        Chat {
+            id: 1,
+            ws_id: 1,
+            name: Some("announcements".to_string()),
+            r#type: ChatType::PublicChannel,
+            members: vec![1, 2],
+            owner_id: 1,
+            discoverable: true,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn parse_should_take_atom_and_rss() {
+        assert_eq!(FeedFormat::parse("12.atom").unwrap(), (12, FeedFormat::Atom));
+        assert_eq!(FeedFormat::parse("12.rss").unwrap(), (12, FeedFormat::Rss));
+        assert!(FeedFormat::parse("12.json").is_err());
+        assert!(FeedFormat::parse("abc.atom").is_err());
+    }
+
+    #[test]
+    fn render_should_escape_content() {
+        let entries = vec![FeedEntry {
+            id: 7,
+            author: "Tyr".to_string(),
+            content: "ship <b>it</b> & \u{1}go".to_string(),
+            created_at: Utc::now(),
+        }];
+        let xml = FeedFormat::Atom.render(&chat(), &entries, "https://chat.acme.org/chats/1", "https://chat.acme.org/feeds/chat/1.atom");
+        assert!(xml.contains("<title>#announcements</title>"));
+        assert!(xml.contains("<content type=\"text\">ship &lt;b&gt;it&lt;/b&gt; &amp; go</content>"));
+        assert!(xml.contains("<id>https://chat.acme.org/chats/1?message=7</id>"));
+        let xml = FeedFormat::Rss.render(&chat(), &entries, "https://chat.acme.org/chats/1", "https://chat.acme.org/feeds/chat/1.rss");
+        assert!(xml.contains("<guid isPermaLink=\"false\">https://chat.acme.org/chats/1?message=7</guid>"));
+    }
+}
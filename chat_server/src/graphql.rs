@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+
+use async_graphql::{
+    dataloader::{DataLoader, Loader},
+    futures_util::Stream,
+    Context, EmptyMutation, Enum, Object, Result, Schema, Subscription,
+};
+use axum::{
+    extract::State,
+    http::{header::ACCEPT, HeaderMap},
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Response, Sse,
+    },
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use sqlx::PgPool;
+
+use crate::{
+    AppError, AppEvent, AppState, Chat, ChatRead, ChatUser, ListMessages, Message, RequireScope, User, Workspace,
+};
+
+// deep or wide queries are refused before they reach the database
+const MAX_DEPTH: usize = 10;
+const MAX_COMPLEXITY: usize = 1000;
+
+pub(crate) type ChatSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+// built once, the caller and the loaders are added to every request
+pub(crate) fn schema() -> ChatSchema {
+    Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+// POST /api/graphql. When the client accepts server sent events, results are streamed as
+// `next` events followed by `complete`, graphql-sse's distinct connections mode; that's how
+// subscriptions are served.
+pub(crate) async fn graphql_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<async_graphql::Request>,
+) -> Response {
+    let pool = state.pool.clone();
+    let request = request
+        .data(user)
+        .data(state.clone())
+        .data(DataLoader::new(UserLoader(pool.clone()), tokio::spawn))
+        .data(DataLoader::new(ChatLoader(pool), tokio::spawn));
+    if !accepts_event_stream(&headers) {
+        return Json(state.graphql.execute(request).await).into_response();
+    }
+    let events = state
+        .graphql
+        .execute_stream(request)
+        .map(|res| Event::default().event("next").json_data(res))
+        .chain(stream::once(async { Ok(Event::default().event("complete").data("")) }));
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+fn accepts_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/event-stream"))
+}
+
+// batches the lookups made while resolving one request, e.g. every message's sender
+struct UserLoader(PgPool);
+
+impl Loader<i64> for UserLoader {
+    type Value = ChatUser;
+    type Error = async_graphql::Error;
+
+    async fn load(&self, ids: &[i64]) -> Result<HashMap<i64, ChatUser>> {
+        let users = ChatUser::fetch_by_ids(ids, &self.0).await?;
+        Ok(users.into_iter().map(|u| (u.id, u)).collect())
+    }
+}
+
+struct ChatLoader(PgPool);
+
+impl Loader<i64> for ChatLoader {
+    type Value = Chat;
+    type Error = async_graphql::Error;
+
+    async fn load(&self, ids: &[i64]) -> Result<HashMap<i64, Chat>> {
+        let chats = Chat::fetch_by_ids(ids, &self.0).await?;
+        Ok(chats.into_iter().map(|c| (c.id, c)).collect())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[graphql(name = "ChatType", remote = "crate::ChatType")]
+enum GqlChatType {
+    Single,
+    Group,
+    PrivateChannel,
+    PublicChannel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[graphql(name = "MessageKind", remote = "crate::MessageKind")]
+enum GqlMessageKind {
+    User,
+    System,
+    Integration,
+    Bot,
+}
+
+struct UserObject(ChatUser);
+
+#[Object(name = "User")]
+impl UserObject {
+    async fn id(&self) -> i64 {
+        self.0.id
+    }
+
+    async fn fullname(&self) -> &str {
+        &self.0.fullname
+    }
+
+    async fn email(&self) -> &str {
+        &self.0.email
+    }
+
+    async fn avatar_url(&self) -> Option<&str> {
+        self.0.avatar_url.as_deref()
+    }
+}
+
+struct WorkspaceObject(Workspace);
+
+#[Object(name = "Workspace")]
+impl WorkspaceObject {
+    async fn id(&self) -> i64 {
+        self.0.id
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn slug(&self) -> &str {
+        &self.0.slug
+    }
+
+    async fn created_at(&self) -> DateTime<Utc> {
+        self.0.created_at
+    }
+
+    async fn owner(&self, ctx: &Context<'_>) -> Result<Option<UserObject>> {
+        load_user(ctx, self.0.owner_id).await
+    }
+}
+
+struct ChatObject(Chat);
+
+#[Object(name = "Chat")]
+impl ChatObject {
+    async fn id(&self) -> i64 {
+        self.0.id
+    }
+
+    async fn name(&self) -> Option<&str> {
+        self.0.name.as_deref()
+    }
+
+    async fn r#type(&self) -> GqlChatType {
+        self.0.r#type.clone().into()
+    }
+
+    async fn discoverable(&self) -> bool {
+        self.0.discoverable
+    }
+
+    async fn created_at(&self) -> DateTime<Utc> {
+        self.0.created_at
+    }
+
+    async fn owner(&self, ctx: &Context<'_>) -> Result<Option<UserObject>> {
+        load_user(ctx, self.0.owner_id).await
+    }
+
+    async fn members(&self, ctx: &Context<'_>) -> Result<Vec<UserObject>> {
+        let mut users = ctx.data::<DataLoader<UserLoader>>()?.load_many(self.0.members.iter().copied()).await?;
+        Ok(self.0.members.iter().filter_map(|id| users.remove(id)).map(UserObject).collect())
+    }
+
+    // newest first, the same as GET /api/chats/{id}/messages; members only
+    async fn messages(
+        &self,
+        ctx: &Context<'_>,
+        last_seq: Option<i64>,
+        #[graphql(default = 20)] limit: u64,
+        thread_id: Option<i64>,
+    ) -> Result<Vec<MessageObject>> {
+        let user = ctx.data::<User>()?;
+        if !self.0.members.contains(&user.id) {
+            return Err(AppError::NotFound(format!("chat not found: {}", self.0.id)).into());
+        }
+        let state = ctx.data::<AppState>()?;
+        let input = ListMessages {
+            last_seq,
+            limit: Some(limit),
+            thread_id,
+            ..Default::default()
+        };
+        let messages = Message::list(&input, self.0.id as _, state.db.reader()).await?;
+        Ok(messages.into_iter().map(MessageObject).collect())
+    }
+}
+
+struct MessageObject(Message);
+
+#[Object(name = "Message")]
+impl MessageObject {
+    async fn id(&self) -> i64 {
+        self.0.id
+    }
+
+    async fn chat_id(&self) -> i64 {
+        self.0.chat_id
+    }
+
+    async fn seq(&self) -> i64 {
+        self.0.seq
+    }
+
+    async fn kind(&self) -> GqlMessageKind {
+        self.0.kind.into()
+    }
+
+    async fn thread_id(&self) -> Option<i64> {
+        self.0.thread_id
+    }
+
+    async fn content(&self) -> &str {
+        &self.0.content
+    }
+
+    async fn images(&self) -> &[String] {
+        &self.0.images
+    }
+
+    async fn created_at(&self) -> DateTime<Utc> {
+        self.0.created_at
+    }
+
+    async fn client_created_at(&self) -> Option<DateTime<Utc>> {
+        self.0.client_created_at
+    }
+
+    async fn display_name(&self) -> Option<&str> {
+        self.0.display_name.as_deref()
+    }
+
+    async fn icon_url(&self) -> Option<&str> {
+        self.0.icon_url.as_deref()
+    }
+
+    async fn sender(&self, ctx: &Context<'_>) -> Result<Option<UserObject>> {
+        load_user(ctx, self.0.sender_id).await
+    }
+
+    async fn chat(&self, ctx: &Context<'_>) -> Result<Option<ChatObject>> {
+        let chat = ctx.data::<DataLoader<ChatLoader>>()?.load_one(self.0.chat_id).await?;
+        Ok(chat.map(ChatObject))
+    }
+}
+
+pub(crate) struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn me(&self, ctx: &Context<'_>) -> Result<UserObject> {
+        let user = ctx.data::<User>()?;
+        Ok(UserObject(ChatUser {
+            id: user.id,
+            fullname: user.fullname.clone(),
+            email: user.email.clone(),
+            avatar_url: None,
+        }))
+    }
+
+    async fn workspace(&self, ctx: &Context<'_>) -> Result<WorkspaceObject> {
+        let user = ctx.data::<User>()?;
+        let state = ctx.data::<AppState>()?;
+        match Workspace::find_by_id(user.ws_id as _, &state.pool).await? {
+            Some(ws) => Ok(WorkspaceObject(ws)),
+            None => Err(AppError::NotFound(format!("workspace not found: {}", user.ws_id)).into()),
+        }
+    }
+
+    // members of the caller's workspace
+    async fn users(&self, ctx: &Context<'_>) -> Result<Vec<UserObject>> {
+        let user = ctx.data::<User>()?;
+        let state = ctx.data::<AppState>()?;
+        let users = Workspace::fetch_all_chat_users(user.ws_id as _, &state.pool).await?;
+        Ok(users.into_iter().map(UserObject).collect())
+    }
+
+    // the caller's chats, most recently active first
+    async fn chats(&self, ctx: &Context<'_>) -> Result<Vec<ChatObject>> {
+        let user = ctx.data::<User>()?;
+        let state = ctx.data::<AppState>()?;
+        let ids: Vec<i64> = Chat::list_for_member(user.ws_id as _, user.id as _, &state.pool)
+            .await?
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        let mut chats = ctx.data::<DataLoader<ChatLoader>>()?.load_many(ids.iter().copied()).await?;
+        Ok(ids.iter().filter_map(|id| chats.remove(id)).map(ChatObject).collect())
+    }
+
+    // any chat in the caller's workspace, its messages only for members
+    async fn chat(&self, ctx: &Context<'_>, id: i64) -> Result<Option<ChatObject>> {
+        let user = ctx.data::<User>()?;
+        let chat = ctx.data::<DataLoader<ChatLoader>>()?.load_one(id).await?;
+        Ok(chat.filter(|c| c.ws_id == user.ws_id).map(ChatObject))
+    }
+}
+
+pub(crate) struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    // messages posted to the caller's chats from any instance, or only to chat_id
+    async fn new_messages(&self, ctx: &Context<'_>, chat_id: Option<i64>) -> Result<impl Stream<Item = Result<MessageObject>>> {
+        let user = ctx.data::<User>()?;
+        let state = ctx.data::<AppState>()?.clone();
+        let subscription = state.subscribers.subscribe(user.id);
+        Ok(stream::unfold((subscription, state), move |(mut subscription, state)| async move {
+            loop {
+                let AppEvent::NewMessage { chat_id: id, message_id, .. } = subscription.recv().await? else {
+                    continue;
+                };
+                if chat_id.is_some_and(|c| c != id) {
+                    continue;
+                }
+                let ret = match Message::fetch_by_ids(&[message_id], &state.pool).await {
+                    Ok(messages) => match messages.into_iter().next() {
+                        Some(message) => Ok(MessageObject(message)),
+                        // deleted since
+                        None => continue,
+                    },
+                    Err(e) => Err(e.into()),
+                };
+                return Some((ret, (subscription, state)));
+            }
+        }))
+    }
+}
+
+async fn load_user(ctx: &Context<'_>, id: i64) -> Result<Option<UserObject>> {
+    let user = ctx.data::<DataLoader<UserLoader>>()?.load_one(id).await?;
+    Ok(user.map(UserObject))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{realtime, AppConfig, CreateMessage};
+    use anyhow::Result;
+    use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+
+    fn request(query: &str, user: User, state: &AppState) -> async_graphql::Request {
+        let pool = state.pool.clone();
+        async_graphql::Request::new(query)
+            .data(user)
+            .data(state.clone())
+            .data(DataLoader::new(UserLoader(pool.clone()), tokio::spawn))
+            .data(DataLoader::new(ChatLoader(pool), tokio::spawn))
+    }
+
+    #[tokio::test]
+    async fn query_should_resolve_chats_with_members_and_messages() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let user = User::find_by_email("tchen@acme.org", &state.pool).await?.expect("user");
+        let query = r#"{
+            me { fullname }
+            workspace { name owner { id } }
+            chats { id type members { fullname } messages(limit: 2) { seq sender { email } chat { id } } }
+        }"#;
+        let res = state.graphql.execute(request(query, user, &state)).await;
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let data = res.data.into_json()?;
+        assert_eq!(data["me"]["fullname"], "Tyr Chen");
+        let chats = data["chats"].as_array().expect("chats");
+        assert_eq!(chats.len(), 4);
+        let general = chats.iter().find(|c| c["id"] == 1).expect("general");
+        assert_eq!(general["type"], "PUBLIC_CHANNEL");
+        assert_eq!(general["members"].as_array().map(Vec::len), Some(5));
+        assert_eq!(general["messages"].as_array().map(Vec::len), Some(2));
+        assert_eq!(general["messages"][0]["chat"]["id"], 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn messages_should_be_for_members_only() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let user = User::find_by_email("daisy@acme.org", &state.pool).await?.expect("user");
+        // daisy isn't in the private channel
+        let res = state.graphql.execute(request("{ chat(id: 2) { name messages { id } } }", user, &state)).await;
+        assert_eq!(res.errors.len(), 1);
+        assert_eq!(res.errors[0].message, "Not found: chat not found: 2");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscription_should_stream_new_messages() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let stop = CancellationToken::new();
+        tokio::spawn(realtime::deliver(state.clone(), stop.clone()));
+        let user = User::find_by_email("tchen@acme.org", &state.pool).await?.expect("user");
+        let query = "subscription { newMessages(chatId: 1) { content sender { fullname } } }";
+        let mut stream = state.graphql.execute_stream(request(query, user, &state));
+        // subscribes on first poll
+        assert!(tokio::time::timeout(Duration::from_millis(100), stream.next()).await.is_err());
+
+        let msg = Message::create(&CreateMessage::new("hello"), 1, 2, &state.pool).await?;
+        let event = AppEvent::NewMessage { ws_id: 1, chat_id: 1, message_id: msg.id, sender_id: 2, seq: msg.seq };
+        state.events.send(event)?;
+        let res = tokio::time::timeout(Duration::from_secs(5), stream.next()).await?.expect("response");
+        let data = res.data.into_json()?;
+        assert_eq!(data["newMessages"]["content"], "hello");
+        stop.cancel();
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use prost_types::Timestamp;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_util::sync::CancellationToken;
+use tonic::{
+    service::Interceptor,
+    transport::{Identity, Server, ServerTlsConfig},
+    Request, Response, Status,
+};
+use tracing::{info, warn};
+
+use crate::{
+    config::{GrpcClient, TlsConfig},
+    utils::constant_time_eq,
+    AppError, AppState, Chat, CreateMessage, ListMessages, Message, User,
+};
+
+pub(crate) mod pb {
+    tonic::include_proto!("chat.v1");
+}
+
+use pb::{
+    chat_service_server::{ChatService, ChatServiceServer},
+    get_user_request::By,
+    GetUserRequest, ListMessagesRequest, ListMessagesResponse, SendMessageRequest,
+};
+
+// trusted callers inside the deployment, each with a token of its own workspace. They act as the
+// user a request names, in that workspace only. Email verification, scopes and ip allowlists
+// are for members and don't apply.
+struct ChatGrpc {
+    state: AppState,
+}
+
+// the workspace the caller's token belongs to, set by Authorize
+#[derive(Debug, Clone, Copy)]
+struct Caller {
+    ws_id: i64,
+}
+
+impl ChatGrpc {
+    async fn chat_of(&self, caller: Caller, chat_id: i64) -> Result<Chat, Status> {
+        match Chat::get_by_id(chat_id as _, &self.state.pool).await? {
+            Some(chat) if chat.ws_id == caller.ws_id => Ok(chat),
+            _ => Err(Status::not_found("chat not found")),
+        }
+    }
+}
+
+fn caller<T>(request: &Request<T>) -> Option<Caller> {
+    request.extensions().get::<Caller>().copied()
+}
+
+fn unauthenticated() -> Status {
+    Status::unauthenticated("invalid token")
+}
+
+#[tonic::async_trait]
+impl ChatService for ChatGrpc {
+    async fn send_message(&self, request: Request<SendMessageRequest>) -> Result<Response<pb::Message>, Status> {
+        let caller = caller(&request).ok_or_else(unauthenticated)?;
+        let req = request.into_inner();
+        let chat = self.chat_of(caller, req.chat_id).await?;
+        let input = CreateMessage {
+            content: req.content,
+            images: req.images,
+            client_created_at: None,
+            thread_id: req.thread_id,
+            external_id: req.external_id,
+        };
+        let message = Message::create(&input, chat.id as _, req.sender_id as _, &self.state.pool).await?;
+        Ok(Response::new(message.into()))
+    }
+
+    async fn list_messages(&self, request: Request<ListMessagesRequest>) -> Result<Response<ListMessagesResponse>, Status> {
+        let caller = caller(&request).ok_or_else(unauthenticated)?;
+        let req = request.into_inner();
+        let chat = self.chat_of(caller, req.chat_id).await?;
+        let input = ListMessages {
+            last_seq: req.last_seq,
+            limit: req.limit,
+            thread_id: req.thread_id,
+            ..Default::default()
+        };
+        let messages = Message::list(&input, chat.id as _, self.state.db.reader()).await?;
+        Ok(Response::new(ListMessagesResponse {
+            messages: messages.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn get_user(&self, request: Request<GetUserRequest>) -> Result<Response<pb::User>, Status> {
+        let caller = caller(&request).ok_or_else(unauthenticated)?;
+        let pool = &self.state.pool;
+        let user = match request.into_inner().by {
+            Some(By::Id(id)) => User::find_by_id(id as _, pool).await?,
+            Some(By::Email(email)) => User::find_by_email(&email, pool).await?,
+            None => return Err(Status::invalid_argument("id or email is required")),
+        };
+        match user {
+            Some(user) if user.ws_id == caller.ws_id => Ok(Response::new(user.into())),
+            _ => Err(Status::not_found("user not found")),
+        }
+    }
+}
+
+// the tls config of grpc.tls, loaded at startup so a bad certificate stops the server
+pub(crate) fn tls_config(config: &TlsConfig) -> anyhow::Result<ServerTlsConfig> {
+    let cert = std::fs::read(&config.cert_path)
+        .with_context(|| format!("load grpc tls certificate from {} failed", config.cert_path))?;
+    let key = std::fs::read(&config.key_path)
+        .with_context(|| format!("load grpc tls key from {} failed", config.key_path))?;
+    Ok(ServerTlsConfig::new().identity(Identity::from_pem(cert, key)))
+}
+
+// serves until stop is cancelled, then lets calls in flight finish
+pub(crate) async fn serve(state: AppState, listener: TcpListener, tls: ServerTlsConfig, stop: CancellationToken) {
+    let Some(config) = state.config.grpc.clone() else {
+        return;
+    };
+    let timeout = Duration::from_secs(state.config.request_limits.timeout_secs);
+    let service = ChatServiceServer::with_interceptor(ChatGrpc { state }, Authorize(config.clients));
+    if let Ok(addr) = listener.local_addr() {
+        info!("grpc listening on {} with tls", addr);
+    }
+    let builder = match Server::builder().tls_config(tls) {
+        Ok(builder) => builder,
+        Err(e) => {
+            warn!("grpc tls config failed: {}", e);
+            return;
+        }
+    };
+    let ret = builder
+        .timeout(timeout)
+        .add_service(service)
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), stop.cancelled_owned())
+        .await;
+    if let Err(e) = ret {
+        warn!("grpc server failed: {}", e);
+    }
+}
+
+// finds the client the bearer token belongs to and passes its workspace on to the call
+#[derive(Clone)]
+struct Authorize(Vec<GrpcClient>);
+
+impl Interceptor for Authorize {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        let token = req
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let valid = |c: &&GrpcClient| {
+            !c.token.is_empty() && token.is_some_and(|token| constant_time_eq(c.token.as_bytes(), token.as_bytes()))
+        };
+        match self.0.iter().find(valid) {
+            Some(client) => {
+                req.extensions_mut().insert(Caller { ws_id: client.ws_id as _ });
+                Ok(req)
+            }
+            None => Err(unauthenticated()),
+        }
+    }
+}
+
+impl From<AppError> for Status {
+    fn from(e: AppError) -> Self {
+        let msg = e.to_string();
+        match e {
+            AppError::NotFound(_) => Status::not_found(msg),
+            AppError::PermissionDenied(_) => Status::permission_denied(msg),
+            AppError::CreateMessageError(_) | AppError::InvalidInput(_) => Status::invalid_argument(msg),
+            AppError::TooManyAttempts(_) | AppError::Overloaded => Status::resource_exhausted(msg),
+            _ => Status::internal(msg),
+        }
+    }
+}
+
+impl From<Message> for pb::Message {
+    fn from(m: Message) -> Self {
+        Self {
+            id: m.id,
+            chat_id: m.chat_id,
+            sender_id: m.sender_id,
+            seq: m.seq,
+            thread_id: m.thread_id,
+            content: m.content,
+            images: m.images,
+            created_at: Some(timestamp(m.created_at)),
+        }
+    }
+}
+
+impl From<User> for pb::User {
+    fn from(u: User) -> Self {
+        Self {
+            id: u.id,
+            ws_id: u.ws_id,
+            fullname: u.fullname,
+            email: u.email,
+            created_at: Some(timestamp(u.created_at)),
+        }
+    }
+}
+
+fn timestamp(at: chrono::DateTime<chrono::Utc>) -> Timestamp {
+    Timestamp {
+        seconds: at.timestamp(),
+        nanos: at.timestamp_subsec_nanos() as i32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppConfig;
+    use anyhow::Result;
+
+    fn request<T>(message: T, ws_id: i64) -> Request<T> {
+        let mut req = Request::new(message);
+        req.extensions_mut().insert(Caller { ws_id });
+        req
+    }
+
+    #[test]
+    fn authorize_should_find_the_callers_workspace() {
+        let client = |ws_id, token: &str| GrpcClient { ws_id, token: token.to_string() };
+        let mut authorize = Authorize(vec![client(1, "old"), client(1, "s3cret"), client(2, "other")]);
+        let request = |value: &str| {
+            let mut req = Request::new(());
+            req.metadata_mut().insert("authorization", value.parse().expect("metadata value"));
+            req
+        };
+        let ws_of = |req: Request<()>| req.extensions().get::<Caller>().map(|c| c.ws_id);
+        assert_eq!(authorize.call(request("Bearer s3cret")).map(ws_of).ok(), Some(Some(1)));
+        assert_eq!(authorize.call(request("Bearer old")).map(ws_of).ok(), Some(Some(1)));
+        assert_eq!(authorize.call(request("Bearer other")).map(ws_of).ok(), Some(Some(2)));
+        let status = authorize.call(request("Bearer nope")).expect_err("wrong token");
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert!(authorize.call(Request::new(())).is_err());
+    }
+
+    #[tokio::test]
+    async fn service_should_send_and_list_messages() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let service = ChatGrpc { state };
+        let req = SendMessageRequest {
+            chat_id: 1,
+            sender_id: 2,
+            content: "from another service".to_string(),
+            ..Default::default()
+        };
+        let sent = service.send_message(request(req, 1)).await?.into_inner();
+        assert_eq!(sent.sender_id, 2);
+
+        let req = ListMessagesRequest { chat_id: 1, limit: Some(1), ..Default::default() };
+        let listed = service.list_messages(request(req, 1)).await?.into_inner();
+        assert_eq!(listed.messages, vec![sent]);
+
+        let req = GetUserRequest { by: Some(By::Email("alice@acme.org".to_string())) };
+        assert_eq!(service.get_user(request(req, 1)).await?.into_inner().id, 2);
+        // daisy isn't in the private channel
+        let req = SendMessageRequest { chat_id: 2, sender_id: 5, content: "hi".to_string(), ..Default::default() };
+        let status = service.send_message(request(req, 1)).await.expect_err("not a member");
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_should_stay_in_the_callers_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let service = ChatGrpc { state };
+        let req = ListMessagesRequest { chat_id: 1, ..Default::default() };
+        let status = service.list_messages(request(req, 2)).await.expect_err("another workspace");
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let req = SendMessageRequest { chat_id: 1, sender_id: 1, content: "hi".to_string(), ..Default::default() };
+        let status = service.send_message(request(req, 2)).await.expect_err("another workspace");
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let req = GetUserRequest { by: Some(By::Id(2)) };
+        let status = service.get_user(request(req, 2)).await.expect_err("another workspace");
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        // calls that didn't go through Authorize have no workspace
+        let req = GetUserRequest { by: Some(By::Id(2)) };
+        let status = service.get_user(Request::new(req)).await.expect_err("no caller");
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        Ok(())
+    }
+}
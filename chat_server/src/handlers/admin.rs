@@ -0,0 +1,117 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{
+    audit, mailer,
+    pagination::{Pager, Paginated},
+    usage, AdminStats, AdminUser, AppError, AppState, AuditAction, DestructiveAction, ExportUsage,
+    ListWorkspaceUsers, SubmitAction, UsageFormat, Workspace,
+};
+
+use super::workspace::submit_action;
+
+// every member, deactivated ones included, the cursor is the id of the last one
+pub(crate) async fn list_admin_users_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    pager: Pager,
+    Query(input): Query<ListWorkspaceUsers>,
+) -> Result<Response, AppError> {
+    let after = pager.cursor::<i64>()?;
+    let users = Workspace::list_users(user.ws_id as _, &input, after, &state.pool).await?;
+    let page = Paginated::page(users, input.limit(), |u| u.id.to_string());
+    Ok(pager.respond(page))
+}
+
+// both are held for a second admin when the workspace requires approvals
+pub(crate) async fn deactivate_user_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Query(input): Query<SubmitAction>,
+) -> Result<Response, AppError> {
+    let action = DestructiveAction::DeactivateMember { user_id: id as _ };
+    let res = submit_action(&state, &user, action, input.dry_run).await?;
+    if !input.dry_run && res.status() == StatusCode::OK {
+        audit::record(&state.pool, &user, AuditAction::UserDeactivated, Some(id as _), serde_json::json!({})).await;
+    }
+    Ok(res)
+}
+
+pub(crate) async fn reactivate_user_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Query(input): Query<SubmitAction>,
+) -> Result<Response, AppError> {
+    let action = DestructiveAction::ReactivateMember { user_id: id as _ };
+    let res = submit_action(&state, &user, action, input.dry_run).await?;
+    if !input.dry_run && res.status() == StatusCode::OK {
+        audit::record(&state.pool, &user, AuditAction::UserReactivated, Some(id as _), serde_json::json!({})).await;
+    }
+    Ok(res)
+}
+
+// the member is signed out and emailed a link to choose a new password
+pub(crate) async fn force_password_reset_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = user.force_password_reset(id, &state.pool).await?;
+    let token = member.create_password_reset(&state.pool).await?;
+    let link = mailer::link(&state, &format!("/reset-password?token={}", token));
+    mailer::send(&state, &member.email, &mailer::reset_password(&member.fullname, &link)).await?;
+    audit::record(&state.pool, &user, AuditAction::PasswordResetForced, Some(member.id), serde_json::json!({})).await;
+    Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "password_reset_required": true }))))
+}
+
+// any chat of the workspace, whether or not the admin is in it. Held for a second admin when the
+// workspace requires approvals, like other destructive actions.
+pub(crate) async fn admin_delete_chat_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Query(input): Query<SubmitAction>,
+) -> Result<Response, AppError> {
+    submit_action(&state, &user, DestructiveAction::DeleteChat { chat_id: id as _ }, input.dry_run).await
+}
+
+// not cached, unlike the stats members see
+pub(crate) async fn admin_stats_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<AdminStats>, AppError> {
+    let stats = Workspace::fetch_admin_stats(user.ws_id as _, &state.pool).await?;
+    Ok(Json(stats))
+}
+
+// daily usage of the workspace as rolled up, for billing and capacity planning. Days still
+// going on are not in it.
+pub(crate) async fn export_usage_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Query(input): Query<ExportUsage>,
+) -> Result<Response, AppError> {
+    let days = usage::fetch(user.ws_id as _, &input, &state.pool).await?;
+    let res = match input.format {
+        UsageFormat::Csv => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                (header::CONTENT_DISPOSITION, "attachment; filename=\"usage.csv\""),
+            ],
+            usage::to_csv(&days),
+        )
+            .into_response(),
+        UsageFormat::OpenMetrics => (
+            [(header::CONTENT_TYPE, "application/openmetrics-text; version=1.0.0; charset=utf-8")],
+            usage::to_openmetrics(&days),
+        )
+            .into_response(),
+    };
+    Ok(res)
+}
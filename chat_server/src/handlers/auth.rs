@@ -1,54 +1,808 @@
-use crate::{error::ErrorOutput, handlers::IntoResponse, AppError, AppState, CreateUser, SigninUser, User};
+use crate::{audit, check_email, error::ErrorOutput, AuthOutput, ldap, mailer, middlewares::ClientIp, oauth, oauth_provider::{self, OAuthError, TokenRequest}, oidc, scope::Grant, security::{self, SecurityEvent, SecurityEventKind}, handlers::IntoResponse, EmailVerification, utils::{clear_session_cookies, cookie_value, session_cookies, verify_csrf, TokenId, JWT_DURATION}, AppError, AppState, AuditAction, AuthEvent, AuthEventKind, AuthorizeApp, AuthorizedApp, ChangeEmail, ConsentInput, ConsentOutput, ConsentRequest, ClientInfo, ConfirmEmailChange, CreateUser, FinishPasskeyRegistration, FinishPasskeySignin, ListAuthEvents, MagicLinkSignin, OAuthCallback, OAuthLogin, OidcConfig, Passkey, RefreshToken, RequestMagicLink, ResetPassword, SigninChallenge, SsoLogin, StartPasskeySignin, UserSession, TotpEnrollment, TwoFactorCode, RefreshTokenInput, RevokedToken, SigninUser, User, Workspace};
 
-use axum::{extract::State, http::StatusCode, Json};
+use std::convert::Infallible;
+
+use axum::{extract::{Form, FromRequest, FromRequestParts, Path, Query, Request, State}, http::{header::{CACHE_CONTROL, CONTENT_TYPE, REFERRER_POLICY, SET_COOKIE, USER_AGENT}, request::Parts, HeaderMap, StatusCode}, response::{Html, Redirect, Response}, Extension, Json};
+use axum_extra::{headers::{authorization::Basic, Authorization}, TypedHeader};
 use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+// failed signins within the window that trigger a security event
+const SIGNIN_FAILURE_THRESHOLD: i64 = 5;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyEmail {
+    pub token: String,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct AuthOutput {
-    token: String,
+pub struct DenySignin {
+    pub token: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResendVerification {
+    pub email: String,
+}
+
+async fn issue_tokens(user: User, remember_me: bool, client: &ClientInfo, state: &AppState) -> Result<AuthOutput, AppError> {
+    let id = TokenId::generate();
+    let refresh_token = RefreshToken::issue(&user, remember_me, client, &id, &state.pool).await?;
+    let grant = Grant::for_user(&user, &state.pool).await?;
+    let token = state.ek.sign_with_id(user, &grant, &id)?;
+    Ok(AuthOutput {
+        token,
+        refresh_token,
+        expires_in: JWT_DURATION,
+    })
+}
+
+// with cookie sessions on, the access token is also set as a cookie along with a csrf token, and
+// the refresh token only goes into its HttpOnly cookie so js never sees it
+fn respond_with_cookies(mut output: AuthOutput, status: StatusCode, state: &AppState) -> Response {
+    let config = &state.config.auth.cookie_session;
+    let cookies = if config.enabled {
+        let cookies = session_cookies(config, &output.token, &output.refresh_token, JWT_DURATION);
+        output.refresh_token.clear();
+        cookies
+    } else {
+        vec![]
+    };
+    let mut res = (status, Json(output)).into_response();
+    for cookie in cookies {
+        res.headers_mut().append(SET_COOKIE, cookie);
+    }
+    res
+}
 
 pub(crate) async fn signup_handler(
     State(state): State<AppState>,
+    client: ClientInfo,
     Json(input): Json<CreateUser>,
 ) -> Result<impl IntoResponse, AppError> {
-    let user = User::create(&input, &state.pool).await?;
-    let token = state.ek.sign(user)?;
-    let body = Json(AuthOutput { token });
-    Ok((StatusCode::CREATED, body))
+    let user = User::create(&input, &state.config.auth.password_policy, &state.config.auth.password_hashing, &state.pool).await?;
+    // the first user of a workspace becomes its admin
+    if user.is_workspace_admin(&state.pool).await? {
+        let event = SecurityEvent::new(SecurityEventKind::AdminGranted, user.ws_id, user.id, serde_json::json!({}));
+        security::notify(&state, event).await;
+    }
+    if let Err(e) = user.record_device(&client.user_agent, &state.pool).await {
+        warn!("record device for user {} failed: {}", user.id, e);
+    }
+    send_verification_email(&user, &state).await?;
+    if state.config.auth.email_verification == EmailVerification::BlockSignin {
+        let body = Json(serde_json::json!({ "email": user.email, "verification_required": true }));
+        return Ok((StatusCode::ACCEPTED, body).into_response());
+    }
+    let output = issue_tokens(user, false, &client, &state).await?;
+    Ok(respond_with_cookies(output, StatusCode::CREATED, &state))
 }
 
+// attempts are throttled per email and ip before the password is even checked
 pub(crate) async fn signin_handler(
     State(state): State<AppState>,
+    client: ClientInfo,
+    headers: HeaderMap,
     Json(input): Json<SigninUser>,
 ) -> Result<impl IntoResponse, AppError> {
-    let user = User::verify(&input, &state.pool).await?;
+    // no account has a longer one, and signin_failures couldn't store it
+    check_email(&input.email)?;
+    let ip = client.ip.as_deref();
+    let throttle = &state.config.auth.signin_throttle;
+    if let Some(secs) = User::signin_retry_after(&input.email, ip, throttle, &state.pool).await? {
+        return Err(AppError::TooManyAttempts(secs));
+    }
+    let user = match ldap::config_for(&input.email, &state.config.auth.ldap, &state.pool).await? {
+        Some(config) => ldap::verify(config, &input, &state.pool).await?,
+        None => User::verify(&input, &state.config.auth.password_hashing, &state.pool).await?,
+    };
     match user {
         Some(user) => {
-            let token = state.ek.sign(user)?;
-            let body = Json(AuthOutput { token });
-            Ok((StatusCode::OK, body).into_response())
+            User::clear_signin_failures(&input.email, &state.pool).await?;
+            if let Some(blocked) = check_signin_blocked(&user, &state).await? {
+                return Ok(blocked);
+            }
+            signin_or_challenge(user, input.remember_me, &client, &headers, &state).await
         }
         None => {
+            let failures = User::record_signin_failure(&input.email, ip, &state.pool).await?;
+            if let Some(user) = User::find_by_email(&input.email, &state.pool).await? {
+                let details = serde_json::json!({ "failures": failures });
+                security::record(&state, &user, AuthEventKind::SigninFailed, &client, details.clone()).await;
+                // only once per burst, not on every failure after the threshold
+                if failures == SIGNIN_FAILURE_THRESHOLD {
+                    let event = SecurityEvent::new(SecurityEventKind::RepeatedSigninFailures, user.ws_id, user.id, details);
+                    security::notify(&state, event).await;
+                }
+            }
             let body = Json(ErrorOutput::new("Invalid email or password"));
             Ok((StatusCode::FORBIDDEN, body).into_response())
         }
     }
 }
 
+pub(crate) async fn signin_challenge_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Json(input): Json<SigninChallenge>,
+) -> Result<impl IntoResponse, AppError> {
+    let (user, remember_me) = match User::redeem_signin_challenge(&input, &state.pool).await {
+        Ok(ret) => ret,
+        Err(e) => {
+            if let Some(user) = User::find_by_signin_challenge(&input.challenge_token, &state.pool).await? {
+                let details = serde_json::json!({});
+                security::record(&state, &user, AuthEventKind::TwoFactorFailed, &client, details).await;
+            }
+            return Err(e);
+        }
+    };
+    complete_signin(user, remember_me, &client, &headers, &state).await
+}
+
+// always accepted, so that it cannot be used to probe which emails have accounts. Emails past
+// their limit, or requested from an ip past its limit, are silently not sent.
+pub(crate) async fn request_magic_link_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(input): Json<RequestMagicLink>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(user) = User::find_by_email(&input.email, &state.pool).await? else {
+        return Ok(StatusCode::ACCEPTED);
+    };
+    if user.is_deactivated(&state.pool).await? {
+        return Ok(StatusCode::ACCEPTED);
+    }
+    if let Some(token) = user.create_magic_link(input.remember_me, client.ip.as_deref(), &state.pool).await? {
+        let link = mailer::link(&state, &format!("/api/signin/magic?token={}", token));
+        mailer::send(&state, &user.email, &mailer::magic_link(&user.fullname, &link)).await?;
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+// the link stands in for the password only, 2fa still applies to users who enabled it. Apps
+// send json, the page of the emailed link posts a form.
+pub(crate) async fn magic_link_signin_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    request: Request,
+) -> Result<impl IntoResponse, AppError> {
+    let is_form = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    let input = if is_form {
+        let Form(input) = Form::<MagicLinkSignin>::from_request(request, &state)
+            .await
+            .map_err(|e| AppError::InvalidInput(e.body_text()))?;
+        input
+    } else {
+        let Json(input) = Json::<MagicLinkSignin>::from_request(request, &state)
+            .await
+            .map_err(|e| AppError::InvalidInput(e.body_text()))?;
+        input
+    };
+    redeem_magic_link(&input, &client, &headers, &state).await
+}
+
+// the link in the email, opened in the browser. Only a click on the page signs in, so mail
+// scanners and link previews that open the link don't use it up.
+pub(crate) async fn open_magic_link_handler(Query(input): Query<MagicLinkSignin>) -> impl IntoResponse {
+    let body = format!(
+        concat!(
+            "<!doctype html><html><head><meta charset=\"utf-8\"><title>Sign in</title></head><body>",
+            "<h1>Sign in to chat</h1>",
+            "<form method=\"post\" action=\"/api/signin/magic/redeem\">",
+            "<input type=\"hidden\" name=\"token\" value=\"{}\">",
+            "<button type=\"submit\">Sign in</button>",
+            "</form></body></html>"
+        ),
+        escape_html(&input.token)
+    );
+    ([(CACHE_CONTROL, "no-store"), (REFERRER_POLICY, "no-referrer")], Html(body))
+}
+
+async fn redeem_magic_link(
+    input: &MagicLinkSignin,
+    client: &ClientInfo,
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<Response, AppError> {
+    let (user, remember_me) = User::redeem_magic_link(input, &state.pool).await?;
+    if let Some(blocked) = check_signin_blocked(&user, state).await? {
+        return Ok(blocked);
+    }
+    signin_or_challenge(user, remember_me, client, headers, state).await
+}
+
+pub(crate) async fn oauth_login_handler(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    Query(input): Query<OAuthLogin>,
+) -> Result<impl IntoResponse, AppError> {
+    let (provider, config) = oauth::provider(&state, &provider)?;
+    let (oauth_state, code_verifier) = User::create_oauth_state(provider.name(), input.remember_me, &state.pool).await?;
+    let redirect_uri = oauth::redirect_uri(&state, provider);
+    let url = oauth::authorize_url(provider, config, &redirect_uri, &oauth_state, &code_verifier)?;
+    Ok(Redirect::to(&url))
+}
+
+// the provider redirects back here, 2fa still applies to users who enabled it
+pub(crate) async fn oauth_callback_handler(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Query(input): Query<OAuthCallback>,
+) -> Result<impl IntoResponse, AppError> {
+    let (provider, config) = oauth::provider(&state, &provider)?;
+    let (code_verifier, remember_me) = User::redeem_oauth_state(&input.state, provider.name(), &state.pool).await?;
+    let code = match (&input.code, &input.error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            return Err(AppError::PermissionDenied(format!(
+                "{} signin was not completed: {}",
+                provider.name(),
+                error.as_deref().unwrap_or("no authorization code")
+            )));
+        }
+    };
+    let identity = oauth::fetch_identity(&state, provider, config, code, &code_verifier).await?;
+    let user = User::from_oauth_identity(provider.name(), &identity, &state.pool).await?;
+    if let Some(blocked) = check_signin_blocked(&user, &state).await? {
+        return Ok(blocked);
+    }
+    signin_or_challenge(user, remember_me, &client, &headers, &state).await
+}
+
+// starts a signin at the workspace's identity provider
+pub(crate) async fn sso_login_handler(
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+    Query(input): Query<SsoLogin>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = Workspace::find_by_slug(&slug, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("workspace not found: {}", slug)))?;
+    let config = OidcConfig::fetch(ws.id as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("sso is not configured for {}", slug)))?;
+    let discovery = oidc::discover(&state, &config.issuer).await?;
+    let (oidc_state, signin) = config.create_signin(input.remember_me, &state.pool).await?;
+    let redirect_uri = oidc::redirect_uri(&state);
+    let url = oidc::authorize_url(&discovery, &config, &redirect_uri, &oidc_state, &signin.code_verifier, &signin.nonce)?;
+    Ok(Redirect::to(&url))
+}
+
+// one callback for all workspaces, the state tells which one the signin is for
+pub(crate) async fn sso_callback_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Query(input): Query<OAuthCallback>,
+) -> Result<impl IntoResponse, AppError> {
+    let signin = OidcConfig::redeem_signin(&input.state, &state.pool).await?;
+    let code = match (&input.code, &input.error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            return Err(AppError::PermissionDenied(format!(
+                "sso signin was not completed: {}",
+                error.as_deref().unwrap_or("no authorization code")
+            )));
+        }
+    };
+    let config = OidcConfig::fetch(signin.ws_id as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::PermissionDenied("sso was turned off for this workspace".to_string()))?;
+    let discovery = oidc::discover(&state, &config.issuer).await?;
+    let identity = oidc::fetch_identity(&state, &discovery, &config, code, &signin.code_verifier, &signin.nonce).await?;
+    let user = User::from_oidc_identity(&config, &identity, &state.pool).await?;
+    if let Some(blocked) = check_signin_blocked(&user, &state).await? {
+        return Ok(blocked);
+    }
+    signin_or_challenge(user, signin.remember_me, &client, &headers, &state).await
+}
+
+pub(crate) async fn start_passkey_signin_handler(
+    State(state): State<AppState>,
+    Json(input): Json<StartPasskeySignin>,
+) -> Result<impl IntoResponse, AppError> {
+    let options = User::start_passkey_signin(&state.config.webauthn, &input, &state.pool).await?;
+    Ok(Json(options))
+}
+
+// a passkey the authenticator verified the user for is both factors, so 2fa is not asked for
+// again. Without user verification it only stands in for the password.
+pub(crate) async fn finish_passkey_signin_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Json(input): Json<FinishPasskeySignin>,
+) -> Result<impl IntoResponse, AppError> {
+    let (user, remember_me, user_verified) =
+        User::finish_passkey_signin(&state.config.webauthn, &input, &state.pool).await?;
+    if let Some(blocked) = check_signin_blocked(&user, &state).await? {
+        return Ok(blocked);
+    }
+    if !user_verified {
+        return signin_or_challenge(user, remember_me, &client, &headers, &state).await;
+    }
+    complete_signin(user, remember_me, &client, &headers, &state).await
+}
+
+// the user proved who they are, but is not allowed to sign in yet
+async fn check_signin_blocked(user: &User, state: &AppState) -> Result<Option<Response>, AppError> {
+    if user.is_deactivated(&state.pool).await? {
+        let body = Json(ErrorOutput::new("Account has been deactivated"));
+        return Ok(Some((StatusCode::FORBIDDEN, body).into_response()));
+    }
+    if state.config.auth.email_verification == EmailVerification::BlockSignin
+        && !user.is_email_verified(&state.pool).await?
+    {
+        let body = Json(ErrorOutput::new("Email address has not been verified"));
+        return Ok(Some((StatusCode::FORBIDDEN, body).into_response()));
+    }
+    if user.is_password_reset_required(&state.pool).await? {
+        let body = Json(ErrorOutput::new("Password reset required, check your email"));
+        return Ok(Some((StatusCode::FORBIDDEN, body).into_response()));
+    }
+    Ok(None)
+}
+
+// the first factor checked out, the second factor is verified in signin_challenge_handler
+async fn signin_or_challenge(
+    user: User,
+    remember_me: bool,
+    client: &ClientInfo,
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<Response, AppError> {
+    if user.has_two_factor(&state.pool).await? {
+        let challenge_token = user.create_signin_challenge(remember_me, &state.pool).await?;
+        let body = Json(serde_json::json!({ "two_factor_required": true, "challenge_token": challenge_token }));
+        return Ok((StatusCode::OK, body).into_response());
+    }
+    complete_signin(user, remember_me, client, headers, state).await
+}
+
+async fn complete_signin(
+    user: User,
+    remember_me: bool,
+    client: &ClientInfo,
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<Response, AppError> {
+    audit::record(&state.pool, &user, AuditAction::Signin, None, serde_json::json!({})).await;
+    security::record(state, &user, AuthEventKind::Signin, client, serde_json::json!({})).await;
+    check_signin_anomaly(&user, client, headers, state).await;
+    let output = issue_tokens(user, remember_me, client, state).await?;
+    Ok(respond_with_cookies(output, StatusCode::OK, state))
+}
+
+pub(crate) async fn enroll_totp_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<Json<TotpEnrollment>, AppError> {
+    let enrollment = user.enroll_totp(&state.pool).await?;
+    Ok(Json(enrollment))
+}
+
+pub(crate) async fn confirm_totp_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(input): Json<TwoFactorCode>,
+) -> Result<impl IntoResponse, AppError> {
+    let recovery_codes = user.confirm_totp(&input.code, &state.pool).await?;
+    security::record(&state, &user, AuthEventKind::TwoFactorEnabled, &client, serde_json::json!({})).await;
+    Ok(Json(serde_json::json!({ "recovery_codes": recovery_codes })))
+}
+
+pub(crate) async fn regenerate_recovery_codes_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(input): Json<TwoFactorCode>,
+) -> Result<impl IntoResponse, AppError> {
+    let recovery_codes = user.regenerate_recovery_codes(&input.code, &state.pool).await?;
+    security::record(&state, &user, AuthEventKind::RecoveryCodesRegenerated, &client, serde_json::json!({})).await;
+    Ok(Json(serde_json::json!({ "recovery_codes": recovery_codes })))
+}
+
+pub(crate) async fn disable_two_factor_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(input): Json<TwoFactorCode>,
+) -> Result<impl IntoResponse, AppError> {
+    user.disable_two_factor(&input.code, &state.pool).await?;
+    security::record(&state, &user, AuthEventKind::TwoFactorDisabled, &client, serde_json::json!({})).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_passkeys_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Passkey>>, AppError> {
+    let passkeys = user.passkeys(&state.pool).await?;
+    Ok(Json(passkeys))
+}
+
+pub(crate) async fn start_passkey_registration_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let options = user.start_passkey_registration(&state.config.webauthn, &state.pool).await?;
+    Ok(Json(options))
+}
+
+pub(crate) async fn finish_passkey_registration_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<FinishPasskeyRegistration>,
+) -> Result<impl IntoResponse, AppError> {
+    let passkey = user.finish_passkey_registration(&state.config.webauthn, &input, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(passkey)))
+}
+
+pub(crate) async fn delete_passkey_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    user.delete_passkey(id, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn refresh_token_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Json(input): Json<RefreshTokenInput>,
+) -> Result<impl IntoResponse, AppError> {
+    let refresh_token = refresh_token_from(&input, &headers, &state)?;
+    let id = TokenId::generate();
+    let (user, refresh_token) = RefreshToken::rotate(refresh_token, &id, &state.pool).await?;
+    security::record(&state, &user, AuthEventKind::TokenRefreshed, &client, serde_json::json!({})).await;
+    // picks up role changes made since the last token was issued
+    let grant = Grant::for_user(&user, &state.pool).await?;
+    let token = state.ek.sign_with_id(user, &grant, &id)?;
+    let output = AuthOutput {
+        token,
+        refresh_token,
+        expires_in: JWT_DURATION,
+    };
+    Ok(respond_with_cookies(output, StatusCode::OK, &state))
+}
+
+// the caller's own signins, failures and credential changes, newest first
+pub(crate) async fn list_security_events_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListAuthEvents>,
+) -> Result<Json<Vec<AuthEvent>>, AppError> {
+    let input = ListAuthEvents { user_id: Some(user.id), ..input };
+    let events = AuthEvent::list(user.ws_id as _, &input, &state.pool).await?;
+    Ok(Json(events))
+}
+
+pub(crate) async fn list_sessions_handler(
+    Extension(user): Extension<User>,
+    Extension(id): Extension<TokenId>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<UserSession>>, AppError> {
+    Ok(Json(user.sessions(&id, &state.pool).await?))
+}
+
+// revoking the current session works like signing out
+pub(crate) async fn revoke_session_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    user.revoke_session(id, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn revoke_token_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(input): Json<RefreshTokenInput>,
+) -> Result<impl IntoResponse, AppError> {
+    let refresh_token = refresh_token_from(&input, &headers, &state)?;
+    RefreshToken::revoke(refresh_token, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// the body's refresh token, or with cookie sessions on the refresh cookie. Like any other cookie
+// authenticated request, using the cookie needs the csrf header.
+fn refresh_token_from<'a>(input: &'a RefreshTokenInput, headers: &'a HeaderMap, state: &AppState) -> Result<&'a str, AppError> {
+    let config = &state.config.auth.cookie_session;
+    if !input.refresh_token.is_empty() || !config.enabled {
+        return Ok(&input.refresh_token);
+    }
+    let token = cookie_value(headers, &config.refresh_cookie_name).filter(|token| !token.is_empty());
+    let Some(token) = token else {
+        return Err(AppError::PermissionDenied("Invalid refresh token".to_string()));
+    };
+    if !verify_csrf(config, headers) {
+        return Err(AppError::PermissionDenied("csrf token missing or invalid".to_string()));
+    }
+    Ok(token)
+}
+
+pub(crate) async fn verify_email_handler(
+    State(state): State<AppState>,
+    Query(input): Query<VerifyEmail>,
+) -> Result<Json<User>, AppError> {
+    let user = User::verify_email(&input.token, &state.pool).await?;
+    Ok(Json(user))
+}
+
+// always accepted, so that it cannot be used to probe which emails have accounts
+pub(crate) async fn resend_verification_handler(
+    State(state): State<AppState>,
+    Json(input): Json<ResendVerification>,
+) -> Result<impl IntoResponse, AppError> {
+    if let Some(user) = User::find_by_email(&input.email, &state.pool).await? {
+        send_verification_email(&user, &state).await?;
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+// the current address is told about the change right away, the switch waits for the new one
+pub(crate) async fn change_email_handler(
+    Extension(user): Extension<User>,
+    id: Option<Extension<TokenId>>,
+    State(state): State<AppState>,
+    Json(input): Json<ChangeEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    let current = id.as_ref().map(|Extension(id)| id);
+    let token = user.request_email_change(&input, current, &state.pool).await?;
+    let new_email = input.email.trim();
+    let link = mailer::link(&state, &format!("/api/me/email/confirm?token={}", token));
+    mailer::send(&state, new_email, &mailer::confirm_email_change(&user.fullname, &link)).await?;
+    mailer::send(&state, &user.email, &mailer::email_change_notice(&user.fullname, new_email, false)).await?;
+    Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "pending_email": new_email }))))
+}
+
+pub(crate) async fn confirm_email_change_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Query(input): Query<ConfirmEmailChange>,
+) -> Result<Json<User>, AppError> {
+    let (user, old_email) = User::confirm_email_change(&input, &state.pool).await?;
+    let details = serde_json::json!({ "from": old_email, "to": user.email });
+    security::record(&state, &user, AuthEventKind::EmailChanged, &client, details).await;
+    mailer::send(&state, &old_email, &mailer::email_change_notice(&user.fullname, &user.email, true)).await?;
+    Ok(Json(user))
+}
+
+// the link in the signin alert opens a page asking to confirm, link previewers and mail scanners
+// fetch links but don't submit forms
+pub(crate) async fn deny_signin_page_handler(Query(input): Query<DenySignin>) -> impl IntoResponse {
+    let body = format!(
+        concat!(
+            "<!doctype html><html><head><meta charset=\"utf-8\"><title>Secure your account</title></head><body>",
+            "<h1>Wasn't you?</h1>",
+            "<p>This signs your account out everywhere. You will then get an email to reset your password.</p>",
+            "<form method=\"post\" action=\"/api/signin/deny\">",
+            "<input type=\"hidden\" name=\"token\" value=\"{}\">",
+            "<button type=\"submit\">Sign out everywhere</button>",
+            "</form></body></html>"
+        ),
+        escape_html(&input.token)
+    );
+    ([(CACHE_CONTROL, "no-store"), (REFERRER_POLICY, "no-referrer")], Html(body))
+}
+
+// a password reset is all the user can do after denying a signin
+pub(crate) async fn deny_signin_handler(
+    State(state): State<AppState>,
+    Form(input): Form<DenySignin>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = User::deny_signin(&input.token, &state.pool).await?;
+    let token = user.create_password_reset(&state.pool).await?;
+    let link = mailer::link(&state, &format!("/reset-password?token={}", token));
+    mailer::send(&state, &user.email, &mailer::reset_password(&user.fullname, &link)).await?;
+    let body = concat!(
+        "<!doctype html><html><head><meta charset=\"utf-8\"><title>Secure your account</title></head><body>",
+        "<h1>You have been signed out everywhere</h1>",
+        "<p>Check your email for the link to reset your password.</p>",
+        "</body></html>"
+    );
+    Ok(([(CACHE_CONTROL, "no-store")], Html(body)))
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub(crate) async fn reset_password_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(input): Json<ResetPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = User::reset_password(&input, &state.config.auth.password_policy, &state.config.auth.password_hashing, &state.pool).await?;
+    security::record(&state, &user, AuthEventKind::PasswordChanged, &client, serde_json::json!({})).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// warns the user and the workspace's security webhooks about signins from a new device or
+// country. Failures are logged only, the signin itself already succeeded.
+async fn check_signin_anomaly(user: &User, client: &ClientInfo, headers: &HeaderMap, state: &AppState) {
+    let user_agent = client.user_agent.as_str();
+    let new_device = user.record_device(user_agent, &state.pool).await.unwrap_or_else(|e| {
+        warn!("record device for user {} failed: {}", user.id, e);
+        false
+    });
+    let country = state
+        .config
+        .auth
+        .country_header
+        .as_ref()
+        .and_then(|name| headers.get(name.as_str()))
+        .and_then(|v| v.to_str().ok());
+    let new_country = match country {
+        Some(country) => user.record_country(country, &state.pool).await.unwrap_or_else(|e| {
+            warn!("record country for user {} failed: {}", user.id, e);
+            false
+        }),
+        None => false,
+    };
+    if !new_device && !new_country {
+        return;
+    }
+
+    let details = serde_json::json!({ "user_agent": user_agent, "country": country });
+    if new_device {
+        let event = SecurityEvent::new(SecurityEventKind::NewDeviceSignin, user.ws_id, user.id, details.clone());
+        security::notify(state, event).await;
+    }
+    let ret = match user.create_signin_alert(&details, &state.pool).await {
+        Ok(token) => {
+            let link = mailer::link(state, &format!("/api/signin/deny?token={}", token));
+            let email = mailer::signin_alert(&user.fullname, user_agent, country, &link);
+            mailer::send(state, &user.email, &email).await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = ret {
+        warn!("send signin alert to user {} failed: {}", user.id, e);
+    }
+}
+
+async fn send_verification_email(user: &User, state: &AppState) -> Result<(), AppError> {
+    let Some(token) = user.create_email_verification(&state.pool).await? else {
+        return Ok(());
+    };
+    let link = mailer::link(state, &format!("/api/verify?token={}", token));
+    mailer::send(state, &user.email, &mailer::verify_email(&user.fullname, &link)).await
+}
+
+// the ip is the one the rate limiter worked out, hops in the forwarded header are only trusted
+// when added by one of the trusted proxies
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ip = parts.extensions.get::<ClientIp>().map(|ClientIp(ip)| ip.clone());
+        Ok(ClientInfo::new(user_agent(&parts.headers), ip))
+    }
+}
+
+fn user_agent(headers: &HeaderMap) -> &str {
+    headers
+        .get(USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("unknown")
+}
+
+// revokes the access token used for this request, the refresh token is revoked separately unless
+// it is in the refresh cookie
+pub(crate) async fn signout_handler(
+    Extension(user): Extension<User>,
+    Extension(id): Extension<TokenId>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    RevokedToken::create(&id, user.id as _, &state.pool).await?;
+    let mut res = StatusCode::NO_CONTENT.into_response();
+    let config = &state.config.auth.cookie_session;
+    if config.enabled {
+        if let Some(token) = cookie_value(&headers, &config.refresh_cookie_name).filter(|token| !token.is_empty()) {
+            RefreshToken::revoke(token, &state.pool).await?;
+        }
+        for cookie in clear_session_cookies(config) {
+            res.headers_mut().append(SET_COOKIE, cookie);
+        }
+    }
+    Ok(res)
+}
+
+// what the app asks for, for the consent screen of the frontend serving the authorize url
+pub(crate) async fn app_consent_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<AuthorizeApp>,
+) -> Result<Json<ConsentRequest>, AppError> {
+    Ok(Json(user.app_consent(&input, &state.pool).await?))
+}
+
+// the user's answer, the frontend sends the browser on to redirect_to
+pub(crate) async fn authorize_app_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<ConsentInput>,
+) -> Result<Json<ConsentOutput>, AppError> {
+    Ok(Json(user.authorize_app(&input, &state.pool).await?))
+}
+
+// called by apps rather than browsers, see oauth_provider
+pub(crate) async fn oauth_token_handler(
+    State(state): State<AppState>,
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+    Form(input): Form<TokenRequest>,
+) -> Result<impl IntoResponse, OAuthError> {
+    let basic = basic.map(|TypedHeader(Authorization(basic))| (basic.username().to_string(), basic.password().to_string()));
+    let token = oauth_provider::issue_token(&state, basic, input).await?;
+    Ok(([(CACHE_CONTROL, "no-store")], Json(token)))
+}
+
+pub(crate) async fn list_authorized_apps_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<AuthorizedApp>>, AppError> {
+    Ok(Json(user.authorized_apps(&state.pool).await?))
+}
+
+pub(crate) async fn revoke_authorized_app_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(app_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    user.revoke_app(app_id, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::AppConfig;
+    use crate::{handlers::ensure_email_verified, utils::totp_code, webauthn::test_authenticator::Authenticator, AppConfig};
     use anyhow::Result;
+    use axum::http::header::COOKIE;
     use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn deny_signin_page_should_post_the_token() -> Result<()> {
+        let input = DenySignin { token: "a\"><script>".to_string() };
+        let ret = deny_signin_page_handler(Query(input)).await.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let body = std::str::from_utf8(&body)?;
+        assert!(body.contains("<form method=\"post\" action=\"/api/signin/deny\">"));
+        assert!(body.contains("value=\"a&quot;&gt;&lt;script&gt;\""));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn signup_should_work() -> Result<()> {
         let config = AppConfig::load()?;
         let (_tdb, state) = AppState::new_for_test(config).await?;
-        let input = CreateUser::new("acme", "Tian Chen", "tyr@acme.org", "123456");
-        let ret = signup_handler(State(state), Json(input)).await?.into_response();
+        let input = CreateUser::new("acme", "Tian Chen", "tyr@acme.org", "hunter42");
+        let ret = signup_handler(State(state), ClientInfo::default(), Json(input)).await?.into_response();
         assert_eq!(ret.status(), StatusCode::CREATED);
         let body = ret.into_body().collect().await?.to_bytes();
         let ret: AuthOutput = serde_json::from_slice(&body)?;
@@ -60,9 +814,9 @@ mod tests {
     async fn signup_duplicate_user_should_409() -> Result<()> {
         let config = AppConfig::load()?;
         let (_tdb, state) = AppState::new_for_test(config).await?;
-        let input = CreateUser::new("acme", "Tian Chen", "tyr@acme.org", "123456");
-        signup_handler(State(state.clone()), Json(input.clone())).await?;
-        let ret = signup_handler(State(state), Json(input.clone())).await.into_response();
+        let input = CreateUser::new("acme", "Tian Chen", "tyr@acme.org", "hunter42");
+        signup_handler(State(state.clone()), ClientInfo::default(), Json(input.clone())).await?;
+        let ret = signup_handler(State(state), ClientInfo::default(), Json(input.clone())).await.into_response();
         assert_eq!(ret.status(), StatusCode::CONFLICT);
         let body = ret.into_body().collect().await?.to_bytes();
         let ret: ErrorOutput = serde_json::from_slice(&body)?;
@@ -76,7 +830,7 @@ mod tests {
         let email = "tchen@acme.org";
         let password = "123456";
         let input = SigninUser::new(email, password);
-        let ret = signin_handler(State(state), Json(input))
+        let ret = signin_handler(State(state), ClientInfo::default(), HeaderMap::new(), Json(input))
             .await?
             .into_response();
         assert_eq!(ret.status(), StatusCode::OK);
@@ -87,6 +841,106 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn repeated_signin_failures_should_be_throttled() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let client = || ClientInfo::new("", Some("10.0.0.1".to_string()));
+        for _ in 0..state.config.auth.signin_throttle.email_failures {
+            let input = SigninUser::new("tchen@acme.org", "wrong");
+            let ret = signin_handler(State(state.clone()), client(), HeaderMap::new(), Json(input)).await?.into_response();
+            assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        }
+        // even the right password has to wait
+        let input = SigninUser::new("tchen@acme.org", "123456");
+        let ret = signin_handler(State(state), client(), HeaderMap::new(), Json(input)).await.into_response();
+        assert_eq!(ret.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(ret.headers().contains_key("retry-after"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_token_should_work() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let input = SigninUser::new("tchen@acme.org", "123456");
+        let ret = signin_handler(State(state.clone()), ClientInfo::default(), HeaderMap::new(), Json(input)).await?.into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let signin: AuthOutput = serde_json::from_slice(&body)?;
+
+        let input = RefreshTokenInput { refresh_token: signin.refresh_token.clone() };
+        let ret = refresh_token_handler(State(state.clone()), ClientInfo::default(), HeaderMap::new(), Json(input.clone())).await?.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        assert_eq!(state.dk.verify(&ret.token)?.0.id, 1);
+        assert_ne!(ret.refresh_token, signin.refresh_token);
+
+        let ret = refresh_token_handler(State(state), ClientInfo::default(), HeaderMap::new(), Json(input)).await.into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_token_should_stay_in_cookie_with_cookie_sessions() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.auth.cookie_session.enabled = true;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let input = SigninUser::new("tchen@acme.org", "123456");
+        let ret = signin_handler(State(state.clone()), ClientInfo::default(), HeaderMap::new(), Json(input)).await?.into_response();
+        let cookies: Vec<_> = ret.headers().get_all(SET_COOKIE).iter().filter_map(|v| v.to_str().ok()).collect();
+        assert!(cookies.iter().all(|c| c.ends_with("; Secure")));
+        let cookie = cookies.iter().map(|c| c.split(';').next().unwrap_or_default()).collect::<Vec<_>>().join("; ");
+        let body = ret.into_body().collect().await?.to_bytes();
+        let signin: serde_json::Value = serde_json::from_slice(&body)?;
+        assert!(signin.get("refresh_token").is_none());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, cookie.parse()?);
+        let input = RefreshTokenInput { refresh_token: String::new() };
+        let ret = refresh_token_handler(State(state.clone()), ClientInfo::default(), headers.clone(), Json(input.clone())).await.into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let csrf = cookie.split("; ").find_map(|c| c.strip_prefix("chat_csrf=")).unwrap_or_default();
+        headers.insert("x-csrf-token", csrf.parse()?);
+        let ret = refresh_token_handler(State(state), ClientInfo::default(), headers, Json(input)).await?.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        assert!(ret.refresh_token.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auth_events_should_be_recorded() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let headers = HeaderMap::new();
+        let client = ClientInfo::new("curl/8.0", Some("10.0.0.1".to_string()));
+        let input = SigninUser::new("tchen@acme.org", "wrong");
+        signin_handler(State(state.clone()), client.clone(), headers.clone(), Json(input)).await?;
+        let input = SigninUser::new("tchen@acme.org", "123456");
+        let ret = signin_handler(State(state.clone()), client.clone(), headers.clone(), Json(input)).await?.into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let signin: AuthOutput = serde_json::from_slice(&body)?;
+        let input = RefreshTokenInput { refresh_token: signin.refresh_token };
+        refresh_token_handler(State(state.clone()), client, headers, Json(input)).await?;
+
+        let (user, _) = state.dk.verify(&signin.token)?;
+        let Json(events) =
+            list_security_events_handler(Extension(user), State(state.clone()), Query(ListAuthEvents::default())).await?;
+        let kinds: Vec<_> = events.iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, [AuthEventKind::TokenRefreshed, AuthEventKind::Signin, AuthEventKind::SigninFailed]);
+        assert!(events.iter().all(|e| e.ip.as_deref() == Some("10.0.0.1") && e.user_agent == "curl/8.0"));
+
+        // members only ever see their own events
+        let alice = User::find_by_email("alice@acme.org", &state.pool).await?.expect("user should exist");
+        let input = ListAuthEvents { user_id: Some(1), ..Default::default() };
+        let Json(events) = list_security_events_handler(Extension(alice), State(state), Query(input)).await?;
+        assert!(events.is_empty());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn signin_with_non_exist_user_should_403() -> Result<()> {
         let config = AppConfig::load()?;
@@ -94,7 +948,7 @@ mod tests {
         let email = "tchen1@acme.org";
         let password = "123456";
         let input = SigninUser::new(email, password);
-        let ret = signin_handler(State(state), Json(input))
+        let ret = signin_handler(State(state), ClientInfo::default(), HeaderMap::new(), Json(input))
             .await
             .into_response();
         assert_eq!(ret.status(), StatusCode::FORBIDDEN);
@@ -104,4 +958,320 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn signout_should_revoke_token() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let token = state.ek.sign(User::new(1, "Tyr Chen", "tchen@acme.org"))?;
+        let (user, id) = state.dk.verify(&token)?;
+        let ret = signout_handler(Extension(user), Extension(id.clone()), State(state.clone()), HeaderMap::new())
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        assert!(RevokedToken::is_revoked(&id.jti, &state.pool).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn magic_link_signin_should_work() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let input = RequestMagicLink { email: "nobody@acme.org".to_string(), remember_me: false };
+        let ret = request_magic_link_handler(State(state.clone()), ClientInfo::default(), Json(input)).await?.into_response();
+        assert_eq!(ret.status(), StatusCode::ACCEPTED);
+        let input = RequestMagicLink { email: "tchen@acme.org".to_string(), remember_me: false };
+        let ret = request_magic_link_handler(State(state.clone()), ClientInfo::default(), Json(input)).await?.into_response();
+        assert_eq!(ret.status(), StatusCode::ACCEPTED);
+        let bodies: Vec<String> = sqlx::query_scalar("SELECT body FROM outbound_emails WHERE subject = 'Your signin link'")
+            .fetch_all(&state.pool)
+            .await?;
+        assert_eq!(bodies.len(), 1);
+        let token = bodies[0]
+            .split("token=")
+            .nth(1)
+            .and_then(|s| s.split_whitespace().next())
+            .expect("email should contain a signin link");
+
+        assert!(bodies[0].contains("/api/signin/magic?token="));
+        // opening the link only shows the page that posts the token
+        let input = MagicLinkSignin { token: token.to_string() };
+        let ret = open_magic_link_handler(Query(input)).await.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let page = String::from_utf8(body.to_vec())?;
+        assert!(page.contains("action=\"/api/signin/magic/redeem\""));
+        assert!(page.contains(&format!("value=\"{}\"", token)));
+
+        let redeem = |content_type: &str, body: String| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, content_type.parse().expect("valid header"));
+            let request = Request::post("/api/signin/magic/redeem")
+                .header(CONTENT_TYPE, content_type)
+                .body(axum::body::Body::from(body))
+                .expect("valid request");
+            magic_link_signin_handler(State(state.clone()), ClientInfo::default(), headers, request)
+        };
+        let ret = redeem("application/x-www-form-urlencoded", format!("token={}", token)).await?.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        assert_eq!(state.dk.verify(&ret.token)?.0.email, "tchen@acme.org");
+
+        let json = serde_json::json!({ "token": token }).to_string();
+        let ret = redeem("application/json", json).await.into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signup_should_send_verification_email() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let input = CreateUser::new("acme", "Tian Chen", "tyr@acme.org", "hunter42");
+        signup_handler(State(state.clone()), ClientInfo::default(), Json(input)).await?;
+        let body: String = sqlx::query_scalar("SELECT body FROM outbound_emails WHERE to_email = 'tyr@acme.org'")
+            .fetch_one(&state.pool)
+            .await?;
+        let token = body
+            .split("token=")
+            .nth(1)
+            .and_then(|s| s.split_whitespace().next())
+            .expect("email should contain a verification link");
+
+        let user = User::find_by_email("tyr@acme.org", &state.pool).await?.expect("user should exist");
+        let ret = ensure_email_verified(&user, &state).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let input = VerifyEmail { token: token.to_string() };
+        let Json(verified) = verify_email_handler(State(state.clone()), Query(input)).await?;
+        assert_eq!(verified.id, user.id);
+        ensure_email_verified(&user, &state).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_from_new_device_should_send_alert() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let input = SigninUser::new("tchen@acme.org", "123456");
+        let client = ClientInfo::new("firefox", None);
+        signin_handler(State(state.clone()), client, HeaderMap::new(), Json(input.clone())).await?;
+        let client = ClientInfo::new("curl", None);
+        signin_handler(State(state.clone()), client, HeaderMap::new(), Json(input.clone())).await?;
+
+        let body: String = sqlx::query_scalar("SELECT body FROM outbound_emails WHERE to_email = 'tchen@acme.org'")
+            .fetch_one(&state.pool)
+            .await?;
+        let token = body
+            .split("token=")
+            .nth(1)
+            .and_then(|s| s.split_whitespace().next())
+            .expect("email should contain a deny link");
+        let input_deny = DenySignin { token: token.to_string() };
+        deny_signin_handler(State(state.clone()), Form(input_deny)).await?;
+
+        let ret = signin_handler(State(state), ClientInfo::default(), HeaderMap::new(), Json(input)).await?.into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_with_two_factor_should_return_challenge() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let user = User::find_by_email("tchen@acme.org", &state.pool).await?.expect("user should exist");
+        let Json(enrollment) = enroll_totp_handler(Extension(user.clone()), State(state.clone())).await?;
+        let input = TwoFactorCode { code: totp_code(&enrollment.secret, chrono::Utc::now().timestamp()) };
+        let ret = confirm_totp_handler(Extension(user), State(state.clone()), ClientInfo::default(), Json(input)).await?.into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: serde_json::Value = serde_json::from_slice(&body)?;
+        let recovery_code = ret["recovery_codes"][0].as_str().expect("recovery codes").to_string();
+
+        let input = SigninUser::new("tchen@acme.org", "123456");
+        let ret = signin_handler(State(state.clone()), ClientInfo::default(), HeaderMap::new(), Json(input)).await?.into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(ret["two_factor_required"], true);
+        assert!(ret.get("token").is_none());
+
+        let input = SigninChallenge {
+            challenge_token: ret["challenge_token"].as_str().expect("challenge token").to_string(),
+            code: recovery_code,
+        };
+        let ret = signin_challenge_handler(State(state), ClientInfo::default(), HeaderMap::new(), Json(input)).await?.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        assert_ne!(ret.token, "");
+        Ok(())
+    }
+
+    // serves the token and user endpoints of a github-like provider
+    async fn mock_github() -> Result<String> {
+        use axum::routing::{get, post};
+        let app = axum::Router::new()
+            .route("/token", post(|| async { Json(serde_json::json!({ "access_token": "at" })) }))
+            .route("/user", get(|| async { Json(serde_json::json!({ "id": 42, "login": "octo", "name": null })) }))
+            .route(
+                "/user/emails",
+                get(|| async { Json(serde_json::json!([{ "email": "alice@acme.org", "primary": true, "verified": true }])) }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok(format!("http://{}", addr))
+    }
+
+    #[tokio::test]
+    async fn oauth_signin_should_work() -> Result<()> {
+        let base_url = mock_github().await?;
+        let mut config = AppConfig::load()?;
+        config.oauth.insert(
+            "github".to_string(),
+            crate::config::OAuthProviderConfig {
+                client_id: "id".to_string(),
+                client_secret: "secret".to_string(),
+                auth_url: None,
+                token_url: Some(format!("{}/token", base_url)),
+                api_url: Some(base_url),
+            },
+        );
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let ret = oauth_login_handler(State(state.clone()), Path("github".to_string()), Query(OAuthLogin::default()))
+            .await?
+            .into_response();
+        let location = ret.headers()["location"].to_str()?.to_string();
+        assert!(location.starts_with("https://github.com/login/oauth/authorize?"));
+        assert!(location.contains("code_challenge_method=S256"));
+        let oauth_state = location
+            .split("state=")
+            .nth(1)
+            .and_then(|s| s.split('&').next())
+            .expect("authorize url should have a state");
+
+        let input = OAuthCallback { code: Some("code".to_string()), state: oauth_state.to_string(), error: None };
+        let ret = oauth_callback_handler(State(state.clone()), Path("github".to_string()), ClientInfo::default(), HeaderMap::new(), Query(input.clone()))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        assert_eq!(state.dk.verify(&ret.token)?.0.email, "alice@acme.org");
+        // the state is used up
+        let ret = oauth_callback_handler(State(state), Path("github".to_string()), ClientInfo::default(), HeaderMap::new(), Query(input)).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    // an identity provider whose id tokens carry the authorization code as the nonce
+    async fn mock_idp() -> Result<String> {
+        use axum::{extract::Form, routing::{get, post}};
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+        use std::collections::HashMap;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let issuer = format!("http://{}", listener.local_addr()?);
+        let discovery = serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+        });
+        let iss = issuer.clone();
+        let app = axum::Router::new()
+            .route("/.well-known/openid-configuration", get(move || async move { Json(discovery) }))
+            .route(
+                "/token",
+                post(move |Form(form): Form<HashMap<String, String>>| async move {
+                    let claims = serde_json::json!({
+                        "iss": iss, "sub": "idp-42", "aud": "chat", "exp": chrono::Utc::now().timestamp() + 300,
+                        "nonce": form["code"], "email": "sso@acme.org", "email_verified": true, "name": "Sso User",
+                    });
+                    let id_token = format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(claims.to_string()));
+                    Json(serde_json::json!({ "access_token": "at", "id_token": id_token }))
+                }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok(issuer)
+    }
+
+    #[tokio::test]
+    async fn sso_signin_should_provision_user() -> Result<()> {
+        let issuer = mock_idp().await?;
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let input = crate::UpdateOidcConfig {
+            issuer: Some(issuer),
+            client_id: Some("chat".to_string()),
+            client_secret: Some("secret".to_string()),
+            jit_provisioning: None,
+        };
+        OidcConfig::update(1, &input, &state.pool).await?;
+
+        let ret = sso_login_handler(State(state.clone()), Path("acme".to_string()), Query(SsoLogin::default()))
+            .await?
+            .into_response();
+        let location = ret.headers()["location"].to_str()?.to_string();
+        let param = |name: &str| {
+            location
+                .split(&format!("{}=", name))
+                .nth(1)
+                .and_then(|s| s.split('&').next())
+                .map(str::to_string)
+                .expect("authorize url should have the param")
+        };
+        let input = OAuthCallback { code: Some(param("nonce")), state: param("state"), error: None };
+        let ret = sso_callback_handler(State(state.clone()), ClientInfo::default(), HeaderMap::new(), Query(input)).await?.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        let (user, _) = state.dk.verify(&ret.token)?;
+        assert_eq!((user.email.as_str(), user.ws_id), ("sso@acme.org", 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn passkey_signin_should_work() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let user = User::find_by_email("tchen@acme.org", &state.pool).await?.expect("user should exist");
+        let mut authenticator = Authenticator::new(5);
+        let ret = start_passkey_registration_handler(Extension(user.clone()), State(state.clone())).await?.into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let options: serde_json::Value = serde_json::from_slice(&body)?;
+        let credential = authenticator.register(&state.config.webauthn, options["challenge"].as_str().expect("challenge"));
+        let input = FinishPasskeyRegistration { name: Some("phone".to_string()), credential };
+        let ret = finish_passkey_registration_handler(Extension(user.clone()), State(state.clone()), Json(input)).await?.into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+
+        let input = StartPasskeySignin { email: user.email.clone(), remember_me: false };
+        let ret = start_passkey_signin_handler(State(state.clone()), Json(input)).await?.into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let options: serde_json::Value = serde_json::from_slice(&body)?;
+        let credential = authenticator.authenticate(&state.config.webauthn, options["challenge"].as_str().expect("challenge"));
+        let ret = finish_passkey_signin_handler(State(state.clone()), ClientInfo::default(), HeaderMap::new(), Json(FinishPasskeySignin { credential }))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        assert_eq!(state.dk.verify(&ret.token)?.0.id, user.id);
+
+        // presence alone doesn't get past 2fa
+        let enrollment = user.enroll_totp(&state.pool).await?;
+        user.confirm_totp(&totp_code(&enrollment.secret, chrono::Utc::now().timestamp()), &state.pool).await?;
+        authenticator.verify_user = false;
+        let input = StartPasskeySignin { email: user.email.clone(), remember_me: false };
+        let ret = start_passkey_signin_handler(State(state.clone()), Json(input)).await?.into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let options: serde_json::Value = serde_json::from_slice(&body)?;
+        let credential = authenticator.authenticate(&state.config.webauthn, options["challenge"].as_str().expect("challenge"));
+        let ret = finish_passkey_signin_handler(State(state.clone()), ClientInfo::default(), HeaderMap::new(), Json(FinishPasskeySignin { credential }))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(ret["two_factor_required"], true);
+        assert!(ret.get("token").is_none());
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -1,28 +1,343 @@
-use crate::{AppError, AppState, Chat, CreateChat, User};
-use axum::{extract::{Path, State}, http::StatusCode, response::IntoResponse, Extension, Json};
+use super::ensure_email_verified;
+use crate::{audit, feeds::FeedFormat, mailer, pagination::{Pager, Paginated}, revoke_feed_tokens, AddChatMember, AppError, AppState, AuditAction, Chat, ChatInvite, ChatRead, ChatWrite, CreateChat, CreateChatInvite, CreateIncomingWebhook, IncomingWebhook, MarkChatRead, PinMessage, ReadFeed, ReorderPins, RequireScope, UpdateChat, UpdateChatFeed, UpdateChatSettings, User, utils::is_merge_patch};
+use axum::{extract::{Path, Query, State}, http::{header, HeaderMap, StatusCode}, response::IntoResponse, Extension, Json};
 
-pub(crate) async fn list_chat_handler(Extension(user): Extension<User>, State(state): State<AppState>)-> Result<impl IntoResponse, AppError> {
-    let chat = Chat::fetch_all(user.ws_id as _, &state.pool).await?;
-    Ok((StatusCode::OK, Json(chat)))
+// not paged, members are in few enough chats for the sidebar to show them all
+pub(crate) async fn list_chat_handler(_: RequireScope<ChatRead>, Extension(user): Extension<User>, State(state): State<AppState>, mut pager: Pager)-> Result<impl IntoResponse, AppError> {
+    let fingerprint = Chat::list_fingerprint(user.ws_id as _, user.id as _, &state.pool).await?;
+    if let Some(res) = pager.not_modified(&fingerprint) {
+        return Ok(res);
+    }
+    let chats = Chat::list_for_member(user.ws_id as _, user.id as _, &state.pool).await?;
+    Ok(pager.respond(Paginated::all(chats)))
+}
+
+pub(crate) async fn mark_chat_read_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<MarkChatRead>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    chat.mark_read(user.id as _, &input, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
-pub(crate) async fn create_chat_handler(Extension(user): Extension<User>, State(state): State<AppState>, Json(input): Json<CreateChat>) -> Result<impl IntoResponse, AppError> {
-    let chat = Chat::create(&input, user.ws_id as _, &state.pool).await?;
+// clients send it every few seconds while the user types, the chat's members see it as an event
+pub(crate) async fn start_typing_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    chat.start_typing(user.id as _, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn create_chat_handler(_: RequireScope<ChatWrite>, Extension(user): Extension<User>, State(state): State<AppState>, Json(input): Json<CreateChat>) -> Result<impl IntoResponse, AppError> {
+    ensure_email_verified(&user, &state).await?;
+    let chat = Chat::create(&input, user.ws_id as _, user.id as _, &state.pool).await?;
+    let details = serde_json::json!({ "name": chat.name, "type": chat.r#type, "members": chat.members });
+    audit::record(&state.pool, &user, AuditAction::ChatCreated, Some(chat.id), details).await;
     Ok((StatusCode::CREATED, Json(chat)))
 }
 
-pub(crate) async fn get_chat_handler(State(state): State<AppState>, Path(id): Path<u64>) -> Result<impl IntoResponse, AppError> {
-    let chat = Chat::get_by_id(id, &state.pool).await?;
-    match chat {
-        Some(chat) => Ok((StatusCode::OK, Json(chat))),
-        None => Err(AppError::NotFound(format!("chat not found: {}", id))),
-    }
+pub(crate) async fn get_chat_handler(_: RequireScope<ChatRead>, Extension(user): Extension<User>, State(state): State<AppState>, Path(id): Path<u64>) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    let detail = chat.detail(user.id as _, &state.pool).await?;
+    Ok((StatusCode::OK, Json(detail)))
 }
 
-pub(crate) async fn update_chat_handler() -> impl IntoResponse {
-    "update chat"
+pub(crate) async fn update_chat_settings_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateChatSettings>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    let notification_level = chat.update_settings(user.id as _, &input, &state.pool).await?;
+    Ok(Json(serde_json::json!({ "notification_level": notification_level })))
 }
 
-pub(crate) async fn delete_chat_handler() -> impl IntoResponse {
+pub(crate) async fn update_chat_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    Json(input): Json<UpdateChat>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    let merge = is_merge_patch(&headers);
+    // checked first, so a patch that can't be applied whole changes nothing
+    let (added, removed) = match &input.members {
+        Some(Some(members)) => chat.member_changes(members, user.id as _, &state.pool).await?,
+        Some(None) if merge => {
+            return Err(AppError::UpdateChatError("Chat members cannot be cleared".to_string()));
+        }
+        _ => (vec![], vec![]),
+    };
+    let name = match &input.name {
+        Some(Some(name)) => Some(Some(name.as_str())),
+        Some(None) if merge => Some(None),
+        _ => None,
+    };
+    let chat = chat.apply_update(name, &added, &removed, user.id as _, &state.pool).await?;
+    for user_id in added {
+        let details = serde_json::json!({ "chat_id": chat.id });
+        audit::record(&state.pool, &user, AuditAction::MemberAdded, Some(user_id), details).await;
+    }
+    for user_id in removed {
+        let details = serde_json::json!({ "chat_id": chat.id });
+        audit::record(&state.pool, &user, AuditAction::MemberRemoved, Some(user_id), details).await;
+    }
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+pub(crate) async fn delete_chat_handler(_: RequireScope<ChatWrite>) -> impl IntoResponse {
     "delete chat"
-}
\ No newline at end of file
+}
+
+pub(crate) async fn add_chat_member_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<AddChatMember>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    let chat = chat.add_member(input.user_id as _, user.id as _, &state.pool).await?;
+    let details = serde_json::json!({ "chat_id": chat.id });
+    audit::record(&state.pool, &user, AuditAction::MemberAdded, Some(input.user_id), details).await;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+pub(crate) async fn remove_chat_member_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, user_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    let chat = chat.remove_member(user_id, user.id as _, &state.pool).await?;
+    let details = serde_json::json!({ "chat_id": chat.id });
+    audit::record(&state.pool, &user, AuditAction::MemberRemoved, Some(user_id as _), details).await;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+pub(crate) async fn list_chat_member_history_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    if !chat.is_admin(user.id as _, &state.pool).await? {
+        return Err(AppError::PermissionDenied(format!(
+            "only chat admins can view membership history of chat {}",
+            id
+        )));
+    }
+    let history = chat.fetch_member_history(&state.pool).await?;
+    Ok((StatusCode::OK, Json(history)))
+}
+
+pub(crate) async fn create_chat_invite_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<CreateChatInvite>,
+) -> Result<impl IntoResponse, AppError> {
+    ensure_email_verified(&user, &state).await?;
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    let invite = ChatInvite::create(&input, &chat, user.id as _, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(invite)))
+}
+
+pub(crate) async fn join_chat_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = ChatInvite::redeem(&token, &user, &state.pool).await?;
+    let details = serde_json::json!({ "chat_id": chat.id, "via": "invite" });
+    audit::record(&state.pool, &user, AuditAction::MemberAdded, Some(user.id), details).await;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+pub(crate) async fn browse_channels_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let channels = Chat::browse_channels(user.ws_id as _, user.id as _, &state.pool).await?;
+    Ok((StatusCode::OK, Json(channels)))
+}
+
+pub(crate) async fn join_channel_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    let chat = chat.join(user.id as _, &state.pool).await?;
+    let details = serde_json::json!({ "chat_id": chat.id, "via": "browse" });
+    audit::record(&state.pool, &user, AuditAction::MemberAdded, Some(user.id), details).await;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+pub(crate) async fn pin_message_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<PinMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    let pinned = chat.pin_message(&input, user.id as _, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(pinned)))
+}
+
+pub(crate) async fn unpin_message_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    let pinned = chat.unpin_message(message_id, user.id as _, &state.pool).await?;
+    Ok(Json(pinned))
+}
+
+pub(crate) async fn reorder_pins_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<ReorderPins>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    let pinned = chat.reorder_pins(&input, user.id as _, &state.pool).await?;
+    Ok(Json(pinned))
+}
+
+pub(crate) async fn list_incoming_webhooks_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_admin(id, &user, &state).await?;
+    let webhooks = IncomingWebhook::list(chat.id as _, &state.pool).await?;
+    Ok(Json(webhooks))
+}
+
+pub(crate) async fn create_incoming_webhook_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<CreateIncomingWebhook>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_admin(id, &user, &state).await?;
+    let webhook = IncomingWebhook::create(chat.id as _, &input, user.id as _, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+pub(crate) async fn delete_incoming_webhook_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, webhook_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_admin(id, &user, &state).await?;
+    IncomingWebhook::delete(chat.id as _, webhook_id, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_chat_admin(id: u64, user: &User, state: &AppState) -> Result<Chat, AppError> {
+    let chat = get_chat_in_workspace(id, user, state).await?;
+    if !chat.is_admin(user.id as _, &state.pool).await? {
+        return Err(AppError::PermissionDenied(format!(
+            "only chat admins can manage incoming webhooks of chat {}",
+            id
+        )));
+    }
+    Ok(chat)
+}
+
+// the caller's own feed url, and the public one when the chat has a public feed
+pub(crate) async fn get_chat_feed_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    let token = chat.feed_token(user.id as _, &state.pool).await?;
+    let public = chat.has_public_feed(&state.pool).await?;
+    let public_url = mailer::link(&state, &format!("/feeds/chat/{}.atom", chat.id));
+    Ok(Json(serde_json::json!({
+        "public": public,
+        "url": format!("{}?token={}", public_url, token),
+        "public_url": public.then_some(public_url),
+    })))
+}
+
+pub(crate) async fn update_chat_feed_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateChatFeed>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = get_chat_in_workspace(id, &user, &state).await?;
+    chat.set_public_feed(&input, user.id as _, &state.pool).await?;
+    Ok(Json(serde_json::json!({ "public": input.public })))
+}
+
+// every feed url the caller was given stops working
+pub(crate) async fn revoke_feed_tokens_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    revoke_feed_tokens(user.id as _, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// read by feed readers, there is no user session. Feeds that aren't public need a member's
+// token, and don't exist without one.
+pub(crate) async fn chat_feed_handler(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+    Query(input): Query<ReadFeed>,
+) -> Result<impl IntoResponse, AppError> {
+    let (id, format) = FeedFormat::parse(&file)?;
+    let not_found = || AppError::NotFound(format!("feed not found: {}", file));
+    let chat = Chat::get_by_id(id, &state.pool).await?.ok_or_else(not_found)?;
+    let allowed = match &input.token {
+        Some(token) => chat.verify_feed_token(token, &state.pool).await?.is_some(),
+        None => false,
+    };
+    if !allowed && !chat.has_public_feed(&state.pool).await? {
+        return Err(not_found());
+    }
+    let entries = chat.feed_entries(&state.pool).await?;
+    let chat_url = mailer::link(&state, &format!("/chats/{}", chat.id));
+    let self_url = mailer::link(&state, &format!("/feeds/chat/{}", file));
+    let body = format.render(&chat, &entries, &chat_url, &self_url);
+    Ok(([(header::CONTENT_TYPE, format.content_type())], body))
+}
+
+async fn get_chat_in_workspace(id: u64, user: &User, state: &AppState) -> Result<Chat, AppError> {
+    match Chat::get_by_id(id, &state.pool).await? {
+        Some(chat) if chat.ws_id == user.ws_id => Ok(chat),
+        _ => Err(AppError::NotFound(format!("chat not found: {}", id))),
+    }
+}
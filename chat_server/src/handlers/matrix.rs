@@ -0,0 +1,28 @@
+use axum::{extract::{Path, State}, response::IntoResponse, Extension, Json};
+use serde_json::json;
+
+use crate::{matrix::{self, MatrixError, Transaction}, models::MatrixHomeserver, AppState};
+
+// events the homeserver pushes, answered once they are stored so that it can move on
+pub(crate) async fn matrix_transaction_handler(
+    Extension(homeserver): Extension<MatrixHomeserver>,
+    State(state): State<AppState>,
+    Path(_txn_id): Path<String>,
+    Json(txn): Json<Transaction>,
+) -> Result<impl IntoResponse, MatrixError> {
+    matrix::receive(&state, &homeserver, &txn).await?;
+    Ok(Json(json!({})))
+}
+
+// puppets are registered when they first send something, none are made on the homeserver's request
+pub(crate) async fn matrix_user_query_handler(Path(user_id): Path<String>) -> MatrixError {
+    MatrixError::not_found(format!("{} is not provided by this bridge", user_id))
+}
+
+pub(crate) async fn matrix_room_query_handler(Path(alias): Path<String>) -> MatrixError {
+    MatrixError::not_found(format!("{} is not provided by this bridge", alias))
+}
+
+pub(crate) async fn matrix_ping_handler() -> impl IntoResponse {
+    Json(json!({}))
+}
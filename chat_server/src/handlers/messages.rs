@@ -1,9 +1,162 @@
-use axum::response::IntoResponse;
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::IntoResponse, Extension, Json};
+use axum_extra::{headers::{authorization::Bearer, Authorization}, TypedHeader};
 
-pub(crate) async fn send_message_handler() -> impl IntoResponse {
-    "send message"
+use super::ensure_email_verified;
+use crate::{commands::{self, CommandReply}, mail_gateway::{self, InboundEmail}, pagination::{Pager, Paginated}, Activity, ActivityPage, AdminUser, AppError, AppState, Chat, ChatRead, ChatWrite, CreateMessage, CreateReaction, IncomingWebhook, IncomingWebhookMessage, ListActivity, ListMentions, ListMessages, ListNotifications, MarkMentionsRead, Mention, Message, Notification, RequireScope, User};
+
+pub(crate) async fn send_message_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<CreateMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    ensure_email_verified(&user, &state).await?;
+    match commands::send(&input, id, &user, &state).await? {
+        CommandReply::Message(message) => Ok((StatusCode::CREATED, Json(message)).into_response()),
+        // nothing was posted, 202 so clients don't take the reply for a message
+        CommandReply::Ephemeral(text) => {
+            let reply = serde_json::json!({ "response_type": "ephemeral", "text": text });
+            Ok((StatusCode::ACCEPTED, Json(reply)).into_response())
+        }
+    }
+}
+
+// the token in the url is the credential, there is no user session
+pub(crate) async fn incoming_webhook_handler(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Json(input): Json<IncomingWebhookMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(webhook) = IncomingWebhook::find_by_token(&token, &state.pool).await? else {
+        return Err(AppError::NotFound("incoming webhook not found".to_string()));
+    };
+    let message = webhook.post(&input, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(message)))
+}
+
+// replies to notification emails, posted by the inbound mail service with the configured token
+pub(crate) async fn inbound_email_handler(
+    State(state): State<AppState>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(input): Json<InboundEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    let message = mail_gateway::receive(&state, bearer.token(), &input).await?;
+    Ok((StatusCode::CREATED, Json(message)))
+}
+
+// what a masked message said, only for workspace admins
+pub(crate) async fn get_original_message_handler(
+    _: RequireScope<ChatRead>,
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let message = Message::find_original(id, user.ws_id as _, &state.pool).await?;
+    Ok(Json(message))
+}
+
+pub(crate) async fn list_message_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    pager: Pager,
+    Query(input): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    if !Chat::is_member(id, user.id as _, &state.pool).await? {
+        return Err(AppError::NotFound(format!("chat not found: {}", id)));
+    }
+    // the cursor is the seq to continue before, like last_seq
+    let input = ListMessages { last_seq: pager.cursor()?.or(input.last_seq), ..input };
+    let messages = Message::list(&input, id, state.db.reader()).await?;
+    Ok(pager.respond(Paginated::page(messages, input.limit(), |m| m.seq.to_string())))
+}
+
+pub(crate) async fn list_mentions_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListMentions>,
+) -> Result<impl IntoResponse, AppError> {
+    let mentions = Mention::list(user.id as _, &input, &state.pool).await?;
+    let unread = Mention::unread_count(user.id as _, &state.pool).await?;
+    Ok((StatusCode::OK, Json(serde_json::json!({ "unread": unread, "mentions": mentions }))))
+}
+
+pub(crate) async fn mark_mentions_read_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<MarkMentionsRead>,
+) -> Result<impl IntoResponse, AppError> {
+    let updated = Mention::mark_read(user.id as _, &input, &state.pool).await?;
+    Ok((StatusCode::OK, Json(serde_json::json!({ "updated": updated }))))
+}
+
+pub(crate) async fn follow_thread_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    Message::follow_thread(id, user.id as _, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn unfollow_thread_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    Message::unfollow_thread(id, user.id as _, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_notifications_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListNotifications>,
+) -> Result<impl IntoResponse, AppError> {
+    let notifications = Notification::list(user.id as _, &input, &state.pool).await?;
+    Ok((StatusCode::OK, Json(notifications)))
+}
+
+pub(crate) async fn mark_notifications_read_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let updated = Notification::mark_all_read(user.id as _, &state.pool).await?;
+    Ok((StatusCode::OK, Json(serde_json::json!({ "updated": updated }))))
+}
+
+pub(crate) async fn add_reaction_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<CreateReaction>,
+) -> Result<impl IntoResponse, AppError> {
+    let reaction = Message::add_reaction(id, user.id as _, &input.emoji, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(reaction)))
+}
+
+pub(crate) async fn remove_reaction_handler(
+    _: RequireScope<ChatWrite>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, emoji)): Path<(u64, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    Message::remove_reaction(id, user.id as _, &emoji, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
-pub(crate) async fn list_message_handler() -> impl IntoResponse {
-    "list message"
+pub(crate) async fn list_activity_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListActivity>,
+) -> Result<Json<ActivityPage>, AppError> {
+    let page = Activity::list(user.id as _, &input, &state.pool).await?;
+    Ok(Json(page))
 }
@@ -1,15 +1,147 @@
+mod admin;
 mod auth;
 mod chat;
+mod matrix;
 mod messages;
+mod scim;
 mod workspace;
 
-use axum::response::IntoResponse;
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Sse,
+    },
+    Extension, Json,
+};
+use futures::stream;
+use tracing::warn;
 
+pub(crate) use admin::*;
 pub(crate) use auth::*;
 pub(crate) use chat::*;
+pub(crate) use matrix::*;
 pub(crate) use messages::*;
+pub(crate) use scim::*;
 pub(crate) use workspace::*;
 
+use crate::{
+    batch::{self, BatchApi, BatchRequest, BatchResponse},
+    middlewares::ClientIp,
+    realtime, sync, ApiVersion, AppError, AppState, ChangeBatch, ChatRead, CheckStatus, DoctorReport, EventBatch, EventFrame, Message,
+    PollEvents, RequireScope, SyncChanges, User, CURRENT_API_VERSION,
+};
+
 pub(crate) async fn index_handler() -> impl IntoResponse {
     "index"
-}
\ No newline at end of file
+}
+
+// liveness, answers as long as the process is serving requests
+pub(crate) async fn healthz_handler() -> impl IntoResponse {
+    "ok"
+}
+
+// readiness, traffic should only be sent while the database and its schema are usable
+pub(crate) async fn readyz_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let report = DoctorReport::readiness(&state).await;
+    for check in report.checks.iter().filter(|c| c.status != CheckStatus::Ok) {
+        warn!("readiness check {} is {:?}: {}", check.name, check.status, check.message);
+    }
+    let status = if report.is_healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, [(header::CACHE_CONTROL, "no-store")], Json(report.summary()))
+}
+
+// prometheus text format
+pub(crate) async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let body = format!(
+        "# HELP chat_requests_in_flight Requests being handled.\n\
+         # TYPE chat_requests_in_flight gauge\n\
+         chat_requests_in_flight {}\n\
+         # HELP chat_requests_shed_total Requests refused with 503 because request_limits.max_in_flight was reached.\n\
+         # TYPE chat_requests_shed_total counter\n\
+         chat_requests_shed_total {}\n\
+         # HELP chat_event_streams Event streams connected to this instance.\n\
+         # TYPE chat_event_streams gauge\n\
+         chat_event_streams {}\n",
+        state.load_shedder.in_flight(),
+        state.load_shedder.shed(),
+        state.subscribers.len(),
+    );
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+// lets other services verify our access tokens, includes keys rotated out whose tokens may still be in use
+pub(crate) async fn jwks_handler(State(state): State<AppState>) -> impl IntoResponse {
+    ([(header::CACHE_CONTROL, "public, max-age=300")], Json(state.dk.jwks()))
+}
+
+// feature flags turned on for the caller, so clients can gate UI the same way the server does
+pub(crate) async fn list_features_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Json<Vec<String>> {
+    let live = state.live.load();
+    let features = live.features.enabled_for(user.id);
+    Json(features.into_iter().map(String::from).collect())
+}
+
+// server sent events for the caller's chats, made through this instance or any other. Events
+// say what changed, clients load the rest. The stream ends when the client falls behind or the
+// instance shuts down, clients reconnect and reload.
+pub(crate) async fn events_handler(_: RequireScope<ChatRead>, Extension(user): Extension<User>, State(state): State<AppState>) -> impl IntoResponse {
+    let subscription = state.subscribers.subscribe(user.id);
+    let events = stream::unfold(subscription, |mut subscription| async move {
+        let event = subscription.recv().await?;
+        Some((Event::default().json_data(EventFrame::from(event)), subscription))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+// for clients whose proxies cut event streams, a batch of events after the cursor. Waits for
+// one when there are none yet, up to the timeout.
+pub(crate) async fn poll_events_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<PollEvents>,
+) -> Result<Json<EventBatch>, AppError> {
+    Ok(Json(realtime::poll(&state, &user, &input).await?))
+}
+
+// for clients back from being offline, what changed in their chats after since
+pub(crate) async fn sync_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<SyncChanges>,
+) -> Result<Json<ChangeBatch<Message>>, AppError> {
+    Ok(Json(sync::changes(&state, &user, &input).await?))
+}
+
+// several api requests in one round trip, e.g. what a client loads on start
+pub(crate) async fn batch_handler(
+    Extension(api): Extension<BatchApi>,
+    version: Option<Extension<ApiVersion>>,
+    ip: Option<Extension<ClientIp>>,
+    headers: HeaderMap,
+    Json(requests): Json<Vec<BatchRequest>>,
+) -> Result<Json<Vec<BatchResponse>>, AppError> {
+    let version = version.map(|Extension(v)| v).unwrap_or(ApiVersion(CURRENT_API_VERSION));
+    let responses = batch::run(&api, &headers, version, ip.map(|Extension(ip)| ip), requests).await?;
+    Ok(Json(responses))
+}
+
+// guards actions that unverified users cannot take
+pub(crate) async fn ensure_email_verified(user: &User, state: &AppState) -> Result<(), AppError> {
+    if !user.is_email_verified(&state.pool).await? {
+        return Err(AppError::PermissionDenied(
+            "verify your email address first".to_string(),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,249 @@
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::IntoResponse, Extension, Json};
+use serde_json::Value;
+
+use crate::{mailer, scim::{self, parse_id, GroupResource, ListQuery, ListResponse, PatchRequest, ScimError, ScimJson, UserResource}, AppState, ScimGroup, ScimUser, Workspace};
+
+type ScimResult<T> = Result<ScimJson<T>, ScimError>;
+
+pub(crate) async fn service_provider_config_handler(State(state): State<AppState>) -> ScimJson<Value> {
+    ScimJson(StatusCode::OK, scim::service_provider_config(&mailer::link(&state, "")))
+}
+
+pub(crate) async fn list_scim_users_handler(
+    Extension(ws): Extension<Workspace>,
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
+) -> ScimResult<ListResponse<UserResource>> {
+    let filter = query.filter()?;
+    if filter.display_name.is_some() {
+        return Err(ScimError::invalid_filter("users cannot be filtered by displayName"));
+    }
+    let (start_index, offset, limit) = query.page();
+    let (users, total) = ScimUser::list(ws.id as _, &filter, offset, limit, &state.pool).await?;
+    let base_url = mailer::link(&state, "");
+    let users = users.iter().map(|u| UserResource::new(u, &base_url)).collect();
+    Ok(ScimJson(StatusCode::OK, ListResponse::new(users, total, start_index)))
+}
+
+pub(crate) async fn get_scim_user_handler(
+    Extension(ws): Extension<Workspace>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> ScimResult<UserResource> {
+    let user = find_user(&ws, &id, &state).await?;
+    Ok(ScimJson(StatusCode::OK, UserResource::new(&user, &mailer::link(&state, ""))))
+}
+
+pub(crate) async fn create_scim_user_handler(
+    Extension(ws): Extension<Workspace>,
+    State(state): State<AppState>,
+    Json(input): Json<UserResource>,
+) -> ScimResult<UserResource> {
+    let user = ScimUser::create(ws.id as _, &input.into_input(), &state.pool).await?;
+    Ok(ScimJson(StatusCode::CREATED, UserResource::new(&user, &mailer::link(&state, ""))))
+}
+
+pub(crate) async fn replace_scim_user_handler(
+    Extension(ws): Extension<Workspace>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(input): Json<UserResource>,
+) -> ScimResult<UserResource> {
+    let user = find_user(&ws, &id, &state).await?;
+    let user = ScimUser::update(ws.id as _, user.id as _, &input.into_input(), &state.pool).await?;
+    Ok(ScimJson(StatusCode::OK, UserResource::new(&user, &mailer::link(&state, ""))))
+}
+
+pub(crate) async fn patch_scim_user_handler(
+    Extension(ws): Extension<Workspace>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(patch): Json<PatchRequest>,
+) -> ScimResult<UserResource> {
+    let user = find_user(&ws, &id, &state).await?;
+    let base_url = mailer::link(&state, "");
+    let mut input = UserResource::new(&user, &base_url).into_input();
+    patch.apply_to_user(&mut input)?;
+    let user = ScimUser::update(ws.id as _, user.id as _, &input, &state.pool).await?;
+    Ok(ScimJson(StatusCode::OK, UserResource::new(&user, &base_url)))
+}
+
+// members are never deleted, their messages stay. Deleting deactivates them instead.
+pub(crate) async fn delete_scim_user_handler(
+    Extension(ws): Extension<Workspace>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ScimError> {
+    let user = find_user(&ws, &id, &state).await?;
+    let mut input = UserResource::new(&user, "").into_input();
+    input.active = false;
+    ScimUser::update(ws.id as _, user.id as _, &input, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_scim_groups_handler(
+    Extension(ws): Extension<Workspace>,
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
+) -> ScimResult<ListResponse<GroupResource>> {
+    let filter = query.filter()?;
+    if filter.email.is_some() {
+        return Err(ScimError::invalid_filter("groups can only be filtered by displayName or externalId"));
+    }
+    let (start_index, offset, limit) = query.page();
+    let (groups, total) = ScimGroup::list(ws.id as _, &filter, offset, limit, &state.pool).await?;
+    let base_url = mailer::link(&state, "");
+    let groups = groups.iter().map(|g| GroupResource::new(g, &base_url)).collect();
+    Ok(ScimJson(StatusCode::OK, ListResponse::new(groups, total, start_index)))
+}
+
+pub(crate) async fn get_scim_group_handler(
+    Extension(ws): Extension<Workspace>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> ScimResult<GroupResource> {
+    let group = find_group(&ws, &id, &state).await?;
+    Ok(ScimJson(StatusCode::OK, GroupResource::new(&group, &mailer::link(&state, ""))))
+}
+
+pub(crate) async fn create_scim_group_handler(
+    Extension(ws): Extension<Workspace>,
+    State(state): State<AppState>,
+    Json(input): Json<GroupResource>,
+) -> ScimResult<GroupResource> {
+    let group = ScimGroup::create(&ws, &input.into_input()?, &state.pool).await?;
+    Ok(ScimJson(StatusCode::CREATED, GroupResource::new(&group, &mailer::link(&state, ""))))
+}
+
+pub(crate) async fn replace_scim_group_handler(
+    Extension(ws): Extension<Workspace>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(input): Json<GroupResource>,
+) -> ScimResult<GroupResource> {
+    let group = find_group(&ws, &id, &state).await?;
+    let group = ScimGroup::update(ws.id as _, group.id as _, &input.into_input()?, &state.pool).await?;
+    Ok(ScimJson(StatusCode::OK, GroupResource::new(&group, &mailer::link(&state, ""))))
+}
+
+pub(crate) async fn patch_scim_group_handler(
+    Extension(ws): Extension<Workspace>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(patch): Json<PatchRequest>,
+) -> ScimResult<GroupResource> {
+    let group = find_group(&ws, &id, &state).await?;
+    let base_url = mailer::link(&state, "");
+    let mut input = GroupResource::new(&group, &base_url).into_input()?;
+    patch.apply_to_group(&mut input)?;
+    let group = ScimGroup::update(ws.id as _, group.id as _, &input, &state.pool).await?;
+    Ok(ScimJson(StatusCode::OK, GroupResource::new(&group, &base_url)))
+}
+
+// the group's channel is kept along with its members
+pub(crate) async fn delete_scim_group_handler(
+    Extension(ws): Extension<Workspace>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ScimError> {
+    let group = find_group(&ws, &id, &state).await?;
+    ScimGroup::delete(ws.id as _, group.id as _, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn find_user(ws: &Workspace, id: &str, state: &AppState) -> Result<ScimUser, ScimError> {
+    ScimUser::find(ws.id as _, parse_id(id)? as _, &state.pool)
+        .await?
+        .ok_or_else(|| ScimError::not_found(format!("user {} not found", id)))
+}
+
+async fn find_group(ws: &Workspace, id: &str, state: &AppState) -> Result<ScimGroup, ScimError> {
+    ScimGroup::find(ws.id as _, parse_id(id)? as _, &state.pool)
+        .await?
+        .ok_or_else(|| ScimError::not_found(format!("group {} not found", id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppConfig;
+    use anyhow::Result;
+    use axum::response::Response;
+    use http_body_util::BodyExt;
+    use serde_json::json;
+
+    async fn body(res: Response) -> Result<Value> {
+        let body = res.into_body().collect().await?.to_bytes();
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    #[tokio::test]
+    async fn scim_user_lifecycle_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let ws = Workspace::find_by_id(1, &state.pool).await?.expect("workspace should exist");
+        let input: UserResource = serde_json::from_value(json!({
+            "schemas": ["urn:ietf:params:scim:schemas:core:2.0:User"],
+            "userName": "eve@acme.org",
+            "name": { "givenName": "Eve", "familyName": "Chen" },
+            "externalId": "okta-eve",
+            "active": true,
+        }))?;
+        let ret = create_scim_user_handler(Extension(ws.clone()), State(state.clone()), Json(input.clone()))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+        let user = body(ret).await?;
+        assert_eq!(user["displayName"], "Eve Chen");
+        assert_eq!(user["emails"][0]["value"], "eve@acme.org");
+        let id = user["id"].as_str().expect("id").to_string();
+
+        let ret = create_scim_user_handler(Extension(ws.clone()), State(state.clone()), Json(input)).await;
+        let ret = ret.expect_err("email is taken").into_response();
+        assert_eq!(ret.status(), StatusCode::CONFLICT);
+        assert_eq!(body(ret).await?["scimType"], "uniqueness");
+
+        let query = ListQuery { filter: Some(r#"userName eq "eve@acme.org""#.to_string()), ..Default::default() };
+        let ScimJson(_, list) =
+            list_scim_users_handler(Extension(ws.clone()), State(state.clone()), Query(query)).await?;
+        assert_eq!(list.total_results, 1);
+        assert_eq!(list.resources[0].id.as_deref(), Some(id.as_str()));
+
+        let patch: PatchRequest = serde_json::from_value(json!({
+            "Operations": [{ "op": "replace", "value": { "active": false } }],
+        }))?;
+        let ScimJson(_, user) =
+            patch_scim_user_handler(Extension(ws.clone()), State(state.clone()), Path(id.clone()), Json(patch)).await?;
+        assert!(!user.active);
+        assert_eq!(user.external_id.as_deref(), Some("okta-eve"));
+
+        let ret = get_scim_user_handler(Extension(ws), State(state), Path("nope".to_string())).await;
+        assert_eq!(ret.expect_err("not a user id").into_response().status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scim_group_patch_should_update_channel() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let ws = Workspace::find_by_id(1, &state.pool).await?.expect("workspace should exist");
+        let input: GroupResource = serde_json::from_value(json!({
+            "displayName": "Engineering",
+            "members": [{ "value": "2" }],
+        }))?;
+        let ScimJson(_, group) =
+            create_scim_group_handler(Extension(ws.clone()), State(state.clone()), Json(input)).await?;
+        let id = group.id.expect("id");
+        let patch: PatchRequest = serde_json::from_value(json!({
+            "Operations": [{ "op": "add", "path": "members", "value": [{ "value": "4" }] }],
+        }))?;
+        let ScimJson(_, group) =
+            patch_scim_group_handler(Extension(ws.clone()), State(state.clone()), Path(id.clone()), Json(patch)).await?;
+        let members: Vec<&str> = group.members.iter().map(|m| m.value.as_str()).collect();
+        assert_eq!(members, vec!["2", "4"]);
+
+        let ret = delete_scim_group_handler(Extension(ws.clone()), State(state.clone()), Path(id.clone())).await?;
+        assert_eq!(ret.into_response().status(), StatusCode::NO_CONTENT);
+        let ret = get_scim_group_handler(Extension(ws), State(state), Path(id)).await;
+        assert_eq!(ret.expect_err("group is gone").into_response().status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
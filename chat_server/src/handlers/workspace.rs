@@ -1,11 +1,896 @@
-use axum::{extract::State, Extension, Json};
+use std::time::{Duration, Instant};
 
-use crate::{AppError, AppState, ChatUser, User, Workspace};
+use axum::{body::Body, extract::{Path, Query, State}, http::{header, StatusCode}, response::{IntoResponse, Redirect, Response}, Extension, Json};
+use futures::{stream, StreamExt};
+use tokio::sync::mpsc;
+use tracing::warn;
 
+use crate::{audit, matrix, mailer::{self, EmailPreview, EmailTemplate, SendTestEmail}, middlewares::ClientIp, pagination::{Pager, Paginated}, utils::{csv_record, txt_records}, ActionReport, AdminApproval, AdminUser, AppError, AppState, ApprovalStatus, AuditAction, AuditLog, AuthEvent, Bot, BridgeIdentity, ChatRead, CloneWorkspace, CreateBot, CreateCustomEmoji, CreateOAuthApp, CreateSlashCommand, CreateWebhook, CustomEmoji, DestructiveAction, ExportMembers, IpAllowlist, ListAuditLogs, ListAuthEvents, ListChatUsers, LinkMatrixRoom, ListWebhookDeliveries, MailSettings, MatrixBridge, MatrixRoom, ModerationPolicy, OAuthApp, OidcConfig, RemoteIdentity, RequireScope, ScimSettings, SessionPolicy, SignupDomain, SlashCommand, SubmitAction, Submitted, UpdateIpAllowlist, UpdateMailSettings, UpdateMatrixBridge, UpdateModerationPolicy, UpdateMemberRole, UpdateOidcConfig, UpdateSessionPolicy, UpdateWorkspace, User, VerifyMailSettings, Webhook, WebhookDelivery, Workspace, WorkspaceRole, WorkspaceStats};
+
+const STATS_CACHE_TTL: Duration = Duration::from_secs(30);
+
+// the cursor is the offset of the next page
 pub(crate) async fn list_chat_users_handler(
+    _: RequireScope<ChatRead>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    mut pager: Pager,
+    Query(input): Query<ListChatUsers>,
+) -> Result<impl IntoResponse, AppError> {
+    let offset = pager.cursor::<u64>()?.or(input.offset).unwrap_or_default();
+    let input = ListChatUsers { offset: Some(offset), ..input };
+    let fingerprint = Workspace::chat_users_fingerprint(user.ws_id as _, &input, state.db.reader()).await?;
+    if let Some(res) = pager.not_modified(&fingerprint) {
+        return Ok(res);
+    }
+    let users = Workspace::search_chat_users(user.ws_id as _, &input, state.db.reader()).await?;
+    let total = Workspace::count_chat_users(user.ws_id as _, &input, state.db.reader()).await?;
+    let n = users.len() as u64;
+    let page = Paginated::page(users, input.limit(), |_| (offset + n).to_string()).with_total(total);
+    Ok(pager.respond(page))
+}
+
+pub(crate) async fn workspace_stats_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>
-) -> Result<Json<Vec<ChatUser>>, AppError> {
-    let users = Workspace::fetch_all_chat_users(user.ws_id as _, &state.pool).await?;
-    Ok(Json(users))
+) -> Result<Json<WorkspaceStats>, AppError> {
+    let cached = state
+        .stats_cache
+        .read()
+        .expect("stats cache poisoned")
+        .get(&user.ws_id)
+        .filter(|(at, _)| at.elapsed() < STATS_CACHE_TTL)
+        .map(|(_, stats)| stats.clone());
+    if let Some(stats) = cached {
+        return Ok(Json(stats));
+    }
+
+    let stats = Workspace::fetch_stats(user.ws_id as _, state.db.reader()).await?;
+    state
+        .stats_cache
+        .write()
+        .expect("stats cache poisoned")
+        .insert(user.ws_id, (Instant::now(), stats.clone()));
+    Ok(Json(stats))
+}
+
+pub(crate) async fn list_audit_logs_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Query(input): Query<ListAuditLogs>,
+) -> Result<Json<Vec<AuditLog>>, AppError> {
+    let logs = AuditLog::list(user.ws_id as _, &input, &state.pool).await?;
+    Ok(Json(logs))
+}
+
+// auth events of every member, or of one with `user_id`
+pub(crate) async fn list_workspace_security_events_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Query(input): Query<ListAuthEvents>,
+) -> Result<Json<Vec<AuthEvent>>, AppError> {
+    let events = AuthEvent::list(user.ws_id as _, &input, &state.pool).await?;
+    Ok(Json(events))
+}
+
+// plain text view of what an audited change did, one line per field
+pub(crate) async fn get_audit_log_changes_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let log = AuditLog::find(user.ws_id as _, id, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("audit log {}", id)))?;
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], audit::describe(&log)))
+}
+
+pub(crate) async fn update_workspace_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateWorkspace>,
+) -> Result<Json<Workspace>, AppError> {
+    let before = Workspace::fetch_settings(user.ws_id as _, &state.pool).await?;
+    if input.require_approval == Some(false) && before.require_approval {
+        return Err(AppError::UpdateWorkspaceError(
+            "Turning off approvals needs a second admin, submit it as a destructive action".to_string(),
+        ));
+    }
+    let mut ws = Workspace::find_by_id(user.ws_id as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("workspace not found: {}", user.ws_id)))?;
+    if let Some(name) = &input.name {
+        ws = ws.rename(name, &state.pool).await?;
+    }
+    if let Some(max_pins) = input.max_pins_per_chat {
+        ws.set_max_pins_per_chat(max_pins, &state.pool).await?;
+    }
+    if let Some(domain) = &input.signup_domain {
+        ws.set_signup_domain(domain, &state.pool).await?;
+    }
+    if input.require_approval == Some(true) {
+        ws.enable_approvals(&state.pool).await?;
+    }
+    let after = Workspace::fetch_settings(user.ws_id as _, &state.pool).await?;
+    audit::record_change(&state.pool, &user, "workspace", Some(&before), Some(&after)).await;
+    Ok(Json(ws))
+}
+
+pub(crate) async fn get_signup_domain_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<SignupDomain>, AppError> {
+    let signup = Workspace::fetch_signup_domain(user.ws_id as _, &state.pool).await?;
+    Ok(Json(signup))
+}
+
+// looks up the TXT record of the pending signup domain, which takes effect once it's there
+pub(crate) async fn verify_signup_domain_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<SignupDomain>, AppError> {
+    let before = Workspace::fetch_settings(user.ws_id as _, &state.pool).await?;
+    let ws = Workspace::find_by_id(user.ws_id as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("workspace not found: {}", user.ws_id)))?;
+    let pending = Workspace::fetch_signup_domain(user.ws_id as _, &state.pool).await?;
+    let Some(name) = &pending.record_name else {
+        return Err(AppError::UpdateWorkspaceError("No signup domain is waiting for verification".to_string()));
+    };
+    let records = txt_records(name).await?;
+    let signup = ws.verify_signup_domain(&records, &state.pool).await?;
+    let after = Workspace::fetch_settings(user.ws_id as _, &state.pool).await?;
+    audit::record_change(&state.pool, &user, "workspace", Some(&before), Some(&after)).await;
+    Ok(Json(signup))
+}
+
+// a copy of the workspace to try things out on, e.g. for staging or training
+pub(crate) async fn clone_workspace_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<CloneWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = Workspace::clone_workspace(user.ws_id as _, &input, &state.pool).await?;
+    let details = serde_json::json!({ "cloned_to": ws.id, "include_messages": input.include_messages });
+    audit::record(&state.pool, &user, AuditAction::WorkspaceCloned, Some(ws.id), details).await;
+    Ok((StatusCode::CREATED, Json(ws)))
+}
+
+pub(crate) async fn update_member_role_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateMemberRole>,
+) -> Result<Response, AppError> {
+    // promotions go through the approval flow, 202 when a second admin has to approve it
+    if input.role == WorkspaceRole::Admin {
+        let action = DestructiveAction::PromoteToAdmin { user_id: id as _ };
+        return submit_action(&state, &user, action, false).await;
+    }
+    user.set_role(id, input.role, &state.pool).await?;
+    let details = serde_json::json!({ "role": input.role });
+    audit::record(&state.pool, &user, AuditAction::RoleChanged, Some(id as _), details).await;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// 202 with the pending approval when a second admin has to approve it, the report of what was
+// deleted once done. With dry_run nothing is changed and the report is what would be deleted.
+pub(crate) async fn submit_destructive_action_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Query(input): Query<SubmitAction>,
+    Json(action): Json<DestructiveAction>,
+) -> Result<Response, AppError> {
+    submit_action(&state, &user, action, input.dry_run).await
+}
+
+pub(super) async fn submit_action(
+    state: &AppState,
+    user: &User,
+    action: DestructiveAction,
+    dry_run: bool,
+) -> Result<Response, AppError> {
+    if dry_run {
+        let report = AdminApproval::dry_run(user, &action, &state.pool).await?;
+        return Ok(Json(report).into_response());
+    }
+    let details = serde_json::json!({ "action": action });
+    match AdminApproval::submit(user, action, &state.pool).await? {
+        Submitted::Pending(approval) => {
+            audit::record(&state.pool, user, AuditAction::ApprovalRequested, Some(approval.id), details).await;
+            Ok((StatusCode::ACCEPTED, Json(approval)).into_response())
+        }
+        Submitted::Done(report) => {
+            // no second admin needed, recorded as approved by the requester
+            let details = serde_json::json!({
+                "action": details["action"],
+                "status": ApprovalStatus::Approved,
+                "report": report,
+            });
+            audit::record(&state.pool, user, AuditAction::ApprovalDecided, None, details).await;
+            Ok(Json(report).into_response())
+        }
+    }
+}
+
+pub(crate) async fn dry_run_approval_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<ActionReport>, AppError> {
+    let report = AdminApproval::dry_run_pending(id, &user, &state.pool).await?;
+    Ok(Json(report))
+}
+
+pub(crate) async fn list_approvals_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<AdminApproval>>, AppError> {
+    let approvals = AdminApproval::list(user.ws_id as _, &state.pool).await?;
+    Ok(Json(approvals))
+}
+
+pub(crate) async fn approve_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<AdminApproval>, AppError> {
+    let approval = AdminApproval::approve(id, &user, &state.pool).await?;
+    record_decision(&state, &user, &approval).await;
+    Ok(Json(approval))
+}
+
+pub(crate) async fn reject_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<AdminApproval>, AppError> {
+    let approval = AdminApproval::reject(id, &user, &state.pool).await?;
+    record_decision(&state, &user, &approval).await;
+    Ok(Json(approval))
+}
+
+async fn record_decision(state: &AppState, user: &User, approval: &AdminApproval) {
+    let details = serde_json::json!({
+        "action": approval.action,
+        "status": approval.status,
+        "report": approval.report,
+    });
+    audit::record(&state.pool, user, AuditAction::ApprovalDecided, Some(approval.id), details).await;
+}
+
+// public lookup, old slugs redirect to the current one
+pub(crate) async fn get_workspace_by_slug_handler(
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+) -> Result<Response, AppError> {
+    let ws = Workspace::find_by_slug(&slug, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("workspace not found: {}", slug)))?;
+    if ws.slug != slug {
+        return Ok(Redirect::permanent(&format!("/api/workspaces/{}", ws.slug)).into_response());
+    }
+    Ok(Json(ws).into_response())
+}
+
+pub(crate) async fn list_webhooks_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Webhook>>, AppError> {
+    let webhooks = Webhook::list(user.ws_id as _, &state.pool).await?;
+    Ok(Json(webhooks))
+}
+
+pub(crate) async fn create_webhook_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<CreateWebhook>,
+) -> Result<impl IntoResponse, AppError> {
+    let webhook = Webhook::create(user.ws_id as _, &input, user.id as _, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+pub(crate) async fn delete_webhook_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    Webhook::delete(user.ws_id as _, id, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_webhook_deliveries_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Query(input): Query<ListWebhookDeliveries>,
+) -> Result<Json<Vec<WebhookDelivery>>, AppError> {
+    let deliveries = WebhookDelivery::list(user.ws_id as _, id, &input, &state.pool).await?;
+    Ok(Json(deliveries))
+}
+
+pub(crate) async fn list_bots_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Bot>>, AppError> {
+    let bots = Bot::list(user.ws_id as _, &state.pool).await?;
+    Ok(Json(bots))
+}
+
+pub(crate) async fn create_bot_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<CreateBot>,
+) -> Result<impl IntoResponse, AppError> {
+    let bot = Bot::create(user.ws_id as _, &input, user.id as _, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(bot)))
+}
+
+pub(crate) async fn rotate_bot_token_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let bot = Bot::rotate_token(user.ws_id as _, id, &state.pool).await?;
+    Ok(Json(bot))
+}
+
+pub(crate) async fn delete_bot_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    Bot::delete(user.ws_id as _, id, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_oauth_apps_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<OAuthApp>>, AppError> {
+    let apps = OAuthApp::list(user.ws_id as _, &state.pool).await?;
+    Ok(Json(apps))
+}
+
+pub(crate) async fn create_oauth_app_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<CreateOAuthApp>,
+) -> Result<impl IntoResponse, AppError> {
+    let app = OAuthApp::create(user.ws_id as _, &input, user.id as _, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(app)))
+}
+
+pub(crate) async fn delete_oauth_app_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    OAuthApp::delete(user.ws_id as _, id, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_slash_commands_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<SlashCommand>>, AppError> {
+    let commands = SlashCommand::list(user.ws_id as _, &state.pool).await?;
+    Ok(Json(commands))
+}
+
+pub(crate) async fn create_slash_command_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<CreateSlashCommand>,
+) -> Result<impl IntoResponse, AppError> {
+    let command = SlashCommand::create(user.ws_id as _, &input, user.id as _, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(command)))
+}
+
+pub(crate) async fn delete_slash_command_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    SlashCommand::delete(user.ws_id as _, id, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_custom_emoji_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<CustomEmoji>>, AppError> {
+    let emoji = CustomEmoji::list(user.ws_id as _, &state.pool).await?;
+    Ok(Json(emoji))
+}
+
+pub(crate) async fn create_custom_emoji_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateCustomEmoji>,
+) -> Result<impl IntoResponse, AppError> {
+    let emoji = CustomEmoji::create(user.ws_id as _, &input, user.id as _, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(emoji)))
+}
+
+// by whoever added it, or a workspace admin
+pub(crate) async fn delete_custom_emoji_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let emoji = CustomEmoji::find(user.ws_id as _, &name, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("emoji {}", name)))?;
+    if emoji.created_by != user.id && !user.is_workspace_admin(&state.pool).await? {
+        return Err(AppError::PermissionDenied(
+            "only the creator or a workspace admin can delete an emoji".to_string(),
+        ));
+    }
+    emoji.delete(&state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn get_session_policy_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<Json<SessionPolicy>, AppError> {
+    let policy = SessionPolicy::fetch(user.ws_id as _, &state.pool).await?;
+    Ok(Json(policy))
+}
+
+pub(crate) async fn update_session_policy_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateSessionPolicy>,
+) -> Result<Json<SessionPolicy>, AppError> {
+    let before = SessionPolicy::fetch(user.ws_id as _, &state.pool).await?;
+    let policy = SessionPolicy::update(user.ws_id as _, &input, &state.pool).await?;
+    audit::record_change(&state.pool, &user, "session_policy", Some(&before), Some(&policy)).await;
+    Ok(Json(policy))
+}
+
+pub(crate) async fn get_moderation_policy_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<Json<ModerationPolicy>, AppError> {
+    let policy = ModerationPolicy::fetch(user.ws_id as _, &state.pool).await?;
+    Ok(Json(policy))
+}
+
+pub(crate) async fn update_moderation_policy_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateModerationPolicy>,
+) -> Result<Json<ModerationPolicy>, AppError> {
+    let before = ModerationPolicy::fetch(user.ws_id as _, &state.pool).await?;
+    let policy = ModerationPolicy::update(user.ws_id as _, &input, &state.pool).await?;
+    audit::record_change(&state.pool, &user, "moderation_policy", Some(&before), Some(&policy)).await;
+    Ok(Json(policy))
+}
+
+pub(crate) async fn get_ip_allowlist_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<IpAllowlist>, AppError> {
+    let allowlist = IpAllowlist::fetch(user.ws_id as _, &state.pool).await?;
+    Ok(Json(allowlist))
+}
+
+pub(crate) async fn update_ip_allowlist_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    client_ip: Option<Extension<ClientIp>>,
+    Json(input): Json<UpdateIpAllowlist>,
+) -> Result<Json<IpAllowlist>, AppError> {
+    let ip = client_ip.as_ref().map(|Extension(ClientIp(ip))| ip.as_str());
+    let before = IpAllowlist::fetch(user.ws_id as _, &state.pool).await?;
+    let allowlist = IpAllowlist::update(user.ws_id as _, &input, ip, &state.pool).await?;
+    audit::record_change(&state.pool, &user, "ip_allowlist", Some(&before), Some(&allowlist)).await;
+    Ok(Json(allowlist))
+}
+
+pub(crate) async fn get_scim_settings_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<ScimSettings>, AppError> {
+    let ws = scim_workspace(&user, &state).await?;
+    Ok(Json(ws.scim_settings(&state.pool).await?))
+}
+
+// the token is only shown here, issuing another one replaces it
+pub(crate) async fn issue_scim_token_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = scim_workspace(&user, &state).await?;
+    let before = ws.scim_settings(&state.pool).await?;
+    let issued = ws.issue_scim_token(&state.pool).await?;
+    let after = ScimSettings { token_created_at: Some(issued.created_at) };
+    audit::record_change(&state.pool, &user, "scim", Some(&before), Some(&after)).await;
+    Ok((StatusCode::CREATED, Json(issued)))
+}
+
+pub(crate) async fn revoke_scim_token_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = scim_workspace(&user, &state).await?;
+    let before = ws.scim_settings(&state.pool).await?;
+    ws.revoke_scim_token(&state.pool).await?;
+    audit::record_change(&state.pool, &user, "scim", Some(&before), Some(&ScimSettings::default())).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn scim_workspace(user: &User, state: &AppState) -> Result<Workspace, AppError> {
+    Workspace::find_by_id(user.ws_id as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("workspace {}", user.ws_id)))
+}
+
+pub(crate) async fn get_oidc_config_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<OidcConfig>, AppError> {
+    let config = OidcConfig::fetch(user.ws_id as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("sso is not configured".to_string()))?;
+    Ok(Json(config))
+}
+
+pub(crate) async fn update_oidc_config_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateOidcConfig>,
+) -> Result<Json<OidcConfig>, AppError> {
+    let before = OidcConfig::fetch(user.ws_id as _, &state.pool).await?;
+    let config = OidcConfig::update(user.ws_id as _, &input, &state.pool).await?;
+    audit::record_change(&state.pool, &user, "sso", before.as_ref(), Some(&config)).await;
+    Ok(Json(config))
+}
+
+pub(crate) async fn delete_oidc_config_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let before = OidcConfig::fetch(user.ws_id as _, &state.pool).await?;
+    OidcConfig::delete(user.ws_id as _, &state.pool).await?;
+    audit::record_change(&state.pool, &user, "sso", before.as_ref(), None).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn get_mail_settings_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<MailSettings>, AppError> {
+    let settings = MailSettings::fetch(user.ws_id as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("mail settings are not configured".to_string()))?;
+    Ok(Json(settings))
+}
+
+// the instance mailer keeps being used until the link mailed through the new settings is opened
+pub(crate) async fn update_mail_settings_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateMailSettings>,
+) -> Result<impl IntoResponse, AppError> {
+    // every update mails the new from address
+    mailer::check_requested_limit(user.id, &state.pool).await?;
+    let before = MailSettings::fetch(user.ws_id as _, &state.pool).await?;
+    let (settings, token) = MailSettings::update(user.ws_id as _, &input, &state.pool).await?;
+    audit::record_change(&state.pool, &user, "mail", before.as_ref(), Some(&settings)).await;
+    mailer::send_mail_verification(&state, &settings, &token, user.id).await?;
+    Ok((StatusCode::ACCEPTED, Json(settings)))
+}
+
+pub(crate) async fn delete_mail_settings_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let before = MailSettings::fetch(user.ws_id as _, &state.pool).await?;
+    MailSettings::delete(user.ws_id as _, &state.pool).await?;
+    audit::record_change(&state.pool, &user, "mail", before.as_ref(), None).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn verify_mail_settings_handler(
+    State(state): State<AppState>,
+    Query(input): Query<VerifyMailSettings>,
+) -> Result<Json<MailSettings>, AppError> {
+    let settings = MailSettings::verify(&input.token, &state.pool).await?;
+    Ok(Json(settings))
+}
+
+pub(crate) async fn list_bridge_identities_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<BridgeIdentity>>, AppError> {
+    let identities = BridgeIdentity::list(user.ws_id as _, &state.pool).await?;
+    Ok(Json(identities))
+}
+
+// called by bridges for every incoming message, returns the user to send it as
+pub(crate) async fn resolve_bridge_identity_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<RemoteIdentity>,
+) -> Result<Json<BridgeIdentity>, AppError> {
+    let identity = BridgeIdentity::resolve(user.ws_id as _, &input, &state.pool).await?;
+    Ok(Json(identity))
+}
+
+pub(crate) async fn delete_bridge_identity_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    BridgeIdentity::delete(user.ws_id as _, id, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn get_matrix_bridge_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<MatrixBridge>, AppError> {
+    let bridge = MatrixBridge::get(user.ws_id as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("matrix bridge".to_string()))?;
+    Ok(Json(bridge))
+}
+
+// the tokens for the homeserver's registration file are only returned when the bridge is set up
+pub(crate) async fn update_matrix_bridge_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateMatrixBridge>,
+) -> Result<Json<MatrixBridge>, AppError> {
+    let bridge = MatrixBridge::update(user.ws_id as _, &input, user.id as _, &state.pool).await?;
+    Ok(Json(bridge))
+}
+
+pub(crate) async fn delete_matrix_bridge_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    MatrixBridge::delete(user.ws_id as _, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn rotate_matrix_tokens_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<MatrixBridge>, AppError> {
+    let bridge = MatrixBridge::rotate_tokens(user.ws_id as _, &state.pool).await?;
+    Ok(Json(bridge))
+}
+
+pub(crate) async fn list_matrix_rooms_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<MatrixRoom>>, AppError> {
+    let rooms = MatrixRoom::list(user.ws_id as _, &state.pool).await?;
+    Ok(Json(rooms))
+}
+
+// the bridge's matrix user joins the room first, it has to be invited to rooms that need it
+pub(crate) async fn link_matrix_room_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<LinkMatrixRoom>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(homeserver) = MatrixBridge::homeserver(user.ws_id as _, &state.pool).await? else {
+        return Err(AppError::NotFound("matrix bridge is not set up or disabled".to_string()));
+    };
+    let room_id = matrix::join_room(&state, &homeserver, input.room.trim()).await?;
+    let room = MatrixRoom::link(user.ws_id as _, input.chat_id, &room_id, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(room)))
+}
+
+pub(crate) async fn unlink_matrix_room_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(chat_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    MatrixRoom::unlink(user.ws_id as _, chat_id, &state.pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// streams the member directory as CSV, rows are written as they come out of the database
+pub(crate) async fn export_members_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Query(input): Query<ExportMembers>,
+) -> Result<Response, AppError> {
+    let columns: Vec<String> = input.columns()?.into_iter().map(String::from).collect();
+    let (tx, rx) = mpsc::channel::<Result<String, sqlx::Error>>(64);
+    let pool = state.pool.clone();
+    tokio::spawn(async move {
+        if tx.send(Ok(csv_record(&columns))).await.is_err() {
+            return;
+        }
+        let mut rows = Workspace::stream_member_directory(user.ws_id as _, &pool);
+        while let Some(row) = rows.next().await {
+            let ret = row.map(|entry| {
+                let fields: Vec<_> = columns.iter().map(|c| entry.column(c)).collect();
+                csv_record(&fields)
+            });
+            if let Err(e) = &ret {
+                warn!("export members of workspace {} failed: {}", user.ws_id, e);
+            }
+            // the client went away
+            if tx.send(ret).await.is_err() {
+                return;
+            }
+        }
+    });
+    let body = Body::from_stream(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    }));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"members.csv\""),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+pub(crate) async fn preview_email_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Path(template): Path<EmailTemplate>,
+) -> Result<Json<EmailPreview>, AppError> {
+    let email = template.sample(&user, &state).await?;
+    Ok(Json(EmailPreview {
+        from: mailer::workspace_sender(&state, user.ws_id).await?,
+        to: user.email.clone(),
+        email,
+    }))
+}
+
+// queues the sample email like any other, so it goes through the same relay, smtp server and sender
+pub(crate) async fn send_test_email_handler(
+    AdminUser(user): AdminUser,
+    State(state): State<AppState>,
+    Json(input): Json<SendTestEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    let to = input.to.as_deref().map(str::trim).unwrap_or(&user.email).to_string();
+    if !to.contains('@') || to.len() > 254 {
+        return Err(AppError::InvalidInput(format!("invalid email address: {}", to)));
+    }
+    let mut email = input.template.sample(&user, &state).await?;
+    email.subject = format!("[Test] {}", email.subject);
+    mailer::send_test(&state, &user, &to, &email).await?;
+    let preview = EmailPreview {
+        from: mailer::workspace_sender(&state, user.ws_id).await?,
+        to,
+        email,
+    };
+    Ok((StatusCode::ACCEPTED, Json(preview)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppConfig;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn email_preview_and_test_send_should_work() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let admin = User::find_by_email("tchen@acme.org", &state.pool).await?.expect("user should exist");
+        let ws = Workspace::find_by_id(1, &state.pool).await?.expect("workspace should exist");
+        ws.update_owner(admin.id as _, &state.pool).await?;
+        let Json(preview) = preview_email_handler(
+            AdminUser(admin.clone()),
+            State(state.clone()),
+            Path(EmailTemplate::Digest),
+        )
+        .await?;
+        assert_eq!(preview.email.subject, "15 unread messages in acme");
+        assert!(preview.email.body.contains("general: 12"));
+
+        let input = SendTestEmail { template: EmailTemplate::Invite, to: Some("alice@acme.org".to_string()) };
+        let ret = send_test_email_handler(AdminUser(admin.clone()), State(state.clone()), Json(input.clone()))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::ACCEPTED);
+        let (to, subject): (String, String) =
+            sqlx::query_as("SELECT to_email, subject FROM outbound_emails ORDER BY id DESC LIMIT 1")
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(to, "alice@acme.org");
+        assert!(subject.starts_with("[Test] "));
+
+        // only members can be mailed
+        let outside = SendTestEmail { template: EmailTemplate::Invite, to: Some("ops@example.com".to_string()) };
+        let ret = send_test_email_handler(AdminUser(admin.clone()), State(state.clone()), Json(outside)).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+
+        // and only a few times an hour
+        for _ in 1..mailer::MAX_REQUESTED_EMAILS {
+            send_test_email_handler(AdminUser(admin.clone()), State(state.clone()), Json(input.clone())).await?;
+        }
+        let ret = send_test_email_handler(AdminUser(admin), State(state.clone()), Json(input)).await;
+        assert!(matches!(ret, Err(AppError::TooManyAttempts(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_setting_changes_should_be_audited_with_diffs() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let admin = User::find_by_email("tchen@acme.org", &state.pool).await?.expect("user should exist");
+        let ws = Workspace::find_by_id(1, &state.pool).await?.expect("workspace should exist");
+        ws.update_owner(admin.id as _, &state.pool).await?;
+
+        let input = UpdateModerationPolicy { mode: Some(crate::ModerationMode::Mask), terms: None };
+        let Json(policy) =
+            update_moderation_policy_handler(AdminUser(admin.clone()), State(state.clone()), Json(input.clone())).await?;
+        // nothing changed the second time, so nothing is recorded
+        let Json(again) =
+            update_moderation_policy_handler(AdminUser(admin.clone()), State(state.clone()), Json(input)).await?;
+        assert_eq!(again, policy);
+
+        let logs = AuditLog::list(1, &ListAuditLogs::default(), &state.pool).await?;
+        let changes: Vec<_> = logs.iter().filter(|l| l.action == crate::AuditAction::SettingsChanged).collect();
+        assert_eq!(changes.len(), 1);
+        let ret = get_audit_log_changes_handler(AdminUser(admin), State(state.clone()), Path(changes[0].id as _))
+            .await?
+            .into_response();
+        let body = axum::body::to_bytes(ret.into_body(), usize::MAX).await?;
+        let text = String::from_utf8(body.to_vec())?;
+        assert!(text.contains("moderation_policy.mode: \"off\" -> \"mask\""), "{}", text);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_mail_settings_should_be_used_once_verified() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let admin = User::find_by_email("tchen@acme.org", &state.pool).await?.expect("user should exist");
+        let ws = Workspace::find_by_id(1, &state.pool).await?.expect("workspace should exist");
+        ws.update_owner(admin.id as _, &state.pool).await?;
+        let last_email = || async {
+            sqlx::query_as::<_, (String, String, Option<i64>)>(
+                "SELECT from_email, body, ws_id FROM outbound_emails ORDER BY id DESC LIMIT 1",
+            )
+            .fetch_one(&state.pool)
+            .await
+        };
+
+        let input = UpdateMailSettings {
+            smtp_host: Some("smtp.acme.org".to_string()),
+            smtp_port: None,
+            smtp_username: Some("chat".to_string()),
+            smtp_password: Some("secret".to_string()),
+            from_email: Some("chat@acme.org".to_string()),
+            from_name: Some("Acme".to_string()),
+        };
+        let ret = update_mail_settings_handler(AdminUser(admin.clone()), State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::ACCEPTED);
+        let (from, body, ws_id) = last_email().await?;
+        assert_eq!((from.as_str(), ws_id), ("\"Acme\" <chat@acme.org>", Some(1)));
+        let token = body.split("token=").nth(1).expect("verification link").trim().to_string();
+
+        // not used until verified
+        let send = |template| SendTestEmail { template, to: None };
+        send_test_email_handler(AdminUser(admin.clone()), State(state.clone()), Json(send(EmailTemplate::Digest))).await?;
+        assert_eq!(last_email().await?.2, None);
+
+        let Json(settings) = verify_mail_settings_handler(State(state.clone()), Query(VerifyMailSettings { token })).await?;
+        assert!(settings.verified_at.is_some());
+        send_test_email_handler(AdminUser(admin.clone()), State(state.clone()), Json(send(EmailTemplate::Invite))).await?;
+        let (from, _, ws_id) = last_email().await?;
+        assert_eq!((from.as_str(), ws_id), ("\"Acme\" <chat@acme.org>", Some(1)));
+
+        delete_mail_settings_handler(AdminUser(admin), State(state.clone())).await?;
+        assert!(MailSettings::fetch(1, &state.pool).await?.is_none());
+        Ok(())
+    }
 }
@@ -0,0 +1,300 @@
+use std::time::Duration;
+
+use ldap3::{ldap_escape, DerefAliases, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry, SearchOptions};
+use sqlx::PgPool;
+use tokio::time::timeout;
+
+use crate::{config::LdapConfig, AppError, SigninUser, User, Workspace};
+
+const SUCCESS: u32 = 0;
+const SIZE_LIMIT_EXCEEDED: u32 = 4;
+
+// a directory user whose password was verified
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LdapIdentity {
+    pub(crate) email: String,
+    pub(crate) name: Option<String>,
+}
+
+// the ldap server that checks the email's password: the one of the member's workspace, or
+// for emails without an account, the one for the email's domain
+pub(crate) async fn config_for<'a>(
+    email: &str,
+    configs: &'a [LdapConfig],
+    pool: &PgPool,
+) -> Result<Option<&'a LdapConfig>, AppError> {
+    if configs.is_empty() {
+        return Ok(None);
+    }
+    let Some(user) = User::find_by_email(email, pool).await? else {
+        let domain = email.rsplit_once('@').map(|(_, domain)| domain).unwrap_or_default();
+        return Ok(configs.iter().find(|c| c.domains.iter().any(|d| d.eq_ignore_ascii_case(domain))));
+    };
+    for config in configs {
+        let ws = Workspace::find_by_slug(&config.workspace, pool).await?;
+        if ws.is_some_and(|ws| ws.id == user.ws_id) {
+            return Ok(Some(config));
+        }
+    }
+    Ok(None)
+}
+
+// None when the directory has no such user or the password is wrong. The local user is
+// created at the first signin.
+pub(crate) async fn verify(config: &LdapConfig, input: &SigninUser, pool: &PgPool) -> Result<Option<User>, AppError> {
+    let Some(identity) = authenticate(config, &input.email, &input.password).await? else {
+        return Ok(None);
+    };
+    let ws = Workspace::find_by_slug(&config.workspace, pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("workspace {}", config.workspace)))?;
+    let user = User::from_ldap_identity(ws.id as _, &identity, pool).await?;
+    Ok(Some(user))
+}
+
+// looks the user up by email with the service account, then binds as them with the password
+pub(crate) async fn authenticate(
+    config: &LdapConfig,
+    email: &str,
+    password: &str,
+) -> Result<Option<LdapIdentity>, AppError> {
+    // a bind without a password is an unauthenticated bind, which servers accept
+    if password.is_empty() {
+        return Ok(None);
+    }
+    let settings = settings(config)?;
+    let secs = config.timeout_secs;
+    let ret = timeout(Duration::from_secs(secs), async {
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &config.url).await.map_err(unavailable)?;
+        ldap3::drive!(conn);
+        let ret = bind_as_user(&mut ldap, config, email, password).await;
+        let _ = ldap.unbind().await;
+        ret
+    })
+    .await;
+    ret.unwrap_or_else(|_| Err(unavailable(format!("no answer from {} in {}s", config.url, secs))))
+}
+
+// passwords only ever go over tls, either ldaps:// or ldap:// upgraded with StartTLS
+fn settings(config: &LdapConfig) -> Result<LdapConnSettings, AppError> {
+    let settings = LdapConnSettings::new().set_conn_timeout(Duration::from_secs(config.timeout_secs));
+    if config.url.starts_with("ldaps://") {
+        return Ok(settings);
+    }
+    if config.url.starts_with("ldap://") && config.starttls {
+        return Ok(settings.set_starttls(true));
+    }
+    Err(unavailable(format!("{} is neither ldaps:// nor StartTLS", config.url)))
+}
+
+async fn bind_as_user(ldap: &mut Ldap, config: &LdapConfig, email: &str, password: &str) -> Result<Option<LdapIdentity>, AppError> {
+    let bind = ldap.simple_bind(&config.bind_dn, &config.bind_password).await.map_err(unavailable)?;
+    if bind.rc != SUCCESS {
+        return Err(unavailable("the service account bind was rejected"));
+    }
+    let Some(entry) = find_user(ldap, config, email).await? else {
+        return Ok(None);
+    };
+    let bind = ldap.simple_bind(&entry.dn, password).await.map_err(unavailable)?;
+    let name = attr(&entry, &config.name_attr);
+    Ok((bind.rc == SUCCESS).then(|| LdapIdentity { email: email.to_string(), name }))
+}
+
+// an email shared by several entries is treated like an unknown one
+async fn find_user(ldap: &mut Ldap, config: &LdapConfig, email: &str) -> Result<Option<SearchEntry>, AppError> {
+    let filter = format!(
+        "(&(objectClass={})({}={}))",
+        ldap_escape(config.object_class.as_str()),
+        config.email_attr,
+        ldap_escape(email)
+    );
+    let options = SearchOptions::new().deref(DerefAliases::Never).sizelimit(2).timelimit(config.timeout_secs as i32);
+    let ret = ldap
+        .with_search_options(options)
+        .search(&config.base_dn, Scope::Subtree, &filter, vec![config.name_attr.as_str()])
+        .await
+        .map_err(unavailable)?;
+    if ret.1.rc != SUCCESS && ret.1.rc != SIZE_LIMIT_EXCEEDED {
+        return Err(unavailable(format!("search failed with result code {}", ret.1.rc)));
+    }
+    // referrals to other servers are not followed
+    let mut entries: Vec<_> = ret.0.into_iter().filter(|e| !e.is_ref() && !e.is_intermediate()).collect();
+    Ok(if entries.len() == 1 { entries.pop().map(SearchEntry::construct) } else { None })
+}
+
+// attribute names are case insensitive
+fn attr(entry: &SearchEntry, name: &str) -> Option<String> {
+    entry
+        .attrs
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .and_then(|(_, vals)| vals.first().cloned())
+}
+
+fn unavailable(e: impl ToString) -> AppError {
+    AppError::AuthBackendUnavailable(format!("ldap: {}", e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    const ALICE_DN: &str = "uid=alice,ou=people,dc=acme,dc=org";
+    const SEQUENCE: u8 = 0x30;
+    const INTEGER: u8 = 0x02;
+    const OCTET_STRING: u8 = 0x04;
+    const ENUMERATED: u8 = 0x0a;
+    const SET: u8 = 0x31;
+    const BIND_REQUEST: u8 = 0x60;
+    const BIND_RESPONSE: u8 = 0x61;
+    const SEARCH_REQUEST: u8 = 0x63;
+    const SEARCH_RESULT_ENTRY: u8 = 0x64;
+    const SEARCH_RESULT_DONE: u8 = 0x65;
+
+    fn config(port: u16) -> LdapConfig {
+        LdapConfig {
+            workspace: "acme".to_string(),
+            url: format!("ldap://127.0.0.1:{}", port),
+            starttls: true,
+            bind_dn: "cn=chat,dc=acme,dc=org".to_string(),
+            bind_password: "service".to_string(),
+            base_dn: "dc=acme,dc=org".to_string(),
+            email_attr: "mail".to_string(),
+            object_class: "person".to_string(),
+            name_attr: "cn".to_string(),
+            domains: vec!["ldap.acme.org".to_string()],
+            timeout_secs: 5,
+        }
+    }
+
+    // just enough ber for the directory below, lengths up to 64k
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        match content.len() {
+            len @ 0..=0x7f => out.push(len as u8),
+            len => out.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        out.extend_from_slice(content);
+        out
+    }
+
+    fn octets(s: &str) -> Vec<u8> {
+        tlv(OCTET_STRING, s.as_bytes())
+    }
+
+    fn message(id: &[u8], op: Vec<u8>) -> Vec<u8> {
+        tlv(SEQUENCE, &[tlv(INTEGER, id), op].concat())
+    }
+
+    fn ldap_result(tag: u8, code: u8) -> Vec<u8> {
+        tlv(tag, &[tlv(ENUMERATED, &[code]), octets(""), octets("")].concat())
+    }
+
+    // the tag and content at the start of buf, and what follows
+    fn split(buf: &[u8]) -> (u8, &[u8], &[u8]) {
+        let (len, start) = match buf[1] {
+            len @ 0..=0x7f => (len as usize, 2),
+            0x81 => (buf[2] as usize, 3),
+            _ => ((buf[2] as usize) << 8 | buf[3] as usize, 4),
+        };
+        (buf[0], &buf[start..start + len], &buf[start + len..])
+    }
+
+    async fn read_message(tcp: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut head = [0; 2];
+        tcp.read_exact(&mut head).await.ok()?;
+        let mut len_bytes = vec![0; if head[1] & 0x80 != 0 { (head[1] & 0x7f) as usize } else { 0 }];
+        tcp.read_exact(&mut len_bytes).await.ok()?;
+        let len = if len_bytes.is_empty() { head[1] as usize } else { len_bytes.iter().fold(0, |l, b| l << 8 | *b as usize) };
+        let mut body = vec![0; len];
+        tcp.read_exact(&mut body).await.ok()?;
+        Some(body)
+    }
+
+    // a directory with the service account and alice@ldap.acme.org, whose password is secret
+    async fn serve() -> Result<u16> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((mut tcp, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    while let Some(msg) = read_message(&mut tcp).await {
+                        let (_, id, rest) = split(&msg);
+                        let (tag, body, _) = split(rest);
+                        let reply = match tag {
+                            BIND_REQUEST => {
+                                let (_, _, rest) = split(body);
+                                let (_, dn, rest) = split(rest);
+                                let (_, password, _) = split(rest);
+                                let ok = matches!(
+                                    (dn, password),
+                                    (b"cn=chat,dc=acme,dc=org", b"service") | (b"uid=alice,ou=people,dc=acme,dc=org", b"secret")
+                                );
+                                message(id, ldap_result(BIND_RESPONSE, if ok { 0 } else { 49 }))
+                            }
+                            SEARCH_REQUEST => {
+                                let mut reply = vec![];
+                                let email = b"alice@ldap.acme.org";
+                                if body.windows(email.len()).any(|w| w == email) {
+                                    let cn = tlv(SEQUENCE, &[octets("cn"), tlv(SET, &octets("Alice Ldap"))].concat());
+                                    let entry = [octets(ALICE_DN), tlv(SEQUENCE, &cn)].concat();
+                                    reply = message(id, tlv(SEARCH_RESULT_ENTRY, &entry));
+                                }
+                                reply.extend(message(id, ldap_result(SEARCH_RESULT_DONE, 0)));
+                                reply
+                            }
+                            _ => return,
+                        };
+                        if tcp.write_all(&reply).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        Ok(port)
+    }
+
+    // the directory above doesn't speak tls, so the connection is opened without it
+    async fn plain_bind(config: &LdapConfig, email: &str, password: &str) -> Result<Option<LdapIdentity>, AppError> {
+        let (conn, mut ldap) = LdapConnAsync::new(&config.url).await.map_err(unavailable)?;
+        ldap3::drive!(conn);
+        bind_as_user(&mut ldap, config, email, password).await
+    }
+
+    #[tokio::test]
+    async fn ldap_should_require_tls() -> Result<()> {
+        let config = config(389);
+        assert!(settings(&config)?.starttls());
+        assert!(!settings(&LdapConfig { url: "ldaps://127.0.0.1".to_string(), starttls: false, ..config.clone() })?.starttls());
+        let config = LdapConfig { starttls: false, ..config };
+        let ret = authenticate(&config, "alice@ldap.acme.org", "secret").await;
+        assert!(matches!(ret, Err(AppError::AuthBackendUnavailable(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn authenticate_should_bind_as_the_user() -> Result<()> {
+        let config = config(serve().await?);
+        let identity = plain_bind(&config, "alice@ldap.acme.org", "secret").await?;
+        assert_eq!(
+            identity,
+            Some(LdapIdentity { email: "alice@ldap.acme.org".to_string(), name: Some("Alice Ldap".to_string()) })
+        );
+        assert!(plain_bind(&config, "alice@ldap.acme.org", "wrong").await?.is_none());
+        assert!(authenticate(&config, "alice@ldap.acme.org", "").await?.is_none());
+        assert!(plain_bind(&config, "bob@ldap.acme.org", "secret").await?.is_none());
+        // StartTLS is refused by the directory above, the password is never sent in the clear
+        assert!(authenticate(&config, "alice@ldap.acme.org", "secret").await.is_err());
+
+        let config = LdapConfig { bind_password: "wrong".to_string(), ..config };
+        let ret = plain_bind(&config, "alice@ldap.acme.org", "secret").await;
+        assert!(matches!(ret, Err(AppError::AuthBackendUnavailable(_))));
+        Ok(())
+    }
+}
@@ -1,28 +1,83 @@
+mod audit;
+mod batch;
+mod cli;
+mod commands;
 mod handlers;
 mod config;
+mod db;
+mod doctor;
 mod models;
 mod error;
+mod events;
+mod features;
+mod feeds;
+mod graphql;
+mod grpc;
+mod ldap;
 mod utils;
+mod mail_gateway;
+mod mailer;
+mod maintenance;
+mod matrix;
 mod middlewares;
+mod oauth;
+mod oauth_provider;
+mod oidc;
+mod pagination;
+mod realtime;
+mod reload;
+mod scim;
+mod scope;
+mod security;
+mod shutdown;
+mod sync;
+mod tls;
+mod usage;
+mod webauthn;
+mod webhooks;
 
 use core::fmt;
-use std::{ops::Deref, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, ops::Deref, sync::{Arc, RwLock}, time::{Duration, Instant}};
 
 use anyhow::Context;
+use arc_swap::ArcSwap;
 use handlers::*;
 
 use axum::{
-    middleware::from_fn_with_state, routing::{get, patch, post}, Router
+    middleware::from_fn_with_state, routing::{delete, get, patch, post, put}, Extension, Router
 };
 
-pub use config::AppConfig;
+pub use batch::{BatchRequest, BatchResponse};
+pub use chat_core::AuthOutput;
+pub use cli::{create_admin, migrate, seed};
+pub use db::wait_for_database;
+pub use config::{AppConfig, EmailVerification, LdapConfig, LogFormat, LogLevel};
+pub use doctor::{Check, CheckStatus, DoctorReport};
 pub use error::AppError;
+pub use events::{AppEvent, EventBatch, EventFrame, PollEvents, PROTOCOL_VERSION};
+pub use features::{FeatureFlag, FeatureFlags};
+pub use mail_gateway::InboundEmail;
+pub use middlewares::{ApiVersion, CURRENT_API_VERSION};
+pub use pagination::Paginated;
+pub use mailer::{Email, EmailPreview, EmailTemplate, SendTestEmail};
+pub use scope::{AdminUser, ChatRead, ChatWrite, Grant, RequireScope, Scope, WorkspaceAdmin};
+pub use sync::{ChangeBatch, ChatMembers, ReadState, SyncChanges};
+pub use usage::{ExportUsage, UsageDay, UsageFormat};
+pub use utils::{DecodingKey, EncodingKey, TokenId};
 pub use models::*;
 use sqlx::PgPool;
+use tokio::{net::TcpListener, sync::broadcast};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{info, level_filters::LevelFilter};
+use tracing_subscriber::{reload::Handle, Registry};
 
+use crate::{batch::BatchApi, config::LiveConfig, db::DbRouter, events::EventBus, graphql::{graphql_handler, ChatSchema}, middlewares::{set_layer, verify_hs_token, verify_metrics_token, verify_scim_token, verify_token, LoadShedder, RateLimiter}, realtime::Subscribers, utils::HttpClient};
 
+// events a subscriber may fall behind by before it starts missing them
+const EVENTS_CAPACITY: usize = 1024;
 
-use crate::{middlewares::{set_layer, verify_token}, utils::{DecodingKey, EncodingKey}};
+// changes the level of the subscriber set up in main, when the config file's log.level does
+pub type LogLevelHandle = Handle<LevelFilter, Registry>;
 
 #[derive(Debug, Clone)]
 pub(crate) struct AppState {
@@ -31,32 +86,316 @@ pub(crate) struct AppState {
 
 pub(crate) struct AppStateInner {
     pub(crate) config: AppConfig,
+    // rate limits, feature flags and log level as of the last config reload, read these
+    // rather than their copies in config
+    pub(crate) live: ArcSwap<LiveConfig>,
     pub(crate) dk: DecodingKey,
     pub(crate) ek: EncodingKey,
+    // the primary, same as db.primary()
     pub(crate) pool: PgPool,
+    pub(crate) db: DbRouter,
+    // shared client for calls to third parties, e.g. webhook deliveries
+    pub(crate) http: HttpClient,
+    // ws_id -> (computed at, stats)
+    pub(crate) stats_cache: RwLock<HashMap<i64, (Instant, WorkspaceStats)>>,
+    pub(crate) rate_limiter: RateLimiter,
+    pub(crate) load_shedder: LoadShedder,
+    // background jobs that should finish before the process exits
+    pub(crate) tasks: TaskTracker,
+    // fed by events::listen, so includes changes made through other instances
+    pub(crate) events: broadcast::Sender<AppEvent>,
+    pub(crate) bus: EventBus,
+    // event streams of clients connected to this instance
+    pub(crate) subscribers: Arc<Subscribers>,
+    pub(crate) graphql: ChatSchema,
 }
 
 pub async fn get_router(config: AppConfig) -> Result<Router, AppError> {
     let state = AppState::try_new(config).await?;
-    let api = Router::new()
+    Ok(router(state))
+}
+
+// serves until ctrl-c or SIGTERM. From then on new connections are refused, and requests in
+// flight and background jobs get server.shutdown_timeout_secs to finish.
+pub async fn serve(config: AppConfig, listener: TcpListener, log_level: LogLevelHandle) -> anyhow::Result<()> {
+    let grace = Duration::from_secs(config.server.shutdown_timeout_secs);
+    let config_path = AppConfig::path()?;
+    let certificate = config.server.tls.as_ref().map(tls::Certificate::load).transpose()?;
+    let acceptor = certificate.clone().map(tls::acceptor).transpose()?;
+    let grpc_listener = match &config.grpc {
+        Some(grpc) => Some((TcpListener::bind((grpc.host.as_str(), grpc.port)).await?, grpc::tls_config(&grpc.tls)?)),
+        None => None,
+    };
+    let state = AppState::try_new(config).await?;
+    let app = router(state.clone());
+    let stopping = CancellationToken::new();
+    // tracked, so shutdown cancels the workers and waits for them, and calls in flight get the
+    // grace period too
+    state.tasks.spawn(events::relay_outbox(state.clone(), stopping.clone()));
+    state.tasks.spawn(events::listen(state.clone(), stopping.clone()));
+    state.tasks.spawn(events::invalidate_stats(state.clone(), stopping.clone()));
+    state.tasks.spawn(events::prune_log(state.clone(), stopping.clone()));
+    state.tasks.spawn(realtime::deliver(state.clone(), stopping.clone()));
+    state.tasks.spawn(realtime::track_presence(state.clone(), stopping.clone()));
+    state.tasks.spawn(webhooks::deliver(state.clone(), stopping.clone()));
+    state.tasks.spawn(matrix::sync(state.clone(), stopping.clone()));
+    state.tasks.spawn(mail_gateway::notify(state.clone(), stopping.clone()));
+    state.tasks.spawn(maintenance::manage_partitions(state.clone(), stopping.clone()));
+    state.tasks.spawn(usage::roll_up(state.clone(), stopping.clone()));
+    state.tasks.spawn(reload::watch(state.clone(), config_path, log_level, stopping.clone()));
+    if let Some(certificate) = certificate {
+        state.tasks.spawn(tls::watch(certificate, stopping.clone()));
+    }
+    if let Some((listener, tls)) = grpc_listener {
+        state.tasks.spawn(grpc::serve(state.clone(), listener, tls, stopping.clone()));
+    }
+    tokio::spawn({
+        let stopping = stopping.clone();
+        async move {
+            shutdown::signal().await;
+            stopping.cancel();
+        }
+    });
+    let server = async {
+        match acceptor {
+            Some(acceptor) => {
+                tls::serve(listener, app, acceptor, stopping.clone()).await;
+                Ok(())
+            }
+            None => {
+                axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                    .with_graceful_shutdown(stopping.clone().cancelled_owned())
+                    .await
+            }
+        }
+    };
+    let served = tokio::select! {
+        ret = server => ret,
+        // the grace period starts with the signal, connections still open after it are cut
+        _ = async {
+            stopping.cancelled().await;
+            tokio::time::sleep(grace).await;
+        } => {
+            info!("requests still in flight after {}s, closing their connections", grace.as_secs());
+            Ok(())
+        }
+    };
+    // the server can also stop without a signal, e.g. on an accept error
+    stopping.cancel();
+    shutdown::drain(&state, grace).await;
+    served?;
+    info!("shutdown complete");
+    Ok(())
+}
+
+fn router(state: AppState) -> Router {
+    let authed = Router::new()
+        .route("/signout", post(signout_handler))
+        .route("/2fa/totp", post(enroll_totp_handler))
+        .route("/2fa/totp/confirm", post(confirm_totp_handler))
+        .route("/2fa/recovery-codes", post(regenerate_recovery_codes_handler))
+        .route("/2fa/disable", post(disable_two_factor_handler))
+        .route("/security/events", get(list_security_events_handler))
+        .route("/sessions", get(list_sessions_handler))
+        .route("/sessions/{id}", delete(revoke_session_handler))
+        .route("/passkeys", get(list_passkeys_handler))
+        .route("/passkeys/register/start", post(start_passkey_registration_handler))
+        .route("/passkeys/register/finish", post(finish_passkey_registration_handler))
+        .route("/passkeys/{id}", delete(delete_passkey_handler))
+        .route("/oauth/authorize", get(app_consent_handler).post(authorize_app_handler))
+        .route("/oauth/apps", get(list_authorized_apps_handler))
+        .route("/oauth/apps/{app_id}", delete(revoke_authorized_app_handler))
+        .route("/me/email", patch(change_email_handler))
+        .route("/me/feed-tokens", delete(revoke_feed_tokens_handler))
+        .route("/events", get(events_handler))
+        .route("/events/poll", get(poll_events_handler))
+        .route("/sync", get(sync_handler))
+        .route("/graphql", post(graphql_handler))
+        .route("/features", get(list_features_handler))
+        .route("/admin/users", get(list_admin_users_handler))
+        .route("/admin/users/{id}/deactivate", post(deactivate_user_handler))
+        .route("/admin/users/{id}/reactivate", post(reactivate_user_handler))
+        .route("/admin/users/{id}/password-reset", post(force_password_reset_handler))
+        .route("/admin/chats/{id}", delete(admin_delete_chat_handler))
+        .route("/admin/stats", get(admin_stats_handler))
+        .route("/admin/usage", get(export_usage_handler))
         .route("/users", get(list_chat_users_handler))
+        .route("/workspace", patch(update_workspace_handler))
+        .route("/workspace/stats", get(workspace_stats_handler))
+        .route("/workspace/signup-domain", get(get_signup_domain_handler))
+        .route("/workspace/signup-domain/verify", post(verify_signup_domain_handler))
+        .route("/workspace/clone", post(clone_workspace_handler))
+        .route("/workspace/audit", get(list_audit_logs_handler))
+        .route("/workspace/audit/{id}/changes", get(get_audit_log_changes_handler))
+        .route("/workspace/security/events", get(list_workspace_security_events_handler))
+        .route("/workspace/members/export", get(export_members_handler))
+        .route("/workspace/members/{id}/role", put(update_member_role_handler))
+        .route("/workspace/actions", post(submit_destructive_action_handler))
+        .route("/workspace/approvals", get(list_approvals_handler))
+        .route("/workspace/approvals/{id}/dry-run", get(dry_run_approval_handler))
+        .route("/workspace/approvals/{id}/approve", post(approve_handler))
+        .route("/workspace/approvals/{id}/reject", post(reject_handler))
+        .route(
+            "/workspace/session-policy",
+            get(get_session_policy_handler).patch(update_session_policy_handler),
+        )
+        .route(
+            "/workspace/moderation",
+            get(get_moderation_policy_handler).patch(update_moderation_policy_handler),
+        )
+        .route("/workspace/ip-allowlist", get(get_ip_allowlist_handler).put(update_ip_allowlist_handler))
+        .route("/workspace/emoji", get(list_custom_emoji_handler).post(create_custom_emoji_handler))
+        .route("/workspace/emoji/{name}", delete(delete_custom_emoji_handler))
+        .route("/workspace/scim", get(get_scim_settings_handler))
+        .route("/workspace/scim/token", post(issue_scim_token_handler).delete(revoke_scim_token_handler))
+        .route(
+            "/workspace/sso",
+            get(get_oidc_config_handler).put(update_oidc_config_handler).delete(delete_oidc_config_handler),
+        )
+        .route("/workspace/emails/{template}/preview", get(preview_email_handler))
+        .route("/workspace/emails/test", post(send_test_email_handler))
+        .route(
+            "/workspace/mail",
+            get(get_mail_settings_handler).put(update_mail_settings_handler).delete(delete_mail_settings_handler),
+        )
+        .route(
+            "/workspace/bridges/identities",
+            get(list_bridge_identities_handler).put(resolve_bridge_identity_handler),
+        )
+        .route("/workspace/bridges/identities/{id}", delete(delete_bridge_identity_handler))
+        .route(
+            "/workspace/bridges/matrix",
+            get(get_matrix_bridge_handler).put(update_matrix_bridge_handler).delete(delete_matrix_bridge_handler),
+        )
+        .route("/workspace/bridges/matrix/tokens", post(rotate_matrix_tokens_handler))
+        .route("/workspace/bridges/matrix/rooms", get(list_matrix_rooms_handler).post(link_matrix_room_handler))
+        .route("/workspace/bridges/matrix/rooms/{chat_id}", delete(unlink_matrix_room_handler))
+        .route("/workspace/apps", get(list_oauth_apps_handler).post(create_oauth_app_handler))
+        .route("/workspace/apps/{id}", delete(delete_oauth_app_handler))
+        .route("/workspace/bots", get(list_bots_handler).post(create_bot_handler))
+        .route("/workspace/bots/{id}", delete(delete_bot_handler))
+        .route("/workspace/bots/{id}/token", post(rotate_bot_token_handler))
+        .route("/workspace/commands", get(list_slash_commands_handler).post(create_slash_command_handler))
+        .route("/workspace/commands/{id}", delete(delete_slash_command_handler))
+        .route("/workspace/webhooks", get(list_webhooks_handler).post(create_webhook_handler))
+        .route("/workspace/webhooks/{id}", delete(delete_webhook_handler))
+        .route("/workspace/webhooks/{id}/deliveries", get(list_webhook_deliveries_handler))
         .route("/chats", get(list_chat_handler).post(create_chat_handler))
         .route(
-            "/chats/{{:id}}",
+            "/chats/{id}",
             get(get_chat_handler).patch(update_chat_handler)
                 .delete(delete_chat_handler)
                 .post(send_message_handler),
         )
-        .route("/chats/{{:id}}/messages", get(list_message_handler))
-        .layer(from_fn_with_state(state.clone(), verify_token))
+        .route("/chats/{id}/messages", get(list_message_handler))
+        .route("/chats/{id}/settings", patch(update_chat_settings_handler))
+        .route("/chats/{id}/feed", get(get_chat_feed_handler).put(update_chat_feed_handler))
+        .route("/chats/{id}/read", post(mark_chat_read_handler))
+        .route("/chats/{id}/typing", post(start_typing_handler))
+        .route("/chats/{id}/members", post(add_chat_member_handler))
+        .route("/chats/{id}/members/history", get(list_chat_member_history_handler))
+        .route("/chats/{id}/members/{user_id}", delete(remove_chat_member_handler))
+        .route("/chats/{id}/pins", post(pin_message_handler).put(reorder_pins_handler))
+        .route("/chats/{id}/pins/{message_id}", delete(unpin_message_handler))
+        .route("/chats/{id}/invite-link", post(create_chat_invite_handler))
+        .route(
+            "/chats/{id}/hooks",
+            get(list_incoming_webhooks_handler).post(create_incoming_webhook_handler),
+        )
+        .route("/chats/{id}/hooks/{webhook_id}", delete(delete_incoming_webhook_handler))
+        .route("/join/{token}", post(join_chat_handler))
+        .route("/mentions", get(list_mentions_handler))
+        .route("/mentions/read", post(mark_mentions_read_handler))
+        .route("/threads/{id}/follow", post(follow_thread_handler).delete(unfollow_thread_handler))
+        .route("/messages/{id}/original", get(get_original_message_handler))
+        .route("/messages/{id}/reactions", post(add_reaction_handler))
+        .route("/messages/{id}/reactions/{emoji}", delete(remove_reaction_handler))
+        .route("/activity", get(list_activity_handler))
+        .route("/notifications", get(list_notifications_handler))
+        .route("/notifications/read", post(mark_notifications_read_handler))
+        .route("/channels/browse", get(browse_channels_handler))
+        .route("/channels/{id}/join", post(join_channel_handler))
+        .layer(from_fn_with_state(state.clone(), verify_token));
+    let api = authed
+        .clone()
+        .route("/workspaces/{slug}", get(get_workspace_by_slug_handler))
         .route("/signin", post(signin_handler))
+        .route("/token/refresh", post(refresh_token_handler))
+        .route("/token/revoke", post(revoke_token_handler))
+        .route("/oauth/token", post(oauth_token_handler))
+        .route("/signin/2fa", post(signin_challenge_handler))
+        .route("/signin/magic", get(open_magic_link_handler).post(request_magic_link_handler))
+        .route("/signin/magic/redeem", post(magic_link_signin_handler))
+        .route("/signin/passkey/start", post(start_passkey_signin_handler))
+        .route("/signin/passkey/finish", post(finish_passkey_signin_handler))
+        .route("/auth/{provider}/login", get(oauth_login_handler))
+        .route("/auth/{provider}/callback", get(oauth_callback_handler))
+        .route("/sso/{slug}/login", get(sso_login_handler))
+        .route("/sso/callback", get(sso_callback_handler))
+        .route("/workspace/mail/verify", get(verify_mail_settings_handler))
+        .route("/signin/deny", get(deny_signin_page_handler).post(deny_signin_handler))
+        .route("/password/reset", post(reset_password_handler))
+        .route("/verify", get(verify_email_handler))
+        .route("/me/email/confirm", get(confirm_email_change_handler))
+        .route("/verify/resend", post(resend_verification_handler))
         .route("/signup", post(signup_handler));
 
+    // served by the authenticated routes, a batch can't contain another
+    let batch = Router::new()
+        .route("/batch", post(batch_handler))
+        .layer(from_fn_with_state(state.clone(), verify_token))
+        .layer(Extension(BatchApi::new(authed, state.clone())));
+    let api = api.merge(batch);
+
+    // provisioning by the workspace's identity provider, see RFC 7644
+    let scim = Router::new()
+        .route("/Users", get(list_scim_users_handler).post(create_scim_user_handler))
+        .route(
+            "/Users/{id}",
+            get(get_scim_user_handler)
+                .put(replace_scim_user_handler)
+                .patch(patch_scim_user_handler)
+                .delete(delete_scim_user_handler),
+        )
+        .route("/Groups", get(list_scim_groups_handler).post(create_scim_group_handler))
+        .route(
+            "/Groups/{id}",
+            get(get_scim_group_handler)
+                .put(replace_scim_group_handler)
+                .patch(patch_scim_group_handler)
+                .delete(delete_scim_group_handler),
+        )
+        .route("/ServiceProviderConfig", get(service_provider_config_handler))
+        .layer(from_fn_with_state(state.clone(), verify_scim_token));
+
+    // the application service api a matrix homeserver calls, see the matrix spec
+    let matrix = Router::new()
+        .route("/transactions/{txn_id}", put(matrix_transaction_handler))
+        .route("/users/{user_id}", get(matrix_user_query_handler))
+        .route("/rooms/{alias}", get(matrix_room_query_handler))
+        .route("/ping", post(matrix_ping_handler))
+        .layer(from_fn_with_state(state.clone(), verify_hs_token));
+
     let app = Router::new()
         .route("/", get(index_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .route(
+            "/metrics",
+            get(metrics_handler).route_layer(from_fn_with_state(state.clone(), verify_metrics_token)),
+        )
+        .route("/.well-known/jwks.json", get(jwks_handler))
+        // /api is an alias of the current version, see middlewares::api_version
+        .nest("/api/v1", api.clone())
+        .nest("/api/v2", api.clone())
         .nest("/api", api)
+        .route("/hooks/{token}", post(incoming_webhook_handler))
+        .route("/mail/inbound", post(inbound_email_handler))
+        .route("/feeds/chat/{file}", get(chat_feed_handler))
+        .nest("/scim/v2", scim)
+        .nest("/_matrix/app/v1", matrix)
         .with_state(state.clone());
-    Ok(set_layer(app))
+    set_layer(app, state)
 }
 
 impl Deref for AppState {
@@ -68,15 +407,37 @@ impl Deref for AppState {
 
 impl AppState {
     async fn try_new(config: AppConfig) -> Result<Self, AppError> {
-        let dk: DecodingKey = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
-        let ek = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
-        let pool = PgPool::connect(config.server.db_url.as_str())
-            .await
-            .context("connect to db failed")?;
+        let dk = DecodingKey::from_config(&config.auth).context("load pk failed")?;
+        let ek = EncodingKey::load(&config.auth.sk, config.auth.kid.as_deref()).context("load sk failed")?;
+        let db = DbRouter::connect(&config.server).await.context("connect to db failed")?;
+        let pool = db.primary().clone();
+        let http = HttpClient::new(&config.outbound);
+        let load_shedder = LoadShedder::new(config.request_limits.max_in_flight);
+        let bus = EventBus::new(&config.events).context("open redis client failed")?;
         Ok(Self {
-            inner: Arc::new(AppStateInner { config: config, dk: dk, ek: ek, pool: pool })
+            inner: Arc::new(AppStateInner {
+                live: ArcSwap::from_pointee(LiveConfig::from(&config)),
+                config,
+                dk,
+                ek,
+                pool,
+                db,
+                http,
+                stats_cache: RwLock::new(HashMap::new()),
+                rate_limiter: RateLimiter::default(),
+                load_shedder,
+                tasks: TaskTracker::new(),
+                events: broadcast::channel(EVENTS_CAPACITY).0,
+                bus,
+                subscribers: Arc::default(),
+                graphql: graphql::schema(),
+            })
         })
     }
+
+    pub(crate) fn subscribe_events(&self) -> broadcast::Receiver<AppEvent> {
+        self.events.subscribe()
+    }
 }
 
 impl fmt::Debug for AppStateInner {
@@ -90,23 +451,45 @@ impl fmt::Debug for AppStateInner {
 
 #[cfg(test)]
 mod test_util {
-    use std::sync::Arc;
+    use std::{collections::HashMap, sync::{Arc, RwLock}};
 
     use anyhow::Context;
+    use arc_swap::ArcSwap;
     use sqlx::PgPool;
     use sqlx_db_tester::TestPg;
+    use tokio::sync::broadcast;
+    use tokio_util::task::TaskTracker;
 
-    use crate::{utils::{DecodingKey, EncodingKey}, AppConfig, AppError, AppState, AppStateInner};
+    use crate::{config::LiveConfig, db::DbRouter, events::EventBus, graphql, middlewares::{LoadShedder, RateLimiter}, utils::{DecodingKey, EncodingKey, HttpClient}, AppConfig, AppError, AppState, AppStateInner, EVENTS_CAPACITY};
 
     impl AppState {
         pub async fn new_for_test(config: AppConfig) -> Result<(TestPg, Self), AppError> {
-            let dk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
-            let ek = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
+            let dk = DecodingKey::from_config(&config.auth).context("load pk failed")?;
+            let ek = EncodingKey::load(&config.auth.sk, config.auth.kid.as_deref()).context("load sk failed")?;
             let post = config.server.db_url.rfind('/').expect("invalid db_url");
             let server_url = &config.server.db_url[..post];
             let (tdb, pool) = get_test_pool(Some(server_url)).await;
+            let http = HttpClient::new(&config.outbound);
+            let load_shedder = LoadShedder::new(config.request_limits.max_in_flight);
+            let bus = EventBus::new(&config.events).context("open redis client failed")?;
             let state = Self {
-                inner: Arc::new(AppStateInner { config, dk, ek, pool })
+                inner: Arc::new(AppStateInner {
+                    live: ArcSwap::from_pointee(LiveConfig::from(&config)),
+                    config,
+                    dk,
+                    ek,
+                    pool: pool.clone(),
+                    db: DbRouter::new(pool, vec![]),
+                    http,
+                    stats_cache: RwLock::new(HashMap::new()),
+                    rate_limiter: RateLimiter::default(),
+                    load_shedder,
+                    tasks: TaskTracker::new(),
+                    events: broadcast::channel(EVENTS_CAPACITY).0,
+                    bus,
+                    subscribers: Arc::default(),
+                    graphql: graphql::schema(),
+                })
             };
             Ok((tdb, state))
         }
@@ -0,0 +1,255 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::{
+    config::MailReplyConfig,
+    mailer,
+    models::DueNotification,
+    utils::{constant_time_eq, hex_encode},
+    AppError, AppState, Chat, ChatType, CreateMessage, Message, User,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const NOTIFY_BATCH: usize = 50;
+// hex characters of the HMAC kept in reply addresses, local parts are at most 64
+const REPLY_SIGNATURE_LEN: usize = 32;
+// the same as for messages, longer ids are dropped
+const MAX_EXTERNAL_ID_LEN: usize = 128;
+
+// a received email as posted by the inbound mail service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InboundEmail {
+    // the sender, either addr@host or Name <addr@host>
+    pub from: String,
+    // the recipients, comma separated
+    pub to: String,
+    #[serde(default)]
+    pub subject: String,
+    // the plain text part
+    pub text: String,
+    // the Message-ID header, used so that the same email posted twice is one message
+    #[serde(default)]
+    pub message_id: Option<String>,
+}
+
+// emails unread mentions and direct messages until stop is cancelled. Every instance runs one,
+// a notification is claimed by one of them.
+pub(crate) async fn notify(state: AppState, stop: CancellationToken) {
+    loop {
+        let delay = match notify_due(&state).await {
+            Ok(n) if n == NOTIFY_BATCH => Duration::ZERO,
+            Ok(_) => POLL_INTERVAL,
+            Err(e) => {
+                warn!("email notifications failed: {}", e);
+                POLL_INTERVAL
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stop.cancelled() => return,
+        }
+    }
+}
+
+// claimed notifications are out of the queue, one that fails to be sent is not retried
+async fn notify_due(state: &AppState) -> Result<usize, AppError> {
+    let due = DueNotification::claim(NOTIFY_BATCH, state.config.mail.notify_delay_secs, &state.pool).await?;
+    let reply = state.config.mail.reply.as_ref();
+    for n in &due {
+        let link = mailer::link(state, &format!("/chats/{}", n.chat_id));
+        let email = if n.direct {
+            mailer::direct_message(&n.fullname, &n.sender_name, &n.content, &link, reply.is_some())
+        } else {
+            let chat = n.chat_name.as_deref().unwrap_or("a chat");
+            mailer::mention(&n.fullname, &n.sender_name, chat, &n.content, &link, reply.is_some())
+        };
+        let reply_to = reply.map(|r| reply_address(r, n.message_id, n.user_id));
+        let ret = mailer::send_replyable(state, n.ws_id, &n.email, &email, reply_to.as_deref()).await;
+        if let Err(e) = ret {
+            warn!("email notification of message {} to user {} failed: {}", n.message_id, n.user_id, e);
+        }
+    }
+    Ok(due.len())
+}
+
+// reply+<message id>.<user id>.<signature>@<domain>, a reply is posted as that user
+pub(crate) fn reply_address(config: &MailReplyConfig, message_id: i64, user_id: i64) -> String {
+    format!(
+        "reply+{}.{}.{}@{}",
+        message_id,
+        user_id,
+        reply_signature(&config.secret, message_id, user_id),
+        config.domain
+    )
+}
+
+fn reply_signature(secret: &str, message_id: i64, user_id: i64) -> String {
+    let mac = hmac_sha256::HMAC::mac(format!("{}.{}", message_id, user_id).as_bytes(), secret.as_bytes());
+    let mut sig = hex_encode(&mac);
+    sig.truncate(REPLY_SIGNATURE_LEN);
+    sig
+}
+
+// the message and user of a reply address of ours with a valid signature
+fn parse_reply_address(config: &MailReplyConfig, address: &str) -> Option<(i64, i64)> {
+    let (local, domain) = address.trim().rsplit_once('@')?;
+    if !domain.eq_ignore_ascii_case(&config.domain) {
+        return None;
+    }
+    let mut parts = local.strip_prefix("reply+")?.splitn(3, '.');
+    let message_id = parts.next()?.parse().ok()?;
+    let user_id = parts.next()?.parse().ok()?;
+    let sig = parts.next()?.to_ascii_lowercase();
+    let expected = reply_signature(&config.secret, message_id, user_id);
+    constant_time_eq(sig.as_bytes(), expected.as_bytes()).then_some((message_id, user_id))
+}
+
+// addr@host out of Name <addr@host>
+fn bare_address(address: &str) -> &str {
+    let address = address.trim();
+    match (address.rfind('<'), address.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &address[start + 1..end],
+        _ => address,
+    }
+}
+
+// what the reply says, without the quoted email below it and the signature
+fn strip_quoted(text: &str) -> String {
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('>') || trimmed == "--" {
+            break;
+        }
+        // the attribution line of most clients, "On <date>, <name> wrote:"
+        if trimmed.starts_with("On ") && trimmed.ends_with("wrote:") {
+            break;
+        }
+        lines.push(line.trim_end());
+    }
+    lines.join("\n").trim().to_string()
+}
+
+// posts the reply in the chat of the message it answers, in its thread. Replies to direct
+// messages stay top level.
+pub(crate) async fn receive(state: &AppState, token: &str, input: &InboundEmail) -> Result<Message, AppError> {
+    let Some(config) = &state.config.mail.reply else {
+        return Err(AppError::NotFound("email replies are not enabled".to_string()));
+    };
+    if !constant_time_eq(token.as_bytes(), config.inbound_token.as_bytes()) {
+        return Err(AppError::PermissionDenied("invalid inbound token".to_string()));
+    }
+    let Some((message_id, user_id)) = input.to.split(',').find_map(|to| parse_reply_address(config, bare_address(to)))
+    else {
+        return Err(AppError::InvalidInput("no valid reply address in to".to_string()));
+    };
+
+    let user = User::find_by_id(user_id as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("user {}", user_id)))?;
+    // the address was only sent to the user, but it may have been forwarded
+    if !bare_address(&input.from).eq_ignore_ascii_case(&user.email) {
+        return Err(AppError::PermissionDenied("reply is not from the notified user".to_string()));
+    }
+    if user.is_deactivated(&state.pool).await? {
+        return Err(AppError::PermissionDenied(format!("user {} is deactivated", user_id)));
+    }
+    let original = Message::find_by_id(message_id, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("message {}", message_id)))?;
+    let chat = Chat::get_by_id(original.chat_id as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("chat {}", original.chat_id)))?;
+
+    let content = strip_quoted(&input.text);
+    if content.is_empty() {
+        return Err(AppError::InvalidInput("the reply is empty".to_string()));
+    }
+    let thread_id = match original.thread_id {
+        Some(thread_id) => Some(thread_id),
+        None if chat.r#type == ChatType::Single => None,
+        None => Some(original.id),
+    };
+    let input = CreateMessage {
+        content,
+        images: vec![],
+        client_created_at: None,
+        thread_id,
+        external_id: input
+            .message_id
+            .as_deref()
+            .map(str::trim)
+            .filter(|id| !id.is_empty() && id.len() <= MAX_EXTERNAL_ID_LEN)
+            .map(String::from),
+    };
+    Message::create(&input, chat.id as _, user.id as _, &state.pool).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppConfig;
+    use anyhow::Result;
+
+    fn reply_config() -> MailReplyConfig {
+        MailReplyConfig {
+            domain: "reply.acme.org".to_string(),
+            secret: "a-long-enough-secret".to_string(),
+            inbound_token: "a-long-enough-token".to_string(),
+        }
+    }
+
+    #[test]
+    fn reply_address_should_round_trip() {
+        let config = reply_config();
+        let address = reply_address(&config, 42, 3);
+        assert!(address.starts_with("reply+42.3."));
+        assert_eq!(parse_reply_address(&config, &address), Some((42, 3)));
+        assert_eq!(parse_reply_address(&config, &address.to_uppercase().replace("REPLY+", "reply+")), Some((42, 3)));
+        let forged = address.replace("reply+42.3.", "reply+42.4.");
+        assert_eq!(parse_reply_address(&config, &forged), None);
+        let other = address.replace("reply.acme.org", "acme.org");
+        assert_eq!(parse_reply_address(&config, &other), None);
+    }
+
+    #[test]
+    fn strip_quoted_should_keep_the_reply() {
+        let text = "Sounds good, ship it.\n\nOn Mon, Oct 12, 2026 at 10:00 Alice wrote:\n> can you take a look?\n";
+        assert_eq!(strip_quoted(text), "Sounds good, ship it.");
+        assert_eq!(strip_quoted("thanks\n-- \nTyr\n"), "thanks");
+        assert_eq!(strip_quoted("> only quoted"), "");
+        assert_eq!(bare_address("Tyr Chen <tchen@acme.org>"), "tchen@acme.org");
+    }
+
+    #[tokio::test]
+    async fn reply_should_post_in_the_thread() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.mail.reply = Some(reply_config());
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let original = Message::create(&CreateMessage::new("hi <@1>"), 1, 2, &state.pool).await?;
+        let user = User::find_by_id(1, &state.pool).await?.expect("user");
+        let email = InboundEmail {
+            from: format!("Tyr <{}>", user.email),
+            to: reply_address(&reply_config(), original.id, 1),
+            subject: "Re: mentioned you".to_string(),
+            text: "on it\n\nOn Mon, Alice wrote:\n> hi".to_string(),
+            message_id: Some("<abc@mail.acme.org>".to_string()),
+        };
+        let message = receive(&state, "a-long-enough-token", &email).await?;
+        assert_eq!(message.content, "on it");
+        assert_eq!(message.thread_id, Some(original.id));
+        assert_eq!(message.sender_id, 1);
+        // the same email again is the same message
+        assert_eq!(receive(&state, "a-long-enough-token", &email).await?.id, message.id);
+
+        let forwarded = InboundEmail { from: "someone@else.org".to_string(), ..email.clone() };
+        let ret = receive(&state, "a-long-enough-token", &forwarded).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = receive(&state, "wrong-token", &email).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+}
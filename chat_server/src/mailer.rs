@@ -0,0 +1,350 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{AppError, AppState, MailSettings, User, Workspace};
+
+// emails an admin asks for, test sends and mail settings checks, at most this many per window
+pub(crate) const MAX_REQUESTED_EMAILS: i64 = 10;
+const REQUESTED_WINDOW_SECS: i64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailTemplate {
+    Invite,
+    Digest,
+    Reset,
+    Verify,
+    SigninAlert,
+    MagicLink,
+    EmailChange,
+    Mention,
+    DirectMessage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Email {
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EmailPreview {
+    pub from: String,
+    pub to: String,
+    #[serde(flatten)]
+    pub email: Email,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendTestEmail {
+    pub template: EmailTemplate,
+    // defaults to the admin's own email
+    #[serde(default)]
+    pub to: Option<String>,
+}
+
+// queues the email in the outbox, delivery is up to the mail relay
+pub(crate) async fn send(state: &AppState, to: &str, email: &Email) -> Result<(), AppError> {
+    enqueue(&state.config.mail.from, to, email, None, None, None, &state.pool).await
+}
+
+// sent on behalf of the workspace, through its own smtp server once verified, with replies
+// going to reply_to rather than the sender
+pub(crate) async fn send_replyable(
+    state: &AppState,
+    ws_id: i64,
+    to: &str,
+    email: &Email,
+    reply_to: Option<&str>,
+) -> Result<(), AppError> {
+    match verified_settings(state, ws_id).await? {
+        Some(settings) => enqueue(&settings.sender(), to, email, Some(ws_id), reply_to, None, &state.pool).await,
+        None => enqueue(&state.config.mail.from, to, email, None, reply_to, None, &state.pool).await,
+    }
+}
+
+// sent on behalf of the workspace like send_replyable, but only to its active members, so
+// that admins can't relay mail to arbitrary addresses
+pub(crate) async fn send_test(state: &AppState, user: &User, to: &str, email: &Email) -> Result<(), AppError> {
+    let member: bool = sqlx::query_scalar(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM users WHERE ws_id = $1 AND lower(email) = lower($2) AND deactivated_at IS NULL
+        )
+        "#,
+    )
+    .bind(user.ws_id)
+    .bind(to)
+    .fetch_one(&state.pool)
+    .await?;
+    if !member {
+        return Err(AppError::InvalidInput(format!("{} is not a member of the workspace", to)));
+    }
+    check_requested_limit(user.id, &state.pool).await?;
+    let (from, ws_id) = match verified_settings(state, user.ws_id).await? {
+        Some(settings) => (settings.sender(), Some(user.ws_id)),
+        None => (state.config.mail.from.clone(), None),
+    };
+    enqueue(&from, to, email, ws_id, None, Some(user.id), &state.pool).await
+}
+
+// fails with the seconds until the user may ask for another email once they used up the window
+pub(crate) async fn check_requested_limit(user_id: i64, pool: &PgPool) -> Result<(), AppError> {
+    let (count, retry_after): (i64, i64) = sqlx::query_as(
+        r#"
+        SELECT COUNT(*),
+            COALESCE(EXTRACT(EPOCH FROM min(created_at) + make_interval(secs => $2) - NOW()), 0)::bigint
+        FROM outbound_emails
+        WHERE requested_by = $1 AND created_at > NOW() - make_interval(secs => $2)
+        "#,
+    )
+    .bind(user_id)
+    .bind(REQUESTED_WINDOW_SECS as f64)
+    .fetch_one(pool)
+    .await?;
+    if count >= MAX_REQUESTED_EMAILS {
+        return Err(AppError::TooManyAttempts(retry_after.max(1) as u64));
+    }
+    Ok(())
+}
+
+// the From address emails of the workspace go out with
+pub(crate) async fn workspace_sender(state: &AppState, ws_id: i64) -> Result<String, AppError> {
+    let settings = verified_settings(state, ws_id).await?;
+    Ok(settings.map(|s| s.sender()).unwrap_or_else(|| state.config.mail.from.clone()))
+}
+
+// goes through the settings being verified, so that it only arrives if they work
+pub(crate) async fn send_mail_verification(
+    state: &AppState,
+    settings: &MailSettings,
+    token: &str,
+    requested_by: i64,
+) -> Result<(), AppError> {
+    let link = link(state, &format!("/api/workspace/mail/verify?token={}", token));
+    let email = Email {
+        subject: "Confirm your workspace email settings".to_string(),
+        body: format!(
+            "Hi,\n\nThis address was set up to send the workspace's emails. Open the link below to start using it:\n\n{}\n",
+            link
+        ),
+    };
+    let sender = settings.sender();
+    enqueue(&sender, &settings.from_email, &email, Some(settings.ws_id), None, Some(requested_by), &state.pool).await
+}
+
+async fn verified_settings(state: &AppState, ws_id: i64) -> Result<Option<MailSettings>, AppError> {
+    let settings = MailSettings::fetch(ws_id as _, &state.pool).await?;
+    Ok(settings.filter(|s| s.verified_at.is_some()))
+}
+
+// absolute link to a path of this server, for use in emails
+pub(crate) fn link(state: &AppState, path: &str) -> String {
+    format!("{}{}", state.config.mail.base_url.trim_end_matches('/'), path)
+}
+
+pub(crate) fn reset_password(fullname: &str, link: &str) -> Email {
+    Email {
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Hi {},\n\nAll your sessions have been signed out. Choose a new password here:\n\n{}\n",
+            fullname, link
+        ),
+    }
+}
+
+pub(crate) fn verify_email(fullname: &str, link: &str) -> Email {
+    Email {
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Hi {},\n\nPlease confirm your email address by opening the link below:\n\n{}\n",
+            fullname, link
+        ),
+    }
+}
+
+pub(crate) fn magic_link(fullname: &str, link: &str) -> Email {
+    Email {
+        subject: "Your signin link".to_string(),
+        body: format!(
+            "Hi {},\n\nOpen the link below within 15 minutes to sign in. It works once:\n\n{}\n\nIf you didn't ask for it, you can ignore this email.\n",
+            fullname, link
+        ),
+    }
+}
+
+// sent to the new address, the change only happens once it's confirmed
+pub(crate) fn confirm_email_change(fullname: &str, link: &str) -> Email {
+    Email {
+        subject: "Confirm your new email address".to_string(),
+        body: format!(
+            "Hi {},\n\nOpen the link below within 24 hours to start using this address for your account:\n\n{}\n",
+            fullname, link
+        ),
+    }
+}
+
+// sent to the current address when a change is requested, and again once it's done
+pub(crate) fn email_change_notice(fullname: &str, new_email: &str, done: bool) -> Email {
+    let what = if done {
+        format!("Your account's email address was changed to {}.", new_email)
+    } else {
+        format!("A change of your account's email address to {} was requested.", new_email)
+    };
+    Email {
+        subject: "Your email address is changing".to_string(),
+        body: format!(
+            "Hi {},\n\n{}\n\nIf this wasn't you, reset your password and contact your workspace admin.\n",
+            fullname, what
+        ),
+    }
+}
+
+pub(crate) fn signin_alert(fullname: &str, user_agent: &str, country: Option<&str>, link: &str) -> Email {
+    Email {
+        subject: "New signin to your account".to_string(),
+        body: format!(
+            "Hi {},\n\nYour account was just signed in to from {} ({}).\n\nIf this wasn't you, open the link below to sign out everywhere and reset your password:\n\n{}\n",
+            fullname,
+            user_agent,
+            country.unwrap_or("unknown location"),
+            link
+        ),
+    }
+}
+
+pub(crate) fn chat_invite(inviter: &str, workspace: &str, chat: &str, link: &str) -> Email {
+    Email {
+        subject: format!("{} invited you to {} on {}", inviter, chat, workspace),
+        body: format!(
+            "Hi,\n\n{} invited you to join {} in the {} workspace. Open the link below to join:\n\n{}\n",
+            inviter, chat, workspace, link
+        ),
+    }
+}
+
+// unread is the message count by chat name
+pub(crate) fn digest(fullname: &str, workspace: &str, unread: &[(&str, i64)], link: &str) -> Email {
+    let total: i64 = unread.iter().map(|(_, n)| n).sum();
+    let lines: Vec<String> = unread.iter().map(|(chat, n)| format!("  {}: {}", chat, n)).collect();
+    Email {
+        subject: format!("{} unread messages in {}", total, workspace),
+        body: format!(
+            "Hi {},\n\nHere is what you missed in {}:\n\n{}\n\nCatch up here:\n\n{}\n",
+            fullname,
+            workspace,
+            lines.join("\n"),
+            link
+        ),
+    }
+}
+
+// the text is quoted, replying above it answers in the chat when replies are enabled
+pub(crate) fn mention(fullname: &str, sender: &str, chat: &str, text: &str, link: &str, replyable: bool) -> Email {
+    Email {
+        subject: format!("{} mentioned you in {}", sender, chat),
+        body: format!(
+            "Hi {},\n\n{} mentioned you in {}:\n\n{}\n\n{}\n\n{}\n",
+            fullname,
+            sender,
+            chat,
+            quote(text),
+            reply_hint(replyable),
+            link
+        ),
+    }
+}
+
+pub(crate) fn direct_message(fullname: &str, sender: &str, text: &str, link: &str, replyable: bool) -> Email {
+    Email {
+        subject: format!("New message from {}", sender),
+        body: format!(
+            "Hi {},\n\n{} sent you a message:\n\n{}\n\n{}\n\n{}\n",
+            fullname,
+            sender,
+            quote(text),
+            reply_hint(replyable),
+            link
+        ),
+    }
+}
+
+fn quote(text: &str) -> String {
+    text.lines().map(|line| format!("> {}", line)).collect::<Vec<_>>().join("\n")
+}
+
+fn reply_hint(replyable: bool) -> &'static str {
+    if replyable {
+        "Reply to this email to answer, or open the chat:"
+    } else {
+        "Open the chat to answer:"
+    }
+}
+
+impl EmailTemplate {
+    // the template rendered for the given user, with made up chats and tokens
+    pub(crate) async fn sample(self, user: &User, state: &AppState) -> Result<Email, AppError> {
+        let ws = Workspace::find_by_id(user.ws_id as _, &state.pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("workspace not found: {}", user.ws_id)))?;
+        let email = match self {
+            Self::Invite => chat_invite(&user.fullname, &ws.name, "general", &link(state, "/join/sample-token")),
+            Self::Digest => digest(&user.fullname, &ws.name, &[("general", 12), ("random", 3)], &link(state, "/")),
+            Self::Reset => reset_password(&user.fullname, &link(state, "/reset-password?token=sample-token")),
+            Self::Verify => verify_email(&user.fullname, &link(state, "/api/verify?token=sample-token")),
+            Self::SigninAlert => signin_alert(
+                &user.fullname,
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_0)",
+                Some("US"),
+                &link(state, "/api/signin/deny?token=sample-token"),
+            ),
+            Self::MagicLink => magic_link(&user.fullname, &link(state, "/signin/magic?token=sample-token")),
+            Self::EmailChange => confirm_email_change(&user.fullname, &link(state, "/api/me/email/confirm?token=sample-token")),
+            Self::Mention => mention(
+                &user.fullname,
+                "Alice",
+                "general",
+                &format!("<@{}> can you take a look?", user.id),
+                &link(state, "/chats/1"),
+                state.config.mail.reply.is_some(),
+            ),
+            Self::DirectMessage => direct_message(
+                &user.fullname,
+                "Alice",
+                "are you around?",
+                &link(state, "/chats/1"),
+                state.config.mail.reply.is_some(),
+            ),
+        };
+        Ok(email)
+    }
+}
+
+// the relay sends emails with a ws_id through the workspace's smtp server, and sets
+// Reply-To when there is one. requested_by counts towards check_requested_limit.
+async fn enqueue(
+    from: &str,
+    to: &str,
+    email: &Email,
+    ws_id: Option<i64>,
+    reply_to: Option<&str>,
+    requested_by: Option<i64>,
+    pool: &PgPool,
+) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        INSERT INTO outbound_emails (from_email, to_email, subject, body, ws_id, reply_to, requested_by)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+    )
+    .bind(from)
+    .bind(to)
+    .bind(&email.subject)
+    .bind(&email.body)
+    .bind(ws_id)
+    .bind(reply_to)
+    .bind(requested_by)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
@@ -1,21 +1,58 @@
-use anyhow::Result;
-use chat_server::{get_router, AppConfig};
+use anyhow::{bail, Result};
+use chat_server::{create_admin, migrate, seed, serve, wait_for_database, AppConfig, CheckStatus, DoctorReport, LogFormat};
 use tokio::net::TcpListener;
-use tracing::{info, level_filters::LevelFilter};
-use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+use tracing::{error, info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, reload, util::SubscriberInitExt, Layer as _};
 
 #[tokio::main]
 async fn main() -> Result<()>{
-    let layer = Layer::new().with_filter(LevelFilter::INFO);
-    tracing_subscriber::registry().with(layer).init();
-    
     let config = AppConfig::load()?;
+    // follows log.level as the config file changes, see reload::watch
+    let (level, log_level) = reload::Layer::new(LevelFilter::from(config.log.level));
+    let layer = match config.log.format {
+        LogFormat::Text => Layer::new().with_filter(level).boxed(),
+        LogFormat::Json => Layer::new()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_filter(level)
+            .boxed(),
+    };
+    tracing_subscriber::registry().with(layer).init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // doctor reports a database that is down rather than waiting for it
+    if args.first().map(String::as_str) != Some("doctor") {
+        wait_for_database(&config).await?;
+    }
+    // bootstrapping a deployment, these run before the startup checks, which fail until then
+    match args.first().map(String::as_str) {
+        Some("migrate") => return migrate(&config).await,
+        Some("seed") => return seed(&config).await,
+        Some("create-admin") => return create_admin(&config, &args[1..]).await,
+        _ => {}
+    }
+
+    // `chat_server doctor` runs the startup checks and prints the report without serving
+    let report = DoctorReport::run(&config).await;
+    if args.first().map(String::as_str) == Some("doctor") {
+        print!("{}", report);
+        std::process::exit(if report.is_healthy() { 0 } else { 1 });
+    }
+    for check in &report.checks {
+        match check.status {
+            CheckStatus::Ok => info!("{}: {}", check.name, check.message),
+            CheckStatus::Warn => warn!("{}: {}", check.name, check.message),
+            CheckStatus::Fail => error!("{}: {}", check.name, check.message),
+        }
+    }
+    if !report.is_healthy() {
+        bail!("startup checks failed, see above or run `chat_server doctor`");
+    }
     let addr = format!("0.0.0.0:{}", config.server.port);
     
-    let app = get_router(config).await?;
     let listener = TcpListener::bind(&addr).await?;
     info!("Listening on {}", &addr);
-    axum::serve(listener, app.into_make_service()).await?;
-    Ok(())
+    serve(config, listener, log_level).await
 }
-
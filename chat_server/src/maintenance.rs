@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+use chrono::{Months, Utc};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{AppError, AppState, Message};
+
+const PARTITION_CHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+// creates upcoming message partitions and expires old ones, at startup and then every few hours
+// until stop is cancelled.
+pub(crate) async fn manage_partitions(state: AppState, stop: CancellationToken) {
+    loop {
+        if let Err(e) = rotate_partitions(&state).await {
+            warn!("manage message partitions failed: {}", e);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(PARTITION_CHECK_INTERVAL) => {}
+            _ = stop.cancelled() => return,
+        }
+    }
+}
+
+async fn rotate_partitions(state: &AppState) -> Result<(), AppError> {
+    let config = &state.config.server.message_partitions;
+    Message::create_partitions(config.months_ahead, &state.pool).await?;
+    let Some(months) = config.retention_months else {
+        return Ok(());
+    };
+    let Some(before) = Utc::now().checked_sub_months(Months::new(months)) else {
+        return Ok(());
+    };
+    let expired = Message::expire_partitions(before, config.drop_expired, &state.pool).await?;
+    if !expired.is_empty() {
+        info!("expired message partitions: {}", expired.join(", "));
+    }
+    Ok(())
+}
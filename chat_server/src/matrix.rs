@@ -0,0 +1,387 @@
+use std::time::Duration;
+
+use axum::{http::StatusCode, response::{IntoResponse, Response}, Json};
+use chrono::Utc;
+use futures::future::join_all;
+use reqwest::{RequestBuilder, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::{
+    models::{DueRoom, MatrixHomeserver, MatrixPuppet},
+    utils::HttpError,
+    AppError, AppState, BridgeIdentity, BridgeKind, Chat, CreateMessage, MatrixRoom, Message, RemoteIdentity, User,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const SYNC_BATCH: usize = 20;
+// messages sent to a room per claim
+const MESSAGE_BATCH: usize = 50;
+const CLAIM_LEASE_SECS: u64 = 120;
+// a room that failed waits this long before it is tried again
+const RETRY_SECS: i64 = 30;
+const MAX_DISPLAY_NAME_CHARS: usize = 64;
+
+// errors in the shape the matrix spec gives them, for the homeserver
+#[derive(Debug)]
+pub(crate) struct MatrixError {
+    status: StatusCode,
+    errcode: &'static str,
+    error: String,
+}
+
+impl MatrixError {
+    pub(crate) fn new(status: StatusCode, errcode: &'static str, error: impl Into<String>) -> Self {
+        Self { status, errcode, error: error.into() }
+    }
+
+    pub(crate) fn not_found(error: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "M_NOT_FOUND", error)
+    }
+}
+
+impl From<AppError> for MatrixError {
+    fn from(e: AppError) -> Self {
+        let (status, errcode) = match &e {
+            AppError::NotFound(_) => (StatusCode::NOT_FOUND, "M_NOT_FOUND"),
+            AppError::PermissionDenied(_) => (StatusCode::FORBIDDEN, "M_FORBIDDEN"),
+            AppError::InvalidInput(_) => (StatusCode::BAD_REQUEST, "M_INVALID_PARAM"),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "M_UNKNOWN"),
+        };
+        Self::new(status, errcode, e.to_string())
+    }
+}
+
+impl IntoResponse for MatrixError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "errcode": self.errcode, "error": self.error }))).into_response()
+    }
+}
+
+// what the homeserver pushes to the application service
+#[derive(Debug, Deserialize)]
+pub(crate) struct Transaction {
+    #[serde(default)]
+    pub events: Vec<RoomEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RoomEvent {
+    pub r#type: String,
+    #[serde(default)]
+    pub event_id: String,
+    #[serde(default)]
+    pub room_id: String,
+    #[serde(default)]
+    pub sender: String,
+    #[serde(default)]
+    pub content: Value,
+}
+
+#[derive(Debug, Error)]
+enum HomeserverError {
+    #[error("invalid homeserver url: {0}")]
+    Url(String),
+    #[error(transparent)]
+    Http(#[from] HttpError),
+    #[error(transparent)]
+    Body(#[from] reqwest::Error),
+    #[error("homeserver responded with {status} {errcode}: {error}")]
+    Matrix { status: u16, errcode: String, error: String },
+    #[error(transparent)]
+    App(#[from] AppError),
+}
+
+impl RoomEvent {
+    // what a text message says, None for other events and edits
+    fn text(&self) -> Option<String> {
+        if self.r#type != "m.room.message" || self.content["m.relates_to"]["rel_type"] == "m.replace" {
+            return None;
+        }
+        let body = self.content["body"].as_str()?.trim();
+        if body.is_empty() {
+            return None;
+        }
+        match self.content["msgtype"].as_str()? {
+            "m.text" | "m.notice" => Some(body.to_string()),
+            // what /me sends here
+            "m.emote" => Some(format!("_{}_", body)),
+            _ => None,
+        }
+    }
+}
+
+// messages from matrix, sent as the ghosts of their senders. The event id is the message's
+// external id, so a transaction the homeserver retries doesn't post anything twice.
+pub(crate) async fn receive(state: &AppState, hs: &MatrixHomeserver, txn: &Transaction) -> Result<(), AppError> {
+    for event in &txn.events {
+        // our own messages coming back
+        if hs.is_ours(&event.sender) {
+            continue;
+        }
+        let Some(content) = event.text() else {
+            continue;
+        };
+        let Some(room) = MatrixRoom::find_by_room(hs.ws_id as _, &event.room_id, &state.pool).await? else {
+            continue;
+        };
+        match post(state, hs, &room, event, content).await {
+            Ok(_) => {}
+            // nothing a retry would change
+            Err(e @ (AppError::InvalidInput(_) | AppError::CreateMessageError(_) | AppError::UpdateChatError(_))) => {
+                warn!("drop matrix event {} in {}: {}", event.event_id, event.room_id, e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+async fn post(state: &AppState, hs: &MatrixHomeserver, room: &MatrixRoom, event: &RoomEvent, content: String) -> Result<Message, AppError> {
+    let remote = RemoteIdentity {
+        bridge: BridgeKind::Matrix,
+        remote_id: event.sender.clone(),
+        display_name: display_name(state, hs, &event.sender).await,
+        avatar_url: None,
+    };
+    let identity = BridgeIdentity::resolve(hs.ws_id as _, &remote, &state.pool).await?;
+    let chat = Chat::get_by_id(room.chat_id as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("chat {}", room.chat_id)))?;
+    if !chat.members.contains(&identity.user_id) {
+        chat.add_bridged_member(identity.user_id as _, &state.pool).await?;
+    }
+    let input = CreateMessage {
+        content,
+        images: vec![],
+        client_created_at: None,
+        thread_id: None,
+        external_id: Some(event.event_id.clone()),
+    };
+    Message::create(&input, room.chat_id as _, identity.user_id as _, &state.pool).await
+}
+
+// the sender's matrix display name, or their localpart if it can't be had
+async fn display_name(state: &AppState, hs: &MatrixHomeserver, mxid: &str) -> String {
+    let localpart = mxid.trim_start_matches('@').split(':').next().unwrap_or(mxid);
+    let name = match endpoint(hs, &["profile", mxid, "displayname"], None) {
+        Ok(url) => call(state, hs, state.http.get(url)).await,
+        Err(e) => Err(e),
+    };
+    let name = match &name {
+        Ok(profile) => profile["displayname"].as_str().map(str::trim).filter(|name| !name.is_empty()),
+        Err(e) => {
+            warn!("get matrix profile of {} failed: {}", mxid, e);
+            None
+        }
+    };
+    name.unwrap_or(localpart).chars().take(MAX_DISPLAY_NAME_CHARS).collect()
+}
+
+// has the bridge's user join the room, for rooms about to be linked. Returns the room id,
+// which is what an alias stands for.
+pub(crate) async fn join_room(state: &AppState, hs: &MatrixHomeserver, room: &str) -> Result<String, AppError> {
+    let joined = match endpoint(hs, &["join", room], None) {
+        Ok(url) => call(state, hs, state.http.post(url).json(&json!({}))).await,
+        Err(e) => Err(e),
+    };
+    match joined {
+        Ok(body) => Ok(body["room_id"].as_str().unwrap_or(room).to_string()),
+        Err(e) => Err(AppError::InvalidInput(format!("can't join matrix room {}: {}", room, e))),
+    }
+}
+
+// sends new messages of linked chats to their rooms until stop is cancelled. Every instance
+// runs one, each room is claimed by one of them at a time.
+pub(crate) async fn sync(state: AppState, stop: CancellationToken) {
+    loop {
+        let delay = match sync_due(&state).await {
+            // more may be due
+            Ok(n) if n == SYNC_BATCH => Duration::ZERO,
+            Ok(_) => POLL_INTERVAL,
+            Err(e) => {
+                warn!("sync matrix rooms failed: {}", e);
+                POLL_INTERVAL
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stop.cancelled() => return,
+        }
+    }
+}
+
+async fn sync_due(state: &AppState) -> Result<usize, AppError> {
+    let rooms = MatrixRoom::claim_behind(SYNC_BATCH, CLAIM_LEASE_SECS, &state.pool).await?;
+    let n = rooms.len();
+    join_all(rooms.into_iter().map(|room| sync_room(state, room))).await;
+    Ok(n)
+}
+
+async fn sync_room(state: &AppState, room: DueRoom) {
+    let mut synced_seq = room.synced_seq;
+    let (error, retry_at) = match push(state, &room, &mut synced_seq).await {
+        Ok(()) => (None, None),
+        Err(e) => {
+            warn!("send chat {} to matrix room {} failed: {}", room.chat_id, room.room_id, e);
+            (Some(e.to_string()), Some(Utc::now() + chrono::Duration::seconds(RETRY_SECS)))
+        }
+    };
+    let ret = MatrixRoom::record_sync(room.chat_id, synced_seq, error.as_deref(), retry_at, &state.pool).await;
+    // the claim runs out and the messages are sent again, the homeserver drops the repeats
+    if let Err(e) = ret {
+        warn!("record matrix sync of chat {} failed: {}", room.chat_id, e);
+    }
+}
+
+// sends messages in order, synced_seq follows the last one sent
+async fn push(state: &AppState, room: &DueRoom, synced_seq: &mut i64) -> Result<(), HomeserverError> {
+    let messages = Message::list_since(room.chat_id as _, room.synced_seq, MESSAGE_BATCH, &state.pool).await?;
+    let senders: Vec<i64> = messages.iter().map(|msg| msg.sender_id).collect();
+    // those came from matrix
+    let ghosts = BridgeIdentity::bridged_users(room.homeserver.ws_id as _, BridgeKind::Matrix, &senders, &state.pool).await?;
+    for msg in messages {
+        if !ghosts.contains(&msg.sender_id) {
+            send(state, room, &msg).await?;
+        }
+        *synced_seq = msg.seq;
+    }
+    Ok(())
+}
+
+async fn send(state: &AppState, room: &DueRoom, msg: &Message) -> Result<(), HomeserverError> {
+    let hs = &room.homeserver;
+    let puppet = puppet(state, hs, msg.sender_id).await?;
+    let body = match &msg.display_name {
+        Some(name) => format!("{}: {}", name, msg.content),
+        None => msg.content.clone(),
+    };
+    let content = json!({ "msgtype": "m.text", "body": body });
+    // the same for every attempt, so the homeserver only keeps one
+    let txn_id = format!("chat-{}", msg.id);
+    let url = endpoint(hs, &["rooms", &room.room_id, "send", "m.room.message", &txn_id], Some(&puppet.mxid))?;
+    match call(state, hs, state.http.put(url.clone()).json(&content)).await {
+        Err(HomeserverError::Matrix { errcode, .. }) if errcode == "M_FORBIDDEN" => {
+            // not in the room yet. The bridge's user invites first, for rooms that need it.
+            let invite = endpoint(hs, &["rooms", &room.room_id, "invite"], None)?;
+            if let Err(e) = call(state, hs, state.http.post(invite).json(&json!({ "user_id": puppet.mxid }))).await {
+                warn!("invite {} to {} failed: {}", puppet.mxid, room.room_id, e);
+            }
+            let join = endpoint(hs, &["rooms", &room.room_id, "join"], Some(&puppet.mxid))?;
+            call(state, hs, state.http.post(join).json(&json!({}))).await?;
+            call(state, hs, state.http.put(url).json(&content)).await?;
+        }
+        ret => {
+            ret?;
+        }
+    }
+    Ok(())
+}
+
+// the member's matrix user, registered and named after the member the first time and renamed
+// when the member is
+async fn puppet(state: &AppState, hs: &MatrixHomeserver, user_id: i64) -> Result<MatrixPuppet, HomeserverError> {
+    let name = match User::find_by_id(user_id as _, &state.pool).await? {
+        Some(user) => user.fullname,
+        None => format!("user {}", user_id),
+    };
+    let mxid = hs.puppet_mxid(user_id);
+    let existing = MatrixPuppet::find(user_id, &state.pool).await?;
+    match &existing {
+        Some(puppet) if puppet.mxid == mxid && puppet.display_name == name => return Ok(puppet.clone()),
+        Some(puppet) if puppet.mxid == mxid => {}
+        _ => {
+            let body = json!({
+                "type": "m.login.application_service",
+                "username": hs.puppet_localpart(user_id),
+                "inhibit_login": true,
+            });
+            match call(state, hs, state.http.post(endpoint(hs, &["register"], None)?).json(&body)).await {
+                Err(HomeserverError::Matrix { errcode, .. }) if errcode == "M_USER_IN_USE" => {}
+                ret => {
+                    ret?;
+                }
+            }
+        }
+    }
+    let url = endpoint(hs, &["profile", &mxid, "displayname"], Some(&mxid))?;
+    call(state, hs, state.http.put(url).json(&json!({ "displayname": name }))).await?;
+    let puppet = MatrixPuppet { user_id, mxid, display_name: name };
+    puppet.save(&state.pool).await?;
+    Ok(puppet)
+}
+
+// a client-server api url, requests on behalf of a puppet name it in user_id
+fn endpoint(hs: &MatrixHomeserver, path: &[&str], user_id: Option<&str>) -> Result<Url, HomeserverError> {
+    let mut url = Url::parse(&hs.homeserver_url).map_err(|_| HomeserverError::Url(hs.homeserver_url.clone()))?;
+    url.path_segments_mut()
+        .map_err(|_| HomeserverError::Url(hs.homeserver_url.clone()))?
+        .pop_if_empty()
+        .extend(["_matrix", "client", "v3"])
+        .extend(path);
+    if let Some(user_id) = user_id {
+        url.query_pairs_mut().append_pair("user_id", user_id);
+    }
+    Ok(url)
+}
+
+async fn call(state: &AppState, hs: &MatrixHomeserver, request: RequestBuilder) -> Result<Value, HomeserverError> {
+    let res = state.http.send(request.bearer_auth(&hs.as_token)).await?;
+    let status = res.status();
+    let body: Value = serde_json::from_slice(&res.bytes().await?).unwrap_or_default();
+    if status.is_success() {
+        return Ok(body);
+    }
+    Err(HomeserverError::Matrix {
+        status: status.as_u16(),
+        errcode: body["errcode"].as_str().unwrap_or("M_UNKNOWN").to_string(),
+        error: body["error"].as_str().unwrap_or_default().to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(content: Value) -> RoomEvent {
+        RoomEvent {
+            r#type: "m.room.message".to_string(),
+            event_id: "$1".to_string(),
+            room_id: "!general:acme.org".to_string(),
+            sender: "@alice:matrix.org".to_string(),
+            content,
+        }
+    }
+
+    #[test]
+    fn room_event_text_should_only_take_messages() {
+        assert_eq!(event(json!({ "msgtype": "m.text", "body": "hi" })).text().as_deref(), Some("hi"));
+        assert_eq!(event(json!({ "msgtype": "m.emote", "body": "waves" })).text().as_deref(), Some("_waves_"));
+        assert_eq!(event(json!({ "msgtype": "m.image", "body": "cat.png" })).text(), None);
+        let edit = json!({ "msgtype": "m.text", "body": "* hi", "m.relates_to": { "rel_type": "m.replace" } });
+        assert_eq!(event(edit).text(), None);
+        let member = RoomEvent { r#type: "m.room.member".to_string(), ..event(json!({ "membership": "join" })) };
+        assert_eq!(member.text(), None);
+    }
+
+    #[test]
+    fn endpoint_should_escape_ids() -> anyhow::Result<()> {
+        let hs = MatrixHomeserver {
+            ws_id: 1,
+            homeserver_url: "https://matrix.acme.org/".to_string(),
+            server_name: "acme.org".to_string(),
+            sender_localpart: "chat".to_string(),
+            user_prefix: "chat_".to_string(),
+            as_token: String::new(),
+        };
+        let url = endpoint(&hs, &["rooms", "!a/b:acme.org", "join"], Some("@chat_1:acme.org"))?;
+        assert_eq!(
+            url.as_str(),
+            "https://matrix.acme.org/_matrix/client/v3/rooms/!a%2Fb:acme.org/join?user_id=%40chat_1%3Aacme.org"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use std::borrow::Cow;
+
+use axum::{
+    extract::Request,
+    http::HeaderValue,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::AppError;
+
+// a new version is added when a response shape changes in a way old clients would break on.
+// /api is the current version, clients that can't follow it use /api/v<n> or send the header.
+// v2: listings answer with Paginated rather than a bare array
+pub const CURRENT_API_VERSION: u32 = 2;
+// oldest first, a version stays here until its clients have moved on
+const SUPPORTED_API_VERSIONS: &[u32] = &[1, 2];
+pub(super) const API_VERSION_HEADER: &str = "api-version";
+
+// the version a request to /api was served with, handlers of routes whose response differs
+// between versions branch on it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ApiVersion(pub u32);
+
+// /api/v1/chats as /api/chats, other paths as they are. Path based config (rate limits, request
+// limits) is written without the version and applies to all of them.
+pub(crate) fn unversioned(path: &str) -> Cow<'_, str> {
+    match path.strip_prefix("/api/").and_then(|rest| rest.split_once('/')) {
+        Some((segment, rest)) if parse_version(segment).is_some() => Cow::Owned(format!("/api/{}", rest)),
+        _ => Cow::Borrowed(path),
+    }
+}
+
+fn parse_version(segment: &str) -> Option<u32> {
+    segment.strip_prefix('v')?.parse().ok()
+}
+
+// the version comes from the path, else from the api-version header, else it is the current
+// one. Versions that aren't supported are refused rather than served with another shape.
+pub async fn negotiate_api_version(mut request: Request, next: Next) -> Response {
+    let Some(rest) = request.uri().path().strip_prefix("/api/") else {
+        return next.run(request).await;
+    };
+    let version = match rest.split('/').next().and_then(parse_version) {
+        Some(v) if SUPPORTED_API_VERSIONS.contains(&v) => v,
+        Some(v) => return AppError::NotFound(format!("api version v{} ({})", v, supported())).into_response(),
+        None => match request.headers().get(API_VERSION_HEADER) {
+            None => CURRENT_API_VERSION,
+            Some(value) => match value.to_str().ok().and_then(|v| v.trim().trim_start_matches('v').parse().ok()) {
+                Some(v) if SUPPORTED_API_VERSIONS.contains(&v) => v,
+                _ => {
+                    let requested = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                    return AppError::UnsupportedApiVersion(format!("{} ({})", requested, supported())).into_response();
+                }
+            },
+        },
+    };
+    request.extensions_mut().insert(ApiVersion(version));
+    let mut res = next.run(request).await;
+    res.headers_mut().insert(API_VERSION_HEADER, HeaderValue::from(version));
+    res
+}
+
+fn supported() -> String {
+    let versions: Vec<String> = SUPPORTED_API_VERSIONS.iter().map(|v| format!("v{}", v)).collect();
+    format!("supported: {}", versions.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{middlewares::set_layer, AppConfig, AppState};
+    use anyhow::Result;
+    use axum::{body::Body, http::StatusCode, routing::get, Extension, Router};
+    use tower::ServiceExt;
+
+    #[test]
+    fn unversioned_should_strip_the_version() {
+        assert_eq!(unversioned("/api/v1/chats/1"), "/api/chats/1");
+        assert_eq!(unversioned("/api/chats/1"), "/api/chats/1");
+        assert_eq!(unversioned("/api/verify"), "/api/verify");
+        assert_eq!(unversioned("/hooks/v1/x"), "/hooks/v1/x");
+    }
+
+    #[tokio::test]
+    async fn api_version_should_be_negotiated() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let api = Router::new().route(
+            "/chats",
+            get(|Extension(ApiVersion(v)): Extension<ApiVersion>| async move { v.to_string() }),
+        );
+        let app = set_layer(Router::new().nest("/api/v1", api.clone()).nest("/api", api), state);
+        let request = |path: &str, header: Option<&str>| {
+            let mut req = Request::get(path);
+            if let Some(v) = header {
+                req = req.header(API_VERSION_HEADER, v);
+            }
+            req.body(Body::empty()).expect("request")
+        };
+
+        let res = app.clone().oneshot(request("/api/v1/chats", None)).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[API_VERSION_HEADER], "1");
+        let res = app.clone().oneshot(request("/api/chats", Some("1"))).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.clone().oneshot(request("/api/v9/chats", None)).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = app.oneshot(request("/api/chats", Some("9"))).await?;
+        assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+        Ok(())
+    }
+}
@@ -1,33 +1,345 @@
-use axum::{extract::{FromRequestParts, Request, State}, http::StatusCode, middleware::Next, response::{IntoResponse, Response}};
+use axum::{extract::{FromRequestParts, Request, State}, http::{request::Parts, StatusCode}, middleware::Next, response::{IntoResponse, Response}};
 use axum_extra::{headers::{authorization::Bearer, Authorization}, TypedHeader};
-use tracing::warn;
+use tracing::{warn, Span};
 
-use crate::AppState;
+use crate::{matrix::MatrixError, middlewares::ClientIp, models::MatrixHomeserver, oauth_provider::app_may_call, scim::ScimError, utils::{constant_time_eq, cookie_value, verify_csrf}, AppError, AppState, Bot, IpAllowlist, RevokedToken, Workspace, BOT_TOKEN_PREFIX};
 
 pub async fn verify_token(State(state): State<AppState>, req: Request, next: Next) -> Response {
     let (mut parts, body) = req.into_parts();
-    let req = 
-        match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
-            Ok(TypedHeader(Authorization(bearer))) => {
-                let token = bearer.token();
-                match state.dk.verify(&token) {
-                    Ok(user) => {
-                        let mut req = Request::from_parts(parts, body);
-                        req.extensions_mut().insert(user);
-                        req
+    let token = match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
+        Ok(TypedHeader(Authorization(bearer))) => Ok(bearer.token().to_string()),
+        Err(e) => match session_cookie(&state, &parts) {
+            Some(token) => {
+                if !parts.method.is_safe() && !verify_csrf(&state.config.auth.cookie_session, &parts.headers) {
+                    warn!("{} {} with a session cookie but no matching csrf token", parts.method, parts.uri);
+                    return AppError::PermissionDenied("csrf token missing or invalid".to_string()).into_response();
+                }
+                Ok(token.to_string())
+            }
+            None => Err(e),
+        },
+    };
+    let token = match token {
+        Ok(token) => token,
+        Err(e) => {
+            let msg = format!("parse Authorization header failed: {}", e);
+            warn!(msg);
+            return (StatusCode::UNAUTHORIZED, msg).into_response();
+        }
+    };
+    // bots have a long lived token of their own, it is revoked by rotating or deleting the bot
+    let bot = token.starts_with(BOT_TOKEN_PREFIX);
+    let (user, grant, id) = if bot {
+        match Bot::authenticate(&token, &state.pool).await {
+            Ok(Some((user, grant))) => (user, grant, None),
+            Ok(None) => {
+                warn!("request with an unknown bot token");
+                return (StatusCode::UNAUTHORIZED, "invalid bot token").into_response();
+            }
+            Err(e) => {
+                let msg = format!("verify bot token failed: {}", e);
+                warn!(msg);
+                return (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response();
+            }
+        }
+    } else {
+        match state.dk.verify_claims(&token) {
+            Ok((user, grant, id)) => {
+                match RevokedToken::is_revoked(&id.jti, &state.pool).await {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        let msg = format!("token {} has been revoked", id.jti);
+                        warn!(msg);
+                        return (StatusCode::UNAUTHORIZED, msg).into_response();
                     }
                     Err(e) => {
-                        let msg = format!("verify token failed: {}", e);
+                        let msg = format!("check token revocation failed: {}", e);
                         warn!(msg);
-                        return (StatusCode::FORBIDDEN, msg).into_response();
+                        return (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response();
                     }
                 }
+                (user, grant, Some(id))
             }
             Err(e) => {
-                let msg = format!("parse Authorization header failed: {}", e);
+                let msg = format!("verify token failed: {}", e);
                 warn!(msg);
+                // 401 so that clients know to renew the access token
                 return (StatusCode::UNAUTHORIZED, msg).into_response();
             }
+        }
+    };
+    // apps the user authorized and bots only act within chats, where every route checks the
+    // token's scopes, see oauth_provider
+    if (bot || grant.app_id.is_some()) && !app_may_call(parts.uri.path()) {
+        warn!("app or bot token of user {} used for {}", user.id, parts.uri.path());
+        return AppError::PermissionDenied("app and bot tokens can't be used here".to_string()).into_response();
+    }
+    let ip = parts.extensions.get::<ClientIp>().map(|ClientIp(ip)| ip.as_str());
+    match IpAllowlist::fetch(user.ws_id as _, &state.pool).await {
+        Ok(allowlist) if allowlist.allows(ip) => {}
+        Ok(_) => {
+            let ip = ip.unwrap_or("unknown address").to_string();
+            warn!("request for workspace {} from {} rejected by its ip allowlist", user.ws_id, ip);
+            return AppError::IpNotAllowed(ip).into_response();
+        }
+        Err(e) => {
+            let msg = format!("check ip allowlist failed: {}", e);
+            warn!(msg);
+            return (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response();
+        }
+    }
+    Span::current().record("user_id", user.id);
+    let mut req = Request::from_parts(parts, body);
+    req.extensions_mut().insert(user);
+    req.extensions_mut().insert(grant);
+    if let Some(id) = id {
+        req.extensions_mut().insert(id);
+    }
+    next.run(req).await
+}
+
+// browser apps send the access token as a cookie when cookie sessions are on
+fn session_cookie<'a>(state: &AppState, parts: &'a Parts) -> Option<&'a str> {
+    let config = &state.config.auth.cookie_session;
+    if !config.enabled {
+        return None;
+    }
+    cookie_value(&parts.headers, &config.cookie_name).filter(|token| !token.is_empty())
+}
+
+// identity providers authenticate with the workspace's scim token, the workspace's ip allowlist
+// is for members and doesn't apply
+pub async fn verify_scim_token(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let token = match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
+        Ok(TypedHeader(Authorization(bearer))) => bearer,
+        Err(e) => {
+            let msg = format!("parse Authorization header failed: {}", e);
+            warn!(msg);
+            return ScimError::new(StatusCode::UNAUTHORIZED, None, msg).into_response();
+        }
+    };
+    let ws = match Workspace::find_by_scim_token(token.token(), &state.pool).await {
+        Ok(Some(ws)) => ws,
+        Ok(None) => {
+            warn!("scim request with an unknown token");
+            return ScimError::new(StatusCode::UNAUTHORIZED, None, "invalid scim token").into_response();
+        }
+        Err(e) => {
+            let msg = format!("verify scim token failed: {}", e);
+            warn!(msg);
+            return ScimError::new(StatusCode::INTERNAL_SERVER_ERROR, None, msg).into_response();
+        }
+    };
+    let mut req = Request::from_parts(parts, body);
+    req.extensions_mut().insert(ws);
+    next.run(req).await
+}
+
+// /metrics is for the monitoring system only, it answers 404 unless metrics.token is set
+pub async fn verify_metrics_token(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(metrics) = &state.config.metrics else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let (mut parts, body) = req.into_parts();
+    let valid = match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
+        Ok(TypedHeader(Authorization(bearer))) => constant_time_eq(bearer.token().as_bytes(), metrics.token.as_bytes()),
+        Err(_) => false,
+    };
+    if !valid {
+        warn!("metrics request without a valid token");
+        return (StatusCode::UNAUTHORIZED, "invalid metrics token").into_response();
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+// the homeserver authenticates with the hs token of the workspace's matrix bridge
+pub async fn verify_hs_token(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let token = match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
+        Ok(TypedHeader(Authorization(bearer))) => bearer,
+        Err(e) => {
+            let msg = format!("parse Authorization header failed: {}", e);
+            warn!(msg);
+            return MatrixError::new(StatusCode::UNAUTHORIZED, "M_MISSING_TOKEN", msg).into_response();
+        }
+    };
+    let homeserver = match MatrixHomeserver::find_by_hs_token(token.token(), &state.pool).await {
+        Ok(Some(homeserver)) => homeserver,
+        Ok(None) => {
+            warn!("matrix request with an unknown token");
+            return MatrixError::new(StatusCode::FORBIDDEN, "M_FORBIDDEN", "invalid hs token").into_response();
+        }
+        Err(e) => {
+            let msg = format!("verify hs token failed: {}", e);
+            warn!(msg);
+            return MatrixError::from(e).into_response();
+        }
+    };
+    let mut req = Request::from_parts(parts, body);
+    req.extensions_mut().insert(homeserver);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::MetricsConfig, error::ErrorOutput, middlewares::set_layer, AppConfig, CreateBot, UpdateIpAllowlist, User};
+    use anyhow::Result;
+    use axum::{body::Body, extract::ConnectInfo, middleware::from_fn_with_state, routing::get, Router};
+    use http_body_util::BodyExt;
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn verify_token_should_enforce_ip_allowlist() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.rate_limit.ip_header = Some("x-forwarded-for".to_string());
+        config.rate_limit.trusted_proxies = vec!["172.16.0.1".to_string()];
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let user = User::find_by_email("tchen@acme.org", &state.pool).await?.expect("user should exist");
+        let token = state.ek.sign(user)?;
+        let input = UpdateIpAllowlist { cidrs: vec!["10.0.0.0/8".to_string()] };
+        IpAllowlist::update(1, &input, Some("10.0.0.1"), &state.pool).await?;
+        let app = Router::new()
+            .route("/api/chats", get(|| async { "ok" }))
+            .layer(from_fn_with_state(state.clone(), verify_token));
+        let app = set_layer(app, state);
+        let request = |forwarded: &str, peer: [u8; 4]| {
+            Request::get("/api/chats")
+                .header("authorization", format!("Bearer {}", token))
+                .header("x-forwarded-for", forwarded)
+                .extension(ConnectInfo(SocketAddr::from((peer, 443))))
+                .body(Body::empty())
+        };
+
+        let res = app.clone().oneshot(request("10.1.2.3", [172, 16, 0, 1])?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        // an allowed address in front of the one the proxy saw doesn't help
+        let res = app.clone().oneshot(request("10.1.2.3, 192.168.0.1", [172, 16, 0, 1])?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        // nor does the header from anyone but the proxy
+        let res = app.clone().oneshot(request("10.1.2.3", [192, 168, 0, 1])?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = app.oneshot(request("192.168.0.1", [172, 16, 0, 1])?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let body = res.into_body().collect().await?.to_bytes();
+        let ret: ErrorOutput = serde_json::from_slice(&body)?;
+        assert!(ret.error.starts_with("ip_not_allowed: 192.168.0.1"), "{}", ret.error);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_token_should_accept_session_cookie_with_csrf() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.auth.cookie_session.enabled = true;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let user = User::find_by_email("tchen@acme.org", &state.pool).await?.expect("user should exist");
+        let token = state.ek.sign(user)?;
+        let app = Router::new()
+            .route("/api/chats", get(|| async { "ok" }).post(|| async { "ok" }))
+            .layer(from_fn_with_state(state.clone(), verify_token));
+        let cookie = format!("chat_token={}; chat_csrf=abc123", token);
+        let request = |method: &str, csrf: Option<&str>| {
+            let req = Request::builder().method(method).uri("/api/chats").header("cookie", cookie.as_str());
+            match csrf {
+                Some(csrf) => req.header("x-csrf-token", csrf).body(Body::empty()),
+                None => req.body(Body::empty()),
+            }
+        };
+
+        let res = app.clone().oneshot(request("GET", None)?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.clone().oneshot(request("POST", None)?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = app.clone().oneshot(request("POST", Some("abc124"))?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = app.oneshot(request("POST", Some("abc123"))?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_token_should_accept_bot_token() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let input = CreateBot { name: "Deploy bot".to_string(), scopes: vec!["chat:read".to_string()] };
+        let bot = Bot::create(1, &input, 1, &state.pool).await?;
+        // nested like the api, so the middleware sees the path within it
+        let api = Router::new()
+            .route("/chats", get(|axum::Extension(user): axum::Extension<User>| async move { user.fullname }))
+            .route("/me/email", get(|| async { "ok" }))
+            .layer(from_fn_with_state(state.clone(), verify_token));
+        let app = Router::new().nest("/api", api);
+        let request = |path: &str, token: &str| {
+            Request::get(path)
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
         };
-        next.run(req).await
+        let token = bot.token.as_deref().unwrap_or_default();
+
+        let res = app.clone().oneshot(request("/api/chats", token)?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.into_body().collect().await?.to_bytes(), "Deploy bot");
+        // routes that don't check scopes are off limits
+        let res = app.clone().oneshot(request("/api/me/email", token)?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = app.oneshot(request("/api/chats", "bot_bogus")?).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_scim_token_should_resolve_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let ws = Workspace::find_by_id(1, &state.pool).await?.expect("workspace should exist");
+        let issued = ws.issue_scim_token(&state.pool).await?;
+        let app = Router::new()
+            .route("/scim/v2/Users", get(|axum::Extension(ws): axum::Extension<Workspace>| async move { ws.name }))
+            .layer(from_fn_with_state(state.clone(), verify_scim_token));
+        let request = |token: &str| {
+            Request::get("/scim/v2/Users")
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+
+        let res = app.clone().oneshot(request(&issued.token)?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.into_body().collect().await?.to_bytes(), "acme");
+        let res = app.oneshot(request("bogus")?).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()["content-type"], "application/scim+json");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_metrics_token_should_need_the_configured_token() -> Result<()> {
+        let token = "a".repeat(32);
+        let app = |metrics: Option<MetricsConfig>| async move {
+            let mut config = AppConfig::load()?;
+            config.metrics = metrics;
+            let (tdb, state) = AppState::new_for_test(config).await?;
+            let app = Router::new()
+                .route("/metrics", get(|| async { "ok" }))
+                .layer(from_fn_with_state(state, verify_metrics_token));
+            anyhow::Ok((tdb, app))
+        };
+        let request = |token: &str| {
+            Request::get("/metrics")
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+
+        let (_tdb, off) = app(None).await?;
+        let res = off.oneshot(request(&token)?).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let (_tdb, on) = app(Some(MetricsConfig { token: token.clone() })).await?;
+        let res = on.clone().oneshot(request(&token)?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = on.clone().oneshot(request("bogus")?).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = on.oneshot(Request::get("/metrics").body(Body::empty())?).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
 }
@@ -0,0 +1,64 @@
+use tower_http::compression::{
+    predicate::{NotForContentType, Predicate, SizeAbove},
+    CompressionLayer,
+};
+
+use crate::config::CompressionConfig;
+
+// gzip or brotli, whichever the client prefers. Small bodies aren't worth it and images are
+// compressed already. Event streams are left alone, compressing them would hold events back.
+pub(super) fn compression_layer(config: &CompressionConfig) -> CompressionLayer<impl Predicate + use<>> {
+    let predicate = SizeAbove::new(config.min_size_bytes)
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES)
+        .and(NotForContentType::SSE);
+    // with no encoding enabled responses pass through untouched
+    CompressionLayer::new()
+        .gzip(config.enabled)
+        .br(config.enabled)
+        .deflate(false)
+        .zstd(false)
+        .compress_when(predicate)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{middlewares::set_layer, AppConfig, AppState};
+    use std::convert::Infallible;
+
+    use anyhow::Result;
+    use axum::{
+        body::Body,
+        http::{header, Request},
+        response::{sse::Event, Sse},
+        routing::get,
+        Router,
+    };
+    use futures::stream;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn compression_should_skip_small_bodies_and_event_streams() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let app = Router::new()
+            .route("/small", get(|| async { "ok" }))
+            .route("/large", get(|| async { "hello world ".repeat(1000) }))
+            .route(
+                "/events",
+                get(|| async {
+                    let event = Event::default().data("hello world ".repeat(1000));
+                    Sse::new(stream::once(async { Ok::<_, Infallible>(event) }))
+                }),
+            );
+        let app = set_layer(app, state);
+        let get = |uri: &str| Request::get(uri).header(header::ACCEPT_ENCODING, "br, gzip").body(Body::empty());
+
+        let res = app.clone().oneshot(get("/large")?).await?;
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "br");
+        let res = app.clone().oneshot(get("/small")?).await?;
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+        let res = app.oneshot(get("/events")?).await?;
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+        Ok(())
+    }
+}
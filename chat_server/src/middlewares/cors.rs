@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use crate::{
+    config::CorsConfig,
+    middlewares::{
+        api_version::API_VERSION_HEADER,
+        rate_limit::{LIMIT_HEADER, REMAINING_HEADER, RESET_HEADER, WARNING_HEADER},
+        REQUEST_ID_HEADER, SERVER_TIME_HEADER,
+    },
+};
+
+// origins are checked when the config is loaded
+pub(super) fn cors_layer(config: &CorsConfig) -> CorsLayer {
+    let origin = if config.allows_any_origin() {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(config.allowed_origins.iter().filter_map(|o| o.parse::<HeaderValue>().ok()))
+    };
+    let exposed = [
+        REQUEST_ID_HEADER,
+        SERVER_TIME_HEADER,
+        LIMIT_HEADER,
+        REMAINING_HEADER,
+        RESET_HEADER,
+        WARNING_HEADER,
+        API_VERSION_HEADER,
+        // for conditional requests on listings
+        "etag",
+    ];
+    // mirroring rather than * as wildcards are not allowed together with credentials
+    CorsLayer::new()
+        .allow_origin(origin)
+        .allow_methods(AllowMethods::mirror_request())
+        .allow_headers(AllowHeaders::mirror_request())
+        .expose_headers(exposed.map(HeaderName::from_static))
+        .allow_credentials(config.allow_credentials)
+        .max_age(Duration::from_secs(config.max_age_secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{middlewares::set_layer, AppConfig, AppState};
+    use anyhow::Result;
+    use axum::{body::Body, http::{header, Method, Request, StatusCode}, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn cors_layer_should_only_allow_configured_origins() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.cors.allowed_origins = vec!["https://app.acme.org".to_string()];
+        config.cors.allow_credentials = true;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let app = set_layer(Router::new().route("/api/chats", get(|| async { "ok" })), state);
+        let preflight = |origin: &str| {
+            Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/api/chats")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+                .body(Body::empty())
+        };
+
+        let res = app.clone().oneshot(preflight("https://app.acme.org")?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let headers = res.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.acme.org");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "authorization");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let res = app.clone().oneshot(preflight("https://evil.example")?).await?;
+        assert!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+        let req = Request::get("/api/chats").header(header::ORIGIN, "https://app.acme.org").body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.acme.org");
+        assert!(res.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS].to_str()?.contains("x-request-id"));
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header::CONTENT_LENGTH, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body_util::Limited;
+use tracing::warn;
+
+use crate::{config::RequestLimitsConfig, middlewares::rate_limit::path_matches, AppError, AppState};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RequestLimit {
+    pub timeout_secs: u64,
+    pub body_bytes: usize,
+}
+
+impl RequestLimitsConfig {
+    pub(crate) fn rule(&self, method: &Method, path: &str) -> RequestLimit {
+        let route = self.routes.iter().find(|r| {
+            r.method.as_deref().is_none_or(|m| m.eq_ignore_ascii_case(method.as_str())) && path_matches(&r.path, path)
+        });
+        RequestLimit {
+            timeout_secs: route.and_then(|r| r.timeout_secs).unwrap_or(self.timeout_secs),
+            body_bytes: route.and_then(|r| r.body_bytes).unwrap_or(self.body_bytes),
+        }
+    }
+}
+
+// bodies announced as too large are refused before the handler runs, others are cut off once
+// they exceed the limit while being read. A handler still running at the timeout is dropped.
+pub async fn enforce_request_limits(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let limit = state.config.request_limits.rule(request.method(), request.uri().path());
+    let length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if length.is_some_and(|n| n > limit.body_bytes) {
+        return AppError::PayloadTooLarge(limit.body_bytes).into_response();
+    }
+    let request = request.map(|body| Body::new(Limited::new(body, limit.body_bytes)));
+    let path = request.uri().path().to_string();
+    match tokio::time::timeout(Duration::from_secs(limit.timeout_secs), next.run(request)).await {
+        // extractors answer a body cut off by the limit in plain text
+        Ok(res) if res.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            AppError::PayloadTooLarge(limit.body_bytes).into_response()
+        }
+        Ok(res) => res,
+        Err(_) => {
+            warn!("{} timed out after {}s", path, limit.timeout_secs);
+            AppError::RequestTimeout(limit.timeout_secs).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::RouteRequestLimit, error::ErrorOutput, middlewares::set_layer, AppConfig};
+    use anyhow::Result;
+    use axum::{routing::post, Json, Router};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    #[test]
+    fn request_limit_rule_should_match_routes() {
+        let config = RequestLimitsConfig::default();
+        assert_eq!(config.rule(&Method::POST, "/api/chats/1").body_bytes, 1024 * 1024);
+        assert_eq!(config.rule(&Method::PATCH, "/api/chats/1").body_bytes, config.body_bytes);
+        let export = config.rule(&Method::GET, "/api/workspace/members/export");
+        assert_eq!(export, RequestLimit { timeout_secs: 120, body_bytes: config.body_bytes });
+    }
+
+    #[tokio::test]
+    async fn request_limits_should_return_408_and_413() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.request_limits.routes = vec![RouteRequestLimit {
+            path: "/".to_string(),
+            method: None,
+            timeout_secs: Some(1),
+            body_bytes: Some(8),
+        }];
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let app = Router::new()
+            .route("/slow", post(|| async { tokio::time::sleep(Duration::from_secs(5)).await }))
+            .route("/echo", post(|body: String| async { body }));
+        let app = set_layer(app, state);
+
+        let res = app.clone().oneshot(Request::post("/slow").body(Body::empty())?).await?;
+        assert_eq!(res.status(), StatusCode::REQUEST_TIMEOUT);
+        let request = Request::post("/slow").header(CONTENT_LENGTH, 10).body(Body::from("0123456789"))?;
+        let res = app.clone().oneshot(request).await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        // without a content-length, the body is cut off while the handler reads it
+        let res = app.clone().oneshot(Request::post("/echo").body(Body::from("0123456789"))?).await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = res.into_body().collect().await?.to_bytes();
+        let Json(err): Json<ErrorOutput> = Json::from_bytes(&body)?;
+        assert_eq!(err.error, "request body is larger than 8 bytes");
+
+        let res = app.oneshot(Request::post("/echo").body(Body::from("hello"))?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+}
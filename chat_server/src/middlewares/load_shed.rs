@@ -0,0 +1,91 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::Semaphore;
+
+use crate::{AppError, AppState};
+
+// probes answer even when the server is full, so an overloaded instance isn't restarted
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/metrics"];
+
+// caps the requests handled at once. Requests beyond the cap are refused right away instead of
+// queueing, so the ones let in keep their latency and clients can retry elsewhere.
+#[derive(Debug)]
+pub(crate) struct LoadShedder {
+    permits: Arc<Semaphore>,
+    max: usize,
+    shed: AtomicU64,
+}
+
+impl LoadShedder {
+    pub(crate) fn new(max_in_flight: Option<usize>) -> Self {
+        let max = max_in_flight.unwrap_or(Semaphore::MAX_PERMITS).min(Semaphore::MAX_PERMITS);
+        Self {
+            permits: Arc::new(Semaphore::new(max)),
+            max,
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn in_flight(&self) -> usize {
+        self.max - self.permits.available_permits()
+    }
+
+    // requests refused since the process started
+    pub(crate) fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+}
+
+pub async fn shed_load(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if EXEMPT_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    let Ok(_permit) = state.load_shedder.permits.clone().try_acquire_owned() else {
+        state.load_shedder.shed.fetch_add(1, Ordering::Relaxed);
+        return AppError::Overloaded.into_response();
+    };
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{middlewares::set_layer, AppConfig};
+    use anyhow::Result;
+    use axum::{body::Body, http::StatusCode, routing::get, Router};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn shed_load_should_refuse_beyond_max_in_flight() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.request_limits.max_in_flight = Some(1);
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let app = Router::new()
+            .route("/slow", get(|| tokio::time::sleep(Duration::from_millis(500))))
+            .route("/healthz", get(|| async { "ok" }));
+        let app = set_layer(app, state.clone());
+
+        let slow = tokio::spawn(app.clone().oneshot(Request::get("/slow").body(Body::empty())?));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(state.load_shedder.in_flight(), 1);
+        let res = app.clone().oneshot(Request::get("/slow").body(Body::empty())?).await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()["retry-after"], "1");
+        let res = app.clone().oneshot(Request::get("/healthz").body(Body::empty())?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        assert_eq!(slow.await??.status(), StatusCode::OK);
+        assert_eq!(state.load_shedder.shed(), 1);
+        assert_eq!(state.load_shedder.in_flight(), 0);
+        Ok(())
+    }
+}
@@ -1,31 +1,67 @@
-use axum::{middleware::from_fn, Router};
+use axum::{extract::DefaultBodyLimit, http::HeaderName, middleware::{from_fn, from_fn_with_state}, Router};
 use tower::ServiceBuilder;
-use tower_http::{compression::CompressionLayer, trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer}, LatencyUnit};
-use tracing::Level;
+use tower_http::{request_id::{PropagateRequestIdLayer, SetRequestIdLayer}, trace::TraceLayer};
 
-use crate::middlewares::{request_id::set_request_id, server_time::ServerTimeLayer};
+use crate::{middlewares::{api_version::negotiate_api_version, compression::compression_layer, cors::cors_layer, limits::enforce_request_limits, load_shed::shed_load, msgpack::negotiate_msgpack, rate_limit::RateLimitLayer, request_id::{LogResponse, MakeRequestUuidV7, RequestSpan}, security_headers::SecurityHeadersLayer, server_time::ServerTimeLayer}, AppState};
 
+mod api_version;
 mod auth;
+mod compression;
+mod cors;
+mod limits;
+mod load_shed;
+mod msgpack;
+mod rate_limit;
 mod request_id;
+mod security_headers;
 mod server_time;
 
 
 const REQUEST_ID_HEADER: &str = "x-request-id";
 const SERVER_TIME_HEADER: &str = "x-server-time";
 
-pub fn set_layer(app: Router) -> Router {
+pub fn set_layer(app: Router, state: AppState) -> Router {
+    let cors = cors_layer(&state.config.cors);
+    let request_id = HeaderName::from_static(REQUEST_ID_HEADER);
     app.layer(
-        ServiceBuilder::new().layer(
+        ServiceBuilder::new()
+        // ids sent by the client are kept, so that a request can be followed across services
+        .layer(SetRequestIdLayer::new(request_id.clone(), MakeRequestUuidV7))
+        .layer(PropagateRequestIdLayer::new(request_id))
+        .layer(
             TraceLayer::new_for_http()
-            .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
-            .on_response(
-                DefaultOnResponse::new()
-                .level(Level::INFO)
-                .latency_unit(LatencyUnit::Micros),
-            ),
-        ).layer(CompressionLayer::new().gzip(true).br(true).deflate(true))
-        .layer(from_fn(set_request_id))
+            .make_span_with(RequestSpan)
+            .on_response(LogResponse::new(state.config.log.slow_request_ms)),
+        )
+        .layer(from_fn_with_state(state.clone(), shed_load))
+        // ahead of rate limiting, so preflight requests don't use up the client's budget
+        .layer(cors)
+        .layer(compression_layer(&state.config.compression))
+        .layer(SecurityHeadersLayer::new(&state.config.security_headers))
         .layer(ServerTimeLayer)
+        .layer(RateLimitLayer::new(state.clone()))
+        // replaces axum's fixed 2MB limit with the route's
+        .layer(DefaultBodyLimit::disable())
+        .layer(from_fn_with_state(state, enforce_request_limits))
+        .layer(from_fn(negotiate_api_version))
+        .layer(from_fn(negotiate_msgpack))
     )
 }
-pub use auth::verify_token;
\ No newline at end of file
+
+// the limits of set_layer that count each request, for requests served without going through
+// it, e.g. the sub-requests of a batch
+pub(crate) fn set_request_limits(app: Router, state: AppState) -> Router {
+    app.layer(
+        ServiceBuilder::new()
+        .layer(from_fn_with_state(state.clone(), shed_load))
+        .layer(RateLimitLayer::new(state.clone()))
+        .layer(DefaultBodyLimit::disable())
+        .layer(from_fn_with_state(state, enforce_request_limits))
+    )
+}
+pub use api_version::{ApiVersion, CURRENT_API_VERSION};
+pub(crate) use api_version::unversioned;
+pub use auth::{verify_hs_token, verify_metrics_token, verify_scim_token, verify_token};
+pub use rate_limit::ClientIp;
+pub(crate) use load_shed::LoadShedder;
+pub(crate) use rate_limit::RateLimiter;
\ No newline at end of file
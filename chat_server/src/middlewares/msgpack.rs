@@ -0,0 +1,161 @@
+use axum::{
+    body::{to_bytes, Body, Bytes, HttpBody},
+    extract::{FromRequest, Request},
+    http::{
+        header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY},
+        HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::utils::{accepts_msgpack, has_msgpack_body, MsgPack, MSGPACK_CONTENT_TYPE};
+
+// larger responses stay json rather than being held in memory twice to convert them
+const MAX_CONVERTED_BYTES: usize = 8 * 1024 * 1024;
+
+// MessagePack on /api for clients that send or accept it. Handlers keep reading and writing
+// json, bodies are converted on the way in and out. Only complete json bodies are converted,
+// streams and anything over MAX_CONVERTED_BYTES go out as they are.
+pub async fn negotiate_msgpack(request: Request, next: Next) -> Response {
+    if !request.uri().path().starts_with("/api/") {
+        return next.run(request).await;
+    }
+    let wants_msgpack = accepts_msgpack(request.headers());
+    // a body that can't be read is answered like any other error
+    let mut res = if has_msgpack_body(request.headers()) {
+        match msgpack_to_json(request).await {
+            Ok(request) => next.run(request).await,
+            Err(res) => res,
+        }
+    } else {
+        next.run(request).await
+    };
+    if !is_json(&res) {
+        return res;
+    }
+    res.headers_mut().append(VARY, HeaderValue::from_static("accept"));
+    if !wants_msgpack || !is_convertible(&res) {
+        return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    let value = match to_bytes(body, MAX_CONVERTED_BYTES).await.map(|bytes| serde_json::from_slice::<Value>(&bytes)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(e)) => {
+            warn!("response isn't the json it says it is: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        Err(e) => {
+            warn!("read response failed: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let (msgpack, body) = MsgPack(value).into_response().into_parts();
+    // failed to encode
+    if msgpack.status != StatusCode::OK {
+        return Response::from_parts(msgpack, body);
+    }
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static(MSGPACK_CONTENT_TYPE));
+    Response::from_parts(parts, body)
+}
+
+async fn msgpack_to_json(request: Request) -> Result<Request, Response> {
+    let (mut parts, body) = request.into_parts();
+    // over the request limit is a 413 as for json
+    let bytes = Bytes::from_request(Request::new(body), &()).await.map_err(IntoResponse::into_response)?;
+    let MsgPack(value) = MsgPack::<Value>::from_bytes(&bytes).map_err(IntoResponse::into_response)?;
+    parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(Request::from_parts(parts, Body::from(value.to_string())))
+}
+
+// the whole body is known up front, i.e. not a stream, and small enough
+fn is_convertible(res: &Response) -> bool {
+    !res.headers().contains_key(CONTENT_ENCODING)
+        && res.body().size_hint().exact().is_some_and(|len| len <= MAX_CONVERTED_BYTES as u64)
+}
+
+fn is_json(res: &Response) -> bool {
+    res.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(';').next().unwrap_or_default().trim() == "application/json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MAX_CONVERTED_BYTES;
+    use crate::{middlewares::set_layer, utils::MSGPACK_CONTENT_TYPE, AppConfig, AppState};
+    use anyhow::Result;
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request, StatusCode},
+        routing::{get, post},
+        Json, Router,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn msgpack_should_be_read_and_written_for_clients_that_ask() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let app = Router::new().route("/api/echo", post(|Json(body): Json<Value>| async { Json(body) }));
+        let app = set_layer(app, state);
+        let value = json!({ "content": "hello", "seq": 1 });
+
+        let request = Request::post("/api/echo")
+            .header(header::CONTENT_TYPE, MSGPACK_CONTENT_TYPE)
+            .header(header::ACCEPT, MSGPACK_CONTENT_TYPE)
+            .body(Body::from(rmp_serde::to_vec_named(&value)?))?;
+        let res = app.clone().oneshot(request).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], MSGPACK_CONTENT_TYPE);
+        assert_eq!(res.headers()[header::VARY], "accept");
+        let body = to_bytes(res.into_body(), usize::MAX).await?;
+        assert_eq!(rmp_serde::from_slice::<Value>(&body)?, value);
+
+        // json out unless asked, and errors go back in msgpack too
+        let request = Request::post("/api/echo")
+            .header(header::CONTENT_TYPE, MSGPACK_CONTENT_TYPE)
+            .body(Body::from(rmp_serde::to_vec_named(&value)?))?;
+        let res = app.clone().oneshot(request).await?;
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        let request = Request::post("/api/echo")
+            .header(header::CONTENT_TYPE, MSGPACK_CONTENT_TYPE)
+            .header(header::ACCEPT, MSGPACK_CONTENT_TYPE)
+            .body(Body::from("not msgpack"))?;
+        let res = app.oneshot(request).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], MSGPACK_CONTENT_TYPE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streamed_and_large_json_should_not_be_converted() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let json = |body: Body| ([(header::CONTENT_TYPE, "application/json")], body);
+        let app = Router::new()
+            .route(
+                "/api/stream",
+                get(move || async move {
+                    let chunks = futures::stream::iter(["[1,", "2]"].map(Ok::<_, std::io::Error>));
+                    json(Body::from_stream(chunks))
+                }),
+            )
+            .route(
+                "/api/large",
+                get(move || async move { json(Body::from(format!("\"{}\"", "a".repeat(MAX_CONVERTED_BYTES)))) }),
+            );
+        let app = set_layer(app, state);
+        for path in ["/api/stream", "/api/large"] {
+            let request = Request::get(path).header(header::ACCEPT, MSGPACK_CONTENT_TYPE).body(Body::empty())?;
+            let res = app.clone().oneshot(request).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json", "{}", path);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header::AUTHORIZATION, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use tower::{Layer, Service};
+use tracing::warn;
+
+use crate::{config::RateLimitConfig, error::ErrorOutput, middlewares::api_version::unversioned, utils::Cidr, AppState};
+
+// buckets refilled to the brim are dropped once the map grows past this
+const SWEEP_THRESHOLD: usize = 10_000;
+
+pub(super) const LIMIT_HEADER: &str = "x-ratelimit-limit";
+pub(super) const REMAINING_HEADER: &str = "x-ratelimit-remaining";
+pub(super) const RESET_HEADER: &str = "x-ratelimit-reset";
+pub(super) const WARNING_HEADER: &str = "x-ratelimit-warning";
+
+// the client's address as far as it can be trusted, see trusted_client_ip
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIp(pub String);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum RateLimitKey {
+    User(i64),
+    Ip(String),
+}
+
+// the limit that applies to a request, rule is the index of the route rule
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Rule {
+    pub(crate) id: usize,
+    pub(crate) requests_per_minute: u32,
+    pub(crate) burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    full_at: Instant,
+    warned: bool,
+}
+
+// token buckets by rule and key
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    buckets: Mutex<HashMap<(usize, RateLimitKey), Bucket>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RateLimitStatus {
+    pub(crate) limit: u32,
+    pub(crate) remaining: u32,
+    // seconds until the bucket is full again
+    pub(crate) reset: u64,
+    // seconds until the next request is let through, 0 when it is
+    pub(crate) retry_after: u64,
+    // past the warning threshold
+    pub(crate) warning: bool,
+    // the request that crossed the threshold
+    pub(crate) crossed: bool,
+}
+
+impl RateLimitStatus {
+    pub(crate) fn allowed(&self) -> bool {
+        self.retry_after == 0
+    }
+}
+
+impl RateLimiter {
+    pub(crate) fn check(&self, rule: &Rule, key: RateLimitKey, warn_percent: u32, now: Instant) -> RateLimitStatus {
+        let capacity = rule.burst.max(1) as f64;
+        // tokens per second
+        let rate = rule.requests_per_minute.max(1) as f64 / 60.0;
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() > SWEEP_THRESHOLD {
+            buckets.retain(|_, b| b.full_at > now);
+        }
+        let bucket = buckets.entry((rule.id, key)).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+            full_at: now,
+            warned: false,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated_at = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let missing = capacity - bucket.tokens;
+        bucket.full_at = now + Duration::from_secs_f64(missing / rate);
+        let warning = missing * 100.0 >= capacity * warn_percent as f64;
+        let crossed = warning && !bucket.warned;
+        bucket.warned = warning;
+        RateLimitStatus {
+            limit: capacity as u32,
+            remaining: bucket.tokens.floor() as u32,
+            reset: (missing / rate).ceil() as u64,
+            retry_after: if allowed { 0 } else { ((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64 },
+            warning,
+            crossed,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub(crate) fn rule(&self, method: &Method, path: &str) -> Rule {
+        self.routes
+            .iter()
+            .enumerate()
+            .find(|(_, r)| {
+                r.method.as_deref().is_none_or(|m| m.eq_ignore_ascii_case(method.as_str()))
+                    && path_matches(&r.path, path)
+            })
+            .map(|(id, r)| Rule {
+                id,
+                requests_per_minute: r.requests_per_minute,
+                burst: r.burst.unwrap_or(r.requests_per_minute),
+            })
+            .unwrap_or(Rule {
+                id: self.routes.len(),
+                requests_per_minute: self.requests_per_minute,
+                burst: self.burst.unwrap_or(self.requests_per_minute),
+            })
+    }
+}
+
+// segment wise prefix match, {name} matches any one segment. /api/v<n>/... matches as /api/...
+pub(super) fn path_matches(pattern: &str, path: &str) -> bool {
+    let path = unversioned(path);
+    let mut segments = path.trim_end_matches('/').split('/');
+    pattern.trim_end_matches('/').split('/').all(|p| {
+        segments
+            .next()
+            .is_some_and(|s| s == p || (p.starts_with('{') && p.ends_with('}') && !s.is_empty()))
+    })
+}
+
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    state: AppState,
+}
+
+impl RateLimitLayer {
+    pub(crate) fn new(state: AppState) -> Self {
+        Self { state }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitMiddleware<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitMiddleware {
+            inner,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitMiddleware<S> {
+    inner: S,
+    state: AppState,
+}
+
+impl<S> Service<Request> for RateLimitMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let live = self.state.live.load();
+        let config = &live.rate_limit;
+        if let Some(ip) = trusted_client_ip(config, &request) {
+            request.extensions_mut().insert(ClientIp(ip));
+        }
+        let Some(key) = rate_limit_key(&self.state, &request) else {
+            return Box::pin(self.inner.call(request));
+        };
+        let rule = config.rule(request.method(), request.uri().path());
+        let status = self.state.rate_limiter.check(&rule, key.clone(), config.warn_percent, Instant::now());
+        if status.crossed {
+            warn!(
+                "{:?} used {}% of the rate limit of {} requests per minute for {}",
+                key,
+                config.warn_percent,
+                rule.requests_per_minute,
+                request.uri().path()
+            );
+        }
+        if !status.allowed() {
+            let body = Json(ErrorOutput::new(format!(
+                "Rate limit exceeded, retry in {}s",
+                status.retry_after
+            )));
+            let mut res = (StatusCode::TOO_MANY_REQUESTS, body).into_response();
+            res.headers_mut().insert("retry-after", HeaderValue::from(status.retry_after));
+            set_headers(res.headers_mut(), &status);
+            return Box::pin(async move { Ok(res) });
+        }
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let mut res = future.await?;
+            set_headers(res.headers_mut(), &status);
+            Ok(res)
+        })
+    }
+}
+
+// requests with a valid access token count against the user, others against the ip.
+// Requests without either are not limited, e.g. in tests without a peer address.
+fn rate_limit_key(state: &AppState, request: &Request) -> Option<RateLimitKey> {
+    let user = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .and_then(|token| state.dk.verify(token).ok());
+    if let Some((user, _)) = user {
+        return Some(RateLimitKey::User(user.id));
+    }
+    request
+        .extensions()
+        .get::<ClientIp>()
+        .map(|ClientIp(ip)| RateLimitKey::Ip(ip.clone()))
+}
+
+fn trusted_client_ip(config: &RateLimitConfig, request: &Request) -> Option<String> {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())?;
+    Some(forwarded_client_ip(config, request.headers(), peer).to_string())
+}
+
+// the peer, or for requests from a trusted proxy the rightmost hop of ip_header that isn't a
+// trusted proxy itself. Proxies append the address they got the request from, hops further
+// left were sent by the client and could be forged.
+pub(crate) fn forwarded_client_ip(config: &RateLimitConfig, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+    let proxies: Vec<Cidr> = config.trusted_proxies.iter().filter_map(|c| c.parse().ok()).collect();
+    let is_proxy = |ip: &IpAddr| proxies.iter().any(|c| c.contains(ip));
+    let Some(name) = config.ip_header.as_deref() else {
+        return peer;
+    };
+    let hops: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect();
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        if !is_proxy(&client) {
+            break;
+        }
+        match hop.parse::<IpAddr>() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+fn set_headers(headers: &mut HeaderMap, status: &RateLimitStatus) {
+    headers.insert(LIMIT_HEADER, HeaderValue::from(status.limit));
+    headers.insert(REMAINING_HEADER, HeaderValue::from(status.remaining));
+    headers.insert(RESET_HEADER, HeaderValue::from(status.reset));
+    if status.warning && status.allowed() {
+        let hint = format!(
+            "{} of {} requests left, slow down to avoid being rejected",
+            status.remaining, status.limit
+        );
+        if let Ok(v) = HeaderValue::from_str(&hint) {
+            headers.insert(WARNING_HEADER, v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::RouteRateLimit, middlewares::set_layer, AppConfig};
+    use std::net::Ipv4Addr;
+    use anyhow::Result;
+    use axum::{body::Body, routing::post, Router};
+    use tower::ServiceExt;
+
+    #[test]
+    fn rate_limiter_should_warn_before_rejecting() {
+        let rule = Rule { id: 0, requests_per_minute: 60, burst: 10 };
+        let limiter = RateLimiter::default();
+        let key = || RateLimitKey::User(1);
+        let now = Instant::now();
+        for _ in 0..7 {
+            let status = limiter.check(&rule, key(), 80, now);
+            assert!(status.allowed() && !status.warning);
+        }
+        let status = limiter.check(&rule, key(), 80, now);
+        assert!(status.allowed() && status.warning && status.crossed);
+        assert_eq!(status.remaining, 2);
+        // the threshold is only crossed once until the bucket refills
+        assert!(!limiter.check(&rule, key(), 80, now).crossed);
+        limiter.check(&rule, key(), 80, now);
+        let status = limiter.check(&rule, key(), 80, now);
+        assert!(!status.allowed());
+        assert_eq!((status.remaining, status.retry_after, status.reset), (0, 1, 10));
+
+        // other keys have their own bucket
+        assert!(limiter.check(&rule, RateLimitKey::Ip("10.0.0.1".to_string()), 80, now).allowed());
+
+        // one token a second
+        let status = limiter.check(&rule, key(), 80, now + Duration::from_secs(1));
+        assert!(status.allowed());
+        let status = limiter.check(&rule, key(), 80, now + Duration::from_secs(60));
+        assert!(status.allowed() && !status.warning);
+        assert_eq!(status.remaining, 9);
+    }
+
+    #[test]
+    fn rate_limit_rule_should_match_routes() {
+        let config = RateLimitConfig::default();
+        let signin = config.rule(&Method::POST, "/api/signin");
+        assert_eq!(signin.requests_per_minute, 10);
+        assert_eq!(config.rule(&Method::POST, "/api/signin/2fa").id, signin.id);
+        assert_eq!(config.rule(&Method::POST, "/api/v1/signin").id, signin.id);
+        assert_eq!(config.rule(&Method::GET, "/api/chats/1/messages").requests_per_minute, 1200);
+        assert_eq!(config.rule(&Method::POST, "/api/chats/1/messages").requests_per_minute, 600);
+        assert_eq!(config.rule(&Method::POST, "/api/signinx").id, config.routes.len());
+        assert!(!path_matches("/api/chats/{id}/messages", "/api/chats//messages"));
+    }
+
+    #[test]
+    fn client_ip_should_only_trust_hops_added_by_trusted_proxies() {
+        let config = RateLimitConfig {
+            ip_header: Some("x-forwarded-for".to_string()),
+            trusted_proxies: vec!["10.0.0.0/8".to_string()],
+            ..Default::default()
+        };
+        let ip = |s: &str| s.parse::<IpAddr>().expect("ip should parse");
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.1.1.1, 2.2.2.2, 10.0.0.2"));
+        // what the client put in front is ignored
+        assert_eq!(forwarded_client_ip(&config, &headers, ip("10.0.0.1")), ip("2.2.2.2"));
+        // the header of a peer that isn't a proxy is not read
+        assert_eq!(forwarded_client_ip(&config, &headers, ip("3.3.3.3")), ip("3.3.3.3"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("junk, 10.0.0.2"));
+        assert_eq!(forwarded_client_ip(&config, &headers, ip("10.0.0.1")), ip("10.0.0.2"));
+        let config = RateLimitConfig { ip_header: None, ..config };
+        assert_eq!(forwarded_client_ip(&config, &headers, ip("10.0.0.1")), ip("10.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_layer_should_return_429() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.rate_limit.ip_header = Some("x-forwarded-for".to_string());
+        config.rate_limit.trusted_proxies = vec!["10.0.0.0/8".to_string()];
+        config.rate_limit.routes = vec![RouteRateLimit {
+            path: "/api/signin".to_string(),
+            method: None,
+            requests_per_minute: 2,
+            burst: None,
+        }];
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let app = set_layer(Router::new().route("/api/signin", post(|| async { "ok" })), state);
+        let request = |ip: &str| {
+            Request::post("/api/signin")
+                .header("x-forwarded-for", format!("{}, 10.0.0.254", ip))
+                .extension(ConnectInfo(SocketAddr::from((Ipv4Addr::new(10, 0, 0, 253), 443))))
+                .body(Body::empty())
+        };
+
+        let res = app.clone().oneshot(request("192.0.2.1")?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[LIMIT_HEADER], "2");
+        assert_eq!(res.headers()[REMAINING_HEADER], "1");
+        let res = app.clone().oneshot(request("192.0.2.1")?).await?;
+        assert!(res.headers().contains_key(WARNING_HEADER));
+        let res = app.clone().oneshot(request("192.0.2.1")?).await?;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "30");
+        let res = app.oneshot(request("192.0.2.2")?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limit_should_ignore_forged_hops() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.rate_limit.ip_header = Some("x-forwarded-for".to_string());
+        config.rate_limit.trusted_proxies = vec!["10.0.0.0/8".to_string()];
+        config.rate_limit.routes = vec![RouteRateLimit {
+            path: "/api/signin".to_string(),
+            method: None,
+            requests_per_minute: 2,
+            burst: None,
+        }];
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let app = set_layer(Router::new().route("/api/signin", post(|| async { "ok" })), state);
+        let request = |forwarded: &str, peer: [u8; 4]| {
+            Request::post("/api/signin")
+                .header("x-forwarded-for", forwarded)
+                .extension(ConnectInfo(SocketAddr::from((peer, 443))))
+                .body(Body::empty())
+        };
+
+        // a new address in front of the one the proxy added every time still counts against it
+        for forged in ["198.51.100.1", "198.51.100.2"] {
+            let res = app.clone().oneshot(request(&format!("{}, 192.0.2.1", forged), [10, 0, 0, 253])?).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = app.clone().oneshot(request("198.51.100.3, 192.0.2.1", [10, 0, 0, 253])?).await?;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // clients connecting directly count against their own address, whatever the header says
+        for forged in ["198.51.100.4", "198.51.100.5"] {
+            let res = app.clone().oneshot(request(forged, [192, 0, 2, 9])?).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = app.oneshot(request("198.51.100.6", [192, 0, 2, 9])?).await?;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        Ok(())
+    }
+}
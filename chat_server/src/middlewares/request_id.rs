@@ -1,29 +1,66 @@
-use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
-use tracing::warn;
+use std::time::Duration;
+
+use axum::{http::{HeaderValue, Request}, response::Response};
+use tower_http::{request_id::{MakeRequestId, RequestId}, trace::{MakeSpan, OnResponse}};
+use tracing::{field::Empty, info, warn, Span};
 
 use crate::middlewares::REQUEST_ID_HEADER;
 
-pub async fn set_request_id(mut req: Request, next: Next) -> Response {
-    let id = match req.headers().get(REQUEST_ID_HEADER) {
-        Some(v) => Some(v.clone()),
-        None => {
-            let request_id = uuid::Uuid::now_v7().to_string();
-            match HeaderValue::from_str(&request_id) {
-                Ok(v) => {
-                    req.headers_mut().insert(REQUEST_ID_HEADER, v.clone());
-                    Some(v)
-                }
-                Err(e) => {
-                    warn!("parse generated request id failed: {}", e);
-                    None
-                }
+// time ordered, so ids sort the same way as the requests came in
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct MakeRequestUuidV7;
+
+impl MakeRequestId for MakeRequestUuidV7 {
+    fn make_request_id<B>(&mut self, _request: &Request<B>) -> Option<RequestId> {
+        let id = uuid::Uuid::now_v7().to_string();
+        HeaderValue::from_str(&id).ok().map(RequestId::new)
+    }
+}
+
+// one span per request, user_id is filled in by verify_token once the caller is known
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct RequestSpan;
+
+impl<B> MakeSpan<B> for RequestSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let request_id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        tracing::info_span!(
+            "request",
+            method = %request.method(),
+            path = request.uri().path(),
+            request_id,
+            user_id = Empty,
+        )
+    }
+}
+
+// requests slower than `slow` are logged as warnings, so they stand out at any log level
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct LogResponse {
+    slow: Option<Duration>,
+}
+
+impl LogResponse {
+    pub(super) fn new(slow_request_ms: Option<u64>) -> Self {
+        Self {
+            slow: slow_request_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+impl<B> OnResponse<B> for LogResponse {
+    fn on_response(self, response: &Response<B>, latency: Duration, _span: &Span) {
+        let status = response.status().as_u16();
+        let latency_us = latency.as_micros() as u64;
+        match self.slow {
+            Some(slow) if latency >= slow => {
+                warn!(status, latency_us, slow_ms = slow.as_millis() as u64, "slow request")
             }
+            _ => info!(status, latency_us, "finished processing request"),
         }
-    };
-    let mut res = next.run(req).await;
-    let Some(id) = id else {
-        return res;
-    };
-    res.headers_mut().insert(REQUEST_ID_HEADER, id);
-    res
-}
\ No newline at end of file
+    }
+}
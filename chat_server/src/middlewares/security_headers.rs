@@ -0,0 +1,94 @@
+use std::{pin::Pin, sync::Arc, task::{Context, Poll}};
+
+use axum::{extract::Request, http::{HeaderName, HeaderValue}, response::Response};
+use tower::{Layer, Service};
+
+use crate::config::SecurityHeadersConfig;
+
+#[derive(Clone)]
+pub struct SecurityHeadersLayer {
+    headers: Arc<Vec<(HeaderName, HeaderValue)>>,
+}
+
+impl SecurityHeadersLayer {
+    // values are checked when the config is loaded
+    pub fn new(config: &SecurityHeadersConfig) -> Self {
+        let headers = config
+            .headers()
+            .into_iter()
+            .filter_map(|(name, value)| Some((HeaderName::from_static(name), value.parse().ok()?)))
+            .collect();
+        Self { headers: Arc::new(headers) }
+    }
+}
+
+impl<S> Layer<S> for SecurityHeadersLayer {
+    type Service = SecurityHeadersMiddleware<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        SecurityHeadersMiddleware { inner, headers: self.headers.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct SecurityHeadersMiddleware<S> {
+    inner: S,
+    headers: Arc<Vec<(HeaderName, HeaderValue)>>,
+}
+
+impl<S> Service<Request> for SecurityHeadersMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let headers = self.headers.clone();
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let mut res: Response = future.await?;
+            // handlers that need a different policy set the header themselves
+            for (name, value) in headers.iter() {
+                res.headers_mut().entry(name).or_insert_with(|| value.clone());
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{body::Body, http::header, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn security_headers_should_be_set_unless_overridden() -> Result<()> {
+        let config = SecurityHeadersConfig {
+            strict_transport_security: "".to_string(),
+            ..Default::default()
+        };
+        let app = Router::new()
+            .route("/", get(|| async { "index" }))
+            .route("/embed", get(|| async { ([(header::CONTENT_SECURITY_POLICY, "frame-ancestors *")], "embed") }))
+            .layer(SecurityHeadersLayer::new(&config));
+
+        let res = app.clone().oneshot(Request::get("/").body(Body::empty())?).await?;
+        let headers = res.headers();
+        assert_eq!(headers[header::CONTENT_SECURITY_POLICY], config.content_security_policy.as_str());
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[header::REFERRER_POLICY], "strict-origin-when-cross-origin");
+        assert!(headers.get(header::STRICT_TRANSPORT_SECURITY).is_none());
+
+        let res = app.oneshot(Request::get("/embed").body(Body::empty())?).await?;
+        assert_eq!(res.headers()[header::CONTENT_SECURITY_POLICY], "frame-ancestors *");
+        Ok(())
+    }
+}
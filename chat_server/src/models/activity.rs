@@ -0,0 +1,136 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{Activity, AppError};
+
+const DEFAULT_ACTIVITY_LIMIT: u64 = 50;
+const MAX_ACTIVITY_LIMIT: u64 = 200;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListActivity {
+    // opaque, the next_cursor of the previous page
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActivityPage {
+    pub items: Vec<Activity>,
+    pub next_cursor: Option<String>,
+}
+
+impl Activity {
+    // reactions to the user's messages, replies in threads they started and chats others
+    // added them to, newest first. Items are ordered by (created_at, key) so that the
+    // cursor is stable even when several happen in the same instant.
+    pub async fn list(user_id: u64, input: &ListActivity, pool: &PgPool) -> Result<ActivityPage, AppError> {
+        let (before, before_key) = match &input.cursor {
+            Some(cursor) => decode_cursor(cursor)?,
+            None => (DateTime::<Utc>::MAX_UTC, String::new()),
+        };
+        let limit = input.limit.unwrap_or(DEFAULT_ACTIVITY_LIMIT).min(MAX_ACTIVITY_LIMIT);
+        let items: Vec<Activity> = sqlx::query_as(
+            r#"
+            SELECT kind, key, actor_id, chat_id, message_id, detail, created_at
+            FROM (
+                SELECT 'reaction' AS kind,
+                    'r:' || r.message_id || ':' || r.user_id || ':' || r.emoji AS key,
+                    r.user_id AS actor_id, m.chat_id, m.id AS message_id, r.emoji AS detail, r.created_at
+                FROM message_reactions r
+                JOIN messages m ON m.id = r.message_id
+                WHERE m.sender_id = $1 AND r.user_id <> $1
+                UNION ALL
+                SELECT 'reply', 'm:' || m.id, m.sender_id, m.chat_id, m.id, NULL, m.created_at
+                FROM messages m
+                JOIN messages root ON root.id = m.thread_id
+                WHERE root.sender_id = $1 AND m.sender_id <> $1
+                UNION ALL
+                SELECT 'invite', 'h:' || h.id, h.actor_id, h.chat_id, NULL, NULL, h.created_at
+                FROM chat_member_history h
+                WHERE h.user_id = $1 AND h.action = 'join' AND h.actor_id <> $1
+            ) a
+            WHERE (created_at, key) < ($2, $3)
+            ORDER BY created_at DESC, key DESC
+            LIMIT $4
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(before)
+        .bind(before_key)
+        .bind(limit as i64)
+        .fetch_all(pool)
+        .await?;
+
+        let next_cursor = match items.last() {
+            Some(last) if items.len() as u64 == limit => Some(encode_cursor(last)),
+            _ => None,
+        };
+        Ok(ActivityPage { items, next_cursor })
+    }
+}
+
+fn encode_cursor(item: &Activity) -> String {
+    format!("{}|{}", item.created_at.timestamp_micros(), item.key)
+}
+
+fn decode_cursor(cursor: &str) -> Result<(DateTime<Utc>, String), AppError> {
+    let invalid = || AppError::InvalidInput(format!("invalid cursor: {}", cursor));
+    let (micros, key) = cursor.split_once('|').ok_or_else(invalid)?;
+    let micros = micros.parse().map_err(|_| invalid())?;
+    let at = DateTime::from_timestamp_micros(micros).ok_or_else(invalid)?;
+    Ok((at, key.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, ActivityKind, AddChatMember, Chat, CreateMessage, Message};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn activity_should_aggregate_and_paginate() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        // message 1 in general was sent by user 1
+        Message::add_reaction(1, 2, "👍", &pool).await?;
+        Message::add_reaction(1, 1, "👍", &pool).await?;
+        let mut reply = CreateMessage::new("reply");
+        reply.thread_id = Some(1);
+        Message::create(&reply, 1, 3, &pool).await?;
+        let chat = Chat::get_by_id(4, &pool).await?.expect("chat 4 should exist");
+        let input = AddChatMember { user_id: 2 };
+        chat.add_member(input.user_id as _, 1, &pool).await?;
+
+        let page = Activity::list(1, &ListActivity::default(), &pool).await?;
+        let kinds: Vec<_> = page.items.iter().map(|a| a.kind).collect();
+        assert_eq!(kinds, vec![ActivityKind::Reply, ActivityKind::Reaction]);
+        assert!(page.next_cursor.is_none());
+
+        let page = Activity::list(2, &ListActivity::default(), &pool).await?;
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].kind, ActivityKind::Invite);
+        assert_eq!(page.items[0].chat_id, 4);
+
+        let input = ListActivity {
+            cursor: None,
+            limit: Some(1),
+        };
+        let page = Activity::list(1, &input, &pool).await?;
+        assert_eq!(page.items[0].kind, ActivityKind::Reply);
+        let input = ListActivity {
+            cursor: page.next_cursor,
+            limit: Some(1),
+        };
+        let page = Activity::list(1, &input, &pool).await?;
+        assert_eq!(page.items[0].kind, ActivityKind::Reaction);
+
+        let input = ListActivity {
+            cursor: Some("garbage".to_string()),
+            limit: None,
+        };
+        assert!(matches!(Activity::list(1, &input, &pool).await, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{AppError, User, Workspace, WorkspaceStats, WorkspaceUser};
+
+use super::{
+    approval::revoke_sessions,
+    workspace::escape_like,
+};
+
+const DEFAULT_USER_LIMIT: u64 = 100;
+const MAX_USER_LIMIT: u64 = 500;
+// sessions used within this many days count as signed in
+const SESSION_ACTIVE_DAYS: i32 = 30;
+
+const SELECT_USER: &str = r#"
+    SELECT u.id, u.fullname, u.email, u.role, u.password_reset_required, u.deactivated_at, u.created_at,
+        (SELECT max(s.last_active_at) FROM user_sessions s WHERE s.user_id = u.id) AS last_active_at
+    FROM users u
+"#;
+
+// the query of /admin/users, the cursor is the id of the last user of the previous page
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListWorkspaceUsers {
+    // matched against fullname and email, case insensitive
+    #[serde(default)]
+    pub q: Option<String>,
+    // only deactivated users, or only active ones
+    #[serde(default)]
+    pub deactivated: Option<bool>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+// what admins see of the workspace on top of WorkspaceStats
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AdminStats {
+    #[serde(flatten)]
+    pub usage: WorkspaceStats,
+    pub active_users: i64,
+    pub deactivated_users: i64,
+    pub admins: i64,
+    // users who have to reset their password before signing in again
+    pub password_resets_pending: i64,
+    // users with a session used in the last 30 days
+    pub signed_in_users: i64,
+    pub pending_approvals: i64,
+}
+
+impl ListWorkspaceUsers {
+    pub(crate) fn limit(&self) -> u64 {
+        self.limit.unwrap_or(DEFAULT_USER_LIMIT).min(MAX_USER_LIMIT)
+    }
+}
+
+impl Workspace {
+    pub async fn list_users(
+        id: u64,
+        input: &ListWorkspaceUsers,
+        after: Option<i64>,
+        pool: &PgPool,
+    ) -> Result<Vec<WorkspaceUser>, AppError> {
+        let pattern = input
+            .q
+            .as_deref()
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .map(|q| format!("%{}%", escape_like(q)));
+        let users = sqlx::query_as(&format!(
+            r#"
+            {}
+            WHERE u.ws_id = $1 AND u.id > $2
+                AND ($3::text IS NULL OR u.fullname ILIKE $3 OR u.email ILIKE $3)
+                AND ($4::bool IS NULL OR (u.deactivated_at IS NOT NULL) = $4)
+            ORDER BY u.id
+            LIMIT $5
+            "#,
+            SELECT_USER
+        ))
+        .bind(id as i64)
+        .bind(after.unwrap_or(-1))
+        .bind(pattern)
+        .bind(input.deactivated)
+        .bind(input.limit() as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(users)
+    }
+
+    pub async fn fetch_admin_stats(id: u64, pool: &PgPool) -> Result<AdminStats, AppError> {
+        let usage = Self::fetch_stats(id, pool).await?;
+        let (active_users, deactivated_users, admins, password_resets_pending, signed_in_users, pending_approvals) =
+            sqlx::query_as(
+                r#"
+                SELECT
+                    COUNT(*) FILTER (WHERE u.deactivated_at IS NULL),
+                    COUNT(*) FILTER (WHERE u.deactivated_at IS NOT NULL),
+                    COUNT(*) FILTER (WHERE u.deactivated_at IS NULL AND (u.role = 'admin' OR u.id = w.owner_id)),
+                    COUNT(*) FILTER (WHERE u.deactivated_at IS NULL AND u.password_reset_required),
+                    COUNT(*) FILTER (WHERE u.deactivated_at IS NULL AND EXISTS(
+                        SELECT 1 FROM user_sessions s
+                        WHERE s.user_id = u.id AND s.last_active_at > NOW() - make_interval(days => $2)
+                    )),
+                    (SELECT COUNT(*) FROM admin_approvals
+                        WHERE ws_id = $1 AND status = 'pending' AND expires_at > NOW())
+                FROM workspaces w LEFT JOIN users u ON u.ws_id = w.id
+                WHERE w.id = $1
+                GROUP BY w.id
+                "#,
+            )
+            .bind(id as i64)
+            .bind(SESSION_ACTIVE_DAYS)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("workspace not found: {}", id)))?;
+        Ok(AdminStats {
+            usage,
+            active_users,
+            deactivated_users,
+            admins,
+            password_resets_pending,
+            signed_in_users,
+            pending_approvals,
+        })
+    }
+}
+
+impl User {
+    // signs the member out everywhere, they can't sign in with their password until they have
+    // reset it. Returns the member for the reset email. The owner and the admin themselves
+    // can't be reset this way.
+    pub async fn force_password_reset(&self, user_id: u64, pool: &PgPool) -> Result<User, AppError> {
+        if user_id as i64 == self.id {
+            return Err(AppError::InvalidInput("admins can't force their own password reset".to_string()));
+        }
+        let owner: bool = sqlx::query_scalar("SELECT owner_id = $2 FROM workspaces WHERE id = $1")
+            .bind(self.ws_id)
+            .bind(user_id as i64)
+            .fetch_one(pool)
+            .await?;
+        if owner {
+            return Err(AppError::InvalidInput("the workspace owner's password can't be reset this way".to_string()));
+        }
+        let mut tx = pool.begin().await?;
+        let user: Option<User> = sqlx::query_as(
+            r#"
+            UPDATE users SET password_reset_required = TRUE
+            WHERE id = $1 AND ws_id = $2 AND deactivated_at IS NULL
+            RETURNING id, ws_id, fullname, email, created_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(self.ws_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(user) = user else {
+            return Err(AppError::NotFound(format!("member {}", user_id)));
+        };
+        revoke_sessions(&mut tx, &[user.id]).await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, AdminApproval, DestructiveAction, Submitted, WorkspaceRole};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn admins_should_manage_members() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let admin = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let bob = User::find_by_email("bob@acme.org", &pool).await?.expect("user should exist");
+
+        let action = DestructiveAction::DeactivateMember { user_id: bob.id };
+        let Submitted::Done(report) = AdminApproval::submit(&admin, action, &pool).await? else {
+            panic!("no approval should be required");
+        };
+        assert_eq!(report.deactivated_users, vec![bob.id]);
+        assert!(bob.is_deactivated(&pool).await?);
+        let input = ListWorkspaceUsers { deactivated: Some(true), ..Default::default() };
+        let users = Workspace::list_users(1, &input, None, &pool).await?;
+        assert_eq!(users.iter().map(|u| u.id).collect::<Vec<_>>(), vec![bob.id]);
+        assert_eq!(users[0].role, WorkspaceRole::Member);
+        let action = DestructiveAction::DeactivateMember { user_id: admin.id };
+        assert!(AdminApproval::submit(&admin, action, &pool).await.is_err());
+
+        let action = DestructiveAction::ReactivateMember { user_id: bob.id };
+        let Submitted::Done(report) = AdminApproval::submit(&admin, action, &pool).await? else {
+            panic!("no approval should be required");
+        };
+        assert_eq!(report.reactivated_users, vec![bob.id]);
+        assert!(!bob.is_deactivated(&pool).await?);
+
+        let user = admin.force_password_reset(bob.id as _, &pool).await?;
+        assert_eq!(user.id, bob.id);
+        assert!(bob.is_password_reset_required(&pool).await?);
+        let ret = admin.force_password_reset(1000, &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        // neither the caller nor the owner
+        let ret = admin.force_password_reset(admin.id as _, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        let owner = Workspace::find_by_id(1, &pool).await?.expect("workspace should exist").owner_id;
+        let ret = bob.force_password_reset(owner as _, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+
+        let stats = Workspace::fetch_admin_stats(1, &pool).await?;
+        assert_eq!(stats.deactivated_users, 0);
+        assert_eq!(stats.password_resets_pending, 1);
+        assert_eq!(stats.active_users, stats.usage.members);
+        Ok(())
+    }
+}
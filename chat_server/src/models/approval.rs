@@ -0,0 +1,764 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, PgPool, Postgres, Transaction};
+
+use crate::{AdminApproval, AppError, ApprovalStatus, AuditAction, ChatMemberAction, User, WorkspaceRole};
+
+use super::chat::record_member_action;
+
+// pending requests nobody decided on within a day have to be made again
+const APPROVAL_TTL_HOURS: i64 = 24;
+const MAX_REMOVED_MEMBERS: usize = 1000;
+
+const SELECT_APPROVAL: &str = r#"
+    SELECT id, ws_id, action, requested_by, status, decided_by, decided_at, expires_at, report, created_at,
+        (status = 'pending' AND expires_at < NOW()) AS expired
+    FROM admin_approvals
+"#;
+
+// admin actions that can't be undone, held for a second admin when the workspace requires it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DestructiveAction {
+    // deactivates every member, the data is kept
+    DeleteWorkspace,
+    // deletes messages sent before the given time, in one chat or the whole workspace
+    PurgeMessages {
+        before: DateTime<Utc>,
+        #[serde(default)]
+        chat_id: Option<i64>,
+    },
+    // deactivates the users and removes them from their group chats
+    RemoveMembers { user_ids: Vec<i64> },
+    // deletes the chat with its messages, pins and invites
+    DeleteChat { chat_id: i64 },
+    // turning the policy off needs the same approval it would otherwise require
+    DisableApprovals,
+    // an admin can run every other action, so making one needs approval too
+    PromoteToAdmin { user_id: i64 },
+    // signs the member out everywhere, their messages and memberships are kept
+    DeactivateMember { user_id: i64 },
+    // they sign in again as before, their sessions are not restored
+    ReactivateMember { user_id: i64 },
+}
+
+// what an action deleted, or would delete when run as a dry run
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ActionReport {
+    // signed out and unable to sign in again
+    pub deactivated_users: Vec<i64>,
+    // group chat and channel memberships taken away
+    pub removed_memberships: u64,
+    // number of deleted messages by chat id
+    pub deleted_messages: BTreeMap<i64, u64>,
+    pub unpinned_messages: u64,
+    // replies kept whose thread root was deleted
+    pub detached_replies: u64,
+    // reports from before chats could be deleted have none
+    #[serde(default)]
+    pub deleted_chats: Vec<i64>,
+    #[serde(default)]
+    pub promoted_users: Vec<i64>,
+    #[serde(default)]
+    pub reactivated_users: Vec<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Submitted {
+    Done(ActionReport),
+    // waiting for a second admin
+    Pending(AdminApproval),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SubmitAction {
+    // reports what the action would delete without running it
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateMemberRole {
+    pub role: WorkspaceRole,
+}
+
+impl DestructiveAction {
+    async fn validate(&self, actor: &User, pool: &PgPool) -> Result<(), AppError> {
+        match self {
+            Self::PurgeMessages { before, chat_id } => {
+                if *before > Utc::now() {
+                    return Err(AppError::InvalidInput("purge cutoff can't be in the future".to_string()));
+                }
+                if let Some(chat_id) = chat_id {
+                    let exists: bool =
+                        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM chats WHERE id = $1 AND ws_id = $2)")
+                            .bind(chat_id)
+                            .bind(actor.ws_id)
+                            .fetch_one(pool)
+                            .await?;
+                    if !exists {
+                        return Err(AppError::NotFound(format!("chat id {}", chat_id)));
+                    }
+                }
+            }
+            Self::RemoveMembers { user_ids } => {
+                if user_ids.is_empty() || user_ids.len() > MAX_REMOVED_MEMBERS {
+                    return Err(AppError::InvalidInput(format!(
+                        "between 1 and {} members can be removed at once",
+                        MAX_REMOVED_MEMBERS
+                    )));
+                }
+                // the owner can't be removed, and admins are demoted first
+                let removable: i64 = sqlx::query_scalar(
+                    r#"
+                    SELECT COUNT(*) FROM users u JOIN workspaces w ON w.id = u.ws_id
+                    WHERE u.ws_id = $1 AND u.id = ANY($2) AND u.id <> w.owner_id AND u.role = 'member'
+                    "#,
+                )
+                .bind(actor.ws_id)
+                .bind(user_ids)
+                .fetch_one(pool)
+                .await?;
+                let mut ids = user_ids.clone();
+                ids.sort();
+                ids.dedup();
+                if removable != ids.len() as i64 {
+                    return Err(AppError::InvalidInput(
+                        "only members of this workspace who are not admins can be removed".to_string(),
+                    ));
+                }
+            }
+            Self::DeleteChat { chat_id } => {
+                let scim: Option<bool> = sqlx::query_scalar(
+                    r#"
+                    SELECT EXISTS(SELECT 1 FROM scim_groups g WHERE g.chat_id = c.id)
+                    FROM chats c WHERE c.id = $1 AND c.ws_id = $2
+                    "#,
+                )
+                .bind(chat_id)
+                .bind(actor.ws_id)
+                .fetch_optional(pool)
+                .await?;
+                match scim {
+                    None => return Err(AppError::NotFound(format!("chat id {}", chat_id))),
+                    Some(true) => {
+                        return Err(AppError::InvalidInput(
+                            "the chat is provisioned by SCIM, delete the group in the identity provider".to_string(),
+                        ))
+                    }
+                    Some(false) => {}
+                }
+            }
+            Self::PromoteToAdmin { user_id } => {
+                let promotable: bool = sqlx::query_scalar(
+                    r#"
+                    SELECT EXISTS(
+                        SELECT 1 FROM users
+                        WHERE id = $1 AND ws_id = $2 AND role = 'member' AND deactivated_at IS NULL
+                    )
+                    "#,
+                )
+                .bind(user_id)
+                .bind(actor.ws_id)
+                .fetch_one(pool)
+                .await?;
+                if !promotable {
+                    return Err(AppError::NotFound(format!("member {}", user_id)));
+                }
+            }
+            Self::DeactivateMember { user_id } => {
+                if *user_id == actor.id {
+                    return Err(AppError::InvalidInput("admins can't deactivate themselves".to_string()));
+                }
+                let owner: Option<bool> = sqlx::query_scalar(
+                    "SELECT w.owner_id = u.id FROM users u JOIN workspaces w ON w.id = u.ws_id WHERE u.id = $1 AND u.ws_id = $2",
+                )
+                .bind(user_id)
+                .bind(actor.ws_id)
+                .fetch_optional(pool)
+                .await?;
+                match owner {
+                    None => return Err(AppError::NotFound(format!("member {}", user_id))),
+                    Some(true) => {
+                        return Err(AppError::InvalidInput("the workspace owner cannot be deactivated".to_string()))
+                    }
+                    Some(false) => {}
+                }
+            }
+            Self::ReactivateMember { user_id } => {
+                let exists: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM users WHERE id = $1 AND ws_id = $2)")
+                    .bind(user_id)
+                    .bind(actor.ws_id)
+                    .fetch_one(pool)
+                    .await?;
+                if !exists {
+                    return Err(AppError::NotFound(format!("member {}", user_id)));
+                }
+            }
+            Self::DeleteWorkspace | Self::DisableApprovals => {}
+        }
+        Ok(())
+    }
+
+    async fn execute(
+        &self,
+        ws_id: i64,
+        actor_id: i64,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<ActionReport, AppError> {
+        let mut report = ActionReport::default();
+        match self {
+            Self::DeleteWorkspace => {
+                let user_ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM users WHERE ws_id = $1")
+                    .bind(ws_id)
+                    .fetch_all(&mut **tx)
+                    .await?;
+                report.deactivated_users = deactivate_users(tx, &user_ids).await?;
+                sqlx::query("UPDATE workspaces SET deleted_at = NOW(), signup_domain = NULL WHERE id = $1")
+                    .bind(ws_id)
+                    .execute(&mut **tx)
+                    .await?;
+            }
+            Self::PurgeMessages { before, chat_id } => {
+                const PURGED: &str = r#"
+                    SELECT m.id FROM messages m JOIN chats c ON c.id = m.chat_id
+                    WHERE c.ws_id = $1 AND ($3::bigint IS NULL OR m.chat_id = $3) AND m.created_at < $2
+                "#;
+                // pins point at the purged messages and replies would lose their thread root
+                report.unpinned_messages =
+                    sqlx::query(&format!("DELETE FROM chat_pins WHERE message_id IN ({})", PURGED))
+                        .bind(ws_id)
+                        .bind(before)
+                        .bind(chat_id)
+                        .execute(&mut **tx)
+                        .await?
+                        .rows_affected();
+                report.detached_replies = sqlx::query(&format!(
+                    "UPDATE messages SET thread_id = NULL WHERE thread_id IN ({0}) AND id NOT IN ({0})",
+                    PURGED
+                ))
+                .bind(ws_id)
+                .bind(before)
+                .bind(chat_id)
+                .execute(&mut **tx)
+                .await?
+                .rows_affected();
+                let deleted: Vec<i64> = sqlx::query_scalar(
+                    r#"
+                    DELETE FROM messages m USING chats c
+                    WHERE c.id = m.chat_id AND c.ws_id = $1 AND ($3::bigint IS NULL OR m.chat_id = $3)
+                        AND m.created_at < $2
+                    RETURNING m.chat_id
+                    "#,
+                )
+                .bind(ws_id)
+                .bind(before)
+                .bind(chat_id)
+                .fetch_all(&mut **tx)
+                .await?;
+                for chat_id in deleted {
+                    *report.deleted_messages.entry(chat_id).or_default() += 1;
+                }
+            }
+            Self::RemoveMembers { user_ids } => {
+                report.deactivated_users = deactivate_users(tx, user_ids).await?;
+                let removed: Vec<(i64, i64)> = sqlx::query_as(
+                    r#"
+                    SELECT c.id, u.id FROM chats c CROSS JOIN unnest($2::bigint[]) AS u(id)
+                    WHERE c.ws_id = $1 AND c.type <> 'single' AND u.id = ANY(c.members)
+                    "#,
+                )
+                .bind(ws_id)
+                .bind(user_ids)
+                .fetch_all(&mut **tx)
+                .await?;
+                sqlx::query(
+                    r#"
+                    UPDATE chats SET members = ARRAY(SELECT m FROM unnest(members) AS m WHERE m <> ALL($2))
+                    WHERE ws_id = $1 AND type <> 'single' AND members && $2
+                    "#,
+                )
+                .bind(ws_id)
+                .bind(user_ids)
+                .execute(&mut **tx)
+                .await?;
+                report.removed_memberships = removed.len() as u64;
+                for (chat_id, user_id) in removed {
+                    record_member_action(tx, chat_id, user_id as _, actor_id as _, ChatMemberAction::Kick).await?;
+                }
+            }
+            Self::DeleteChat { chat_id } => {
+                // rows of the chat's messages, then of the chat, the rest goes with the chat
+                for table in ["message_chunks", "message_mentions", "message_reactions", "email_notifications"] {
+                    sqlx::query(&format!(
+                        "DELETE FROM {} WHERE message_id IN (SELECT id FROM messages WHERE chat_id = $1)",
+                        table
+                    ))
+                    .bind(chat_id)
+                    .execute(&mut **tx)
+                    .await?;
+                }
+                sqlx::query("DELETE FROM thread_subscriptions WHERE thread_id IN (SELECT id FROM messages WHERE chat_id = $1)")
+                    .bind(chat_id)
+                    .execute(&mut **tx)
+                    .await?;
+                report.unpinned_messages = sqlx::query("DELETE FROM chat_pins WHERE chat_id = $1")
+                    .bind(chat_id)
+                    .execute(&mut **tx)
+                    .await?
+                    .rows_affected();
+                for table in ["chat_invites", "chat_member_history", "notifications"] {
+                    sqlx::query(&format!("DELETE FROM {} WHERE chat_id = $1", table))
+                        .bind(chat_id)
+                        .execute(&mut **tx)
+                        .await?;
+                }
+                let deleted = sqlx::query("DELETE FROM messages WHERE chat_id = $1")
+                    .bind(chat_id)
+                    .execute(&mut **tx)
+                    .await?
+                    .rows_affected();
+                if deleted > 0 {
+                    report.deleted_messages.insert(*chat_id, deleted);
+                }
+                let chat: Option<(Vec<i64>, Option<String>)> =
+                    sqlx::query_as("DELETE FROM chats WHERE id = $1 AND ws_id = $2 RETURNING members, name")
+                        .bind(chat_id)
+                        .bind(ws_id)
+                        .fetch_optional(&mut **tx)
+                        .await?;
+                if let Some((members, name)) = chat {
+                    report.removed_memberships = members.len() as u64;
+                    report.deleted_chats.push(*chat_id);
+                    // with the delete, so a deleted chat always has its entry
+                    sqlx::query(
+                        r#"
+                        INSERT INTO audit_logs (ws_id, actor_id, action, target_id, details)
+                        VALUES ($1, $2, $3, $4, $5)
+                        "#,
+                    )
+                    .bind(ws_id)
+                    .bind(actor_id)
+                    .bind(AuditAction::ChatDeleted)
+                    .bind(chat_id)
+                    .bind(serde_json::json!({ "name": name, "members": members, "deleted_messages": deleted }))
+                    .execute(&mut **tx)
+                    .await?;
+                }
+            }
+            Self::DisableApprovals => {
+                sqlx::query("UPDATE workspaces SET require_approval = FALSE WHERE id = $1")
+                    .bind(ws_id)
+                    .execute(&mut **tx)
+                    .await?;
+            }
+            Self::PromoteToAdmin { user_id } => {
+                // the member may have been deactivated while the request was pending
+                report.promoted_users = sqlx::query_scalar(
+                    r#"
+                    UPDATE users SET role = 'admin'
+                    WHERE id = $1 AND ws_id = $2 AND role = 'member' AND deactivated_at IS NULL
+                    RETURNING id
+                    "#,
+                )
+                .bind(user_id)
+                .bind(ws_id)
+                .fetch_all(&mut **tx)
+                .await?;
+            }
+            Self::DeactivateMember { user_id } => {
+                report.deactivated_users = deactivate_users(tx, &[*user_id]).await?;
+            }
+            Self::ReactivateMember { user_id } => {
+                report.reactivated_users = sqlx::query_scalar(
+                    "UPDATE users SET deactivated_at = NULL WHERE id = $1 AND ws_id = $2 AND deactivated_at IS NOT NULL RETURNING id",
+                )
+                .bind(user_id)
+                .bind(ws_id)
+                .fetch_all(&mut **tx)
+                .await?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl AdminApproval {
+    // runs the action right away unless the workspace wants a second admin to approve it
+    pub async fn submit(actor: &User, action: DestructiveAction, pool: &PgPool) -> Result<Submitted, AppError> {
+        action.validate(actor, pool).await?;
+        if !requires_approval(actor.ws_id, pool).await? {
+            let mut tx = pool.begin().await?;
+            let report = action.execute(actor.ws_id, actor.id, &mut tx).await?;
+            tx.commit().await?;
+            return Ok(Submitted::Done(report));
+        }
+        let approval = sqlx::query_as(&format!(
+            r#"
+            WITH approval AS (
+                INSERT INTO admin_approvals (ws_id, action, requested_by, expires_at)
+                VALUES ($1, $2, $3, $4)
+                RETURNING *
+            )
+            {}
+            "#,
+            SELECT_APPROVAL.replace("FROM admin_approvals", "FROM approval")
+        ))
+        .bind(actor.ws_id)
+        .bind(Json(&action))
+        .bind(actor.id)
+        .bind(Utc::now() + Duration::hours(APPROVAL_TTL_HOURS))
+        .fetch_one(pool)
+        .await?;
+        Ok(Submitted::Pending(approval))
+    }
+
+    // the action is run and rolled back, so the report is exactly what running it would do
+    pub async fn dry_run(actor: &User, action: &DestructiveAction, pool: &PgPool) -> Result<ActionReport, AppError> {
+        action.validate(actor, pool).await?;
+        let mut tx = pool.begin().await?;
+        let report = action.execute(actor.ws_id, actor.id, &mut tx).await?;
+        tx.rollback().await?;
+        Ok(report)
+    }
+
+    // what approving a pending request would do right now
+    pub async fn dry_run_pending(id: u64, approver: &User, pool: &PgPool) -> Result<ActionReport, AppError> {
+        let approval: Option<Self> = sqlx::query_as(&format!("{} WHERE id = $1 AND ws_id = $2", SELECT_APPROVAL))
+            .bind(id as i64)
+            .bind(approver.ws_id)
+            .fetch_optional(pool)
+            .await?;
+        let Some(approval) = approval else {
+            return Err(AppError::NotFound(format!("approval {}", id)));
+        };
+        if approval.status != ApprovalStatus::Pending || approval.expired {
+            return Err(AppError::InvalidInput(format!("approval {} is no longer pending", id)));
+        }
+        Self::dry_run(approver, &approval.action, pool).await
+    }
+
+    pub async fn list(ws_id: u64, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let approvals = sqlx::query_as(&format!("{} WHERE ws_id = $1 ORDER BY id DESC", SELECT_APPROVAL))
+            .bind(ws_id as i64)
+            .fetch_all(pool)
+            .await?;
+        Ok(approvals)
+    }
+
+    // the decision and the action are committed together, so an action runs at most once
+    pub async fn approve(id: u64, approver: &User, pool: &PgPool) -> Result<Self, AppError> {
+        let mut tx = pool.begin().await?;
+        let mut approval = decide(&mut tx, id, approver, true).await?;
+        let report = approval.action.execute(approval.ws_id, approver.id, &mut tx).await?;
+        sqlx::query("UPDATE admin_approvals SET report = $2 WHERE id = $1")
+            .bind(approval.id)
+            .bind(Json(&report))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        approval.report = Some(Json(report));
+        Ok(approval)
+    }
+
+    // the requester can withdraw their own request this way
+    pub async fn reject(id: u64, approver: &User, pool: &PgPool) -> Result<Self, AppError> {
+        let mut tx = pool.begin().await?;
+        let approval = decide(&mut tx, id, approver, false).await?;
+        tx.commit().await?;
+        Ok(approval)
+    }
+}
+
+impl User {
+    pub async fn is_deactivated(&self, pool: &PgPool) -> Result<bool, AppError> {
+        let deactivated: Option<bool> =
+            sqlx::query_scalar("SELECT deactivated_at IS NOT NULL FROM users WHERE id = $1")
+                .bind(self.id)
+                .fetch_optional(pool)
+                .await?;
+        Ok(deactivated.unwrap_or(true))
+    }
+
+    // the owner's role can't be changed, they stay an admin
+    pub async fn set_role(&self, user_id: u64, role: WorkspaceRole, pool: &PgPool) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            UPDATE users u SET role = $3
+            FROM workspaces w
+            WHERE u.id = $1 AND u.ws_id = $2 AND w.id = u.ws_id AND w.owner_id <> u.id
+                AND u.deactivated_at IS NULL
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(self.ws_id)
+        .bind(role)
+        .execute(pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("member {}", user_id)));
+        }
+        Ok(())
+    }
+}
+
+async fn requires_approval(ws_id: i64, pool: &PgPool) -> Result<bool, AppError> {
+    let required: Option<bool> = sqlx::query_scalar("SELECT require_approval FROM workspaces WHERE id = $1")
+        .bind(ws_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(required.unwrap_or(false))
+}
+
+async fn decide(
+    tx: &mut Transaction<'_, Postgres>,
+    id: u64,
+    approver: &User,
+    approve: bool,
+) -> Result<AdminApproval, AppError> {
+    let approval: Option<AdminApproval> =
+        sqlx::query_as(&format!("{} WHERE id = $1 AND ws_id = $2 FOR UPDATE", SELECT_APPROVAL))
+            .bind(id as i64)
+            .bind(approver.ws_id)
+            .fetch_optional(&mut **tx)
+            .await?;
+    let Some(mut approval) = approval else {
+        return Err(AppError::NotFound(format!("approval {}", id)));
+    };
+    if approval.status != ApprovalStatus::Pending || approval.expired {
+        return Err(AppError::InvalidInput(format!("approval {} is no longer pending", id)));
+    }
+    if approve && approval.requested_by == approver.id {
+        return Err(AppError::PermissionDenied(
+            "a destructive action must be approved by another admin".to_string(),
+        ));
+    }
+    let status = if approve { ApprovalStatus::Approved } else { ApprovalStatus::Rejected };
+    let decided_at: DateTime<Utc> = sqlx::query_scalar(
+        r#"
+        UPDATE admin_approvals SET status = $2, decided_by = $3, decided_at = NOW()
+        WHERE id = $1
+        RETURNING decided_at
+        "#,
+    )
+    .bind(approval.id)
+    .bind(status)
+    .bind(approver.id)
+    .fetch_one(&mut **tx)
+    .await?;
+    approval.status = status;
+    approval.decided_by = Some(approver.id);
+    approval.decided_at = Some(decided_at);
+    Ok(approval)
+}
+
+// revokes every session, including access tokens still in use. Returns the users who were
+// still active.
+pub(super) async fn deactivate_users(
+    tx: &mut Transaction<'_, Postgres>,
+    user_ids: &[i64],
+) -> Result<Vec<i64>, AppError> {
+    let deactivated = sqlx::query_scalar(
+        r#"
+        UPDATE users SET deactivated_at = NOW(), role = 'member'
+        WHERE id = ANY($1) AND deactivated_at IS NULL
+        RETURNING id
+        "#,
+    )
+    .bind(user_ids)
+    .fetch_all(&mut **tx)
+    .await?;
+    revoke_sessions(tx, user_ids).await?;
+    Ok(deactivated)
+}
+
+// signs the users out everywhere, access tokens still in use included
+pub(super) async fn revoke_sessions(tx: &mut Transaction<'_, Postgres>, user_ids: &[i64]) -> Result<(), AppError> {
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = ANY($1) AND revoked_at IS NULL")
+        .bind(user_ids)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO revoked_tokens (jti, user_id, expires_at)
+        SELECT access_jti, user_id, access_expires_at FROM user_sessions
+        WHERE user_id = ANY($1) AND access_jti IS NOT NULL AND access_expires_at > NOW()
+        ON CONFLICT (jti) DO NOTHING
+        "#,
+    )
+    .bind(user_ids)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, Chat, ChatType, Workspace};
+    use anyhow::Result;
+
+    async fn admins(pool: &PgPool) -> Result<(User, User)> {
+        let owner = User::find_by_email("tchen@acme.org", pool).await?.expect("user should exist");
+        let ws = Workspace::find_by_id(1, pool).await?.expect("workspace should exist");
+        ws.update_owner(owner.id as _, pool).await?;
+        let admin = User::find_by_email("alice@acme.org", pool).await?.expect("user should exist");
+        owner.set_role(admin.id as _, WorkspaceRole::Admin, pool).await?;
+        sqlx::query("UPDATE workspaces SET require_approval = TRUE WHERE id = 1").execute(pool).await?;
+        Ok((owner, admin))
+    }
+
+    #[tokio::test]
+    async fn destructive_action_should_wait_for_second_admin() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let (owner, admin) = admins(&pool).await?;
+        let bob = User::find_by_email("bob@acme.org", &pool).await?.expect("user should exist");
+        let action = DestructiveAction::RemoveMembers { user_ids: vec![bob.id] };
+
+        let Submitted::Pending(approval) = AdminApproval::submit(&owner, action, &pool).await? else {
+            panic!("approval should be required");
+        };
+        assert_eq!(approval.status, ApprovalStatus::Pending);
+        assert!(!bob.is_deactivated(&pool).await?);
+
+        // approvers see what they are about to do, nothing is changed yet
+        let preview = AdminApproval::dry_run_pending(approval.id as _, &admin, &pool).await?;
+        assert_eq!(preview.deactivated_users, vec![bob.id]);
+        assert!(preview.removed_memberships > 0);
+        assert!(!bob.is_deactivated(&pool).await?);
+
+        // the requester can't approve their own request
+        let ret = AdminApproval::approve(approval.id as _, &owner, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let approved = AdminApproval::approve(approval.id as _, &admin, &pool).await?;
+        assert_eq!(approved.status, ApprovalStatus::Approved);
+        assert_eq!(approved.decided_by, Some(admin.id));
+        assert_eq!(approved.report.as_deref(), Some(&preview));
+        assert!(bob.is_deactivated(&pool).await?);
+        let chats = Chat::fetch_all(1, &pool).await?;
+        assert!(chats.iter().filter(|c| c.r#type != ChatType::Single).all(|c| !c.members.contains(&bob.id)));
+
+        // an action runs at most once
+        let ret = AdminApproval::approve(approval.id as _, &admin, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_or_rejected_approvals_should_not_run() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let (owner, admin) = admins(&pool).await?;
+        let purge = DestructiveAction::PurgeMessages { before: Utc::now(), chat_id: None };
+
+        let Submitted::Pending(approval) = AdminApproval::submit(&owner, purge.clone(), &pool).await? else {
+            panic!("approval should be required");
+        };
+        let rejected = AdminApproval::reject(approval.id as _, &owner, &pool).await?;
+        assert_eq!(rejected.status, ApprovalStatus::Rejected);
+
+        let Submitted::Pending(approval) = AdminApproval::submit(&owner, purge, &pool).await? else {
+            panic!("approval should be required");
+        };
+        sqlx::query("UPDATE admin_approvals SET expires_at = NOW() - INTERVAL '1 minute' WHERE id = $1")
+            .bind(approval.id)
+            .execute(&pool)
+            .await?;
+        let ret = AdminApproval::approve(approval.id as _, &admin, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        let messages: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages").fetch_one(&pool).await?;
+        assert!(messages > 0);
+
+        let listed = AdminApproval::list(1, &pool).await?;
+        assert_eq!(listed.len(), 2);
+        assert!(listed[0].expired);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn destructive_action_should_run_right_away_without_policy() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let owner = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let ws = Workspace::find_by_id(1, &pool).await?.expect("workspace should exist");
+        ws.update_owner(owner.id as _, &pool).await?;
+        let purge = DestructiveAction::PurgeMessages { before: Utc::now(), chat_id: None };
+        let count = || {
+            sqlx::query_scalar::<_, i64>(
+                "SELECT COUNT(*) FROM messages m JOIN chats c ON c.id = m.chat_id WHERE c.ws_id = 1",
+            )
+            .fetch_one(&pool)
+        };
+        let before = count().await?;
+        let preview = AdminApproval::dry_run(&owner, &purge, &pool).await?;
+        assert_eq!(preview.deleted_messages.values().sum::<u64>(), before as u64);
+        assert_eq!(count().await?, before);
+
+        let Submitted::Done(report) = AdminApproval::submit(&owner, purge, &pool).await? else {
+            panic!("no approval should be required");
+        };
+        assert_eq!(report, preview);
+        let messages: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM messages m JOIN chats c ON c.id = m.chat_id WHERE c.ws_id = 1",
+        )
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(messages, 0);
+
+        let remove_owner = DestructiveAction::RemoveMembers { user_ids: vec![owner.id] };
+        let ret = AdminApproval::submit(&owner, remove_owner, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_chat_should_remove_the_chat_and_its_messages() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let owner = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let chat = Chat::get_by_id(1, &pool).await?.expect("chat should exist");
+        let action = DestructiveAction::DeleteChat { chat_id: chat.id };
+        let Submitted::Done(report) = AdminApproval::submit(&owner, action.clone(), &pool).await? else {
+            panic!("no approval should be required");
+        };
+        assert_eq!(report.deleted_chats, vec![chat.id]);
+        assert_eq!(report.removed_memberships, chat.members.len() as u64);
+        assert!(Chat::get_by_id(1, &pool).await?.is_none());
+        let messages: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE chat_id = 1").fetch_one(&pool).await?;
+        assert_eq!(messages, 0);
+        let (actor_id, details): (i64, serde_json::Value) = sqlx::query_as(
+            "SELECT actor_id, details FROM audit_logs WHERE action = 'chat_deleted' AND target_id = $1",
+        )
+        .bind(chat.id)
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(actor_id, owner.id);
+        assert_eq!(details["name"], "general");
+
+        let ret = AdminApproval::submit(&owner, action, &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn promotion_to_admin_should_wait_for_second_admin() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let (owner, admin) = admins(&pool).await?;
+        let bob = User::find_by_email("bob@acme.org", &pool).await?.expect("user should exist");
+        let action = DestructiveAction::PromoteToAdmin { user_id: bob.id };
+        let Submitted::Pending(approval) = AdminApproval::submit(&owner, action, &pool).await? else {
+            panic!("approval should be required");
+        };
+        assert!(!bob.is_workspace_admin(&pool).await?);
+
+        let approved = AdminApproval::approve(approval.id as _, &admin, &pool).await?;
+        assert_eq!(approved.report.map(|r| r.0.promoted_users), Some(vec![bob.id]));
+        assert!(bob.is_workspace_admin(&pool).await?);
+
+        // admins can't be promoted again
+        let action = DestructiveAction::PromoteToAdmin { user_id: bob.id };
+        let ret = AdminApproval::submit(&owner, action, &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+}
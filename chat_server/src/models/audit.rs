@@ -0,0 +1,148 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{AppError, AuditAction, AuditLog};
+
+const DEFAULT_AUDIT_LIMIT: u64 = 100;
+const MAX_AUDIT_LIMIT: u64 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateAuditLog {
+    pub ws_id: i64,
+    pub actor_id: i64,
+    pub action: AuditAction,
+    pub target_id: Option<i64>,
+    pub details: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListAuditLogs {
+    #[serde(default)]
+    pub actor_id: Option<i64>,
+    #[serde(default)]
+    pub action: Option<AuditAction>,
+    // inclusive
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    // exclusive
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+impl AuditLog {
+    pub async fn create(input: &CreateAuditLog, pool: &PgPool) -> Result<Self, AppError> {
+        let log = sqlx::query_as(
+            r#"
+            INSERT INTO audit_logs (ws_id, actor_id, action, target_id, details)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, ws_id, actor_id, action, target_id, details, created_at
+            "#,
+        )
+        .bind(input.ws_id)
+        .bind(input.actor_id)
+        .bind(input.action)
+        .bind(input.target_id)
+        .bind(&input.details)
+        .fetch_one(pool)
+        .await?;
+        Ok(log)
+    }
+
+    pub async fn find(ws_id: u64, id: u64, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let log = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, actor_id, action, target_id, details, created_at
+            FROM audit_logs
+            WHERE ws_id = $1 AND id = $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(id as i64)
+        .fetch_optional(pool)
+        .await?;
+        Ok(log)
+    }
+
+    pub async fn list(ws_id: u64, input: &ListAuditLogs, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let limit = input.limit.unwrap_or(DEFAULT_AUDIT_LIMIT).min(MAX_AUDIT_LIMIT);
+        let logs = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, actor_id, action, target_id, details, created_at
+            FROM audit_logs
+            WHERE ws_id = $1
+                AND ($2::bigint IS NULL OR actor_id = $2)
+                AND ($3::audit_action IS NULL OR action = $3)
+                AND ($4::timestamptz IS NULL OR created_at >= $4)
+                AND ($5::timestamptz IS NULL OR created_at < $5)
+            ORDER BY created_at DESC, id DESC
+            LIMIT $6
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(input.actor_id)
+        .bind(input.action)
+        .bind(input.since)
+        .bind(input.until)
+        .bind(limit as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(logs)
+    }
+}
+
+#[cfg(test)]
+impl CreateAuditLog {
+    pub fn new(ws_id: i64, actor_id: i64, action: AuditAction) -> Self {
+        Self {
+            ws_id,
+            actor_id,
+            action,
+            target_id: None,
+            details: serde_json::json!({}),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn audit_log_create_and_list_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        AuditLog::create(&CreateAuditLog::new(1, 1, AuditAction::Signin), &pool).await?;
+        AuditLog::create(&CreateAuditLog::new(1, 2, AuditAction::Signin), &pool).await?;
+        let mut input = CreateAuditLog::new(1, 1, AuditAction::ChatCreated);
+        input.target_id = Some(1);
+        input.details = serde_json::json!({ "name": "general" });
+        let log = AuditLog::create(&input, &pool).await?;
+        assert_eq!(log.details["name"], "general");
+        AuditLog::create(&CreateAuditLog::new(2, 1, AuditAction::Signin), &pool).await?;
+
+        let logs = AuditLog::list(1, &ListAuditLogs::default(), &pool).await?;
+        assert_eq!(logs.len(), 3);
+        assert_eq!(logs[0].action, AuditAction::ChatCreated);
+
+        let input = ListAuditLogs {
+            actor_id: Some(1),
+            action: Some(AuditAction::Signin),
+            ..Default::default()
+        };
+        let logs = AuditLog::list(1, &input, &pool).await?;
+        assert_eq!(logs.len(), 1);
+
+        let input = ListAuditLogs {
+            since: Some(Utc::now() + Duration::minutes(1)),
+            ..Default::default()
+        };
+        let logs = AuditLog::list(1, &input, &pool).await?;
+        assert!(logs.is_empty());
+        Ok(())
+    }
+}
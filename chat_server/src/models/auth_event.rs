@@ -0,0 +1,132 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{AppError, AuthEvent, AuthEventKind, ClientInfo, User};
+
+const DEFAULT_AUTH_EVENT_LIMIT: u64 = 100;
+const MAX_AUTH_EVENT_LIMIT: u64 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateAuthEvent {
+    pub ws_id: i64,
+    pub user_id: i64,
+    pub kind: AuthEventKind,
+    pub ip: Option<String>,
+    pub user_agent: String,
+    pub details: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListAuthEvents {
+    // only honored for workspace admins, users always see their own events
+    #[serde(default)]
+    pub user_id: Option<i64>,
+    #[serde(default)]
+    pub kind: Option<AuthEventKind>,
+    // inclusive
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    // exclusive
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+impl CreateAuthEvent {
+    pub fn new(user: &User, kind: AuthEventKind, client: &ClientInfo, details: serde_json::Value) -> Self {
+        Self {
+            ws_id: user.ws_id,
+            user_id: user.id,
+            kind,
+            ip: client.ip.clone(),
+            user_agent: client.user_agent.clone(),
+            details,
+        }
+    }
+}
+
+impl AuthEvent {
+    pub async fn create(input: &CreateAuthEvent, pool: &PgPool) -> Result<Self, AppError> {
+        let event = sqlx::query_as(
+            r#"
+            INSERT INTO auth_events (ws_id, user_id, kind, ip, user_agent, details)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, ws_id, user_id, kind, ip, user_agent, details, created_at
+            "#,
+        )
+        .bind(input.ws_id)
+        .bind(input.user_id)
+        .bind(input.kind)
+        .bind(&input.ip)
+        .bind(&input.user_agent)
+        .bind(&input.details)
+        .fetch_one(pool)
+        .await?;
+        Ok(event)
+    }
+
+    pub async fn list(ws_id: u64, input: &ListAuthEvents, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let limit = input.limit.unwrap_or(DEFAULT_AUTH_EVENT_LIMIT).min(MAX_AUTH_EVENT_LIMIT);
+        let events = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, user_id, kind, ip, user_agent, details, created_at
+            FROM auth_events
+            WHERE ws_id = $1
+                AND ($2::bigint IS NULL OR user_id = $2)
+                AND ($3::auth_event_kind IS NULL OR kind = $3)
+                AND ($4::timestamptz IS NULL OR created_at >= $4)
+                AND ($5::timestamptz IS NULL OR created_at < $5)
+            ORDER BY created_at DESC, id DESC
+            LIMIT $6
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(input.user_id)
+        .bind(input.kind)
+        .bind(input.since)
+        .bind(input.until)
+        .bind(limit as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn auth_events_should_be_listed_by_user_and_kind() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let tchen = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let alice = User::find_by_email("alice@acme.org", &pool).await?.expect("user should exist");
+        let client = ClientInfo::new("firefox", Some("10.0.0.1".to_string()));
+        for (user, kind) in [
+            (&tchen, AuthEventKind::Signin),
+            (&tchen, AuthEventKind::TokenRefreshed),
+            (&alice, AuthEventKind::SigninFailed),
+        ] {
+            AuthEvent::create(&CreateAuthEvent::new(user, kind, &client, serde_json::json!({})), &pool).await?;
+        }
+
+        let input = ListAuthEvents { user_id: Some(tchen.id), ..Default::default() };
+        let events = AuthEvent::list(1, &input, &pool).await?;
+        assert_eq!(events.len(), 2);
+        // newest first
+        assert_eq!(events[0].kind, AuthEventKind::TokenRefreshed);
+        assert_eq!(events[0].ip.as_deref(), Some("10.0.0.1"));
+        assert_eq!(events[0].user_agent, "firefox");
+
+        let input = ListAuthEvents { kind: Some(AuthEventKind::SigninFailed), ..Default::default() };
+        let events = AuthEvent::list(1, &input, &pool).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].user_id, alice.id);
+        assert!(AuthEvent::list(2, &ListAuthEvents::default(), &pool).await?.is_empty());
+        Ok(())
+    }
+}
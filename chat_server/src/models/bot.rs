@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+use crate::{utils::generate_token, AppError, Bot, ChatRead, ChatWrite, Grant, Scope, User, WorkspaceRole};
+
+// tells bot tokens apart from access tokens, see verify_token
+pub(crate) const BOT_TOKEN_PREFIX: &str = "bot_";
+const BOT_TOKEN_BYTES: usize = 32;
+const BOT_EMAIL_TOKEN_BYTES: usize = 8;
+const MAX_BOTS_PER_WORKSPACE: i64 = 50;
+// bots never administer the workspace
+const BOT_SCOPES: &[&str] = &[ChatRead::NAME, ChatWrite::NAME];
+
+const SELECT_BOT: &str = r#"
+    SELECT b.user_id AS id, b.ws_id, u.fullname AS name, b.scopes, b.created_by, b.created_at
+    FROM bots b JOIN users u ON u.id = b.user_id
+"#;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateBot {
+    pub name: String,
+    // what members get when left out
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, FromRow)]
+struct BotUser {
+    #[sqlx(flatten)]
+    user: User,
+    scopes: Vec<String>,
+}
+
+fn default_scopes() -> Vec<String> {
+    BOT_SCOPES.iter().map(|s| s.to_string()).collect()
+}
+
+impl Bot {
+    // the bot's user is made with it. It has no password and an undeliverable email, so it can
+    // only act through the token, which is only returned here and by rotate_token.
+    pub async fn create(ws_id: u64, input: &CreateBot, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let name = input.name.trim();
+        if name.is_empty() || name.chars().count() > 64 {
+            return Err(AppError::InvalidInput("bot name must be 1 to 64 characters".to_string()));
+        }
+        validate_scopes(&input.scopes)?;
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM bots WHERE ws_id = $1")
+            .bind(ws_id as i64)
+            .fetch_one(pool)
+            .await?;
+        if count >= MAX_BOTS_PER_WORKSPACE {
+            return Err(AppError::InvalidInput(format!(
+                "a workspace can have at most {} bots",
+                MAX_BOTS_PER_WORKSPACE
+            )));
+        }
+
+        let mut tx = pool.begin().await?;
+        let email = format!("{}@bots.invalid", generate_token(BOT_EMAIL_TOKEN_BYTES));
+        let bot_id: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO users (ws_id, email, fullname, email_verified_at)
+            VALUES ($1, $2, $3, NOW())
+            RETURNING id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(&email)
+        .bind(name)
+        .fetch_one(&mut *tx)
+        .await?;
+        let token = format!("{}{}", BOT_TOKEN_PREFIX, generate_token(BOT_TOKEN_BYTES));
+        sqlx::query(
+            r#"
+            INSERT INTO bots (user_id, ws_id, token_hash, scopes, created_by)
+            VALUES ($1, $2, sha256(convert_to($3, 'UTF8')), $4, $5)
+            "#,
+        )
+        .bind(bot_id)
+        .bind(ws_id as i64)
+        .bind(&token)
+        .bind(&input.scopes)
+        .bind(user_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        let mut bot = Self::find(ws_id, bot_id as _, pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("bot {}", bot_id)))?;
+        bot.token = Some(token);
+        Ok(bot)
+    }
+
+    pub async fn find(ws_id: u64, id: u64, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let bot = sqlx::query_as(&format!("{} WHERE b.ws_id = $1 AND b.user_id = $2", SELECT_BOT))
+            .bind(ws_id as i64)
+            .bind(id as i64)
+            .fetch_optional(pool)
+            .await?;
+        Ok(bot)
+    }
+
+    pub async fn list(ws_id: u64, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let bots = sqlx::query_as(&format!("{} WHERE b.ws_id = $1 ORDER BY b.user_id", SELECT_BOT))
+            .bind(ws_id as i64)
+            .fetch_all(pool)
+            .await?;
+        Ok(bots)
+    }
+
+    // the old token stops working right away
+    pub async fn rotate_token(ws_id: u64, id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let token = format!("{}{}", BOT_TOKEN_PREFIX, generate_token(BOT_TOKEN_BYTES));
+        let ret = sqlx::query(
+            "UPDATE bots SET token_hash = sha256(convert_to($3, 'UTF8')) WHERE ws_id = $1 AND user_id = $2",
+        )
+        .bind(ws_id as i64)
+        .bind(id as i64)
+        .bind(&token)
+        .execute(pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("bot {}", id)));
+        }
+        let mut bot = Self::find(ws_id, id, pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("bot {}", id)))?;
+        bot.token = Some(token);
+        Ok(bot)
+    }
+
+    // the bot's user is deactivated rather than deleted, so its messages keep their author
+    pub async fn delete(ws_id: u64, id: u64, pool: &PgPool) -> Result<(), AppError> {
+        let mut tx = pool.begin().await?;
+        let ret = sqlx::query("DELETE FROM bots WHERE ws_id = $1 AND user_id = $2")
+            .bind(ws_id as i64)
+            .bind(id as i64)
+            .execute(&mut *tx)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("bot {}", id)));
+        }
+        sqlx::query("UPDATE users SET deactivated_at = NOW() WHERE id = $1")
+            .bind(id as i64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // the bot user a token belongs to and what it may do, for verify_token
+    pub async fn authenticate(token: &str, pool: &PgPool) -> Result<Option<(User, Grant)>, AppError> {
+        let bot: Option<BotUser> = sqlx::query_as(
+            r#"
+            SELECT u.id, u.ws_id, u.fullname, u.email, u.created_at, b.scopes
+            FROM bots b JOIN users u ON u.id = b.user_id
+            WHERE b.token_hash = sha256(convert_to($1, 'UTF8')) AND u.deactivated_at IS NULL
+            "#,
+        )
+        .bind(token)
+        .fetch_optional(pool)
+        .await?;
+        Ok(bot.map(|bot| {
+            let grant = Grant {
+                role: WorkspaceRole::Member,
+                scopes: bot.scopes,
+                app_id: None,
+            };
+            (bot.user, grant)
+        }))
+    }
+}
+
+fn validate_scopes(scopes: &[String]) -> Result<(), AppError> {
+    if scopes.is_empty() {
+        return Err(AppError::InvalidInput("a bot needs at least one scope".to_string()));
+    }
+    if let Some(scope) = scopes.iter().find(|s| !BOT_SCOPES.contains(&s.as_str())) {
+        return Err(AppError::InvalidInput(format!(
+            "bots can't have the {} scope, only {}",
+            scope,
+            BOT_SCOPES.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, CreateMessage, Message, MessageKind, WorkspaceAdmin};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn bot_token_should_authenticate_bot_user() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateBot { name: "Deploy bot".to_string(), scopes: vec![ChatWrite::NAME.to_string()] };
+        let bot = Bot::create(1, &input, 1, &pool).await?;
+        let token = bot.token.clone().expect("token");
+        assert!(token.starts_with(BOT_TOKEN_PREFIX));
+
+        let (user, grant) = Bot::authenticate(&token, &pool).await?.expect("bot user");
+        assert_eq!((user.id, user.ws_id, user.fullname.as_str()), (bot.id, 1, "Deploy bot"));
+        assert!(grant.allows(ChatWrite::NAME));
+        assert!(!grant.allows(ChatRead::NAME));
+
+        let rotated = Bot::rotate_token(1, bot.id as _, &pool).await?;
+        assert!(Bot::authenticate(&token, &pool).await?.is_none());
+        let token = rotated.token.expect("token");
+        assert!(Bot::authenticate(&token, &pool).await?.is_some());
+
+        Bot::delete(1, bot.id as _, &pool).await?;
+        assert!(Bot::authenticate(&token, &pool).await?.is_none());
+        assert!(Bot::list(1, &pool).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bot_should_not_get_admin_scope() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateBot { name: "Root".to_string(), scopes: vec![WorkspaceAdmin::NAME.to_string()] };
+        let ret = Bot::create(1, &input, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bot_messages_should_be_attributed_to_bot() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateBot { name: "Deploy bot".to_string(), scopes: default_scopes() };
+        let bot = Bot::create(1, &input, 1, &pool).await?;
+        let chat = crate::Chat::get_by_id(1, &pool).await?.expect("chat");
+        chat.add_member(bot.id as _, 1, &pool).await?;
+
+        let msg = Message::create(&CreateMessage::new("deployed"), 1, bot.id as _, &pool).await?;
+        assert_eq!(msg.kind, MessageKind::Bot);
+        let msg = Message::create(&CreateMessage::new("thanks"), 1, 1, &pool).await?;
+        assert_eq!(msg.kind, MessageKind::User);
+        Ok(())
+    }
+}
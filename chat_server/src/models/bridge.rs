@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{utils::generate_token, AppError, BridgeIdentity, BridgeKind};
+
+const GHOST_EMAIL_TOKEN_BYTES: usize = 8;
+
+const SELECT_IDENTITY: &str = r#"
+    SELECT i.id, i.ws_id, i.bridge, i.remote_id, i.user_id, i.ghost, u.fullname, u.avatar_url,
+        i.created_at, i.updated_at
+    FROM bridge_identities i JOIN users u ON u.id = i.user_id
+"#;
+
+// who sent a bridged message, as seen by the bridge
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteIdentity {
+    pub bridge: BridgeKind,
+    // e.g. @alice:matrix.org, U024BE7LH or alice@example.com
+    pub remote_id: String,
+    pub display_name: String,
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+}
+
+impl RemoteIdentity {
+    fn normalized(&self) -> Result<Self, AppError> {
+        let remote_id = match self.bridge {
+            BridgeKind::Email => self.remote_id.trim().to_lowercase(),
+            _ => self.remote_id.trim().to_string(),
+        };
+        if remote_id.is_empty() || remote_id.len() > 255 {
+            return Err(AppError::InvalidInput("remote id must be 1 to 255 bytes".to_string()));
+        }
+        let display_name = self.display_name.trim().to_string();
+        if display_name.is_empty() || display_name.chars().count() > 64 {
+            return Err(AppError::InvalidInput("display name must be 1 to 64 characters".to_string()));
+        }
+        let avatar_url = self.avatar_url.as_deref().map(str::trim).filter(|url| !url.is_empty());
+        if let Some(url) = avatar_url
+            && (url.len() > 512 || !(url.starts_with("https://") || url.starts_with("http://")))
+        {
+            return Err(AppError::InvalidInput(format!("invalid avatar url: {}", url)));
+        }
+        Ok(Self {
+            bridge: self.bridge,
+            remote_id,
+            display_name,
+            avatar_url: avatar_url.map(str::to_string),
+        })
+    }
+}
+
+impl BridgeIdentity {
+    // the user to send the remote identity's messages as, created as a ghost user the first time
+    // it is seen. An email sender with an account in the workspace is mapped to that account.
+    pub async fn resolve(ws_id: u64, input: &RemoteIdentity, pool: &PgPool) -> Result<Self, AppError> {
+        let input = input.normalized()?;
+        if let Some(identity) = Self::sync(ws_id, &input, pool).await? {
+            return Ok(identity);
+        }
+
+        let mut tx = pool.begin().await?;
+        let member: Option<i64> = match input.bridge {
+            BridgeKind::Email => {
+                sqlx::query_scalar("SELECT id FROM users WHERE ws_id = $1 AND email = $2")
+                    .bind(ws_id as i64)
+                    .bind(&input.remote_id)
+                    .fetch_optional(&mut *tx)
+                    .await?
+            }
+            _ => None,
+        };
+        let user_id = match member {
+            Some(id) => id,
+            None => {
+                // ghosts have no password and an undeliverable email, so they can never sign in
+                let token = generate_token(GHOST_EMAIL_TOKEN_BYTES);
+                let email = format!("{}@{}.bridge.invalid", token, bridge_name(input.bridge));
+                sqlx::query_scalar(
+                    r#"
+                    INSERT INTO users (ws_id, email, fullname, avatar_url)
+                    VALUES ($1, $2, $3, $4)
+                    RETURNING id
+                    "#,
+                )
+                .bind(ws_id as i64)
+                .bind(&email)
+                .bind(&input.display_name)
+                .bind(&input.avatar_url)
+                .fetch_one(&mut *tx)
+                .await?
+            }
+        };
+        let id: Option<i64> = sqlx::query_scalar(
+            r#"
+            INSERT INTO bridge_identities (ws_id, bridge, remote_id, user_id, ghost)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (ws_id, bridge, remote_id) DO NOTHING
+            RETURNING id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(input.bridge)
+        .bind(&input.remote_id)
+        .bind(user_id)
+        .bind(member.is_none())
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(id) = id else {
+            // another message from the same sender got here first, drop the ghost made for it
+            tx.rollback().await?;
+            return Self::sync(ws_id, &input, pool)
+                .await?
+                .ok_or_else(|| AppError::NotFound(format!("bridge identity {}", input.remote_id)));
+        };
+        tx.commit().await?;
+        Self::find(ws_id, id as _, pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("bridge identity {}", id)))
+    }
+
+    pub async fn find(ws_id: u64, id: u64, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let identity = sqlx::query_as(&format!("{} WHERE i.ws_id = $1 AND i.id = $2", SELECT_IDENTITY))
+            .bind(ws_id as i64)
+            .bind(id as i64)
+            .fetch_optional(pool)
+            .await?;
+        Ok(identity)
+    }
+
+    pub async fn list(ws_id: u64, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let identities = sqlx::query_as(&format!("{} WHERE i.ws_id = $1 ORDER BY i.id", SELECT_IDENTITY))
+            .bind(ws_id as i64)
+            .fetch_all(pool)
+            .await?;
+        Ok(identities)
+    }
+
+    // the ghost user is kept so that messages already bridged keep their author
+    pub async fn delete(ws_id: u64, id: u64, pool: &PgPool) -> Result<(), AppError> {
+        let ret = sqlx::query("DELETE FROM bridge_identities WHERE ws_id = $1 AND id = $2")
+            .bind(ws_id as i64)
+            .bind(id as i64)
+            .execute(pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("bridge identity {}", id)));
+        }
+        Ok(())
+    }
+
+    // which of the users stand in for someone on the bridge
+    pub async fn bridged_users(ws_id: u64, bridge: BridgeKind, user_ids: &[i64], pool: &PgPool) -> Result<Vec<i64>, AppError> {
+        let ids = sqlx::query_scalar(
+            "SELECT user_id FROM bridge_identities WHERE ws_id = $1 AND bridge = $2 AND user_id = ANY($3)",
+        )
+        .bind(ws_id as i64)
+        .bind(bridge)
+        .bind(user_ids)
+        .fetch_all(pool)
+        .await?;
+        Ok(ids)
+    }
+
+    // an already mapped identity, with the ghost's name and avatar updated to the remote ones
+    async fn sync(ws_id: u64, input: &RemoteIdentity, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let identity: Option<Self> =
+            sqlx::query_as(&format!("{} WHERE i.ws_id = $1 AND i.bridge = $2 AND i.remote_id = $3", SELECT_IDENTITY))
+                .bind(ws_id as i64)
+                .bind(input.bridge)
+                .bind(&input.remote_id)
+                .fetch_optional(pool)
+                .await?;
+        let Some(mut identity) = identity else {
+            return Ok(None);
+        };
+        if identity.ghost && (identity.fullname != input.display_name || identity.avatar_url != input.avatar_url) {
+            let mut tx = pool.begin().await?;
+            sqlx::query("UPDATE users SET fullname = $2, avatar_url = $3 WHERE id = $1")
+                .bind(identity.user_id)
+                .bind(&input.display_name)
+                .bind(&input.avatar_url)
+                .execute(&mut *tx)
+                .await?;
+            identity.updated_at =
+                sqlx::query_scalar("UPDATE bridge_identities SET updated_at = NOW() WHERE id = $1 RETURNING updated_at")
+                    .bind(identity.id)
+                    .fetch_one(&mut *tx)
+                    .await?;
+            tx.commit().await?;
+            identity.fullname = input.display_name.clone();
+            identity.avatar_url = input.avatar_url.clone();
+        }
+        Ok(Some(identity))
+    }
+}
+
+fn bridge_name(bridge: BridgeKind) -> &'static str {
+    match bridge {
+        BridgeKind::Matrix => "matrix",
+        BridgeKind::Slack => "slack",
+        BridgeKind::Email => "email",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::PasswordHashing, test_util::get_test_pool, SigninUser, User, Workspace};
+    use anyhow::Result;
+
+    fn matrix(name: &str, avatar_url: Option<&str>) -> RemoteIdentity {
+        RemoteIdentity {
+            bridge: BridgeKind::Matrix,
+            remote_id: "@alice:matrix.org".to_string(),
+            display_name: name.to_string(),
+            avatar_url: avatar_url.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn remote_identity_should_map_to_one_ghost_user() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ghost = BridgeIdentity::resolve(1, &matrix("Alice", None), &pool).await?;
+        assert!(ghost.ghost);
+        assert_eq!(ghost.fullname, "Alice");
+
+        // renames on the remote side follow the same user
+        let renamed = BridgeIdentity::resolve(1, &matrix("Alice W.", Some("https://matrix.org/a.png")), &pool).await?;
+        assert_eq!(renamed.user_id, ghost.user_id);
+        assert_eq!(renamed.fullname, "Alice W.");
+        let users = Workspace::fetch_all_chat_users(1, &pool).await?;
+        let user = users.iter().find(|u| u.id == ghost.user_id).expect("ghost user should be listed");
+        assert_eq!(user.avatar_url.as_deref(), Some("https://matrix.org/a.png"));
+
+        // the same id in another workspace or on another bridge is someone else
+        let other = BridgeIdentity::resolve(2, &matrix("Alice", None), &pool).await?;
+        assert_ne!(other.user_id, ghost.user_id);
+        let slack = RemoteIdentity { bridge: BridgeKind::Slack, ..matrix("Alice", None) };
+        assert_ne!(BridgeIdentity::resolve(1, &slack, &pool).await?.user_id, ghost.user_id);
+        assert_eq!(BridgeIdentity::list(1, &pool).await?.len(), 2);
+
+        let user = User::find_by_email(&user.email, &pool).await?.expect("ghost user should exist");
+        assert!(User::verify(&SigninUser::new(&user.email, ""), &PasswordHashing::default(), &pool).await?.is_none());
+
+        BridgeIdentity::delete(1, ghost.id as _, &pool).await?;
+        assert!(matches!(BridgeIdentity::delete(1, ghost.id as _, &pool).await, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn email_sender_with_account_should_map_to_member() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = RemoteIdentity {
+            bridge: BridgeKind::Email,
+            remote_id: "TChen@acme.org".to_string(),
+            display_name: "Someone Else".to_string(),
+            avatar_url: None,
+        };
+        let identity = BridgeIdentity::resolve(1, &input, &pool).await?;
+        let member = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        assert_eq!(identity.user_id, member.id);
+        assert!(!identity.ghost);
+        // members keep their own name
+        assert_eq!(identity.fullname, member.fullname);
+
+        let bad = RemoteIdentity { avatar_url: Some("javascript:alert(1)".to_string()), ..input };
+        assert!(matches!(BridgeIdentity::resolve(1, &bad, &pool).await, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+}
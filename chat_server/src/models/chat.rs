@@ -1,19 +1,39 @@
-use std::ops::BitAnd;
-
 use serde::{Deserialize, Serialize};
-use sqlx::{pool, PgPool};
+use sqlx::{PgPool, Postgres, Transaction};
 
-use crate::{AppError, Chat, ChatType, ChatUser};
+use crate::{events::{record, AppEvent}, utils::nullable, AppError, Chat, ChatMemberAction, ChatMemberEvent, ChatSummary, ChatType, ChatUser, ChannelSummary, MarkChatRead, Webhook, WebhookEvent, Workspace};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateChat {
     pub name: Option<String>,
     pub members: Vec<i64>,
     pub public: bool,
+    // defaults to true for public channels, only channels can be discoverable
+    #[serde(default)]
+    pub discoverable: Option<bool>,
+}
+
+// a missing field is left as it is. Sent as a merge patch null clears the name, as plain json
+// null is the same as missing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateChat {
+    #[serde(default, deserialize_with = "nullable")]
+    pub name: Option<Option<String>>,
+    // the whole list, members not in it are removed
+    #[serde(default, deserialize_with = "nullable")]
+    pub members: Option<Option<Vec<i64>>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddChatMember {
+    pub user_id: i64,
 }
 
+const CHAT_NAME_INDEX: &str = "chats_ws_id_name_index";
+const MAX_CHAT_NAME_LEN: usize = 64;
+
 impl Chat {
-    pub async fn create(input: &CreateChat, ws_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+    pub async fn create(input: &CreateChat, ws_id: u64, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
         let len = input.members.len();
         if len < 2 {
             return Err(AppError::CreateChatError(
@@ -25,6 +45,12 @@ impl Chat {
                 "Group chat with more than 8 members must have a name".to_string()
             ))
         }
+        if let Some(name) = &input.name {
+            validate_name(name).map_err(AppError::CreateChatError)?;
+            if Self::name_exists(ws_id, name, pool).await? {
+                return Err(AppError::ChatNameTaken(name.clone()));
+            }
+        }
         let users = ChatUser::fetch_by_ids(&input.members, pool).await?;
         if users.len() != len {
             return Err(AppError::CreateChatError(
@@ -43,20 +69,45 @@ impl Chat {
                 }
             } 
         };
+        let discoverable = match chat_type {
+            ChatType::PublicChannel => input.discoverable.unwrap_or(true),
+            ChatType::PrivateChannel => input.discoverable.unwrap_or(false),
+            _ => false,
+        };
 
-        let chat = sqlx::query_as(
+        let mut tx = pool.begin().await?;
+        let chat: Chat = sqlx::query_as(
         r#"
-        INSERT INTO chats (ws_id, name, type, members)
-        VALUES ($1, $2, $3, $4)
-        RETURNING id, ws_id, name, type, members, created_at
+        INSERT INTO chats (ws_id, name, type, members, owner_id, discoverable)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, ws_id, name, type, members, owner_id, discoverable, created_at
         "#,
         )
         .bind(ws_id as i64)
         .bind(&input.name)
         .bind(chat_type)
         .bind(&input.members)
-        .fetch_one(pool)
+        .bind(user_id as i64)
+        .bind(discoverable)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| map_name_conflict(e, input.name.as_deref().unwrap_or_default()))?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO chat_member_history (chat_id, user_id, actor_id, action)
+            SELECT $1, unnest($2::bigint[]), $3, 'join'
+            "#,
+        )
+        .bind(chat.id)
+        .bind(&chat.members)
+        .bind(user_id as i64)
+        .execute(&mut *tx)
         .await?;
+        record(&mut tx, &AppEvent::ChatCreated { ws_id: chat.ws_id, chat_id: chat.id }).await?;
+        let payload = WebhookEvent::ChatCreated.payload(chat.ws_id, &chat);
+        Webhook::enqueue(&mut tx, chat.ws_id, Some(chat.id), WebhookEvent::ChatCreated, &payload).await?;
+        tx.commit().await?;
 
         Ok(chat)
     }
@@ -64,7 +115,7 @@ impl Chat {
     pub async fn fetch_all(ws_id: u64, pool: &PgPool) -> Result<Vec<Self>, AppError> {
         let chats = sqlx::query_as(
         r#"
-        SELECT id, ws_id, name, type, members, created_at
+        SELECT id, ws_id, name, type, members, owner_id, discoverable, created_at
         FROM chats
         WHERE ws_id = $1
         "#,
@@ -75,10 +126,88 @@ impl Chat {
         
         Ok(chats)
     }
+
+    // the caller's chats for the sidebar, most recently active first
+    pub async fn list_for_member(ws_id: u64, user_id: u64, pool: &PgPool) -> Result<Vec<ChatSummary>, AppError> {
+        let chats = sqlx::query_as(
+            r#"
+            SELECT c.id, c.name, c.type, c.member_count::bigint AS member_count, c.last_message_id,
+                c.last_message_at, c.last_message_preview, c.last_sender_id,
+                GREATEST(c.last_seq - COALESCE(s.last_read_seq, 0), 0) AS unread
+            FROM chats c
+            LEFT JOIN chat_member_settings s ON s.chat_id = c.id AND s.user_id = $2
+            WHERE c.members @> ARRAY[$2::bigint] AND c.ws_id = $1
+            ORDER BY c.last_message_at DESC NULLS LAST, c.id DESC
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(chats)
+    }
+
+    // changes whenever list_for_member would answer differently: a chat joined or left, renamed,
+    // with a new message or read further
+    pub async fn list_fingerprint(ws_id: u64, user_id: u64, pool: &PgPool) -> Result<String, AppError> {
+        let (count, updated_at, read): (i64, i64, i64) = sqlx::query_as(
+            r#"
+            SELECT COUNT(*), COALESCE((EXTRACT(EPOCH FROM MAX(c.updated_at)) * 1000000)::bigint, 0),
+                COALESCE(SUM(s.last_read_seq), 0)::bigint
+            FROM chats c
+            LEFT JOIN chat_member_settings s ON s.chat_id = c.id AND s.user_id = $2
+            WHERE c.members @> ARRAY[$2::bigint] AND c.ws_id = $1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_one(pool)
+        .await?;
+        Ok(format!("chats:{}:{}:{}:{}:{}", ws_id, user_id, count, updated_at, read))
+    }
+
+    // moves the read marker forward, never back and never past the newest message
+    pub async fn mark_read(&self, user_id: u64, input: &MarkChatRead, pool: &PgPool) -> Result<(), AppError> {
+        if !self.members.contains(&(user_id as i64)) {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} is not a member of chat {}",
+                user_id, self.id
+            )));
+        }
+        sqlx::query(
+            r#"
+            INSERT INTO chat_member_settings (chat_id, user_id, last_read_seq)
+            SELECT id, $2, LEAST($3, last_seq) FROM chats WHERE id = $1
+            ON CONFLICT (chat_id, user_id) DO UPDATE
+            SET last_read_seq = GREATEST(chat_member_settings.last_read_seq, EXCLUDED.last_read_seq)
+            "#,
+        )
+        .bind(self.id)
+        .bind(user_id as i64)
+        .bind(input.seq)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn start_typing(&self, user_id: u64, pool: &PgPool) -> Result<(), AppError> {
+        if !self.members.contains(&(user_id as i64)) {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} is not a member of chat {}",
+                user_id, self.id
+            )));
+        }
+        let mut tx = pool.begin().await?;
+        let event = AppEvent::TypingStarted { ws_id: self.ws_id, chat_id: self.id, user_id: user_id as i64 };
+        record(&mut tx, &event).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
     pub async fn get_by_id(id: u64, pool: &PgPool) -> Result<Option<Self>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, owner_id, discoverable, created_at
             FROM chats
             WHERE id=$1
             "#,
@@ -87,6 +216,436 @@ impl Chat {
         .await?;
         Ok(chat)
     }
+
+    pub async fn fetch_by_ids(ids: &[i64], pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let chats = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, members, owner_id, discoverable, created_at
+            FROM chats
+            WHERE id = ANY($1)
+            "#,
+        )
+        .bind(ids)
+        .fetch_all(pool)
+        .await?;
+        Ok(chats)
+    }
+
+    pub async fn name_exists(ws_id: u64, name: &str, pool: &PgPool) -> Result<bool, AppError> {
+        let exists = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS(SELECT 1 FROM chats WHERE ws_id = $1 AND lower(name) = lower($2))
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(name)
+        .fetch_one(pool)
+        .await?;
+        Ok(exists)
+    }
+
+    // renames the chat and records a system message in its timeline
+    pub async fn rename(&self, name: &str, actor_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        if !self.check_rename(name, actor_id, pool).await? {
+            return Ok(self.clone());
+        }
+        let mut tx = pool.begin().await?;
+        let chat = self.set_name(&mut tx, Some(name), actor_id).await?;
+        tx.commit().await?;
+        Ok(chat)
+    }
+
+    // only unnamed groups can be, channels keep their name and large groups need one
+    pub async fn clear_name(&self, actor_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        if !self.check_clear_name(actor_id, pool).await? {
+            return Ok(self.clone());
+        }
+        let mut tx = pool.begin().await?;
+        let chat = self.set_name(&mut tx, None, actor_id).await?;
+        tx.commit().await?;
+        Ok(chat)
+    }
+
+    // renames or clears the name when given, then adds and removes the members from
+    // member_changes, in one transaction so that either all of it or none of it is applied
+    pub async fn apply_update(
+        &self,
+        name: Option<Option<&str>>,
+        added: &[i64],
+        removed: &[i64],
+        actor_id: u64,
+        pool: &PgPool,
+    ) -> Result<Self, AppError> {
+        let name = match name {
+            Some(Some(name)) => self.check_rename(name, actor_id, pool).await?.then_some(Some(name)),
+            Some(None) => self.check_clear_name(actor_id, pool).await?.then_some(None),
+            None => None,
+        };
+        let mut tx = pool.begin().await?;
+        let mut chat = self.clone();
+        if let Some(name) = name {
+            chat = self.set_name(&mut tx, name, actor_id).await?;
+        }
+        // added first, the actor may be leaving after adding someone
+        for user_id in added {
+            chat = self.insert_member_in(&mut tx, *user_id as _, actor_id).await?;
+        }
+        for user_id in removed {
+            chat = self.delete_member_in(&mut tx, *user_id as _, actor_id).await?;
+        }
+        tx.commit().await?;
+        Ok(chat)
+    }
+
+    // whether renaming changes anything, failing when the name can't be used
+    async fn check_rename(&self, name: &str, actor_id: u64, pool: &PgPool) -> Result<bool, AppError> {
+        if self.r#type == ChatType::Single {
+            return Err(AppError::UpdateChatError(
+                "Cannot rename a single chat".to_string(),
+            ));
+        }
+        validate_name(name).map_err(AppError::UpdateChatError)?;
+        if !self.is_admin(actor_id, pool).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} cannot rename chat {}",
+                actor_id, self.id
+            )));
+        }
+        if self.name.as_deref() == Some(name) {
+            return Ok(false);
+        }
+        // allow changing only the case of the current name
+        let is_own_name = self.name.as_deref().is_some_and(|n| n.eq_ignore_ascii_case(name));
+        if !is_own_name && Self::name_exists(self.ws_id as _, name, pool).await? {
+            return Err(AppError::ChatNameTaken(name.to_string()));
+        }
+        Ok(true)
+    }
+
+    // whether clearing the name changes anything, failing when the chat needs one
+    async fn check_clear_name(&self, actor_id: u64, pool: &PgPool) -> Result<bool, AppError> {
+        if self.r#type != ChatType::Group || self.members.len() > 8 {
+            return Err(AppError::UpdateChatError(
+                "Only group chats of up to 8 members can be without a name".to_string(),
+            ));
+        }
+        if !self.is_admin(actor_id, pool).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} cannot rename chat {}",
+                actor_id, self.id
+            )));
+        }
+        Ok(self.name.is_some())
+    }
+
+    async fn set_name(&self, tx: &mut Transaction<'_, Postgres>, name: Option<&str>, actor_id: u64) -> Result<Self, AppError> {
+        let chat: Chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET name = $2, last_seq = last_seq + 1
+            WHERE id = $1
+            RETURNING id, ws_id, name, type, members, owner_id, discoverable, created_at
+            "#,
+        )
+        .bind(self.id)
+        .bind(name)
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(|e| map_name_conflict(e, name.unwrap_or_default()))?;
+
+        let content = match (&self.name, name) {
+            (Some(old), Some(name)) => format!("renamed the chat from \"{}\" to \"{}\"", old, name),
+            (None, Some(name)) => format!("named the chat \"{}\"", name),
+            (_, None) => "removed the chat name".to_string(),
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO messages (chat_id, sender_id, seq, kind, content, images)
+            SELECT id, $2, last_seq, 'system', $3, '{}' FROM chats WHERE id = $1
+            "#,
+        )
+        .bind(self.id)
+        .bind(actor_id as i64)
+        .bind(content)
+        .execute(&mut **tx)
+        .await?;
+        record(tx, &AppEvent::ChatUpdated { ws_id: chat.ws_id, chat_id: chat.id }).await?;
+        Ok(chat)
+    }
+
+    // who to add and who to remove for the chat to have these members, checked as add_member and
+    // remove_member would so nothing is changed when one of them would fail
+    pub async fn member_changes(&self, members: &[i64], actor_id: u64, pool: &PgPool) -> Result<(Vec<i64>, Vec<i64>), AppError> {
+        if self.r#type == ChatType::Single {
+            return Err(AppError::UpdateChatError(
+                "Cannot change members of a single chat".to_string(),
+            ));
+        }
+        if members.is_empty() {
+            return Err(AppError::UpdateChatError(
+                "Chat must keep at least one member".to_string(),
+            ));
+        }
+        let mut added: Vec<i64> = members.iter().filter(|id| !self.members.contains(id)).copied().collect();
+        added.sort_unstable();
+        added.dedup();
+        let removed: Vec<i64> = self.members.iter().filter(|id| !members.contains(id)).copied().collect();
+
+        let kicks = removed.iter().any(|id| *id != actor_id as i64);
+        let adds = !added.is_empty() && !self.members.contains(&(actor_id as i64));
+        if (kicks || adds) && !self.is_admin(actor_id, pool).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} cannot change the members of chat {}",
+                actor_id, self.id
+            )));
+        }
+        let found: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE id = ANY($1) AND ws_id = $2")
+            .bind(&added)
+            .bind(self.ws_id)
+            .fetch_one(pool)
+            .await?;
+        if found != added.len() as i64 {
+            return Err(AppError::UpdateChatError(
+                "Some members do not exist in this workspace".to_string(),
+            ));
+        }
+        Ok((added, removed))
+    }
+
+    pub async fn is_member(id: u64, user_id: u64, pool: &PgPool) -> Result<bool, AppError> {
+        let is_member = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS(SELECT 1 FROM chats WHERE id = $1 AND $2 = ANY(members))
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .fetch_one(pool)
+        .await?;
+        Ok(is_member)
+    }
+
+    // chat owner and workspace owner can manage members and view membership history
+    pub async fn is_admin(&self, user_id: u64, pool: &PgPool) -> Result<bool, AppError> {
+        if self.owner_id == user_id as i64 {
+            return Ok(true);
+        }
+        let ws = Workspace::find_by_id(self.ws_id as _, pool).await?;
+        Ok(ws.is_some_and(|ws| ws.owner_id == user_id as i64))
+    }
+
+    pub async fn add_member(&self, user_id: u64, actor_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        if self.r#type == ChatType::Single {
+            return Err(AppError::UpdateChatError(
+                "Cannot change members of a single chat".to_string(),
+            ));
+        }
+        if !self.members.contains(&(actor_id as i64)) && !self.is_admin(actor_id, pool).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} cannot add members to chat {}",
+                actor_id, self.id
+            )));
+        }
+
+        self.insert_member(user_id, actor_id, pool).await
+    }
+
+    // one click join for public channels, private ones need an invite or an admin
+    pub async fn join(&self, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        if self.r#type != ChatType::PublicChannel {
+            return Err(AppError::PermissionDenied(format!(
+                "chat {} is not a public channel",
+                self.id
+            )));
+        }
+        self.insert_member(user_id, user_id, pool).await
+    }
+
+    // ghosts of people on the other side of a bridge join when they first speak there
+    pub(crate) async fn add_bridged_member(&self, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        self.insert_member(user_id, user_id, pool).await
+    }
+
+    async fn insert_member(&self, user_id: u64, actor_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let mut tx = pool.begin().await?;
+        let chat = self.insert_member_in(&mut tx, user_id, actor_id).await?;
+        tx.commit().await?;
+        Ok(chat)
+    }
+
+    async fn insert_member_in(&self, tx: &mut Transaction<'_, Postgres>, user_id: u64, actor_id: u64) -> Result<Self, AppError> {
+        let chat: Option<Chat> = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = array_append(members, $2)
+            WHERE id = $1 AND NOT ($2 = ANY(members))
+                AND ws_id = (SELECT ws_id FROM users WHERE id = $2)
+            RETURNING id, ws_id, name, type, members, owner_id, discoverable, created_at
+            "#,
+        )
+        .bind(self.id)
+        .bind(user_id as i64)
+        .fetch_optional(&mut **tx)
+        .await?;
+        let Some(chat) = chat else {
+            return Err(AppError::UpdateChatError(format!(
+                "User {} is already a member or not in this workspace",
+                user_id
+            )));
+        };
+        record_member_action(tx, self.id, user_id, actor_id, ChatMemberAction::Join).await?;
+        // history from before joining doesn't count as unread
+        mark_all_read(tx, self.id, user_id as i64).await?;
+        record(tx, &AppEvent::ChatUpdated { ws_id: chat.ws_id, chat_id: chat.id }).await?;
+        let data = serde_json::json!({ "chat_id": chat.id, "user_id": user_id, "actor_id": actor_id });
+        let payload = WebhookEvent::MemberAdded.payload(chat.ws_id, data);
+        Webhook::enqueue(tx, chat.ws_id, Some(chat.id), WebhookEvent::MemberAdded, &payload).await?;
+        Ok(chat)
+    }
+
+    // discoverable channels in the workspace the user has not joined yet
+    pub async fn browse_channels(ws_id: u64, user_id: u64, pool: &PgPool) -> Result<Vec<ChannelSummary>, AppError> {
+        let channels = sqlx::query_as(
+            r#"
+            SELECT id, name, type, cardinality(members)::bigint AS member_count, created_at
+            FROM chats
+            WHERE ws_id = $1 AND discoverable AND NOT ($2 = ANY(members))
+            ORDER BY cardinality(members) DESC, id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(channels)
+    }
+
+    // leave when actor is the user, kick otherwise
+    pub async fn remove_member(&self, user_id: u64, actor_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        if self.r#type == ChatType::Single {
+            return Err(AppError::UpdateChatError(
+                "Cannot change members of a single chat".to_string(),
+            ));
+        }
+        let action = if user_id == actor_id {
+            ChatMemberAction::Leave
+        } else {
+            ChatMemberAction::Kick
+        };
+        if action == ChatMemberAction::Kick && !self.is_admin(actor_id, pool).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} cannot remove members from chat {}",
+                actor_id, self.id
+            )));
+        }
+
+        let mut tx = pool.begin().await?;
+        let chat = self.delete_member_in(&mut tx, user_id, actor_id).await?;
+        tx.commit().await?;
+        Ok(chat)
+    }
+
+    async fn delete_member_in(&self, tx: &mut Transaction<'_, Postgres>, user_id: u64, actor_id: u64) -> Result<Self, AppError> {
+        let action = if user_id == actor_id {
+            ChatMemberAction::Leave
+        } else {
+            ChatMemberAction::Kick
+        };
+        let chat: Option<Chat> = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = array_remove(members, $2)
+            WHERE id = $1 AND $2 = ANY(members)
+            RETURNING id, ws_id, name, type, members, owner_id, discoverable, created_at
+            "#,
+        )
+        .bind(self.id)
+        .bind(user_id as i64)
+        .fetch_optional(&mut **tx)
+        .await?;
+        let Some(chat) = chat else {
+            return Err(AppError::UpdateChatError(format!(
+                "User {} is not a member of chat {}",
+                user_id, self.id
+            )));
+        };
+        record_member_action(tx, self.id, user_id, actor_id, action).await?;
+        record(tx, &AppEvent::ChatUpdated { ws_id: chat.ws_id, chat_id: chat.id }).await?;
+        Ok(chat)
+    }
+
+    pub async fn fetch_member_history(&self, pool: &PgPool) -> Result<Vec<ChatMemberEvent>, AppError> {
+        let events = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, user_id, actor_id, action, created_at
+            FROM chat_member_history
+            WHERE chat_id = $1
+            ORDER BY created_at DESC, id DESC
+            "#,
+        )
+        .bind(self.id)
+        .fetch_all(pool)
+        .await?;
+        Ok(events)
+    }
+}
+
+pub(super) fn validate_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("Chat name cannot be empty".to_string());
+    }
+    if name.chars().count() > MAX_CHAT_NAME_LEN {
+        return Err(format!("Chat name cannot be longer than {} characters", MAX_CHAT_NAME_LEN));
+    }
+    Ok(())
+}
+
+// the unique index is the source of truth, the check before insert only gives a nicer error
+pub(super) fn map_name_conflict(e: sqlx::Error, name: &str) -> AppError {
+    match &e {
+        sqlx::Error::Database(db) if db.constraint() == Some(CHAT_NAME_INDEX) => {
+            AppError::ChatNameTaken(name.to_string())
+        }
+        _ => e.into(),
+    }
+}
+
+// sets the member's read marker to the chat's newest seq
+pub(super) async fn mark_all_read(tx: &mut Transaction<'_, Postgres>, chat_id: i64, user_id: i64) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        INSERT INTO chat_member_settings (chat_id, user_id, last_read_seq)
+        SELECT id, $2, last_seq FROM chats WHERE id = $1
+        ON CONFLICT (chat_id, user_id) DO UPDATE SET last_read_seq = EXCLUDED.last_read_seq
+        "#,
+    )
+    .bind(chat_id)
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+pub(super) async fn record_member_action(
+    tx: &mut Transaction<'_, Postgres>,
+    chat_id: i64,
+    user_id: u64,
+    actor_id: u64,
+    action: ChatMemberAction,
+) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        INSERT INTO chat_member_history (chat_id, user_id, actor_id, action)
+        VALUES ($1, $2, $3, $4)
+        "#,
+    )
+    .bind(chat_id)
+    .bind(user_id as i64)
+    .bind(actor_id as i64)
+    .bind(action)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -101,21 +660,20 @@ impl CreateChat {
             name,
             members: members.to_vec(),
             public,
+            discoverable: None,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use sqlx::pool;
-
-    use crate::{models::chat::CreateChat, test_util::get_test_pool, Chat, ChatType};
+    use crate::{events::AppEvent, models::chat::{CreateChat, MarkChatRead}, test_util::get_test_pool, AppError, Chat, ChatMemberAction, ChatType, CreateMessage, ListMessages, Message, MessageKind};
 
     #[tokio::test]
     async fn create_single_chat_should_work() {
         let (_tdb, pool) = get_test_pool(None).await;
         let input = CreateChat::new("", &[1, 2], false);
-        let chat = Chat::create(&input, 1, &pool).await.expect("create chat failed");
+        let chat = Chat::create(&input, 1, 1, &pool).await.expect("create chat failed");
         assert_eq!(chat.ws_id, 1);
         assert_eq!(chat.members.len(), 2);
         assert_eq!(chat.r#type, ChatType::Single);
@@ -123,8 +681,8 @@ mod tests {
     #[tokio::test]
     async fn create_public_named_chat_should_work() {
         let (_tdb, pool) = get_test_pool(None).await;
-        let input = CreateChat::new("general", &[1, 2, 3], true);
-        let chat = Chat::create(&input, 1, &pool).await.expect("create chat failed");
+        let input = CreateChat::new("random", &[1, 2, 3], true);
+        let chat = Chat::create(&input, 1, 1, &pool).await.expect("create chat failed");
         assert_eq!(chat.ws_id, 1);
         assert_eq!(chat.members.len(), 3);
         assert_eq!(chat.r#type, ChatType::PublicChannel);
@@ -147,4 +705,178 @@ mod tests {
 
         assert_eq!(chats.len(), 4);
     }
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn chat_is_member_should_work() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        assert!(Chat::is_member(2, 3, &pool).await.expect("is member failed"));
+        assert!(!Chat::is_member(2, 4, &pool).await.expect("is member failed"));
+    }
+    #[tokio::test]
+    async fn chat_member_changes_should_be_recorded() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateChat::new("ops", &[1, 2], false);
+        let chat = Chat::create(&input, 1, 1, &pool).await.expect("create chat failed");
+        let chat = chat.add_member(3, 2, &pool).await.expect("add member failed");
+        assert_eq!(chat.members, vec![1, 2, 3]);
+        let chat = chat.remove_member(3, 3, &pool).await.expect("leave failed");
+        let chat = chat.remove_member(2, 1, &pool).await.expect("kick failed");
+        assert_eq!(chat.members, vec![1]);
+
+        let history = chat.fetch_member_history(&pool).await.expect("fetch history failed");
+        let actions: Vec<_> = history.iter().map(|e| (e.user_id, e.actor_id, e.action)).collect();
+        assert_eq!(
+            actions,
+            vec![
+                (2, 1, ChatMemberAction::Kick),
+                (3, 3, ChatMemberAction::Leave),
+                (3, 2, ChatMemberAction::Join),
+                (2, 1, ChatMemberAction::Join),
+                (1, 1, ChatMemberAction::Join),
+            ]
+        );
+    }
+    #[tokio::test]
+    async fn browse_and_join_channels_should_work() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let mut input = CreateChat::new("secret", &[1, 2], false);
+        input.discoverable = Some(true);
+        let secret = Chat::create(&input, 1, 1, &pool).await.expect("create chat failed");
+        let hidden = Chat::create(&CreateChat::new("hidden", &[1, 2], false), 1, 1, &pool)
+            .await
+            .expect("create chat failed");
+        assert!(!hidden.discoverable);
+
+        let channels = Chat::browse_channels(1, 4, &pool).await.expect("browse failed");
+        let ids: Vec<_> = channels.iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![secret.id]);
+        assert_eq!(channels[0].member_count, 2);
+
+        let ret = secret.join(4, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let public = Chat::create(&CreateChat::new("random", &[1, 2], true), 1, 1, &pool)
+            .await
+            .expect("create chat failed");
+        let chat = public.join(4, &pool).await.expect("join failed");
+        assert!(chat.members.contains(&4));
+        let channels = Chat::browse_channels(1, 4, &pool).await.expect("browse failed");
+        assert!(channels.iter().all(|c| c.id != public.id));
+    }
+    #[tokio::test]
+    async fn create_chat_with_taken_name_should_fail() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateChat::new("General", &[1, 2], true);
+        let ret = Chat::create(&input, 1, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::ChatNameTaken(_))));
+        // names are only unique within a workspace
+        let input = CreateChat::new("general", &[1, 2], true);
+        Chat::create(&input, 2, 1, &pool).await.expect("create chat failed");
+    }
+    #[tokio::test]
+    async fn rename_chat_should_work() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let chat = Chat::get_by_id(1, &pool).await.expect("get chat failed").unwrap();
+        let ret = chat.rename("private", 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::ChatNameTaken(_))));
+        let ret = chat.rename("announcements", 2, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let chat = chat.rename("announcements", 1, &pool).await.expect("rename failed");
+        assert_eq!(chat.name.as_deref(), Some("announcements"));
+        let messages = Message::list(&ListMessages::default(), 1, &pool).await.expect("list failed");
+        assert_eq!(messages[0].seq, 11);
+        assert_eq!(messages[0].kind, MessageKind::System);
+        assert_eq!(messages[0].content, "renamed the chat from \"general\" to \"announcements\"");
+
+        let single = Chat::get_by_id(3, &pool).await.expect("get chat failed").unwrap();
+        let ret = single.rename("dm", 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+    }
+    #[tokio::test]
+    async fn chat_kick_by_non_admin_should_fail() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let chat = Chat::get_by_id(2, &pool).await.expect("get chat failed").unwrap();
+        let ret = chat.remove_member(3, 2, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+    }
+    #[tokio::test]
+    async fn chat_list_should_track_last_message_and_unread() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let chats = Chat::list_for_member(1, 2, &pool).await.expect("list chats failed");
+        assert_eq!(chats.len(), 3);
+        assert_eq!(chats[0].id, 1);
+        assert_eq!(chats[0].member_count, 5);
+        assert_eq!(chats[0].last_message_preview.as_deref(), Some("Hello, world!"));
+        assert_eq!(chats[0].unread, 10);
+
+        let chat = Chat::get_by_id(1, &pool).await.expect("get chat failed").unwrap();
+        chat.mark_read(2, &MarkChatRead { seq: 7 }, &pool).await.expect("mark read failed");
+        // the marker doesn't move back
+        chat.mark_read(2, &MarkChatRead { seq: 5 }, &pool).await.expect("mark read failed");
+        let chats = Chat::list_for_member(1, 2, &pool).await.expect("list chats failed");
+        assert_eq!(chats[0].unread, 3);
+        let ret = chat.mark_read(6, &MarkChatRead { seq: 1 }, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let message = Message::create(&CreateMessage::new("hi all"), 1, 3, &pool).await.expect("create message failed");
+        let chats = Chat::list_for_member(1, 3, &pool).await.expect("list chats failed");
+        assert_eq!(chats[0].last_message_id, Some(message.id));
+        assert_eq!(chats[0].last_sender_id, Some(3));
+        assert_eq!(chats[0].unread, 0);
+        let chats = Chat::list_for_member(1, 2, &pool).await.expect("list chats failed");
+        assert_eq!(chats[0].unread, 4);
+    }
+
+    #[tokio::test]
+    async fn start_typing_should_queue_an_event_for_members() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let chat = Chat::get_by_id(1, &pool).await.expect("get chat failed").unwrap();
+        chat.start_typing(2, &pool).await.expect("start typing failed");
+        let ret = chat.start_typing(6, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let event: sqlx::types::Json<AppEvent> = sqlx::query_scalar("SELECT event FROM event_outbox ORDER BY id DESC LIMIT 1")
+            .fetch_one(&pool)
+            .await
+            .expect("read outbox failed");
+        assert_eq!(event.0, AppEvent::TypingStarted { ws_id: 1, chat_id: 1, user_id: 2 });
+    }
+
+    #[tokio::test]
+    async fn patching_a_group_should_clear_its_name_and_diff_members() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let group = Chat::get_by_id(4, &pool).await.expect("get chat failed").unwrap();
+        let group = group.rename("team", 1, &pool).await.expect("rename failed");
+        let group = group.clear_name(1, &pool).await.expect("clear name failed");
+        assert_eq!(group.name, None);
+        let channel = Chat::get_by_id(1, &pool).await.expect("get chat failed").unwrap();
+        assert!(matches!(channel.clear_name(1, &pool).await, Err(AppError::UpdateChatError(_))));
+
+        let (added, removed) = group.member_changes(&[1, 3, 5, 5], 1, &pool).await.expect("diff failed");
+        assert_eq!((added, removed), (vec![5], vec![4]));
+        // leaving is fine, removing someone else needs an admin
+        let (added, removed) = group.member_changes(&[1, 4], 3, &pool).await.expect("diff failed");
+        assert_eq!((added, removed), (vec![], vec![3]));
+        let ret = group.member_changes(&[1, 3], 3, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = group.member_changes(&[], 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        let ret = group.member_changes(&[1, 3, 4, 999], 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+    }
+
+    #[tokio::test]
+    async fn chat_update_should_apply_all_or_nothing() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let group = Chat::get_by_id(4, &pool).await.expect("get chat failed").unwrap();
+        // 2 is not a member, so removing them fails after the rename and the add
+        let ret = group.apply_update(Some(Some("team")), &[5], &[2], 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        let unchanged = Chat::get_by_id(4, &pool).await.expect("get chat failed").unwrap();
+        assert_eq!((unchanged.name, unchanged.members), (group.name.clone(), group.members.clone()));
+
+        let chat = group.apply_update(Some(Some("team")), &[5], &[4], 1, &pool).await.expect("update failed");
+        assert_eq!(chat.name.as_deref(), Some("team"));
+        assert_eq!(chat.members, vec![1, 3, 5]);
+    }
+}
@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{AppError, Chat, ChatDetail, ChatRole, NotificationLevel};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateChatSettings {
+    #[serde(default)]
+    pub notification_level: Option<NotificationLevel>,
+}
+
+impl Chat {
+    pub async fn role_of(&self, user_id: u64, pool: &PgPool) -> Result<ChatRole, AppError> {
+        let role = if self.owner_id == user_id as i64 {
+            ChatRole::Owner
+        } else if self.is_admin(user_id, pool).await? {
+            ChatRole::Admin
+        } else if self.members.contains(&(user_id as i64)) {
+            ChatRole::Member
+        } else {
+            ChatRole::NonMember
+        };
+        Ok(role)
+    }
+
+    pub async fn notification_level(&self, user_id: u64, pool: &PgPool) -> Result<NotificationLevel, AppError> {
+        let level: Option<NotificationLevel> = sqlx::query_scalar(
+            "SELECT notification_level FROM chat_member_settings WHERE chat_id = $1 AND user_id = $2",
+        )
+        .bind(self.id)
+        .bind(user_id as i64)
+        .fetch_optional(pool)
+        .await?;
+        Ok(level.unwrap_or_default())
+    }
+
+    pub async fn update_settings(&self, user_id: u64, input: &UpdateChatSettings, pool: &PgPool) -> Result<NotificationLevel, AppError> {
+        if !self.members.contains(&(user_id as i64)) {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} is not a member of chat {}",
+                user_id, self.id
+            )));
+        }
+        let Some(level) = input.notification_level else {
+            return self.notification_level(user_id, pool).await;
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO chat_member_settings (chat_id, user_id, notification_level)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (chat_id, user_id) DO UPDATE SET notification_level = EXCLUDED.notification_level, updated_at = NOW()
+            "#,
+        )
+        .bind(self.id)
+        .bind(user_id as i64)
+        .bind(level)
+        .execute(pool)
+        .await?;
+        Ok(level)
+    }
+
+    // everything a chat view needs for the caller in one response
+    pub async fn detail(self, user_id: u64, pool: &PgPool) -> Result<ChatDetail, AppError> {
+        let role = self.role_of(user_id, pool).await?;
+        let notification_level = self.notification_level(user_id, pool).await?;
+        let pinned = self.pinned_messages(pool).await?;
+        Ok(ChatDetail {
+            member_count: self.members.len() as i64,
+            chat: self,
+            role,
+            notification_level,
+            pinned,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn chat_detail_should_reflect_caller() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        // private channel owned by user 1, with members 1, 2 and 3
+        let chat = Chat::get_by_id(2, &pool).await?.expect("chat should exist");
+        let detail = chat.clone().detail(1, &pool).await?;
+        assert_eq!(detail.role, ChatRole::Owner);
+        assert_eq!(detail.member_count, 3);
+        assert_eq!(detail.notification_level, NotificationLevel::All);
+        assert_eq!(chat.role_of(2, &pool).await?, ChatRole::Member);
+        assert_eq!(chat.role_of(4, &pool).await?, ChatRole::NonMember);
+
+        let input = UpdateChatSettings { notification_level: Some(NotificationLevel::Mentions) };
+        chat.update_settings(2, &input, &pool).await?;
+        assert_eq!(chat.clone().detail(2, &pool).await?.notification_level, NotificationLevel::Mentions);
+        assert_eq!(chat.notification_level(1, &pool).await?, NotificationLevel::All);
+        let ret = chat.update_settings(4, &input, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+}
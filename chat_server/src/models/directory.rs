@@ -0,0 +1,99 @@
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{AppError, MemberDirectoryEntry, Workspace};
+
+pub const DIRECTORY_COLUMNS: &[&str] = &["name", "email", "role", "last_active_at", "two_factor_enabled"];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExportMembers {
+    // comma separated subset of DIRECTORY_COLUMNS, all of them when not set
+    #[serde(default)]
+    pub columns: Option<String>,
+}
+
+impl ExportMembers {
+    pub fn columns(&self) -> Result<Vec<&str>, AppError> {
+        let Some(columns) = self.columns.as_deref().filter(|c| !c.trim().is_empty()) else {
+            return Ok(DIRECTORY_COLUMNS.to_vec());
+        };
+        columns
+            .split(',')
+            .map(str::trim)
+            .map(|c| {
+                DIRECTORY_COLUMNS
+                    .iter()
+                    .find(|name| **name == c)
+                    .copied()
+                    .ok_or_else(|| AppError::InvalidInput(format!("unknown column: {}", c)))
+            })
+            .collect()
+    }
+}
+
+impl MemberDirectoryEntry {
+    pub fn column(&self, name: &str) -> String {
+        match name {
+            "name" => self.fullname.clone(),
+            "email" => self.email.clone(),
+            "role" => self.role.clone(),
+            "last_active_at" => self.last_active_at.map(|t| t.to_rfc3339()).unwrap_or_default(),
+            "two_factor_enabled" => self.two_factor_enabled.to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+impl Workspace {
+    // rows are streamed, a workspace can have far more members than we want to buffer
+    pub fn stream_member_directory(ws_id: u64, pool: &PgPool) -> BoxStream<'_, Result<MemberDirectoryEntry, sqlx::Error>> {
+        sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email,
+                CASE WHEN w.owner_id = u.id THEN 'owner' ELSE u.role::text END AS role,
+                GREATEST(
+                    (SELECT MAX(created_at) FROM messages WHERE sender_id = u.id),
+                    (SELECT MAX(last_seen_at) FROM user_devices WHERE user_id = u.id)
+                ) AS last_active_at,
+                EXISTS(SELECT 1 FROM user_totp WHERE user_id = u.id AND enabled_at IS NOT NULL) AS two_factor_enabled
+            FROM users u
+            JOIN workspaces w ON w.id = u.ws_id
+            WHERE u.ws_id = $1
+            ORDER BY u.id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch(pool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+    use futures::TryStreamExt;
+
+    #[tokio::test]
+    async fn member_directory_should_stream_all_members() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let entries: Vec<_> = Workspace::stream_member_directory(1, &pool).try_collect().await?;
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[0].column("email"), "tchen@acme.org");
+        // user 1 sent messages in the fixture, user 5 too
+        assert!(entries[0].last_active_at.is_some());
+        assert_eq!(entries[0].column("two_factor_enabled"), "false");
+        Ok(())
+    }
+
+    #[test]
+    fn export_columns_should_be_validated() {
+        let input = ExportMembers::default();
+        assert_eq!(input.columns().unwrap(), DIRECTORY_COLUMNS);
+        let input = ExportMembers { columns: Some("email, role".to_string()) };
+        assert_eq!(input.columns().unwrap(), vec!["email", "role"]);
+        let input = ExportMembers { columns: Some("password_hash".to_string()) };
+        assert!(matches!(input.columns(), Err(AppError::InvalidInput(_))));
+    }
+}
@@ -0,0 +1,202 @@
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{utils::{generate_token, TokenId}, AppError, User};
+
+use super::user::{check_email, verify_password};
+
+const EMAIL_CHANGE_TOKEN_BYTES: usize = 32;
+const EMAIL_CHANGE_TTL_HOURS: i64 = 24;
+// how long after signing in a change goes through without the password
+const REAUTH_WINDOW_MINUTES: i64 = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeEmail {
+    pub email: String,
+    // the current password, not needed right after signing in
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfirmEmailChange {
+    pub token: String,
+}
+
+impl User {
+    // replaces any change still pending, the returned token goes to the new address. current is
+    // the access token of the request.
+    pub async fn request_email_change(
+        &self,
+        input: &ChangeEmail,
+        current: Option<&TokenId>,
+        pool: &PgPool,
+    ) -> Result<String, AppError> {
+        self.confirm_identity(input.password.as_deref(), current, pool).await?;
+        let email = input.email.trim();
+        check_email(email)?;
+        if email.eq_ignore_ascii_case(&self.email) {
+            return Err(AppError::InvalidInput("that is already your email".to_string()));
+        }
+        if Self::find_by_email(email, pool).await?.is_some() {
+            return Err(AppError::EmailAlreadyExists(email.to_string()));
+        }
+        let token = generate_token(EMAIL_CHANGE_TOKEN_BYTES);
+        sqlx::query(
+            r#"
+            INSERT INTO email_changes (user_id, new_email, token_hash, expires_at)
+            VALUES ($1, $2, sha256(convert_to($3, 'UTF8')), $4)
+            ON CONFLICT (user_id) DO UPDATE
+            SET new_email = EXCLUDED.new_email, token_hash = EXCLUDED.token_hash,
+                expires_at = EXCLUDED.expires_at, created_at = NOW()
+            "#,
+        )
+        .bind(self.id)
+        .bind(email)
+        .bind(&token)
+        .bind(Utc::now() + Duration::hours(EMAIL_CHANGE_TTL_HOURS))
+        .execute(pool)
+        .await?;
+        Ok(token)
+    }
+
+    // switches to the confirmed address, which counts as verified as the token arrived there, and
+    // signs the user out everywhere. Returns the user with the new email and the address they had
+    // before.
+    pub async fn confirm_email_change(input: &ConfirmEmailChange, pool: &PgPool) -> Result<(User, String), AppError> {
+        let mut tx = pool.begin().await?;
+        let change: Option<(i64, String, String)> = sqlx::query_as(
+            r#"
+            DELETE FROM email_changes c
+            USING users u
+            WHERE c.user_id = u.id AND c.token_hash = sha256(convert_to($1, 'UTF8')) AND c.expires_at > NOW()
+            RETURNING c.user_id, c.new_email, u.email
+            "#,
+        )
+        .bind(&input.token)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((user_id, new_email, old_email)) = change else {
+            return Err(AppError::NotFound("email change token is invalid or has expired".to_string()));
+        };
+        // the address may have been taken since the change was requested
+        let taken: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM users WHERE email = $1)")
+            .bind(&new_email)
+            .fetch_one(&mut *tx)
+            .await?;
+        if taken {
+            return Err(AppError::EmailAlreadyExists(new_email));
+        }
+        let user: User = sqlx::query_as(
+            r#"
+            UPDATE users SET email = $2, email_verified_at = NOW()
+            WHERE id = $1
+            RETURNING id, ws_id, fullname, email, created_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(&new_email)
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok((user, old_email))
+    }
+
+    // a leaked access token alone must not be enough to take the account over: the caller gives
+    // the current password, or signed in on this session a moment ago, e.g. users of a social
+    // login who have no password
+    async fn confirm_identity(&self, password: Option<&str>, current: Option<&TokenId>, pool: &PgPool) -> Result<(), AppError> {
+        if let Some(password) = password {
+            let password_hash: Option<String> = sqlx::query_scalar("SELECT password_hash FROM users WHERE id = $1")
+                .bind(self.id)
+                .fetch_one(pool)
+                .await?;
+            return match password_hash {
+                Some(password_hash) if verify_password(password, &password_hash)? => Ok(()),
+                _ => Err(AppError::PermissionDenied("wrong password".to_string())),
+            };
+        }
+        if let Some(current) = current {
+            let recent: bool = sqlx::query_scalar(
+                "SELECT EXISTS(SELECT 1 FROM user_sessions WHERE user_id = $1 AND access_jti = $2 AND created_at > $3)",
+            )
+            .bind(self.id)
+            .bind(&current.jti)
+            .bind(Utc::now() - Duration::minutes(REAUTH_WINDOW_MINUTES))
+            .fetch_one(pool)
+            .await?;
+            if recent {
+                return Ok(());
+            }
+        }
+        Err(AppError::PermissionDenied(
+            "enter your current password, or sign in again, to change your email".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, ClientInfo, RefreshToken};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn email_change_should_switch_after_confirmation() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let change = |email: &str| ChangeEmail { email: email.to_string(), password: Some("123456".to_string()) };
+        let ret = user.request_email_change(&change("alice@acme.org"), None, &pool).await;
+        assert!(matches!(ret, Err(AppError::EmailAlreadyExists(_))));
+
+        let input = change("tyr@acme.org");
+        let first = user.request_email_change(&input, None, &pool).await?;
+        let token = user.request_email_change(&input, None, &pool).await?;
+        // nothing changes until the new address is confirmed
+        assert!(User::find_by_email("tyr@acme.org", &pool).await?.is_none());
+        let ret = User::confirm_email_change(&ConfirmEmailChange { token: first }, &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        let refresh_token = RefreshToken::issue(&user, false, &ClientInfo::default(), &TokenId::generate(), &pool).await?;
+        let (changed, old_email) = User::confirm_email_change(&ConfirmEmailChange { token: token.clone() }, &pool).await?;
+        assert_eq!(changed.email, "tyr@acme.org");
+        assert_eq!(old_email, "tchen@acme.org");
+        assert!(changed.is_email_verified(&pool).await?);
+        // the sessions from before the change are signed out
+        let ret = RefreshToken::rotate(&refresh_token, &TokenId::generate(), &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = User::confirm_email_change(&ConfirmEmailChange { token }, &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn email_change_should_need_password_or_recent_signin() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let mut input = ChangeEmail { email: "tyr@acme.org".to_string(), password: None };
+        let ret = user.request_email_change(&input, Some(&TokenId::generate()), &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        input.password = Some("nope".to_string());
+        let ret = user.request_email_change(&input, None, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        // the access token of a session that just started is enough
+        input.password = None;
+        let access = TokenId::generate();
+        RefreshToken::issue(&user, false, &ClientInfo::default(), &access, &pool).await?;
+        user.request_email_change(&input, Some(&access), &pool).await?;
+        sqlx::query("UPDATE user_sessions SET created_at = NOW() - INTERVAL '1 hour' WHERE access_jti = $1")
+            .bind(&access.jti)
+            .execute(&pool)
+            .await?;
+        let ret = user.request_email_change(&input, Some(&access), &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+
+use crate::{AppError, Message};
+
+// a claimed notification with what is needed to write the email
+#[derive(Debug, Clone, FromRow)]
+pub(crate) struct DueNotification {
+    pub message_id: i64,
+    pub user_id: i64,
+    pub email: String,
+    pub fullname: String,
+    pub ws_id: i64,
+    pub chat_id: i64,
+    pub chat_name: Option<String>,
+    // a single chat, the message is a direct message rather than a mention
+    pub direct: bool,
+    pub sender_name: String,
+    pub content: String,
+}
+
+// queues the members mentioned in the message, and the other member of a single chat.
+// Called in the transaction inserting the message, after its mentions.
+pub(super) async fn queue(tx: &mut Transaction<'_, Postgres>, message: &Message) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        INSERT INTO email_notifications (message_id, user_id)
+        SELECT $1, user_id FROM message_mentions WHERE message_id = $1
+        UNION
+        SELECT $1, u FROM chats c, unnest(c.members) AS u
+        WHERE c.id = $2 AND c.type = 'single' AND u <> $3
+        ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(message.id)
+    .bind(message.chat_id)
+    .bind(message.sender_id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+impl DueNotification {
+    // takes the notifications queued at least delay_secs ago out of the queue. Those read by
+    // then, muted, or for members who left or were deactivated are dropped rather than returned.
+    pub(crate) async fn claim(limit: usize, delay_secs: u64, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let due = sqlx::query_as(
+            r#"
+            WITH due AS (
+                DELETE FROM email_notifications
+                WHERE (message_id, user_id) IN (
+                    SELECT message_id, user_id FROM email_notifications
+                    WHERE created_at <= now() - make_interval(secs => $2)
+                    ORDER BY created_at
+                    LIMIT $1
+                    FOR UPDATE SKIP LOCKED
+                )
+                RETURNING message_id, user_id
+            )
+            SELECT m.id AS message_id, u.id AS user_id, u.email, u.fullname, c.ws_id, c.id AS chat_id,
+                c.name AS chat_name, c.type = 'single' AS direct, COALESCE(m.display_name, s.fullname) AS sender_name,
+                CASE WHEN m.chunks = 0 THEN m.content
+                ELSE m.content || (
+                    SELECT string_agg(mc.content, '' ORDER BY mc.idx)
+                    FROM message_chunks mc
+                    WHERE mc.message_id = m.id
+                ) END AS content
+            FROM due d
+            JOIN messages m ON m.id = d.message_id
+            JOIN chats c ON c.id = m.chat_id
+            JOIN users s ON s.id = m.sender_id
+            JOIN users u ON u.id = d.user_id
+            LEFT JOIN chat_member_settings cs ON cs.chat_id = c.id AND cs.user_id = u.id
+            WHERE u.deactivated_at IS NULL AND u.id = ANY(c.members)
+                AND NOT EXISTS(SELECT 1 FROM bots b WHERE b.user_id = u.id)
+                AND COALESCE(cs.last_read_seq, 0) < m.seq
+                AND COALESCE(cs.notification_level, 'all') <> 'none'
+            ORDER BY m.id
+            "#,
+        )
+        .bind(limit as i64)
+        .bind(delay_secs as f64)
+        .fetch_all(pool)
+        .await?;
+        Ok(due)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, CreateMessage, MarkChatRead};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn mentions_should_be_claimed_unless_read() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let message = Message::create(&CreateMessage::new("hi <@2> and <@3>"), 1, 1, &pool).await?;
+        assert!(DueNotification::claim(10, 60, &pool).await?.is_empty());
+
+        let chat = crate::Chat::get_by_id(1, &pool).await?.expect("chat");
+        chat.mark_read(3, &MarkChatRead { seq: message.seq }, &pool).await?;
+        let due = DueNotification::claim(10, 0, &pool).await?;
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].user_id, 2);
+        assert_eq!(due[0].content, "hi <@2> and <@3>");
+        assert!(!due[0].direct);
+        // claimed once
+        assert!(DueNotification::claim(10, 0, &pool).await?.is_empty());
+        Ok(())
+    }
+}
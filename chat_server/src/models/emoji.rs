@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{AppError, CustomEmoji};
+
+const MAX_EMOJI_NAME_LEN: usize = 32;
+const MAX_CUSTOM_EMOJI_PER_WORKSPACE: i64 = 500;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateCustomEmoji {
+    // used in reactions as :name:
+    pub name: String,
+    pub image_url: String,
+}
+
+impl CustomEmoji {
+    pub async fn create(ws_id: u64, input: &CreateCustomEmoji, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let name = input.name.trim().trim_matches(':').to_lowercase();
+        if !is_valid_emoji_name(&name) {
+            return Err(AppError::InvalidInput(format!(
+                "emoji names are 1 to {} of a-z, 0-9, _, + and -",
+                MAX_EMOJI_NAME_LEN
+            )));
+        }
+        let image_url = input.image_url.trim();
+        if !(image_url.starts_with("https://") || image_url.starts_with("http://")) || image_url.len() > 1024 {
+            return Err(AppError::InvalidInput(format!("invalid emoji image url: {}", image_url)));
+        }
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM workspace_emoji WHERE ws_id = $1")
+            .bind(ws_id as i64)
+            .fetch_one(pool)
+            .await?;
+        if count >= MAX_CUSTOM_EMOJI_PER_WORKSPACE {
+            return Err(AppError::InvalidInput(format!(
+                "a workspace can have at most {} custom emoji",
+                MAX_CUSTOM_EMOJI_PER_WORKSPACE
+            )));
+        }
+
+        let emoji: Option<Self> = sqlx::query_as(
+            r#"
+            INSERT INTO workspace_emoji (ws_id, name, image_url, created_by)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (ws_id, name) DO NOTHING
+            RETURNING id, ws_id, name, image_url, created_by, created_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(&name)
+        .bind(image_url)
+        .bind(user_id as i64)
+        .fetch_optional(pool)
+        .await?;
+        emoji.ok_or_else(|| AppError::InvalidInput(format!("emoji already exists: {}", name)))
+    }
+
+    pub async fn list(ws_id: u64, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let emoji = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, image_url, created_by, created_at
+            FROM workspace_emoji
+            WHERE ws_id = $1
+            ORDER BY name
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(emoji)
+    }
+
+    pub async fn find(ws_id: u64, name: &str, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let emoji = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, image_url, created_by, created_at
+            FROM workspace_emoji
+            WHERE ws_id = $1 AND name = $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(name.trim_matches(':'))
+        .fetch_optional(pool)
+        .await?;
+        Ok(emoji)
+    }
+
+    // reactions with it stay, shown by name without an image
+    pub async fn delete(&self, pool: &PgPool) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM workspace_emoji WHERE id = $1")
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+}
+
+pub(crate) fn is_valid_emoji_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_EMOJI_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | '+' | '-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn custom_emoji_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateCustomEmoji {
+            name: ":Party-Parrot:".to_string(),
+            image_url: "https://example.com/parrot.gif".to_string(),
+        };
+        let emoji = CustomEmoji::create(1, &input, 1, &pool).await?;
+        assert_eq!(emoji.name, "party-parrot");
+        assert!(CustomEmoji::create(1, &input, 2, &pool).await.is_err());
+        // names are per workspace
+        CustomEmoji::create(2, &input, 1, &pool).await?;
+
+        let bad = CreateCustomEmoji { name: "no spaces".to_string(), image_url: input.image_url.clone() };
+        assert!(matches!(CustomEmoji::create(1, &bad, 1, &pool).await, Err(AppError::InvalidInput(_))));
+
+        assert_eq!(CustomEmoji::list(1, &pool).await?.len(), 1);
+        let found = CustomEmoji::find(1, ":party-parrot:", &pool).await?.expect("emoji should exist");
+        found.delete(&pool).await?;
+        assert!(CustomEmoji::list(1, &pool).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+use crate::{
+    utils::{constant_time_eq, generate_token, hex_encode},
+    AppError, Chat, ChatType,
+};
+
+const FEED_KEY_BYTES: usize = 32;
+// hex characters of the HMAC kept in feed tokens
+const FEED_SIGNATURE_LEN: usize = 32;
+const FEED_ENTRIES: i64 = 50;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateChatFeed {
+    // anyone can read the feed, only for public channels
+    pub public: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReadFeed {
+    // needed unless the feed is public
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+// a top level message as a feed entry
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct FeedEntry {
+    pub id: i64,
+    pub author: String,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Chat {
+    pub async fn has_public_feed(&self, pool: &PgPool) -> Result<bool, AppError> {
+        let public = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM chat_feeds WHERE chat_id = $1)")
+            .bind(self.id)
+            .fetch_one(pool)
+            .await?;
+        Ok(public)
+    }
+
+    pub async fn set_public_feed(&self, input: &UpdateChatFeed, actor_id: u64, pool: &PgPool) -> Result<(), AppError> {
+        if input.public && self.r#type != ChatType::PublicChannel {
+            return Err(AppError::UpdateChatError(
+                "only public channels can have a public feed".to_string(),
+            ));
+        }
+        if !self.is_admin(actor_id, pool).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} cannot change the feed of chat {}",
+                actor_id, self.id
+            )));
+        }
+        if input.public {
+            sqlx::query("INSERT INTO chat_feeds (chat_id, enabled_by) VALUES ($1, $2) ON CONFLICT (chat_id) DO NOTHING")
+                .bind(self.id)
+                .bind(actor_id as i64)
+                .execute(pool)
+                .await?;
+        } else {
+            sqlx::query("DELETE FROM chat_feeds WHERE chat_id = $1")
+                .bind(self.id)
+                .execute(pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // <user id>.<signature>, only good for this chat and while the user is a member
+    pub async fn feed_token(&self, user_id: u64, pool: &PgPool) -> Result<String, AppError> {
+        if !self.members.contains(&(user_id as i64)) {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} is not a member of chat {}",
+                user_id, self.id
+            )));
+        }
+        // the row is created on first use, a concurrent first use keeps the one inserted first
+        let secret: String = sqlx::query_scalar(
+            r#"
+            WITH created AS (
+                INSERT INTO feed_keys (user_id, secret) VALUES ($1, $2)
+                ON CONFLICT (user_id) DO NOTHING
+                RETURNING secret
+            )
+            SELECT secret FROM created
+            UNION ALL
+            SELECT secret FROM feed_keys WHERE user_id = $1
+            LIMIT 1
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(generate_token(FEED_KEY_BYTES))
+        .fetch_one(pool)
+        .await?;
+        Ok(format!("{}.{}", user_id, feed_signature(&secret, self.id)))
+    }
+
+    // the member a feed token was issued to, None if it is invalid or revoked or they left
+    pub async fn verify_feed_token(&self, token: &str, pool: &PgPool) -> Result<Option<i64>, AppError> {
+        let Some((user_id, sig)) = token.split_once('.') else {
+            return Ok(None);
+        };
+        let Ok(user_id) = user_id.parse::<i64>() else {
+            return Ok(None);
+        };
+        if !self.members.contains(&user_id) {
+            return Ok(None);
+        }
+        let secret: Option<String> = sqlx::query_scalar(
+            r#"
+            SELECT k.secret FROM feed_keys k JOIN users u ON u.id = k.user_id
+            WHERE k.user_id = $1 AND u.deactivated_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+        let valid = secret.is_some_and(|s| constant_time_eq(sig.as_bytes(), feed_signature(&s, self.id).as_bytes()));
+        Ok(valid.then_some(user_id))
+    }
+
+    // the newest top level messages, newest first
+    pub async fn feed_entries(&self, pool: &PgPool) -> Result<Vec<FeedEntry>, AppError> {
+        let entries = sqlx::query_as(
+            r#"
+            SELECT m.id, COALESCE(m.display_name, u.fullname) AS author, m.created_at,
+                CASE WHEN m.chunks = 0 THEN m.content
+                ELSE m.content || (
+                    SELECT string_agg(c.content, '' ORDER BY c.idx)
+                    FROM message_chunks c
+                    WHERE c.message_id = m.id
+                ) END AS content
+            FROM messages m JOIN users u ON u.id = m.sender_id
+            WHERE m.chat_id = $1 AND m.thread_id IS NULL
+            ORDER BY m.seq DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(self.id)
+        .bind(FEED_ENTRIES)
+        .fetch_all(pool)
+        .await?;
+        Ok(entries)
+    }
+}
+
+// revokes every feed token of the user, new ones are signed with a new key
+pub async fn revoke_feed_tokens(user_id: u64, pool: &PgPool) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM feed_keys WHERE user_id = $1")
+        .bind(user_id as i64)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+fn feed_signature(secret: &str, chat_id: i64) -> String {
+    let mut sig = hex_encode(&hmac_sha256::HMAC::mac(chat_id.to_string().as_bytes(), secret.as_bytes()));
+    sig.truncate(FEED_SIGNATURE_LEN);
+    sig
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn feed_token_should_only_work_for_its_chat() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let chat = Chat::get_by_id(1, &pool).await?.expect("chat");
+        let other = Chat::get_by_id(2, &pool).await?.expect("chat");
+        let token = chat.feed_token(1, &pool).await?;
+        assert_eq!(chat.feed_token(1, &pool).await?, token);
+        assert_eq!(chat.verify_feed_token(&token, &pool).await?, Some(1));
+        assert_eq!(other.verify_feed_token(&token, &pool).await?, None);
+        assert_eq!(chat.verify_feed_token("1.forged", &pool).await?, None);
+
+        revoke_feed_tokens(1, &pool).await?;
+        assert_eq!(chat.verify_feed_token(&token, &pool).await?, None);
+        assert_ne!(chat.feed_token(1, &pool).await?, token);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn public_feed_should_only_be_for_public_channels() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let chat = Chat::get_by_id(1, &pool).await?.expect("chat");
+        chat.set_public_feed(&UpdateChatFeed { public: true }, 1, &pool).await?;
+        assert!(chat.has_public_feed(&pool).await?);
+        chat.set_public_feed(&UpdateChatFeed { public: false }, 1, &pool).await?;
+        assert!(!chat.has_public_feed(&pool).await?);
+
+        let private = Chat::get_by_id(2, &pool).await?.expect("chat");
+        let ret = private.set_public_feed(&UpdateChatFeed { public: true }, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        Ok(())
+    }
+}
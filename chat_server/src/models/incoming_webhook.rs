@@ -0,0 +1,254 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{utils::generate_token, AppError, CreateMessage, IncomingWebhook, Message};
+
+use super::message::IntegrationSender;
+
+const INCOMING_WEBHOOK_TOKEN_BYTES: usize = 32;
+const MAX_INCOMING_WEBHOOKS_PER_CHAT: i64 = 20;
+const MAX_NAME_CHARS: usize = 64;
+const MAX_ICON_URL_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateIncomingWebhook {
+    // shown as the sender of its messages unless a message says otherwise
+    pub name: String,
+}
+
+// what CI systems and the like post, the fields follow what most of them already send
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IncomingWebhookMessage {
+    pub text: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default, alias = "icon")]
+    pub icon_url: Option<String>,
+}
+
+impl IncomingWebhook {
+    // the token is only returned here, it is the secret part of the url
+    pub async fn create(chat_id: u64, input: &CreateIncomingWebhook, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let name = validate_name(&input.name)?;
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM incoming_webhooks WHERE chat_id = $1")
+            .bind(chat_id as i64)
+            .fetch_one(pool)
+            .await?;
+        if count >= MAX_INCOMING_WEBHOOKS_PER_CHAT {
+            return Err(AppError::InvalidInput(format!(
+                "a chat can have at most {} incoming webhooks",
+                MAX_INCOMING_WEBHOOKS_PER_CHAT
+            )));
+        }
+
+        let token = generate_token(INCOMING_WEBHOOK_TOKEN_BYTES);
+        let mut webhook: Self = sqlx::query_as(
+            r#"
+            INSERT INTO incoming_webhooks (chat_id, name, token_hash, created_by)
+            VALUES ($1, $2, sha256(convert_to($3, 'UTF8')), $4)
+            RETURNING id, chat_id, name, created_by, created_at, last_used_at, disabled_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(name)
+        .bind(&token)
+        .bind(user_id as i64)
+        .fetch_one(pool)
+        .await?;
+        webhook.token = Some(token);
+        Ok(webhook)
+    }
+
+    pub async fn list(chat_id: u64, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let webhooks = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, name, created_by, created_at, last_used_at, disabled_at
+            FROM incoming_webhooks
+            WHERE chat_id = $1
+            ORDER BY id
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(webhooks)
+    }
+
+    pub async fn delete(chat_id: u64, id: u64, pool: &PgPool) -> Result<(), AppError> {
+        let ret = sqlx::query("DELETE FROM incoming_webhooks WHERE id = $1 AND chat_id = $2")
+            .bind(id as i64)
+            .bind(chat_id as i64)
+            .execute(pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("incoming webhook {}", id)));
+        }
+        Ok(())
+    }
+
+    // the webhook the url token belongs to, marked as used
+    pub async fn find_by_token(token: &str, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let webhook = sqlx::query_as(
+            r#"
+            UPDATE incoming_webhooks SET last_used_at = NOW()
+            WHERE token_hash = sha256(convert_to($1, 'UTF8'))
+            RETURNING id, chat_id, name, created_by, created_at, last_used_at, disabled_at
+            "#,
+        )
+        .bind(token)
+        .fetch_optional(pool)
+        .await?;
+        Ok(webhook)
+    }
+
+    // posted as the user who created the webhook, shown as the webhook or the name the payload gives
+    pub async fn post(&self, input: &IncomingWebhookMessage, pool: &PgPool) -> Result<Message, AppError> {
+        self.ensure_enabled(pool).await?;
+        let display_name = match &input.username {
+            Some(username) => validate_name(username)?,
+            None => self.name.clone(),
+        };
+        let icon_url = input.icon_url.as_deref().map(str::trim).filter(|url| !url.is_empty());
+        if let Some(url) = icon_url
+            && (url.len() > MAX_ICON_URL_LEN || !(url.starts_with("https://") || url.starts_with("http://")))
+        {
+            return Err(AppError::InvalidInput(format!("invalid icon url: {}", url)));
+        }
+        let sender = IntegrationSender {
+            display_name,
+            icon_url: icon_url.map(str::to_string),
+        };
+        let input = CreateMessage {
+            content: input.text.clone(),
+            images: vec![],
+            client_created_at: None,
+            thread_id: None,
+            external_id: None,
+        };
+        Message::create_integration(&input, self.chat_id as _, self.created_by as _, &sender, pool).await
+    }
+
+    // the creator must still be an active member of the chat, otherwise the webhook is turned off
+    // for good: whoever holds the url could keep posting as someone who has left
+    async fn ensure_enabled(&self, pool: &PgPool) -> Result<(), AppError> {
+        if self.disabled_at.is_none() {
+            let allowed: bool = sqlx::query_scalar(
+                r#"
+                SELECT EXISTS(
+                    SELECT 1 FROM chats c JOIN users u ON u.ws_id = c.ws_id
+                    WHERE c.id = $1 AND u.id = $2 AND u.id = ANY(c.members) AND u.deactivated_at IS NULL
+                )
+                "#,
+            )
+            .bind(self.chat_id)
+            .bind(self.created_by)
+            .fetch_one(pool)
+            .await?;
+            if allowed {
+                return Ok(());
+            }
+            sqlx::query("UPDATE incoming_webhooks SET disabled_at = NOW() WHERE id = $1 AND disabled_at IS NULL")
+                .bind(self.id)
+                .execute(pool)
+                .await?;
+        }
+        Err(AppError::PermissionDenied(format!(
+            "incoming webhook {} is disabled, its creator can no longer post in the chat",
+            self.id
+        )))
+    }
+}
+
+fn validate_name(name: &str) -> Result<String, AppError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+        return Err(AppError::InvalidInput(format!(
+            "name must be 1 to {} characters",
+            MAX_NAME_CHARS
+        )));
+    }
+    Ok(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, MessageKind};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn incoming_webhook_should_post_integration_messages() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateIncomingWebhook { name: "CI".to_string() };
+        let webhook = IncomingWebhook::create(2, &input, 3, &pool).await?;
+        let token = webhook.token.clone().expect("token");
+
+        let found = IncomingWebhook::find_by_token(&token, &pool).await?.expect("webhook");
+        assert!(found.token.is_none());
+        assert!(found.last_used_at.is_some());
+        assert!(IncomingWebhook::find_by_token("nope", &pool).await?.is_none());
+
+        let input = IncomingWebhookMessage {
+            text: "build #42 passed".to_string(),
+            username: None,
+            icon_url: Some("https://ci.acme.org/icon.png".to_string()),
+        };
+        let msg = found.post(&input, &pool).await?;
+        assert_eq!((msg.chat_id, msg.sender_id, msg.kind), (2, 3, MessageKind::Integration));
+        assert_eq!(msg.display_name.as_deref(), Some("CI"));
+        assert_eq!(msg.icon_url.as_deref(), Some("https://ci.acme.org/icon.png"));
+
+        IncomingWebhook::delete(2, webhook.id as _, &pool).await?;
+        assert!(IncomingWebhook::find_by_token(&token, &pool).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incoming_webhook_should_be_disabled_once_its_creator_cannot_post() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateIncomingWebhook { name: "CI".to_string() };
+        let left = IncomingWebhook::create(2, &input, 3, &pool).await?;
+        let deactivated = IncomingWebhook::create(1, &input, 4, &pool).await?;
+        let message = IncomingWebhookMessage { text: "build #42 passed".to_string(), username: None, icon_url: None };
+        let post = |webhook: &IncomingWebhook| {
+            let token = webhook.token.clone().expect("token");
+            let pool = pool.clone();
+            let message = message.clone();
+            async move {
+                let webhook = IncomingWebhook::find_by_token(&token, &pool).await?.expect("webhook");
+                webhook.post(&message, &pool).await
+            }
+        };
+        post(&left).await?;
+        post(&deactivated).await?;
+
+        sqlx::query("UPDATE chats SET members = array_remove(members, 3) WHERE id = 2").execute(&pool).await?;
+        sqlx::query("UPDATE users SET deactivated_at = NOW() WHERE id = 4").execute(&pool).await?;
+        assert!(matches!(post(&left).await, Err(AppError::PermissionDenied(_))));
+        assert!(matches!(post(&deactivated).await, Err(AppError::PermissionDenied(_))));
+
+        // coming back doesn't turn the webhook on again
+        sqlx::query("UPDATE chats SET members = array_append(members, 3) WHERE id = 2").execute(&pool).await?;
+        assert!(matches!(post(&left).await, Err(AppError::PermissionDenied(_))));
+        let webhooks = IncomingWebhook::list(2, &pool).await?;
+        assert!(webhooks[0].disabled_at.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incoming_webhook_should_reject_bad_payloads() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateIncomingWebhook { name: " ".to_string() };
+        assert!(matches!(IncomingWebhook::create(1, &input, 1, &pool).await, Err(AppError::InvalidInput(_))));
+
+        let input = CreateIncomingWebhook { name: "CI".to_string() };
+        let webhook = IncomingWebhook::create(1, &input, 1, &pool).await?;
+        let input = IncomingWebhookMessage {
+            text: "hi".to_string(),
+            username: None,
+            icon_url: Some("javascript:alert(1)".to_string()),
+        };
+        assert!(matches!(webhook.post(&input, &pool).await, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+}
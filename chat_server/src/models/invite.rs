@@ -0,0 +1,163 @@
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{utils::generate_token, AppError, Chat, ChatInvite, ChatMemberAction, ChatType, User};
+
+use super::chat::record_member_action;
+
+const INVITE_TOKEN_BYTES: usize = 16;
+const MAX_INVITE_EXPIRY_SECS: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreateChatInvite {
+    // link never expires when not set, at most MAX_INVITE_EXPIRY_SECS otherwise
+    #[serde(default)]
+    pub expires_in_secs: Option<u64>,
+    // unlimited when not set
+    #[serde(default)]
+    pub max_uses: Option<i32>,
+}
+
+impl ChatInvite {
+    pub async fn create(input: &CreateChatInvite, chat: &Chat, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        if chat.r#type == ChatType::Single {
+            return Err(AppError::UpdateChatError(
+                "Cannot create invite links for a single chat".to_string(),
+            ));
+        }
+        if !chat.members.contains(&(user_id as i64)) && !chat.is_admin(user_id, pool).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} cannot create invite links for chat {}",
+                user_id, chat.id
+            )));
+        }
+        if input.max_uses.is_some_and(|n| n <= 0) {
+            return Err(AppError::UpdateChatError(
+                "max_uses must be positive".to_string(),
+            ));
+        }
+        if input.expires_in_secs.is_some_and(|secs| secs > MAX_INVITE_EXPIRY_SECS) {
+            return Err(AppError::UpdateChatError(format!(
+                "expires_in_secs cannot be more than {}",
+                MAX_INVITE_EXPIRY_SECS
+            )));
+        }
+        let expires_at = input
+            .expires_in_secs
+            .map(|secs| Utc::now() + Duration::seconds(secs as i64));
+
+        let invite = sqlx::query_as(
+            r#"
+            INSERT INTO chat_invites (chat_id, token, created_by, expires_at, max_uses)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, chat_id, token, created_by, expires_at, max_uses, uses, created_at
+            "#,
+        )
+        .bind(chat.id)
+        .bind(generate_token(INVITE_TOKEN_BYTES))
+        .bind(user_id as i64)
+        .bind(expires_at)
+        .bind(input.max_uses)
+        .fetch_one(pool)
+        .await?;
+        Ok(invite)
+    }
+
+    // consumes one use of the invite and adds the user to the chat
+    pub async fn redeem(token: &str, user: &User, pool: &PgPool) -> Result<Chat, AppError> {
+        let mut tx = pool.begin().await?;
+        let chat_id: Option<i64> = sqlx::query_scalar(
+            r#"
+            UPDATE chat_invites
+            SET uses = uses + 1
+            WHERE token = $1
+                AND (expires_at IS NULL OR expires_at > NOW())
+                AND (max_uses IS NULL OR uses < max_uses)
+            RETURNING chat_id
+            "#,
+        )
+        .bind(token)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(chat_id) = chat_id else {
+            return Err(AppError::NotFound(
+                "invite link not found or expired".to_string(),
+            ));
+        };
+
+        let chat: Option<Chat> = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = array_append(members, $2)
+            WHERE id = $1 AND ws_id = $3 AND NOT ($2 = ANY(members))
+            RETURNING id, ws_id, name, type, members, owner_id, discoverable, created_at
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user.id)
+        .bind(user.ws_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(chat) = chat else {
+            return Err(AppError::UpdateChatError(
+                "Already a member or chat is in another workspace".to_string(),
+            ));
+        };
+        record_member_action(&mut tx, chat.id, user.id as _, user.id as _, ChatMemberAction::Join).await?;
+        tx.commit().await?;
+
+        Ok(chat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn invite_should_add_member_until_max_uses() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let chat = Chat::get_by_id(2, &pool).await?.expect("chat 2 should exist");
+        let input = CreateChatInvite {
+            expires_in_secs: Some(3600),
+            max_uses: Some(1),
+        };
+        let invite = ChatInvite::create(&input, &chat, 2, &pool).await?;
+        assert_eq!(invite.uses, 0);
+
+        let mut user = User::new(4, "Charlie Chen", "charlie@acme.org");
+        user.ws_id = 1;
+        let chat = ChatInvite::redeem(&invite.token, &user, &pool).await?;
+        assert!(chat.members.contains(&4));
+
+        let mut user = User::new(5, "Daisy Chen", "daisy@acme.org");
+        user.ws_id = 1;
+        let ret = ChatInvite::redeem(&invite.token, &user, &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invite_with_too_long_expiry_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let chat = Chat::get_by_id(2, &pool).await?.expect("chat 2 should exist");
+        for secs in [MAX_INVITE_EXPIRY_SECS + 1, i64::MAX as u64 + 1, u64::MAX] {
+            let input = CreateChatInvite { expires_in_secs: Some(secs), max_uses: None };
+            let ret = ChatInvite::create(&input, &chat, 2, &pool).await;
+            assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invite_for_single_chat_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let chat = Chat::get_by_id(3, &pool).await?.expect("chat 3 should exist");
+        let ret = ChatInvite::create(&CreateChatInvite::default(), &chat, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{utils::Cidr, AppError, IpAllowlist};
+
+const MAX_CIDRS: usize = 100;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateIpAllowlist {
+    // replaces the current list, an empty list allows any address
+    pub cidrs: Vec<String>,
+}
+
+impl IpAllowlist {
+    pub async fn fetch(ws_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let allowlist: Option<Self> = sqlx::query_as("SELECT ip_allowlist AS cidrs FROM workspaces WHERE id = $1")
+            .bind(ws_id as i64)
+            .fetch_optional(pool)
+            .await?;
+        allowlist.ok_or_else(|| AppError::NotFound(format!("workspace not found: {}", ws_id)))
+    }
+
+    // `client_ip` is where the change is made from, it has to stay allowed so that admins
+    // can't lock themselves out
+    pub async fn update(
+        ws_id: u64,
+        input: &UpdateIpAllowlist,
+        client_ip: Option<&str>,
+        pool: &PgPool,
+    ) -> Result<Self, AppError> {
+        let mut cidrs = input
+            .cidrs
+            .iter()
+            .filter(|c| !c.trim().is_empty())
+            .map(|c| c.parse::<Cidr>().map(|c| c.to_string()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(AppError::UpdateWorkspaceError)?;
+        cidrs.sort();
+        cidrs.dedup();
+        if cidrs.len() > MAX_CIDRS {
+            return Err(AppError::UpdateWorkspaceError(format!(
+                "At most {} address ranges can be allowed",
+                MAX_CIDRS
+            )));
+        }
+        let allowlist = Self { cidrs };
+        if !allowlist.allows(client_ip) {
+            return Err(AppError::UpdateWorkspaceError(format!(
+                "The allowlist must include the address it is changed from: {}",
+                client_ip.unwrap_or("unknown")
+            )));
+        }
+
+        sqlx::query("UPDATE workspaces SET ip_allowlist = $2 WHERE id = $1")
+            .bind(ws_id as i64)
+            .bind(&allowlist.cidrs)
+            .execute(pool)
+            .await?;
+        Ok(allowlist)
+    }
+
+    // unknown or unparsable addresses are only allowed when there is no allowlist
+    pub fn allows(&self, ip: Option<&str>) -> bool {
+        if self.cidrs.is_empty() {
+            return true;
+        }
+        let Some(ip) = ip.and_then(|ip| ip.parse::<IpAddr>().ok()) else {
+            return false;
+        };
+        self.cidrs
+            .iter()
+            .filter_map(|c| c.parse::<Cidr>().ok())
+            .any(|c| c.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn ip_allowlist_should_update() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let allowlist = IpAllowlist::fetch(1, &pool).await?;
+        assert_eq!(allowlist, IpAllowlist::default());
+        assert!(allowlist.allows(None));
+
+        let input = UpdateIpAllowlist {
+            cidrs: ["10.1.2.3/8", " ", "10.0.0.0/8", "2001:db8::/32"].map(str::to_string).to_vec(),
+        };
+        let allowlist = IpAllowlist::update(1, &input, Some("10.0.0.1"), &pool).await?;
+        assert_eq!(allowlist.cidrs, vec!["10.0.0.0/8", "2001:db8::/32"]);
+        assert_eq!(IpAllowlist::fetch(1, &pool).await?, allowlist);
+        assert!(allowlist.allows(Some("10.20.30.40")));
+        assert!(allowlist.allows(Some("2001:db8::7")));
+        assert!(!allowlist.allows(Some("192.168.0.1")));
+        assert!(!allowlist.allows(Some("not an ip")));
+        assert!(!allowlist.allows(None));
+        // other workspaces are not affected
+        assert!(IpAllowlist::fetch(2, &pool).await?.allows(Some("192.168.0.1")));
+
+        let input = UpdateIpAllowlist { cidrs: vec!["10.0.0.0/8".to_string()] };
+        let ret = IpAllowlist::update(1, &input, Some("192.168.0.1"), &pool).await;
+        assert!(matches!(ret, Err(AppError::UpdateWorkspaceError(_))));
+        let input = UpdateIpAllowlist { cidrs: vec!["10.0.0.0/40".to_string()] };
+        let ret = IpAllowlist::update(1, &input, Some("10.0.0.1"), &pool).await;
+        assert!(matches!(ret, Err(AppError::UpdateWorkspaceError(_))));
+
+        // clearing the list allows any address again
+        let allowlist = IpAllowlist::update(1, &UpdateIpAllowlist::default(), None, &pool).await?;
+        assert!(allowlist.allows(Some("192.168.0.1")));
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use sqlx::PgPool;
+
+use crate::{ldap::LdapIdentity, AppError, User};
+
+const MAX_NAME_LEN: usize = 64;
+
+impl User {
+    // the member with the directory user's email, created at their first signin. The
+    // directory vouches for the email.
+    pub(crate) async fn from_ldap_identity(
+        ws_id: u64,
+        identity: &LdapIdentity,
+        pool: &PgPool,
+    ) -> Result<Self, AppError> {
+        if let Some(user) = Self::find_by_email(&identity.email, pool).await? {
+            if user.ws_id != ws_id as i64 {
+                return Err(AppError::PermissionDenied(format!(
+                    "{} belongs to another workspace",
+                    identity.email
+                )));
+            }
+            return Ok(user);
+        }
+        if identity.email.len() > MAX_NAME_LEN {
+            return Err(AppError::InvalidInput(format!("email is too long: {}", identity.email)));
+        }
+        let fullname: String = identity
+            .name
+            .as_deref()
+            .filter(|n| !n.trim().is_empty())
+            .unwrap_or_else(|| identity.email.split('@').next().unwrap_or_default())
+            .chars()
+            .take(MAX_NAME_LEN)
+            .collect();
+        let user = sqlx::query_as(
+            r#"
+            INSERT INTO users (ws_id, email, fullname, email_verified_at)
+            VALUES ($1, $2, $3, NOW())
+            RETURNING id, ws_id, fullname, email, created_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(&identity.email)
+        .bind(&fullname)
+        .fetch_one(pool)
+        .await?;
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn ldap_identity_should_create_user_once() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let identity = LdapIdentity { email: "eve@ldap.acme.org".to_string(), name: Some("Eve Ldap".to_string()) };
+        let user = User::from_ldap_identity(1, &identity, &pool).await?;
+        assert_eq!((user.ws_id, user.fullname.as_str()), (1, "Eve Ldap"));
+        assert!(user.is_email_verified(&pool).await?);
+        assert_eq!(User::from_ldap_identity(1, &identity, &pool).await?.id, user.id);
+
+        let ret = User::from_ldap_identity(2, &identity, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+}
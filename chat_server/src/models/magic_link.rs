@@ -0,0 +1,160 @@
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{utils::generate_token, AppError, User};
+
+const MAGIC_LINK_TOKEN_BYTES: usize = 32;
+const MAGIC_LINK_TTL_MINUTES: i64 = 15;
+// requests within this window don't send another email
+const RESEND_INTERVAL_SECS: i64 = 60;
+// at most this many emails per address, and from one ip across addresses, within the limit window
+const MAX_LINKS_PER_EMAIL: i64 = 5;
+const MAX_LINKS_PER_IP: i64 = 20;
+const LIMIT_WINDOW_SECS: i64 = 60 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestMagicLink {
+    pub email: String,
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MagicLinkSignin {
+    pub token: String,
+}
+
+impl User {
+    // returns None when a link was sent moments ago, or the email or ip used up their limit.
+    // Either way nothing is sent, so the endpoint can't be used to flood an inbox.
+    pub async fn create_magic_link(&self, remember_me: bool, ip: Option<&str>, pool: &PgPool) -> Result<Option<String>, AppError> {
+        let token = generate_token(MAGIC_LINK_TOKEN_BYTES);
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO magic_links (user_id, token_hash, remember_me, expires_at, requested_ip)
+            SELECT $1, sha256(convert_to($2, 'UTF8')), $3, $4, $5
+            WHERE NOT EXISTS(
+                SELECT 1 FROM magic_links
+                WHERE user_id = $1 AND created_at > NOW() - make_interval(secs => $6)
+            )
+            AND (
+                SELECT COUNT(*) FROM magic_links
+                WHERE user_id = $1 AND created_at > NOW() - make_interval(secs => $7)
+            ) < $8
+            AND (
+                $5::varchar IS NULL OR (
+                    SELECT COUNT(*) FROM magic_links
+                    WHERE requested_ip = $5 AND created_at > NOW() - make_interval(secs => $7)
+                ) < $9
+            )
+            "#,
+        )
+        .bind(self.id)
+        .bind(&token)
+        .bind(remember_me)
+        .bind(Utc::now() + Duration::minutes(MAGIC_LINK_TTL_MINUTES))
+        .bind(ip)
+        .bind(RESEND_INTERVAL_SECS as f64)
+        .bind(LIMIT_WINDOW_SECS as f64)
+        .bind(MAX_LINKS_PER_EMAIL)
+        .bind(MAX_LINKS_PER_IP)
+        .execute(pool)
+        .await?;
+        Ok((ret.rows_affected() > 0).then_some(token))
+    }
+
+    // returns the user and whether they asked to be remembered. Every link of the user is used
+    // up, and since the link arrived by email, the email counts as verified.
+    pub async fn redeem_magic_link(input: &MagicLinkSignin, pool: &PgPool) -> Result<(User, bool), AppError> {
+        let mut tx = pool.begin().await?;
+        let link: Option<(i64, bool)> = sqlx::query_as(
+            r#"
+            UPDATE magic_links SET used_at = NOW()
+            WHERE token_hash = sha256(convert_to($1, 'UTF8')) AND expires_at > NOW() AND used_at IS NULL
+            RETURNING user_id, remember_me
+            "#,
+        )
+        .bind(&input.token)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((user_id, remember_me)) = link else {
+            return Err(AppError::PermissionDenied("magic link is invalid, expired or already used".to_string()));
+        };
+        sqlx::query("UPDATE magic_links SET used_at = NOW() WHERE user_id = $1 AND used_at IS NULL")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        let user: User = sqlx::query_as(
+            r#"
+            UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW())
+            WHERE id = $1
+            RETURNING id, ws_id, fullname, email, created_at
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok((user, remember_me))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn magic_link_should_work_once() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let token = user.create_magic_link(true, None, &pool).await?.expect("magic link");
+        // no second email right away
+        assert!(user.create_magic_link(false, None, &pool).await?.is_none());
+
+        let input = MagicLinkSignin { token };
+        let (signed_in, remember_me) = User::redeem_magic_link(&input, &pool).await?;
+        assert_eq!(signed_in.id, user.id);
+        assert!(remember_me);
+        let ret = User::redeem_magic_link(&input, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let input = MagicLinkSignin { token: "bogus".to_string() };
+        assert!(User::redeem_magic_link(&input, &pool).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn magic_links_should_be_limited_per_email_and_ip() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let tchen = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let alice = User::find_by_email("alice@acme.org", &pool).await?.expect("user should exist");
+        // past the resend interval, but within the limit window
+        let backdate = || sqlx::query("UPDATE magic_links SET created_at = NOW() - interval '2 minutes'").execute(&pool);
+        for _ in 0..MAX_LINKS_PER_EMAIL {
+            assert!(tchen.create_magic_link(false, None, &pool).await?.is_some());
+            backdate().await?;
+        }
+        assert!(tchen.create_magic_link(false, Some("10.0.0.1"), &pool).await?.is_none());
+        assert!(alice.create_magic_link(false, Some("10.0.0.1"), &pool).await?.is_some());
+
+        sqlx::query("UPDATE magic_links SET requested_ip = '10.0.0.2', user_id = $1")
+            .bind(alice.id)
+            .execute(&pool)
+            .await?;
+        for _ in MAX_LINKS_PER_EMAIL + 1..MAX_LINKS_PER_IP {
+            sqlx::query("INSERT INTO magic_links (user_id, token_hash, expires_at, requested_ip) VALUES ($1, sha256(convert_to($2, 'UTF8')), NOW(), '10.0.0.2')")
+                .bind(alice.id)
+                .bind(generate_token(8))
+                .execute(&pool)
+                .await?;
+        }
+        backdate().await?;
+        let bob = User::find_by_email("bob@acme.org", &pool).await?.expect("user should exist");
+        assert!(bob.create_magic_link(false, Some("10.0.0.2"), &pool).await?.is_none());
+        assert!(bob.create_magic_link(false, Some("10.0.0.3"), &pool).await?.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{utils::generate_token, AppError, MailSettings};
+
+const VERIFICATION_TOKEN_BYTES: usize = 32;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateMailSettings {
+    #[serde(default)]
+    pub smtp_host: Option<String>,
+    #[serde(default)]
+    pub smtp_port: Option<u16>,
+    #[serde(default)]
+    pub smtp_username: Option<String>,
+    // kept when not given
+    #[serde(default)]
+    pub smtp_password: Option<String>,
+    #[serde(default)]
+    pub from_email: Option<String>,
+    // an empty name removes it
+    #[serde(default)]
+    pub from_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyMailSettings {
+    pub token: String,
+}
+
+impl MailSettings {
+    pub async fn fetch(ws_id: u64, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let settings = sqlx::query_as(
+            r#"
+            SELECT ws_id, smtp_host, smtp_port, smtp_username, smtp_password, from_email, from_name, verified_at,
+                created_at, updated_at
+            FROM workspace_mail
+            WHERE ws_id = $1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_optional(pool)
+        .await?;
+        Ok(settings)
+    }
+
+    // any change has to be verified again, returns the token to mail to the sender address
+    pub async fn update(ws_id: u64, input: &UpdateMailSettings, pool: &PgPool) -> Result<(Self, String), AppError> {
+        let current = Self::fetch(ws_id, pool).await?;
+        let pick = |new: &Option<String>, current: Option<&String>| {
+            new.as_deref().map(str::trim).map(str::to_string).or_else(|| current.cloned())
+        };
+        let smtp_host = pick(&input.smtp_host, current.as_ref().map(|c| &c.smtp_host));
+        let smtp_username = pick(&input.smtp_username, current.as_ref().map(|c| &c.smtp_username));
+        let smtp_password = input
+            .smtp_password
+            .clone()
+            .or_else(|| current.as_ref().map(|c| c.smtp_password.clone()));
+        let from_email = pick(&input.from_email, current.as_ref().map(|c| &c.from_email)).map(|e| e.to_lowercase());
+        let from_name = match &input.from_name {
+            Some(name) => Some(name.trim().to_string()).filter(|n| !n.is_empty()),
+            None => current.as_ref().and_then(|c| c.from_name.clone()),
+        };
+        let smtp_port = input
+            .smtp_port
+            .map(i32::from)
+            .or(current.as_ref().map(|c| c.smtp_port))
+            .unwrap_or(587);
+        let (Some(smtp_host), Some(smtp_username), Some(smtp_password), Some(from_email)) =
+            (smtp_host, smtp_username, smtp_password, from_email)
+        else {
+            return Err(AppError::UpdateWorkspaceError(
+                "SMTP needs a host, a username, a password and a from address".to_string(),
+            ));
+        };
+        if smtp_host.is_empty() || smtp_host.len() > 255 || smtp_host.contains(char::is_whitespace) {
+            return Err(AppError::UpdateWorkspaceError(format!("Invalid SMTP host: {}", smtp_host)));
+        }
+        if smtp_port == 0 {
+            return Err(AppError::UpdateWorkspaceError("Invalid SMTP port: 0".to_string()));
+        }
+        if smtp_username.len() > 255 || smtp_password.len() > 255 {
+            return Err(AppError::UpdateWorkspaceError(
+                "SMTP username and password must be at most 255 characters".to_string(),
+            ));
+        }
+        if !is_valid_address(&from_email) {
+            return Err(AppError::UpdateWorkspaceError(format!("Invalid from address: {}", from_email)));
+        }
+        if from_name.as_ref().is_some_and(|n| n.chars().count() > 64 || n.contains(['<', '>', '"', '\r', '\n'])) {
+            return Err(AppError::UpdateWorkspaceError(
+                "From name must be at most 64 characters, without <, > or quotes".to_string(),
+            ));
+        }
+
+        let token = generate_token(VERIFICATION_TOKEN_BYTES);
+        let settings = sqlx::query_as(
+            r#"
+            INSERT INTO workspace_mail
+                (ws_id, smtp_host, smtp_port, smtp_username, smtp_password, from_email, from_name, verification_token_hash)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, sha256(convert_to($8, 'UTF8')))
+            ON CONFLICT (ws_id) DO UPDATE SET
+                smtp_host = EXCLUDED.smtp_host, smtp_port = EXCLUDED.smtp_port, smtp_username = EXCLUDED.smtp_username,
+                smtp_password = EXCLUDED.smtp_password, from_email = EXCLUDED.from_email,
+                from_name = EXCLUDED.from_name, verification_token_hash = EXCLUDED.verification_token_hash,
+                verified_at = NULL, updated_at = NOW()
+            RETURNING ws_id, smtp_host, smtp_port, smtp_username, smtp_password, from_email, from_name, verified_at,
+                created_at, updated_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(&smtp_host)
+        .bind(smtp_port)
+        .bind(&smtp_username)
+        .bind(&smtp_password)
+        .bind(&from_email)
+        .bind(&from_name)
+        .bind(&token)
+        .fetch_one(pool)
+        .await?;
+        Ok((settings, token))
+    }
+
+    // the token arriving proves both that the smtp server delivers and that the sender address is ours
+    pub async fn verify(token: &str, pool: &PgPool) -> Result<Self, AppError> {
+        let settings: Option<Self> = sqlx::query_as(
+            r#"
+            UPDATE workspace_mail SET verified_at = NOW(), verification_token_hash = NULL
+            WHERE verification_token_hash = sha256(convert_to($1, 'UTF8'))
+            RETURNING ws_id, smtp_host, smtp_port, smtp_username, smtp_password, from_email, from_name, verified_at,
+                created_at, updated_at
+            "#,
+        )
+        .bind(token)
+        .fetch_optional(pool)
+        .await?;
+        settings.ok_or_else(|| AppError::PermissionDenied("mail verification token is invalid".to_string()))
+    }
+
+    pub async fn delete(ws_id: u64, pool: &PgPool) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM workspace_mail WHERE ws_id = $1")
+            .bind(ws_id as i64)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    // the From header, e.g. "Acme" <noreply@acme.org>
+    pub fn sender(&self) -> String {
+        match &self.from_name {
+            Some(name) => format!("\"{}\" <{}>", name, self.from_email),
+            None => self.from_email.clone(),
+        }
+    }
+}
+
+fn is_valid_address(email: &str) -> bool {
+    email.len() <= 254
+        && !email.contains(char::is_whitespace)
+        && email
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.') && !domain.contains('@'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    fn input() -> UpdateMailSettings {
+        UpdateMailSettings {
+            smtp_host: Some("smtp.acme.org".to_string()),
+            smtp_port: Some(465),
+            smtp_username: Some("chat".to_string()),
+            smtp_password: Some("secret".to_string()),
+            from_email: Some("Chat@Acme.org".to_string()),
+            from_name: Some("Acme Chat".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn mail_settings_should_need_verification_after_update() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let partial = UpdateMailSettings { smtp_host: Some("smtp.acme.org".to_string()), ..Default::default() };
+        assert!(MailSettings::update(1, &partial, &pool).await.is_err());
+
+        let (settings, token) = MailSettings::update(1, &input(), &pool).await?;
+        assert_eq!(settings.sender(), "\"Acme Chat\" <chat@acme.org>");
+        assert!(settings.verified_at.is_none());
+        let verified = MailSettings::verify(&token, &pool).await?;
+        assert!(verified.verified_at.is_some());
+        assert!(MailSettings::verify(&token, &pool).await.is_err());
+
+        // the password is kept, but the change needs verifying again
+        let change = UpdateMailSettings { from_name: Some("".to_string()), ..Default::default() };
+        let (settings, _) = MailSettings::update(1, &change, &pool).await?;
+        assert_eq!(settings.smtp_password, "secret");
+        assert_eq!(settings.sender(), "chat@acme.org");
+        assert!(settings.verified_at.is_none());
+
+        let bad = UpdateMailSettings { from_email: Some("chat".to_string()), ..Default::default() };
+        assert!(MailSettings::update(1, &bad, &pool).await.is_err());
+        MailSettings::delete(1, &pool).await?;
+        assert!(MailSettings::fetch(1, &pool).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,497 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+use crate::{utils::generate_token, AppError, MatrixBridge, MatrixRoom};
+
+const MATRIX_TOKEN_BYTES: usize = 32;
+const MAX_LOCALPART_LEN: usize = 64;
+
+const SELECT_BRIDGE: &str = r#"
+    SELECT ws_id, homeserver_url, server_name, sender_localpart, user_prefix, enabled, created_by,
+        created_at, updated_at
+    FROM matrix_bridges
+"#;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateMatrixBridge {
+    pub homeserver_url: String,
+    pub server_name: String,
+    #[serde(default = "default_sender_localpart")]
+    pub sender_localpart: String,
+    #[serde(default = "default_user_prefix")]
+    pub user_prefix: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkMatrixRoom {
+    pub chat_id: u64,
+    // a room id or alias the bridge's user can join
+    pub room: String,
+}
+
+// what talking to the homeserver takes, the tokens never leave the server
+#[derive(Debug, Clone, FromRow)]
+pub(crate) struct MatrixHomeserver {
+    pub ws_id: i64,
+    pub homeserver_url: String,
+    pub server_name: String,
+    pub sender_localpart: String,
+    pub user_prefix: String,
+    pub as_token: String,
+}
+
+// a room with messages not sent yet, claimed for sending them
+#[derive(Debug, Clone, FromRow)]
+pub(crate) struct DueRoom {
+    pub chat_id: i64,
+    pub room_id: String,
+    pub synced_seq: i64,
+    #[sqlx(flatten)]
+    pub homeserver: MatrixHomeserver,
+}
+
+// a member's matrix user, registered the first time they have something to send
+#[derive(Debug, Clone, FromRow)]
+pub(crate) struct MatrixPuppet {
+    pub user_id: i64,
+    pub mxid: String,
+    pub display_name: String,
+}
+
+fn default_sender_localpart() -> String {
+    "chat".to_string()
+}
+
+fn default_user_prefix() -> String {
+    "chat_".to_string()
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl UpdateMatrixBridge {
+    fn validate(&self) -> Result<(), AppError> {
+        let url = &self.homeserver_url;
+        if !(url.starts_with("https://") || url.starts_with("http://")) || url.len() > 1024 {
+            return Err(AppError::InvalidInput(format!("invalid homeserver url: {}", url)));
+        }
+        let name = &self.server_name;
+        if name.is_empty() || name.len() > 255 || name.contains(|c: char| c.is_whitespace() || c == '/' || c == '@') {
+            return Err(AppError::InvalidInput(format!("invalid server name: {}", name)));
+        }
+        for localpart in [&self.sender_localpart, &self.user_prefix] {
+            if !is_localpart(localpart) {
+                return Err(AppError::InvalidInput(format!(
+                    "{} must be 1 to {} of a-z, 0-9, ., _, = and -",
+                    localpart, MAX_LOCALPART_LEN
+                )));
+            }
+        }
+        if self.sender_localpart.starts_with(&self.user_prefix) {
+            return Err(AppError::InvalidInput(
+                "the sender localpart can't start with the user prefix".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// the characters matrix allows in the part of a user id before the colon
+fn is_localpart(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= MAX_LOCALPART_LEN
+        && s.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"._=-".contains(&b))
+}
+
+impl MatrixBridge {
+    pub async fn get(ws_id: u64, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let bridge = sqlx::query_as(&format!("{} WHERE ws_id = $1", SELECT_BRIDGE))
+            .bind(ws_id as i64)
+            .fetch_optional(pool)
+            .await?;
+        Ok(bridge)
+    }
+
+    // tokens are made when the bridge is first set up and kept on later updates
+    pub async fn update(ws_id: u64, input: &UpdateMatrixBridge, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let input = UpdateMatrixBridge {
+            homeserver_url: input.homeserver_url.trim().trim_end_matches('/').to_string(),
+            server_name: input.server_name.trim().to_string(),
+            ..input.clone()
+        };
+        input.validate()?;
+        if Self::get(ws_id, pool).await?.is_some() {
+            let bridge = sqlx::query_as(
+                r#"
+                UPDATE matrix_bridges
+                SET homeserver_url = $2, server_name = $3, sender_localpart = $4, user_prefix = $5,
+                    enabled = $6, updated_at = NOW()
+                WHERE ws_id = $1
+                RETURNING ws_id, homeserver_url, server_name, sender_localpart, user_prefix, enabled,
+                    created_by, created_at, updated_at
+                "#,
+            )
+            .bind(ws_id as i64)
+            .bind(&input.homeserver_url)
+            .bind(&input.server_name)
+            .bind(&input.sender_localpart)
+            .bind(&input.user_prefix)
+            .bind(input.enabled)
+            .fetch_one(pool)
+            .await?;
+            return Ok(bridge);
+        }
+
+        let as_token = generate_token(MATRIX_TOKEN_BYTES);
+        let hs_token = generate_token(MATRIX_TOKEN_BYTES);
+        let mut bridge: Self = sqlx::query_as(
+            r#"
+            INSERT INTO matrix_bridges (ws_id, homeserver_url, server_name, sender_localpart, user_prefix,
+                as_token, hs_token_hash, enabled, created_by)
+            VALUES ($1, $2, $3, $4, $5, $6, sha256(convert_to($7, 'UTF8')), $8, $9)
+            RETURNING ws_id, homeserver_url, server_name, sender_localpart, user_prefix, enabled, as_token,
+                created_by, created_at, updated_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(&input.homeserver_url)
+        .bind(&input.server_name)
+        .bind(&input.sender_localpart)
+        .bind(&input.user_prefix)
+        .bind(&as_token)
+        .bind(&hs_token)
+        .bind(input.enabled)
+        .bind(user_id as i64)
+        .fetch_one(pool)
+        .await?;
+        bridge.hs_token = Some(hs_token);
+        Ok(bridge)
+    }
+
+    // both tokens change, the homeserver's registration file has to be updated with them
+    pub async fn rotate_tokens(ws_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let hs_token = generate_token(MATRIX_TOKEN_BYTES);
+        let bridge: Option<Self> = sqlx::query_as(
+            r#"
+            UPDATE matrix_bridges
+            SET as_token = $2, hs_token_hash = sha256(convert_to($3, 'UTF8')), updated_at = NOW()
+            WHERE ws_id = $1
+            RETURNING ws_id, homeserver_url, server_name, sender_localpart, user_prefix, enabled, as_token,
+                created_by, created_at, updated_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(generate_token(MATRIX_TOKEN_BYTES))
+        .bind(&hs_token)
+        .fetch_optional(pool)
+        .await?;
+        let mut bridge = bridge.ok_or_else(|| AppError::NotFound("matrix bridge".to_string()))?;
+        bridge.hs_token = Some(hs_token);
+        Ok(bridge)
+    }
+
+    // the rooms go with it, puppets are kept for when it is set up again
+    pub async fn delete(ws_id: u64, pool: &PgPool) -> Result<(), AppError> {
+        let ret = sqlx::query("DELETE FROM matrix_bridges WHERE ws_id = $1")
+            .bind(ws_id as i64)
+            .execute(pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound("matrix bridge".to_string()));
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn homeserver(ws_id: u64, pool: &PgPool) -> Result<Option<MatrixHomeserver>, AppError> {
+        let homeserver = sqlx::query_as(
+            r#"
+            SELECT ws_id, homeserver_url, server_name, sender_localpart, user_prefix, as_token
+            FROM matrix_bridges
+            WHERE ws_id = $1 AND enabled
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_optional(pool)
+        .await?;
+        Ok(homeserver)
+    }
+}
+
+impl MatrixHomeserver {
+    // the bridge the homeserver's token belongs to, for requests from the homeserver
+    pub(crate) async fn find_by_hs_token(token: &str, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let homeserver = sqlx::query_as(
+            r#"
+            SELECT ws_id, homeserver_url, server_name, sender_localpart, user_prefix, as_token
+            FROM matrix_bridges
+            WHERE hs_token_hash = sha256(convert_to($1, 'UTF8')) AND enabled
+            "#,
+        )
+        .bind(token)
+        .fetch_optional(pool)
+        .await?;
+        Ok(homeserver)
+    }
+
+    pub(crate) fn bot_mxid(&self) -> String {
+        format!("@{}:{}", self.sender_localpart, self.server_name)
+    }
+
+    pub(crate) fn puppet_localpart(&self, user_id: i64) -> String {
+        format!("{}{}", self.user_prefix, user_id)
+    }
+
+    pub(crate) fn puppet_mxid(&self, user_id: i64) -> String {
+        format!("@{}:{}", self.puppet_localpart(user_id), self.server_name)
+    }
+
+    // the bridge's own users, what they send came from here
+    pub(crate) fn is_ours(&self, mxid: &str) -> bool {
+        if mxid == self.bot_mxid() {
+            return true;
+        }
+        let Some((localpart, server)) = mxid.strip_prefix('@').and_then(|id| id.split_once(':')) else {
+            return false;
+        };
+        server == self.server_name && localpart.starts_with(&self.user_prefix)
+    }
+}
+
+impl MatrixRoom {
+    // only messages sent from now on are mirrored, the chat's history isn't
+    pub async fn link(ws_id: u64, chat_id: u64, room_id: &str, pool: &PgPool) -> Result<Self, AppError> {
+        if !room_id.starts_with('!') || !room_id.contains(':') || room_id.len() > 255 {
+            return Err(AppError::InvalidInput(format!("invalid matrix room id: {}", room_id)));
+        }
+        let room = sqlx::query_as(
+            r#"
+            INSERT INTO matrix_rooms (chat_id, ws_id, room_id, synced_seq)
+            SELECT id, ws_id, $3, last_seq
+            FROM chats
+            WHERE id = $2 AND ws_id = $1 AND type != 'single'
+            RETURNING chat_id, ws_id, room_id, synced_seq, last_error, created_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(chat_id as i64)
+        .bind(room_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(e) if e.is_unique_violation() => {
+                AppError::InvalidInput(format!("chat {} or room {} is already linked", chat_id, room_id))
+            }
+            e => e.into(),
+        })?;
+        room.ok_or_else(|| AppError::NotFound(format!("chat {}", chat_id)))
+    }
+
+    pub async fn list(ws_id: u64, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let rooms = sqlx::query_as(
+            r#"
+            SELECT chat_id, ws_id, room_id, synced_seq, last_error, created_at
+            FROM matrix_rooms
+            WHERE ws_id = $1
+            ORDER BY chat_id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(rooms)
+    }
+
+    pub async fn find_by_room(ws_id: u64, room_id: &str, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let room = sqlx::query_as(
+            r#"
+            SELECT chat_id, ws_id, room_id, synced_seq, last_error, created_at
+            FROM matrix_rooms
+            WHERE ws_id = $1 AND room_id = $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(room_id)
+        .fetch_optional(pool)
+        .await?;
+        Ok(room)
+    }
+
+    pub async fn unlink(ws_id: u64, chat_id: u64, pool: &PgPool) -> Result<(), AppError> {
+        let ret = sqlx::query("DELETE FROM matrix_rooms WHERE ws_id = $1 AND chat_id = $2")
+            .bind(ws_id as i64)
+            .bind(chat_id as i64)
+            .execute(pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("matrix room of chat {}", chat_id)));
+        }
+        Ok(())
+    }
+
+    // rooms of enabled bridges whose chat has messages past synced_seq. A claimed room isn't
+    // claimed again until the lease runs out or record_sync releases it.
+    pub(crate) async fn claim_behind(limit: usize, lease_secs: u64, pool: &PgPool) -> Result<Vec<DueRoom>, AppError> {
+        let rooms = sqlx::query_as(
+            r#"
+            UPDATE matrix_rooms r
+            SET lease_until = now() + make_interval(secs => $2)
+            FROM matrix_bridges b
+            WHERE b.ws_id = r.ws_id AND r.chat_id IN (
+                SELECT m.chat_id FROM matrix_rooms m
+                JOIN chats c ON c.id = m.chat_id
+                JOIN matrix_bridges mb ON mb.ws_id = m.ws_id
+                WHERE mb.enabled AND c.last_seq > m.synced_seq
+                    AND (m.lease_until IS NULL OR m.lease_until <= now())
+                ORDER BY m.lease_until NULLS FIRST
+                LIMIT $1
+                FOR UPDATE OF m SKIP LOCKED
+            )
+            RETURNING r.chat_id, r.room_id, r.synced_seq, b.ws_id, b.homeserver_url, b.server_name,
+                b.sender_localpart, b.user_prefix, b.as_token
+            "#,
+        )
+        .bind(limit as i64)
+        .bind(lease_secs as f64)
+        .fetch_all(pool)
+        .await?;
+        Ok(rooms)
+    }
+
+    // retry_at keeps the room from being claimed before then, none releases it right away
+    pub(crate) async fn record_sync(
+        chat_id: i64,
+        synced_seq: i64,
+        error: Option<&str>,
+        retry_at: Option<DateTime<Utc>>,
+        pool: &PgPool,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE matrix_rooms
+            SET synced_seq = GREATEST(synced_seq, $2), last_error = $3, lease_until = $4
+            WHERE chat_id = $1
+            "#,
+        )
+        .bind(chat_id)
+        .bind(synced_seq)
+        .bind(error)
+        .bind(retry_at)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+impl MatrixPuppet {
+    pub(crate) async fn find(user_id: i64, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let puppet = sqlx::query_as("SELECT user_id, mxid, display_name FROM matrix_puppets WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+        Ok(puppet)
+    }
+
+    pub(crate) async fn save(&self, pool: &PgPool) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO matrix_puppets (user_id, mxid, display_name)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id) DO UPDATE SET mxid = $2, display_name = $3
+            "#,
+        )
+        .bind(self.user_id)
+        .bind(&self.mxid)
+        .bind(&self.display_name)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, CreateMessage, Message};
+    use anyhow::Result;
+
+    fn acme() -> UpdateMatrixBridge {
+        UpdateMatrixBridge {
+            homeserver_url: "https://matrix.acme.org/".to_string(),
+            server_name: "acme.org".to_string(),
+            sender_localpart: default_sender_localpart(),
+            user_prefix: default_user_prefix(),
+            enabled: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn matrix_bridge_tokens_should_only_be_returned_once() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let bridge = MatrixBridge::update(1, &acme(), 1, &pool).await?;
+        assert_eq!(bridge.homeserver_url, "https://matrix.acme.org");
+        let hs_token = bridge.hs_token.expect("hs token");
+        let homeserver = MatrixHomeserver::find_by_hs_token(&hs_token, &pool).await?.expect("homeserver");
+        assert_eq!(Some(homeserver.as_token), bridge.as_token);
+
+        let input = UpdateMatrixBridge { enabled: false, ..acme() };
+        let bridge = MatrixBridge::update(1, &input, 1, &pool).await?;
+        assert!(bridge.as_token.is_none() && bridge.hs_token.is_none());
+        assert!(MatrixHomeserver::find_by_hs_token(&hs_token, &pool).await?.is_none());
+
+        MatrixBridge::update(1, &acme(), 1, &pool).await?;
+        let rotated = MatrixBridge::rotate_tokens(1, &pool).await?;
+        assert!(MatrixHomeserver::find_by_hs_token(&hs_token, &pool).await?.is_none());
+        assert!(MatrixHomeserver::find_by_hs_token(&rotated.hs_token.expect("hs token"), &pool).await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn matrix_bridge_should_reject_overlapping_namespaces() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = UpdateMatrixBridge { sender_localpart: "chat_bot".to_string(), ..acme() };
+        let ret = MatrixBridge::update(1, &input, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn matrix_room_should_be_claimed_when_behind() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        MatrixBridge::update(1, &acme(), 1, &pool).await?;
+        let room = MatrixRoom::link(1, 1, "!general:acme.org", &pool).await?;
+        assert!(matches!(MatrixRoom::link(1, 1, "!other:acme.org", &pool).await, Err(AppError::InvalidInput(_))));
+        assert!(MatrixRoom::claim_behind(10, 60, &pool).await?.is_empty());
+
+        let msg = Message::create(&CreateMessage::new("hello"), 1, 1, &pool).await?;
+        let due = MatrixRoom::claim_behind(10, 60, &pool).await?;
+        assert_eq!((due[0].chat_id, due[0].synced_seq), (1, room.synced_seq));
+        assert_eq!(due[0].homeserver.bot_mxid(), "@chat:acme.org");
+        assert!(MatrixRoom::claim_behind(10, 60, &pool).await?.is_empty());
+
+        MatrixRoom::record_sync(1, msg.seq, None, None, &pool).await?;
+        assert!(MatrixRoom::claim_behind(10, 60, &pool).await?.is_empty());
+        let rooms = MatrixRoom::list(1, &pool).await?;
+        assert_eq!(rooms[0].synced_seq, msg.seq);
+        Ok(())
+    }
+
+    #[test]
+    fn is_ours_should_match_bridge_users() {
+        let homeserver = MatrixHomeserver {
+            ws_id: 1,
+            homeserver_url: "https://matrix.acme.org".to_string(),
+            server_name: "acme.org".to_string(),
+            sender_localpart: default_sender_localpart(),
+            user_prefix: default_user_prefix(),
+            as_token: String::new(),
+        };
+        assert!(homeserver.is_ours("@chat:acme.org"));
+        assert!(homeserver.is_ours(&homeserver.puppet_mxid(7)));
+        assert!(!homeserver.is_ours("@chat_7:matrix.org"));
+        assert!(!homeserver.is_ours("@alice:acme.org"));
+    }
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{AppError, Mention};
+
+const DEFAULT_MENTION_LIMIT: u64 = 50;
+const MAX_MENTION_LIMIT: u64 = 200;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListMentions {
+    #[serde(default)]
+    pub unread_only: bool,
+    // only return mentions in messages with id smaller than this
+    #[serde(default)]
+    pub last_id: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MarkMentionsRead {
+    // message ids, marks everything read when empty
+    #[serde(default)]
+    pub ids: Vec<i64>,
+}
+
+impl Mention {
+    pub async fn list(user_id: u64, input: &ListMentions, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let limit = input.limit.unwrap_or(DEFAULT_MENTION_LIMIT).min(MAX_MENTION_LIMIT);
+        let mentions = sqlx::query_as(
+            r#"
+            SELECT m.id, m.chat_id, m.sender_id, m.seq, m.kind, m.thread_id, m.images, m.created_at, m.client_created_at,
+                CASE WHEN m.chunks = 0 THEN m.content
+                ELSE m.content || (
+                    SELECT string_agg(c.content, '' ORDER BY c.idx)
+                    FROM message_chunks c
+                    WHERE c.message_id = m.id
+                ) END AS content,
+                mm.read_at
+            FROM message_mentions mm
+            JOIN messages m ON m.id = mm.message_id
+            -- mentions in chats the user has since left are not shown
+            JOIN chats ch ON ch.id = m.chat_id AND $1 = ANY(ch.members)
+            WHERE mm.user_id = $1
+                AND mm.message_id < $2
+                AND (NOT $3 OR mm.read_at IS NULL)
+            ORDER BY mm.message_id DESC
+            LIMIT $4
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(input.last_id.unwrap_or(i64::MAX))
+        .bind(input.unread_only)
+        .bind(limit as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(mentions)
+    }
+
+    pub async fn unread_count(user_id: u64, pool: &PgPool) -> Result<i64, AppError> {
+        let count = sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*)
+            FROM message_mentions mm
+            JOIN messages m ON m.id = mm.message_id
+            JOIN chats ch ON ch.id = m.chat_id AND $1 = ANY(ch.members)
+            WHERE mm.user_id = $1 AND mm.read_at IS NULL
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_one(pool)
+        .await?;
+        Ok(count)
+    }
+
+    // returns number of mentions newly marked read
+    pub async fn mark_read(user_id: u64, input: &MarkMentionsRead, pool: &PgPool) -> Result<u64, AppError> {
+        let ret = sqlx::query(
+            r#"
+            UPDATE message_mentions
+            SET read_at = NOW()
+            WHERE user_id = $1 AND read_at IS NULL
+                AND (cardinality($2::bigint[]) = 0 OR message_id = ANY($2))
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(&input.ids)
+        .execute(pool)
+        .await?;
+        Ok(ret.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, CreateMessage, Message};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn mentions_should_be_listed_and_marked_read() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        // user 4 is not in chat 2, so is not mentioned there
+        let m1 = Message::create(&CreateMessage::new("hi <@2> <@4>"), 2, 1, &pool).await?;
+        let m2 = Message::create(&CreateMessage::new("<@2> <@1> ping"), 1, 1, &pool).await?;
+
+        let mentions = Mention::list(2, &ListMentions::default(), &pool).await?;
+        let ids: Vec<_> = mentions.iter().map(|m| m.message.id).collect();
+        assert_eq!(ids, vec![m2.id, m1.id]);
+        assert!(Mention::list(4, &ListMentions::default(), &pool).await?.is_empty());
+        // self mention is ignored
+        assert!(Mention::list(1, &ListMentions::default(), &pool).await?.is_empty());
+        assert_eq!(Mention::unread_count(2, &pool).await?, 2);
+
+        let input = MarkMentionsRead { ids: vec![m1.id] };
+        assert_eq!(Mention::mark_read(2, &input, &pool).await?, 1);
+        let input = ListMentions {
+            unread_only: true,
+            ..Default::default()
+        };
+        let mentions = Mention::list(2, &input, &pool).await?;
+        assert_eq!(mentions.len(), 1);
+        assert_eq!(mentions[0].message.id, m2.id);
+        assert!(mentions[0].read_at.is_none());
+
+        assert_eq!(Mention::mark_read(2, &MarkMentionsRead::default(), &pool).await?, 1);
+        assert_eq!(Mention::unread_count(2, &pool).await?, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,567 @@
+use chrono::{Duration, Utc};
+use sqlx::PgPool;
+
+use crate::{events::{record, AppEvent}, AppError, CreateMessage, ListMessages, Message, MessageHas, MessageKind, MessageSort, Webhook, WebhookEvent};
+
+use super::{chat::mark_all_read, email_notification, moderation::workspace_policy, thread::record_reply};
+
+// how far ahead of the server clock a client timestamp may be
+const MAX_CLIENT_CLOCK_SKEW_SECS: i64 = 5 * 60;
+// how long a message may have been composed offline before it is sent
+const MAX_CLIENT_TIME_AGE_SECS: i64 = 60 * 60 * 24 * 7;
+// content beyond this many bytes is split into message_chunks rows
+const MESSAGE_CHUNK_BYTES: usize = 8 * 1024;
+const MAX_EXTERNAL_ID_LEN: usize = 128;
+
+// messages with their chunks reassembled
+const SELECT_MESSAGE: &str = r#"
+    SELECT id, chat_id, sender_id, seq, kind, thread_id, images, created_at, client_created_at, display_name,
+        icon_url, CASE WHEN chunks = 0 THEN content
+        ELSE content || (
+            SELECT string_agg(c.content, '' ORDER BY c.idx)
+            FROM message_chunks c
+            WHERE c.message_id = messages.id
+        ) END AS content
+    FROM messages
+"#;
+
+// who an integration message is shown as, instead of its sender
+#[derive(Debug, Clone)]
+pub(crate) struct IntegrationSender {
+    pub display_name: String,
+    pub icon_url: Option<String>,
+}
+
+impl Message {
+    pub async fn create(input: &CreateMessage, chat_id: u64, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        Self::insert(input, chat_id, user_id, None, pool).await
+    }
+
+    // sent by an integration on behalf of user_id, who need not be a member of the chat
+    pub(crate) async fn create_integration(
+        input: &CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+        sender: &IntegrationSender,
+        pool: &PgPool,
+    ) -> Result<Self, AppError> {
+        Self::insert(input, chat_id, user_id, Some(sender), pool).await
+    }
+
+    async fn insert(
+        input: &CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+        integration: Option<&IntegrationSender>,
+        pool: &PgPool,
+    ) -> Result<Self, AppError> {
+        if input.content.is_empty() {
+            return Err(AppError::CreateMessageError(
+                "Content cannot be empty".to_string(),
+            ));
+        }
+        if let Some(client_created_at) = input.client_created_at {
+            let now = Utc::now();
+            if client_created_at > now + Duration::seconds(MAX_CLIENT_CLOCK_SKEW_SECS) {
+                return Err(AppError::CreateMessageError(
+                    "client_created_at is too far in the future".to_string(),
+                ));
+            }
+            if client_created_at < now - Duration::seconds(MAX_CLIENT_TIME_AGE_SECS) {
+                return Err(AppError::CreateMessageError(
+                    "client_created_at is too far in the past".to_string(),
+                ));
+            }
+        }
+        if let Some(external_id) = &input.external_id
+            && (external_id.is_empty() || external_id.len() > MAX_EXTERNAL_ID_LEN)
+        {
+            return Err(AppError::CreateMessageError(format!(
+                "external_id must be 1 to {} bytes",
+                MAX_EXTERNAL_ID_LEN
+            )));
+        }
+
+        // bumping last_seq takes the chat row lock, so concurrent senders are serialized
+        // and seq is gap free within a chat
+        let mut tx = pool.begin().await?;
+        let row: Option<(i64, i64, i32, bool)> = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET last_seq = last_seq + 1
+            WHERE id = $1 AND ($2 = ANY(members) OR $3)
+            RETURNING last_seq, ws_id, (SELECT max_message_length FROM workspaces WHERE id = chats.ws_id),
+                EXISTS(SELECT 1 FROM bots WHERE user_id = $2)
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(integration.is_some())
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((seq, ws_id, max_len, is_bot)) = row else {
+            return Err(AppError::CreateMessageError(format!(
+                "User {} is not a member of chat {}",
+                user_id, chat_id
+            )));
+        };
+        if input.content.chars().count() > max_len as usize {
+            return Err(AppError::CreateMessageError(format!(
+                "Content exceeds the workspace limit of {} characters",
+                max_len
+            )));
+        }
+
+        // checked under the chat row lock, so the same external id arriving twice at once
+        // still ends up as one message; the seq bump is rolled back along with the tx
+        if let Some(external_id) = &input.external_id {
+            let existing = sqlx::query_as(&format!("{} WHERE chat_id = $1 AND external_id = $2", SELECT_MESSAGE))
+                .bind(chat_id as i64)
+                .bind(external_id)
+                .fetch_optional(&mut *tx)
+                .await?;
+            if let Some(message) = existing {
+                tx.rollback().await?;
+                return Ok(message);
+            }
+        }
+
+        // masked messages are stored masked, with the original kept aside for moderators
+        let masked = workspace_policy(&mut tx, ws_id).await?.apply(&input.content)?;
+        let content = masked.as_deref().unwrap_or(&input.content);
+        let original_content = masked.is_some().then_some(&input.content);
+
+        if let Some(thread_id) = input.thread_id {
+            let is_root: bool = sqlx::query_scalar(
+                r#"
+                SELECT EXISTS(SELECT 1 FROM messages WHERE id = $1 AND chat_id = $2 AND thread_id IS NULL)
+                "#,
+            )
+            .bind(thread_id)
+            .bind(chat_id as i64)
+            .fetch_one(&mut *tx)
+            .await?;
+            if !is_root {
+                return Err(AppError::CreateMessageError(format!(
+                    "Thread {} not found in chat {}",
+                    thread_id, chat_id
+                )));
+            }
+        }
+
+        let mut chunks = split_content(content, MESSAGE_CHUNK_BYTES);
+        let head = chunks.remove(0);
+        let mut message: Message = sqlx::query_as(
+            r#"
+            INSERT INTO messages
+                (chat_id, sender_id, seq, content, images, client_created_at, chunks, thread_id, external_id,
+                original_content, kind, display_name, icon_url)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+            RETURNING id, chat_id, sender_id, seq, kind, thread_id, content, images, created_at, client_created_at,
+                display_name, icon_url
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(seq)
+        .bind(head)
+        .bind(&input.images)
+        .bind(input.client_created_at)
+        .bind(chunks.len() as i32)
+        .bind(input.thread_id)
+        .bind(&input.external_id)
+        .bind(original_content)
+        .bind(match (integration, is_bot) {
+            (Some(_), _) => MessageKind::Integration,
+            (None, true) => MessageKind::Bot,
+            (None, false) => MessageKind::User,
+        })
+        .bind(integration.map(|i| &i.display_name))
+        .bind(integration.and_then(|i| i.icon_url.as_ref()))
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if !chunks.is_empty() {
+            sqlx::query(
+                r#"
+                INSERT INTO message_chunks (message_id, idx, content)
+                SELECT $1, idx, content FROM unnest($2::text[]) WITH ORDINALITY AS t(content, idx)
+                "#,
+            )
+            .bind(message.id)
+            .bind(&chunks)
+            .execute(&mut *tx)
+            .await?;
+            message.content = content.to_string();
+        }
+
+        let mentions = parse_mentions(content);
+        if !mentions.is_empty() {
+            // only members of the chat can be mentioned, and mentioning yourself is a no-op
+            sqlx::query(
+                r#"
+                INSERT INTO message_mentions (message_id, user_id)
+                SELECT $1, u FROM unnest($2::bigint[]) AS u, chats c
+                WHERE c.id = $3 AND u = ANY(c.members) AND u <> $4
+                "#,
+            )
+            .bind(message.id)
+            .bind(&mentions)
+            .bind(chat_id as i64)
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+        email_notification::queue(&mut tx, &message).await?;
+        if message.thread_id.is_some() {
+            record_reply(&mut tx, &message).await?;
+        }
+        // your own messages aren't unread, an integration's aren't yours
+        if integration.is_none() {
+            mark_all_read(&mut tx, chat_id as i64, user_id as i64).await?;
+        }
+        let event = AppEvent::NewMessage {
+            ws_id,
+            chat_id: message.chat_id,
+            message_id: message.id,
+            sender_id: message.sender_id,
+            seq: message.seq,
+        };
+        record(&mut tx, &event).await?;
+        let payload = WebhookEvent::MessageCreated.payload(ws_id, &message);
+        Webhook::enqueue(&mut tx, ws_id, Some(message.chat_id), WebhookEvent::MessageCreated, &payload).await?;
+        tx.commit().await?;
+
+        Ok(message)
+    }
+
+    pub(crate) async fn find_by_id(id: i64, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let message = sqlx::query_as(&format!("{} WHERE id = $1", SELECT_MESSAGE))
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+        Ok(message)
+    }
+
+    // a masked message as it was written, for moderators of the workspace
+    pub async fn find_original(id: u64, ws_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let message: Option<Self> = sqlx::query_as(
+            r#"
+            SELECT m.id, m.chat_id, m.sender_id, m.seq, m.kind, m.thread_id, m.images, m.created_at,
+                m.client_created_at, m.original_content AS content
+            FROM messages m JOIN chats c ON c.id = m.chat_id
+            WHERE m.id = $1 AND c.ws_id = $2 AND m.original_content IS NOT NULL
+            "#,
+        )
+        .bind(id as i64)
+        .bind(ws_id as i64)
+        .fetch_optional(pool)
+        .await?;
+        message.ok_or_else(|| AppError::NotFound(format!("masked message not found: {}", id)))
+    }
+
+    pub async fn fetch_by_ids(ids: &[i64], pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let messages = sqlx::query_as(&format!("{} WHERE id = ANY($1)", SELECT_MESSAGE))
+            .bind(ids)
+            .fetch_all(pool)
+            .await?;
+        Ok(messages)
+    }
+
+    pub async fn list(input: &ListMessages, chat_id: u64, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        if let (Some(after), Some(before)) = (input.after, input.before)
+            && after >= before
+        {
+            return Err(AppError::InvalidInput("after must be earlier than before".to_string()));
+        }
+        // only these fixed pieces are put in the sql, every value is bound
+        let (past, order) = match input.sort {
+            MessageSort::Newest => ("<", "DESC"),
+            MessageSort::Oldest => (">", "ASC"),
+        };
+        let messages = sqlx::query_as(&format!(
+            r#"
+            {}
+            WHERE chat_id = $1 AND ($2::bigint IS NULL OR seq {} $2)
+                AND thread_id IS NOT DISTINCT FROM $4
+                AND ($5::bigint IS NULL OR sender_id = $5)
+                AND ($6::message_kind IS NULL OR kind = $6)
+                AND ($7::timestamptz IS NULL OR created_at < $7)
+                AND ($8::timestamptz IS NULL OR created_at >= $8)
+                AND (NOT $9 OR cardinality(images) > 0)
+                AND (NOT $10 OR (CASE WHEN chunks = 0 THEN content
+                    ELSE content || (
+                        SELECT string_agg(c.content, '' ORDER BY c.idx)
+                        FROM message_chunks c
+                        WHERE c.message_id = messages.id
+                    ) END) ~* 'https?://')
+                AND (NOT $11 OR EXISTS (SELECT 1 FROM message_mentions m WHERE m.message_id = messages.id))
+            ORDER BY seq {}
+            LIMIT $3
+            "#,
+            SELECT_MESSAGE, past, order
+        ))
+        .bind(chat_id as i64)
+        .bind(input.last_seq)
+        .bind(input.limit() as i64)
+        .bind(input.thread_id)
+        .bind(input.sender)
+        .bind(input.kind)
+        .bind(input.before)
+        .bind(input.after)
+        .bind(input.has.contains(&MessageHas::Attachment))
+        .bind(input.has.contains(&MessageHas::Link))
+        .bind(input.has.contains(&MessageHas::Mention))
+        .fetch_all(pool)
+        .await?;
+
+        Ok(messages)
+    }
+
+    // oldest first, thread replies included
+    pub(crate) async fn list_since(chat_id: u64, seq: i64, limit: usize, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let messages = sqlx::query_as(&format!("{} WHERE chat_id = $1 AND seq > $2 ORDER BY seq LIMIT $3", SELECT_MESSAGE))
+            .bind(chat_id as i64)
+            .bind(seq)
+            .bind(limit as i64)
+            .fetch_all(pool)
+            .await?;
+        Ok(messages)
+    }
+}
+
+// split into pieces of at most `max_bytes` bytes without breaking utf-8 characters,
+// always returns at least one piece
+fn split_content(content: &str, max_bytes: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = content;
+    while rest.len() > max_bytes {
+        let mut at = max_bytes;
+        while !rest.is_char_boundary(at) {
+            at -= 1;
+        }
+        let (chunk, tail) = rest.split_at(at);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks.push(rest);
+    chunks
+}
+
+// user ids mentioned with `<@id>`, deduplicated
+fn parse_mentions(content: &str) -> Vec<i64> {
+    let mut ids: Vec<i64> = content
+        .split("<@")
+        .skip(1)
+        .filter_map(|s| s.split_once('>'))
+        .filter_map(|(id, _)| id.parse().ok())
+        .collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn create_message_should_assign_next_seq() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateMessage::new("hello");
+        let msg = Message::create(&input, 1, 1, &pool).await?;
+        assert_eq!(msg.seq, 11);
+        let msg = Message::create(&input, 1, 2, &pool).await?;
+        assert_eq!(msg.seq, 12);
+
+        // seq is per chat
+        let msg = Message::create(&input, 2, 1, &pool).await?;
+        assert_eq!(msg.seq, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_by_non_member_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateMessage::new("hello");
+        let ret = Message::create(&input, 2, 4, &pool).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_should_keep_client_time() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let mut input = CreateMessage::new("written on a plane");
+        let written_at = Utc::now() - Duration::hours(3);
+        input.client_created_at = Some(written_at);
+        let msg = Message::create(&input, 1, 1, &pool).await?;
+        assert_eq!(msg.client_created_at.map(|t| t.timestamp()), Some(written_at.timestamp()));
+        assert!(msg.created_at > written_at);
+
+        input.client_created_at = Some(Utc::now() + Duration::hours(1));
+        let ret = Message::create(&input, 1, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+
+        input.client_created_at = Some(Utc::now() - Duration::days(30));
+        let ret = Message::create(&input, 1, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_with_seen_external_id_should_return_existing() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let mut input = CreateMessage::new("forwarded from email");
+        input.external_id = Some("<abc@mail.acme.org>".to_string());
+        let msg = Message::create(&input, 1, 1, &pool).await?;
+        assert_eq!(msg.seq, 11);
+
+        // the same message coming in through the bridge
+        input.content = "forwarded from the bridge".to_string();
+        let dup = Message::create(&input, 1, 2, &pool).await?;
+        assert_eq!(dup, msg);
+        let next = Message::create(&CreateMessage::new("hello"), 1, 1, &pool).await?;
+        assert_eq!(next.seq, 12);
+
+        // external ids are per chat
+        let other = Message::create(&input, 2, 1, &pool).await?;
+        assert_ne!(other.id, msg.id);
+
+        input.external_id = Some("".to_string());
+        let ret = Message::create(&input, 1, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn masked_message_should_keep_original_for_moderators() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        sqlx::query("UPDATE workspaces SET moderation_mode = 'mask', moderation_terms = '{heck}' WHERE id = 1")
+            .execute(&pool)
+            .await?;
+        let msg = Message::create(&CreateMessage::new("what the heck"), 1, 1, &pool).await?;
+        assert_eq!(msg.content, "what the ****");
+        let messages = Message::list(&ListMessages { limit: Some(1), ..Default::default() }, 1, &pool).await?;
+        assert_eq!(messages[0].content, "what the ****");
+        assert_eq!(Message::find_original(msg.id as _, 1, &pool).await?.content, "what the heck");
+        assert!(Message::find_original(msg.id as _, 2, &pool).await.is_err());
+
+        let clean = Message::create(&CreateMessage::new("hello"), 1, 1, &pool).await?;
+        assert!(Message::find_original(clean.id as _, 1, &pool).await.is_err());
+
+        sqlx::query("UPDATE workspaces SET moderation_mode = 'reject' WHERE id = 1")
+            .execute(&pool)
+            .await?;
+        let ret = Message::create(&CreateMessage::new("what the heck"), 1, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_mentions_should_work() {
+        assert!(parse_mentions("hello world").is_empty());
+        assert_eq!(parse_mentions("<@3> hi <@2>, <@3> again"), vec![2, 3]);
+        assert_eq!(parse_mentions("<@> <@abc> <@12 <@4>"), vec![4]);
+    }
+
+    #[test]
+    fn split_content_should_respect_char_boundaries() {
+        assert_eq!(split_content("", 4), vec![""]);
+        assert_eq!(split_content("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        // each is 3 bytes in utf-8
+        let chunks = split_content("你好世界", 4);
+        assert_eq!(chunks, vec!["你", "好", "世", "界"]);
+    }
+
+    #[tokio::test]
+    async fn long_message_should_be_chunked_and_reassembled() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let content = "你好, world! ".repeat(3000);
+        let input = CreateMessage::new(&content);
+        let msg = Message::create(&input, 1, 1, &pool).await?;
+        assert_eq!(msg.content, content);
+        let chunks: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM message_chunks WHERE message_id = $1")
+            .bind(msg.id)
+            .fetch_one(&pool)
+            .await?;
+        assert!(chunks > 0);
+
+        let messages = Message::list(&ListMessages { limit: Some(1), ..Default::default() }, 1, &pool).await?;
+        assert_eq!(messages[0].id, msg.id);
+        assert_eq!(messages[0].content, content);
+
+        sqlx::query("UPDATE workspaces SET max_message_length = 100 WHERE id = 1")
+            .execute(&pool)
+            .await?;
+        let ret = Message::create(&input, 1, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = ListMessages {
+            limit: Some(6),
+            ..Default::default()
+        };
+        let messages = Message::list(&input, 1, &pool).await?;
+        assert_eq!(messages.len(), 6);
+        assert_eq!(messages[0].seq, 10);
+
+        let input = ListMessages {
+            last_seq: Some(messages[5].seq),
+            limit: Some(6),
+            ..Default::default()
+        };
+        let messages = Message::list(&input, 1, &pool).await?;
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[3].seq, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_should_filter_and_sort() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let seqs = |messages: Vec<Message>| messages.iter().map(|m| m.seq).collect::<Vec<_>>();
+        let input = ListMessages { sender: Some(1), ..Default::default() };
+        assert_eq!(seqs(Message::list(&input, 1, &pool).await?), vec![10, 9, 6, 1]);
+        let input = ListMessages { sender: Some(1), sort: MessageSort::Oldest, last_seq: Some(1), ..Default::default() };
+        assert_eq!(seqs(Message::list(&input, 1, &pool).await?), vec![6, 9, 10]);
+
+        let input = CreateMessage {
+            images: vec!["/files/1/abc/def/0123.png".to_string()],
+            ..CreateMessage::new("see https://acme.org <@2>")
+        };
+        let rich = Message::create(&input, 1, 3, &pool).await?;
+        Message::create(&CreateMessage::new("no link <@2>"), 1, 3, &pool).await?;
+        for has in [MessageHas::Attachment, MessageHas::Link] {
+            let input = ListMessages { has: vec![has, MessageHas::Mention], ..Default::default() };
+            assert_eq!(seqs(Message::list(&input, 1, &pool).await?), vec![rich.seq]);
+        }
+        let input = ListMessages { has: vec![MessageHas::Mention], ..Default::default() };
+        assert_eq!(Message::list(&input, 1, &pool).await?.len(), 2);
+        // a link in a later chunk counts too
+        let content = format!("{} see https://acme.org", "a".repeat(MESSAGE_CHUNK_BYTES));
+        let long = Message::create(&CreateMessage::new(&content), 1, 3, &pool).await?;
+        let input = ListMessages { has: vec![MessageHas::Link], ..Default::default() };
+        assert_eq!(seqs(Message::list(&input, 1, &pool).await?), vec![long.seq, rich.seq]);
+
+        let input = ListMessages { after: Some(rich.created_at), before: Some(rich.created_at), ..Default::default() };
+        assert!(matches!(Message::list(&input, 1, &pool).await, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn message_should_read_as_the_clients_message() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let msg = Message::create(&CreateMessage::new("hello"), 1, 1, &pool).await?;
+        let json = serde_json::to_value(&msg)?;
+        let shared: chat_core::Message = serde_json::from_value(json.clone())?;
+        assert_eq!(serde_json::to_value(&shared)?, json);
+        Ok(())
+    }
+}
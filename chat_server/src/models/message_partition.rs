@@ -0,0 +1,172 @@
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{AppError, Message};
+
+// rows pointing at messages, foreign keys can't enforce these since messages is partitioned
+const DEPENDENTS: &[(&str, &str)] = &[
+    ("message_chunks", "message_id"),
+    ("message_mentions", "message_id"),
+    ("message_reactions", "message_id"),
+    ("thread_subscriptions", "thread_id"),
+    ("notifications", "message_id"),
+];
+
+impl Message {
+    // makes sure the partitions for this month and the next `months_ahead` exist, inserts fail
+    // for months without one
+    pub async fn create_partitions(months_ahead: u32, pool: &PgPool) -> Result<Vec<String>, AppError> {
+        let names = sqlx::query_scalar(
+            r#"
+            SELECT create_messages_partition(((NOW() AT TIME ZONE 'UTC') + make_interval(months => m))::date)
+            FROM generate_series(0, $1) AS m
+            "#,
+        )
+        .bind(months_ahead as i32)
+        .fetch_all(pool)
+        .await?;
+        Ok(names)
+    }
+
+    // takes out the monthly partitions that end before `before`, along with everything pointing
+    // at their messages. Detached partitions stay around as plain tables to be archived, unless
+    // `drop` is set. Returns the partitions taken out.
+    pub async fn expire_partitions(before: DateTime<Utc>, drop: bool, pool: &PgPool) -> Result<Vec<String>, AppError> {
+        let names: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT c.relname::text
+            FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid
+            WHERE i.inhparent = 'messages'::regclass AND c.relname ~ '^messages_\d{4}_\d{2}$'
+                AND (to_date(substr(c.relname, 10), 'YYYY_MM') + interval '1 month') AT TIME ZONE 'UTC' <= $1
+            ORDER BY c.relname
+            "#,
+        )
+        .bind(before)
+        .fetch_all(pool)
+        .await?;
+
+        for name in &names {
+            let mut tx = pool.begin().await?;
+            for (table, column) in DEPENDENTS {
+                sqlx::query(&format!("DELETE FROM {} WHERE {} IN (SELECT id FROM \"{}\")", table, column, name))
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            // replies sent after the cutoff stay, as top level messages
+            sqlx::query(&format!("UPDATE messages SET thread_id = NULL WHERE thread_id IN (SELECT id FROM \"{}\")", name))
+                .execute(&mut *tx)
+                .await?;
+            // detaching fires no delete triggers, the chunks were booked when deleted above
+            sqlx::query(&format!(
+                "SELECT book_storage_delta(chat_id, -SUM(octet_length(content))::bigint) FROM \"{}\" GROUP BY chat_id",
+                name
+            ))
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(&format!("ALTER TABLE messages DETACH PARTITION \"{}\"", name))
+                .execute(&mut *tx)
+                .await?;
+            remove_dangling_pins(&mut tx).await?;
+            if drop {
+                sqlx::query(&format!("DELETE FROM message_ids WHERE id IN (SELECT id FROM \"{}\")", name))
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query(&format!("DROP TABLE \"{}\"", name)).execute(&mut *tx).await?;
+            }
+            tx.commit().await?;
+        }
+        Ok(names)
+    }
+}
+
+// pins of messages no longer in the table, such as those of a partition just taken out or one
+// dropped by hand. The pins left in those chats are numbered from 0 again.
+async fn remove_dangling_pins(tx: &mut Transaction<'_, Postgres>) -> Result<(), AppError> {
+    let chats: Vec<i64> = sqlx::query_scalar(
+        r#"
+        DELETE FROM chat_pins p
+        WHERE NOT EXISTS (SELECT 1 FROM messages m WHERE m.id = p.message_id)
+        RETURNING p.chat_id
+        "#,
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+    if chats.is_empty() {
+        return Ok(());
+    }
+    sqlx::query(
+        r#"
+        UPDATE chat_pins p SET position = r.position
+        FROM (
+            SELECT chat_id, message_id, (row_number() OVER (PARTITION BY chat_id ORDER BY position) - 1)::int AS position
+            FROM chat_pins
+            WHERE chat_id = ANY($1)
+        ) r
+        WHERE p.chat_id = r.chat_id AND p.message_id = r.message_id AND p.position <> r.position
+        "#,
+    )
+    .bind(&chats)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+    use chrono::{Datelike, Months, TimeZone};
+
+    #[tokio::test]
+    async fn expire_partitions_should_remove_old_months() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let names = Message::create_partitions(2, &pool).await?;
+        assert_eq!(names.len(), 3);
+
+        let now = Utc::now();
+        let this_month = Utc.with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0).unwrap();
+        let old = this_month - Months::new(3);
+        sqlx::query("SELECT create_messages_partition($1::date)").bind(old).execute(&pool).await?;
+        let id: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO messages (chat_id, sender_id, seq, content, images, created_at)
+            VALUES (1, 1, 100, 'old', '{}', $1)
+            RETURNING id
+            "#,
+        )
+        .bind(old)
+        .fetch_one(&pool)
+        .await?;
+        sqlx::query("INSERT INTO message_reactions (message_id, user_id, emoji) VALUES ($1, 1, 'x')")
+            .bind(id)
+            .execute(&pool)
+            .await?;
+        sqlx::query("INSERT INTO chat_pins (chat_id, message_id, position, pinned_by) VALUES (1, $1, 0, 1), (1, 1, 1, 1)")
+            .bind(id)
+            .execute(&pool)
+            .await?;
+        // ids stay unique across partitions
+        let ret = sqlx::query("INSERT INTO messages (id, chat_id, sender_id, seq, content, created_at) VALUES ($1, 1, 1, 101, 'dup', NOW())")
+            .bind(id)
+            .execute(&pool)
+            .await;
+        assert!(ret.is_err());
+
+        let expired = Message::expire_partitions(this_month, true, &pool).await?;
+        assert_eq!(expired, vec![format!("messages_{}", old.format("%Y_%m"))]);
+        let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM message_reactions WHERE message_id = $1")
+            .bind(id)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(left, 0);
+        let pins: Vec<(i64, i32)> = sqlx::query_as("SELECT message_id, position FROM chat_pins WHERE chat_id = 1")
+            .fetch_all(&pool)
+            .await?;
+        assert_eq!(pins, [(1, 0)]);
+        // the current month's messages are untouched
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages").fetch_one(&pool).await?;
+        assert_eq!(count, 10);
+        Ok(())
+    }
+}
@@ -4,10 +4,99 @@ use sqlx::FromRow;
 
 mod user;
 mod workspace;
+mod activity;
+mod admin;
+mod approval;
+mod audit;
+mod auth_event;
+mod bot;
+mod bridge;
 mod chat;
+mod chat_settings;
+mod directory;
+mod email_change;
+mod email_notification;
+mod emoji;
+mod feed;
+mod incoming_webhook;
+mod invite;
+mod magic_link;
+mod ip_allowlist;
+mod ldap;
+mod mail_settings;
+mod matrix;
+mod mention;
+mod message;
+mod message_partition;
+mod moderation;
+mod notification;
+mod oauth;
+mod oauth_app;
+mod passkey;
+mod password;
+mod pin;
+mod reaction;
+mod refresh_token;
+mod revoked_token;
+mod scim;
+mod security;
+mod session;
+mod session_policy;
+mod slash_command;
+mod sso;
+mod thread;
+mod two_factor;
+mod verification;
+mod webhook;
+mod workspace_clone;
 
-pub use user::{CreateUser, SigninUser};
-pub use chat::CreateChat;
+pub use activity::{ActivityPage, ListActivity};
+pub use admin::{AdminStats, ListWorkspaceUsers};
+pub use approval::{ActionReport, DestructiveAction, SubmitAction, Submitted, UpdateMemberRole};
+pub use audit::{CreateAuditLog, ListAuditLogs};
+pub use auth_event::{CreateAuthEvent, ListAuthEvents};
+pub use bot::CreateBot;
+pub(crate) use bot::BOT_TOKEN_PREFIX;
+pub use bridge::RemoteIdentity;
+pub use user::{check_email, hash_password, verify_password, CreateUser};
+pub use chat::{AddChatMember, CreateChat, UpdateChat};
+pub use chat_settings::UpdateChatSettings;
+pub use directory::ExportMembers;
+pub use email_change::{ChangeEmail, ConfirmEmailChange};
+pub(crate) use email_notification::DueNotification;
+pub use emoji::CreateCustomEmoji;
+pub use feed::{revoke_feed_tokens, FeedEntry, ReadFeed, UpdateChatFeed};
+pub use incoming_webhook::{CreateIncomingWebhook, IncomingWebhookMessage};
+pub use invite::CreateChatInvite;
+pub use ip_allowlist::UpdateIpAllowlist;
+pub use magic_link::{MagicLinkSignin, RequestMagicLink};
+pub use mail_settings::{UpdateMailSettings, VerifyMailSettings};
+pub use matrix::{LinkMatrixRoom, UpdateMatrixBridge};
+pub(crate) use matrix::{DueRoom, MatrixHomeserver, MatrixPuppet};
+pub use mention::{ListMentions, MarkMentionsRead};
+pub(crate) use message::IntegrationSender;
+pub use moderation::UpdateModerationPolicy;
+pub use notification::ListNotifications;
+pub use oauth::{OAuthCallback, OAuthLogin};
+pub use oauth_app::{AuthorizeApp, ConsentInput, ConsentOutput, ConsentRequest, CreateOAuthApp};
+pub use passkey::{FinishPasskeyRegistration, FinishPasskeySignin, StartPasskeySignin};
+pub use password::ResetPassword;
+pub use pin::{PinMessage, ReorderPins};
+pub use reaction::CreateReaction;
+pub use refresh_token::RefreshTokenInput;
+pub use scim::{IssuedScimToken, ProvisionGroup, ProvisionUser, ScimFilter};
+pub use session::ClientInfo;
+pub use session_policy::UpdateSessionPolicy;
+pub use slash_command::CreateSlashCommand;
+pub(crate) use slash_command::is_command_name;
+pub use sso::{SsoLogin, UpdateOidcConfig};
+pub use two_factor::{SigninChallenge, TotpEnrollment, TwoFactorCode};
+pub use webhook::{CreateWebhook, ListWebhookDeliveries};
+pub(crate) use webhook::DueDelivery;
+pub use workspace_clone::CloneWorkspace;
+pub use workspace::{ListChatUsers, SignupDomain, UpdateWorkspace, WorkspaceSettings, WorkspaceStats};
+// shared with clients
+pub use chat_core::{ChatSummary, ChatType, CreateMessage, ListMessages, MarkChatRead, MessageHas, MessageKind, MessageSort, SigninUser};
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
 pub struct User {
@@ -21,10 +110,259 @@ pub struct User {
     pub created_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, FromRow, PartialEq)]
+pub struct RefreshToken {
+    pub id: i64,
+    pub family_id: String,
+    pub user_id: i64,
+    pub session_started_at: DateTime<Utc>,
+    pub remember_me: bool,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+// a signed in device, i.e. a refresh token family
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct UserSession {
+    pub id: i64,
+    pub device_name: String,
+    pub user_agent: String,
+    pub ip: Option<String>,
+    pub last_active_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    // the session the request was made with
+    pub current: bool,
+}
+
+#[derive(Debug, Clone, FromRow, PartialEq)]
+pub struct RevokedToken {
+    pub jti: String,
+    pub user_id: i64,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Webhook {
+    pub id: i64,
+    pub ws_id: i64,
+    pub url: String,
+    // only set when the webhook is created
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub events: Vec<WebhookEvent>,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+// a workspace user another program acts as, with its own token, id is the user's
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Bot {
+    pub id: i64,
+    pub ws_id: i64,
+    pub name: String,
+    pub scopes: Vec<String>,
+    // only set when the bot is created or its token rotated
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+// a third party app a workspace admin registered, users let it act for them through
+// /oauth/authorize, see oauth_provider
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct OAuthApp {
+    pub id: i64,
+    pub ws_id: i64,
+    pub name: String,
+    pub client_id: String,
+    // has a secret to authenticate with, besides PKCE
+    pub confidential: bool,
+    pub redirect_uris: Vec<String>,
+    // the most it may ask for
+    pub scopes: Vec<String>,
+    // only set when the app is registered
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+// an app the user let act for them, and what they let it do
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct AuthorizedApp {
+    pub app_id: i64,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    // when the app last redeemed a code or refreshed its token
+    pub updated_at: DateTime<Utc>,
+}
+
+// a /name command of the workspace, run by calling its url
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct SlashCommand {
+    pub id: i64,
+    pub ws_id: i64,
+    pub name: String,
+    pub url: String,
+    pub description: String,
+    // only set when the command is created
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+// posts into its chat for whoever has the url, e.g. a CI system
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct IncomingWebhook {
+    pub id: i64,
+    pub chat_id: i64,
+    pub name: String,
+    // only set when the webhook is created, messages are posted to /hooks/{token}
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    // once the creator can't post in the chat any more, see IncomingWebhook::post
+    pub disabled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name="webhook_event")]
+pub enum WebhookEvent {
+    // signins from new devices and the like, see security::notify
+    #[sqlx(rename="security")]
+    #[serde(rename="security")]
+    Security,
+    #[sqlx(rename="message.created")]
+    #[serde(rename="message.created")]
+    MessageCreated,
+    #[sqlx(rename="chat.created")]
+    #[serde(rename="chat.created")]
+    ChatCreated,
+    #[sqlx(rename="member.added")]
+    #[serde(rename="member.added")]
+    MemberAdded,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name="webhook_delivery_status", rename_all="snake_case")]
+#[serde(rename_all="snake_case")]
+pub enum WebhookDeliveryStatus {
+    Pending,
+    Succeeded,
+    // gave up after the last retry
+    Failed,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event: WebhookEvent,
+    pub payload: serde_json::Value,
+    pub status: WebhookDeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    // of the last attempt, none when there was no response
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Passkey {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub sign_count: i64,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+// session lifetimes in seconds, enforced when refresh tokens are issued and rotated
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct SessionPolicy {
+    // a session ends when it is not refreshed for this long
+    pub idle_timeout: i32,
+    pub max_age: i32,
+    // max age for sessions signed in with remember me
+    pub remember_me_max_age: i32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name="moderation_mode", rename_all="snake_case")]
+#[serde(rename_all="snake_case")]
+pub enum ModerationMode {
+    #[default]
+    Off,
+    // messages with a blocked term are not sent
+    Reject,
+    // blocked terms are replaced with asterisks, the original is kept for moderators
+    Mask,
+}
+
+// applied to every message sent in the workspace
+#[derive(Debug, Clone, Default, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ModerationPolicy {
+    pub mode: ModerationMode,
+    // lowercase words, matched case insensitively against whole words
+    pub terms: Vec<String>,
+}
+
+// requests from outside these ranges are rejected once signed in, an empty list allows any address
+#[derive(Debug, Clone, Default, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct IpAllowlist {
+    // normalized CIDR ranges, e.g. 10.0.0.0/8
+    pub cidrs: Vec<String>,
+}
+
+// the client secret is write only
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct OidcConfig {
+    pub ws_id: i64,
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(skip)]
+    pub client_secret: String,
+    pub jit_provisioning: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// the smtp password is write only
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct MailSettings {
+    pub ws_id: i64,
+    pub smtp_host: String,
+    pub smtp_port: i32,
+    pub smtp_username: String,
+    #[serde(skip)]
+    pub smtp_password: String,
+    pub from_email: String,
+    pub from_name: Option<String>,
+    pub verified_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
 pub struct Workspace {
     pub id: i64,
     pub name: String,
+    pub slug: String,
     pub owner_id: i64,
     pub created_at: DateTime<Utc>,
 }
@@ -34,15 +372,91 @@ pub struct ChatUser {
     pub id: i64,
     pub fullname: String,
     pub email: String,
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+}
+
+// a member as admins see them, deactivated ones included
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceUser {
+    pub id: i64,
+    pub fullname: String,
+    pub email: String,
+    pub role: WorkspaceRole,
+    pub password_reset_required: bool,
+    pub deactivated_at: Option<DateTime<Utc>>,
+    // the last time any of their sessions was used
+    pub last_active_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name="bridge_kind", rename_all="snake_case")]
+#[serde(rename_all="snake_case")]
+pub enum BridgeKind {
+    Matrix,
+    Slack,
+    Email,
+}
+
+// a remote identity and the (usually ghost) user its bridged messages are sent as
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct BridgeIdentity {
+    pub id: i64,
+    pub ws_id: i64,
+    pub bridge: BridgeKind,
+    pub remote_id: String,
+    pub user_id: i64,
+    pub ghost: bool,
+    pub fullname: String,
+    pub avatar_url: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// the workspace's application service on a matrix homeserver
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct MatrixBridge {
+    pub ws_id: i64,
+    pub homeserver_url: String,
+    pub server_name: String,
+    pub sender_localpart: String,
+    pub user_prefix: String,
+    pub enabled: bool,
+    // only set when the bridge is set up or its tokens are rotated, they go in the homeserver's
+    // registration file
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub as_token: Option<String>,
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hs_token: Option<String>,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// a chat mirrored to a matrix room
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct MatrixRoom {
+    pub chat_id: i64,
+    pub ws_id: i64,
+    pub room_id: String,
+    pub synced_seq: i64,
+    // why sending to the room last failed, cleared once it works again
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd, sqlx::Type)]
-#[sqlx(type_name="chat_type", rename_all="snake_case")]
-pub enum ChatType {
-    Single,
-    Group,
-    PrivateChannel,
-    PublicChannel,
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct MemberDirectoryEntry {
+    pub id: i64,
+    pub fullname: String,
+    pub email: String,
+    pub role: String,
+    pub last_active_at: Option<DateTime<Utc>>,
+    pub two_factor_enabled: bool,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
@@ -52,6 +466,318 @@ pub struct Chat {
     pub name: Option<String>,
     pub r#type: ChatType,
     pub members: Vec<i64>,
+    pub owner_id: i64,
+    pub discoverable: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ChannelSummary {
+    pub id: i64,
+    pub name: Option<String>,
+    pub r#type: ChatType,
+    pub member_count: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name="chat_member_action", rename_all="snake_case")]
+#[serde(rename_all="snake_case")]
+pub enum ChatMemberAction {
+    Join,
+    Leave,
+    Kick,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ChatMemberEvent {
+    pub id: i64,
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub actor_id: i64,
+    pub action: ChatMemberAction,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ChatInvite {
+    pub id: i64,
+    pub chat_id: i64,
+    pub token: String,
+    pub created_by: i64,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Message {
+    pub id: i64,
+    pub chat_id: i64,
+    pub sender_id: i64,
+    // per chat monotonically increasing, clients should order by this rather than created_at
+    pub seq: i64,
+    pub kind: MessageKind,
+    // root message id for thread replies
+    pub thread_id: Option<i64>,
+    pub content: String,
+    pub images: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    // when the client composed it, may predate created_at for messages written offline
+    pub client_created_at: Option<DateTime<Utc>>,
+    // shown instead of the sender's name and avatar, for integration messages
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Mention {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub message: Message,
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct PinnedMessage {
+    pub message_id: i64,
+    pub sender_id: i64,
+    pub preview: String,
+    pub position: i32,
+    pub pinned_by: i64,
+    pub pinned_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name="notification_level", rename_all="snake_case")]
+#[serde(rename_all="snake_case")]
+pub enum NotificationLevel {
+    #[default]
+    All,
+    Mentions,
+    None,
+}
+
+// the caller's relation to a chat, admins are workspace admins who don't own it
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all="snake_case")]
+pub enum ChatRole {
+    Owner,
+    Admin,
+    Member,
+    NonMember,
+}
+
+// chat detail response, with the caller's settings and the pinned bar
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChatDetail {
+    #[serde(flatten)]
+    pub chat: Chat,
+    pub role: ChatRole,
+    pub notification_level: NotificationLevel,
+    pub member_count: i64,
+    pub pinned: Vec<PinnedMessage>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Reaction {
+    pub message_id: i64,
+    pub user_id: i64,
+    // without skin tone or presentation selectors, custom emoji are :name:
+    pub emoji: String,
+    // 1 to 5, lightest to darkest
+    pub skin_tone: Option<i16>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+    pub users: Vec<i64>,
+    // skin tone of each of the users
+    pub skin_tones: Vec<Option<i16>>,
+    // set for custom emoji that still exist
+    pub image_url: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct CustomEmoji {
+    pub id: i64,
+    pub ws_id: i64,
+    pub name: String,
+    pub image_url: String,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name="text", rename_all="snake_case")]
+#[serde(rename_all="snake_case")]
+pub enum ActivityKind {
+    Reaction,
+    Reply,
+    Invite,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Activity {
+    pub kind: ActivityKind,
+    #[serde(skip)]
+    pub key: String,
+    pub actor_id: i64,
+    pub chat_id: i64,
+    pub message_id: Option<i64>,
+    // the emoji for reactions
+    pub detail: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name="notification_kind", rename_all="snake_case")]
+#[serde(rename_all="snake_case")]
+pub enum NotificationKind {
+    ThreadReply,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Notification {
+    pub id: i64,
+    pub user_id: i64,
+    pub kind: NotificationKind,
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub read_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name="audit_action", rename_all="snake_case")]
+#[serde(rename_all="snake_case")]
+pub enum AuditAction {
+    Signin,
+    ChatCreated,
+    ChatDeleted,
+    MemberAdded,
+    MemberRemoved,
+    RoleChanged,
+    SettingsChanged,
+    ApprovalRequested,
+    ApprovalDecided,
+    WorkspaceCloned,
+    UserDeactivated,
+    UserReactivated,
+    PasswordResetForced,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name="workspace_role", rename_all="snake_case")]
+#[serde(rename_all="snake_case")]
+pub enum WorkspaceRole {
+    #[default]
+    Member,
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name="approval_status", rename_all="snake_case")]
+#[serde(rename_all="snake_case")]
+pub enum ApprovalStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+// a destructive action waiting for, or decided by, a second admin
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct AdminApproval {
+    pub id: i64,
+    pub ws_id: i64,
+    pub action: sqlx::types::Json<DestructiveAction>,
+    pub requested_by: i64,
+    pub status: ApprovalStatus,
+    pub decided_by: Option<i64>,
+    pub decided_at: Option<DateTime<Utc>>,
+    pub expires_at: DateTime<Utc>,
+    // what the action did, once approved
+    pub report: Option<sqlx::types::Json<ActionReport>>,
+    pub created_at: DateTime<Utc>,
+    // still pending but past expires_at
+    pub expired: bool,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct AuditLog {
+    pub id: i64,
+    pub ws_id: i64,
+    pub actor_id: i64,
+    pub action: AuditAction,
+    pub target_id: Option<i64>,
+    pub details: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name="auth_event_kind", rename_all="snake_case")]
+#[serde(rename_all="snake_case")]
+pub enum AuthEventKind {
+    Signin,
+    SigninFailed,
+    PasswordChanged,
+    TokenRefreshed,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    TwoFactorFailed,
+    RecoveryCodesRegenerated,
+    EmailChanged,
+}
+
+// something that happened to a user's credentials, shown to the user and workspace admins
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct AuthEvent {
+    pub id: i64,
+    pub ws_id: i64,
+    pub user_id: i64,
+    pub kind: AuthEventKind,
+    pub ip: Option<String>,
+    pub user_agent: String,
+    pub details: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+// a member as the identity provider sees them through scim
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ScimUser {
+    pub id: i64,
+    pub ws_id: i64,
+    pub email: String,
+    pub fullname: String,
+    pub external_id: Option<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+// a group provisioned through scim, its members are added to the backing channel
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ScimGroup {
+    pub id: i64,
+    pub ws_id: i64,
+    pub chat_id: i64,
+    pub display_name: String,
+    pub external_id: Option<String>,
+    pub members: Vec<i64>,
     pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
 }
 
+// the scim token itself is write only
+#[derive(Debug, Clone, Default, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ScimSettings {
+    // None while provisioning is off
+    pub token_created_at: Option<DateTime<Utc>>,
+}
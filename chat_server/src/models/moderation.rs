@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{AppError, ModerationMode, ModerationPolicy};
+
+const MAX_TERMS: usize = 500;
+const MAX_TERM_CHARS: usize = 64;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateModerationPolicy {
+    #[serde(default)]
+    pub mode: Option<ModerationMode>,
+    // replaces the current list
+    #[serde(default)]
+    pub terms: Option<Vec<String>>,
+}
+
+impl ModerationPolicy {
+    pub async fn fetch(ws_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let policy: Option<Self> = sqlx::query_as(
+            "SELECT moderation_mode AS mode, moderation_terms AS terms FROM workspaces WHERE id = $1",
+        )
+        .bind(ws_id as i64)
+        .fetch_optional(pool)
+        .await?;
+        policy.ok_or_else(|| AppError::NotFound(format!("workspace not found: {}", ws_id)))
+    }
+
+    pub async fn update(ws_id: u64, input: &UpdateModerationPolicy, pool: &PgPool) -> Result<Self, AppError> {
+        let current = Self::fetch(ws_id, pool).await?;
+        let mut terms = match &input.terms {
+            Some(terms) => terms.iter().map(|t| t.trim().to_lowercase()).filter(|t| !t.is_empty()).collect(),
+            None => current.terms,
+        };
+        terms.sort();
+        terms.dedup();
+        if terms.len() > MAX_TERMS {
+            return Err(AppError::UpdateWorkspaceError(format!(
+                "At most {} blocked terms are allowed",
+                MAX_TERMS
+            )));
+        }
+        if let Some(term) = terms
+            .iter()
+            .find(|t| t.chars().count() > MAX_TERM_CHARS || !t.chars().all(char::is_alphanumeric))
+        {
+            return Err(AppError::UpdateWorkspaceError(format!(
+                "Blocked terms must be single words of at most {} characters: {}",
+                MAX_TERM_CHARS, term
+            )));
+        }
+        let policy = Self {
+            mode: input.mode.unwrap_or(current.mode),
+            terms,
+        };
+
+        sqlx::query("UPDATE workspaces SET moderation_mode = $2, moderation_terms = $3 WHERE id = $1")
+            .bind(ws_id as i64)
+            .bind(policy.mode)
+            .bind(&policy.terms)
+            .execute(pool)
+            .await?;
+        Ok(policy)
+    }
+
+    // the content to store, masked if needed, or an error if it may not be sent at all
+    pub fn apply(&self, content: &str) -> Result<Option<String>, AppError> {
+        if self.mode == ModerationMode::Off || self.terms.is_empty() {
+            return Ok(None);
+        }
+        let flagged = self.flagged_words(content);
+        if flagged.is_empty() {
+            return Ok(None);
+        }
+        match self.mode {
+            ModerationMode::Off => Ok(None),
+            ModerationMode::Reject => Err(AppError::CreateMessageError(
+                "Content contains terms blocked in this workspace".to_string(),
+            )),
+            ModerationMode::Mask => {
+                let mut masked = String::with_capacity(content.len());
+                let mut last = 0;
+                for (start, end) in flagged {
+                    masked.push_str(&content[last..start]);
+                    masked.extend(std::iter::repeat_n('*', content[start..end].chars().count()));
+                    last = end;
+                }
+                masked.push_str(&content[last..]);
+                Ok(Some(masked))
+            }
+        }
+    }
+
+    // byte ranges of the words that are blocked terms
+    fn flagged_words(&self, content: &str) -> Vec<(usize, usize)> {
+        let mut flagged = vec![];
+        let mut start = None;
+        for (i, c) in content.char_indices().chain([(content.len(), ' ')]) {
+            match (c.is_alphanumeric(), start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    if self.terms.contains(&content[s..i].to_lowercase()) {
+                        flagged.push((s, i));
+                    }
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        flagged
+    }
+}
+
+pub(super) async fn workspace_policy(tx: &mut Transaction<'_, Postgres>, ws_id: i64) -> Result<ModerationPolicy, AppError> {
+    let policy = sqlx::query_as("SELECT moderation_mode AS mode, moderation_terms AS terms FROM workspaces WHERE id = $1")
+        .bind(ws_id)
+        .fetch_one(&mut **tx)
+        .await?;
+    Ok(policy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    fn policy(mode: ModerationMode) -> ModerationPolicy {
+        ModerationPolicy {
+            mode,
+            terms: vec!["darn".to_string(), "heck".to_string()],
+        }
+    }
+
+    #[test]
+    fn moderation_should_mask_or_reject_whole_words() -> Result<()> {
+        let mask = policy(ModerationMode::Mask);
+        assert_eq!(mask.apply("what the Heck, darn it")?.as_deref(), Some("what the ****, **** it"));
+        // only whole words are flagged
+        assert_eq!(mask.apply("darning socks")?, None);
+
+        let reject = policy(ModerationMode::Reject);
+        assert!(matches!(reject.apply("heck"), Err(AppError::CreateMessageError(_))));
+        assert_eq!(reject.apply("hello")?, None);
+        assert_eq!(policy(ModerationMode::Off).apply("heck")?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn moderation_policy_should_update() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        assert_eq!(ModerationPolicy::fetch(1, &pool).await?, ModerationPolicy::default());
+
+        let input = UpdateModerationPolicy {
+            mode: Some(ModerationMode::Mask),
+            terms: Some(vec!["Heck".to_string(), " darn ".to_string(), "heck".to_string()]),
+        };
+        let policy = ModerationPolicy::update(1, &input, &pool).await?;
+        assert_eq!(policy.terms, vec!["darn", "heck"]);
+        assert_eq!(ModerationPolicy::fetch(1, &pool).await?, policy);
+
+        let input = UpdateModerationPolicy {
+            terms: Some(vec!["two words".to_string()]),
+            ..Default::default()
+        };
+        let ret = ModerationPolicy::update(1, &input, &pool).await;
+        assert!(matches!(ret, Err(AppError::UpdateWorkspaceError(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{AppError, Notification};
+
+const DEFAULT_NOTIFICATION_LIMIT: u64 = 50;
+const MAX_NOTIFICATION_LIMIT: u64 = 200;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListNotifications {
+    #[serde(default)]
+    pub unread_only: bool,
+    #[serde(default)]
+    pub last_id: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+impl Notification {
+    pub async fn list(user_id: u64, input: &ListNotifications, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let limit = input.limit.unwrap_or(DEFAULT_NOTIFICATION_LIMIT).min(MAX_NOTIFICATION_LIMIT);
+        let notifications = sqlx::query_as(
+            r#"
+            SELECT id, user_id, kind, chat_id, message_id, read_at, created_at
+            FROM notifications
+            WHERE user_id = $1 AND id < $2 AND (NOT $3 OR read_at IS NULL)
+            ORDER BY id DESC
+            LIMIT $4
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(input.last_id.unwrap_or(i64::MAX))
+        .bind(input.unread_only)
+        .bind(limit as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(notifications)
+    }
+
+    pub async fn mark_all_read(user_id: u64, pool: &PgPool) -> Result<u64, AppError> {
+        let ret = sqlx::query("UPDATE notifications SET read_at = NOW() WHERE user_id = $1 AND read_at IS NULL")
+            .bind(user_id as i64)
+            .execute(pool)
+            .await?;
+        Ok(ret.rows_affected())
+    }
+}
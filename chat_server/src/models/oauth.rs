@@ -0,0 +1,188 @@
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{oauth::OAuthIdentity, utils::generate_token, AppError, User, Workspace};
+
+const STATE_TOKEN_BYTES: usize = 32;
+const CODE_VERIFIER_BYTES: usize = 48;
+const STATE_TTL_SECS: i64 = 600;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OAuthLogin {
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuthCallback {
+    #[serde(default)]
+    pub code: Option<String>,
+    pub state: String,
+    // set by the provider when the user declined
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl User {
+    // returns the state to send to the provider and the PKCE code verifier
+    pub async fn create_oauth_state(provider: &str, remember_me: bool, pool: &PgPool) -> Result<(String, String), AppError> {
+        let state = generate_token(STATE_TOKEN_BYTES);
+        let code_verifier = generate_token(CODE_VERIFIER_BYTES);
+        sqlx::query(
+            r#"
+            INSERT INTO oauth_states (state_hash, provider, code_verifier, remember_me, expires_at)
+            VALUES (sha256(convert_to($1, 'UTF8')), $2, $3, $4, $5)
+            "#,
+        )
+        .bind(&state)
+        .bind(provider)
+        .bind(&code_verifier)
+        .bind(remember_me)
+        .bind(Utc::now() + Duration::seconds(STATE_TTL_SECS))
+        .execute(pool)
+        .await?;
+        Ok((state, code_verifier))
+    }
+
+    // returns the code verifier and whether the user asked to be remembered
+    pub async fn redeem_oauth_state(state: &str, provider: &str, pool: &PgPool) -> Result<(String, bool), AppError> {
+        let row = sqlx::query_as(
+            r#"
+            DELETE FROM oauth_states
+            WHERE state_hash = sha256(convert_to($1, 'UTF8')) AND provider = $2 AND expires_at > NOW()
+            RETURNING code_verifier, remember_me
+            "#,
+        )
+        .bind(state)
+        .bind(provider)
+        .fetch_optional(pool)
+        .await?;
+        row.ok_or_else(|| AppError::PermissionDenied("oauth state is invalid or has expired".to_string()))
+    }
+
+    // the linked user, else the user with the same (provider verified) email, else a new user in
+    // the workspace that claimed the email's domain
+    pub(crate) async fn from_oauth_identity(provider: &str, identity: &OAuthIdentity, pool: &PgPool) -> Result<Self, AppError> {
+        let mut tx = pool.begin().await?;
+        let linked: Option<User> = sqlx::query_as(
+            r#"
+            SELECT u.id, u.ws_id, u.fullname, u.email, u.created_at
+            FROM user_identities i JOIN users u ON u.id = i.user_id
+            WHERE i.provider = $1 AND i.subject = $2
+            "#,
+        )
+        .bind(provider)
+        .bind(&identity.subject)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(user) = linked {
+            return Ok(user);
+        }
+
+        let existing: Option<User> = sqlx::query_as("SELECT id, ws_id, fullname, email, created_at FROM users WHERE email = $1")
+            .bind(&identity.email)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let user = match existing {
+            Some(user) => {
+                sqlx::query("UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW()) WHERE id = $1")
+                    .bind(user.id)
+                    .execute(&mut *tx)
+                    .await?;
+                user
+            }
+            None => {
+                let domain = identity.email.rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+                let ws = Workspace::find_by_signup_domain(domain, pool)
+                    .await?
+                    .ok_or_else(|| AppError::PermissionDenied(format!("no workspace accepts signups from {}", domain)))?;
+                if identity.email.len() > 64 {
+                    return Err(AppError::InvalidInput(format!("email is too long: {}", identity.email)));
+                }
+                let fullname: String = identity.name.chars().take(64).collect();
+                sqlx::query_as(
+                    r#"
+                    INSERT INTO users (ws_id, email, fullname, email_verified_at)
+                    VALUES ($1, $2, $3, NOW())
+                    RETURNING id, ws_id, fullname, email, created_at
+                    "#,
+                )
+                .bind(ws.id)
+                .bind(&identity.email)
+                .bind(&fullname)
+                .fetch_one(&mut *tx)
+                .await?
+            }
+        };
+        sqlx::query("INSERT INTO user_identities (provider, subject, user_id, email) VALUES ($1, $2, $3, $4)")
+            .bind(provider)
+            .bind(&identity.subject)
+            .bind(user.id)
+            .bind(&identity.email)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::PasswordHashing, test_util::get_test_pool, SigninUser};
+    use anyhow::Result;
+
+    fn identity(subject: &str, email: &str) -> OAuthIdentity {
+        OAuthIdentity {
+            subject: subject.to_string(),
+            email: email.to_string(),
+            name: "Oauth User".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn oauth_state_should_be_single_use() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let (state, verifier) = User::create_oauth_state("github", true, &pool).await?;
+        assert!(User::redeem_oauth_state(&state, "google", &pool).await.is_err());
+        assert_eq!(User::redeem_oauth_state(&state, "github", &pool).await?, (verifier, true));
+        assert!(User::redeem_oauth_state(&state, "github", &pool).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oauth_identity_should_link_or_provision_users() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        // existing users are linked by email
+        let user = User::from_oauth_identity("google", &identity("g-1", "alice@acme.org"), &pool).await?;
+        assert_eq!(user.email, "alice@acme.org");
+        let again = User::from_oauth_identity("google", &identity("g-1", "renamed@gmail.com"), &pool).await?;
+        assert_eq!(again.id, user.id);
+
+        let ret = User::from_oauth_identity("github", &identity("1", "new@acme.org"), &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ws = Workspace::find_by_id(1, &pool).await?.expect("workspace should exist");
+        let signup = ws.set_signup_domain("@ACME.org", &pool).await?;
+        assert_eq!(signup.pending_domain.as_deref(), Some("acme.org"));
+        assert_eq!(signup.record_name.as_deref(), Some("_chat-verification.acme.org"));
+        // nobody joins until the domain is verified
+        let ret = User::from_oauth_identity("github", &identity("1", "new@acme.org"), &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        assert!(ws.verify_signup_domain(&["v=spf1 -all".to_string()], &pool).await.is_err());
+        let records = vec![signup.record_value.clone().unwrap_or_default()];
+        let signup = ws.verify_signup_domain(&records, &pool).await?;
+        assert_eq!(signup.domain.as_deref(), Some("acme.org"));
+        assert!(signup.pending_domain.is_none());
+        let user = User::from_oauth_identity("github", &identity("1", "new@acme.org"), &pool).await?;
+        assert_eq!(user.ws_id, ws.id);
+        assert!(user.is_email_verified(&pool).await?);
+        // without a password, password signin fails instead of erroring
+        let input = SigninUser::new("new@acme.org", "");
+        assert!(User::verify(&input, &PasswordHashing::default(), &pool).await?.is_none());
+
+        let other = Workspace::find_by_id(2, &pool).await?.expect("workspace should exist");
+        assert!(other.set_signup_domain("acme.org", &pool).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,564 @@
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+use crate::{oauth::pkce_challenge, utils::{constant_time_eq, generate_token, TokenId}, AppError, AuthorizedApp, ChatRead, ChatWrite, OAuthApp, Scope, User};
+
+const CLIENT_ID_BYTES: usize = 16;
+const CLIENT_SECRET_BYTES: usize = 32;
+const CODE_BYTES: usize = 32;
+const REFRESH_TOKEN_BYTES: usize = 32;
+// apps redeem the code as soon as the browser is back
+const CODE_TTL_SECS: i64 = 60;
+const MAX_APPS_PER_WORKSPACE: i64 = 50;
+const MAX_REDIRECT_URIS: usize = 10;
+// apps never administer the workspace
+const APP_SCOPES: &[&str] = &[ChatRead::NAME, ChatWrite::NAME];
+
+const SELECT_APP: &str = r#"
+    SELECT id, ws_id, name, client_id, client_secret_hash IS NOT NULL AS confidential, redirect_uris, scopes,
+        created_by, created_at
+    FROM oauth_apps
+"#;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateOAuthApp {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    // what members get when left out
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    // false for apps that can't keep a secret, mobile and single page apps
+    #[serde(default = "default_confidential")]
+    pub confidential: bool,
+}
+
+// the query the app sent the user's browser with, see RFC 6749 section 4.1.1. PKCE (RFC 7636)
+// is required of every app.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizeApp {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    // space separated, everything the app may ask for when left out
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub state: Option<String>,
+    pub code_challenge: String,
+    // only S256
+    #[serde(default)]
+    pub code_challenge_method: Option<String>,
+}
+
+// what the consent screen shows
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ConsentRequest {
+    pub app_id: i64,
+    pub name: String,
+    pub scopes: Vec<String>,
+    // the user already let the app do all of it, the screen can be skipped
+    pub consented: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsentInput {
+    #[serde(flatten)]
+    pub request: AuthorizeApp,
+    pub approve: bool,
+}
+
+// where to send the browser, back to the app with a code or with access_denied
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ConsentOutput {
+    pub redirect_to: String,
+}
+
+// what an access token for the app is issued for, see oauth_provider
+#[derive(Debug)]
+pub(crate) struct AppToken {
+    pub(crate) user: User,
+    pub(crate) scopes: Vec<String>,
+    pub(crate) refresh_token: String,
+}
+
+#[derive(Debug, FromRow)]
+struct GrantUser {
+    #[sqlx(flatten)]
+    user: User,
+    scopes: Vec<String>,
+}
+
+#[derive(Debug, FromRow)]
+struct Code {
+    user_id: i64,
+    redirect_uri: String,
+    scopes: Vec<String>,
+    code_challenge: String,
+    expires_at: DateTime<Utc>,
+}
+
+fn default_scopes() -> Vec<String> {
+    APP_SCOPES.iter().map(|s| s.to_string()).collect()
+}
+
+fn default_confidential() -> bool {
+    true
+}
+
+impl OAuthApp {
+    // the secret of a confidential app is only returned here
+    pub async fn create(ws_id: u64, input: &CreateOAuthApp, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let name = input.name.trim();
+        if name.is_empty() || name.chars().count() > 64 {
+            return Err(AppError::InvalidInput("app name must be 1 to 64 characters".to_string()));
+        }
+        if input.redirect_uris.is_empty() || input.redirect_uris.len() > MAX_REDIRECT_URIS {
+            return Err(AppError::InvalidInput(format!(
+                "an app needs 1 to {} redirect uris",
+                MAX_REDIRECT_URIS
+            )));
+        }
+        for uri in &input.redirect_uris {
+            validate_redirect_uri(uri)?;
+        }
+        if input.scopes.is_empty() {
+            return Err(AppError::InvalidInput("an app needs at least one scope".to_string()));
+        }
+        validate_scopes(&input.scopes)?;
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM oauth_apps WHERE ws_id = $1")
+            .bind(ws_id as i64)
+            .fetch_one(pool)
+            .await?;
+        if count >= MAX_APPS_PER_WORKSPACE {
+            return Err(AppError::InvalidInput(format!(
+                "a workspace can have at most {} apps",
+                MAX_APPS_PER_WORKSPACE
+            )));
+        }
+
+        let client_id = generate_token(CLIENT_ID_BYTES);
+        let secret = input.confidential.then(|| generate_token(CLIENT_SECRET_BYTES));
+        let id: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO oauth_apps (ws_id, name, client_id, client_secret_hash, redirect_uris, scopes, created_by)
+            VALUES ($1, $2, $3, sha256(convert_to($4, 'UTF8')), $5, $6, $7)
+            RETURNING id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(name)
+        .bind(&client_id)
+        .bind(&secret)
+        .bind(&input.redirect_uris)
+        .bind(&input.scopes)
+        .bind(user_id as i64)
+        .fetch_one(pool)
+        .await?;
+
+        let mut app = Self::find(ws_id, id as _, pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("app {}", id)))?;
+        app.client_secret = secret;
+        Ok(app)
+    }
+
+    pub async fn find(ws_id: u64, id: u64, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let app = sqlx::query_as(&format!("{} WHERE ws_id = $1 AND id = $2", SELECT_APP))
+            .bind(ws_id as i64)
+            .bind(id as i64)
+            .fetch_optional(pool)
+            .await?;
+        Ok(app)
+    }
+
+    pub async fn list(ws_id: u64, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let apps = sqlx::query_as(&format!("{} WHERE ws_id = $1 ORDER BY id", SELECT_APP))
+            .bind(ws_id as i64)
+            .fetch_all(pool)
+            .await?;
+        Ok(apps)
+    }
+
+    // with its codes and grants, the access tokens it holds are revoked
+    pub async fn delete(ws_id: u64, id: u64, pool: &PgPool) -> Result<(), AppError> {
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO revoked_tokens (jti, user_id, expires_at)
+            SELECT t.jti, t.user_id, t.expires_at
+            FROM oauth_access_tokens t JOIN oauth_apps a ON a.id = t.app_id
+            WHERE a.ws_id = $1 AND a.id = $2 AND t.expires_at > NOW()
+            ON CONFLICT (jti) DO NOTHING
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(id as i64)
+        .execute(&mut *tx)
+        .await?;
+        let ret = sqlx::query("DELETE FROM oauth_apps WHERE ws_id = $1 AND id = $2")
+            .bind(ws_id as i64)
+            .bind(id as i64)
+            .execute(&mut *tx)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("app {}", id)));
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // kept until it expires, so that revoking the app can revoke it too
+    pub(crate) async fn record_access_token(&self, user_id: i64, id: &TokenId, pool: &PgPool) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM oauth_access_tokens WHERE expires_at < NOW()")
+            .execute(pool)
+            .await?;
+        sqlx::query("INSERT INTO oauth_access_tokens (jti, app_id, user_id, expires_at) VALUES ($1, $2, $3, $4)")
+            .bind(&id.jti)
+            .bind(self.id)
+            .bind(user_id)
+            .bind(id.expires_at)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    // a confidential app must send its secret, a public one has none to send
+    pub(crate) async fn authenticate(client_id: &str, secret: Option<&str>, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let app = sqlx::query_as(&format!(
+            r#"
+            {}
+            WHERE client_id = $1
+                AND client_secret_hash IS NOT DISTINCT FROM sha256(convert_to($2, 'UTF8'))
+            "#,
+            SELECT_APP
+        ))
+        .bind(client_id)
+        .bind(secret)
+        .fetch_optional(pool)
+        .await?;
+        Ok(app)
+    }
+
+    // single use, even when redeeming fails. The verifier must match the challenge of the
+    // authorization request.
+    pub(crate) async fn redeem_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+        pool: &PgPool,
+    ) -> Result<AppToken, AppError> {
+        let row: Option<Code> = sqlx::query_as(
+            r#"
+            DELETE FROM oauth_codes
+            WHERE code_hash = sha256(convert_to($1, 'UTF8')) AND app_id = $2
+            RETURNING user_id, redirect_uri, scopes, code_challenge, expires_at
+            "#,
+        )
+        .bind(code)
+        .bind(self.id)
+        .fetch_optional(pool)
+        .await?;
+        let code = match row {
+            Some(code) if code.expires_at > Utc::now() => code,
+            _ => return Err(AppError::PermissionDenied("code is invalid or has expired".to_string())),
+        };
+        if code.redirect_uri != redirect_uri {
+            return Err(AppError::PermissionDenied("redirect_uri doesn't match the authorization request".to_string()));
+        }
+        if !constant_time_eq(pkce_challenge(code_verifier).as_bytes(), code.code_challenge.as_bytes()) {
+            return Err(AppError::PermissionDenied("code_verifier doesn't match the code challenge".to_string()));
+        }
+        let refresh_token = generate_token(REFRESH_TOKEN_BYTES);
+        // the grant is gone when the user revoked the app since approving
+        let grant: Option<GrantUser> = sqlx::query_as(
+            r#"
+            UPDATE oauth_grants g
+            SET refresh_token_hash = sha256(convert_to($3, 'UTF8')), updated_at = NOW()
+            FROM users u
+            WHERE g.app_id = $1 AND g.user_id = $2 AND u.id = g.user_id AND u.deactivated_at IS NULL
+            RETURNING u.id, u.ws_id, u.fullname, u.email, u.created_at, g.scopes
+            "#,
+        )
+        .bind(self.id)
+        .bind(code.user_id)
+        .bind(&refresh_token)
+        .fetch_optional(pool)
+        .await?;
+        let Some(grant) = grant else {
+            return Err(AppError::PermissionDenied("the user no longer allows this app".to_string()));
+        };
+        Ok(AppToken { user: grant.user, scopes: code.scopes, refresh_token })
+    }
+
+    // the refresh token is replaced, the old one stops working
+    pub(crate) async fn refresh(&self, refresh_token: &str, pool: &PgPool) -> Result<AppToken, AppError> {
+        let next = generate_token(REFRESH_TOKEN_BYTES);
+        let grant: Option<GrantUser> = sqlx::query_as(
+            r#"
+            UPDATE oauth_grants g
+            SET refresh_token_hash = sha256(convert_to($3, 'UTF8')), updated_at = NOW()
+            FROM users u
+            WHERE g.app_id = $1 AND g.refresh_token_hash = sha256(convert_to($2, 'UTF8'))
+                AND u.id = g.user_id AND u.deactivated_at IS NULL
+            RETURNING u.id, u.ws_id, u.fullname, u.email, u.created_at, g.scopes
+            "#,
+        )
+        .bind(self.id)
+        .bind(refresh_token)
+        .bind(&next)
+        .fetch_optional(pool)
+        .await?;
+        let Some(grant) = grant else {
+            return Err(AppError::PermissionDenied("refresh token is invalid or revoked".to_string()));
+        };
+        Ok(AppToken { user: grant.user, scopes: grant.scopes, refresh_token: next })
+    }
+}
+
+impl User {
+    // what the app asks for, for the consent screen
+    pub async fn app_consent(&self, input: &AuthorizeApp, pool: &PgPool) -> Result<ConsentRequest, AppError> {
+        let (app, scopes) = self.check_authorization(input, pool).await?;
+        let granted: Option<Vec<String>> =
+            sqlx::query_scalar("SELECT scopes FROM oauth_grants WHERE app_id = $1 AND user_id = $2")
+                .bind(app.id)
+                .bind(self.id)
+                .fetch_optional(pool)
+                .await?;
+        let consented = granted.is_some_and(|granted| scopes.iter().all(|s| granted.contains(s)));
+        Ok(ConsentRequest { app_id: app.id, name: app.name, scopes, consented })
+    }
+
+    // on approval the scopes replace what the user let the app do before, and the app gets a
+    // code to redeem at /oauth/token
+    pub async fn authorize_app(&self, input: &ConsentInput, pool: &PgPool) -> Result<ConsentOutput, AppError> {
+        let request = &input.request;
+        let (app, scopes) = self.check_authorization(request, pool).await?;
+        let mut url = Url::parse(&request.redirect_uri)
+            .map_err(|_| AppError::InvalidInput(format!("invalid redirect_uri: {}", request.redirect_uri)))?;
+        if !input.approve {
+            url.query_pairs_mut().append_pair("error", "access_denied");
+        } else {
+            let code = generate_token(CODE_BYTES);
+            let mut tx = pool.begin().await?;
+            sqlx::query(
+                r#"
+                INSERT INTO oauth_grants (app_id, user_id, scopes)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (app_id, user_id) DO UPDATE SET scopes = EXCLUDED.scopes, updated_at = NOW()
+                "#,
+            )
+            .bind(app.id)
+            .bind(self.id)
+            .bind(&scopes)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(
+                r#"
+                INSERT INTO oauth_codes (code_hash, app_id, user_id, redirect_uri, scopes, code_challenge, expires_at)
+                VALUES (sha256(convert_to($1, 'UTF8')), $2, $3, $4, $5, $6, $7)
+                "#,
+            )
+            .bind(&code)
+            .bind(app.id)
+            .bind(self.id)
+            .bind(&request.redirect_uri)
+            .bind(&scopes)
+            .bind(&request.code_challenge)
+            .bind(Utc::now() + Duration::seconds(CODE_TTL_SECS))
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            url.query_pairs_mut().append_pair("code", &code);
+        }
+        if let Some(state) = &request.state {
+            url.query_pairs_mut().append_pair("state", state);
+        }
+        Ok(ConsentOutput { redirect_to: url.into() })
+    }
+
+    pub async fn authorized_apps(&self, pool: &PgPool) -> Result<Vec<AuthorizedApp>, AppError> {
+        let apps = sqlx::query_as(
+            r#"
+            SELECT g.app_id, a.name, g.scopes, g.created_at, g.updated_at
+            FROM oauth_grants g JOIN oauth_apps a ON a.id = g.app_id
+            WHERE g.user_id = $1
+            ORDER BY g.created_at
+            "#,
+        )
+        .bind(self.id)
+        .fetch_all(pool)
+        .await?;
+        Ok(apps)
+    }
+
+    // the app's refresh token and the access tokens it holds for the user stop working
+    pub async fn revoke_app(&self, app_id: u64, pool: &PgPool) -> Result<(), AppError> {
+        let mut tx = pool.begin().await?;
+        let ret = sqlx::query("DELETE FROM oauth_grants WHERE app_id = $1 AND user_id = $2")
+            .bind(app_id as i64)
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("authorized app {}", app_id)));
+        }
+        sqlx::query("DELETE FROM oauth_codes WHERE app_id = $1 AND user_id = $2")
+            .bind(app_id as i64)
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO revoked_tokens (jti, user_id, expires_at)
+            SELECT jti, user_id, expires_at FROM oauth_access_tokens
+            WHERE app_id = $1 AND user_id = $2 AND expires_at > NOW()
+            ON CONFLICT (jti) DO NOTHING
+            "#,
+        )
+        .bind(app_id as i64)
+        .bind(self.id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM oauth_access_tokens WHERE app_id = $1 AND user_id = $2")
+            .bind(app_id as i64)
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // the app of the user's workspace and the scopes it asks for. A bad client_id or
+    // redirect_uri is answered with an error rather than a redirect, so the browser is never sent
+    // anywhere the app didn't register.
+    async fn check_authorization(&self, input: &AuthorizeApp, pool: &PgPool) -> Result<(OAuthApp, Vec<String>), AppError> {
+        let app: Option<OAuthApp> = sqlx::query_as(&format!("{} WHERE client_id = $1 AND ws_id = $2", SELECT_APP))
+            .bind(&input.client_id)
+            .bind(self.ws_id)
+            .fetch_optional(pool)
+            .await?;
+        let app = app.ok_or_else(|| AppError::NotFound(format!("app {}", input.client_id)))?;
+        if !app.redirect_uris.contains(&input.redirect_uri) {
+            return Err(AppError::InvalidInput(format!("redirect_uri is not registered: {}", input.redirect_uri)));
+        }
+        if input.response_type != "code" {
+            return Err(AppError::InvalidInput(format!("unsupported response_type: {}", input.response_type)));
+        }
+        if input.code_challenge_method.as_deref() != Some("S256") {
+            return Err(AppError::InvalidInput("code_challenge_method must be S256".to_string()));
+        }
+        // the challenge of a verifier of 43 to 128 characters, see RFC 7636 section 4.2
+        if input.code_challenge.len() != 43 {
+            return Err(AppError::InvalidInput("invalid code_challenge".to_string()));
+        }
+        let scopes: Vec<String> = match input.scope.as_deref().map(str::trim) {
+            None | Some("") => app.scopes.clone(),
+            Some(scope) => scope.split_whitespace().map(str::to_string).collect(),
+        };
+        if let Some(scope) = scopes.iter().find(|s| !app.scopes.contains(s)) {
+            return Err(AppError::InvalidInput(format!("the app can't ask for the {} scope", scope)));
+        }
+        Ok((app, scopes))
+    }
+}
+
+// https, or http to the device itself for native apps, or a scheme of the app's own
+fn validate_redirect_uri(uri: &str) -> Result<(), AppError> {
+    let invalid = || AppError::InvalidInput(format!("invalid redirect uri: {}", uri));
+    let url = Url::parse(uri).map_err(|_| invalid())?;
+    if url.fragment().is_some() {
+        return Err(invalid());
+    }
+    match url.scheme() {
+        "https" => Ok(()),
+        "http" if matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]")) => Ok(()),
+        "http" | "javascript" | "data" | "file" => Err(invalid()),
+        _ => Ok(()),
+    }
+}
+
+fn validate_scopes(scopes: &[String]) -> Result<(), AppError> {
+    if let Some(scope) = scopes.iter().find(|s| !APP_SCOPES.contains(&s.as_str())) {
+        return Err(AppError::InvalidInput(format!(
+            "apps can't have the {} scope, only {}",
+            scope,
+            APP_SCOPES.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, RevokedToken};
+    use anyhow::Result;
+
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+
+    fn authorize(app: &OAuthApp, scope: &str) -> AuthorizeApp {
+        AuthorizeApp {
+            response_type: "code".to_string(),
+            client_id: app.client_id.clone(),
+            redirect_uri: "https://app.example/cb".to_string(),
+            scope: Some(scope.to_string()),
+            state: Some("xyz".to_string()),
+            code_challenge: pkce_challenge(VERIFIER),
+            code_challenge_method: Some("S256".to_string()),
+        }
+    }
+
+    fn code_of(output: &ConsentOutput) -> String {
+        let url = Url::parse(&output.redirect_to).expect("redirect should be a url");
+        url.query_pairs().find(|(k, _)| k == "code").expect("code should be given").1.to_string()
+    }
+
+    #[tokio::test]
+    async fn app_should_get_tokens_for_what_the_user_approved() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateOAuthApp {
+            name: "Reader".to_string(),
+            redirect_uris: vec!["https://app.example/cb".to_string()],
+            scopes: vec![ChatRead::NAME.to_string()],
+            confidential: true,
+        };
+        let app = OAuthApp::create(1, &input, 1, &pool).await?;
+        let secret = app.client_secret.clone().expect("confidential apps should get a secret");
+        assert!(OAuthApp::authenticate(&app.client_id, Some("wrong"), &pool).await?.is_none());
+        let app = OAuthApp::authenticate(&app.client_id, Some(&secret), &pool).await?.expect("app should exist");
+
+        let user = User::find_by_id(1, &pool).await?.expect("user should exist");
+        assert!(user.app_consent(&authorize(&app, ChatWrite::NAME), &pool).await.is_err());
+        let consent = user.app_consent(&authorize(&app, ChatRead::NAME), &pool).await?;
+        assert!(!consent.consented);
+
+        let denied = ConsentInput { request: authorize(&app, ChatRead::NAME), approve: false };
+        let output = user.authorize_app(&denied, &pool).await?;
+        assert_eq!(output.redirect_to, "https://app.example/cb?error=access_denied&state=xyz");
+
+        let approved = ConsentInput { request: authorize(&app, ChatRead::NAME), approve: true };
+        let code = code_of(&user.authorize_app(&approved, &pool).await?);
+        // a wrong verifier uses the code up
+        assert!(app.redeem_code(&code, "https://app.example/cb", "wrong", &pool).await.is_err());
+        assert!(app.redeem_code(&code, "https://app.example/cb", VERIFIER, &pool).await.is_err());
+
+        let code = code_of(&user.authorize_app(&approved, &pool).await?);
+        let token = app.redeem_code(&code, "https://app.example/cb", VERIFIER, &pool).await?;
+        assert_eq!((token.user.id, token.scopes), (1, vec![ChatRead::NAME.to_string()]));
+        let next = app.refresh(&token.refresh_token, &pool).await?;
+        assert!(app.refresh(&token.refresh_token, &pool).await.is_err());
+
+        assert_eq!(user.authorized_apps(&pool).await?.len(), 1);
+        let access = TokenId::generate();
+        app.record_access_token(user.id, &access, &pool).await?;
+        user.revoke_app(app.id as _, &pool).await?;
+        assert!(app.refresh(&next.refresh_token, &pool).await.is_err());
+        // access tokens already issued stop working too
+        assert!(RevokedToken::is_revoked(&access.jti, &pool).await?);
+        Ok(())
+    }
+}
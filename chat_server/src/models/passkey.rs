@@ -0,0 +1,299 @@
+use std::sync::LazyLock;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::PgPool;
+
+use crate::{
+    config::WebauthnConfig,
+    webauthn::{
+        b64url_decode, client_challenge, creation_options, generate_challenge, request_options, verify_authentication,
+        verify_registration, AuthenticationCredential, RegistrationCredential, CEREMONY_TIMEOUT_MS,
+    },
+    AppError, Passkey, User,
+};
+
+const MAX_PASSKEYS_PER_USER: i64 = 10;
+
+// emails without a passkey get options that look like a real user's, with a credential id
+// that is stable per email so that asking twice doesn't tell them apart
+static DECOY_KEY: LazyLock<[u8; 32]> = LazyLock::new(|| {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+});
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FinishPasskeyRegistration {
+    // shown in the passkey list, defaults to "Passkey"
+    #[serde(default)]
+    pub name: Option<String>,
+    pub credential: RegistrationCredential,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StartPasskeySignin {
+    pub email: String,
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FinishPasskeySignin {
+    pub credential: AuthenticationCredential,
+}
+
+impl User {
+    pub async fn passkeys(&self, pool: &PgPool) -> Result<Vec<Passkey>, AppError> {
+        let passkeys = sqlx::query_as(
+            r#"
+            SELECT id, user_id, name, sign_count, last_used_at, created_at
+            FROM user_passkeys
+            WHERE user_id = $1
+            ORDER BY id
+            "#,
+        )
+        .bind(self.id)
+        .fetch_all(pool)
+        .await?;
+        Ok(passkeys)
+    }
+
+    // returns the options for navigator.credentials.create()
+    pub async fn start_passkey_registration(&self, config: &WebauthnConfig, pool: &PgPool) -> Result<Value, AppError> {
+        let exclude = credential_ids(self.id, pool).await?;
+        if exclude.len() as i64 >= MAX_PASSKEYS_PER_USER {
+            return Err(AppError::InvalidInput(format!(
+                "at most {} passkeys can be registered",
+                MAX_PASSKEYS_PER_USER
+            )));
+        }
+        let challenge = create_ceremony(self.id, "register", false, pool).await?;
+        Ok(creation_options(config, &challenge, self.id, &self.email, &self.fullname, &exclude))
+    }
+
+    pub async fn finish_passkey_registration(
+        &self,
+        config: &WebauthnConfig,
+        input: &FinishPasskeyRegistration,
+        pool: &PgPool,
+    ) -> Result<Passkey, AppError> {
+        let name = input.name.as_deref().map(str::trim).unwrap_or_default();
+        let name = if name.is_empty() { "Passkey" } else { name };
+        if name.chars().count() > 64 {
+            return Err(AppError::InvalidInput("passkey name is too long".to_string()));
+        }
+        let challenge = client_challenge(&input.credential.response.client_data_json)?;
+        let (user_id, _) = redeem_ceremony(&challenge, "register", pool).await?;
+        if user_id != self.id {
+            return Err(AppError::PermissionDenied("passkey ceremony belongs to another user".to_string()));
+        }
+        let verified = verify_registration(config, &challenge, &input.credential)?;
+        let passkey = sqlx::query_as(
+            r#"
+            INSERT INTO user_passkeys (user_id, credential_id, public_key, sign_count, name)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (credential_id) DO NOTHING
+            RETURNING id, user_id, name, sign_count, last_used_at, created_at
+            "#,
+        )
+        .bind(self.id)
+        .bind(&verified.credential_id)
+        .bind(&verified.public_key)
+        .bind(verified.sign_count as i64)
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+        passkey.ok_or_else(|| AppError::InvalidInput("passkey is already registered".to_string()))
+    }
+
+    pub async fn delete_passkey(&self, id: u64, pool: &PgPool) -> Result<(), AppError> {
+        let ret = sqlx::query("DELETE FROM user_passkeys WHERE id = $1 AND user_id = $2")
+            .bind(id as i64)
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("passkey {}", id)));
+        }
+        Ok(())
+    }
+
+    // returns the options for navigator.credentials.get(). Emails without a passkey get options
+    // no response can satisfy, so the answer doesn't tell who has one.
+    pub async fn start_passkey_signin(config: &WebauthnConfig, input: &StartPasskeySignin, pool: &PgPool) -> Result<Value, AppError> {
+        let user = User::find_by_email(&input.email, pool).await?;
+        let allow = match &user {
+            Some(user) => credential_ids(user.id, pool).await?,
+            None => vec![],
+        };
+        let (Some(user), false) = (user, allow.is_empty()) else {
+            let decoy = hmac_sha256::HMAC::mac(input.email.trim().to_lowercase().as_bytes(), *DECOY_KEY);
+            return Ok(request_options(config, &generate_challenge(), &[decoy[..16].to_vec()]));
+        };
+        let challenge = create_ceremony(user.id, "signin", input.remember_me, pool).await?;
+        Ok(request_options(config, &challenge, &allow))
+    }
+
+    // returns the user, whether they asked to be remembered and whether the authenticator
+    // verified them
+    pub async fn finish_passkey_signin(
+        config: &WebauthnConfig,
+        input: &FinishPasskeySignin,
+        pool: &PgPool,
+    ) -> Result<(User, bool, bool), AppError> {
+        let challenge = client_challenge(&input.credential.response.client_data_json)?;
+        let (user_id, remember_me) = redeem_ceremony(&challenge, "signin", pool).await?;
+        let credential_id = b64url_decode(&input.credential.id)?;
+
+        let mut tx = pool.begin().await?;
+        let row: Option<(i64, Vec<u8>, i64)> = sqlx::query_as(
+            r#"
+            SELECT id, public_key, sign_count FROM user_passkeys
+            WHERE credential_id = $1 AND user_id = $2
+            FOR UPDATE
+            "#,
+        )
+        .bind(&credential_id)
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((id, public_key, sign_count)) = row else {
+            return Err(AppError::PermissionDenied("passkey is not registered".to_string()));
+        };
+        let verified = verify_authentication(config, &challenge, &public_key, sign_count as u32, &input.credential)?;
+        sqlx::query("UPDATE user_passkeys SET sign_count = $2, last_used_at = NOW() WHERE id = $1")
+            .bind(id)
+            .bind(verified.sign_count as i64)
+            .execute(&mut *tx)
+            .await?;
+        let user: User = sqlx::query_as("SELECT id, ws_id, fullname, email, created_at FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok((user, remember_me, verified.user_verified))
+    }
+}
+
+async fn credential_ids(user_id: i64, pool: &PgPool) -> Result<Vec<Vec<u8>>, AppError> {
+    let ids = sqlx::query_scalar("SELECT credential_id FROM user_passkeys WHERE user_id = $1 ORDER BY id")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+    Ok(ids)
+}
+
+async fn create_ceremony(user_id: i64, kind: &str, remember_me: bool, pool: &PgPool) -> Result<String, AppError> {
+    let challenge = generate_challenge();
+    sqlx::query(
+        r#"
+        INSERT INTO webauthn_ceremonies (user_id, challenge, kind, remember_me, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(user_id)
+    .bind(&challenge)
+    .bind(kind)
+    .bind(remember_me)
+    .bind(Utc::now() + Duration::milliseconds(CEREMONY_TIMEOUT_MS as i64))
+    .execute(pool)
+    .await?;
+    Ok(challenge)
+}
+
+// ceremonies are single use, whether or not the response verifies
+async fn redeem_ceremony(challenge: &str, kind: &str, pool: &PgPool) -> Result<(i64, bool), AppError> {
+    let ceremony = sqlx::query_as(
+        r#"
+        DELETE FROM webauthn_ceremonies
+        WHERE challenge = $1 AND kind = $2 AND expires_at > NOW()
+        RETURNING user_id, remember_me
+        "#,
+    )
+    .bind(challenge.trim_end_matches('='))
+    .bind(kind)
+    .fetch_optional(pool)
+    .await?;
+    ceremony.ok_or_else(|| AppError::PermissionDenied("passkey ceremony is invalid or has expired".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, webauthn::test_authenticator::Authenticator};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn passkey_registration_and_signin_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let config = WebauthnConfig::default();
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let mut authenticator = Authenticator::new(3);
+
+        let options = user.start_passkey_registration(&config, &pool).await?;
+        let challenge = options["challenge"].as_str().expect("challenge");
+        let input = FinishPasskeyRegistration {
+            name: Some("laptop".to_string()),
+            credential: authenticator.register(&config, challenge),
+        };
+        let passkey = user.finish_passkey_registration(&config, &input, &pool).await?;
+        assert_eq!(passkey.name, "laptop");
+        // the ceremony is used up
+        assert!(user.finish_passkey_registration(&config, &input, &pool).await.is_err());
+
+        let input = StartPasskeySignin { email: user.email.clone(), remember_me: true };
+        let options = User::start_passkey_signin(&config, &input, &pool).await?;
+        assert_eq!(options["allowCredentials"].as_array().map(Vec::len), Some(1));
+        let challenge = options["challenge"].as_str().expect("challenge");
+        let input = FinishPasskeySignin { credential: authenticator.authenticate(&config, challenge) };
+        let (signed_in, remember_me, user_verified) = User::finish_passkey_signin(&config, &input, &pool).await?;
+        assert_eq!(signed_in.id, user.id);
+        assert!(remember_me && user_verified);
+        assert!(User::finish_passkey_signin(&config, &input, &pool).await.is_err());
+        assert_eq!(user.passkeys(&pool).await?[0].sign_count, 1);
+
+        user.delete_passkey(passkey.id as _, &pool).await?;
+        // without a passkey the options look the same, but lead nowhere
+        let input = StartPasskeySignin { email: user.email.clone(), remember_me: false };
+        let options = User::start_passkey_signin(&config, &input, &pool).await?;
+        assert_eq!(options["allowCredentials"].as_array().map(Vec::len), Some(1));
+        let again = User::start_passkey_signin(&config, &input, &pool).await?;
+        assert_eq!(options["allowCredentials"], again["allowCredentials"]);
+        let input = StartPasskeySignin { email: "nobody@acme.org".to_string(), remember_me: false };
+        assert!(User::start_passkey_signin(&config, &input, &pool).await.is_ok());
+        let challenge = options["challenge"].as_str().expect("challenge");
+        let input = FinishPasskeySignin { credential: authenticator.authenticate(&config, challenge) };
+        assert!(User::finish_passkey_signin(&config, &input, &pool).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn passkey_signin_should_reject_other_users_credential() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let config = WebauthnConfig::default();
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let other = User::find_by_email("alice@acme.org", &pool).await?.expect("user should exist");
+        let mut authenticator = Authenticator::new(3);
+        let options = user.start_passkey_registration(&config, &pool).await?;
+        let credential = authenticator.register(&config, options["challenge"].as_str().expect("challenge"));
+        let input = FinishPasskeyRegistration { name: None, credential };
+        user.finish_passkey_registration(&config, &input, &pool).await?;
+
+        let mut other_authenticator = Authenticator::new(4);
+        let options = other.start_passkey_registration(&config, &pool).await?;
+        let credential = other_authenticator.register(&config, options["challenge"].as_str().expect("challenge"));
+        let input = FinishPasskeyRegistration { name: None, credential };
+        other.finish_passkey_registration(&config, &input, &pool).await?;
+
+        // signing in as the other user with the first user's passkey
+        let input = StartPasskeySignin { email: other.email.clone(), remember_me: false };
+        let options = User::start_passkey_signin(&config, &input, &pool).await?;
+        let credential = authenticator.authenticate(&config, options["challenge"].as_str().expect("challenge"));
+        let ret = User::finish_passkey_signin(&config, &FinishPasskeySignin { credential }, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{config::{PasswordHashing, PasswordPolicy}, utils::generate_token, AppError, User};
+
+use super::user::hash_password;
+
+const PASSWORD_RESET_TOKEN_BYTES: usize = 32;
+const PASSWORD_RESET_TTL_HOURS: i64 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
+
+impl User {
+    pub async fn create_password_reset(&self, pool: &PgPool) -> Result<String, AppError> {
+        let token = generate_token(PASSWORD_RESET_TOKEN_BYTES);
+        sqlx::query(
+            r#"
+            INSERT INTO password_resets (user_id, token_hash, expires_at)
+            VALUES ($1, sha256(convert_to($2, 'UTF8')), $3)
+            "#,
+        )
+        .bind(self.id)
+        .bind(&token)
+        .bind(Utc::now() + Duration::hours(PASSWORD_RESET_TTL_HOURS))
+        .execute(pool)
+        .await?;
+        Ok(token)
+    }
+
+    // sets the new password and signs the user out everywhere
+    pub async fn reset_password(
+        input: &ResetPassword,
+        policy: &PasswordPolicy,
+        hashing: &PasswordHashing,
+        pool: &PgPool,
+    ) -> Result<User, AppError> {
+        let mut tx = pool.begin().await?;
+        let user: Option<User> = sqlx::query_as(
+            r#"
+            SELECT u.id, u.ws_id, u.fullname, u.email, u.created_at
+            FROM users u JOIN password_resets r ON r.user_id = u.id
+            WHERE r.token_hash = sha256(convert_to($1, 'UTF8')) AND r.expires_at > NOW()
+            FOR UPDATE OF u
+            "#,
+        )
+        .bind(&input.token)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(user) = user else {
+            return Err(AppError::NotFound("password reset token is invalid or has expired".to_string()));
+        };
+        policy.check(&input.password, &[&user.fullname, &user.email])?;
+        let password_hash = hash_password(&input.password, hashing)?;
+        sqlx::query("UPDATE users SET password_hash = $2, password_reset_required = FALSE WHERE id = $1")
+            .bind(user.id)
+            .bind(&password_hash)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM password_resets WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL")
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, SigninUser};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn reset_password_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let token = user.create_password_reset(&pool).await?;
+        let policy = PasswordPolicy { min_guess_score: Some(2), ..Default::default() };
+        let mut input = ResetPassword {
+            token: token.clone(),
+            password: "tchen123".to_string(),
+        };
+        let ret = User::reset_password(&input, &policy, &PasswordHashing::default(), &pool).await;
+        assert!(matches!(ret, Err(AppError::WeakPassword(_))));
+
+        input.password = "crab-rave-42".to_string();
+        User::reset_password(&input, &policy, &PasswordHashing::default(), &pool).await?;
+        assert!(User::verify(&SigninUser::new("tchen@acme.org", "123456"), &PasswordHashing::default(), &pool).await?.is_none());
+        assert!(User::verify(&SigninUser::new("tchen@acme.org", "crab-rave-42"), &PasswordHashing::default(), &pool).await?.is_some());
+
+        let ret = User::reset_password(&input, &policy, &PasswordHashing::default(), &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+}
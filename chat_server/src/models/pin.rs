@@ -0,0 +1,228 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{AppError, Chat, PinnedMessage};
+
+// characters of the message shown in the pinned bar
+const PIN_PREVIEW_LEN: i32 = 200;
+const MAX_PINS_LIMIT: i32 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinMessage {
+    pub message_id: u64,
+    // 0 based, appended at the end when not set
+    #[serde(default)]
+    pub position: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReorderPins {
+    // every pinned message of the chat, in the new order
+    pub message_ids: Vec<i64>,
+}
+
+impl Chat {
+    pub async fn pinned_messages(&self, pool: &PgPool) -> Result<Vec<PinnedMessage>, AppError> {
+        let pins = sqlx::query_as(
+            r#"
+            SELECT p.message_id, m.sender_id, LEFT(m.content, $2) AS preview, p.position, p.pinned_by, p.pinned_at
+            FROM chat_pins p
+            JOIN messages m ON m.id = p.message_id
+            WHERE p.chat_id = $1
+            ORDER BY p.position
+            "#,
+        )
+        .bind(self.id)
+        .bind(PIN_PREVIEW_LEN)
+        .fetch_all(pool)
+        .await?;
+        Ok(pins)
+    }
+
+    pub async fn pin_message(&self, input: &PinMessage, user_id: u64, pool: &PgPool) -> Result<Vec<PinnedMessage>, AppError> {
+        self.ensure_member(user_id)?;
+        let mut tx = pool.begin().await?;
+        let (count, max_pins) = lock_pins(&mut tx, self.id).await?;
+        let in_chat: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM messages WHERE id = $1 AND chat_id = $2)")
+            .bind(input.message_id as i64)
+            .bind(self.id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if !in_chat {
+            return Err(AppError::NotFound(format!("message {} in chat {}", input.message_id, self.id)));
+        }
+        let pinned: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM chat_pins WHERE chat_id = $1 AND message_id = $2)")
+            .bind(self.id)
+            .bind(input.message_id as i64)
+            .fetch_one(&mut *tx)
+            .await?;
+        if pinned {
+            return Err(AppError::InvalidInput(format!("message {} is already pinned", input.message_id)));
+        }
+        if count >= max_pins as i64 {
+            return Err(AppError::InvalidInput(format!("a chat can have at most {} pins", max_pins)));
+        }
+
+        let position = input.position.unwrap_or(count as i32).clamp(0, count as i32);
+        sqlx::query("UPDATE chat_pins SET position = position + 1 WHERE chat_id = $1 AND position >= $2")
+            .bind(self.id)
+            .bind(position)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO chat_pins (chat_id, message_id, position, pinned_by)
+            VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(self.id)
+        .bind(input.message_id as i64)
+        .bind(position)
+        .bind(user_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.pinned_messages(pool).await
+    }
+
+    pub async fn unpin_message(&self, message_id: u64, user_id: u64, pool: &PgPool) -> Result<Vec<PinnedMessage>, AppError> {
+        self.ensure_member(user_id)?;
+        let mut tx = pool.begin().await?;
+        lock_pins(&mut tx, self.id).await?;
+        let position: Option<i32> = sqlx::query_scalar(
+            "DELETE FROM chat_pins WHERE chat_id = $1 AND message_id = $2 RETURNING position",
+        )
+        .bind(self.id)
+        .bind(message_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(position) = position else {
+            return Err(AppError::NotFound(format!("pinned message {} in chat {}", message_id, self.id)));
+        };
+        sqlx::query("UPDATE chat_pins SET position = position - 1 WHERE chat_id = $1 AND position > $2")
+            .bind(self.id)
+            .bind(position)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        self.pinned_messages(pool).await
+    }
+
+    pub async fn reorder_pins(&self, input: &ReorderPins, user_id: u64, pool: &PgPool) -> Result<Vec<PinnedMessage>, AppError> {
+        self.ensure_member(user_id)?;
+        let mut tx = pool.begin().await?;
+        lock_pins(&mut tx, self.id).await?;
+        let mut current: Vec<i64> = sqlx::query_scalar("SELECT message_id FROM chat_pins WHERE chat_id = $1")
+            .bind(self.id)
+            .fetch_all(&mut *tx)
+            .await?;
+        let mut requested = input.message_ids.clone();
+        current.sort_unstable();
+        requested.sort_unstable();
+        if current != requested {
+            return Err(AppError::InvalidInput(
+                "message_ids must list every pinned message exactly once".to_string(),
+            ));
+        }
+        sqlx::query(
+            r#"
+            UPDATE chat_pins p SET position = o.position - 1
+            FROM UNNEST($2::bigint[]) WITH ORDINALITY AS o(message_id, position)
+            WHERE p.chat_id = $1 AND p.message_id = o.message_id
+            "#,
+        )
+        .bind(self.id)
+        .bind(&input.message_ids)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.pinned_messages(pool).await
+    }
+
+    fn ensure_member(&self, user_id: u64) -> Result<(), AppError> {
+        if !self.members.contains(&(user_id as i64)) {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} is not a member of chat {}",
+                user_id, self.id
+            )));
+        }
+        Ok(())
+    }
+}
+
+// serializes pin changes of a chat, returns the number of pins and the workspace limit
+async fn lock_pins(tx: &mut Transaction<'_, Postgres>, chat_id: i64) -> Result<(i64, i32), AppError> {
+    let ret = sqlx::query_as(
+        r#"
+        SELECT (SELECT COUNT(*) FROM chat_pins WHERE chat_id = c.id), w.max_pins_per_chat
+        FROM chats c
+        JOIN workspaces w ON w.id = c.ws_id
+        WHERE c.id = $1
+        FOR UPDATE OF c
+        "#,
+    )
+    .bind(chat_id)
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(ret)
+}
+
+pub(super) fn validate_max_pins(max_pins: i32) -> Result<(), AppError> {
+    if !(1..=MAX_PINS_LIMIT).contains(&max_pins) {
+        return Err(AppError::UpdateWorkspaceError(format!(
+            "max_pins_per_chat must be between 1 and {}",
+            MAX_PINS_LIMIT
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    fn pin(message_id: u64, position: Option<i32>) -> PinMessage {
+        PinMessage { message_id, position }
+    }
+
+    #[tokio::test]
+    async fn pins_should_keep_order() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let chat = Chat::get_by_id(1, &pool).await?.expect("chat should exist");
+        chat.pin_message(&pin(1, None), 1, &pool).await?;
+        chat.pin_message(&pin(2, None), 1, &pool).await?;
+        let pins = chat.pin_message(&pin(3, Some(0)), 2, &pool).await?;
+        let ids: Vec<_> = pins.iter().map(|p| p.message_id).collect();
+        assert_eq!(ids, vec![3, 1, 2]);
+        assert_eq!(pins[0].pinned_by, 2);
+
+        let pins = chat.unpin_message(1, 1, &pool).await?;
+        let positions: Vec<_> = pins.iter().map(|p| (p.message_id, p.position)).collect();
+        assert_eq!(positions, vec![(3, 0), (2, 1)]);
+
+        let input = ReorderPins { message_ids: vec![2, 3] };
+        let pins = chat.reorder_pins(&input, 1, &pool).await?;
+        let ids: Vec<_> = pins.iter().map(|p| p.message_id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        let input = ReorderPins { message_ids: vec![2] };
+        assert!(matches!(chat.reorder_pins(&input, 1, &pool).await, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pins_should_respect_limit() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        sqlx::query("UPDATE workspaces SET max_pins_per_chat = 1 WHERE id = 1")
+            .execute(&pool)
+            .await?;
+        let chat = Chat::get_by_id(1, &pool).await?.expect("chat should exist");
+        chat.pin_message(&pin(1, None), 1, &pool).await?;
+        let ret = chat.pin_message(&pin(1, None), 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        let ret = chat.pin_message(&pin(2, None), 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+}
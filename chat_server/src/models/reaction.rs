@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use std::ops::RangeInclusive;
+
+use crate::{models::emoji::is_valid_emoji_name, AppError, Message, Reaction, ReactionCount};
+
+const MAX_EMOJI_LEN: usize = 64;
+// U+1F3FB to U+1F3FF, Fitzpatrick type 1-2 to 6
+const SKIN_TONES: RangeInclusive<char> = '\u{1F3FB}'..='\u{1F3FF}';
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateReaction {
+    // a unicode emoji, optionally with a skin tone, or a custom one as :name:
+    pub emoji: String,
+}
+
+impl Message {
+    pub async fn add_reaction(id: u64, user_id: u64, emoji: &str, pool: &PgPool) -> Result<Reaction, AppError> {
+        if emoji.is_empty() || emoji.len() > MAX_EMOJI_LEN {
+            return Err(AppError::CreateMessageError(format!(
+                "Emoji must be 1 to {} bytes",
+                MAX_EMOJI_LEN
+            )));
+        }
+        let (emoji, skin_tone) = normalize_emoji(emoji);
+        let custom = custom_emoji_name(&emoji);
+        if custom.is_some_and(|name| !is_valid_emoji_name(name)) {
+            return Err(AppError::CreateMessageError(format!("Invalid custom emoji: {}", emoji)));
+        }
+        // custom emoji must exist in the message's workspace
+        let reaction: Option<Reaction> = sqlx::query_as(
+            r#"
+            INSERT INTO message_reactions (message_id, user_id, emoji, skin_tone)
+            SELECT m.id, $2, $3, $4
+            FROM messages m JOIN chats c ON c.id = m.chat_id
+            WHERE m.id = $1 AND $2 = ANY(c.members)
+                AND ($5::text IS NULL OR EXISTS(SELECT 1 FROM workspace_emoji e WHERE e.ws_id = c.ws_id AND e.name = $5))
+            ON CONFLICT (message_id, user_id, emoji) DO UPDATE SET skin_tone = EXCLUDED.skin_tone
+            RETURNING message_id, user_id, emoji, skin_tone, created_at
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .bind(&emoji)
+        .bind(skin_tone)
+        .bind(custom)
+        .fetch_optional(pool)
+        .await?;
+        reaction.ok_or_else(|| match custom {
+            Some(name) => AppError::NotFound(format!("message {} or emoji {} not found", id, name)),
+            None => AppError::NotFound(format!("message not found: {}", id)),
+        })
+    }
+
+    // removes the reaction whatever its skin tone
+    pub async fn remove_reaction(id: u64, user_id: u64, emoji: &str, pool: &PgPool) -> Result<(), AppError> {
+        let (emoji, _) = normalize_emoji(emoji);
+        sqlx::query("DELETE FROM message_reactions WHERE message_id = $1 AND user_id = $2 AND emoji = $3")
+            .bind(id as i64)
+            .bind(user_id as i64)
+            .bind(&emoji)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn reaction_counts(id: u64, pool: &PgPool) -> Result<Vec<ReactionCount>, AppError> {
+        let counts = sqlx::query_as(
+            r#"
+            SELECT r.emoji, COUNT(*) AS count,
+                array_agg(r.user_id ORDER BY r.created_at) AS users,
+                array_agg(r.skin_tone ORDER BY r.created_at) AS skin_tones,
+                e.image_url
+            FROM message_reactions r
+            JOIN messages m ON m.id = r.message_id
+            JOIN chats c ON c.id = m.chat_id
+            LEFT JOIN workspace_emoji e ON e.ws_id = c.ws_id AND ':' || e.name || ':' = r.emoji
+            WHERE r.message_id = $1
+            GROUP BY r.emoji, e.image_url
+            ORDER BY MIN(r.created_at)
+            "#,
+        )
+        .bind(id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(counts)
+    }
+}
+
+// equivalent spellings of an emoji aggregate under one key: the text and emoji presentation
+// selectors are dropped, and a single skin tone modifier is split off. Sequences with several
+// tones (e.g. two people holding hands) are distinct emoji and kept whole.
+// the reactions migration backfills existing rows the same way
+pub(crate) fn normalize_emoji(emoji: &str) -> (String, Option<i16>) {
+    let emoji: String = emoji.chars().filter(|c| !matches!(c, '\u{FE0E}' | '\u{FE0F}')).collect();
+    let mut tones = emoji.chars().filter(|c| SKIN_TONES.contains(c));
+    match (tones.next(), tones.next()) {
+        (Some(tone), None) => {
+            let base = emoji.chars().filter(|c| *c != tone).collect();
+            (base, Some((tone as u32 - *SKIN_TONES.start() as u32 + 1) as i16))
+        }
+        _ => (emoji, None),
+    }
+}
+
+fn custom_emoji_name(emoji: &str) -> Option<&str> {
+    emoji
+        .strip_prefix(':')
+        .and_then(|s| s.strip_suffix(':'))
+        .filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn reactions_should_be_counted() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        Message::add_reaction(1, 2, "👍", &pool).await?;
+        Message::add_reaction(1, 3, "👍", &pool).await?;
+        // reacting twice is idempotent
+        Message::add_reaction(1, 3, "👍", &pool).await?;
+        Message::add_reaction(1, 3, "🎉", &pool).await?;
+        let counts = Message::reaction_counts(1, &pool).await?;
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[0].emoji, "👍");
+        assert_eq!(counts[0].count, 2);
+        assert_eq!(counts[0].users, vec![2, 3]);
+
+        Message::remove_reaction(1, 3, "🎉", &pool).await?;
+        assert_eq!(Message::reaction_counts(1, &pool).await?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn normalize_emoji_should_fold_equivalent_spellings() {
+        assert_eq!(normalize_emoji("❤️"), ("❤".to_string(), None));
+        assert_eq!(normalize_emoji("❤"), ("❤".to_string(), None));
+        assert_eq!(normalize_emoji("👍🏽"), ("👍".to_string(), Some(3)));
+        assert_eq!(normalize_emoji("👍🏿"), ("👍".to_string(), Some(5)));
+        // the tone moves out of zwj sequences too
+        assert_eq!(normalize_emoji("🧑🏻‍💻"), ("🧑‍💻".to_string(), Some(1)));
+        // several tones make a distinct emoji
+        assert_eq!(normalize_emoji("🧑🏻‍🤝‍🧑🏿"), ("🧑🏻‍🤝‍🧑🏿".to_string(), None));
+        assert_eq!(normalize_emoji(":parrot:"), (":parrot:".to_string(), None));
+    }
+
+    #[tokio::test]
+    async fn reactions_with_skin_tones_should_aggregate() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        Message::add_reaction(1, 1, "👍", &pool).await?;
+        Message::add_reaction(1, 2, "👍🏽", &pool).await?;
+        Message::add_reaction(1, 3, "👍🏿", &pool).await?;
+        // changing the tone replaces the reaction
+        let reaction = Message::add_reaction(1, 3, "👍🏻", &pool).await?;
+        assert_eq!(reaction.skin_tone, Some(1));
+        let counts = Message::reaction_counts(1, &pool).await?;
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].count, 3);
+        assert_eq!(counts[0].skin_tones, vec![None, Some(3), Some(1)]);
+
+        Message::remove_reaction(1, 2, "👍", &pool).await?;
+        assert_eq!(Message::reaction_counts(1, &pool).await?[0].count, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn custom_emoji_reactions_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ret = Message::add_reaction(1, 1, ":parrot:", &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        let input = crate::CreateCustomEmoji {
+            name: "parrot".to_string(),
+            image_url: "https://example.com/parrot.gif".to_string(),
+        };
+        let emoji = crate::CustomEmoji::create(1, &input, 1, &pool).await?;
+        Message::add_reaction(1, 1, ":parrot:", &pool).await?;
+        let counts = Message::reaction_counts(1, &pool).await?;
+        assert_eq!(counts[0].image_url.as_deref(), Some("https://example.com/parrot.gif"));
+
+        // the reaction outlives the emoji
+        emoji.delete(&pool).await?;
+        let counts = Message::reaction_counts(1, &pool).await?;
+        assert_eq!(counts[0].emoji, ":parrot:");
+        assert_eq!(counts[0].image_url, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reaction_by_non_member_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let msg = Message::create(&crate::CreateMessage::new("hi"), 2, 1, &pool).await?;
+        let ret = Message::add_reaction(msg.id as _, 4, "👍", &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+}
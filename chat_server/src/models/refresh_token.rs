@@ -0,0 +1,243 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{utils::{generate_token, TokenId}, AppError, ClientInfo, RefreshToken, SessionPolicy, User};
+
+use super::session::{insert_session, touch_session};
+
+const REFRESH_TOKEN_BYTES: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshTokenInput {
+    // browser apps with cookie sessions send {} and the refresh cookie instead
+    #[serde(default)]
+    pub refresh_token: String,
+}
+
+impl RefreshToken {
+    // starts a new token family, i.e. a session, called on signin / signup. The access token
+    // issued along with it is recorded so that revoking the session also revokes it.
+    pub async fn issue(
+        user: &User,
+        remember_me: bool,
+        client: &ClientInfo,
+        access: &TokenId,
+        pool: &PgPool,
+    ) -> Result<String, AppError> {
+        let policy = SessionPolicy::fetch(user.ws_id as _, pool).await?;
+        let session = Session {
+            family_id: generate_token(16),
+            user_id: user.id,
+            started_at: Utc::now(),
+            remember_me,
+        };
+        let mut tx = pool.begin().await?;
+        let token = insert_token(&mut tx, &session, &policy).await?;
+        insert_session(&mut tx, &session.family_id, user.id, client, access).await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    // exchanges a refresh token for a new one in the same family. A token can only be used
+    // once: presenting it again means it leaked, so the whole family gets revoked.
+    pub async fn rotate(token: &str, access: &TokenId, pool: &PgPool) -> Result<(User, String), AppError> {
+        let mut tx = pool.begin().await?;
+        let current: Option<RefreshToken> = sqlx::query_as(
+            r#"
+            SELECT id, family_id, user_id, session_started_at, remember_me, expires_at, used_at, revoked_at, created_at
+            FROM refresh_tokens
+            WHERE token_hash = sha256(convert_to($1, 'UTF8'))
+            FOR UPDATE
+            "#,
+        )
+        .bind(token)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(current) = current else {
+            return Err(AppError::PermissionDenied("Invalid refresh token".to_string()));
+        };
+        if current.revoked_at.is_some() {
+            return Err(AppError::PermissionDenied("Refresh token has been revoked".to_string()));
+        }
+        if current.used_at.is_some() {
+            revoke_family(&mut tx, &current.family_id).await?;
+            tx.commit().await?;
+            return Err(AppError::PermissionDenied("Refresh token has already been used".to_string()));
+        }
+        let user: Option<User> = sqlx::query_as(
+            "SELECT id, ws_id, fullname, email, created_at FROM users WHERE id = $1 AND deactivated_at IS NULL",
+        )
+        .bind(current.user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(user) = user else {
+            return Err(AppError::PermissionDenied("Account has been deactivated".to_string()));
+        };
+        // the current policy applies, so shortening it also ends existing sessions
+        let policy = SessionPolicy::fetch(user.ws_id as _, pool).await?;
+        let now = Utc::now();
+        let idle_until = current.created_at + Duration::seconds(policy.idle_timeout as i64);
+        if current.expires_at < now || idle_until < now {
+            return Err(AppError::PermissionDenied("Refresh token has expired".to_string()));
+        }
+        let session = Session {
+            family_id: current.family_id,
+            user_id: current.user_id,
+            started_at: current.session_started_at,
+            remember_me: current.remember_me,
+        };
+        if session.ends_at(&policy) < now {
+            return Err(AppError::PermissionDenied("Session has expired".to_string()));
+        }
+
+        sqlx::query("UPDATE refresh_tokens SET used_at = NOW() WHERE id = $1")
+            .bind(current.id)
+            .execute(&mut *tx)
+            .await?;
+        let token = insert_token(&mut tx, &session, &policy).await?;
+        touch_session(&mut tx, &session.family_id, access).await?;
+        tx.commit().await?;
+        Ok((user, token))
+    }
+
+    // revokes every token of the family the given token belongs to, e.g. on signout
+    pub async fn revoke(token: &str, pool: &PgPool) -> Result<(), AppError> {
+        let mut tx = pool.begin().await?;
+        let family_id: Option<String> = sqlx::query_scalar(
+            "SELECT family_id FROM refresh_tokens WHERE token_hash = sha256(convert_to($1, 'UTF8'))",
+        )
+        .bind(token)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(family_id) = family_id else {
+            return Err(AppError::NotFound("refresh token".to_string()));
+        };
+        revoke_family(&mut tx, &family_id).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+struct Session {
+    family_id: String,
+    user_id: i64,
+    started_at: DateTime<Utc>,
+    remember_me: bool,
+}
+
+impl Session {
+    fn ends_at(&self, policy: &SessionPolicy) -> DateTime<Utc> {
+        self.started_at + Duration::seconds(policy.session_max_age(self.remember_me) as i64)
+    }
+}
+
+// a token expires after the idle timeout, but never outlives its session
+async fn insert_token(tx: &mut Transaction<'_, Postgres>, session: &Session, policy: &SessionPolicy) -> Result<String, AppError> {
+    let token = generate_token(REFRESH_TOKEN_BYTES);
+    let expires_at = (Utc::now() + Duration::seconds(policy.idle_timeout as i64)).min(session.ends_at(policy));
+    sqlx::query(
+        r#"
+        INSERT INTO refresh_tokens (family_id, user_id, token_hash, expires_at, session_started_at, remember_me)
+        VALUES ($1, $2, sha256(convert_to($3, 'UTF8')), $4, $5, $6)
+        "#,
+    )
+    .bind(&session.family_id)
+    .bind(session.user_id)
+    .bind(&token)
+    .bind(expires_at)
+    .bind(session.started_at)
+    .bind(session.remember_me)
+    .execute(&mut **tx)
+    .await?;
+    Ok(token)
+}
+
+pub(super) async fn revoke_family(tx: &mut Transaction<'_, Postgres>, family_id: &str) -> Result<(), AppError> {
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE family_id = $1 AND revoked_at IS NULL")
+        .bind(family_id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, UpdateSessionPolicy};
+    use anyhow::Result;
+
+    async fn issue(user: &User, remember_me: bool, pool: &PgPool) -> Result<String> {
+        Ok(RefreshToken::issue(user, remember_me, &ClientInfo::default(), &TokenId::generate(), pool).await?)
+    }
+
+    async fn rotate(token: &str, pool: &PgPool) -> Result<(User, String), AppError> {
+        RefreshToken::rotate(token, &TokenId::generate(), pool).await
+    }
+
+    async fn user(pool: &PgPool) -> Result<User> {
+        Ok(User::find_by_email("tchen@acme.org", pool).await?.expect("user should exist"))
+    }
+
+    #[tokio::test]
+    async fn refresh_token_should_rotate() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let token = issue(&user(&pool).await?, false, &pool).await?;
+        let (user, next) = rotate(&token, &pool).await?;
+        assert_eq!(user.id, 1);
+        assert_ne!(token, next);
+        let (_, last) = rotate(&next, &pool).await?;
+
+        // reusing a rotated token revokes the whole family
+        let ret = rotate(&token, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = rotate(&last, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revoked_refresh_token_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = user(&pool).await?;
+        let other = issue(&user, false, &pool).await?;
+        let token = issue(&user, false, &pool).await?;
+        RefreshToken::revoke(&token, &pool).await?;
+        let ret = rotate(&token, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        // other sessions of the same user are unaffected
+        rotate(&other, &pool).await?;
+
+        let ret = RefreshToken::revoke("nope", &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_policy_should_expire_refresh_tokens() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = user(&pool).await?;
+        let token = issue(&user, false, &pool).await?;
+        let remembered = issue(&user, true, &pool).await?;
+        // pretend both sessions started 60 days ago and were refreshed just now
+        sqlx::query("UPDATE refresh_tokens SET session_started_at = NOW() - INTERVAL '60 days'")
+            .execute(&pool)
+            .await?;
+        let ret = rotate(&token, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let (_, remembered) = rotate(&remembered, &pool).await?;
+
+        // lowering the idle timeout applies to tokens already issued
+        sqlx::query("UPDATE refresh_tokens SET created_at = NOW() - INTERVAL '2 hours'")
+            .execute(&pool)
+            .await?;
+        let input = UpdateSessionPolicy {
+            idle_timeout: Some(3600),
+            ..Default::default()
+        };
+        SessionPolicy::update(user.ws_id as _, &input, &pool).await?;
+        let ret = rotate(&remembered, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+}
\ No newline at end of file
@@ -0,0 +1,56 @@
+use sqlx::PgPool;
+
+use crate::{utils::TokenId, AppError, RevokedToken};
+
+impl RevokedToken {
+    pub async fn create(id: &TokenId, user_id: u64, pool: &PgPool) -> Result<(), AppError> {
+        // expired tokens are rejected anyway, no need to keep them around
+        sqlx::query("DELETE FROM revoked_tokens WHERE expires_at < NOW()")
+            .execute(pool)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO revoked_tokens (jti, user_id, expires_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (jti) DO NOTHING
+            "#,
+        )
+        .bind(&id.jti)
+        .bind(user_id as i64)
+        .bind(id.expires_at)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn is_revoked(jti: &str, pool: &PgPool) -> Result<bool, AppError> {
+        let ret: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE jti = $1)")
+            .bind(jti)
+            .fetch_one(pool)
+            .await?;
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+    use chrono::{Duration, Utc};
+
+    #[tokio::test]
+    async fn revoked_token_should_be_found() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let id = TokenId {
+            jti: "jti-1".to_string(),
+            expires_at: Utc::now() + Duration::minutes(5),
+        };
+        assert!(!RevokedToken::is_revoked(&id.jti, &pool).await?);
+        RevokedToken::create(&id, 1, &pool).await?;
+        RevokedToken::create(&id, 1, &pool).await?;
+        assert!(RevokedToken::is_revoked(&id.jti, &pool).await?);
+        assert!(!RevokedToken::is_revoked("jti-2", &pool).await?);
+        Ok(())
+    }
+}
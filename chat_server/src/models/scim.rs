@@ -0,0 +1,582 @@
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+
+use super::{
+    approval::deactivate_users,
+    chat::{map_name_conflict, record_member_action, validate_name},
+    user::check_email,
+};
+use crate::{utils::generate_token, AppError, ChatMemberAction, ScimGroup, ScimSettings, ScimUser, Workspace};
+
+const SCIM_TOKEN_BYTES: usize = 32;
+const MAX_NAME_LEN: usize = 64;
+const EMAIL_INDEX: &str = "email_index";
+
+// the state of a member as the identity provider wants it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvisionUser {
+    pub email: String,
+    pub fullname: String,
+    #[serde(default)]
+    pub external_id: Option<String>,
+    #[serde(default = "default_active")]
+    pub active: bool,
+}
+
+// the members replace the current ones
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvisionGroup {
+    pub display_name: String,
+    #[serde(default)]
+    pub external_id: Option<String>,
+    #[serde(default)]
+    pub members: Vec<i64>,
+}
+
+// exact matches, emails are compared case insensitively
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScimFilter {
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub external_id: Option<String>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+}
+
+// returned once, only a hash of the token is kept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuedScimToken {
+    pub token: String,
+    pub created_at: DateTime<Utc>,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+impl Workspace {
+    // replaces the current token, identity providers using it lose access
+    pub async fn issue_scim_token(&self, pool: &PgPool) -> Result<IssuedScimToken, AppError> {
+        let token = generate_token(SCIM_TOKEN_BYTES);
+        let created_at = sqlx::query_scalar(
+            r#"
+            UPDATE workspaces SET scim_token_hash = sha256(convert_to($2, 'UTF8')), scim_token_created_at = NOW()
+            WHERE id = $1
+            RETURNING scim_token_created_at
+            "#,
+        )
+        .bind(self.id)
+        .bind(&token)
+        .fetch_one(pool)
+        .await?;
+        Ok(IssuedScimToken { token, created_at })
+    }
+
+    pub async fn revoke_scim_token(&self, pool: &PgPool) -> Result<(), AppError> {
+        sqlx::query("UPDATE workspaces SET scim_token_hash = NULL, scim_token_created_at = NULL WHERE id = $1")
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn scim_settings(&self, pool: &PgPool) -> Result<ScimSettings, AppError> {
+        let settings = sqlx::query_as("SELECT scim_token_created_at AS token_created_at FROM workspaces WHERE id = $1")
+            .bind(self.id)
+            .fetch_one(pool)
+            .await?;
+        Ok(settings)
+    }
+
+    pub async fn find_by_scim_token(token: &str, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let ws = sqlx::query_as(
+            r#"
+            SELECT id, name, slug, owner_id, created_at
+            FROM workspaces
+            WHERE scim_token_hash = sha256(convert_to($1, 'UTF8')) AND deleted_at IS NULL
+            "#,
+        )
+        .bind(token)
+        .fetch_optional(pool)
+        .await?;
+        Ok(ws)
+    }
+}
+
+// the system user, bridged ghosts and bots are not workspace members an identity provider manages
+const SCIM_USERS: &str = r#"
+    SELECT u.id, u.ws_id, u.email, u.fullname, u.scim_external_id AS external_id,
+        u.deactivated_at IS NULL AS active, u.created_at
+    FROM users u
+    WHERE u.ws_id = $1 AND u.id <> 0
+        AND NOT EXISTS(SELECT 1 FROM bridge_identities b WHERE b.user_id = u.id AND b.ghost)
+        AND NOT EXISTS(SELECT 1 FROM bots b WHERE b.user_id = u.id)
+"#;
+
+impl ScimUser {
+    // returns a page of users and the number of users matching the filter
+    pub async fn list(
+        ws_id: u64,
+        filter: &ScimFilter,
+        offset: u64,
+        limit: u64,
+        pool: &PgPool,
+    ) -> Result<(Vec<Self>, u64), AppError> {
+        const FILTERED: &str = r#"
+            AND ($2::text IS NULL OR lower(u.email) = lower($2))
+            AND ($3::text IS NULL OR u.scim_external_id = $3)
+        "#;
+        let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM ({} {}) s", SCIM_USERS, FILTERED))
+            .bind(ws_id as i64)
+            .bind(&filter.email)
+            .bind(&filter.external_id)
+            .fetch_one(pool)
+            .await?;
+        let users = sqlx::query_as(&format!("{} {} ORDER BY u.id OFFSET $4 LIMIT $5", SCIM_USERS, FILTERED))
+            .bind(ws_id as i64)
+            .bind(&filter.email)
+            .bind(&filter.external_id)
+            .bind(offset as i64)
+            .bind(limit as i64)
+            .fetch_all(pool)
+            .await?;
+        Ok((users, total as u64))
+    }
+
+    pub async fn find(ws_id: u64, id: u64, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let user = sqlx::query_as(&format!("{} AND u.id = $2", SCIM_USERS))
+            .bind(ws_id as i64)
+            .bind(id as i64)
+            .fetch_optional(pool)
+            .await?;
+        Ok(user)
+    }
+
+    // provisioned users have no password, they sign in through sso or a magic link. The email
+    // stays unverified until such a signin proves it, the identity provider isn't trusted for it.
+    pub async fn create(ws_id: u64, input: &ProvisionUser, pool: &PgPool) -> Result<Self, AppError> {
+        validate_user(input)?;
+        let id: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO users (ws_id, email, fullname, scim_external_id, deactivated_at)
+            VALUES ($1, $2, $3, $4, CASE WHEN $5 THEN NULL ELSE NOW() END)
+            RETURNING id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(&input.email)
+        .bind(&input.fullname)
+        .bind(&input.external_id)
+        .bind(input.active)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| map_email_conflict(e, &input.email))?;
+        Self::find(ws_id, id as _, pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("user {}", id)))
+    }
+
+    // deactivating signs the user out everywhere, the workspace owner cannot be deactivated
+    pub async fn update(ws_id: u64, id: u64, input: &ProvisionUser, pool: &PgPool) -> Result<Self, AppError> {
+        validate_user(input)?;
+        let Some(user) = Self::find(ws_id, id, pool).await? else {
+            return Err(AppError::NotFound(format!("user {}", id)));
+        };
+        let mut tx = pool.begin().await?;
+        // the identity provider doesn't vouch for the address, a new one has to be verified again
+        sqlx::query(
+            r#"
+            UPDATE users SET email = $2, fullname = $3, scim_external_id = $4,
+                email_verified_at = CASE WHEN email = $2 THEN email_verified_at END
+            WHERE id = $1
+            "#,
+        )
+            .bind(user.id)
+            .bind(&input.email)
+            .bind(&input.fullname)
+            .bind(&input.external_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| map_email_conflict(e, &input.email))?;
+        match (user.active, input.active) {
+            (true, false) => {
+                let is_owner: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM workspaces WHERE owner_id = $1)")
+                    .bind(user.id)
+                    .fetch_one(&mut *tx)
+                    .await?;
+                if is_owner {
+                    return Err(AppError::PermissionDenied(
+                        "the workspace owner cannot be deactivated".to_string(),
+                    ));
+                }
+                deactivate_users(&mut tx, &[user.id]).await?;
+            }
+            (false, true) => {
+                sqlx::query("UPDATE users SET deactivated_at = NULL WHERE id = $1")
+                    .bind(user.id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            _ => {}
+        }
+        tx.commit().await?;
+        Self::find(ws_id, id, pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("user {}", id)))
+    }
+}
+
+fn validate_user(input: &ProvisionUser) -> Result<(), AppError> {
+    check_email(&input.email)?;
+    if input.fullname.trim().is_empty() || input.fullname.chars().count() > MAX_NAME_LEN {
+        return Err(AppError::InvalidInput(format!(
+            "full name must be 1 to {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn map_email_conflict(e: sqlx::Error, email: &str) -> AppError {
+    match &e {
+        sqlx::Error::Database(db) if db.constraint() == Some(EMAIL_INDEX) => {
+            AppError::EmailAlreadyExists(email.to_string())
+        }
+        _ => e.into(),
+    }
+}
+
+const SCIM_GROUPS: &str = r#"
+    SELECT g.id, g.ws_id, g.chat_id, g.display_name, g.external_id,
+        ARRAY(SELECT m.user_id FROM scim_group_members m WHERE m.group_id = g.id ORDER BY m.user_id) AS members,
+        g.created_at, g.updated_at
+    FROM scim_groups g
+    WHERE g.ws_id = $1
+"#;
+
+impl ScimGroup {
+    // returns a page of groups and the number of groups matching the filter
+    pub async fn list(
+        ws_id: u64,
+        filter: &ScimFilter,
+        offset: u64,
+        limit: u64,
+        pool: &PgPool,
+    ) -> Result<(Vec<Self>, u64), AppError> {
+        const FILTERED: &str = r#"
+            AND ($2::text IS NULL OR lower(g.display_name) = lower($2))
+            AND ($3::text IS NULL OR g.external_id = $3)
+        "#;
+        let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM ({} {}) s", SCIM_GROUPS, FILTERED))
+            .bind(ws_id as i64)
+            .bind(&filter.display_name)
+            .bind(&filter.external_id)
+            .fetch_one(pool)
+            .await?;
+        let groups = sqlx::query_as(&format!("{} {} ORDER BY g.id OFFSET $4 LIMIT $5", SCIM_GROUPS, FILTERED))
+            .bind(ws_id as i64)
+            .bind(&filter.display_name)
+            .bind(&filter.external_id)
+            .bind(offset as i64)
+            .bind(limit as i64)
+            .fetch_all(pool)
+            .await?;
+        Ok((groups, total as u64))
+    }
+
+    pub async fn find(ws_id: u64, id: u64, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let group = sqlx::query_as(&format!("{} AND g.id = $2", SCIM_GROUPS))
+            .bind(ws_id as i64)
+            .bind(id as i64)
+            .fetch_optional(pool)
+            .await?;
+        Ok(group)
+    }
+
+    // every group is backed by a channel: an existing channel with the group's name that no
+    // other group uses, else a new private channel owned by the workspace owner
+    pub async fn create(ws: &Workspace, input: &ProvisionGroup, pool: &PgPool) -> Result<Self, AppError> {
+        validate_name(&input.display_name).map_err(AppError::InvalidInput)?;
+        let mut tx = pool.begin().await?;
+        let existing: Option<i64> = sqlx::query_scalar(
+            r#"
+            SELECT c.id FROM chats c
+            WHERE c.ws_id = $1 AND lower(c.name) = lower($2) AND c.type IN ('public_channel', 'private_channel')
+                AND NOT EXISTS(SELECT 1 FROM scim_groups g WHERE g.chat_id = c.id)
+            "#,
+        )
+        .bind(ws.id)
+        .bind(&input.display_name)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let chat_id = match existing {
+            Some(id) => id,
+            None => sqlx::query_scalar(
+                r#"
+                INSERT INTO chats (ws_id, name, type, members, owner_id, discoverable)
+                VALUES ($1, $2, 'private_channel', '{}', $3, FALSE)
+                RETURNING id
+                "#,
+            )
+            .bind(ws.id)
+            .bind(&input.display_name)
+            .bind(ws.owner_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| map_name_conflict(e, &input.display_name))?,
+        };
+        let id: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO scim_groups (ws_id, chat_id, display_name, external_id)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id
+            "#,
+        )
+        .bind(ws.id)
+        .bind(chat_id)
+        .bind(&input.display_name)
+        .bind(&input.external_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        sync_members(&mut tx, ws.id, id, chat_id, &input.members).await?;
+        tx.commit().await?;
+        Self::find(ws.id as _, id as _, pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("group {}", id)))
+    }
+
+    // renaming the group renames its channel
+    pub async fn update(ws_id: u64, id: u64, input: &ProvisionGroup, pool: &PgPool) -> Result<Self, AppError> {
+        validate_name(&input.display_name).map_err(AppError::InvalidInput)?;
+        let Some(group) = Self::find(ws_id, id, pool).await? else {
+            return Err(AppError::NotFound(format!("group {}", id)));
+        };
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            r#"
+            UPDATE scim_groups SET display_name = $2, external_id = $3, updated_at = NOW()
+            WHERE id = $1
+            "#,
+        )
+        .bind(group.id)
+        .bind(&input.display_name)
+        .bind(&input.external_id)
+        .execute(&mut *tx)
+        .await?;
+        if group.display_name != input.display_name {
+            sqlx::query("UPDATE chats SET name = $2 WHERE id = $1")
+                .bind(group.chat_id)
+                .bind(&input.display_name)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| map_name_conflict(e, &input.display_name))?;
+        }
+        sync_members(&mut tx, group.ws_id, group.id, group.chat_id, &input.members).await?;
+        tx.commit().await?;
+        Self::find(ws_id, id, pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("group {}", id)))
+    }
+
+    // the channel and its members are kept, it is just no longer managed by the group
+    pub async fn delete(ws_id: u64, id: u64, pool: &PgPool) -> Result<(), AppError> {
+        let ret = sqlx::query("DELETE FROM scim_groups WHERE ws_id = $1 AND id = $2")
+            .bind(ws_id as i64)
+            .bind(id as i64)
+            .execute(pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("group {}", id)));
+        }
+        Ok(())
+    }
+}
+
+// adds new members to the channel and removes the ones the group no longer has, as long as it
+// was the group that added them. Changes are recorded in the channel's member history as made by
+// the system user.
+async fn sync_members(
+    tx: &mut Transaction<'_, Postgres>,
+    ws_id: i64,
+    group_id: i64,
+    chat_id: i64,
+    members: &[i64],
+) -> Result<(), AppError> {
+    let members: Vec<i64> = members.iter().copied().collect::<BTreeSet<_>>().into_iter().collect();
+    let valid: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM users WHERE ws_id = $1 AND id = ANY($2) AND id <> 0",
+    )
+    .bind(ws_id)
+    .bind(&members)
+    .fetch_one(&mut **tx)
+    .await?;
+    if valid as usize != members.len() {
+        return Err(AppError::InvalidInput("some group members are not in this workspace".to_string()));
+    }
+
+    let current: Vec<i64> = sqlx::query_scalar("SELECT members FROM chats WHERE id = $1 FOR UPDATE")
+        .bind(chat_id)
+        .fetch_one(&mut **tx)
+        .await?;
+    let removed: Vec<i64> = sqlx::query_scalar(
+        r#"
+        WITH removed AS (
+            DELETE FROM scim_group_members WHERE group_id = $1 AND user_id <> ALL($2)
+            RETURNING user_id, added_to_chat
+        )
+        SELECT user_id FROM removed WHERE added_to_chat
+        "#,
+    )
+    .bind(group_id)
+    .bind(&members)
+    .fetch_all(&mut **tx)
+    .await?;
+    let added: Vec<i64> = sqlx::query_scalar(
+        r#"
+        INSERT INTO scim_group_members (group_id, user_id, added_to_chat)
+        SELECT $1, m, m <> ALL($3) FROM unnest($2::bigint[]) AS m
+        ON CONFLICT DO NOTHING
+        RETURNING user_id
+        "#,
+    )
+    .bind(group_id)
+    .bind(&members)
+    .bind(&current)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let kicked: Vec<i64> = removed.into_iter().filter(|id| current.contains(id)).collect();
+    let joined: Vec<i64> = added.into_iter().filter(|id| !current.contains(id)).collect();
+    sqlx::query(
+        r#"
+        UPDATE chats SET members = ARRAY(SELECT m FROM unnest(members) AS m WHERE m <> ALL($2)) || $3::bigint[]
+        WHERE id = $1
+        "#,
+    )
+    .bind(chat_id)
+    .bind(&kicked)
+    .bind(&joined)
+    .execute(&mut **tx)
+    .await?;
+    for user_id in kicked {
+        record_member_action(tx, chat_id, user_id as _, 0, ChatMemberAction::Kick).await?;
+    }
+    for user_id in joined {
+        record_member_action(tx, chat_id, user_id as _, 0, ChatMemberAction::Join).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, Chat, User};
+    use anyhow::Result;
+
+    fn provision(email: &str, active: bool) -> ProvisionUser {
+        ProvisionUser {
+            email: email.to_string(),
+            fullname: "Eve Chen".to_string(),
+            external_id: Some("okta-eve".to_string()),
+            active,
+        }
+    }
+
+    #[tokio::test]
+    async fn scim_token_should_find_workspace() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws = Workspace::find_by_id(1, &pool).await?.expect("workspace should exist");
+        assert_eq!(ws.scim_settings(&pool).await?, ScimSettings::default());
+        let issued = ws.issue_scim_token(&pool).await?;
+        assert_eq!(Workspace::find_by_scim_token(&issued.token, &pool).await?, Some(ws.clone()));
+        assert_eq!(ws.scim_settings(&pool).await?.token_created_at, Some(issued.created_at));
+
+        // a new token replaces the old one
+        let again = ws.issue_scim_token(&pool).await?;
+        assert!(Workspace::find_by_scim_token(&issued.token, &pool).await?.is_none());
+        ws.revoke_scim_token(&pool).await?;
+        assert!(Workspace::find_by_scim_token(&again.token, &pool).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scim_users_should_be_provisioned_and_deactivated() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let (users, total) = ScimUser::list(1, &ScimFilter::default(), 0, 2, &pool).await?;
+        assert_eq!((users.len(), total), (2, 5));
+
+        let user = ScimUser::create(1, &provision("eve@acme.org", true), &pool).await?;
+        assert!(user.active);
+        // the member verifies the email themselves
+        let eve = User::find_by_email("eve@acme.org", &pool).await?.expect("user should exist");
+        assert!(!eve.is_email_verified(&pool).await?);
+        let filter = ScimFilter { email: Some("EVE@acme.org".to_string()), ..Default::default() };
+        let (users, total) = ScimUser::list(1, &filter, 0, 10, &pool).await?;
+        assert_eq!((users, total), (vec![user.clone()], 1));
+        let ret = ScimUser::create(1, &provision("eve@acme.org", true), &pool).await;
+        assert!(matches!(ret, Err(AppError::EmailAlreadyExists(_))));
+        // members of other workspaces are not visible
+        assert!(ScimUser::find(2, user.id as _, &pool).await?.is_none());
+
+        let user = ScimUser::update(1, user.id as _, &provision("eve@acme.org", false), &pool).await?;
+        assert!(!user.active);
+        let user = ScimUser::update(1, user.id as _, &provision("eve@acme.com", true), &pool).await?;
+        assert!(user.active);
+        assert_eq!(user.email, "eve@acme.com");
+
+        let ws = Workspace::find_by_id(1, &pool).await?.expect("workspace should exist");
+        ws.update_owner(1, &pool).await?;
+        let ret = ScimUser::update(1, 1, &provision("tchen@acme.org", false), &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scim_groups_should_sync_channel_members() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws = Workspace::find_by_id(1, &pool).await?.expect("workspace should exist");
+        let input = ProvisionGroup { display_name: "Sales".to_string(), external_id: None, members: vec![2, 3] };
+        let group = ScimGroup::create(&ws, &input, &pool).await?;
+        assert_eq!(group.members, vec![2, 3]);
+        let chat = Chat::get_by_id(group.chat_id as _, &pool).await?.expect("chat should exist");
+        assert_eq!(chat.name.as_deref(), Some("Sales"));
+        assert_eq!(chat.members, vec![2, 3]);
+
+        // an existing channel is linked, members already in it are kept
+        let input = ProvisionGroup { display_name: "private".to_string(), external_id: None, members: vec![3, 4] };
+        let linked = ScimGroup::create(&ws, &input, &pool).await?;
+        assert_eq!(linked.chat_id, 2);
+        let chat = Chat::get_by_id(2, &pool).await?.expect("chat should exist");
+        assert_eq!(chat.members, vec![1, 2, 3, 4]);
+        let ret = ScimGroup::create(&ws, &input, &pool).await;
+        assert!(matches!(ret, Err(AppError::ChatNameTaken(_))));
+        // dropping them from the group only removes the members the group added
+        let input = ProvisionGroup { members: vec![], ..input };
+        ScimGroup::update(1, linked.id as _, &input, &pool).await?;
+        let chat = Chat::get_by_id(2, &pool).await?.expect("chat should exist");
+        assert_eq!(chat.members, vec![1, 2, 3]);
+
+        let input = ProvisionGroup { display_name: "Sales EU".to_string(), external_id: None, members: vec![3, 5] };
+        let group = ScimGroup::update(1, group.id as _, &input, &pool).await?;
+        assert_eq!(group.members, vec![3, 5]);
+        let chat = Chat::get_by_id(group.chat_id as _, &pool).await?.expect("chat should exist");
+        assert_eq!(chat.name.as_deref(), Some("Sales EU"));
+        assert_eq!(chat.members, vec![3, 5]);
+        let history = chat.fetch_member_history(&pool).await?;
+        assert!(history.iter().any(|e| e.user_id == 2 && e.action == ChatMemberAction::Kick && e.actor_id == 0));
+
+        let input = ProvisionGroup { members: vec![42], ..input };
+        let ret = ScimGroup::update(1, group.id as _, &input, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+
+        // the channel outlives the group
+        ScimGroup::delete(1, group.id as _, &pool).await?;
+        assert!(ScimGroup::find(1, group.id as _, &pool).await?.is_none());
+        assert!(Chat::get_by_id(group.chat_id as _, &pool).await?.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+use chrono::{DateTime, Duration, Utc};
+use serde_json::Value;
+use sqlx::PgPool;
+
+use crate::{config::SigninThrottleConfig, utils::generate_token, AppError, User};
+
+// failures older than this are not counted towards repeated failures
+const SIGNIN_FAILURE_WINDOW_MINUTES: i64 = 15;
+const SIGNIN_ALERT_TOKEN_BYTES: usize = 32;
+// how long the "this wasn't me" link keeps working
+const SIGNIN_ALERT_TTL_DAYS: i32 = 7;
+
+impl User {
+    // remembers the device the user signed in from, returns true when it has not been seen
+    // before and the user already had other devices, i.e. the signin deserves a warning
+    pub async fn record_device(&self, user_agent: &str, pool: &PgPool) -> Result<bool, AppError> {
+        let (inserted, known): (bool, i64) = sqlx::query_as(
+            r#"
+            WITH known AS (
+                SELECT COUNT(*) AS n FROM user_devices WHERE user_id = $1
+            ), upsert AS (
+                INSERT INTO user_devices (user_id, fingerprint, user_agent)
+                VALUES ($1, sha256(convert_to($2, 'UTF8')), $2)
+                ON CONFLICT (user_id, fingerprint) DO UPDATE SET last_seen_at = NOW()
+                RETURNING (xmax = 0) AS inserted
+            )
+            SELECT upsert.inserted, known.n FROM upsert, known
+            "#,
+        )
+        .bind(self.id)
+        .bind(user_agent)
+        .fetch_one(pool)
+        .await?;
+        Ok(inserted && known > 0)
+    }
+
+    // same as record_device, for the country the signin came from
+    pub async fn record_country(&self, country: &str, pool: &PgPool) -> Result<bool, AppError> {
+        let (inserted, known): (bool, i64) = sqlx::query_as(
+            r#"
+            WITH known AS (
+                SELECT COUNT(*) AS n FROM user_signin_countries WHERE user_id = $1
+            ), upsert AS (
+                INSERT INTO user_signin_countries (user_id, country)
+                VALUES ($1, $2)
+                ON CONFLICT (user_id, country) DO UPDATE SET last_seen_at = NOW()
+                RETURNING (xmax = 0) AS inserted
+            )
+            SELECT upsert.inserted, known.n FROM upsert, known
+            "#,
+        )
+        .bind(self.id)
+        .bind(country.to_uppercase())
+        .fetch_one(pool)
+        .await?;
+        Ok(inserted && known > 0)
+    }
+
+    // returns the token for the "this wasn't me" link
+    pub async fn create_signin_alert(&self, details: &Value, pool: &PgPool) -> Result<String, AppError> {
+        let token = generate_token(SIGNIN_ALERT_TOKEN_BYTES);
+        sqlx::query(
+            r#"
+            INSERT INTO signin_alerts (user_id, token_hash, details)
+            VALUES ($1, sha256(convert_to($2, 'UTF8')), $3)
+            "#,
+        )
+        .bind(self.id)
+        .bind(&token)
+        .bind(details)
+        .execute(pool)
+        .await?;
+        Ok(token)
+    }
+
+    // the user says they did not sign in: their password is considered compromised, so every
+    // session is revoked and they have to reset it. Access tokens already handed out stay
+    // valid until they expire, which is short.
+    pub async fn deny_signin(token: &str, pool: &PgPool) -> Result<User, AppError> {
+        let mut tx = pool.begin().await?;
+        let user: Option<User> = sqlx::query_as(
+            r#"
+            UPDATE users u SET password_reset_required = TRUE
+            FROM signin_alerts a
+            WHERE a.user_id = u.id AND a.token_hash = sha256(convert_to($1, 'UTF8'))
+                AND a.denied_at IS NULL AND a.created_at > NOW() - make_interval(days => $2)
+            RETURNING u.id, u.ws_id, u.fullname, u.email, u.created_at
+            "#,
+        )
+        .bind(token)
+        .bind(SIGNIN_ALERT_TTL_DAYS)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(user) = user else {
+            return Err(AppError::NotFound("signin alert is invalid or has expired".to_string()));
+        };
+        sqlx::query("UPDATE signin_alerts SET denied_at = NOW() WHERE token_hash = sha256(convert_to($1, 'UTF8'))")
+            .bind(token)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL")
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+
+    pub async fn is_password_reset_required(&self, pool: &PgPool) -> Result<bool, AppError> {
+        let required: bool = sqlx::query_scalar("SELECT password_reset_required FROM users WHERE id = $1")
+            .bind(self.id)
+            .fetch_one(pool)
+            .await?;
+        Ok(required)
+    }
+
+    // returns the number of failures within the window, including this one
+    // counted by email whether or not it has an account, so that throttling does not tell
+    // them apart. Returns the recent failures for the email.
+    pub async fn record_signin_failure(email: &str, ip: Option<&str>, pool: &PgPool) -> Result<i64, AppError> {
+        let email = email.trim().to_lowercase();
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO signin_failures (user_id, email, ip)
+            VALUES ((SELECT id FROM users WHERE lower(email) = $1), $1, $2)
+            "#,
+        )
+        .bind(&email)
+        .bind(ip)
+        .execute(&mut *tx)
+        .await?;
+        let count: i64 = sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*) FROM signin_failures
+            WHERE email = $1 AND created_at > NOW() - make_interval(mins => $2)
+            "#,
+        )
+        .bind(&email)
+        .bind(SIGNIN_FAILURE_WINDOW_MINUTES as i32)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(count)
+    }
+
+    // seconds until the next signin attempt for the email, or from the ip, is accepted
+    pub async fn signin_retry_after(
+        email: &str,
+        ip: Option<&str>,
+        config: &SigninThrottleConfig,
+        pool: &PgPool,
+    ) -> Result<Option<u64>, AppError> {
+        let email = email.trim().to_lowercase();
+        let (email_failures, email_last, ip_failures, ip_last): (i64, Option<DateTime<Utc>>, i64, Option<DateTime<Utc>>) =
+            sqlx::query_as(
+                r#"
+                SELECT COUNT(*) FILTER (WHERE email = $1), MAX(created_at) FILTER (WHERE email = $1),
+                    COUNT(*) FILTER (WHERE ip = $2), MAX(created_at) FILTER (WHERE ip = $2)
+                FROM signin_failures
+                WHERE (email = $1 OR ip = $2) AND created_at > NOW() - make_interval(secs => $3)
+                "#,
+            )
+            .bind(&email)
+            .bind(ip)
+            .bind(config.window_secs as f64)
+            .fetch_one(pool)
+            .await?;
+        let now = Utc::now();
+        let retry_after = [
+            backoff_until(email_failures, email_last, config.email_failures, config),
+            backoff_until(ip_failures, ip_last, config.ip_failures, config),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|until| (until - now).num_seconds())
+        .filter(|secs| *secs > 0)
+        .max();
+        Ok(retry_after.map(|secs| secs as u64))
+    }
+
+    // the password checked out, earlier failures for the email are forgiven
+    pub async fn clear_signin_failures(email: &str, pool: &PgPool) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM signin_failures WHERE email = $1")
+            .bind(email.trim().to_lowercase())
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+}
+
+// the base delay once the threshold is reached, doubling with every further failure
+fn backoff_until(
+    failures: i64,
+    last: Option<DateTime<Utc>>,
+    threshold: u32,
+    config: &SigninThrottleConfig,
+) -> Option<DateTime<Utc>> {
+    let last = last?;
+    let over = failures - threshold as i64;
+    if threshold == 0 || over < 0 {
+        return None;
+    }
+    let delay = (config.base_delay_secs as i64)
+        .saturating_mul(1i64 << over.min(32))
+        .min(config.max_delay_secs as i64);
+    Some(last + Duration::seconds(delay))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, utils::TokenId, ClientInfo, RefreshToken};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn record_device_should_flag_new_devices() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        // the very first device is not suspicious
+        assert!(!user.record_device("firefox", &pool).await?);
+        assert!(!user.record_device("firefox", &pool).await?);
+        assert!(user.record_device("curl", &pool).await?);
+        assert!(!user.record_device("curl", &pool).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn record_country_should_flag_new_countries() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        assert!(!user.record_country("us", &pool).await?);
+        assert!(!user.record_country("US", &pool).await?);
+        assert!(user.record_country("FR", &pool).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deny_signin_should_revoke_sessions() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let refresh_token = RefreshToken::issue(&user, false, &ClientInfo::default(), &TokenId::generate(), &pool).await?;
+        let token = user.create_signin_alert(&serde_json::json!({ "country": "FR" }), &pool).await?;
+        assert!(!user.is_password_reset_required(&pool).await?);
+
+        let denied = User::deny_signin(&token, &pool).await?;
+        assert_eq!(denied.id, user.id);
+        assert!(user.is_password_reset_required(&pool).await?);
+        assert!(RefreshToken::rotate(&refresh_token, &TokenId::generate(), &pool).await.is_err());
+        // the link works once
+        let ret = User::deny_signin(&token, &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn record_signin_failure_should_count() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        assert_eq!(User::record_signin_failure(&user.email, None, &pool).await?, 1);
+        assert_eq!(User::record_signin_failure(" TCHEN@acme.org", None, &pool).await?, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_failures_should_back_off() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let config = SigninThrottleConfig {
+            email_failures: 3,
+            ip_failures: 5,
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            User::record_signin_failure("nobody@acme.org", Some("10.0.0.1"), &pool).await?;
+        }
+        assert_eq!(User::signin_retry_after("nobody@acme.org", None, &config, &pool).await?, None);
+        User::record_signin_failure("nobody@acme.org", Some("10.0.0.1"), &pool).await?;
+        let secs = User::signin_retry_after("nobody@acme.org", None, &config, &pool).await?;
+        assert!(secs.is_some_and(|s| s > 25 && s <= 30));
+        User::record_signin_failure("nobody@acme.org", Some("10.0.0.1"), &pool).await?;
+        let secs = User::signin_retry_after("Nobody@acme.org", None, &config, &pool).await?;
+        assert!(secs.is_some_and(|s| s > 55 && s <= 60));
+
+        // the ip is throttled across emails
+        assert_eq!(User::signin_retry_after("alice@acme.org", Some("10.0.0.1"), &config, &pool).await?, None);
+        User::record_signin_failure("alice@acme.org", Some("10.0.0.1"), &pool).await?;
+        assert!(User::signin_retry_after("alice@acme.org", Some("10.0.0.1"), &config, &pool).await?.is_some());
+        assert_eq!(User::signin_retry_after("alice@acme.org", Some("10.0.0.2"), &config, &pool).await?, None);
+
+        User::clear_signin_failures("nobody@acme.org", &pool).await?;
+        assert_eq!(User::signin_retry_after("nobody@acme.org", None, &config, &pool).await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{utils::TokenId, AppError, RevokedToken, User, UserSession};
+
+use super::refresh_token::revoke_family;
+
+// where a session was started from, taken from the signin request
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientInfo {
+    pub user_agent: String,
+    pub ip: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn new(user_agent: impl Into<String>, ip: Option<String>) -> Self {
+        Self {
+            user_agent: user_agent.into(),
+            ip,
+        }
+    }
+
+    // e.g. "Firefox on macOS", good enough to tell one's devices apart
+    pub fn device_name(&self) -> String {
+        let ua = self.user_agent.as_str();
+        let browser = [
+            ("Edg/", "Edge"),
+            ("OPR/", "Opera"),
+            ("Firefox/", "Firefox"),
+            ("Chrome/", "Chrome"),
+            ("Safari/", "Safari"),
+            ("curl/", "curl"),
+        ]
+        .into_iter()
+        .find(|(token, _)| ua.contains(token))
+        .map(|(_, name)| name);
+        let os = [
+            ("iPhone", "iOS"),
+            ("iPad", "iPadOS"),
+            ("Android", "Android"),
+            ("Windows", "Windows"),
+            ("Mac OS X", "macOS"),
+            ("CrOS", "ChromeOS"),
+            ("Linux", "Linux"),
+        ]
+        .into_iter()
+        .find(|(token, _)| ua.contains(token))
+        .map(|(_, name)| name);
+        match (browser, os) {
+            (Some(browser), Some(os)) => format!("{} on {}", browser, os),
+            (Some(name), None) | (None, Some(name)) => name.to_string(),
+            (None, None) => "Unknown device".to_string(),
+        }
+    }
+}
+
+impl User {
+    // sessions that can still be refreshed, most recently active first
+    pub async fn sessions(&self, current: &TokenId, pool: &PgPool) -> Result<Vec<UserSession>, AppError> {
+        let sessions = sqlx::query_as(
+            r#"
+            SELECT s.id, s.device_name, s.user_agent, s.ip, s.last_active_at, s.created_at,
+                COALESCE(s.access_jti = $2, FALSE) AS current
+            FROM user_sessions s
+            WHERE s.user_id = $1 AND EXISTS (
+                SELECT 1 FROM refresh_tokens t
+                WHERE t.family_id = s.family_id AND t.used_at IS NULL AND t.revoked_at IS NULL AND t.expires_at > NOW()
+            )
+            ORDER BY s.last_active_at DESC, s.id DESC
+            "#,
+        )
+        .bind(self.id)
+        .bind(&current.jti)
+        .fetch_all(pool)
+        .await?;
+        Ok(sessions)
+    }
+
+    // signs the device out: its refresh tokens and latest access token stop working
+    pub async fn revoke_session(&self, id: u64, pool: &PgPool) -> Result<(), AppError> {
+        let mut tx = pool.begin().await?;
+        let session: Option<(String, Option<String>, Option<DateTime<Utc>>)> = sqlx::query_as(
+            "SELECT family_id, access_jti, access_expires_at FROM user_sessions WHERE id = $1 AND user_id = $2",
+        )
+        .bind(id as i64)
+        .bind(self.id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((family_id, access_jti, access_expires_at)) = session else {
+            return Err(AppError::NotFound(format!("session {}", id)));
+        };
+        revoke_family(&mut tx, &family_id).await?;
+        tx.commit().await?;
+        if let (Some(jti), Some(expires_at)) = (access_jti, access_expires_at) {
+            RevokedToken::create(&TokenId { jti, expires_at }, self.id as _, pool).await?;
+        }
+        Ok(())
+    }
+}
+
+pub(super) async fn insert_session(
+    tx: &mut Transaction<'_, Postgres>,
+    family_id: &str,
+    user_id: i64,
+    client: &ClientInfo,
+    access: &TokenId,
+) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        INSERT INTO user_sessions (family_id, user_id, device_name, user_agent, ip, access_jti, access_expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+    )
+    .bind(family_id)
+    .bind(user_id)
+    .bind(client.device_name())
+    .bind(&client.user_agent)
+    .bind(client.ip.as_ref().map(|ip| ip.chars().take(64).collect::<String>()))
+    .bind(&access.jti)
+    .bind(access.expires_at)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+pub(super) async fn touch_session(tx: &mut Transaction<'_, Postgres>, family_id: &str, access: &TokenId) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        UPDATE user_sessions SET access_jti = $2, access_expires_at = $3, last_active_at = NOW()
+        WHERE family_id = $1
+        "#,
+    )
+    .bind(family_id)
+    .bind(&access.jti)
+    .bind(access.expires_at)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, RefreshToken};
+    use anyhow::Result;
+
+    #[test]
+    fn device_name_should_be_readable() {
+        let ua = "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_0; rv:128.0) Gecko/20100101 Firefox/128.0";
+        assert_eq!(ClientInfo::new(ua, None).device_name(), "Firefox on macOS");
+        let ua = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 Chrome/126.0 Safari/537.36 Edg/126.0";
+        assert_eq!(ClientInfo::new(ua, None).device_name(), "Edge on Windows");
+        assert_eq!(ClientInfo::new("curl/8.4.0", None).device_name(), "curl");
+        assert_eq!(ClientInfo::default().device_name(), "Unknown device");
+    }
+
+    #[tokio::test]
+    async fn sessions_should_be_listed_and_revoked() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let laptop = TokenId::generate();
+        let client = ClientInfo::new("Firefox/128.0 (Linux)", Some("10.0.0.1".to_string()));
+        let token = RefreshToken::issue(&user, false, &client, &laptop, &pool).await?;
+        let phone = TokenId::generate();
+        RefreshToken::issue(&user, false, &ClientInfo::new("iPhone Safari/17", None), &phone, &pool).await?;
+
+        let sessions = user.sessions(&laptop, &pool).await?;
+        assert_eq!(sessions.len(), 2);
+        let current = sessions.iter().find(|s| s.current).expect("current session");
+        assert_eq!(current.device_name, "Firefox on Linux");
+        assert_eq!(current.ip.as_deref(), Some("10.0.0.1"));
+
+        // the session follows its refresh tokens
+        let renewed = TokenId::generate();
+        let (_, token) = RefreshToken::rotate(&token, &renewed, &pool).await?;
+        let sessions = user.sessions(&renewed, &pool).await?;
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].device_name, "Firefox on Linux");
+        assert!(sessions[0].current);
+
+        let other = User::find_by_email("alice@acme.org", &pool).await?.expect("user should exist");
+        let ret = other.revoke_session(sessions[0].id as _, &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        user.revoke_session(sessions[0].id as _, &pool).await?;
+        assert!(RefreshToken::rotate(&token, &TokenId::generate(), &pool).await.is_err());
+        assert!(RevokedToken::is_revoked(&renewed.jti, &pool).await?);
+        assert!(!RevokedToken::is_revoked(&phone.jti, &pool).await?);
+        let sessions = user.sessions(&renewed, &pool).await?;
+        assert_eq!(sessions.len(), 1);
+        assert!(!sessions[0].current);
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{AppError, SessionPolicy};
+
+// shorter sessions would expire before the access token does
+const MIN_SESSION_SECS: i32 = 60 * 15;
+const MAX_SESSION_SECS: i32 = 60 * 60 * 24 * 365;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateSessionPolicy {
+    #[serde(default)]
+    pub idle_timeout: Option<i32>,
+    #[serde(default)]
+    pub max_age: Option<i32>,
+    #[serde(default)]
+    pub remember_me_max_age: Option<i32>,
+}
+
+impl SessionPolicy {
+    pub async fn fetch(ws_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let policy: Option<Self> = sqlx::query_as(
+            r#"
+            SELECT session_idle_timeout AS idle_timeout, session_max_age AS max_age,
+                session_remember_me_max_age AS remember_me_max_age
+            FROM workspaces
+            WHERE id = $1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_optional(pool)
+        .await?;
+        policy.ok_or_else(|| AppError::NotFound(format!("workspace not found: {}", ws_id)))
+    }
+
+    pub async fn update(ws_id: u64, input: &UpdateSessionPolicy, pool: &PgPool) -> Result<Self, AppError> {
+        let current = Self::fetch(ws_id, pool).await?;
+        let policy = Self {
+            idle_timeout: input.idle_timeout.unwrap_or(current.idle_timeout),
+            max_age: input.max_age.unwrap_or(current.max_age),
+            remember_me_max_age: input.remember_me_max_age.unwrap_or(current.remember_me_max_age),
+        };
+        for secs in [policy.idle_timeout, policy.max_age, policy.remember_me_max_age] {
+            if !(MIN_SESSION_SECS..=MAX_SESSION_SECS).contains(&secs) {
+                return Err(AppError::UpdateWorkspaceError(format!(
+                    "Session lifetimes must be between {} and {} seconds",
+                    MIN_SESSION_SECS, MAX_SESSION_SECS
+                )));
+            }
+        }
+        if policy.idle_timeout > policy.max_age {
+            return Err(AppError::UpdateWorkspaceError(
+                "Idle timeout cannot exceed the session max age".to_string(),
+            ));
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE workspaces
+            SET session_idle_timeout = $2, session_max_age = $3, session_remember_me_max_age = $4
+            WHERE id = $1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(policy.idle_timeout)
+        .bind(policy.max_age)
+        .bind(policy.remember_me_max_age)
+        .execute(pool)
+        .await?;
+        Ok(policy)
+    }
+
+    // how long a session may live from signin
+    pub fn session_max_age(&self, remember_me: bool) -> i32 {
+        if remember_me {
+            self.remember_me_max_age
+        } else {
+            self.max_age
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn session_policy_should_update() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = UpdateSessionPolicy {
+            idle_timeout: Some(3600),
+            ..Default::default()
+        };
+        let policy = SessionPolicy::update(1, &input, &pool).await?;
+        assert_eq!(policy, SessionPolicy::fetch(1, &pool).await?);
+        assert_eq!(policy.idle_timeout, 3600);
+        assert_eq!(policy.session_max_age(false), 2592000);
+        assert_eq!(policy.session_max_age(true), 7776000);
+
+        let input = UpdateSessionPolicy {
+            idle_timeout: Some(10),
+            ..Default::default()
+        };
+        let ret = SessionPolicy::update(1, &input, &pool).await;
+        assert!(matches!(ret, Err(AppError::UpdateWorkspaceError(_))));
+        let input = UpdateSessionPolicy {
+            max_age: Some(1800),
+            ..Default::default()
+        };
+        let ret = SessionPolicy::update(1, &input, &pool).await;
+        assert!(matches!(ret, Err(AppError::UpdateWorkspaceError(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{utils::{check_public_url, generate_token}, AppError, SlashCommand};
+
+const COMMAND_SECRET_BYTES: usize = 32;
+const MAX_COMMANDS_PER_WORKSPACE: i64 = 50;
+const MAX_NAME_LEN: usize = 32;
+const MAX_DESCRIPTION_CHARS: usize = 256;
+// the builtins, see commands::run
+const RESERVED_NAMES: &[&str] = &["me", "shrug", "help"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateSlashCommand {
+    // without the leading slash
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+// lowercase letters, digits, - and _, so that paths like /usr/bin in a message aren't commands
+pub(crate) fn is_command_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+impl SlashCommand {
+    // the secret is only returned here, callers need it to verify the call signatures
+    pub async fn create(ws_id: u64, input: &CreateSlashCommand, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let name = input.name.trim().trim_start_matches('/');
+        if !is_command_name(name) {
+            return Err(AppError::InvalidInput(format!(
+                "command name must be 1 to {} lowercase letters, digits, - or _",
+                MAX_NAME_LEN
+            )));
+        }
+        if RESERVED_NAMES.contains(&name) {
+            return Err(AppError::InvalidInput(format!("/{} is a builtin command", name)));
+        }
+        let url = input.url.trim();
+        check_public_url(url, "command")?;
+        let description = input.description.trim();
+        if description.chars().count() > MAX_DESCRIPTION_CHARS {
+            return Err(AppError::InvalidInput(format!(
+                "description must be at most {} characters",
+                MAX_DESCRIPTION_CHARS
+            )));
+        }
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM slash_commands WHERE ws_id = $1")
+            .bind(ws_id as i64)
+            .fetch_one(pool)
+            .await?;
+        if count >= MAX_COMMANDS_PER_WORKSPACE {
+            return Err(AppError::InvalidInput(format!(
+                "a workspace can have at most {} commands",
+                MAX_COMMANDS_PER_WORKSPACE
+            )));
+        }
+
+        let command = sqlx::query_as(
+            r#"
+            INSERT INTO slash_commands (ws_id, name, url, description, secret, created_by)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, ws_id, name, url, description, secret, created_by, created_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(name)
+        .bind(url)
+        .bind(description)
+        .bind(generate_token(COMMAND_SECRET_BYTES))
+        .bind(user_id as i64)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(e) if e.is_unique_violation() => {
+                AppError::InvalidInput(format!("/{} already exists", name))
+            }
+            e => e.into(),
+        })?;
+        Ok(command)
+    }
+
+    pub async fn list(ws_id: u64, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let commands = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, url, description, created_by, created_at
+            FROM slash_commands
+            WHERE ws_id = $1
+            ORDER BY name
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(commands)
+    }
+
+    // with the secret, for signing the call
+    pub async fn find_by_name(ws_id: u64, name: &str, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let command = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, url, description, secret, created_by, created_at
+            FROM slash_commands
+            WHERE ws_id = $1 AND name = $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+        Ok(command)
+    }
+
+    pub async fn delete(ws_id: u64, id: u64, pool: &PgPool) -> Result<(), AppError> {
+        let ret = sqlx::query("DELETE FROM slash_commands WHERE id = $1 AND ws_id = $2")
+            .bind(id as i64)
+            .bind(ws_id as i64)
+            .execute(pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("command {}", id)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    fn deploy() -> CreateSlashCommand {
+        CreateSlashCommand {
+            name: "/deploy".to_string(),
+            url: "https://ops.acme.org/deploy".to_string(),
+            description: "deploy a service".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn slash_command_crud_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let command = SlashCommand::create(1, &deploy(), 1, &pool).await?;
+        assert_eq!(command.name, "deploy");
+        assert_eq!(command.secret.as_ref().map(|s| s.len()), Some(64));
+        let ret = SlashCommand::create(1, &deploy(), 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+
+        let commands = SlashCommand::list(1, &pool).await?;
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].secret.is_none());
+        let found = SlashCommand::find_by_name(1, "deploy", &pool).await?.expect("command");
+        assert_eq!(found.secret, command.secret);
+        assert!(SlashCommand::find_by_name(2, "deploy", &pool).await?.is_none());
+
+        SlashCommand::delete(1, command.id as _, &pool).await?;
+        assert!(SlashCommand::list(1, &pool).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slash_command_should_not_shadow_builtins() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateSlashCommand { name: "shrug".to_string(), ..deploy() };
+        let ret = SlashCommand::create(1, &input, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        let input = CreateSlashCommand { name: "Deploy Now".to_string(), ..deploy() };
+        let ret = SlashCommand::create(1, &input, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        // the server must not be made to call its own network
+        let input = CreateSlashCommand { url: "http://169.254.169.254/latest".to_string(), ..deploy() };
+        let ret = SlashCommand::create(1, &input, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn is_command_name_should_reject_paths() {
+        assert!(is_command_name("deploy"));
+        assert!(is_command_name("on-call_2"));
+        assert!(!is_command_name("usr/bin"));
+        assert!(!is_command_name(""));
+    }
+}
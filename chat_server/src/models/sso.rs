@@ -0,0 +1,325 @@
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+use crate::{oauth::OAuthIdentity, utils::generate_token, AppError, OidcConfig, User};
+
+const STATE_TOKEN_BYTES: usize = 32;
+const CODE_VERIFIER_BYTES: usize = 48;
+const NONCE_BYTES: usize = 16;
+const STATE_TTL_SECS: i64 = 600;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateOidcConfig {
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    // kept when not given
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    #[serde(default)]
+    pub jit_provisioning: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SsoLogin {
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+// a pending signin at the workspace's identity provider
+#[derive(Debug, Clone, FromRow, PartialEq)]
+pub(crate) struct OidcSignin {
+    pub(crate) ws_id: i64,
+    pub(crate) code_verifier: String,
+    pub(crate) nonce: String,
+    pub(crate) remember_me: bool,
+}
+
+impl OidcConfig {
+    pub async fn fetch(ws_id: u64, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let config = sqlx::query_as(
+            r#"
+            SELECT ws_id, issuer, client_id, client_secret, jit_provisioning, created_at, updated_at
+            FROM workspace_oidc
+            WHERE ws_id = $1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_optional(pool)
+        .await?;
+        Ok(config)
+    }
+
+    pub async fn update(ws_id: u64, input: &UpdateOidcConfig, pool: &PgPool) -> Result<Self, AppError> {
+        let current = Self::fetch(ws_id, pool).await?;
+        let pick = |new: &Option<String>, current: Option<&String>| {
+            new.as_deref().map(str::trim).map(str::to_string).or_else(|| current.cloned())
+        };
+        let issuer = pick(&input.issuer, current.as_ref().map(|c| &c.issuer));
+        let client_id = pick(&input.client_id, current.as_ref().map(|c| &c.client_id));
+        let client_secret = pick(&input.client_secret, current.as_ref().map(|c| &c.client_secret));
+        let jit_provisioning = input
+            .jit_provisioning
+            .or(current.as_ref().map(|c| c.jit_provisioning))
+            .unwrap_or(true);
+        let (Some(issuer), Some(client_id), Some(client_secret)) = (issuer, client_id, client_secret) else {
+            return Err(AppError::UpdateWorkspaceError(
+                "OIDC needs an issuer, a client id and a client secret".to_string(),
+            ));
+        };
+        if !is_valid_issuer(&issuer) {
+            return Err(AppError::UpdateWorkspaceError(format!("Invalid OIDC issuer: {}", issuer)));
+        }
+        if client_id.is_empty() || client_id.len() > 255 || client_secret.is_empty() || client_secret.len() > 255 {
+            return Err(AppError::UpdateWorkspaceError(
+                "OIDC client id and secret must be 1 to 255 characters".to_string(),
+            ));
+        }
+
+        let config = sqlx::query_as(
+            r#"
+            INSERT INTO workspace_oidc (ws_id, issuer, client_id, client_secret, jit_provisioning)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (ws_id) DO UPDATE SET
+                issuer = EXCLUDED.issuer, client_id = EXCLUDED.client_id, client_secret = EXCLUDED.client_secret,
+                jit_provisioning = EXCLUDED.jit_provisioning, updated_at = NOW()
+            RETURNING ws_id, issuer, client_id, client_secret, jit_provisioning, created_at, updated_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(&issuer)
+        .bind(&client_id)
+        .bind(&client_secret)
+        .bind(jit_provisioning)
+        .fetch_one(pool)
+        .await?;
+        Ok(config)
+    }
+
+    pub async fn delete(ws_id: u64, pool: &PgPool) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM workspace_oidc WHERE ws_id = $1")
+            .bind(ws_id as i64)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    // identities are namespaced per workspace, two workspaces may use the same issuer
+    pub(crate) fn provider(&self) -> String {
+        format!("oidc:{}", self.ws_id)
+    }
+
+    // returns the state to send to the identity provider, and the signin it stands for
+    pub(crate) async fn create_signin(&self, remember_me: bool, pool: &PgPool) -> Result<(String, OidcSignin), AppError> {
+        let state = generate_token(STATE_TOKEN_BYTES);
+        let signin = OidcSignin {
+            ws_id: self.ws_id,
+            code_verifier: generate_token(CODE_VERIFIER_BYTES),
+            nonce: generate_token(NONCE_BYTES),
+            remember_me,
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO oauth_states (state_hash, provider, ws_id, code_verifier, nonce, remember_me, expires_at)
+            VALUES (sha256(convert_to($1, 'UTF8')), $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+        .bind(&state)
+        .bind(self.provider())
+        .bind(signin.ws_id)
+        .bind(&signin.code_verifier)
+        .bind(&signin.nonce)
+        .bind(remember_me)
+        .bind(Utc::now() + Duration::seconds(STATE_TTL_SECS))
+        .execute(pool)
+        .await?;
+        Ok((state, signin))
+    }
+
+    pub(crate) async fn redeem_signin(state: &str, pool: &PgPool) -> Result<OidcSignin, AppError> {
+        let signin = sqlx::query_as(
+            r#"
+            DELETE FROM oauth_states
+            WHERE state_hash = sha256(convert_to($1, 'UTF8')) AND ws_id IS NOT NULL AND nonce IS NOT NULL
+                AND expires_at > NOW()
+            RETURNING ws_id, code_verifier, nonce, remember_me
+            "#,
+        )
+        .bind(state)
+        .fetch_optional(pool)
+        .await?;
+        signin.ok_or_else(|| AppError::PermissionDenied("sso state is invalid or has expired".to_string()))
+    }
+}
+
+impl User {
+    // the linked user, else the member with the same email, else a new member when the
+    // workspace provisions users just in time
+    pub(crate) async fn from_oidc_identity(config: &OidcConfig, identity: &OAuthIdentity, pool: &PgPool) -> Result<Self, AppError> {
+        let provider = config.provider();
+        let mut tx = pool.begin().await?;
+        let linked: Option<User> = sqlx::query_as(
+            r#"
+            SELECT u.id, u.ws_id, u.fullname, u.email, u.created_at
+            FROM user_identities i JOIN users u ON u.id = i.user_id
+            WHERE i.provider = $1 AND i.subject = $2
+            "#,
+        )
+        .bind(&provider)
+        .bind(&identity.subject)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(user) = linked {
+            return Ok(user);
+        }
+
+        let existing: Option<User> = sqlx::query_as("SELECT id, ws_id, fullname, email, created_at FROM users WHERE email = $1")
+            .bind(&identity.email)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let user = match existing {
+            Some(user) if user.ws_id != config.ws_id => {
+                return Err(AppError::PermissionDenied(format!(
+                    "{} belongs to another workspace",
+                    identity.email
+                )));
+            }
+            Some(user) => {
+                sqlx::query("UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW()) WHERE id = $1")
+                    .bind(user.id)
+                    .execute(&mut *tx)
+                    .await?;
+                user
+            }
+            None if !config.jit_provisioning => {
+                return Err(AppError::PermissionDenied(format!(
+                    "{} is not a member of this workspace",
+                    identity.email
+                )));
+            }
+            None => {
+                if identity.email.len() > 64 {
+                    return Err(AppError::InvalidInput(format!("email is too long: {}", identity.email)));
+                }
+                let fullname: String = identity.name.chars().take(64).collect();
+                sqlx::query_as(
+                    r#"
+                    INSERT INTO users (ws_id, email, fullname, email_verified_at)
+                    VALUES ($1, $2, $3, NOW())
+                    RETURNING id, ws_id, fullname, email, created_at
+                    "#,
+                )
+                .bind(config.ws_id)
+                .bind(&identity.email)
+                .bind(&fullname)
+                .fetch_one(&mut *tx)
+                .await?
+            }
+        };
+        sqlx::query("INSERT INTO user_identities (provider, subject, user_id, email) VALUES ($1, $2, $3, $4)")
+            .bind(&provider)
+            .bind(&identity.subject)
+            .bind(user.id)
+            .bind(&identity.email)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+}
+
+// https, or http for an identity provider on this machine
+fn is_valid_issuer(issuer: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(issuer) else {
+        return false;
+    };
+    let local = matches!(url.host_str(), Some("localhost" | "127.0.0.1"));
+    issuer.len() <= 255
+        && (url.scheme() == "https" || (url.scheme() == "http" && local))
+        && url.query().is_none()
+        && url.fragment().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    fn identity(subject: &str, email: &str) -> OAuthIdentity {
+        OAuthIdentity {
+            subject: subject.to_string(),
+            email: email.to_string(),
+            name: "Sso User".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn oidc_config_should_keep_secret_on_update() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = UpdateOidcConfig { issuer: Some("https://idp.acme.org".to_string()), ..Default::default() };
+        assert!(OidcConfig::update(1, &input, &pool).await.is_err());
+        let input = UpdateOidcConfig {
+            issuer: Some("https://idp.acme.org".to_string()),
+            client_id: Some("chat".to_string()),
+            client_secret: Some("secret".to_string()),
+            jit_provisioning: None,
+        };
+        OidcConfig::update(1, &input, &pool).await?;
+        let input = UpdateOidcConfig { jit_provisioning: Some(false), ..Default::default() };
+        let config = OidcConfig::update(1, &input, &pool).await?;
+        assert_eq!(config.client_secret, "secret");
+        assert!(!config.jit_provisioning);
+
+        let input = UpdateOidcConfig { issuer: Some("http://idp.acme.org".to_string()), ..Default::default() };
+        assert!(OidcConfig::update(1, &input, &pool).await.is_err());
+        OidcConfig::delete(1, &pool).await?;
+        assert!(OidcConfig::fetch(1, &pool).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oidc_identity_should_map_to_workspace_members() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = UpdateOidcConfig {
+            issuer: Some("https://idp.acme.org".to_string()),
+            client_id: Some("chat".to_string()),
+            client_secret: Some("secret".to_string()),
+            jit_provisioning: Some(false),
+        };
+        let config = OidcConfig::update(1, &input, &pool).await?;
+        let user = User::from_oidc_identity(&config, &identity("s1", "alice@acme.org"), &pool).await?;
+        assert_eq!(user.email, "alice@acme.org");
+        // linked by subject from now on
+        let again = User::from_oidc_identity(&config, &identity("s1", "alice@idp.org"), &pool).await?;
+        assert_eq!(again.id, user.id);
+        let ret = User::from_oidc_identity(&config, &identity("s2", "new@acme.org"), &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let input = UpdateOidcConfig { jit_provisioning: Some(true), ..Default::default() };
+        let config = OidcConfig::update(1, &input, &pool).await?;
+        let user = User::from_oidc_identity(&config, &identity("s2", "new@acme.org"), &pool).await?;
+        assert_eq!(user.ws_id, 1);
+
+        // members of other workspaces cannot be taken over
+        let other = OidcConfig::update(2, &input_for("https://idp.foo.org"), &pool).await?;
+        let ret = User::from_oidc_identity(&other, &identity("s3", "bob@acme.org"), &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let (state, signin) = config.create_signin(true, &pool).await?;
+        assert_eq!(OidcConfig::redeem_signin(&state, &pool).await?, signin);
+        assert!(OidcConfig::redeem_signin(&state, &pool).await.is_err());
+        Ok(())
+    }
+
+    fn input_for(issuer: &str) -> UpdateOidcConfig {
+        UpdateOidcConfig {
+            issuer: Some(issuer.to_string()),
+            client_id: Some("chat".to_string()),
+            client_secret: Some("secret".to_string()),
+            jit_provisioning: None,
+        }
+    }
+}
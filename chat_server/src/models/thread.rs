@@ -0,0 +1,150 @@
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{AppError, Message};
+
+impl Message {
+    pub async fn follow_thread(thread_id: u64, user_id: u64, pool: &PgPool) -> Result<(), AppError> {
+        set_following(thread_id, user_id, true, pool).await
+    }
+
+    pub async fn unfollow_thread(thread_id: u64, user_id: u64, pool: &PgPool) -> Result<(), AppError> {
+        set_following(thread_id, user_id, false, pool).await
+    }
+
+    pub async fn thread_followers(thread_id: u64, pool: &PgPool) -> Result<Vec<i64>, AppError> {
+        let users = sqlx::query_scalar(
+            r#"
+            SELECT user_id FROM thread_subscriptions
+            WHERE thread_id = $1 AND following
+            ORDER BY user_id
+            "#,
+        )
+        .bind(thread_id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(users)
+    }
+}
+
+async fn set_following(thread_id: u64, user_id: u64, following: bool, pool: &PgPool) -> Result<(), AppError> {
+    let ret = sqlx::query(
+        r#"
+        INSERT INTO thread_subscriptions (thread_id, user_id, following)
+        SELECT m.id, $2, $3
+        FROM messages m JOIN chats c ON c.id = m.chat_id
+        WHERE m.id = $1 AND m.thread_id IS NULL AND $2 = ANY(c.members)
+        ON CONFLICT (thread_id, user_id)
+        DO UPDATE SET following = EXCLUDED.following, updated_at = NOW()
+        "#,
+    )
+    .bind(thread_id as i64)
+    .bind(user_id as i64)
+    .bind(following)
+    .execute(pool)
+    .await?;
+    if ret.rows_affected() == 0 {
+        return Err(AppError::NotFound(format!("thread not found: {}", thread_id)));
+    }
+    Ok(())
+}
+
+// replying (re)subscribes the sender, the root author is subscribed unless they opted out,
+// then every follower except the sender gets a notification. Chat level settings do not
+// apply here, following a thread is an explicit opt in.
+pub(super) async fn record_reply(tx: &mut Transaction<'_, Postgres>, reply: &Message) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        INSERT INTO thread_subscriptions (thread_id, user_id)
+        SELECT id, sender_id FROM messages WHERE id = $1
+        ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(reply.thread_id)
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO thread_subscriptions (thread_id, user_id)
+        VALUES ($1, $2)
+        ON CONFLICT (thread_id, user_id)
+        DO UPDATE SET following = true, updated_at = NOW()
+        "#,
+    )
+    .bind(reply.thread_id)
+    .bind(reply.sender_id)
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO notifications (user_id, kind, chat_id, message_id)
+        SELECT s.user_id, 'thread_reply', $2, $3
+        FROM thread_subscriptions s JOIN chats c ON c.id = $2
+        WHERE s.thread_id = $1 AND s.following AND s.user_id <> $4 AND s.user_id = ANY(c.members)
+        "#,
+    )
+    .bind(reply.thread_id)
+    .bind(reply.chat_id)
+    .bind(reply.id)
+    .bind(reply.sender_id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, CreateMessage, ListMessages, ListNotifications, Notification};
+    use anyhow::Result;
+
+    fn reply(content: &str, thread_id: i64) -> CreateMessage {
+        let mut input = CreateMessage::new(content);
+        input.thread_id = Some(thread_id);
+        input
+    }
+
+    #[tokio::test]
+    async fn replies_should_subscribe_and_notify_followers() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        // message 2 in general is from user 2
+        Message::create(&reply("first", 2), 1, 3, &pool).await?;
+        assert_eq!(Message::thread_followers(2, &pool).await?, vec![2, 3]);
+
+        Message::follow_thread(2, 5, &pool).await?;
+        Message::unfollow_thread(2, 2, &pool).await?;
+        let r2 = Message::create(&reply("second", 2), 1, 4, &pool).await?;
+        assert_eq!(Message::thread_followers(2, &pool).await?, vec![3, 4, 5]);
+
+        let input = ListNotifications::default();
+        let notifications = Notification::list(3, &input, &pool).await?;
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].message_id, r2.id);
+        // unfollowed before the second reply
+        assert_eq!(Notification::list(2, &input, &pool).await?.len(), 1);
+        assert!(Notification::list(4, &input, &pool).await?.is_empty());
+
+        let input = ListMessages {
+            thread_id: Some(2),
+            ..Default::default()
+        };
+        let replies = Message::list(&input, 1, &pool).await?;
+        assert_eq!(replies.len(), 2);
+        // replies do not show up in the top level timeline
+        let top = Message::list(&ListMessages::default(), 1, &pool).await?;
+        assert_eq!(top.len(), 10);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reply_to_reply_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let r1 = Message::create(&reply("first", 2), 1, 3, &pool).await?;
+        let ret = Message::create(&reply("nested", r1.id), 1, 3, &pool).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+        let ret = Message::follow_thread(r1.id as _, 3, &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{
+    utils::{generate_token, generate_totp_secret, totp_provisioning_uri, verify_totp},
+    AppError, User,
+};
+
+const TOTP_ISSUER: &str = "chat";
+const RECOVERY_CODE_COUNT: usize = 10;
+const CHALLENGE_TOKEN_BYTES: usize = 32;
+const CHALLENGE_TTL_SECS: i64 = 300;
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    // render as a QR code for authenticator apps
+    pub provisioning_uri: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwoFactorCode {
+    // a TOTP code, or one of the recovery codes
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigninChallenge {
+    pub challenge_token: String,
+    pub code: String,
+}
+
+impl User {
+    pub async fn has_two_factor(&self, pool: &PgPool) -> Result<bool, AppError> {
+        let enabled: bool = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM user_totp WHERE user_id = $1 AND enabled_at IS NOT NULL)",
+        )
+        .bind(self.id)
+        .fetch_one(pool)
+        .await?;
+        Ok(enabled)
+    }
+
+    // starts over with a new secret until a code from it is confirmed
+    pub async fn enroll_totp(&self, pool: &PgPool) -> Result<TotpEnrollment, AppError> {
+        if self.has_two_factor(pool).await? {
+            return Err(AppError::InvalidInput("two factor authentication is already enabled".to_string()));
+        }
+        let secret = generate_totp_secret();
+        sqlx::query(
+            r#"
+            INSERT INTO user_totp (user_id, secret)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET secret = EXCLUDED.secret, last_used_step = 0, created_at = NOW()
+            "#,
+        )
+        .bind(self.id)
+        .bind(&secret)
+        .execute(pool)
+        .await?;
+        Ok(TotpEnrollment {
+            provisioning_uri: totp_provisioning_uri(TOTP_ISSUER, &self.email, &secret),
+            secret,
+        })
+    }
+
+    // enables 2fa and returns the recovery codes, the only time they are shown
+    pub async fn confirm_totp(&self, code: &str, pool: &PgPool) -> Result<Vec<String>, AppError> {
+        let mut tx = pool.begin().await?;
+        if !check_totp(&mut tx, self.id, code, false).await? {
+            return Err(AppError::PermissionDenied("invalid two factor code".to_string()));
+        }
+        sqlx::query("UPDATE user_totp SET enabled_at = NOW() WHERE user_id = $1")
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+        let codes = replace_recovery_codes(&mut tx, self.id).await?;
+        tx.commit().await?;
+        Ok(codes)
+    }
+
+    pub async fn regenerate_recovery_codes(&self, code: &str, pool: &PgPool) -> Result<Vec<String>, AppError> {
+        let mut tx = pool.begin().await?;
+        if !check_second_factor(&mut tx, self.id, code).await? {
+            return Err(AppError::PermissionDenied("invalid two factor code".to_string()));
+        }
+        let codes = replace_recovery_codes(&mut tx, self.id).await?;
+        tx.commit().await?;
+        Ok(codes)
+    }
+
+    pub async fn disable_two_factor(&self, code: &str, pool: &PgPool) -> Result<(), AppError> {
+        let mut tx = pool.begin().await?;
+        if !check_second_factor(&mut tx, self.id, code).await? {
+            return Err(AppError::PermissionDenied("invalid two factor code".to_string()));
+        }
+        sqlx::query("DELETE FROM user_totp WHERE user_id = $1")
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = $1")
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // issued instead of tokens once the password checked out, see redeem_signin_challenge
+    pub async fn create_signin_challenge(&self, remember_me: bool, pool: &PgPool) -> Result<String, AppError> {
+        let token = generate_token(CHALLENGE_TOKEN_BYTES);
+        sqlx::query(
+            r#"
+            INSERT INTO signin_challenges (user_id, token_hash, remember_me, expires_at)
+            VALUES ($1, sha256(convert_to($2, 'UTF8')), $3, $4)
+            "#,
+        )
+        .bind(self.id)
+        .bind(&token)
+        .bind(remember_me)
+        .bind(Utc::now() + Duration::seconds(CHALLENGE_TTL_SECS))
+        .execute(pool)
+        .await?;
+        Ok(token)
+    }
+
+    // returns the user and whether they asked to be remembered
+    pub async fn redeem_signin_challenge(input: &SigninChallenge, pool: &PgPool) -> Result<(User, bool), AppError> {
+        let mut tx = pool.begin().await?;
+        let challenge: Option<(i64, i64, bool)> = sqlx::query_as(
+            r#"
+            UPDATE signin_challenges SET attempts = attempts + 1
+            WHERE token_hash = sha256(convert_to($1, 'UTF8')) AND expires_at > NOW() AND attempts < $2
+            RETURNING id, user_id, remember_me
+            "#,
+        )
+        .bind(&input.challenge_token)
+        .bind(MAX_CHALLENGE_ATTEMPTS)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((id, user_id, remember_me)) = challenge else {
+            return Err(AppError::PermissionDenied("signin challenge is invalid or has expired".to_string()));
+        };
+        if !check_second_factor(&mut tx, user_id, &input.code).await? {
+            // keep the attempt count
+            tx.commit().await?;
+            return Err(AppError::PermissionDenied("invalid two factor code".to_string()));
+        }
+        sqlx::query("DELETE FROM signin_challenges WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        let user: User = sqlx::query_as("SELECT id, ws_id, fullname, email, created_at FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok((user, remember_me))
+    }
+
+    // who a challenge is for, so that failed codes can be recorded against them
+    pub async fn find_by_signin_challenge(token: &str, pool: &PgPool) -> Result<Option<User>, AppError> {
+        let user = sqlx::query_as(
+            r#"
+            SELECT u.id, u.ws_id, u.fullname, u.email, u.created_at
+            FROM users u JOIN signin_challenges c ON c.user_id = u.id
+            WHERE c.token_hash = sha256(convert_to($1, 'UTF8'))
+            "#,
+        )
+        .bind(token)
+        .fetch_optional(pool)
+        .await?;
+        Ok(user)
+    }
+}
+
+// a TOTP code, or else an unused recovery code which is then used up
+async fn check_second_factor(tx: &mut Transaction<'_, Postgres>, user_id: i64, code: &str) -> Result<bool, AppError> {
+    if check_totp(tx, user_id, code, true).await? {
+        return Ok(true);
+    }
+    let code = normalize_recovery_code(code);
+    let ret = sqlx::query(
+        r#"
+        UPDATE totp_recovery_codes SET used_at = NOW()
+        WHERE id = (
+            SELECT id FROM totp_recovery_codes
+            WHERE user_id = $1 AND code_hash = sha256(convert_to($2, 'UTF8')) AND used_at IS NULL
+            LIMIT 1
+        )
+        "#,
+    )
+    .bind(user_id)
+    .bind(&code)
+    .execute(&mut **tx)
+    .await?;
+    Ok(ret.rows_affected() > 0)
+}
+
+async fn check_totp(tx: &mut Transaction<'_, Postgres>, user_id: i64, code: &str, enabled: bool) -> Result<bool, AppError> {
+    let row: Option<(String, i64)> = sqlx::query_as(
+        r#"
+        SELECT secret, last_used_step FROM user_totp
+        WHERE user_id = $1 AND (enabled_at IS NOT NULL) = $2
+        FOR UPDATE
+        "#,
+    )
+    .bind(user_id)
+    .bind(enabled)
+    .fetch_optional(&mut **tx)
+    .await?;
+    let Some((secret, last_used_step)) = row else {
+        return Ok(false);
+    };
+    match verify_totp(&secret, code, Utc::now().timestamp()) {
+        Some(step) if step > last_used_step => {
+            sqlx::query("UPDATE user_totp SET last_used_step = $2 WHERE user_id = $1")
+                .bind(user_id)
+                .bind(step)
+                .execute(&mut **tx)
+                .await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+async fn replace_recovery_codes(tx: &mut Transaction<'_, Postgres>, user_id: i64) -> Result<Vec<String>, AppError> {
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = generate_token(5);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+    let hashes: Vec<String> = codes.iter().map(|c| normalize_recovery_code(c)).collect();
+    sqlx::query(
+        r#"
+        INSERT INTO totp_recovery_codes (user_id, code_hash)
+        SELECT $1, sha256(convert_to(code, 'UTF8')) FROM UNNEST($2::text[]) AS code
+        "#,
+    )
+    .bind(user_id)
+    .bind(&hashes)
+    .execute(&mut **tx)
+    .await?;
+    Ok(codes)
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, utils::totp_code};
+    use anyhow::Result;
+
+    fn current_code(secret: &str) -> String {
+        totp_code(secret, Utc::now().timestamp())
+    }
+
+    #[tokio::test]
+    async fn totp_enrollment_and_challenge_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let enrollment = user.enroll_totp(&pool).await?;
+        assert!(enrollment.provisioning_uri.starts_with("otpauth://totp/chat:"));
+        assert!(!user.has_two_factor(&pool).await?);
+
+        let code = current_code(&enrollment.secret);
+        let recovery_codes = user.confirm_totp(&code, &pool).await?;
+        assert_eq!(recovery_codes.len(), RECOVERY_CODE_COUNT);
+        assert!(user.has_two_factor(&pool).await?);
+
+        // the code was used to confirm, it cannot be replayed
+        let token = user.create_signin_challenge(true, &pool).await?;
+        let input = SigninChallenge { challenge_token: token.clone(), code };
+        let ret = User::redeem_signin_challenge(&input, &pool).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let input = SigninChallenge {
+            challenge_token: token.clone(),
+            code: recovery_codes[0].to_uppercase(),
+        };
+        let (signed_in, remember_me) = User::redeem_signin_challenge(&input, &pool).await?;
+        assert_eq!(signed_in.id, user.id);
+        assert!(remember_me);
+        // challenges and recovery codes are single use
+        assert!(User::redeem_signin_challenge(&input, &pool).await.is_err());
+        let token = user.create_signin_challenge(false, &pool).await?;
+        let input = SigninChallenge { challenge_token: token, code: recovery_codes[0].clone() };
+        assert!(User::redeem_signin_challenge(&input, &pool).await.is_err());
+
+        user.disable_two_factor(&recovery_codes[1], &pool).await?;
+        assert!(!user.has_two_factor(&pool).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_challenge_should_limit_attempts() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let enrollment = user.enroll_totp(&pool).await?;
+        let recovery_codes = user.confirm_totp(&current_code(&enrollment.secret), &pool).await?;
+        let token = user.create_signin_challenge(false, &pool).await?;
+        for _ in 0..MAX_CHALLENGE_ATTEMPTS {
+            let input = SigninChallenge { challenge_token: token.clone(), code: "000000".to_string() };
+            assert!(User::redeem_signin_challenge(&input, &pool).await.is_err());
+        }
+        let input = SigninChallenge { challenge_token: token, code: recovery_codes[0].clone() };
+        assert!(User::redeem_signin_challenge(&input, &pool).await.is_err());
+        Ok(())
+    }
+}
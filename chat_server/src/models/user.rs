@@ -1,10 +1,13 @@
 use std::mem;
 
-use argon2::{password_hash::{rand_core::OsRng, SaltString}, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::{password_hash::{rand_core::OsRng, SaltString}, Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
 use sqlx::PgPool;
 use serde::{Deserialize, Serialize};
 
-use crate::{AppError, ChatUser, User, Workspace};
+use crate::{config::{PasswordHashing, PasswordPolicy}, AppError, ChatUser, SigninUser, User, Workspace};
+
+// the longest address RFC 5321 allows, and the size of users.email
+const MAX_EMAIL_LEN: usize = 254;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateUser {
@@ -14,12 +17,6 @@ pub struct CreateUser {
     pub password: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SigninUser {
-    pub email: String,
-    pub password: String,
-}
-
 impl User {
     pub async fn find_by_email(email: &str, pool: &PgPool) -> Result<Option<Self>, AppError> {
         let user = sqlx::query_as("SELECT id, ws_id, fullname, email, created_at FROM users WHERE email = $1")
@@ -29,7 +26,22 @@ impl User {
         Ok(user)
     }
 
-    pub async fn create(input: &CreateUser, pool: &PgPool) -> Result<Self, AppError> {
+    pub async fn find_by_id(id: u64, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let user = sqlx::query_as("SELECT id, ws_id, fullname, email, created_at FROM users WHERE id = $1")
+            .bind(id as i64)
+            .fetch_optional(pool)
+            .await?;
+        Ok(user)
+    }
+
+    pub async fn create(
+        input: &CreateUser,
+        policy: &PasswordPolicy,
+        hashing: &PasswordHashing,
+        pool: &PgPool,
+    ) -> Result<Self, AppError> {
+        check_email(&input.email)?;
+        policy.check(&input.password, &[&input.fullname, &input.email, &input.workspace])?;
         let user = Self::find_by_email(&input.email, pool).await?;
         if user.is_some() {
             return Err(AppError::EmailAlreadyExists(input.email.clone()));
@@ -38,7 +50,7 @@ impl User {
             Some(ws) => ws,
             None => Workspace::create(&input.workspace, 0, pool).await?
         };
-        let password_hash = hash_password(&input.password)?;
+        let password_hash = hash_password(&input.password, hashing)?;
         let user: User = sqlx::query_as(
             r#"
             INSERT INTO users (ws_id, email, fullname, password_hash)
@@ -59,9 +71,11 @@ impl User {
         Ok(user)
     }
     
+    // a hash made with lower costs than configured is replaced once the password is known to be right
     pub async fn verify(
         input: &SigninUser,
-        pool: &PgPool,   
+        hashing: &PasswordHashing,
+        pool: &PgPool,
     ) -> Result<Option<Self>, AppError> {
         let user: Option<Self> = sqlx::query_as("SELECT id, ws_id, fullname, email, created_at, password_hash FROM users WHERE email = $1")
             .bind(&input.email)
@@ -69,19 +83,46 @@ impl User {
             .await?;
         match user {
             Some(mut user) => {
-                let password_hash = mem::take(&mut user.password_hash);
-                let is_valid = verify_password(&input.password, &password_hash.unwrap_or_default())?;
-                if is_valid {
-                    Ok(Some(user))
-                } else {
-                    Ok(None)
+                // users from a social login have no password
+                let Some(password_hash) = mem::take(&mut user.password_hash) else {
+                    return Ok(None);
+                };
+                if !verify_password(&input.password, &password_hash)? {
+                    return Ok(None);
                 }
+                if needs_rehash(&password_hash, hashing)? {
+                    // only replaces the hash that was verified, a password changed meanwhile wins
+                    sqlx::query("UPDATE users SET password_hash = $2 WHERE id = $1 AND password_hash = $3")
+                        .bind(user.id)
+                        .bind(hash_password(&input.password, hashing)?)
+                        .bind(&password_hash)
+                        .execute(pool)
+                        .await?;
+                }
+                Ok(Some(user))
             }
             None => Ok(None)
         }
     }
 }
 
+impl User {
+    // the owner, and members promoted to admin
+    pub async fn is_workspace_admin(&self, pool: &PgPool) -> Result<bool, AppError> {
+        let admin: Option<bool> = sqlx::query_scalar(
+            r#"
+            SELECT w.owner_id = u.id OR u.role = 'admin'
+            FROM users u JOIN workspaces w ON w.id = u.ws_id
+            WHERE u.id = $1 AND u.deactivated_at IS NULL
+            "#,
+        )
+        .bind(self.id)
+        .fetch_optional(pool)
+        .await?;
+        Ok(admin.unwrap_or(false))
+    }
+}
+
 impl ChatUser {
     pub async fn fetch_by_ids(ids: &[i64], pool: &PgPool) -> Result<Vec<Self>, AppError> {
         let users = sqlx::query_as(
@@ -107,14 +148,35 @@ impl ChatUser {
     }
 }
 
-fn hash_password(password: &str) -> Result<String, AppError> {
+pub fn hash_password(password: &str, hashing: &PasswordHashing) -> Result<String, AppError> {
     let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
+    let params = Params::new(hashing.memory_kib, hashing.iterations, hashing.parallelism, None)
+        .map_err(argon2::password_hash::Error::from)?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
     let password_hash = argon2.hash_password(password.as_bytes(), &salt)?.to_string();
     Ok(password_hash)
 }
 
-fn verify_password(password: &str, password_hash: &str) -> Result<bool, AppError> {
+// lowering the configured costs never downgrades existing hashes
+fn needs_rehash(password_hash: &str, hashing: &PasswordHashing) -> Result<bool, AppError> {
+    let password_hash = PasswordHash::new(password_hash)?;
+    if password_hash.algorithm != Algorithm::Argon2id.ident() || password_hash.version != Some(Version::V0x13.into()) {
+        return Ok(true);
+    }
+    let params = Params::try_from(&password_hash)?;
+    Ok(params.m_cost() < hashing.memory_kib
+        || params.t_cost() < hashing.iterations
+        || params.p_cost() < hashing.parallelism)
+}
+
+pub fn check_email(email: &str) -> Result<(), AppError> {
+    if !email.contains('@') || email.len() > MAX_EMAIL_LEN {
+        return Err(AppError::InvalidInput(format!("invalid email: {}", email)));
+    }
+    Ok(())
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> Result<bool, AppError> {
     let password_hash = PasswordHash::new(password_hash)?;
     let argon2 = Argon2::default();
     let is_valid = argon2.verify_password(password.as_bytes(), &password_hash).is_ok();
@@ -148,29 +210,48 @@ impl CreateUser {
     }
 }
 
-#[cfg(test)]
-impl SigninUser {
-    pub fn new(email: &str, password: &str) -> Self {
-        Self {
-            email: email.to_string(),
-            password: password.to_string(),
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
     use crate::test_util::get_test_pool;
     use super::*;
     use anyhow::Result;
-    use sqlx_db_tester::TestPg;
     #[test]
     fn hash_password_and_verify_should_workd() -> Result<()> {
         let password = "hunter42";
-        let password_hash = hash_password(password)?;
+        let hashing = PasswordHashing::default();
+        let password_hash = hash_password(password, &hashing)?;
         assert_eq!(password_hash.len(), 97);
         assert!(verify_password(password, &password_hash)?);
+        assert!(!needs_rehash(&password_hash, &hashing)?);
+
+        let stronger = PasswordHashing { iterations: 3, ..Default::default() };
+        assert!(needs_rehash(&password_hash, &stronger)?);
+        let weaker = PasswordHashing { memory_kib: 8 * 1024, ..Default::default() };
+        assert!(!needs_rehash(&password_hash, &weaker)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_should_rehash_weaker_hashes() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let stronger = PasswordHashing { memory_kib: 32 * 1024, iterations: 3, parallelism: 2 };
+        let input = SigninUser::new("tchen@acme.org", "123456");
+        assert!(User::verify(&input, &stronger, &pool).await?.is_some());
+        let password_hash: String = sqlx::query_scalar("SELECT password_hash FROM users WHERE email = $1")
+            .bind(&input.email)
+            .fetch_one(&pool)
+            .await?;
+        assert!(password_hash.starts_with("$argon2id$v=19$m=32768,t=3,p=2$"));
+        assert!(User::verify(&input, &PasswordHashing::default(), &pool).await?.is_some());
+
+        // a wrong password changes nothing
+        let wrong = SigninUser::new("tchen@acme.org", "1234567");
+        assert!(User::verify(&wrong, &PasswordHashing { iterations: 4, ..stronger }, &pool).await?.is_none());
+        let unchanged: String = sqlx::query_scalar("SELECT password_hash FROM users WHERE email = $1")
+            .bind(&input.email)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(unchanged, password_hash);
         Ok(())
     }
 
@@ -178,7 +259,7 @@ mod tests {
     async fn create_and_verify_user_should_work() -> Result<()> {
         let (_tdb, pool) = get_test_pool(None).await;
         let create_input = CreateUser::new("none", "Tian Chen", "tyr@acme.org", "hunter42");
-        let user = User::create(&create_input, &pool).await?;
+        let user = User::create(&create_input, &PasswordPolicy::default(), &PasswordHashing::default(), &pool).await?;
         assert_eq!(user.email, create_input.email);
         assert_eq!(user.fullname, create_input.fullname);
         let user = User::find_by_email(&create_input.email, &pool).await?;
@@ -187,16 +268,29 @@ mod tests {
         assert_eq!(user.email, create_input.email);
         assert_eq!(user.fullname, create_input.fullname);
         let signin_input = SigninUser::new(&create_input.email, &create_input.password);
-        let user = User::verify(&signin_input, &pool).await?;
+        let user = User::verify(&signin_input, &PasswordHashing::default(), &pool).await?;
         assert!(user.is_some());
         Ok(())
     }
     #[tokio::test]
+    async fn create_user_with_long_email_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        // longer than users.email used to be, still fits
+        let email = format!("{}@acme.org", "a".repeat(100));
+        let create_input = CreateUser::new("acme", "Tian Chen", &email, "hunter42");
+        User::create(&create_input, &PasswordPolicy::default(), &PasswordHashing::default(), &pool).await?;
+        let email = format!("{}@acme.org", "a".repeat(MAX_EMAIL_LEN));
+        let create_input = CreateUser::new("acme", "Tian Chen", &email, "hunter42");
+        let ret = User::create(&create_input, &PasswordPolicy::default(), &PasswordHashing::default(), &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+    #[tokio::test]
     async fn create_duplicate_user_should_fail() -> Result<()> {
         let (_tdb, pool) = get_test_pool(None).await;
         let create_input = CreateUser::new("acme", "Tian Chen", "tyr@acme.org", "hunter42");
-        User::create(&create_input, &pool).await?;
-        let ret = User::create(&create_input, &pool).await;
+        User::create(&create_input, &PasswordPolicy::default(), &PasswordHashing::default(), &pool).await?;
+        let ret = User::create(&create_input, &PasswordPolicy::default(), &PasswordHashing::default(), &pool).await;
         match ret {
             Err(AppError::EmailAlreadyExists(email)) => {
                 assert_eq!(email, create_input.email)
@@ -205,4 +299,15 @@ mod tests {
         }
         Ok(())
     }
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn workspace_owner_should_be_admin() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateUser::new("new_ws", "Tian Chen", "tyr@acme.org", "hunter42");
+        let owner = User::create(&input, &PasswordPolicy::default(), &PasswordHashing::default(), &pool).await?;
+        assert!(owner.is_workspace_admin(&pool).await?);
+        let input = CreateUser::new("new_ws", "Alice Chen", "alice@new.org", "hunter42");
+        let user = User::create(&input, &PasswordPolicy::default(), &PasswordHashing::default(), &pool).await?;
+        assert!(!user.is_workspace_admin(&pool).await?);
+        Ok(())
+    }
+}
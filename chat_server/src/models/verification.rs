@@ -0,0 +1,106 @@
+use chrono::{Duration, Utc};
+use sqlx::PgPool;
+
+use crate::{utils::generate_token, AppError, User};
+
+const VERIFICATION_TOKEN_BYTES: usize = 32;
+const VERIFICATION_TOKEN_TTL_HOURS: i64 = 48;
+// resends within this window are dropped
+const RESEND_INTERVAL_SECS: i64 = 60;
+
+impl User {
+    pub async fn is_email_verified(&self, pool: &PgPool) -> Result<bool, AppError> {
+        let verified: bool = sqlx::query_scalar("SELECT email_verified_at IS NOT NULL FROM users WHERE id = $1")
+            .bind(self.id)
+            .fetch_one(pool)
+            .await?;
+        Ok(verified)
+    }
+
+    // returns None when the email is already verified or a token was issued moments ago
+    pub async fn create_email_verification(&self, pool: &PgPool) -> Result<Option<String>, AppError> {
+        let token = generate_token(VERIFICATION_TOKEN_BYTES);
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO email_verifications (user_id, token_hash, expires_at)
+            SELECT $1, sha256(convert_to($2, 'UTF8')), $3
+            FROM users u
+            WHERE u.id = $1 AND u.email_verified_at IS NULL
+                AND NOT EXISTS(
+                    SELECT 1 FROM email_verifications
+                    WHERE user_id = $1 AND created_at > NOW() - make_interval(secs => $4)
+                )
+            "#,
+        )
+        .bind(self.id)
+        .bind(&token)
+        .bind(Utc::now() + Duration::hours(VERIFICATION_TOKEN_TTL_HOURS))
+        .bind(RESEND_INTERVAL_SECS as f64)
+        .execute(pool)
+        .await?;
+        Ok((ret.rows_affected() > 0).then_some(token))
+    }
+
+    // marks the owner of the token as verified, all their tokens are consumed
+    pub async fn verify_email(token: &str, pool: &PgPool) -> Result<User, AppError> {
+        let mut tx = pool.begin().await?;
+        let user: Option<User> = sqlx::query_as(
+            r#"
+            UPDATE users u SET email_verified_at = COALESCE(u.email_verified_at, NOW())
+            FROM email_verifications v
+            WHERE v.user_id = u.id AND v.token_hash = sha256(convert_to($1, 'UTF8')) AND v.expires_at > NOW()
+            RETURNING u.id, u.ws_id, u.fullname, u.email, u.created_at
+            "#,
+        )
+        .bind(token)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(user) = user else {
+            return Err(AppError::NotFound("verification token is invalid or has expired".to_string()));
+        };
+        sqlx::query("DELETE FROM email_verifications WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::{PasswordHashing, PasswordPolicy}, test_util::get_test_pool, CreateUser};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn email_verification_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateUser::new("acme", "Eve Chen", "eve@acme.org", "hunter42");
+        let user = User::create(&input, &PasswordPolicy::default(), &PasswordHashing::default(), &pool).await?;
+        assert!(!user.is_email_verified(&pool).await?);
+
+        let token = user.create_email_verification(&pool).await?.expect("token should be issued");
+        // resending right away is throttled
+        assert!(user.create_email_verification(&pool).await?.is_none());
+        let ret = User::verify_email("nope", &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        let verified = User::verify_email(&token, &pool).await?;
+        assert_eq!(verified.id, user.id);
+        assert!(user.is_email_verified(&pool).await?);
+        // tokens are single use
+        let ret = User::verify_email(&token, &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verified_user_should_not_get_token() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let user = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        assert!(user.is_email_verified(&pool).await?);
+        assert!(user.create_email_verification(&pool).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{types::Json, FromRow, PgConnection, PgPool};
+
+use crate::{utils::{check_public_url, generate_token}, AppError, Webhook, WebhookDelivery, WebhookEvent};
+
+const WEBHOOK_SECRET_BYTES: usize = 32;
+const MAX_WEBHOOKS_PER_WORKSPACE: i64 = 10;
+const DEFAULT_DELIVERY_LIMIT: u64 = 50;
+const MAX_DELIVERY_LIMIT: u64 = 200;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateWebhook {
+    pub url: String,
+    // security events only when left out, as for webhooks registered before the filter
+    #[serde(default = "default_events")]
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListWebhookDeliveries {
+    // exclusive, for the next page
+    #[serde(default)]
+    pub before_id: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+// a claimed delivery with what is needed to send it
+#[derive(Debug, Clone, FromRow)]
+pub(crate) struct DueDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event: WebhookEvent,
+    pub payload: Json<Value>,
+    pub attempts: i32,
+    pub url: String,
+    pub secret: String,
+}
+
+fn default_events() -> Vec<WebhookEvent> {
+    vec![WebhookEvent::Security]
+}
+
+impl WebhookEvent {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Security => "security",
+            Self::MessageCreated => "message.created",
+            Self::ChatCreated => "chat.created",
+            Self::MemberAdded => "member.added",
+        }
+    }
+
+    // what workspace events are posted as, security events keep the body they always had
+    pub(crate) fn payload(&self, ws_id: i64, data: impl Serialize) -> Value {
+        serde_json::json!({
+            "event": self.as_str(),
+            "ws_id": ws_id,
+            "occurred_at": Utc::now(),
+            "data": data,
+        })
+    }
+}
+
+impl Webhook {
+    // the secret is only returned here, callers need it to verify the payload signatures
+    pub async fn create(ws_id: u64, input: &CreateWebhook, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let url = input.url.trim();
+        check_public_url(url, "webhook")?;
+        if input.events.is_empty() {
+            return Err(AppError::InvalidInput("a webhook needs at least one event".to_string()));
+        }
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM workspace_webhooks WHERE ws_id = $1")
+            .bind(ws_id as i64)
+            .fetch_one(pool)
+            .await?;
+        if count >= MAX_WEBHOOKS_PER_WORKSPACE {
+            return Err(AppError::InvalidInput(format!(
+                "a workspace can have at most {} webhooks",
+                MAX_WEBHOOKS_PER_WORKSPACE
+            )));
+        }
+
+        let webhook = sqlx::query_as(
+            r#"
+            INSERT INTO workspace_webhooks (ws_id, url, secret, events, created_by)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, ws_id, url, secret, events, created_by, created_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(url)
+        .bind(generate_token(WEBHOOK_SECRET_BYTES))
+        .bind(&input.events)
+        .bind(user_id as i64)
+        .fetch_one(pool)
+        .await?;
+        Ok(webhook)
+    }
+
+    pub async fn list(ws_id: u64, pool: &PgPool) -> Result<Vec<Self>, AppError> {
+        let webhooks = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, url, events, created_by, created_at
+            FROM workspace_webhooks
+            WHERE ws_id = $1
+            ORDER BY id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(webhooks)
+    }
+
+    pub async fn delete(ws_id: u64, id: u64, pool: &PgPool) -> Result<(), AppError> {
+        let ret = sqlx::query("DELETE FROM workspace_webhooks WHERE id = $1 AND ws_id = $2")
+            .bind(id as i64)
+            .bind(ws_id as i64)
+            .execute(pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("webhook {}", id)));
+        }
+        Ok(())
+    }
+
+    // queues a delivery for every webhook of the workspace that wants the event. Called in the
+    // transaction that made the event, so it is sent only if that commits. Events about a chat
+    // only go to webhooks whose creator can read it: a public channel or one they are in.
+    pub(crate) async fn enqueue(
+        conn: &mut PgConnection,
+        ws_id: i64,
+        chat_id: Option<i64>,
+        event: WebhookEvent,
+        payload: &Value,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO webhook_deliveries (webhook_id, event, payload)
+            SELECT w.id, $2, $3 FROM workspace_webhooks w
+            WHERE w.ws_id = $1 AND $2 = ANY(w.events) AND ($4::bigint IS NULL OR EXISTS (
+                SELECT 1 FROM chats c JOIN users u ON u.id = w.created_by
+                WHERE c.id = $4 AND u.deactivated_at IS NULL
+                    AND (c.type = 'public_channel' OR w.created_by = ANY(c.members))
+            ))
+            "#,
+        )
+        .bind(ws_id)
+        .bind(event)
+        .bind(payload)
+        .bind(chat_id)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+}
+
+impl WebhookDelivery {
+    // newest first
+    pub async fn list(
+        ws_id: u64,
+        webhook_id: u64,
+        input: &ListWebhookDeliveries,
+        pool: &PgPool,
+    ) -> Result<Vec<Self>, AppError> {
+        let limit = input.limit.unwrap_or(DEFAULT_DELIVERY_LIMIT).min(MAX_DELIVERY_LIMIT);
+        let deliveries = sqlx::query_as(
+            r#"
+            SELECT d.id, d.webhook_id, d.event, d.payload, d.status, d.attempts, d.next_attempt_at,
+                d.response_status, d.last_error, d.created_at, d.delivered_at
+            FROM webhook_deliveries d JOIN workspace_webhooks w ON w.id = d.webhook_id
+            WHERE d.webhook_id = $1 AND w.ws_id = $2 AND ($3::bigint IS NULL OR d.id < $3)
+            ORDER BY d.id DESC
+            LIMIT $4
+            "#,
+        )
+        .bind(webhook_id as i64)
+        .bind(ws_id as i64)
+        .bind(input.before_id)
+        .bind(limit as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(deliveries)
+    }
+
+    // claims up to limit due deliveries, across instances each is claimed once. A claim lasts
+    // lease_secs, a delivery whose attempt didn't record an outcome by then is due again.
+    pub(crate) async fn claim_due(limit: usize, lease_secs: u64, pool: &PgPool) -> Result<Vec<DueDelivery>, AppError> {
+        let deliveries = sqlx::query_as(
+            r#"
+            UPDATE webhook_deliveries d
+            SET attempts = d.attempts + 1, next_attempt_at = now() + make_interval(secs => $2)
+            FROM workspace_webhooks w
+            WHERE w.id = d.webhook_id AND d.id IN (
+                SELECT id FROM webhook_deliveries
+                WHERE status = 'pending' AND next_attempt_at <= now()
+                ORDER BY next_attempt_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING d.id, d.webhook_id, d.event, d.payload, d.attempts, w.url, w.secret
+            "#,
+        )
+        .bind(limit as i64)
+        .bind(lease_secs as f64)
+        .fetch_all(pool)
+        .await?;
+        Ok(deliveries)
+    }
+
+    // retry_at none means no more attempts, the delivery is failed unless it succeeded
+    pub(crate) async fn record_attempt(
+        id: i64,
+        response_status: Option<u16>,
+        error: Option<&str>,
+        retry_at: Option<DateTime<Utc>>,
+        pool: &PgPool,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = CASE
+                    WHEN $3::text IS NULL THEN 'succeeded'
+                    WHEN $4::timestamptz IS NULL THEN 'failed'
+                    ELSE 'pending'
+                END::webhook_delivery_status,
+                response_status = $2,
+                last_error = $3,
+                next_attempt_at = COALESCE($4, next_attempt_at),
+                delivered_at = CASE WHEN $3::text IS NULL THEN now() END
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(response_status.map(i32::from))
+        .bind(error)
+        .bind(retry_at)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    // drops the log of deliveries that are done, returns how many went
+    pub(crate) async fn prune(retention_days: i64, pool: &PgPool) -> Result<u64, AppError> {
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM webhook_deliveries
+            WHERE status <> 'pending' AND created_at < now() - make_interval(days => $1)
+            "#,
+        )
+        .bind(retention_days as i32)
+        .execute(pool)
+        .await?;
+        Ok(ret.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn webhook_crud_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateWebhook { url: "https://siem.acme.org/hook".to_string(), events: default_events() };
+        let webhook = Webhook::create(1, &input, 1, &pool).await?;
+        assert_eq!(webhook.secret.as_ref().map(|s| s.len()), Some(64));
+
+        let webhooks = Webhook::list(1, &pool).await?;
+        assert_eq!(webhooks.len(), 1);
+        assert!(webhooks[0].secret.is_none());
+        assert_eq!(webhooks[0].events, vec![WebhookEvent::Security]);
+
+        // webhooks of other workspaces cannot be deleted
+        let ret = Webhook::delete(2, webhook.id as _, &pool).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Webhook::delete(1, webhook.id as _, &pool).await?;
+        assert!(Webhook::list(1, &pool).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn webhook_with_invalid_url_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateWebhook { url: "ftp://siem.acme.org".to_string(), events: default_events() };
+        let ret = Webhook::create(1, &input, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        let input = CreateWebhook { url: "https://siem.acme.org".to_string(), events: vec![] };
+        let ret = Webhook::create(1, &input, 1, &pool).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deliveries_should_be_queued_for_subscribed_webhooks_only() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateWebhook {
+            url: "https://hooks.acme.org/messages".to_string(),
+            events: vec![WebhookEvent::MessageCreated],
+        };
+        let webhook = Webhook::create(1, &input, 1, &pool).await?;
+        let input = CreateWebhook { url: "https://siem.acme.org/hook".to_string(), events: default_events() };
+        Webhook::create(1, &input, 1, &pool).await?;
+
+        let mut conn = pool.acquire().await?;
+        let payload = serde_json::json!({ "hello": "world" });
+        Webhook::enqueue(&mut conn, 1, None, WebhookEvent::MessageCreated, &payload).await?;
+        // another workspace
+        Webhook::enqueue(&mut conn, 2, None, WebhookEvent::MessageCreated, &payload).await?;
+
+        let due = WebhookDelivery::claim_due(10, 60, &pool).await?;
+        assert_eq!(due.len(), 1);
+        assert_eq!((due[0].webhook_id, due[0].attempts), (webhook.id, 1));
+        assert_eq!(due[0].secret, webhook.secret.unwrap_or_default());
+        // claimed until the lease runs out
+        assert!(WebhookDelivery::claim_due(10, 60, &pool).await?.is_empty());
+
+        WebhookDelivery::record_attempt(due[0].id, Some(500), Some("HTTP 500"), Some(chrono::Utc::now()), &pool).await?;
+        let due = WebhookDelivery::claim_due(10, 60, &pool).await?;
+        assert_eq!(due[0].attempts, 2);
+        WebhookDelivery::record_attempt(due[0].id, Some(204), None, None, &pool).await?;
+
+        let input = ListWebhookDeliveries::default();
+        let log = WebhookDelivery::list(1, webhook.id as _, &input, &pool).await?;
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].status, crate::WebhookDeliveryStatus::Succeeded);
+        assert_eq!(log[0].response_status, Some(204));
+        assert_eq!(log[0].payload, payload);
+        // not visible from other workspaces
+        assert!(WebhookDelivery::list(2, webhook.id as _, &input, &pool).await?.is_empty());
+
+        // done deliveries are dropped once they are old enough
+        assert_eq!(WebhookDelivery::prune(1, &pool).await?, 0);
+        sqlx::query("UPDATE webhook_deliveries SET created_at = now() - interval '2 days'").execute(&pool).await?;
+        assert_eq!(WebhookDelivery::prune(1, &pool).await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_events_should_only_go_to_webhooks_whose_creator_can_read_the_chat() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = CreateWebhook {
+            url: "https://hooks.acme.org/messages".to_string(),
+            events: vec![WebhookEvent::MessageCreated],
+        };
+        Webhook::create(1, &input, 1, &pool).await?;
+
+        let mut conn = pool.acquire().await?;
+        let payload = serde_json::json!({ "hello": "world" });
+        // a private channel without the webhook's creator
+        sqlx::query("UPDATE chats SET type = 'private_channel', members = array_remove(members, 1::bigint) WHERE id = 1")
+            .execute(&mut *conn)
+            .await?;
+        Webhook::enqueue(&mut conn, 1, Some(1), WebhookEvent::MessageCreated, &payload).await?;
+        assert!(WebhookDelivery::claim_due(10, 60, &pool).await?.is_empty());
+
+        sqlx::query("UPDATE chats SET type = 'public_channel' WHERE id = 1").execute(&mut *conn).await?;
+        Webhook::enqueue(&mut conn, 1, Some(1), WebhookEvent::MessageCreated, &payload).await?;
+        assert_eq!(WebhookDelivery::claim_due(10, 60, &pool).await?.len(), 1);
+        Ok(())
+    }
+}
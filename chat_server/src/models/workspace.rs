@@ -1,28 +1,195 @@
-use sqlx::{PgPool, Pool};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
 
-use crate::{AppError, ChatUser, Workspace};
+use crate::{utils::generate_token, AppError, ChatUser, Workspace};
+
+use super::pin::validate_max_pins;
+
+const DEFAULT_USER_LIMIT: u64 = 100;
+const MAX_USER_LIMIT: u64 = 500;
+pub(super) const MAX_WORKSPACE_NAME_LEN: usize = 32;
+pub(super) const WORKSPACE_NAME_INDEX: &str = "workspaces_name_key";
+// where the TXT record proving a signup domain is looked up, e.g. _chat-verification.acme.org
+const SIGNUP_DOMAIN_RECORD: &str = "_chat-verification";
+const SIGNUP_DOMAIN_TOKEN_PREFIX: &str = "chat-verification=";
+const SIGNUP_DOMAIN_TOKEN_BYTES: usize = 16;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateWorkspace {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub max_pins_per_chat: Option<i32>,
+    // email domain for social login signups, empty to clear. Takes effect once verified, see
+    // SignupDomain.
+    #[serde(default)]
+    pub signup_domain: Option<String>,
+    // only turning it on, turning it off is a DestructiveAction
+    #[serde(default)]
+    pub require_approval: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListChatUsers {
+    // matched against fullname and email, case insensitive
+    #[serde(default)]
+    pub q: Option<String>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+    #[serde(default)]
+    pub offset: Option<u64>,
+}
+
+// what admins can change through UpdateWorkspace
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceSettings {
+    pub name: String,
+    pub slug: String,
+    pub max_pins_per_chat: i32,
+    pub signup_domain: Option<String>,
+    pub require_approval: bool,
+}
+
+// the domain social login signups join from, and the one waiting for its DNS record
+#[derive(Debug, Clone, Default, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct SignupDomain {
+    pub domain: Option<String>,
+    pub pending_domain: Option<String>,
+    // publish a TXT record named record_name with record_value, then verify
+    pub record_name: Option<String>,
+    pub record_value: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceStats {
+    pub members: i64,
+    pub chats: i64,
+    pub messages_7d: i64,
+    pub messages_30d: i64,
+    // bytes of message content stored for this workspace
+    pub storage_bytes: i64,
+}
+
+impl ListChatUsers {
+    pub(crate) fn limit(&self) -> u64 {
+        self.limit.unwrap_or(DEFAULT_USER_LIMIT).min(MAX_USER_LIMIT)
+    }
+
+    fn pattern(&self) -> Option<String> {
+        self.q
+            .as_deref()
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .map(|q| format!("%{}%", escape_like(q)))
+    }
+}
 
 impl Workspace {
     pub async fn create(name: &str, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+        let slug = available_slug(name, None, pool).await?;
         let workspace = sqlx::query_as(
         r#"
-            INSERT INTO workspaces (name, owner_id)
-            VALUES ($1, $2)
-            RETURNING id, name, owner_id, created_at
+            INSERT INTO workspaces (name, slug, owner_id)
+            VALUES ($1, $2, $3)
+            RETURNING id, name, slug, owner_id, created_at
         "#,)
         .bind(name)
+        .bind(slug)
         .bind(user_id as i64)
         .fetch_one(pool)
         .await?;
         Ok(workspace)
     }
+
+    pub async fn set_max_pins_per_chat(&self, max_pins: i32, pool: &PgPool) -> Result<(), AppError> {
+        validate_max_pins(max_pins)?;
+        sqlx::query("UPDATE workspaces SET max_pins_per_chat = $2 WHERE id = $1")
+            .bind(self.id)
+            .bind(max_pins)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    // renames the workspace, the previous slug keeps resolving as an alias
+    pub async fn rename(&self, name: &str, pool: &PgPool) -> Result<Self, AppError> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_WORKSPACE_NAME_LEN {
+            return Err(AppError::UpdateWorkspaceError(format!(
+                "Workspace name must be 1 to {} characters",
+                MAX_WORKSPACE_NAME_LEN
+            )));
+        }
+        if name == self.name {
+            return Ok(self.clone());
+        }
+        let slug = available_slug(name, Some(self.id), pool).await?;
+
+        let mut tx = pool.begin().await?;
+        if slug != self.slug {
+            sqlx::query(
+                r#"
+                INSERT INTO workspace_slug_aliases (slug, ws_id)
+                VALUES ($1, $2)
+                ON CONFLICT (slug) DO NOTHING
+                "#,
+            )
+            .bind(&self.slug)
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+            // renaming back to an old name reclaims its slug
+            sqlx::query("DELETE FROM workspace_slug_aliases WHERE slug = $1 AND ws_id = $2")
+                .bind(&slug)
+                .bind(self.id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        let ws = sqlx::query_as(
+            r#"
+            UPDATE workspaces
+            SET name = $2, slug = $3
+            WHERE id = $1
+            RETURNING id, name, slug, owner_id, created_at
+            "#,
+        )
+        .bind(self.id)
+        .bind(name)
+        .bind(&slug)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| match &e {
+            sqlx::Error::Database(db) if db.constraint() == Some(WORKSPACE_NAME_INDEX) => {
+                AppError::WorkspaceNameTaken(name.to_string())
+            }
+            _ => e.into(),
+        })?;
+        tx.commit().await?;
+        Ok(ws)
+    }
+
+    // resolves current slugs as well as aliases left behind by renames
+    pub async fn find_by_slug(slug: &str, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let ws = sqlx::query_as(
+            r#"
+            SELECT id, name, slug, owner_id, created_at
+            FROM workspaces
+            WHERE (slug = $1 OR id = (SELECT ws_id FROM workspace_slug_aliases WHERE slug = $1))
+                AND deleted_at IS NULL
+            "#,
+        )
+        .bind(slug)
+        .fetch_optional(pool)
+        .await?;
+        Ok(ws)
+    }
     pub async fn update_owner(&self, user_id: u64, pool: &PgPool) -> Result<Self, AppError> {
         let ws = sqlx::query_as(
             r#"
             UPDATE workspaces
             SET owner_id = $1
             WHERE id = $2 and (SELECT ws_id FROM users WHERE id = $1) = $2
-            RETURNING id, name, owner_id, created_at
+            RETURNING id, name, slug, owner_id, created_at
             "#,
         )
         .bind(user_id as i64)
@@ -35,9 +202,9 @@ impl Workspace {
     pub async fn find_by_name(name: &str, pool: &PgPool) -> Result<Option<Self>, AppError> {
         let ws = sqlx::query_as(
             r#"
-            SELECT id, name, owner_id, created_at
+            SELECT id, name, slug, owner_id, created_at
             FROM workspaces
-            WHERE name = $1
+            WHERE name = $1 AND deleted_at IS NULL
             "#,
         )
         .bind(name)
@@ -46,10 +213,131 @@ impl Workspace {
         Ok(ws)
     }
     
+    // the workspace new users with an email at this domain join
+    pub async fn find_by_signup_domain(domain: &str, pool: &PgPool) -> Result<Option<Self>, AppError> {
+        let ws = sqlx::query_as(
+            r#"
+            SELECT id, name, slug, owner_id, created_at
+            FROM workspaces
+            WHERE signup_domain = lower($1) AND deleted_at IS NULL
+            "#,
+        )
+        .bind(domain)
+        .fetch_optional(pool)
+        .await?;
+        Ok(ws)
+    }
+
+    // anyone could claim a domain, so a new one waits for its TXT record before users signing
+    // up from it join. An empty domain stops auto provisioning right away.
+    pub async fn set_signup_domain(&self, domain: &str, pool: &PgPool) -> Result<SignupDomain, AppError> {
+        let domain = domain.trim().trim_start_matches('@').to_lowercase();
+        let valid = domain.len() <= 255
+            && (domain.is_empty() || domain.contains('.'))
+            && domain.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+        if !valid {
+            return Err(AppError::UpdateWorkspaceError(format!("Invalid signup domain: {}", domain)));
+        }
+        let claimed: bool = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM workspaces WHERE signup_domain = $2 AND id <> $1)",
+        )
+        .bind(self.id)
+        .bind(&domain)
+        .fetch_one(pool)
+        .await?;
+        if claimed {
+            return Err(AppError::UpdateWorkspaceError(format!("Signup domain is claimed by another workspace: {}", domain)));
+        }
+        // the verified domain is kept until the new one is verified
+        sqlx::query(
+            r#"
+            UPDATE workspaces SET
+                signup_domain = CASE WHEN $2 = '' THEN NULL ELSE signup_domain END,
+                pending_signup_domain = CASE WHEN $2 = '' OR signup_domain = $2 THEN NULL ELSE $2 END,
+                signup_domain_token = CASE WHEN $2 = '' OR signup_domain = $2 THEN NULL ELSE $3 END
+            WHERE id = $1
+            "#,
+        )
+            .bind(self.id)
+            .bind(&domain)
+            .bind(generate_token(SIGNUP_DOMAIN_TOKEN_BYTES))
+            .execute(pool)
+            .await?;
+        Self::fetch_signup_domain(self.id as _, pool).await
+    }
+
+    pub async fn fetch_signup_domain(id: u64, pool: &PgPool) -> Result<SignupDomain, AppError> {
+        let signup: Option<SignupDomain> = sqlx::query_as(
+            r#"
+            SELECT signup_domain AS domain, pending_signup_domain AS pending_domain,
+                $2 || '.' || pending_signup_domain AS record_name,
+                $3 || signup_domain_token AS record_value
+            FROM workspaces WHERE id = $1
+            "#,
+        )
+        .bind(id as i64)
+        .bind(SIGNUP_DOMAIN_RECORD)
+        .bind(SIGNUP_DOMAIN_TOKEN_PREFIX)
+        .fetch_optional(pool)
+        .await?;
+        signup.ok_or_else(|| AppError::NotFound(format!("workspace not found: {}", id)))
+    }
+
+    // records are the TXT records found at the pending domain's record_name
+    pub async fn verify_signup_domain(&self, records: &[String], pool: &PgPool) -> Result<SignupDomain, AppError> {
+        let signup = Self::fetch_signup_domain(self.id as _, pool).await?;
+        let (Some(domain), Some(value)) = (&signup.pending_domain, &signup.record_value) else {
+            return Err(AppError::UpdateWorkspaceError("No signup domain is waiting for verification".to_string()));
+        };
+        if !records.iter().any(|r| r.trim() == value) {
+            return Err(AppError::UpdateWorkspaceError(format!(
+                "TXT record {} with {} not found",
+                signup.record_name.as_deref().unwrap_or_default(),
+                value
+            )));
+        }
+        sqlx::query(
+            r#"
+            UPDATE workspaces SET signup_domain = pending_signup_domain, pending_signup_domain = NULL,
+                signup_domain_token = NULL
+            WHERE id = $1 AND pending_signup_domain = $2
+            "#,
+        )
+        .bind(self.id)
+        .bind(domain)
+        .execute(pool)
+        .await
+        .map_err(|e| match &e {
+            sqlx::Error::Database(db) if db.is_unique_violation() => {
+                AppError::UpdateWorkspaceError(format!("Signup domain is claimed by another workspace: {}", domain))
+            }
+            _ => e.into(),
+        })?;
+        Self::fetch_signup_domain(self.id as _, pool).await
+    }
+
+    pub async fn enable_approvals(&self, pool: &PgPool) -> Result<(), AppError> {
+        sqlx::query("UPDATE workspaces SET require_approval = TRUE WHERE id = $1")
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn fetch_settings(id: u64, pool: &PgPool) -> Result<WorkspaceSettings, AppError> {
+        let settings: Option<WorkspaceSettings> = sqlx::query_as(
+            "SELECT name, slug, max_pins_per_chat, signup_domain, require_approval FROM workspaces WHERE id = $1",
+        )
+        .bind(id as i64)
+        .fetch_optional(pool)
+        .await?;
+        settings.ok_or_else(|| AppError::NotFound(format!("workspace not found: {}", id)))
+    }
+
     pub async fn find_by_id(id: u64, pool: &PgPool) -> Result<Option<Self>, AppError> {
         let ws = sqlx::query_as(
             r#"
-            SELECT id, name, owner_id, created_at
+            SELECT id, name, slug, owner_id, created_at
             FROM workspaces
             WHERE id = $1
             "#,
@@ -63,7 +351,7 @@ impl Workspace {
     pub async fn fetch_all_chat_users(id: u64, pool: &PgPool) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
             r#"
-            SELECT id, fullname, email
+            SELECT id, fullname, email, avatar_url
             FROM users
             WHERE ws_id = $1
             "#,
@@ -73,17 +361,134 @@ impl Workspace {
         .await?;
         Ok(users)
     }
+
+    pub async fn search_chat_users(id: u64, input: &ListChatUsers, pool: &PgPool) -> Result<Vec<ChatUser>, AppError> {
+        let pattern = input.pattern();
+        let limit = input.limit();
+        let users = sqlx::query_as(
+            r#"
+            SELECT id, fullname, email, avatar_url
+            FROM users
+            WHERE ws_id = $1 AND ($2::text IS NULL OR fullname ILIKE $2 OR email ILIKE $2)
+            ORDER BY id
+            LIMIT $3 OFFSET $4
+            "#,
+        )
+        .bind(id as i64)
+        .bind(pattern)
+        .bind(limit as i64)
+        .bind(input.offset.unwrap_or_default() as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(users)
+    }
+
+    // all the matches of search_chat_users, not just the page
+    pub async fn count_chat_users(id: u64, input: &ListChatUsers, pool: &PgPool) -> Result<i64, AppError> {
+        let count = sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*) FROM users
+            WHERE ws_id = $1 AND ($2::text IS NULL OR fullname ILIKE $2 OR email ILIKE $2)
+            "#,
+        )
+        .bind(id as i64)
+        .bind(input.pattern())
+        .fetch_one(pool)
+        .await?;
+        Ok(count)
+    }
+
+    // changes whenever search_chat_users would answer differently, users are only ever updated
+    // or added
+    pub async fn chat_users_fingerprint(id: u64, input: &ListChatUsers, pool: &PgPool) -> Result<String, AppError> {
+        let (count, updated_at): (i64, i64) = sqlx::query_as(
+            r#"
+            SELECT COUNT(*), COALESCE((EXTRACT(EPOCH FROM MAX(updated_at)) * 1000000)::bigint, 0)
+            FROM users
+            WHERE ws_id = $1 AND ($2::text IS NULL OR fullname ILIKE $2 OR email ILIKE $2)
+            "#,
+        )
+        .bind(id as i64)
+        .bind(input.pattern())
+        .fetch_one(pool)
+        .await?;
+        Ok(format!("users:{}:{}:{}", id, count, updated_at))
+    }
+
+    pub async fn fetch_stats(id: u64, pool: &PgPool) -> Result<WorkspaceStats, AppError> {
+        let stats = sqlx::query_as(
+            r#"
+            SELECT
+                (SELECT COUNT(*) FROM users WHERE ws_id = $1) AS members,
+                (SELECT COUNT(*) FROM chats WHERE ws_id = $1) AS chats,
+                COUNT(*) FILTER (WHERE m.created_at > NOW() - INTERVAL '7 days') AS messages_7d,
+                COUNT(*) FILTER (WHERE m.created_at > NOW() - INTERVAL '30 days') AS messages_30d,
+                (COALESCE(SUM(octet_length(m.content)), 0) + COALESCE((
+                    SELECT SUM(octet_length(mc.content))
+                    FROM message_chunks mc
+                    JOIN messages m2 ON m2.id = mc.message_id
+                    JOIN chats c2 ON c2.id = m2.chat_id
+                    WHERE c2.ws_id = $1
+                ), 0))::bigint AS storage_bytes
+            FROM messages m
+            JOIN chats c ON c.id = m.chat_id
+            WHERE c.ws_id = $1
+            "#,
+        )
+        .bind(id as i64)
+        .fetch_one(pool)
+        .await?;
+        Ok(stats)
+    }
 }
 
+fn slugify(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "ws".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+// first of `slug`, `slug-2`, `slug-3`... not used by another workspace, current or alias
+pub(super) async fn available_slug(name: &str, ws_id: Option<i64>, pool: &PgPool) -> Result<String, AppError> {
+    let base = slugify(name);
+    let taken: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT slug FROM workspaces WHERE (slug = $1 OR slug LIKE $1 || '-%') AND id IS DISTINCT FROM $2
+        UNION
+        SELECT slug FROM workspace_slug_aliases WHERE (slug = $1 OR slug LIKE $1 || '-%') AND ws_id IS DISTINCT FROM $2
+        "#,
+    )
+    .bind(&base)
+    .bind(ws_id)
+    .fetch_all(pool)
+    .await?;
+    let slug = std::iter::once(base.clone())
+        .chain((2..).map(|n| format!("{}-{}", base, n)))
+        .find(|s| !taken.contains(s))
+        .expect("slug candidates are infinite");
+    Ok(slug)
+}
+
+pub(super) fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
 
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
-
     use anyhow::Result;
-    use sqlx_db_tester::TestPg;
 
-    use crate::{test_util::get_test_pool, CreateUser, User};
+    use crate::{config::{PasswordHashing, PasswordPolicy}, test_util::get_test_pool, CreateUser, User};
 
     use super::*;
     #[tokio::test]
@@ -92,7 +497,7 @@ mod tests {
         let ws = Workspace::create("test", 0, &pool).await.unwrap();
 
         let input = CreateUser::new(&ws.name, "Tian Chen", "tyr@acme.org", "Hunter42");
-        let user = User::create(&input, &pool).await.unwrap();
+        let user = User::create(&input, &PasswordPolicy::default(), &PasswordHashing::default(), &pool).await.unwrap();
 
         assert_eq!(ws.name, "test");
 
@@ -107,7 +512,7 @@ mod tests {
     async fn workspace_should_find_by_name() -> Result<()> {
         let (_tdb, pool) = get_test_pool(None).await;
         let ws_name = "acme";
-        let ws2 = Workspace::find_by_name(&ws_name, &pool).await.unwrap().unwrap();
+        let ws2 = Workspace::find_by_name(ws_name, &pool).await.unwrap().unwrap();
         assert_eq!(ws_name, ws2.name);
         Ok(())
     }
@@ -118,4 +523,77 @@ mod tests {
         assert_eq!(users.len(), 5);
         Ok(())
     }
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn workspace_should_fetch_stats() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let stats = Workspace::fetch_stats(1, &pool).await?;
+        assert_eq!(stats.members, 5);
+        assert_eq!(stats.chats, 4);
+        assert_eq!(stats.messages_7d, 10);
+        assert_eq!(stats.messages_30d, 10);
+        assert!(stats.storage_bytes > 0);
+
+        let stats = Workspace::fetch_stats(2, &pool).await?;
+        assert_eq!(stats.members, 0);
+        assert_eq!(stats.messages_30d, 0);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn workspace_should_search_chat_users() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let input = ListChatUsers {
+            q: Some("CHEN".to_string()),
+            limit: Some(2),
+            offset: Some(1),
+        };
+        let users = Workspace::search_chat_users(1, &input, &pool).await?;
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].email, "alice@acme.org");
+
+        let input = ListChatUsers {
+            q: Some("bob@".to_string()),
+            ..Default::default()
+        };
+        let users = Workspace::search_chat_users(1, &input, &pool).await?;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].fullname, "Bob Chen");
+
+        let input = ListChatUsers {
+            q: Some("%".to_string()),
+            ..Default::default()
+        };
+        let users = Workspace::search_chat_users(1, &input, &pool).await?;
+        assert!(users.is_empty());
+        Ok(())
+    }
+    #[test]
+    fn slugify_should_work() {
+        assert_eq!(slugify("Acme Corp."), "acme-corp");
+        assert_eq!(slugify("  --Rust__Bootcamp 2025  "), "rust-bootcamp-2025");
+        assert_eq!(slugify("你好"), "ws");
+    }
+    #[tokio::test]
+    async fn workspace_rename_should_keep_old_slug_as_alias() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws = Workspace::find_by_slug("acme", &pool).await?.expect("acme should exist");
+        let ws = ws.rename("Acme Corp", &pool).await?;
+        assert_eq!(ws.slug, "acme-corp");
+        let found = Workspace::find_by_slug("acme", &pool).await?.expect("alias should resolve");
+        assert_eq!(found.id, ws.id);
+        assert_eq!(found.slug, "acme-corp");
+
+        // slug of another workspace's alias is not reused
+        let other = Workspace::create("ACME", 0, &pool).await?;
+        assert_eq!(other.slug, "acme-2");
+
+        let ret = other.rename("foo", &pool).await;
+        assert!(matches!(ret, Err(AppError::WorkspaceNameTaken(_))));
+
+        // renaming back reclaims the slug
+        let ws = ws.rename("acme", &pool).await?;
+        assert_eq!(ws.slug, "acme");
+        let found = Workspace::find_by_slug("acme-corp", &pool).await?.expect("alias should resolve");
+        assert_eq!(found.slug, "acme");
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{AppError, Workspace};
+
+use super::workspace::{available_slug, MAX_WORKSPACE_NAME_LEN, WORKSPACE_NAME_INDEX};
+
+// users are bound to one workspace and emails are unique, so members are copied as new users
+// at a plus address of their email, e.g. alice+ws7@acme.org, with the same password
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CloneWorkspace {
+    pub name: String,
+    // copies the messages of the cloned chats too, only the structure is copied otherwise
+    #[serde(default)]
+    pub include_messages: bool,
+}
+
+impl Workspace {
+    // channels and group chats, members and settings. Direct messages, sso, mail settings and
+    // second factors are left out, and the signup domain stays with the original workspace.
+    pub async fn clone_workspace(id: u64, input: &CloneWorkspace, pool: &PgPool) -> Result<Self, AppError> {
+        let name = input.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_WORKSPACE_NAME_LEN {
+            return Err(AppError::UpdateWorkspaceError(format!(
+                "Workspace name must be 1 to {} characters",
+                MAX_WORKSPACE_NAME_LEN
+            )));
+        }
+        let slug = available_slug(name, None, pool).await?;
+        let mut tx = pool.begin().await?;
+        let ws: Self = sqlx::query_as(
+            r#"
+            INSERT INTO workspaces (name, slug, owner_id, max_message_length, session_idle_timeout, session_max_age,
+                session_remember_me_max_age, max_pins_per_chat, moderation_mode, moderation_terms, require_approval)
+            SELECT $2, $3, 0, max_message_length, session_idle_timeout, session_max_age,
+                session_remember_me_max_age, max_pins_per_chat, moderation_mode, moderation_terms, require_approval
+            FROM workspaces
+            WHERE id = $1 AND deleted_at IS NULL
+            RETURNING id, name, slug, owner_id, created_at
+            "#,
+        )
+        .bind(id as i64)
+        .bind(name)
+        .bind(&slug)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| match &e {
+            sqlx::Error::Database(db) if db.constraint() == Some(WORKSPACE_NAME_INDEX) => {
+                AppError::WorkspaceNameTaken(name.to_string())
+            }
+            _ => e.into(),
+        })?
+        .ok_or_else(|| AppError::NotFound(format!("workspace not found: {}", id)))?;
+
+        clone_members(&mut tx, id as _, ws.id).await?;
+        clone_chats(&mut tx, id as _, ws.id, input.include_messages).await?;
+        if input.include_messages {
+            clone_messages(&mut tx).await?;
+        }
+        let ws = sqlx::query_as(
+            r#"
+            UPDATE workspaces
+            SET owner_id = COALESCE((
+                SELECT m.new_id FROM clone_users m JOIN workspaces w ON w.owner_id = m.old_id WHERE w.id = $2
+            ), 0)
+            WHERE id = $1
+            RETURNING id, name, slug, owner_id, created_at
+            "#,
+        )
+        .bind(ws.id)
+        .bind(id as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(ws)
+    }
+}
+
+// ids are allocated up front so that references between the copies can be mapped
+async fn clone_members(tx: &mut Transaction<'_, Postgres>, from: i64, to: i64) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        CREATE TEMP TABLE clone_users ON COMMIT DROP AS
+        SELECT id AS old_id, nextval(pg_get_serial_sequence('users', 'id')) AS new_id
+        FROM users WHERE ws_id = $1
+        "#,
+    )
+    .bind(from)
+    .execute(&mut **tx)
+    .await?;
+    // addresses too long for the column get an undeliverable one, like bridge ghosts
+    sqlx::query(
+        r#"
+        INSERT INTO users (id, ws_id, email, fullname, password_hash, avatar_url, role, email_verified_at,
+            deactivated_at)
+        SELECT m.new_id, $1, e.email, u.fullname, u.password_hash, u.avatar_url, u.role, u.email_verified_at,
+            u.deactivated_at
+        FROM clone_users m
+        JOIN users u ON u.id = m.old_id
+        CROSS JOIN LATERAL (SELECT regexp_replace(u.email, '@', '+ws' || $1 || '@') AS plus) p
+        CROSS JOIN LATERAL (
+            SELECT CASE WHEN length(p.plus) <= 64 THEN p.plus ELSE m.new_id || '@ws' || $1 || '.clone.invalid' END
+                AS email
+        ) e
+        "#,
+    )
+    .bind(to)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+async fn clone_chats(
+    tx: &mut Transaction<'_, Postgres>,
+    from: i64,
+    to: i64,
+    with_messages: bool,
+) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        CREATE TEMP TABLE clone_chats ON COMMIT DROP AS
+        SELECT id AS old_id, nextval(pg_get_serial_sequence('chats', 'id')) AS new_id
+        FROM chats WHERE ws_id = $1 AND type <> 'single'
+        "#,
+    )
+    .bind(from)
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO chats (id, ws_id, name, type, members, owner_id, discoverable, last_seq)
+        SELECT m.new_id, $1, c.name, c.type,
+            ARRAY(
+                SELECT u.new_id FROM unnest(c.members) WITH ORDINALITY AS cm(id, n)
+                JOIN clone_users u ON u.old_id = cm.id
+                ORDER BY cm.n
+            ),
+            COALESCE((SELECT u.new_id FROM clone_users u WHERE u.old_id = c.owner_id), 0),
+            c.discoverable,
+            CASE WHEN $2 THEN c.last_seq ELSE 0 END
+        FROM clone_chats m JOIN chats c ON c.id = m.old_id
+        "#,
+    )
+    .bind(to)
+    .bind(with_messages)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+async fn clone_messages(tx: &mut Transaction<'_, Postgres>) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        CREATE TEMP TABLE clone_messages ON COMMIT DROP AS
+        SELECT m.id AS old_id, nextval(pg_get_serial_sequence('messages', 'id')) AS new_id
+        FROM messages m JOIN clone_chats c ON c.old_id = m.chat_id
+        "#,
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO messages (id, chat_id, sender_id, content, images, created_at, seq, client_created_at, chunks,
+            kind, thread_id, external_id, original_content, display_name, icon_url)
+        SELECT cm.new_id, c.new_id, u.new_id, m.content, m.images, m.created_at, m.seq, m.client_created_at,
+            m.chunks, m.kind, t.new_id, m.external_id, m.original_content, m.display_name, m.icon_url
+        FROM clone_messages cm
+        JOIN messages m ON m.id = cm.old_id
+        JOIN clone_chats c ON c.old_id = m.chat_id
+        JOIN clone_users u ON u.old_id = m.sender_id
+        LEFT JOIN clone_messages t ON t.old_id = m.thread_id
+        "#,
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO message_chunks (message_id, idx, content)
+        SELECT cm.new_id, mc.idx, mc.content
+        FROM clone_messages cm JOIN message_chunks mc ON mc.message_id = cm.old_id
+        "#,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::PasswordHashing, test_util::get_test_pool, Chat, ListMessages, Message, SigninUser, User};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn clone_workspace_should_copy_structure() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let owner = User::find_by_email("tchen@acme.org", &pool).await?.expect("user should exist");
+        let ws = Workspace::find_by_id(1, &pool).await?.expect("workspace should exist");
+        ws.update_owner(owner.id as _, &pool).await?;
+
+        let input = CloneWorkspace { name: "acme staging".to_string(), include_messages: false };
+        let staging = Workspace::clone_workspace(1, &input, &pool).await?;
+        assert_eq!(staging.slug, "acme-staging");
+        let email = format!("tchen+ws{}@acme.org", staging.id);
+        let copy = User::find_by_email(&email, &pool).await?.expect("member should be copied");
+        assert_eq!(copy.ws_id, staging.id);
+        assert_eq!(staging.owner_id, copy.id);
+        // members sign in to the copy with their own password
+        let signin = SigninUser::new(&email, "123456");
+        assert!(User::verify(&signin, &PasswordHashing::default(), &pool).await?.is_some());
+
+        let chats = Chat::fetch_all(staging.id as _, &pool).await?;
+        let originals = Chat::fetch_all(1, &pool).await?;
+        assert_eq!(chats.len(), originals.iter().filter(|c| c.r#type != crate::ChatType::Single).count());
+        assert!(chats.iter().all(|c| c.members.iter().all(|id| *id != owner.id)));
+        let messages = Message::list(&ListMessages::default(), chats[0].id as _, &pool).await?;
+        assert!(messages.is_empty());
+
+        let input = CloneWorkspace { name: "acme training".to_string(), include_messages: true };
+        let training = Workspace::clone_workspace(1, &input, &pool).await?;
+        let chats = Chat::fetch_all(training.id as _, &pool).await?;
+        let original = originals.iter().find(|c| c.name == chats[0].name).expect("chat should be copied");
+        let messages = Message::list(&ListMessages::default(), chats[0].id as _, &pool).await?;
+        let expected = Message::list(&ListMessages::default(), original.id as _, &pool).await?;
+        assert_eq!(messages.len(), expected.len());
+        assert_eq!(messages.first().map(|m| &m.content), expected.first().map(|m| &m.content));
+
+        let input = CloneWorkspace { name: "acme staging".to_string(), ..input };
+        let ret = Workspace::clone_workspace(1, &input, &pool).await;
+        assert!(matches!(ret, Err(AppError::WorkspaceNameTaken(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+use std::fmt;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use reqwest::{header::{ACCEPT, USER_AGENT}, Url};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{config::OAuthProviderConfig, mailer, AppError, AppState};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Provider {
+    Google,
+    Github,
+}
+
+// the account at the provider, the email has been verified by the provider
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct OAuthIdentity {
+    pub(crate) subject: String,
+    pub(crate) email: String,
+    pub(crate) name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TokenResponse {
+    pub(crate) access_token: String,
+    // only from openid connect providers
+    #[serde(default)]
+    pub(crate) id_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GoogleUser {
+    sub: String,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubUser {
+    id: i64,
+    login: String,
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+impl Provider {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "google" => Some(Self::Google),
+            "github" => Some(Self::Github),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Google => "google",
+            Self::Github => "github",
+        }
+    }
+
+    fn auth_url<'a>(&self, config: &'a OAuthProviderConfig) -> &'a str {
+        config.auth_url.as_deref().unwrap_or(match self {
+            Self::Google => "https://accounts.google.com/o/oauth2/v2/auth",
+            Self::Github => "https://github.com/login/oauth/authorize",
+        })
+    }
+
+    fn token_url<'a>(&self, config: &'a OAuthProviderConfig) -> &'a str {
+        config.token_url.as_deref().unwrap_or(match self {
+            Self::Google => "https://oauth2.googleapis.com/token",
+            Self::Github => "https://github.com/login/oauth/access_token",
+        })
+    }
+
+    fn api_url<'a>(&self, config: &'a OAuthProviderConfig) -> &'a str {
+        config.api_url.as_deref().unwrap_or(match self {
+            Self::Google => "https://openidconnect.googleapis.com",
+            Self::Github => "https://api.github.com",
+        })
+    }
+
+    fn scope(&self) -> &'static str {
+        match self {
+            Self::Google => "openid email profile",
+            Self::Github => "read:user user:email",
+        }
+    }
+}
+
+// looks up the provider by name, only configured providers can be used
+pub(crate) fn provider<'a>(state: &'a AppState, name: &str) -> Result<(Provider, &'a OAuthProviderConfig), AppError> {
+    Provider::parse(name)
+        .zip(state.config.oauth.get(name))
+        .ok_or_else(|| AppError::NotFound(format!("oauth provider {}", name)))
+}
+
+pub(crate) fn redirect_uri(state: &AppState, provider: Provider) -> String {
+    mailer::link(state, &format!("/api/auth/{}/callback", provider.name()))
+}
+
+// where the browser is sent to sign in at the provider, with a PKCE challenge for the verifier
+pub(crate) fn authorize_url(
+    provider: Provider,
+    config: &OAuthProviderConfig,
+    redirect_uri: &str,
+    state: &str,
+    code_verifier: &str,
+) -> Result<String, AppError> {
+    let code_challenge = pkce_challenge(code_verifier);
+    let url = Url::parse_with_params(
+        provider.auth_url(config),
+        &[
+            ("response_type", "code"),
+            ("client_id", config.client_id.as_str()),
+            ("redirect_uri", redirect_uri),
+            ("scope", provider.scope()),
+            ("state", state),
+            ("code_challenge", code_challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|e| AppError::InvalidInput(format!("invalid oauth authorize url: {}", e)))?;
+    Ok(url.into())
+}
+
+// exchanges the authorization code, then reads the account from the provider's api
+pub(crate) async fn fetch_identity(
+    state: &AppState,
+    provider: Provider,
+    config: &OAuthProviderConfig,
+    code: &str,
+    code_verifier: &str,
+) -> Result<OAuthIdentity, AppError> {
+    let redirect_uri = redirect_uri(state, provider);
+    let client = (config.client_id.as_str(), config.client_secret.as_str());
+    let token = exchange_code(state, provider.token_url(config), client, &redirect_uri, code, code_verifier).await?;
+
+    let api_url = provider.api_url(config).trim_end_matches('/');
+    match provider {
+        Provider::Google => {
+            let user: GoogleUser = get_json(state, &format!("{}/v1/userinfo", api_url), &token.access_token).await?;
+            let email = user
+                .email
+                .filter(|_| user.email_verified)
+                .ok_or_else(|| AppError::PermissionDenied("the google account has no verified email".to_string()))?;
+            Ok(OAuthIdentity {
+                subject: user.sub,
+                name: user.name.unwrap_or_else(|| email.clone()),
+                email,
+            })
+        }
+        Provider::Github => {
+            let user: GithubUser = get_json(state, &format!("{}/user", api_url), &token.access_token).await?;
+            let emails: Vec<GithubEmail> = get_json(state, &format!("{}/user/emails", api_url), &token.access_token).await?;
+            let email = emails
+                .into_iter()
+                .find(|e| e.primary && e.verified)
+                .map(|e| e.email)
+                .ok_or_else(|| AppError::PermissionDenied("the github account has no verified primary email".to_string()))?;
+            Ok(OAuthIdentity {
+                subject: user.id.to_string(),
+                name: user.name.unwrap_or(user.login),
+                email,
+            })
+        }
+    }
+}
+
+pub(crate) fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+// the client is its id and secret
+pub(crate) async fn exchange_code(
+    state: &AppState,
+    token_url: &str,
+    client: (&str, &str),
+    redirect_uri: &str,
+    code: &str,
+    code_verifier: &str,
+) -> Result<TokenResponse, AppError> {
+    let request = state
+        .http
+        .post(token_url)
+        .header(ACCEPT, "application/json")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", client.0),
+            ("client_secret", client.1),
+            ("code_verifier", code_verifier),
+        ]);
+    state
+        .http
+        .send(request)
+        .await
+        .map_err(provider_error)?
+        .error_for_status()
+        .map_err(provider_error)?
+        .json()
+        .await
+        .map_err(provider_error)
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(state: &AppState, url: &str, access_token: &str) -> Result<T, AppError> {
+    let request = state
+        .http
+        .get(url)
+        .bearer_auth(access_token)
+        .header(ACCEPT, "application/json")
+        // github rejects requests without one
+        .header(USER_AGENT, "chat-server");
+    state
+        .http
+        .send(request)
+        .await
+        .map_err(provider_error)?
+        .error_for_status()
+        .map_err(provider_error)?
+        .json()
+        .await
+        .map_err(provider_error)
+}
+
+pub(crate) fn provider_error(e: impl fmt::Display) -> AppError {
+    AppError::PermissionDenied(format!("oauth provider request failed: {}", e))
+}
@@ -0,0 +1,157 @@
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::warn;
+
+use crate::{utils::{TokenId, JWT_DURATION}, AppError, AppState, Grant, OAuthApp, WorkspaceRole};
+
+// what app and bot tokens can reach, the chats the user can see. Every route here checks the
+// token's scopes. Their account, sessions and the workspace's settings stay with the user's own
+// tokens, whatever the scopes.
+const APP_PATHS: &[&str] = &["batch", "channels", "chats", "events", "graphql", "mentions", "messages", "sync", "threads", "users"];
+
+// a request to the token endpoint, form encoded, see RFC 6749 sections 4.1.3 and 6
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub redirect_uri: Option<String>,
+    #[serde(default)]
+    pub code_verifier: Option<String>,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    // or in basic auth
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: u64,
+    pub refresh_token: String,
+    // space separated
+    pub scope: String,
+}
+
+// errors in the shape apps expect, see RFC 6749 section 5.2
+#[derive(Debug)]
+pub(crate) struct OAuthError {
+    status: StatusCode,
+    error: &'static str,
+    description: String,
+}
+
+impl OAuthError {
+    fn new(status: StatusCode, error: &'static str, description: impl Into<String>) -> Self {
+        Self { status, error, description: description.into() }
+    }
+
+    fn invalid_request(description: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request", description)
+    }
+}
+
+impl From<AppError> for OAuthError {
+    fn from(e: AppError) -> Self {
+        let (status, error) = match &e {
+            AppError::PermissionDenied(_) => (StatusCode::BAD_REQUEST, "invalid_grant"),
+            AppError::InvalidInput(_) => (StatusCode::BAD_REQUEST, "invalid_request"),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "server_error"),
+        };
+        Self::new(status, error, e.to_string())
+    }
+}
+
+impl IntoResponse for OAuthError {
+    fn into_response(self) -> Response {
+        let body = json!({ "error": self.error, "error_description": self.description });
+        let mut res = (self.status, Json(body)).into_response();
+        res.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        res
+    }
+}
+
+// an access token limited to what the user consented to, and a new refresh token. The client
+// authenticates with basic auth or in the form, public clients with their id alone.
+pub(crate) async fn issue_token(
+    state: &AppState,
+    basic: Option<(String, String)>,
+    input: TokenRequest,
+) -> Result<TokenResponse, OAuthError> {
+    let (client_id, secret) = match basic {
+        Some((id, secret)) => (Some(id), Some(secret)),
+        None => (input.client_id, input.client_secret),
+    };
+    let Some(client_id) = client_id else {
+        return Err(OAuthError::invalid_request("client_id is required"));
+    };
+    let Some(app) = OAuthApp::authenticate(&client_id, secret.as_deref(), &state.pool).await? else {
+        warn!("token request for app {} with a wrong or missing secret", client_id);
+        return Err(OAuthError::new(StatusCode::UNAUTHORIZED, "invalid_client", "unknown client or wrong secret"));
+    };
+    let token = match input.grant_type.as_str() {
+        "authorization_code" => {
+            let (Some(code), Some(redirect_uri), Some(verifier)) = (input.code, input.redirect_uri, input.code_verifier)
+            else {
+                return Err(OAuthError::invalid_request("code, redirect_uri and code_verifier are required"));
+            };
+            app.redeem_code(&code, &redirect_uri, &verifier, &state.pool).await?
+        }
+        "refresh_token" => {
+            let Some(refresh_token) = input.refresh_token else {
+                return Err(OAuthError::invalid_request("refresh_token is required"));
+            };
+            app.refresh(&refresh_token, &state.pool).await?
+        }
+        other => {
+            let msg = format!("unsupported grant_type: {}", other);
+            return Err(OAuthError::new(StatusCode::BAD_REQUEST, "unsupported_grant_type", msg));
+        }
+    };
+    let grant = Grant {
+        role: WorkspaceRole::Member,
+        scopes: token.scopes,
+        app_id: Some(app.id),
+    };
+    let id = TokenId::generate();
+    app.record_access_token(token.user.id, &id, &state.pool).await?;
+    let access_token = state.ek.sign_with_id(token.user, &grant, &id)?;
+    Ok(TokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: JWT_DURATION,
+        refresh_token: token.refresh_token,
+        scope: grant.scopes.join(" "),
+    })
+}
+
+// path is within the api, e.g. /chats/1
+pub(crate) fn app_may_call(path: &str) -> bool {
+    let first = path.trim_start_matches('/').split('/').next().unwrap_or_default();
+    APP_PATHS.contains(&first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn app_tokens_should_only_reach_chats() {
+        assert!(app_may_call("/chats/1/messages"));
+        assert!(app_may_call("/sync"));
+        assert!(!app_may_call("/me/email"));
+        assert!(!app_may_call("/workspace/bots"));
+        assert!(!app_may_call("/oauth/authorize"));
+        assert!(!app_may_call("/"));
+    }
+}
@@ -0,0 +1,196 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use reqwest::{header::ACCEPT, Url};
+use serde::Deserialize;
+
+use crate::{
+    mailer,
+    oauth::{exchange_code, pkce_challenge, provider_error, OAuthIdentity},
+    AppError, AppState, OidcConfig,
+};
+
+// accepted difference between our clock and the identity provider's
+const CLOCK_SKEW_SECS: i64 = 60;
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    // left out means unverified, the email is only trusted when the provider says so
+    #[serde(default)]
+    email_verified: bool,
+    #[serde(default)]
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+pub(crate) fn redirect_uri(state: &AppState) -> String {
+    mailer::link(state, "/api/sso/callback")
+}
+
+pub(crate) async fn discover(state: &AppState, issuer: &str) -> Result<Discovery, AppError> {
+    let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+    let request = state.http.get(url).header(ACCEPT, "application/json");
+    let discovery: Discovery = state
+        .http
+        .send(request)
+        .await
+        .map_err(provider_error)?
+        .error_for_status()
+        .map_err(provider_error)?
+        .json()
+        .await
+        .map_err(provider_error)?;
+    if discovery.issuer != issuer {
+        return Err(AppError::PermissionDenied(format!(
+            "identity provider reports issuer {} instead of {}",
+            discovery.issuer, issuer
+        )));
+    }
+    Ok(discovery)
+}
+
+pub(crate) fn authorize_url(
+    discovery: &Discovery,
+    config: &OidcConfig,
+    redirect_uri: &str,
+    state: &str,
+    code_verifier: &str,
+    nonce: &str,
+) -> Result<String, AppError> {
+    let code_challenge = pkce_challenge(code_verifier);
+    let url = Url::parse_with_params(
+        &discovery.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", config.client_id.as_str()),
+            ("redirect_uri", redirect_uri),
+            ("scope", "openid email profile"),
+            ("state", state),
+            ("nonce", nonce),
+            ("code_challenge", code_challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|e| AppError::InvalidInput(format!("invalid oidc authorization endpoint: {}", e)))?;
+    Ok(url.into())
+}
+
+// the id token comes straight from the token endpoint over TLS, so per OIDC core 3.1.3.7 its
+// signature need not be checked, the claims still are
+pub(crate) async fn fetch_identity(
+    state: &AppState,
+    discovery: &Discovery,
+    config: &OidcConfig,
+    code: &str,
+    code_verifier: &str,
+    nonce: &str,
+) -> Result<OAuthIdentity, AppError> {
+    let client = (config.client_id.as_str(), config.client_secret.as_str());
+    let token = exchange_code(state, &discovery.token_endpoint, client, &redirect_uri(state), code, code_verifier).await?;
+    let id_token = token
+        .id_token
+        .ok_or_else(|| AppError::PermissionDenied("identity provider returned no id token".to_string()))?;
+    let claims = decode_claims(&id_token)?;
+    verify_claims(&claims, config, nonce, Utc::now().timestamp())?;
+    let email = claims
+        .email
+        .filter(|_| claims.email_verified)
+        .ok_or_else(|| AppError::PermissionDenied("identity provider returned no verified email".to_string()))?;
+    Ok(OAuthIdentity {
+        subject: claims.sub,
+        name: claims.name.unwrap_or_else(|| email.clone()),
+        email,
+    })
+}
+
+fn decode_claims(id_token: &str) -> Result<IdTokenClaims, AppError> {
+    let invalid = || AppError::PermissionDenied("id token is malformed".to_string());
+    let payload = id_token.split('.').nth(1).ok_or_else(invalid)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).map_err(|_| invalid())?;
+    serde_json::from_slice(&payload).map_err(|_| invalid())
+}
+
+fn verify_claims(claims: &IdTokenClaims, config: &OidcConfig, nonce: &str, now: i64) -> Result<(), AppError> {
+    let audience_ok = match &claims.aud {
+        Audience::One(aud) => *aud == config.client_id,
+        Audience::Many(auds) => auds.contains(&config.client_id),
+    };
+    let reason = if claims.iss != config.issuer {
+        "issuer mismatch"
+    } else if !audience_ok {
+        "audience mismatch"
+    } else if claims.exp + CLOCK_SKEW_SECS < now {
+        "id token has expired"
+    } else if claims.nonce.as_deref() != Some(nonce) {
+        "nonce mismatch"
+    } else {
+        return Ok(());
+    };
+    Err(AppError::PermissionDenied(format!("id token rejected: {}", reason)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> OidcConfig {
+        OidcConfig {
+            ws_id: 1,
+            issuer: "https://idp.acme.org".to_string(),
+            client_id: "chat".to_string(),
+            client_secret: "secret".to_string(),
+            jit_provisioning: true,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn id_token(claims: serde_json::Value) -> String {
+        format!("eyJhbGciOiJSUzI1NiJ9.{}.sig", URL_SAFE_NO_PAD.encode(claims.to_string()))
+    }
+
+    #[test]
+    fn id_token_claims_should_be_verified() {
+        let config = config();
+        let claims = serde_json::json!({
+            "iss": "https://idp.acme.org", "sub": "u1", "aud": ["chat", "other"], "exp": 1000, "nonce": "n1",
+            "email": "alice@acme.org",
+        });
+        let claims = decode_claims(&id_token(claims)).unwrap();
+        assert!(verify_claims(&claims, &config, "n1", 1000).is_ok());
+        assert!(verify_claims(&claims, &config, "n2", 1000).is_err());
+        assert!(verify_claims(&claims, &config, "n1", 2000).is_err());
+        assert!(!claims.email_verified);
+
+        let claims = serde_json::json!({
+            "iss": "https://idp.acme.org", "sub": "u1", "aud": "chat", "exp": 1000, "email": "alice@acme.org",
+            "email_verified": true,
+        });
+        assert!(decode_claims(&id_token(claims)).unwrap().email_verified);
+
+        let claims = serde_json::json!({ "iss": "https://evil.org", "sub": "u1", "aud": "chat", "exp": 1000, "nonce": "n1" });
+        let claims = decode_claims(&id_token(claims)).unwrap();
+        assert!(verify_claims(&claims, &config, "n1", 1000).is_err());
+        assert!(decode_claims("not-a-jwt").is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use std::str::FromStr;
+
+use axum::{
+    extract::{FromRequestParts, OriginalUri, Query},
+    http::{
+        header::{ETAG, IF_NONE_MATCH, LINK},
+        request::Parts,
+        HeaderValue, StatusCode, Uri,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+pub use chat_core::Paginated;
+
+use crate::{utils::hex_encode, ApiVersion, AppError, CURRENT_API_VERSION};
+
+// listings answer with Paginated from this api version on, older ones get the bare items
+const PAGINATED_SINCE: u32 = 2;
+// bytes of the hash kept in etags
+const ETAG_BYTES: usize = 16;
+
+#[derive(Debug, Default, Deserialize)]
+struct PageQuery {
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+// answers a listing in the shape of the request's api version, with a Link header to the next
+// page (RFC 8288). Listings take the cursor from the query, next to their own parameters.
+pub(crate) struct Pager {
+    uri: Uri,
+    version: ApiVersion,
+    cursor: Option<String>,
+    if_none_match: Option<String>,
+    etag: Option<HeaderValue>,
+}
+
+impl<S> FromRequestParts<S> for Pager
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // the full path, nested routers only see the part after their prefix
+        let uri = match parts.extensions.get::<OriginalUri>() {
+            Some(OriginalUri(uri)) => uri.clone(),
+            None => parts.uri.clone(),
+        };
+        let version = parts.extensions.get::<ApiVersion>().copied().unwrap_or(ApiVersion(CURRENT_API_VERSION));
+        let Query(query) = Query::<PageQuery>::try_from_uri(&parts.uri)
+            .map_err(|e| AppError::InvalidInput(format!("invalid query: {}", e)))?;
+        let if_none_match = parts
+            .headers
+            .get(IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        Ok(Self { uri, version, cursor: query.cursor, if_none_match, etag: None })
+    }
+}
+
+impl Pager {
+    // cursors are numbers for now, what they are is up to the listing
+    pub(crate) fn cursor<N: FromStr>(&self) -> Result<Option<N>, AppError> {
+        self.cursor
+            .as_deref()
+            .map(|c| c.parse().map_err(|_| AppError::InvalidInput(format!("invalid cursor: {}", c))))
+            .transpose()
+    }
+
+    // a weak etag (RFC 9110) of the listing, from a fingerprint that changes whenever it does.
+    // The same fingerprint gives another etag for another query or api version. A 304 when the
+    // client has it already, else the etag goes out with the response.
+    pub(crate) fn not_modified(&mut self, fingerprint: &str) -> Option<Response> {
+        let key = format!("{}?{}:{}:{}", self.uri.path(), self.uri.query().unwrap_or_default(), self.version.0, fingerprint);
+        let hash = hmac_sha256::Hash::hash(key.as_bytes());
+        let tag = format!("W/\"{}\"", hex_encode(&hash[..ETAG_BYTES]));
+        let matched = self.if_none_match.as_deref().is_some_and(|v| etag_matches(v, &tag));
+        let value = HeaderValue::from_str(&tag).ok()?;
+        if matched {
+            return Some((StatusCode::NOT_MODIFIED, [(ETAG, value)]).into_response());
+        }
+        self.etag = Some(value);
+        None
+    }
+
+    pub(crate) fn respond<T: Serialize>(&self, page: Paginated<T>) -> Response {
+        let link = page.next_cursor.as_deref().map(|cursor| self.next_link(cursor));
+        let mut res = if self.version.0 >= PAGINATED_SINCE {
+            (StatusCode::OK, Json(&page)).into_response()
+        } else {
+            (StatusCode::OK, Json(&page.items)).into_response()
+        };
+        if let Some(link) = link.and_then(|l| HeaderValue::from_str(&l).ok()) {
+            res.headers_mut().insert(LINK, link);
+        }
+        if let Some(etag) = &self.etag {
+            res.headers_mut().insert(ETAG, etag.clone());
+        }
+        res
+    }
+
+    // the request with the cursor replaced, other parameters are kept
+    fn next_link(&self, cursor: &str) -> String {
+        let mut params: Vec<&str> = self
+            .uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|p| !p.is_empty() && !p.starts_with("cursor="))
+            .collect();
+        let cursor = format!("cursor={}", cursor);
+        params.push(&cursor);
+        format!("<{}?{}>; rel=\"next\"", self.uri.path(), params.join("&"))
+    }
+}
+
+// If-None-Match is * or a list of etags, compared weakly: W/"x" and "x" are the same
+fn etag_matches(if_none_match: &str, tag: &str) -> bool {
+    let opaque = |t: &str| t.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|t| opaque(t) == opaque(tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    async fn pager(uri: &str, version: u32) -> Pager {
+        let (mut parts, _) = Request::get(uri).body(()).expect("request").into_parts();
+        parts.extensions.insert(ApiVersion(version));
+        Pager::from_request_parts(&mut parts, &()).await.expect("pager")
+    }
+
+    #[tokio::test]
+    async fn respond_should_follow_the_api_version() {
+        let page = || Paginated::page(vec![5, 4], 2, |n| n.to_string());
+        let pager2 = pager("/api/chats/1/messages?limit=2&cursor=6", 2).await;
+        assert_eq!(pager2.cursor::<i64>().expect("cursor"), Some(6));
+        let res = pager2.respond(page());
+        assert_eq!(res.headers()[LINK], "</api/chats/1/messages?limit=2&cursor=4>; rel=\"next\"");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.expect("body");
+        assert_eq!(&body[..], br#"{"items":[5,4],"next_cursor":"4"}"#);
+
+        let res = pager("/api/v1/chats/1/messages?limit=2", 1).await.respond(page());
+        assert!(res.headers().contains_key(LINK));
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.expect("body");
+        assert_eq!(&body[..], b"[5,4]");
+        assert!(pager("/api/users?cursor=abc", 2).await.cursor::<u64>().is_err());
+    }
+
+    #[tokio::test]
+    async fn not_modified_should_match_the_etag() {
+        let mut first = pager("/api/chats", 2).await;
+        assert!(first.not_modified("chats:1:1:4").is_none());
+        let res = first.respond(Paginated::all(vec![1]));
+        let etag = res.headers()[ETAG].to_str().expect("etag").to_string();
+        assert!(etag.starts_with("W/\""));
+
+        let request = |tag: &str, uri: &str| Request::get(uri).header(IF_NONE_MATCH, tag).body(()).expect("request");
+        let (mut parts, _) = request(&format!("\"x\", {}", etag), "/api/chats").into_parts();
+        let mut again = Pager::from_request_parts(&mut parts, &()).await.expect("pager");
+        let res = again.not_modified("chats:1:1:4").expect("304");
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[ETAG], etag.as_str());
+        assert!(again.not_modified("chats:1:1:5").is_none());
+
+        // another query is another listing
+        let (mut parts, _) = request(&etag, "/api/chats?limit=1").into_parts();
+        let mut other = Pager::from_request_parts(&mut parts, &()).await.expect("pager");
+        assert!(other.not_modified("chats:1:1:4").is_none());
+        assert!(etag_matches("*", &etag));
+    }
+}
@@ -0,0 +1,471 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::{events::record, AppError, AppEvent, AppState, EventBatch, EventFrame, PollEvents, User};
+
+// events a stream may have queued before it is closed as too slow
+const STREAM_CAPACITY: usize = 64;
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(10);
+// rows of an instance not refreshed for this long are taken as gone
+const PRESENCE_TTL_SECS: i64 = 60;
+// pg_advisory_xact_lock key held while an instance updates presence
+const PRESENCE_LOCK_KEY: i64 = 0x70726573656e6365;
+// longest a poll waits, below the default request timeout
+const MAX_POLL_SECS: u64 = 25;
+// how often a waiting poll looks again without being woken, events published on redis can arrive
+// before the log has them
+const POLL_RECHECK: Duration = Duration::from_secs(2);
+const POLL_BATCH: i64 = 100;
+
+// one user's streams, by id
+type Streams = Vec<(u64, mpsc::Sender<AppEvent>)>;
+
+// event streams connected to this instance, by user. Each instance keeps its own, events made
+// on any of them reach all through the bus, see events::listen.
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers {
+    next_id: AtomicU64,
+    streams: RwLock<HashMap<i64, Streams>>,
+}
+
+// the user's events, until dropped
+#[derive(Debug)]
+pub(crate) struct Subscription {
+    id: u64,
+    user_id: i64,
+    subscribers: Arc<Subscribers>,
+    rx: mpsc::Receiver<AppEvent>,
+}
+
+impl Subscribers {
+    pub(crate) fn subscribe(self: &Arc<Self>, user_id: i64) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(STREAM_CAPACITY);
+        let mut streams = self.streams.write().expect("subscribers poisoned");
+        streams.entry(user_id).or_default().push((id, tx));
+        Subscription {
+            id,
+            user_id,
+            subscribers: self.clone(),
+            rx,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.streams.read().expect("subscribers poisoned").values().map(Vec::len).sum()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.streams.read().expect("subscribers poisoned").is_empty()
+    }
+
+    fn user_ids(&self) -> HashSet<i64> {
+        self.streams.read().expect("subscribers poisoned").keys().copied().collect()
+    }
+
+    // a stream that can't keep up is closed, its client reconnects and reloads what it missed
+    fn send(&self, user_ids: &[i64], event: &AppEvent) {
+        let mut streams = self.streams.write().expect("subscribers poisoned");
+        for user_id in user_ids {
+            let Some(senders) = streams.get_mut(user_id) else {
+                continue;
+            };
+            senders.retain(|(_, tx)| tx.try_send(event.clone()).is_ok());
+            if senders.is_empty() {
+                streams.remove(user_id);
+            }
+        }
+    }
+
+    fn remove(&self, user_id: i64, id: u64) {
+        let mut streams = self.streams.write().expect("subscribers poisoned");
+        if let Some(senders) = streams.get_mut(&user_id) {
+            senders.retain(|(i, _)| *i != id);
+            if senders.is_empty() {
+                streams.remove(&user_id);
+            }
+        }
+    }
+
+    // ends every stream
+    fn clear(&self) {
+        self.streams.write().expect("subscribers poisoned").clear();
+    }
+}
+
+impl Subscription {
+    pub(crate) async fn recv(&mut self) -> Option<AppEvent> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.subscribers.remove(self.user_id, self.id);
+    }
+}
+
+// hands events to the streams of the chat's members connected here, until stop is cancelled.
+// Streams are ended then, so graceful shutdown doesn't wait on them.
+pub(crate) async fn deliver(state: AppState, stop: CancellationToken) {
+    let mut events = state.subscribe_events();
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = stop.cancelled() => break,
+        };
+        match event {
+            Ok(event) => {
+                if let Err(e) = fan_out(&state, &event).await {
+                    warn!("deliver {:?} failed: {}", event, e);
+                }
+            }
+            // streams can't tell what they missed
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("event delivery fell {} events behind, closing streams", n);
+                state.subscribers.clear();
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+    state.subscribers.clear();
+}
+
+async fn fan_out(state: &AppState, event: &AppEvent) -> Result<(), sqlx::Error> {
+    if state.subscribers.is_empty() {
+        return Ok(());
+    }
+    // from the primary, a replica may not have the change yet
+    let user_ids: Vec<i64> = match (event.chat_id(), event.ws_id()) {
+        (Some(chat_id), _) => {
+            let members: Option<Vec<i64>> = sqlx::query_scalar("SELECT members FROM chats WHERE id = $1")
+                .bind(chat_id)
+                .fetch_optional(&state.pool)
+                .await?;
+            members.unwrap_or_default()
+        }
+        (None, Some(ws_id)) => {
+            let connected: Vec<i64> = state.subscribers.user_ids().into_iter().collect();
+            sqlx::query_scalar("SELECT id FROM users WHERE ws_id = $1 AND id = ANY($2)")
+                .bind(ws_id)
+                .bind(&connected)
+                .fetch_all(&state.pool)
+                .await?
+        }
+        (None, None) => return Ok(()),
+    };
+    state.subscribers.send(&user_ids, event);
+    Ok(())
+}
+
+// keeps this instance's rows of event_stream_presence in step with its streams and announces
+// users coming online or going offline, until stop is cancelled. Its users are taken offline
+// then, unless they have streams on another instance.
+pub(crate) async fn track_presence(state: AppState, stop: CancellationToken) {
+    let instance = uuid::Uuid::now_v7().to_string();
+    let mut known = HashSet::new();
+    loop {
+        let connected = state.subscribers.user_ids();
+        match update_presence(&state, &instance, &known, &connected).await {
+            Ok(()) => known = connected,
+            Err(e) => warn!("update presence failed: {}", e),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(PRESENCE_INTERVAL) => {}
+            _ = stop.cancelled() => break,
+        }
+    }
+    if let Err(e) = update_presence(&state, &instance, &known, &HashSet::new()).await {
+        warn!("clear presence failed: {}", e);
+    }
+}
+
+// known are the users this instance had rows for, connected those it has streams for now
+async fn update_presence(
+    state: &AppState,
+    instance: &str,
+    known: &HashSet<i64>,
+    connected: &HashSet<i64>,
+) -> Result<(), AppError> {
+    let mut tx = state.pool.begin().await?;
+    // one instance at a time, so two of them can't both miss that a user went offline
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(PRESENCE_LOCK_KEY)
+        .execute(&mut *tx)
+        .await?;
+    let mut gone: Vec<i64> = sqlx::query_scalar(
+        r#"
+        DELETE FROM event_stream_presence
+        WHERE seen_at < now() - make_interval(secs => $1)
+        RETURNING user_id
+        "#,
+    )
+    .bind(PRESENCE_TTL_SECS as f64)
+    .fetch_all(&mut *tx)
+    .await?;
+    let connected: Vec<i64> = connected.iter().copied().collect();
+    let online: Vec<i64> = sqlx::query_scalar(
+        "SELECT DISTINCT user_id FROM event_stream_presence WHERE user_id = ANY($1)",
+    )
+    .bind(&connected)
+    .fetch_all(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO event_stream_presence (user_id, instance_id)
+        SELECT id, $2 FROM users WHERE id = ANY($1)
+        ON CONFLICT (user_id, instance_id) DO UPDATE SET seen_at = now()
+        "#,
+    )
+    .bind(&connected)
+    .bind(instance)
+    .execute(&mut *tx)
+    .await?;
+    let left: Vec<i64> = known.iter().filter(|id| !connected.contains(id)).copied().collect();
+    sqlx::query("DELETE FROM event_stream_presence WHERE instance_id = $1 AND user_id = ANY($2)")
+        .bind(instance)
+        .bind(&left)
+        .execute(&mut *tx)
+        .await?;
+    gone.extend(left);
+    let offline: Vec<i64> = sqlx::query_scalar(
+        r#"
+        SELECT DISTINCT id FROM unnest($1::bigint[]) AS id
+        WHERE NOT EXISTS (SELECT 1 FROM event_stream_presence p WHERE p.user_id = id)
+        "#,
+    )
+    .bind(&gone)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let came_online: Vec<i64> = connected.into_iter().filter(|id| !online.contains(id)).collect();
+    let changes: Vec<(i64, i64)> = sqlx::query_as("SELECT id, ws_id FROM users WHERE id = ANY($1) ORDER BY id")
+        .bind([came_online.as_slice(), offline.as_slice()].concat())
+        .fetch_all(&mut *tx)
+        .await?;
+    for (user_id, ws_id) in changes {
+        let event = AppEvent::PresenceChanged { ws_id, user_id, online: came_online.contains(&user_id) };
+        record(&mut tx, &event).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// the user's logged events after the cursor, waiting up to the timeout for one when there are
+// none yet. The first poll, without a cursor, answers at once with where the log is.
+pub(crate) async fn poll(state: &AppState, user: &User, input: &PollEvents) -> Result<EventBatch, AppError> {
+    // before reading, so an event published in between still wakes the poll
+    let mut events = state.subscribe_events();
+    let (oldest, newest): (Option<i64>, Option<i64>) = sqlx::query_as("SELECT min(id), max(id) FROM event_log")
+        .fetch_one(&state.pool)
+        .await?;
+    let Some(cursor) = input.cursor else {
+        return Ok(EventBatch { events: vec![], cursor: newest.unwrap_or(0), reset: false });
+    };
+    if cursor < 0 || newest.is_some_and(|newest| cursor > newest) {
+        return Err(AppError::InvalidInput(format!("invalid cursor: {}", cursor)));
+    }
+    let reset = oldest.is_some_and(|oldest| cursor < oldest - 1);
+
+    let timeout = Duration::from_secs(input.timeout.unwrap_or(MAX_POLL_SECS).min(MAX_POLL_SECS));
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut chats = member_chats(state, user).await?;
+    loop {
+        let batch = read_log(state, user, cursor, reset).await?;
+        let now = tokio::time::Instant::now();
+        if reset || batch.cursor != cursor || now >= deadline {
+            return Ok(batch);
+        }
+        // events the user can't see don't send the poll back to the log
+        let recheck = tokio::time::sleep(POLL_RECHECK.min(deadline - now));
+        tokio::pin!(recheck);
+        loop {
+            tokio::select! {
+                ret = events.recv() => match ret {
+                    Ok(event) if concerns(user, &chats, &event) => {
+                        if matches!(event, AppEvent::ChatCreated { .. } | AppEvent::ChatUpdated { .. }) {
+                            chats = member_chats(state, user).await?;
+                        }
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => break,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(batch),
+                },
+                _ = &mut recheck => break,
+            }
+        }
+    }
+}
+
+async fn member_chats(state: &AppState, user: &User) -> Result<HashSet<i64>, AppError> {
+    let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM chats WHERE ws_id = $1 AND members @> ARRAY[$2]::bigint[]")
+        .bind(user.ws_id)
+        .bind(user.id)
+        .fetch_all(&state.pool)
+        .await?;
+    Ok(ids.into_iter().collect())
+}
+
+// whether the event could be in the user's next batch. Chats the user was just added to aren't
+// in `chats` yet, their ChatCreated or ChatUpdated always counts.
+fn concerns(user: &User, chats: &HashSet<i64>, event: &AppEvent) -> bool {
+    if event.ws_id() != Some(user.ws_id) {
+        return false;
+    }
+    match event {
+        AppEvent::ChatCreated { .. } | AppEvent::ChatUpdated { .. } => true,
+        _ => event.chat_id().is_none_or(|id| chats.contains(&id)),
+    }
+}
+
+async fn read_log(state: &AppState, user: &User, cursor: i64, reset: bool) -> Result<EventBatch, AppError> {
+    let rows: Vec<(i64, sqlx::types::Json<serde_json::Value>)> = sqlx::query_as(
+        r#"
+        SELECT id, event
+        FROM event_log
+        WHERE id > $1 AND ws_id = $2
+            AND (chat_id IS NULL OR chat_id IN (SELECT id FROM chats WHERE $3 = ANY(members)))
+        ORDER BY id
+        LIMIT $4
+        "#,
+    )
+    .bind(cursor)
+    .bind(user.ws_id)
+    .bind(user.id)
+    .bind(POLL_BATCH)
+    .fetch_all(&state.pool)
+    .await?;
+    let cursor = rows.last().map_or(cursor, |(id, _)| *id);
+    // logged by an instance of another version, they are skipped as the listener skips them
+    let events = rows
+        .into_iter()
+        .filter_map(|(id, event)| match serde_json::from_value::<EventFrame>(event.0) {
+            Ok(frame) => Some(frame),
+            Err(e) => {
+                warn!("skip logged event {} this version can't read: {}", id, e);
+                None
+            }
+        })
+        .collect();
+    Ok(EventBatch { events, cursor, reset })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppConfig;
+    use anyhow::Result;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn deliver_should_reach_chat_members_only() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let stop = CancellationToken::new();
+        tokio::spawn(deliver(state.clone(), stop.clone()));
+        tokio::task::yield_now().await;
+        let mut member = state.subscribers.subscribe(1);
+        // not in the private channel
+        let mut other = state.subscribers.subscribe(5);
+        assert_eq!(state.subscribers.len(), 2);
+
+        let event = AppEvent::NewMessage { ws_id: 1, chat_id: 2, message_id: 1, sender_id: 2, seq: 1 };
+        state.events.send(event.clone())?;
+        let received = tokio::time::timeout(Duration::from_secs(5), member.recv()).await?;
+        assert_eq!(received, Some(event));
+        assert!(tokio::time::timeout(Duration::from_millis(100), other.recv()).await.is_err());
+
+        drop(other);
+        assert_eq!(state.subscribers.len(), 1);
+        stop.cancel();
+        assert_eq!(member.recv().await, None);
+        Ok(())
+    }
+
+    #[test]
+    fn polls_should_only_wake_for_events_the_user_can_see() {
+        let user = User { ws_id: 1, ..User::new(5, "Daisy", "daisy@acme.org") };
+        let chats = HashSet::from([1]);
+        let message = |ws_id, chat_id| AppEvent::NewMessage { ws_id, chat_id, message_id: 1, sender_id: 2, seq: 1 };
+        assert!(concerns(&user, &chats, &message(1, 1)));
+        assert!(!concerns(&user, &chats, &message(1, 2)));
+        assert!(!concerns(&user, &chats, &message(2, 1)));
+        assert!(concerns(&user, &chats, &AppEvent::ChatUpdated { ws_id: 1, chat_id: 2 }));
+        assert!(concerns(&user, &chats, &AppEvent::PresenceChanged { ws_id: 1, user_id: 2, online: true }));
+        assert!(!concerns(&user, &chats, &AppEvent::PresenceChanged { ws_id: 2, user_id: 2, online: true }));
+    }
+
+    #[tokio::test]
+    async fn presence_should_change_with_the_first_and_last_stream() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let one = HashSet::from([1]);
+        update_presence(&state, "a", &HashSet::new(), &one).await?;
+        // streams on a second instance don't bring the user online again
+        update_presence(&state, "b", &HashSet::new(), &one).await?;
+        update_presence(&state, "a", &one, &HashSet::new()).await?;
+        update_presence(&state, "b", &one, &HashSet::new()).await?;
+
+        let events: Vec<sqlx::types::Json<AppEvent>> = sqlx::query_scalar("SELECT event FROM event_outbox ORDER BY id")
+            .fetch_all(&state.pool)
+            .await?;
+        let events: Vec<AppEvent> = events.into_iter().map(|e| e.0).collect();
+        assert_eq!(
+            events,
+            vec![
+                AppEvent::PresenceChanged { ws_id: 1, user_id: 1, online: true },
+                AppEvent::PresenceChanged { ws_id: 1, user_id: 1, online: false },
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn poll_should_return_logged_events_the_user_can_see() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let member = User::find_by_id(1, &state.pool).await?.expect("user should exist");
+        // not in the private channel
+        let other = User::find_by_id(5, &state.pool).await?.expect("user should exist");
+        let first = poll(&state, &member, &PollEvents::default()).await?;
+        assert_eq!(first.cursor, 0);
+
+        let event = AppEvent::NewMessage { ws_id: 1, chat_id: 2, message_id: 1, sender_id: 2, seq: 1 };
+        sqlx::query("INSERT INTO event_log (ws_id, chat_id, event) VALUES (1, 2, $1)")
+            .bind(sqlx::types::Json(EventFrame::from(event.clone())))
+            .execute(&state.pool)
+            .await?;
+
+        let input = PollEvents { cursor: Some(0), timeout: Some(0) };
+        let batch = poll(&state, &member, &input).await?;
+        assert_eq!(batch.events, vec![EventFrame::from(event)]);
+        assert!(batch.cursor > 0 && !batch.reset);
+        let batch = poll(&state, &other, &input).await?;
+        assert!(batch.events.is_empty());
+
+        let input = PollEvents { cursor: Some(100), timeout: Some(0) };
+        assert!(poll(&state, &member, &input).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn send_should_close_streams_that_fall_behind() {
+        let subscribers = Arc::new(Subscribers::default());
+        let _sub = subscribers.subscribe(1);
+        let event = AppEvent::ChatUpdated { ws_id: 1, chat_id: 1 };
+        for _ in 0..STREAM_CAPACITY {
+            subscribers.send(&[1], &event);
+        }
+        assert_eq!(subscribers.len(), 1);
+        subscribers.send(&[1], &event);
+        assert_eq!(subscribers.len(), 0);
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{config::LiveConfig, AppConfig, AppState, LogLevel, LogLevelHandle};
+
+// editors and kubernetes config maps replace the file in several steps, reload once they're done
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+// applies rate limits, feature flags and the log level from the config file whenever it changes,
+// until stop is cancelled. Other settings still need a restart. A file that fails to load or
+// validate is ignored, the last good settings stay in place.
+pub(crate) async fn watch(state: AppState, path: PathBuf, log_level: LogLevelHandle, stop: CancellationToken) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok_and(|event| !event.kind.is_access()) {
+            let _ = tx.send(());
+        }
+    });
+    // the directory rather than the file, which is swapped for a new one on most updates
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let _watcher = match watcher.and_then(|mut w| w.watch(&dir, RecursiveMode::NonRecursive).map(|_| w)) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("watch {} failed: {}, config changes need a restart", path.display(), e);
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            Some(()) = rx.recv() => {}
+            _ = stop.cancelled() => return,
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(SETTLE_DELAY) => {}
+            _ = stop.cancelled() => return,
+        }
+        while rx.try_recv().is_ok() {}
+        match apply(&state, &path) {
+            Ok(level) => {
+                if let Err(e) = log_level.reload(level) {
+                    warn!("change log level failed: {}", e);
+                }
+                info!("reloaded rate limits, feature flags and log level from {}", path.display());
+            }
+            Err(e) => warn!("reload {} failed: {:#}, keeping the current settings", path.display(), e),
+        }
+    }
+}
+
+fn apply(state: &AppState, path: &Path) -> anyhow::Result<LogLevel> {
+    let live = LiveConfig::from(&AppConfig::load_from(path)?);
+    let level = live.log_level;
+    state.live.store(Arc::new(live));
+    Ok(level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn apply_should_swap_live_settings() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        assert_eq!(state.live.load().rate_limit.requests_per_minute, 600);
+
+        let path = std::env::temp_dir().join(format!("chat-reload-{}.yml", std::process::id()));
+        let yml = std::fs::read_to_string("app.yml")?
+            .replacen("requests_per_minute: 600", "requests_per_minute: 60", 1)
+            .replacen("level: info", "level: debug", 1);
+        std::fs::write(&path, yml)?;
+        assert_eq!(apply(&state, &path)?, LogLevel::Debug);
+        assert_eq!(state.live.load().rate_limit.requests_per_minute, 60);
+
+        // a broken file leaves the settings alone
+        std::fs::write(&path, "rate_limit: [")?;
+        assert!(apply(&state, &path).is_err());
+        assert_eq!(state.live.load().rate_limit.requests_per_minute, 60);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
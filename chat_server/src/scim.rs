@@ -0,0 +1,556 @@
+use axum::{http::{header, HeaderValue, StatusCode}, response::{IntoResponse, Response}, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::{AppError, ProvisionGroup, ProvisionUser, ScimFilter, ScimGroup, ScimUser};
+
+const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+const LIST_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const SERVICE_PROVIDER_CONFIG_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig";
+const SCIM_CONTENT_TYPE: &str = "application/scim+json";
+const DEFAULT_PAGE_SIZE: u64 = 100;
+const MAX_PAGE_SIZE: u64 = 200;
+
+// a SCIM response body, sent as application/scim+json
+#[derive(Debug)]
+pub(crate) struct ScimJson<T>(pub(crate) StatusCode, pub(crate) T);
+
+impl<T: Serialize> IntoResponse for ScimJson<T> {
+    fn into_response(self) -> Response {
+        let mut res = (self.0, Json(self.1)).into_response();
+        res.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(SCIM_CONTENT_TYPE));
+        res
+    }
+}
+
+// errors in the shape identity providers expect, see RFC 7644 section 3.12
+#[derive(Debug, Error)]
+#[error("{detail}")]
+pub(crate) struct ScimError {
+    status: StatusCode,
+    scim_type: Option<&'static str>,
+    detail: String,
+}
+
+impl ScimError {
+    pub(crate) fn new(status: StatusCode, scim_type: Option<&'static str>, detail: impl Into<String>) -> Self {
+        Self { status, scim_type, detail: detail.into() }
+    }
+
+    pub(crate) fn invalid_value(detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, Some("invalidValue"), detail)
+    }
+
+    pub(crate) fn invalid_filter(detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, Some("invalidFilter"), detail)
+    }
+
+    pub(crate) fn not_found(detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, None, detail)
+    }
+}
+
+impl From<AppError> for ScimError {
+    fn from(e: AppError) -> Self {
+        let (status, scim_type) = match &e {
+            AppError::EmailAlreadyExists(_) | AppError::ChatNameTaken(_) => (StatusCode::CONFLICT, Some("uniqueness")),
+            AppError::InvalidInput(_) => (StatusCode::BAD_REQUEST, Some("invalidValue")),
+            AppError::NotFound(_) => (StatusCode::NOT_FOUND, None),
+            AppError::PermissionDenied(_) => (StatusCode::FORBIDDEN, None),
+            AppError::SqlxError(_) => (StatusCode::INTERNAL_SERVER_ERROR, None),
+            _ => (StatusCode::BAD_REQUEST, None),
+        };
+        Self::new(status, scim_type, e.to_string())
+    }
+}
+
+impl IntoResponse for ScimError {
+    fn into_response(self) -> Response {
+        let mut body = json!({
+            "schemas": [ERROR_SCHEMA],
+            "status": self.status.as_u16().to_string(),
+            "detail": self.detail,
+        });
+        if let Some(scim_type) = self.scim_type {
+            body["scimType"] = json!(scim_type);
+        }
+        ScimJson(self.status, body).into_response()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Meta {
+    resource_type: &'static str,
+    created: DateTime<Utc>,
+    last_modified: DateTime<Utc>,
+    location: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Name {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) formatted: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) given_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) family_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct MultiValue {
+    pub(crate) value: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) primary: bool,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) kind: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UserResource {
+    #[serde(default)]
+    pub(crate) schemas: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) external_id: Option<String>,
+    // the email, which is how members sign in
+    pub(crate) user_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) name: Option<Name>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) display_name: Option<String>,
+    #[serde(default)]
+    pub(crate) emails: Vec<MultiValue>,
+    #[serde(default = "default_active")]
+    pub(crate) active: bool,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub(crate) meta: Option<Meta>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GroupResource {
+    #[serde(default)]
+    pub(crate) schemas: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) external_id: Option<String>,
+    pub(crate) display_name: String,
+    // user ids
+    #[serde(default)]
+    pub(crate) members: Vec<MultiValue>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub(crate) meta: Option<Meta>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ListResponse<T> {
+    pub(crate) schemas: Vec<String>,
+    pub(crate) total_results: u64,
+    // 1 based
+    pub(crate) start_index: u64,
+    pub(crate) items_per_page: u64,
+    #[serde(rename = "Resources")]
+    pub(crate) resources: Vec<T>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ListQuery {
+    #[serde(default)]
+    pub(crate) filter: Option<String>,
+    #[serde(default)]
+    pub(crate) start_index: Option<u64>,
+    #[serde(default)]
+    pub(crate) count: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct PatchRequest {
+    #[serde(rename = "Operations")]
+    pub(crate) operations: Vec<PatchOperation>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct PatchOperation {
+    pub(crate) op: String,
+    #[serde(default)]
+    pub(crate) path: Option<String>,
+    #[serde(default)]
+    pub(crate) value: Value,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+impl UserResource {
+    pub(crate) fn new(user: &ScimUser, base_url: &str) -> Self {
+        Self {
+            schemas: vec![USER_SCHEMA.to_string()],
+            id: Some(user.id.to_string()),
+            external_id: user.external_id.clone(),
+            user_name: user.email.clone(),
+            name: Some(Name { formatted: Some(user.fullname.clone()), ..Default::default() }),
+            display_name: Some(user.fullname.clone()),
+            emails: vec![MultiValue { value: user.email.clone(), primary: true, kind: Some("work".to_string()) }],
+            active: user.active,
+            meta: Some(Meta {
+                resource_type: "User",
+                created: user.created_at,
+                last_modified: user.created_at,
+                location: format!("{}/scim/v2/Users/{}", base_url, user.id),
+            }),
+        }
+    }
+
+    // the user name is used as the email unless it isn't one, the display name is preferred
+    // over the name parts
+    pub(crate) fn into_input(self) -> ProvisionUser {
+        let email = if self.user_name.contains('@') {
+            self.user_name
+        } else {
+            primary_value(&self.emails).unwrap_or(self.user_name)
+        };
+        let name = self.name.unwrap_or_default();
+        let fullname = self
+            .display_name
+            .filter(|n| !n.trim().is_empty())
+            .or(name.formatted.filter(|n| !n.trim().is_empty()))
+            .unwrap_or_else(|| join_name(name.given_name.as_deref(), name.family_name.as_deref(), &email));
+        ProvisionUser { email, fullname, external_id: self.external_id, active: self.active }
+    }
+}
+
+impl GroupResource {
+    pub(crate) fn new(group: &ScimGroup, base_url: &str) -> Self {
+        let members = group
+            .members
+            .iter()
+            .map(|id| MultiValue { value: id.to_string(), primary: false, kind: None })
+            .collect();
+        Self {
+            schemas: vec![GROUP_SCHEMA.to_string()],
+            id: Some(group.id.to_string()),
+            external_id: group.external_id.clone(),
+            display_name: group.display_name.clone(),
+            members,
+            meta: Some(Meta {
+                resource_type: "Group",
+                created: group.created_at,
+                last_modified: group.updated_at,
+                location: format!("{}/scim/v2/Groups/{}", base_url, group.id),
+            }),
+        }
+    }
+
+    pub(crate) fn into_input(self) -> Result<ProvisionGroup, ScimError> {
+        let members = self
+            .members
+            .iter()
+            .map(|m| parse_id(&m.value))
+            .collect::<Result<_, _>>()?;
+        Ok(ProvisionGroup { display_name: self.display_name, external_id: self.external_id, members })
+    }
+}
+
+impl<T> ListResponse<T> {
+    pub(crate) fn new(resources: Vec<T>, total_results: u64, start_index: u64) -> Self {
+        Self {
+            schemas: vec![LIST_SCHEMA.to_string()],
+            total_results,
+            start_index,
+            items_per_page: resources.len() as u64,
+            resources,
+        }
+    }
+}
+
+impl ListQuery {
+    // (start index, offset, limit)
+    pub(crate) fn page(&self) -> (u64, u64, u64) {
+        let start_index = self.start_index.unwrap_or(1).max(1);
+        let count = self.count.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+        (start_index, start_index - 1, count)
+    }
+
+    // only `attribute eq "value"` is supported, which is what identity providers use to look
+    // up a user or group before creating it
+    pub(crate) fn filter(&self) -> Result<ScimFilter, ScimError> {
+        let Some(filter) = self.filter.as_deref().map(str::trim).filter(|f| !f.is_empty()) else {
+            return Ok(ScimFilter::default());
+        };
+        let unsupported = || ScimError::invalid_filter(format!("unsupported filter: {}", filter));
+        let mut parts = filter.splitn(3, ' ');
+        let (Some(attr), Some(op), Some(value)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(unsupported());
+        };
+        let value = value.trim();
+        if !op.eq_ignore_ascii_case("eq") || value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+            return Err(unsupported());
+        }
+        let value = Some(value[1..value.len() - 1].replace("\\\"", "\""));
+        let mut ret = ScimFilter::default();
+        match attr.to_ascii_lowercase().as_str() {
+            "username" | "emails" | "emails.value" => ret.email = value,
+            "externalid" => ret.external_id = value,
+            "displayname" => ret.display_name = value,
+            _ => return Err(unsupported()),
+        }
+        Ok(ret)
+    }
+}
+
+impl PatchRequest {
+    // attributes that are not stored are ignored
+    pub(crate) fn apply_to_user(&self, input: &mut ProvisionUser) -> Result<(), ScimError> {
+        for op in &self.operations {
+            match (op.op.to_ascii_lowercase().as_str(), op.path.as_deref()) {
+                ("add" | "replace", Some(path)) => set_user_attr(input, path, &op.value)?,
+                ("add" | "replace", None) => {
+                    let Value::Object(attrs) = &op.value else {
+                        return Err(ScimError::invalid_value("patch value must be an object"));
+                    };
+                    for (path, value) in attrs {
+                        set_user_attr(input, path, value)?;
+                    }
+                }
+                ("remove", Some(path)) if path.eq_ignore_ascii_case("externalId") => input.external_id = None,
+                ("remove", _) => return Err(cannot_remove()),
+                (op, _) => return Err(invalid_op(op)),
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apply_to_group(&self, input: &mut ProvisionGroup) -> Result<(), ScimError> {
+        for op in &self.operations {
+            let name = op.op.to_ascii_lowercase();
+            let path = op.path.as_deref().map(str::to_ascii_lowercase);
+            match (name.as_str(), path.as_deref()) {
+                ("add" | "replace", None) => {
+                    let Value::Object(attrs) = &op.value else {
+                        return Err(ScimError::invalid_value("patch value must be an object"));
+                    };
+                    for (path, value) in attrs {
+                        set_group_attr(input, &name, &path.to_ascii_lowercase(), value)?;
+                    }
+                }
+                ("add" | "replace", Some(path)) => set_group_attr(input, &name, path, &op.value)?,
+                ("remove", Some("members")) if op.value.is_null() => input.members.clear(),
+                ("remove", Some("members")) => {
+                    let removed = member_ids(&op.value)?;
+                    input.members.retain(|id| !removed.contains(id));
+                }
+                // members[value eq "42"]
+                ("remove", Some(path)) if path.starts_with("members[") && path.ends_with(']') => {
+                    let id = member_filter_id(&path["members[".len()..path.len() - 1])?;
+                    input.members.retain(|m| *m != id);
+                }
+                ("remove", Some("externalid")) => input.external_id = None,
+                ("remove", _) => return Err(cannot_remove()),
+                (op, _) => return Err(invalid_op(op)),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn service_provider_config(base_url: &str) -> Value {
+    json!({
+        "schemas": [SERVICE_PROVIDER_CONFIG_SCHEMA],
+        "documentationUri": format!("{}/scim/v2", base_url),
+        "patch": { "supported": true },
+        "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+        "filter": { "supported": true, "maxResults": MAX_PAGE_SIZE },
+        "changePassword": { "supported": false },
+        "sort": { "supported": false },
+        "etag": { "supported": false },
+        "authenticationSchemes": [{
+            "type": "oauthbearertoken",
+            "name": "Bearer token",
+            "description": "The workspace's SCIM token",
+            "primary": true,
+        }],
+    })
+}
+
+pub(crate) fn parse_id(id: &str) -> Result<i64, ScimError> {
+    id.parse().map_err(|_| ScimError::not_found(format!("no resource with id {}", id)))
+}
+
+fn set_user_attr(input: &mut ProvisionUser, path: &str, value: &Value) -> Result<(), ScimError> {
+    match path.to_ascii_lowercase().as_str() {
+        "active" => input.active = as_bool(value)?,
+        "username" => input.email = as_string(value)?,
+        "displayname" | "name.formatted" => input.fullname = as_string(value)?,
+        "externalid" => input.external_id = Some(as_string(value)?),
+        "emails" => {
+            let emails: Vec<MultiValue> = serde_json::from_value(value.clone())
+                .map_err(|e| ScimError::invalid_value(format!("invalid emails: {}", e)))?;
+            if let Some(email) = primary_value(&emails) {
+                input.email = email;
+            }
+        }
+        // e.g. emails[type eq "work"].value
+        p if p.starts_with("emails[") && p.ends_with("].value") => input.email = as_string(value)?,
+        "name" => {
+            let name: Name = serde_json::from_value(value.clone())
+                .map_err(|e| ScimError::invalid_value(format!("invalid name: {}", e)))?;
+            if let Some(formatted) = name.formatted.filter(|n| !n.trim().is_empty()) {
+                input.fullname = formatted;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn set_group_attr(input: &mut ProvisionGroup, op: &str, path: &str, value: &Value) -> Result<(), ScimError> {
+    match path {
+        "displayname" => input.display_name = as_string(value)?,
+        "externalid" => input.external_id = Some(as_string(value)?),
+        "members" if op == "add" => {
+            for id in member_ids(value)? {
+                if !input.members.contains(&id) {
+                    input.members.push(id);
+                }
+            }
+        }
+        "members" => input.members = member_ids(value)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn member_ids(value: &Value) -> Result<Vec<i64>, ScimError> {
+    let members: Vec<MultiValue> = serde_json::from_value(value.clone())
+        .map_err(|e| ScimError::invalid_value(format!("invalid members: {}", e)))?;
+    members.iter().map(|m| parse_id(&m.value)).collect()
+}
+
+// the user id in `value eq "42"`
+fn member_filter_id(raw: &str) -> Result<i64, ScimError> {
+    let mut parts = raw.splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(attr), Some(op), Some(value)) if attr == "value" && op == "eq" => {
+            parse_id(value.trim().trim_matches('"'))
+        }
+        _ => Err(ScimError::new(StatusCode::BAD_REQUEST, Some("invalidPath"), format!("unsupported path: {}", raw))),
+    }
+}
+
+fn primary_value(values: &[MultiValue]) -> Option<String> {
+    values.iter().find(|v| v.primary).or(values.first()).map(|v| v.value.clone())
+}
+
+fn join_name(given: Option<&str>, family: Option<&str>, email: &str) -> String {
+    let name = [given, family].into_iter().flatten().collect::<Vec<_>>().join(" ");
+    match name.trim() {
+        "" => email.split('@').next().unwrap_or(email).to_string(),
+        name => name.to_string(),
+    }
+}
+
+// some identity providers send booleans as "True" and "False"
+fn as_bool(value: &Value) -> Result<bool, ScimError> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        Value::String(s) if s.eq_ignore_ascii_case("true") => Ok(true),
+        Value::String(s) if s.eq_ignore_ascii_case("false") => Ok(false),
+        _ => Err(ScimError::invalid_value(format!("expected a boolean, got {}", value))),
+    }
+}
+
+fn as_string(value: &Value) -> Result<String, ScimError> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| ScimError::invalid_value(format!("expected a string, got {}", value)))
+}
+
+fn cannot_remove() -> ScimError {
+    ScimError::new(StatusCode::BAD_REQUEST, Some("mutability"), "cannot remove this attribute")
+}
+
+fn invalid_op(op: &str) -> ScimError {
+    ScimError::new(StatusCode::BAD_REQUEST, Some("invalidSyntax"), format!("unsupported patch op: {}", op))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(filter: &str) -> ListQuery {
+        ListQuery { filter: Some(filter.to_string()), ..Default::default() }
+    }
+
+    #[test]
+    fn filter_should_parse_eq() {
+        let filter = query(r#"userName eq "Alice@acme.org""#).filter().expect("valid filter");
+        assert_eq!(filter.email.as_deref(), Some("Alice@acme.org"));
+        let filter = query(r#"displayName eq "Sales Team""#).filter().expect("valid filter");
+        assert_eq!(filter.display_name.as_deref(), Some("Sales Team"));
+        assert!(query("").filter().expect("empty filter").email.is_none());
+        for bad in [r#"userName co "alice""#, "userName eq alice", r#"title eq "cto""#, "userName"] {
+            assert!(query(bad).filter().is_err(), "{}", bad);
+        }
+        assert_eq!(ListQuery { start_index: Some(0), count: Some(1000), ..Default::default() }.page(), (1, 0, 200));
+    }
+
+    #[test]
+    fn patch_should_update_user() {
+        let mut input = ProvisionUser {
+            email: "alice@acme.org".to_string(),
+            fullname: "Alice".to_string(),
+            external_id: None,
+            active: true,
+        };
+        let patch: PatchRequest = serde_json::from_value(json!({
+            "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+            "Operations": [
+                { "op": "Replace", "path": "active", "value": "False" },
+                { "op": "replace", "value": { "displayName": "Alice Chen", "externalId": "a1" } },
+                { "op": "replace", "path": "emails[type eq \"work\"].value", "value": "alice@acme.com" },
+            ],
+        }))
+        .expect("valid patch");
+        patch.apply_to_user(&mut input).expect("patch applies");
+        assert!(!input.active);
+        assert_eq!(input.fullname, "Alice Chen");
+        assert_eq!(input.external_id.as_deref(), Some("a1"));
+        assert_eq!(input.email, "alice@acme.com");
+    }
+
+    #[test]
+    fn patch_should_update_group_members() {
+        let mut input = ProvisionGroup { display_name: "sales".to_string(), external_id: None, members: vec![1, 2] };
+        let patch: PatchRequest = serde_json::from_value(json!({
+            "Operations": [
+                { "op": "add", "path": "members", "value": [{ "value": "3" }, { "value": "2" }] },
+                { "op": "remove", "path": "members[value eq \"1\"]" },
+                { "op": "replace", "path": "displayName", "value": "Sales" },
+            ],
+        }))
+        .expect("valid patch");
+        patch.apply_to_group(&mut input).expect("patch applies");
+        assert_eq!(input.members, vec![2, 3]);
+        assert_eq!(input.display_name, "Sales");
+
+        let patch: PatchRequest = serde_json::from_value(json!({
+            "Operations": [{ "op": "remove", "path": "members" }],
+        }))
+        .expect("valid patch");
+        patch.apply_to_group(&mut input).expect("patch applies");
+        assert!(input.members.is_empty());
+    }
+}
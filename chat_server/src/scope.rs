@@ -0,0 +1,172 @@
+use std::marker::PhantomData;
+
+use axum::{extract::FromRequestParts, http::request::Parts};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{AppError, AppState, User, WorkspaceRole};
+
+// a permission a handler can require with RequireScope
+pub trait Scope {
+    const NAME: &'static str;
+}
+
+macro_rules! scopes {
+    ($($ty:ident => $name:literal),* $(,)?) => {
+        $(
+            pub struct $ty;
+
+            impl Scope for $ty {
+                const NAME: &'static str = $name;
+            }
+        )*
+    };
+}
+
+scopes! {
+    ChatRead => "chat:read",
+    ChatWrite => "chat:write",
+    WorkspaceAdmin => "workspace:admin",
+}
+
+const MEMBER_SCOPES: &[&str] = &[ChatRead::NAME, ChatWrite::NAME];
+
+// the role and scopes an access token was issued with, carried in its claims next to the user.
+// Fixed for the token's lifetime, so checks that must see role changes right away still ask
+// the database.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Grant {
+    #[serde(default)]
+    pub role: WorkspaceRole,
+    // tokens issued before scopes existed get what members get
+    #[serde(default = "member_scopes")]
+    pub scopes: Vec<String>,
+    // the third party app the user let act for them, see oauth_provider
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<i64>,
+}
+
+fn member_scopes() -> Vec<String> {
+    MEMBER_SCOPES.iter().map(|s| s.to_string()).collect()
+}
+
+impl Default for Grant {
+    fn default() -> Self {
+        Self {
+            role: WorkspaceRole::Member,
+            scopes: member_scopes(),
+            app_id: None,
+        }
+    }
+}
+
+impl Grant {
+    pub async fn for_user(user: &User, pool: &PgPool) -> Result<Self, AppError> {
+        if !user.is_workspace_admin(pool).await? {
+            return Ok(Self::default());
+        }
+        let mut scopes = member_scopes();
+        scopes.push(WorkspaceAdmin::NAME.to_string());
+        Ok(Self {
+            role: WorkspaceRole::Admin,
+            scopes,
+            app_id: None,
+        })
+    }
+
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+// rejects the request unless verify_token found the scope in the access token, e.g.
+// `_: RequireScope<ChatWrite>`
+pub struct RequireScope<S: Scope>(PhantomData<S>);
+
+impl<S, St> FromRequestParts<St> for RequireScope<S>
+where
+    S: Scope,
+    St: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &St) -> Result<Self, Self::Rejection> {
+        match parts.extensions.get::<Grant>() {
+            Some(grant) if grant.allows(S::NAME) => Ok(Self(PhantomData)),
+            _ => Err(AppError::PermissionDenied(format!("token lacks the {} scope", S::NAME))),
+        }
+    }
+}
+
+// the signed in user, for handlers only workspace admins may use. The token has to carry the
+// admin scope and the role is checked again in the database, so a demoted admin is turned away
+// before their token runs out.
+pub struct AdminUser(pub User);
+
+impl FromRequestParts<AppState> for AdminUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        RequireScope::<WorkspaceAdmin>::from_request_parts(parts, state).await?;
+        let Some(user) = parts.extensions.get::<User>().cloned() else {
+            return Err(AppError::PermissionDenied("not signed in".to_string()));
+        };
+        if !user.is_workspace_admin(&state.pool).await? {
+            return Err(AppError::PermissionDenied("only workspace admins can do this".to_string()));
+        }
+        Ok(Self(user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::Request;
+
+    use super::*;
+    use crate::{AppConfig, Workspace};
+
+    #[tokio::test]
+    async fn require_scope_should_check_grant() -> Result<()> {
+        let (mut parts, _) = Request::new(()).into_parts();
+        assert!(RequireScope::<ChatRead>::from_request_parts(&mut parts, &()).await.is_err());
+        parts.extensions.insert(Grant::default());
+        assert!(RequireScope::<ChatWrite>::from_request_parts(&mut parts, &()).await.is_ok());
+        let ret = RequireScope::<WorkspaceAdmin>::from_request_parts(&mut parts, &()).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_user_should_need_scope_and_role() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let admin = User::find_by_email("tchen@acme.org", &state.pool).await?.expect("user should exist");
+        let ws = Workspace::find_by_id(1, &state.pool).await?.expect("workspace should exist");
+        ws.update_owner(admin.id as _, &state.pool).await?;
+        let member = User::find_by_email("alice@acme.org", &state.pool).await?.expect("user should exist");
+
+        let (mut parts, _) = Request::new(()).into_parts();
+        parts.extensions.insert(admin.clone());
+        parts.extensions.insert(Grant::default());
+        let ret = AdminUser::from_request_parts(&mut parts, &state).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        parts.extensions.insert(Grant::for_user(&admin, &state.pool).await?);
+        let AdminUser(user) = AdminUser::from_request_parts(&mut parts, &state).await?;
+        assert_eq!(user.id, admin.id);
+
+        // a token issued with the admin scope is turned away once the role is gone
+        parts.extensions.insert(member);
+        let ret = AdminUser::from_request_parts(&mut parts, &state).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn grant_should_default_to_member_scopes() -> Result<()> {
+        let grant: Grant = serde_json::from_str("{}")?;
+        assert_eq!(grant, Grant::default());
+        assert!(grant.allows("chat:write"));
+        assert!(!grant.allows("workspace:admin"));
+        Ok(())
+    }
+}
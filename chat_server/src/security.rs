@@ -0,0 +1,58 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{AppState, AuthEvent, AuthEventKind, ClientInfo, CreateAuthEvent, User, Webhook, WebhookEvent};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityEventKind {
+    NewDeviceSignin,
+    RepeatedSigninFailures,
+    AdminGranted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SecurityEvent {
+    pub kind: SecurityEventKind,
+    pub ws_id: i64,
+    pub user_id: i64,
+    pub details: Value,
+    pub occurred_at: DateTime<Utc>,
+}
+
+impl SecurityEvent {
+    pub fn new(kind: SecurityEventKind, ws_id: i64, user_id: i64, details: Value) -> Self {
+        Self {
+            kind,
+            ws_id,
+            user_id,
+            details,
+            occurred_at: Utc::now(),
+        }
+    }
+}
+
+// like audit records, failing to record the event does not fail the request
+pub(crate) async fn record(state: &AppState, user: &User, kind: AuthEventKind, client: &ClientInfo, details: Value) {
+    let input = CreateAuthEvent::new(user, kind, client, details);
+    if let Err(e) = AuthEvent::create(&input, &state.pool).await {
+        warn!("record auth event {:?} failed: {}", input, e);
+    }
+}
+
+// queued for every webhook of the workspace that wants security events, see webhooks::deliver.
+// Like audit records, failures are only logged: the request that triggered the event must not fail.
+pub(crate) async fn notify(state: &AppState, event: SecurityEvent) {
+    if let Err(e) = enqueue(state, &event).await {
+        warn!("queue security event {:?} failed: {}", event, e);
+    }
+}
+
+async fn enqueue(state: &AppState, event: &SecurityEvent) -> anyhow::Result<()> {
+    let payload = serde_json::to_value(event)?;
+    let mut conn = state.pool.acquire().await?;
+    Webhook::enqueue(&mut conn, event.ws_id, None, WebhookEvent::Security, &payload).await?;
+    Ok(())
+}
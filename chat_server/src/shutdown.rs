@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::AppState;
+
+// resolves on ctrl-c, or SIGTERM as sent by kubernetes and docker before killing the process
+pub(crate) async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("listen for ctrl-c failed: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                warn!("listen for SIGTERM failed: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => info!("received ctrl-c, shutting down"),
+        _ = terminate => info!("received SIGTERM, shutting down"),
+    }
+}
+
+// waits for background jobs, e.g. webhook deliveries, then closes the database pools. Jobs still
+// running after the timeout are dropped.
+pub(crate) async fn drain(state: &AppState, grace: Duration) {
+    state.tasks.close();
+    if !state.tasks.is_empty() {
+        info!("waiting for {} background jobs", state.tasks.len());
+    }
+    if timeout(grace, state.tasks.wait()).await.is_err() {
+        warn!("{} background jobs did not finish in time", state.tasks.len());
+    }
+    state.db.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppConfig;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn drain_should_wait_for_background_jobs() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        state.tasks.spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let _ = tx.send(());
+        });
+        drain(&state, Duration::from_secs(5)).await;
+        assert!(state.tasks.is_empty());
+        assert!(rx.await.is_ok());
+        assert!(state.pool.is_closed());
+        Ok(())
+    }
+}
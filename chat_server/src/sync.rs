@@ -0,0 +1,181 @@
+use std::collections::HashSet;
+
+use sqlx::{Postgres, Transaction};
+
+use crate::{AppError, AppState, Message, User};
+
+pub use chat_core::{ChangeBatch, ChatMembers, ReadState, SyncChanges};
+
+// changes read per sync, messages, chats and read states together
+const SYNC_BATCH: i64 = 500;
+
+// gives changes committed since the last pass their seq, after every seq given out before.
+// Called by the outbox relay under its lock, so one instance at a time.
+pub(crate) async fn stamp(tx: &mut Transaction<'_, Postgres>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE sync_changes s
+        SET seq = p.seq
+        FROM (
+            SELECT id, (SELECT coalesce(max(seq), 0) FROM sync_changes) + row_number() OVER (ORDER BY id) AS seq
+            FROM sync_changes
+            WHERE seq IS NULL
+        ) p
+        WHERE s.id = p.id
+        "#,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+// what changed for the user after since, as it is now. The first sync, without since, answers
+// at once with the newest seq.
+pub(crate) async fn changes(state: &AppState, user: &User, input: &SyncChanges) -> Result<ChangeBatch<Message>, AppError> {
+    let (oldest, newest): (Option<i64>, Option<i64>) = sqlx::query_as("SELECT min(seq), max(seq) FROM sync_changes")
+        .fetch_one(&state.pool)
+        .await?;
+    let newest = newest.unwrap_or(0);
+    let Some(since) = input.since else {
+        return Ok(empty(newest));
+    };
+    if since < 0 || since > newest {
+        return Err(AppError::InvalidInput(format!("invalid since: {}", since)));
+    }
+    if oldest.is_some_and(|oldest| since < oldest - 1) {
+        return Ok(ChangeBatch { reset: true, ..empty(newest) });
+    }
+
+    // messages of chats the user is in now, those of a chat they just joined are theirs to load
+    let rows: Vec<(i64, i64, String, Option<i64>)> = sqlx::query_as(
+        r#"
+        SELECT seq, chat_id, kind, message_id
+        FROM sync_changes
+        WHERE seq > $1 AND seq <= $2
+            AND CASE kind
+                WHEN 'read' THEN user_id = $3
+                WHEN 'members' THEN $3 = ANY(members)
+                ELSE chat_id IN (SELECT id FROM chats WHERE members @> ARRAY[$3::bigint])
+            END
+        ORDER BY seq
+        LIMIT $4
+        "#,
+    )
+    .bind(since)
+    .bind(newest)
+    .bind(user.id)
+    .bind(SYNC_BATCH)
+    .fetch_all(&state.pool)
+    .await?;
+    let has_more = rows.len() as i64 == SYNC_BATCH;
+    let seq = match rows.last() {
+        Some((seq, ..)) if has_more => *seq,
+        _ => newest,
+    };
+
+    let (mut message_ids, mut chat_ids, mut read_chat_ids) = (vec![], vec![], vec![]);
+    for (_, chat_id, kind, message_id) in rows {
+        match kind.as_str() {
+            "message" => message_ids.extend(message_id),
+            "members" => chat_ids.push(chat_id),
+            "read" => read_chat_ids.push(chat_id),
+            _ => {}
+        }
+    }
+    for ids in [&mut message_ids, &mut chat_ids, &mut read_chat_ids] {
+        ids.sort_unstable();
+        ids.dedup();
+    }
+
+    let mut messages = Message::fetch_by_ids(&message_ids, &state.pool).await?;
+    messages.sort_unstable_by_key(|m| (m.chat_id, m.seq));
+    let found: HashSet<i64> = messages.iter().map(|m| m.id).collect();
+    let deleted_messages = message_ids.into_iter().filter(|id| !found.contains(id)).collect();
+
+    let chats: Vec<ChatMembers> = sqlx::query_as(
+        "SELECT id AS chat_id, members FROM chats WHERE id = ANY($1) AND members @> ARRAY[$2::bigint] ORDER BY id",
+    )
+    .bind(&chat_ids)
+    .bind(user.id)
+    .fetch_all(&state.pool)
+    .await?;
+    let found: HashSet<i64> = chats.iter().map(|c| c.chat_id).collect();
+    let left_chats = chat_ids.into_iter().filter(|id| !found.contains(id)).collect();
+
+    let read_states = sqlx::query_as(
+        "SELECT chat_id, last_read_seq FROM chat_member_settings WHERE user_id = $1 AND chat_id = ANY($2) ORDER BY chat_id",
+    )
+    .bind(user.id)
+    .bind(&read_chat_ids)
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(ChangeBatch {
+        messages,
+        deleted_messages,
+        chats,
+        left_chats,
+        read_states,
+        seq,
+        has_more,
+        reset: false,
+    })
+}
+
+fn empty(seq: i64) -> ChangeBatch<Message> {
+    ChangeBatch {
+        messages: vec![],
+        deleted_messages: vec![],
+        chats: vec![],
+        left_chats: vec![],
+        read_states: vec![],
+        seq,
+        has_more: false,
+        reset: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppConfig, Chat, CreateMessage, MarkChatRead};
+    use anyhow::Result;
+
+    // as the outbox relay would
+    async fn stamp_pending(state: &AppState) -> Result<()> {
+        let mut tx = state.pool.begin().await?;
+        stamp(&mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn changes_should_follow_the_users_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        // not in the private channel yet
+        let user = User::find_by_id(5, &state.pool).await?.expect("user should exist");
+        stamp_pending(&state).await?;
+        let since = changes(&state, &user, &SyncChanges::default()).await?.seq;
+
+        let chat = Chat::get_by_id(2, &state.pool).await?.expect("chat should exist");
+        let chat = chat.add_member(5, 1, &state.pool).await?;
+        let message = Message::create(&CreateMessage::new("hello"), 2, 1, &state.pool).await?;
+        chat.mark_read(5, &MarkChatRead { seq: message.seq }, &state.pool).await?;
+        stamp_pending(&state).await?;
+        let batch = changes(&state, &user, &SyncChanges { since: Some(since) }).await?;
+        assert!(batch.messages.contains(&message));
+        assert_eq!(batch.chats, vec![ChatMembers { chat_id: 2, members: vec![1, 2, 3, 5] }]);
+        assert_eq!(batch.read_states, vec![ReadState { chat_id: 2, last_read_seq: message.seq }]);
+        assert!(batch.seq > since && !batch.has_more);
+
+        chat.remove_member(5, 1, &state.pool).await?;
+        stamp_pending(&state).await?;
+        let batch = changes(&state, &user, &SyncChanges { since: Some(batch.seq) }).await?;
+        assert_eq!((batch.chats.len(), batch.left_chats), (0, vec![2]));
+        assert!(batch.messages.is_empty());
+
+        let ret = changes(&state, &user, &SyncChanges { since: Some(batch.seq + 1) }).await;
+        assert!(ret.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use axum::{extract::ConnectInfo, http::Request, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto::Builder, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    rustls::{
+        self,
+        crypto::CryptoProvider,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    },
+    TlsAcceptor,
+};
+use tokio_util::sync::CancellationToken;
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+use crate::config::TlsConfig;
+
+// clients that connect but never finish the handshake are dropped after this
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// e.g. out of file descriptors, retrying right away would only spin
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+// how often the certificate files are checked for changes
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// a failed reload is retried after this, doubling up to the check interval
+const CERT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// the certificate being served, replaced when the files change so that renewed certificates are
+// picked up without a restart
+#[derive(Debug)]
+pub(crate) struct Certificate {
+    config: TlsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Certificate {
+    pub(crate) fn load(config: &TlsConfig) -> anyhow::Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            config: config.clone(),
+            current: RwLock::new(Arc::new(read_certified_key(config)?)),
+        }))
+    }
+
+    // the certificate in use stays when the files can't be read or don't match
+    fn reload(&self) -> anyhow::Result<()> {
+        let key = read_certified_key(&self.config)?;
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(key);
+        Ok(())
+    }
+
+    fn modified(&self) -> anyhow::Result<(SystemTime, SystemTime)> {
+        let modified = |path: &str| {
+            fs::metadata(path)
+                .and_then(|m| m.modified())
+                .with_context(|| format!("read {} failed", path))
+        };
+        Ok((modified(&self.config.cert_path)?, modified(&self.config.key_path)?))
+    }
+}
+
+impl ResolvesServerCert for Certificate {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap_or_else(|e| e.into_inner()).clone())
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn read_certified_key(config: &TlsConfig) -> anyhow::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(&config.cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("load tls certificates from {} failed", config.cert_path))?;
+    if certs.is_empty() {
+        anyhow::bail!("no tls certificate in {}", config.cert_path);
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key_path)
+        .with_context(|| format!("load tls key from {} failed", config.key_path))?;
+    CertifiedKey::from_der(certs, key, &provider()).context("invalid tls certificate or key")
+}
+
+// http/2 is offered first through alpn, clients without it get http/1.1
+pub(crate) fn acceptor(certificate: Arc<Certificate>) -> anyhow::Result<TlsAcceptor> {
+    let mut server = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(certificate);
+    server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+// reloads the certificate when its files change, until stop is cancelled. Files that can't be
+// read, e.g. halfway through a renewal, are retried with a growing delay.
+pub(crate) async fn watch(certificate: Arc<Certificate>, stop: CancellationToken) {
+    let mut loaded = certificate.modified().ok();
+    let mut delay = CERT_CHECK_INTERVAL;
+    let mut retry = None;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stop.cancelled() => return,
+        }
+        let ret = certificate.modified().and_then(|modified| {
+            if loaded != Some(modified) {
+                certificate.reload()?;
+                info!("reloaded tls certificate from {}", certificate.config.cert_path);
+                loaded = Some(modified);
+            }
+            Ok(())
+        });
+        retry = match ret {
+            Ok(()) => None,
+            Err(e) => {
+                let delay = next_retry_delay(retry);
+                warn!("reload tls certificate failed: {:#}, retrying in {}s", e, delay.as_secs());
+                Some(delay)
+            }
+        };
+        delay = retry.unwrap_or(CERT_CHECK_INTERVAL);
+    }
+}
+
+fn next_retry_delay(previous: Option<Duration>) -> Duration {
+    match previous {
+        Some(delay) => (delay * 2).min(CERT_CHECK_INTERVAL),
+        None => CERT_RETRY_DELAY,
+    }
+}
+
+// accepts until stop is cancelled, then waits for connections to finish the requests they
+// have in flight
+pub(crate) async fn serve(listener: TcpListener, app: Router, acceptor: TlsAcceptor, stop: CancellationToken) {
+    let graceful = GracefulShutdown::new();
+    loop {
+        let (tcp, addr) = tokio::select! {
+            ret = listener.accept() => match ret {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("accept connection failed: {}", e);
+                    tokio::select! {
+                        _ = tokio::time::sleep(ACCEPT_ERROR_DELAY) => continue,
+                        _ = stop.cancelled() => break,
+                    }
+                }
+            },
+            _ = stop.cancelled() => break,
+        };
+        let acceptor = acceptor.clone();
+        // the same as into_make_service_with_connect_info does for plain connections
+        let service = app.clone().map_request(move |mut req: Request<Incoming>| {
+            req.extensions_mut().insert(ConnectInfo::<SocketAddr>(addr));
+            req
+        });
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => return debug!("tls handshake with {} failed: {}", addr, e),
+                Err(_) => return debug!("tls handshake with {} timed out", addr),
+            };
+            let conn = Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), TowerToHyperService::new(service))
+                .into_owned();
+            if let Err(e) = watcher.watch(conn).await {
+                debug!("connection from {} closed: {}", addr, e);
+            }
+        });
+    }
+    graceful.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT1: &str = include_str!("../fixtures/tls1.crt");
+    const KEY1: &str = include_str!("../fixtures/tls1.key");
+    const CERT2: &str = include_str!("../fixtures/tls2.crt");
+    const KEY2: &str = include_str!("../fixtures/tls2.key");
+
+    fn served(certificate: &Certificate) -> Vec<u8> {
+        let current = certificate.current.read().expect("lock should not be poisoned");
+        current.cert[0].to_vec()
+    }
+
+    fn der(pem: &str) -> Vec<u8> {
+        CertificateDer::from_pem_slice(pem.as_bytes()).expect("fixture should parse").to_vec()
+    }
+
+    #[test]
+    fn certificate_should_reload_and_keep_the_last_good_one() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("chat-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let config = TlsConfig {
+            cert_path: dir.join("tls.crt").to_string_lossy().into_owned(),
+            key_path: dir.join("tls.key").to_string_lossy().into_owned(),
+        };
+        fs::write(&config.cert_path, CERT1)?;
+        fs::write(&config.key_path, KEY1)?;
+        let certificate = Certificate::load(&config)?;
+        acceptor(certificate.clone())?;
+        assert_eq!(served(&certificate), der(CERT1));
+
+        fs::write(&config.cert_path, CERT2)?;
+        fs::write(&config.key_path, KEY2)?;
+        certificate.reload()?;
+        assert_eq!(served(&certificate), der(CERT2));
+
+        // halfway through a renewal the key doesn't match yet, or a file is missing
+        fs::write(&config.cert_path, CERT1)?;
+        assert!(certificate.reload().is_err());
+        fs::remove_file(&config.key_path)?;
+        assert!(certificate.reload().is_err());
+        assert!(certificate.modified().is_err());
+        assert_eq!(served(&certificate), der(CERT2));
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn failed_reloads_should_back_off() {
+        let mut delay = next_retry_delay(None);
+        assert_eq!(delay, CERT_RETRY_DELAY);
+        for _ in 0..10 {
+            let next = next_retry_delay(Some(delay));
+            assert!(next >= delay && next <= CERT_CHECK_INTERVAL);
+            delay = next;
+        }
+        assert_eq!(delay, CERT_CHECK_INTERVAL);
+    }
+}
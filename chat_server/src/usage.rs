@@ -0,0 +1,288 @@
+use std::{fmt::Write, time::Duration};
+
+use chrono::{Days, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection, PgPool};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{utils::csv_record, AppError, AppState};
+
+// the rollup only writes days that are over, checking often enough picks them up soon after
+const ROLLUP_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// how far back the first rollup goes
+const BACKFILL_DAYS: u64 = 90;
+// pg_try_advisory_xact_lock key held while an instance rolls up
+const ROLLUP_LOCK_KEY: i64 = 0x7573616765;
+const DEFAULT_EXPORT_DAYS: u64 = 30;
+const MAX_EXPORT_DAYS: i64 = 366;
+
+const CSV_COLUMNS: &[&str] = &["day", "ws_id", "active_users", "messages", "storage_bytes"];
+
+// name, help and value of the OpenMetrics export
+type Gauge = (&'static str, &'static str, fn(&UsageDay) -> i64);
+
+const GAUGES: &[Gauge] = &[
+    ("chat_usage_active_users", "Members who signed in or sent a message that day.", |d| d.active_users),
+    ("chat_usage_messages", "Messages sent that day.", |d| d.messages),
+    ("chat_usage_storage_bytes", "Bytes of message content stored at the end of the day.", |d| d.storage_bytes),
+];
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageFormat {
+    #[default]
+    Csv,
+    OpenMetrics,
+}
+
+// the query of /admin/usage, days in UTC and both included. The last 30 days up to yesterday
+// when left out.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExportUsage {
+    #[serde(default)]
+    pub from: Option<NaiveDate>,
+    #[serde(default)]
+    pub to: Option<NaiveDate>,
+    #[serde(default)]
+    pub format: UsageFormat,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct UsageDay {
+    pub ws_id: i64,
+    pub day: NaiveDate,
+    pub active_users: i64,
+    pub messages: i64,
+    pub storage_bytes: i64,
+}
+
+impl ExportUsage {
+    fn window(&self) -> Result<(NaiveDate, NaiveDate), AppError> {
+        let yesterday = Utc::now().date_naive() - Days::new(1);
+        let to = self.to.unwrap_or(yesterday);
+        let from = self.from.unwrap_or(to - Days::new(DEFAULT_EXPORT_DAYS - 1));
+        if from > to {
+            return Err(AppError::InvalidInput("from must not be after to".to_string()));
+        }
+        if (to - from).num_days() >= MAX_EXPORT_DAYS {
+            return Err(AppError::InvalidInput(format!("at most {} days can be exported at once", MAX_EXPORT_DAYS)));
+        }
+        Ok((from, to))
+    }
+}
+
+// rolls up the days that ended since the last run, at startup and then every hour until stop
+// is cancelled. One instance at a time does it, the others skip the run.
+pub(crate) async fn roll_up(state: AppState, stop: CancellationToken) {
+    loop {
+        if let Err(e) = roll_up_pending(&state.pool).await {
+            warn!("usage rollup failed: {}", e);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(ROLLUP_CHECK_INTERVAL) => {}
+            _ = stop.cancelled() => return,
+        }
+    }
+}
+
+async fn roll_up_pending(pool: &PgPool) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock($1)")
+        .bind(ROLLUP_LOCK_KEY)
+        .fetch_one(&mut *tx)
+        .await?;
+    if !locked {
+        return Ok(());
+    }
+    let today = Utc::now().date_naive();
+    let last: Option<NaiveDate> = sqlx::query_scalar("SELECT max(day) FROM usage_daily").fetch_one(&mut *tx).await?;
+    let mut day = match last {
+        Some(last) => last + Days::new(1),
+        None => today - Days::new(BACKFILL_DAYS),
+    };
+    let mut rolled = 0;
+    while day < today {
+        roll_up_day(day, &mut tx).await?;
+        day = day + Days::new(1);
+        rolled += 1;
+    }
+    tx.commit().await?;
+    if rolled > 0 {
+        info!("rolled up usage of {} days", rolled);
+    }
+    Ok(())
+}
+
+// the usage of every workspace that existed on the day. Storage is the day before's plus what
+// changed that day, see usage_storage_deltas; all the changes up to the day when there is no
+// row for the day before.
+pub(crate) async fn roll_up_day(day: NaiveDate, conn: &mut PgConnection) -> Result<(), AppError> {
+    let start = day.and_time(NaiveTime::MIN).and_utc();
+    let end = start + chrono::Duration::days(1);
+    sqlx::query(
+        r#"
+        INSERT INTO usage_daily (ws_id, day, active_users, messages, storage_bytes)
+        SELECT
+            w.id,
+            $1,
+            (SELECT COUNT(DISTINCT a.user_id) FROM (
+                SELECT m.sender_id AS user_id
+                FROM messages m JOIN chats c ON c.id = m.chat_id
+                WHERE c.ws_id = w.id AND m.created_at >= $2 AND m.created_at < $3
+                UNION
+                SELECT l.actor_id FROM audit_logs l
+                WHERE l.ws_id = w.id AND l.action = 'signin' AND l.created_at >= $2 AND l.created_at < $3
+            ) a),
+            (SELECT COUNT(*) FROM messages m JOIN chats c ON c.id = m.chat_id
+                WHERE c.ws_id = w.id AND m.created_at >= $2 AND m.created_at < $3),
+            (COALESCE(
+                (SELECT u.storage_bytes FROM usage_daily u WHERE u.ws_id = w.id AND u.day = $1::date - 1),
+                (SELECT SUM(d.bytes) FROM usage_storage_deltas d WHERE d.ws_id = w.id AND d.day < $1),
+                0
+            ) + COALESCE(
+                (SELECT d.bytes FROM usage_storage_deltas d WHERE d.ws_id = w.id AND d.day = $1),
+                0
+            ))::bigint
+        FROM workspaces w
+        WHERE w.created_at < $3 AND (w.deleted_at IS NULL OR w.deleted_at >= $2)
+        ON CONFLICT (ws_id, day) DO UPDATE
+        SET active_users = EXCLUDED.active_users, messages = EXCLUDED.messages,
+            storage_bytes = EXCLUDED.storage_bytes, created_at = NOW()
+        "#,
+    )
+    .bind(day)
+    .bind(start)
+    .bind(end)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub(crate) async fn fetch(ws_id: u64, input: &ExportUsage, pool: &PgPool) -> Result<Vec<UsageDay>, AppError> {
+    let (from, to) = input.window()?;
+    let days = sqlx::query_as(
+        r#"
+        SELECT ws_id, day, active_users, messages, storage_bytes
+        FROM usage_daily
+        WHERE ws_id = $1 AND day >= $2 AND day <= $3
+        ORDER BY day
+        "#,
+    )
+    .bind(ws_id as i64)
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?;
+    Ok(days)
+}
+
+pub(crate) fn to_csv(days: &[UsageDay]) -> String {
+    let mut out = csv_record(CSV_COLUMNS);
+    for d in days {
+        let fields = [
+            d.day.to_string(),
+            d.ws_id.to_string(),
+            d.active_users.to_string(),
+            d.messages.to_string(),
+            d.storage_bytes.to_string(),
+        ];
+        out.push_str(&csv_record(&fields));
+    }
+    out
+}
+
+// one sample per day, timestamped with the start of the day
+pub(crate) fn to_openmetrics(days: &[UsageDay]) -> String {
+    let mut out = String::new();
+    for (name, help, value) in GAUGES {
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        if name.ends_with("_bytes") {
+            let _ = writeln!(out, "# UNIT {} bytes", name);
+        }
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        for d in days {
+            let at = d.day.and_time(NaiveTime::MIN).and_utc().timestamp();
+            let _ = writeln!(out, "{}{{ws_id=\"{}\"}} {} {}", name, d.ws_id, value(d), at);
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::get_test_pool, CreateMessage, Message};
+    use anyhow::Result;
+
+    fn day(s: &str) -> NaiveDate {
+        s.parse().expect("date should parse")
+    }
+
+    #[test]
+    fn usage_should_render_as_csv_and_openmetrics() {
+        let days = vec![UsageDay { ws_id: 1, day: day("2025-11-01"), active_users: 3, messages: 10, storage_bytes: 512 }];
+        assert_eq!(
+            to_csv(&days),
+            "day,ws_id,active_users,messages,storage_bytes\r\n2025-11-01,1,3,10,512\r\n"
+        );
+        let metrics = to_openmetrics(&days);
+        assert!(metrics.contains("# UNIT chat_usage_storage_bytes bytes\n"));
+        assert!(metrics.contains("chat_usage_messages{ws_id=\"1\"} 10 1761955200\n"));
+        assert!(metrics.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn export_window_should_be_checked() {
+        let input = ExportUsage { from: Some(day("2025-11-02")), to: Some(day("2025-11-01")), ..Default::default() };
+        assert!(input.window().is_err());
+        let input = ExportUsage { from: Some(day("2024-01-01")), to: Some(day("2025-11-01")), ..Default::default() };
+        assert!(input.window().is_err());
+        let (from, to) = ExportUsage::default().window().expect("default window should be valid");
+        assert_eq!((to - from).num_days(), DEFAULT_EXPORT_DAYS as i64 - 1);
+    }
+
+    #[tokio::test]
+    async fn rollup_should_count_the_days_usage() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let message = Message::create(&CreateMessage::new("hello"), 1, 1, &pool).await?;
+        let today = message.created_at.date_naive();
+        let mut conn = pool.acquire().await?;
+        roll_up_day(today, &mut conn).await?;
+        // rolling up again replaces the rows
+        roll_up_day(today, &mut conn).await?;
+        let input = ExportUsage { from: Some(today), to: Some(today), ..Default::default() };
+        let days = fetch(1, &input, &pool).await?;
+        assert_eq!(days.len(), 1);
+        assert!(days[0].messages >= 1 && days[0].active_users >= 1);
+        let stored: i64 = sqlx::query_scalar(
+            "SELECT SUM(octet_length(m.content))::bigint FROM messages m JOIN chats c ON c.id = m.chat_id WHERE c.ws_id = 1",
+        )
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(days[0].storage_bytes, stored);
+
+        // deleting books the bytes as removed on the day it happens
+        let booked = |day: NaiveDate| {
+            sqlx::query_scalar::<_, i64>("SELECT bytes FROM usage_storage_deltas WHERE ws_id = 1 AND day = $1")
+                .bind(day)
+                .fetch_one(&pool)
+        };
+        let before = booked(today).await?;
+        sqlx::query("DELETE FROM messages WHERE id = $1").bind(message.id).execute(&pool).await?;
+        assert_eq!(booked(today).await?, before - "hello".len() as i64);
+
+        // the next day starts from this one and adds what changed
+        let tomorrow = today + Days::new(1);
+        sqlx::query("INSERT INTO usage_storage_deltas (ws_id, day, bytes) VALUES (1, $1, -5)")
+            .bind(tomorrow)
+            .execute(&pool)
+            .await?;
+        roll_up_day(tomorrow, &mut conn).await?;
+        let input = ExportUsage { from: Some(tomorrow), to: Some(tomorrow), ..Default::default() };
+        let days = fetch(1, &input, &pool).await?;
+        assert_eq!(days[0].storage_bytes, stored - "hello".len() as i64);
+        Ok(())
+    }
+}
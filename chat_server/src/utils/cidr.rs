@@ -0,0 +1,95 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+// an address range like 10.0.0.0/8 or 2001:db8::/32, a bare address is a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    // ipv4 clients reaching a dual stack listener show up as ::ffff:a.b.c.d
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => mask(u32::from(ip).into(), self.prefix, 32) == u32::from(net).into(),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => mask(u128::from(ip), self.prefix, 128) == u128::from(net),
+            _ => false,
+        }
+    }
+}
+
+fn mask(bits: u128, prefix: u8, width: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        _ => bits & (u128::MAX << (width - prefix)) & (u128::MAX >> (128 - width)),
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    // host bits are cleared, so 10.1.2.3/8 is read as 10.0.0.0/8
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid CIDR range: {}", s);
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|_| invalid())?.to_canonical();
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) if !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()) => p.parse().map_err(|_| invalid())?,
+            Some(_) => return Err(invalid()),
+            None => width,
+        };
+        if prefix > width {
+            return Err(invalid());
+        }
+        let addr = match addr {
+            IpAddr::V4(ip) => IpAddr::V4((mask(u32::from(ip).into(), prefix, 32) as u32).into()),
+            IpAddr::V6(ip) => IpAddr::V6(mask(u128::from(ip), prefix, 128).into()),
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().expect("valid ip")
+    }
+
+    #[test]
+    fn cidr_should_parse_and_match() {
+        let net: Cidr = "10.1.2.3/8".parse().expect("valid cidr");
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert!(net.contains(&ip("10.255.0.1")));
+        assert!(net.contains(&ip("::ffff:10.0.0.1")));
+        assert!(!net.contains(&ip("11.0.0.1")));
+
+        let host: Cidr = "192.168.1.7".parse().expect("valid cidr");
+        assert_eq!(host.to_string(), "192.168.1.7/32");
+        assert!(host.contains(&ip("192.168.1.7")));
+        assert!(!host.contains(&ip("192.168.1.8")));
+
+        let v6: Cidr = "2001:db8::1/32".parse().expect("valid cidr");
+        assert_eq!(v6.to_string(), "2001:db8::/32");
+        assert!(v6.contains(&ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(&ip("10.0.0.1")));
+
+        let any: Cidr = "0.0.0.0/0".parse().expect("valid cidr");
+        assert!(any.contains(&ip("8.8.8.8")));
+
+        for bad in ["10.0.0.0/33", "10.0.0.0/", "10.0.0/8", "example.com", "10.0.0.0/+8"] {
+            assert!(bad.parse::<Cidr>().is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use axum::http::{header::COOKIE, HeaderMap, HeaderValue};
+
+use crate::{
+    config::CookieSessionConfig,
+    utils::{constant_time_eq, generate_token},
+};
+
+// the refresh token expires with its session, checked when it's used. The cookie only has to
+// outlive the longest session policy.
+const REFRESH_COOKIE_MAX_AGE: u64 = 60 * 60 * 24 * 365;
+
+// the token cookie and a fresh csrf cookie, both expiring together with the access token, and the
+// refresh token cookie
+pub fn session_cookies(config: &CookieSessionConfig, token: &str, refresh_token: &str, max_age: u64) -> Vec<HeaderValue> {
+    let csrf = generate_token(16);
+    [
+        set_cookie(config, &config.cookie_name, token, max_age, true),
+        set_cookie(config, &config.csrf_cookie_name, &csrf, max_age, false),
+        set_cookie(config, &config.refresh_cookie_name, refresh_token, REFRESH_COOKIE_MAX_AGE, true),
+    ]
+    .into_iter()
+    .filter_map(|v| v.parse().ok())
+    .collect()
+}
+
+pub fn clear_session_cookies(config: &CookieSessionConfig) -> Vec<HeaderValue> {
+    [
+        set_cookie(config, &config.cookie_name, "", 0, true),
+        set_cookie(config, &config.csrf_cookie_name, "", 0, false),
+        set_cookie(config, &config.refresh_cookie_name, "", 0, true),
+    ]
+    .into_iter()
+    .filter_map(|v| v.parse().ok())
+    .collect()
+}
+
+// the value of the first cookie called `name` across all cookie headers
+pub fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+// double submit, the header must repeat the csrf cookie which other origins can't read
+pub fn verify_csrf(config: &CookieSessionConfig, headers: &HeaderMap) -> bool {
+    let cookie = cookie_value(headers, &config.csrf_cookie_name);
+    let header = headers.get(config.csrf_header.as_str()).and_then(|v| v.to_str().ok());
+    match (cookie, header) {
+        (Some(cookie), Some(header)) if !cookie.is_empty() => constant_time_eq(cookie.as_bytes(), header.as_bytes()),
+        _ => false,
+    }
+}
+
+fn set_cookie(config: &CookieSessionConfig, name: &str, value: &str, max_age: u64, http_only: bool) -> String {
+    let mut cookie = format!("{}={}; Path=/; Max-Age={}; SameSite={}", name, value, max_age, config.same_site);
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    if config.secure {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn session_cookies_should_pass_csrf_check() -> Result<()> {
+        let config = CookieSessionConfig::default();
+        let cookies = session_cookies(&config, "t0ken", "r3fresh", 900);
+        assert_eq!(cookies[0], "chat_token=t0ken; Path=/; Max-Age=900; SameSite=Lax; HttpOnly; Secure");
+        assert!(cookies[2].to_str()?.starts_with("chat_refresh=r3fresh; "));
+        assert!(cookies[2].to_str()?.ends_with("; HttpOnly; Secure"));
+        let csrf = cookies[1].to_str()?.split(';').next().unwrap_or_default();
+        let (_, csrf_value) = csrf.split_once('=').unwrap_or_default();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, format!("chat_token=t0ken; {}", csrf).parse()?);
+        assert_eq!(cookie_value(&headers, "chat_token"), Some("t0ken"));
+        assert!(!verify_csrf(&config, &headers));
+        headers.insert("x-csrf-token", "forged".parse()?);
+        assert!(!verify_csrf(&config, &headers));
+        headers.insert("x-csrf-token", csrf_value.parse()?);
+        assert!(verify_csrf(&config, &headers));
+        Ok(())
+    }
+}
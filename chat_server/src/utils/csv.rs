@@ -0,0 +1,36 @@
+// one CSV record, with fields quoted as needed. Fields that spreadsheets would evaluate as
+// formulas are prefixed with a quote, exports end up in Excel more often than not.
+pub fn csv_record<S: AsRef<str>>(fields: &[S]) -> String {
+    let mut line = fields
+        .iter()
+        .map(|f| csv_field(f.as_ref()))
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line
+}
+
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", field)
+    } else {
+        field.to_string()
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_record_should_escape() {
+        assert_eq!(csv_record(&["a", "b"]), "a,b\r\n");
+        assert_eq!(csv_record(&["Chen, Tyr", "say \"hi\""]), "\"Chen, Tyr\",\"say \"\"hi\"\"\"\r\n");
+        assert_eq!(csv_record(&["=1+1"]), "'=1+1\r\n");
+    }
+}
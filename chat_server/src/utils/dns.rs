@@ -0,0 +1,20 @@
+use hickory_resolver::{error::ResolveErrorKind, TokioAsyncResolver};
+
+use crate::AppError;
+
+// the TXT records at the name, none when it doesn't exist. Strings split across a record are
+// joined back.
+pub async fn txt_records(name: &str) -> Result<Vec<String>, AppError> {
+    let failed = |e: &dyn std::fmt::Display| AppError::UpdateWorkspaceError(format!("DNS lookup of {} failed: {}", name, e));
+    let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(|e| failed(&e))?;
+    let lookup = match resolver.txt_lookup(name).await {
+        Ok(lookup) => lookup,
+        Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => return Ok(vec![]),
+        Err(e) => return Err(failed(&e)),
+    };
+    let records = lookup
+        .iter()
+        .map(|txt| txt.txt_data().iter().map(|s| String::from_utf8_lossy(s)).collect())
+        .collect();
+    Ok(records)
+}
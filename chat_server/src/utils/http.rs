@@ -0,0 +1,368 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Client, Method, RequestBuilder, Response, Url,
+};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{config::OutboundConfig, AppError};
+
+const MAX_URL_LEN: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("{0} failed repeatedly, requests to it are paused")]
+    CircuitOpen(String),
+    #[error("{0} doesn't use https")]
+    NotHttps(String),
+    #[error("{0} is not a public address")]
+    PrivateAddress(String),
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+}
+
+impl HttpError {
+    // with its causes, a refused address or a failed tls handshake only shows up in those
+    pub fn detail(&self) -> String {
+        let mut detail = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            detail.push_str(": ");
+            detail.push_str(&e.to_string());
+            source = e.source();
+        }
+        detail
+    }
+}
+
+// shared client for calls to third parties. Each host gets its own timeout and retries, and each
+// endpoint a circuit breaker, so a slow or failing one can't tie up handlers and background
+// jobs, nor pause the other endpoints on its host.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    // for urls members gave, see send_public
+    public: reqwest::Client,
+    config: Arc<OutboundConfig>,
+    // by endpoint, see endpoint
+    breakers: Arc<Mutex<HashMap<String, Breaker>>>,
+}
+
+#[derive(Debug, Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl HttpClient {
+    pub fn new(config: &OutboundConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+            .build()
+            .expect("build http client");
+        let mut public = reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+            .redirect(redirect::Policy::none());
+        if !config.allow_private_targets {
+            public = public.dns_resolver(Arc::new(PublicResolver));
+        }
+        Self {
+            client,
+            public: public.build().expect("build http client"),
+            config: Arc::new(config.clone()),
+            breakers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn get(&self, url: impl reqwest::IntoUrl) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn head(&self, url: impl reqwest::IntoUrl) -> RequestBuilder {
+        self.client.head(url)
+    }
+
+    pub fn post(&self, url: impl reqwest::IntoUrl) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub fn put(&self, url: impl reqwest::IntoUrl) -> RequestBuilder {
+        self.client.put(url)
+    }
+
+    // a request's own timeout wins over the configured one. Responses with a 5xx status
+    // count as failures, but are still returned once retries run out.
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, HttpError> {
+        self.execute(&self.client, request).await
+    }
+
+    // for urls members gave, e.g. webhooks and slash commands. The address the host resolves
+    // to is checked on every call, the record may have changed since the url was checked.
+    // Redirects aren't followed.
+    pub async fn send_public(&self, request: RequestBuilder) -> Result<Response, HttpError> {
+        let request = request.build()?;
+        if !self.config.allow_private_targets {
+            let url = request.url();
+            if url.scheme() != "https" {
+                return Err(HttpError::NotHttps(url.to_string()));
+            }
+            let host = url.host_str().unwrap_or_default();
+            let ip = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>();
+            if ip.is_ok_and(|ip| !is_public_ip(ip)) {
+                return Err(HttpError::PrivateAddress(host.to_string()));
+            }
+        }
+        self.execute(&self.public, RequestBuilder::from_parts(self.public.clone(), request)).await
+    }
+
+    async fn execute(&self, client: &Client, request: RequestBuilder) -> Result<Response, HttpError> {
+        let mut request = request.build()?;
+        let host = request.url().host_str().unwrap_or_default().to_string();
+        let endpoint = endpoint(request.url());
+        let policy = self.config.hosts.get(&host);
+        if request.timeout().is_none() {
+            let ms = policy.and_then(|p| p.timeout_ms).unwrap_or(self.config.timeout_ms);
+            *request.timeout_mut() = Some(Duration::from_millis(ms));
+        }
+        let retries = policy.and_then(|p| p.retries).unwrap_or(self.config.retries);
+        let idempotent = matches!(*request.method(), Method::GET | Method::HEAD);
+        let mut backoff = Duration::from_millis(self.config.retry_backoff_ms);
+        let mut attempt = 0;
+        loop {
+            if !self.allow(&endpoint, Instant::now()) {
+                return Err(HttpError::CircuitOpen(endpoint));
+            }
+            // bodies that are streamed can't be sent twice
+            let Some(next) = request.try_clone() else {
+                let ret = client.execute(request).await;
+                self.record(&endpoint, succeeded(&ret), Instant::now());
+                return Ok(ret?);
+            };
+            let ret = client.execute(next).await;
+            let ok = succeeded(&ret);
+            self.record(&endpoint, ok, Instant::now());
+            let retry = match &ret {
+                _ if ok || attempt >= retries => false,
+                Err(e) if e.is_connect() => true,
+                _ => idempotent,
+            };
+            if !retry {
+                return Ok(ret?);
+            }
+            attempt += 1;
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    fn allow(&self, endpoint: &str, now: Instant) -> bool {
+        let breakers = self.breakers.lock().expect("breakers poisoned");
+        breakers.get(endpoint).is_none_or(|b| b.allow(now))
+    }
+
+    fn record(&self, endpoint: &str, ok: bool, now: Instant) {
+        let mut breakers = self.breakers.lock().expect("breakers poisoned");
+        if ok {
+            breakers.remove(endpoint);
+            return;
+        }
+        let breaker = breakers.entry(endpoint.to_string()).or_default();
+        if breaker.fail(self.config.failure_threshold, Duration::from_secs(self.config.open_secs), now) {
+            warn!("{} failed {} times in a row, pausing requests for {}s", endpoint, breaker.failures, self.config.open_secs);
+        }
+    }
+}
+
+// the url up to its path, what a breaker is kept for. The query and credentials are left out,
+// they often hold secrets and the breaker shows up in logs.
+fn endpoint(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url.to_string()
+}
+
+impl Breaker {
+    fn allow(&self, now: Instant) -> bool {
+        self.open_until.is_none_or(|until| now >= until)
+    }
+
+    // once open, the first request after the pause decides: a success closes the breaker, a
+    // failure opens it again. Returns whether it just opened.
+    fn fail(&mut self, threshold: u32, open_for: Duration, now: Instant) -> bool {
+        self.failures += 1;
+        if self.failures < threshold.max(1) || !self.allow(now) {
+            return false;
+        }
+        self.open_until = Some(now + open_for);
+        true
+    }
+}
+
+// resolves like the system does, leaving out addresses that aren't public
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(HttpError::PrivateAddress(host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// urls members give the server to call, e.g. webhooks and slash commands. Hosts on this
+// machine or its network are refused, so the server can't be made to call its neighbours.
+pub(crate) fn check_public_url(url: &str, what: &str) -> Result<(), AppError> {
+    let invalid = || AppError::InvalidInput(format!("invalid {} url: {}", what, url));
+    if url.len() > MAX_URL_LEN {
+        return Err(invalid());
+    }
+    let parsed = Url::parse(url).map_err(|_| invalid())?;
+    if parsed.scheme() != "https" {
+        return Err(AppError::InvalidInput(format!("{} url must use https: {}", what, url)));
+    }
+    // ip hosts come normalized, e.g. http://2130706433/ is 127.0.0.1
+    let host = parsed.host_str().unwrap_or_default();
+    let public = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => is_public_ip(ip),
+        Err(_) => {
+            let domain = host.trim_end_matches('.').to_ascii_lowercase();
+            !domain.is_empty() && domain != "localhost" && !domain.ends_with(".localhost")
+        }
+    };
+    if !public {
+        return Err(AppError::InvalidInput(format!("{} url must not point at a private address: {}", what, url)));
+    }
+    Ok(())
+}
+
+// not loopback, private, link local, or otherwise not reachable on the internet
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            // 100.64.0.0/10, shared by carrier grade nat
+            let shared = a == 100 && (b & 0xc0) == 64;
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || shared)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public_ip(v4.into()),
+            None => !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()),
+        },
+    }
+}
+
+fn succeeded(ret: &Result<Response, reqwest::Error>) -> bool {
+    ret.as_ref().is_ok_and(|res| !res.status().is_server_error())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breaker_should_open_after_threshold_and_probe_after_pause() {
+        let now = Instant::now();
+        let open_for = Duration::from_secs(30);
+        let mut breaker = Breaker::default();
+        assert!(!breaker.fail(3, open_for, now));
+        assert!(!breaker.fail(3, open_for, now));
+        assert!(breaker.allow(now));
+        assert!(breaker.fail(3, open_for, now));
+        assert!(!breaker.allow(now + Duration::from_secs(29)));
+
+        let later = now + open_for;
+        assert!(breaker.allow(later));
+        // the probe failed
+        assert!(breaker.fail(3, open_for, later));
+        assert!(!breaker.allow(later + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn breakers_should_be_kept_per_endpoint() -> anyhow::Result<()> {
+        let config = OutboundConfig { failure_threshold: 1, ..Default::default() };
+        let http = HttpClient::new(&config);
+        let failing = endpoint(&Url::parse("https://user:pw@hooks.acme.org/a/b?token=s3cret#x")?);
+        assert_eq!(failing, "https://hooks.acme.org/a/b");
+        let now = Instant::now();
+        http.record(&failing, false, now);
+        assert!(!http.allow(&failing, now));
+        // other webhooks on the same host still go out
+        assert!(http.allow(&endpoint(&Url::parse("https://hooks.acme.org/c")?), now));
+        Ok(())
+    }
+
+    #[test]
+    fn check_public_url_should_refuse_private_hosts() {
+        assert!(check_public_url("https://hooks.acme.org/deploy", "command").is_ok());
+        assert!(check_public_url("https://8.8.8.8/hook", "command").is_ok());
+        for url in [
+            "ftp://hooks.acme.org/",
+            "http://hooks.acme.org/",
+            "https://localhost:8080/",
+            "https://api.localhost/",
+            "https://127.0.0.1/",
+            "https://2130706433/",
+            "https://10.1.2.3/",
+            "https://192.168.0.1/",
+            "https://169.254.169.254/latest/meta-data",
+            "https://[::1]/",
+            "https://[fd00::1]/",
+            "https://[::ffff:127.0.0.1]/",
+            "not a url",
+        ] {
+            assert!(check_public_url(url, "command").is_err(), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn send_public_should_refuse_private_addresses() {
+        let http = HttpClient::new(&OutboundConfig::default());
+        let ret = http.send_public(http.post("http://hooks.acme.org/hook")).await;
+        assert!(matches!(ret, Err(HttpError::NotHttps(_))));
+        let ret = http.send_public(http.post("https://127.0.0.1:9/hook")).await;
+        assert!(matches!(ret, Err(HttpError::PrivateAddress(_))));
+        // the name resolves to loopback
+        let ret = http.send_public(http.post("https://localhost:9/hook")).await;
+        assert!(matches!(ret, Err(HttpError::Request(_))));
+    }
+
+    #[tokio::test]
+    async fn send_should_fail_fast_while_open() {
+        let config = OutboundConfig { retries: 0, failure_threshold: 1, ..Default::default() };
+        let http = HttpClient::new(&config);
+        // nothing listens on port 9 of this machine
+        let ret = http.send(http.get("http://127.0.0.1:9/")).await;
+        assert!(matches!(ret, Err(HttpError::Request(_))));
+        let ret = http.send(http.get("http://127.0.0.1:9/")).await;
+        assert!(matches!(ret, Err(HttpError::CircuitOpen(endpoint)) if endpoint == "http://127.0.0.1:9/"));
+    }
+}
@@ -1,42 +1,160 @@
 use std::collections::HashSet;
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
 use jwt_simple::{claims::Claims, common::VerificationOptions};
 use jwt_simple::prelude::*;
 
-use crate::{AppError, User};
+use crate::{config::AuthConfig, scope::Grant, AppError, User};
 
-const JWT_DURATION: u64 = 60 * 60 * 24 * 7;
+// access tokens are short lived, clients renew them with a refresh token
+pub const JWT_DURATION: u64 = 60 * 15;
 const JWT_ISS: &str = "chat_server";
 const JWT_AUD: &str = "chat_web";
 
 pub struct EncodingKey(Ed25519KeyPair);
-#[allow(unused)]
-pub struct DecodingKey(Ed25519PublicKey);
+// the key currently signing comes first, then the ones tokens still in use may have been signed with
+pub struct DecodingKey(Vec<Ed25519PublicKey>);
+
+// the public keys in JWK format, for other services to verify access tokens with
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub x: String,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub use_: String,
+}
+
+// the custom claims of an access token, the user including their workspace id, and their grant
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AccessClaims {
+    #[serde(flatten)]
+    user: User,
+    #[serde(flatten)]
+    grant: Grant,
+}
+
+// identifies a single access token so that it can be revoked before it expires
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenId {
+    pub jti: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl TokenId {
+    // ids are generated up front so that the session can record the access token it issues
+    pub fn generate() -> Self {
+        Self {
+            jti: uuid::Uuid::now_v7().to_string(),
+            expires_at: Utc::now() + chrono::Duration::seconds(JWT_DURATION as i64),
+        }
+    }
+}
 
 impl EncodingKey {
-    pub fn load(pem: &str) -> Result<Self, AppError> {
-        Ok(Self(Ed25519KeyPair::from_pem(pem)?))
+    // tokens carry the key id, derived from the public key when not given
+    pub fn load(pem: &str, kid: Option<&str>) -> Result<Self, AppError> {
+        let key = Ed25519KeyPair::from_pem(pem)?;
+        let kid = match kid {
+            Some(kid) => kid.to_string(),
+            None => key.public_key().create_key_id().to_string(),
+        };
+        Ok(Self(key.with_key_id(&kid)))
     }
+    // outside of tests every access token belongs to a session, see sign_with_id
+    #[cfg(test)]
     pub fn sign(&self, user: impl Into<User>) -> Result<String, AppError> {
-        let claims = Claims::with_custom_claims(user.into(), Duration::from_secs(JWT_DURATION));
-        let claims =claims.with_issuer(JWT_ISS).with_audience(JWT_AUD);
+        self.sign_with_id(user, &Grant::default(), &TokenId::generate())
+    }
+    pub fn sign_with_id(&self, user: impl Into<User>, grant: &Grant, id: &TokenId) -> Result<String, AppError> {
+        let custom = AccessClaims { user: user.into(), grant: grant.clone() };
+        let claims = Claims::with_custom_claims(custom, Duration::from_secs(JWT_DURATION));
+        let claims = claims
+            .with_issuer(JWT_ISS)
+            .with_audience(JWT_AUD)
+            .with_jwt_id(&id.jti);
         Ok(self.0.sign(claims)?)
     }
 }
 
 impl DecodingKey {
-    pub fn load(pem: &str) -> Result<Self, AppError> {
-        Ok(Self(Ed25519PublicKey::from_pem(pem)?))
+    pub fn load(pem: &str, kid: Option<&str>) -> Result<Self, AppError> {
+        Ok(Self(vec![load_public_key(pem, kid)?]))
+    }
+
+    // to rotate, move the current public key to previous_keys and configure the new key pair.
+    // The old key can be dropped once the access tokens it signed have expired.
+    pub fn from_config(auth: &AuthConfig) -> Result<Self, AppError> {
+        let mut key = Self::load(&auth.pk, auth.kid.as_deref())?;
+        for previous in &auth.previous_keys {
+            key.0.push(load_public_key(&previous.pk, previous.kid.as_deref())?);
+        }
+        Ok(key)
+    }
+
+    pub fn verify(&self, token: &str) -> Result<(User, TokenId), AppError> {
+        let (user, _, id) = self.verify_claims(token)?;
+        Ok((user, id))
     }
-    pub fn verify(&self, token: &str) -> Result<User, AppError> {
+
+    pub fn verify_claims(&self, token: &str) -> Result<(User, Grant, TokenId), AppError> {
         let opts = VerificationOptions {
             allowed_issuers: Some(HashSet::from_strings(&[JWT_ISS])),
             allowed_audiences: Some(HashSet::from_strings(&[JWT_AUD])),
             ..Default::default()
         };
-        let claims = self.0.verify_token::<User>(token, Some(opts))?;
-        Ok(claims.custom)
+        // tokens from before key ids were used are checked against the current key
+        let metadata = Token::decode_metadata(token)?;
+        let key = match metadata.key_id() {
+            Some(kid) => self.0.iter().find(|k| k.key_id().as_deref() == Some(kid)),
+            None => self.0.first(),
+        };
+        let Some(key) = key else {
+            return Err(jwt_simple::Error::msg("token is signed with an unknown key").into());
+        };
+        let claims = key.verify_token::<AccessClaims>(token, Some(opts))?;
+        // tokens without an id could never be revoked
+        let (Some(jti), Some(expires_at)) = (claims.jwt_id, claims.expires_at) else {
+            return Err(jwt_simple::Error::msg("token has no id or expiry").into());
+        };
+        let expires_at = DateTime::from_timestamp(expires_at.as_secs() as i64, 0).unwrap_or(DateTime::<Utc>::MAX_UTC);
+        Ok((claims.custom.user, claims.custom.grant, TokenId { jti, expires_at }))
+    }
+
+    pub fn jwks(&self) -> Jwks {
+        let keys = self
+            .0
+            .iter()
+            .map(|key| Jwk {
+                kty: "OKP".to_string(),
+                crv: "Ed25519".to_string(),
+                x: URL_SAFE_NO_PAD.encode(key.to_bytes()),
+                kid: key.key_id().clone().unwrap_or_default(),
+                alg: "EdDSA".to_string(),
+                use_: "sig".to_string(),
+            })
+            .collect();
+        Jwks { keys }
+    }
+}
+
+fn load_public_key(pem: &str, kid: Option<&str>) -> Result<Ed25519PublicKey, AppError> {
+    let mut key = Ed25519PublicKey::from_pem(pem)?;
+    match kid {
+        Some(kid) => key.set_key_id(kid.to_string()),
+        None => {
+            key.create_key_id();
+        }
     }
+    Ok(key)
 }
 
 #[cfg(test)]
@@ -48,12 +166,44 @@ mod tests {
     async fn jwt_sign_verify_should_work() -> Result<()> {
         let encoding_pem = include_str!("../../fixtures/encoding.pem");
         let decoding_pem = include_str!("../../fixtures/decoding.pem");
-        let ek = EncodingKey::load(encoding_pem)?;
-        let dk = DecodingKey::load(decoding_pem)?;
+        let ek = EncodingKey::load(encoding_pem, None)?;
+        let dk = DecodingKey::load(decoding_pem, None)?;
         let user = User::new(1, "Tyr Chen", "tchen@acme.org");
         let token = ek.sign(user.clone())?;
-        let user2 = dk.verify(token.as_str())?;
+        let (user2, id) = dk.verify(token.as_str())?;
         assert_eq!(user, user2);
+        assert!(id.expires_at > Utc::now());
+        let (_, id2) = dk.verify(&ek.sign(user.clone())?)?;
+        assert_ne!(id.jti, id2.jti);
+        let id3 = TokenId::generate();
+        let grant = Grant { role: crate::WorkspaceRole::Admin, scopes: vec!["workspace:admin".to_string()], app_id: None };
+        let (_, verified_grant, verified) = dk.verify_claims(&ek.sign_with_id(user, &grant, &id3)?)?;
+        assert_eq!(verified.jti, id3.jti);
+        assert_eq!(verified_grant, grant);
+        Ok(())
+    }
+
+    #[test]
+    fn rotated_keys_should_keep_verifying_old_tokens() -> Result<()> {
+        let old_ek = EncodingKey::load(include_str!("../../fixtures/encoding.pem"), Some("2025-07"))?;
+        let new_pair = Ed25519KeyPair::generate();
+        let new_ek = EncodingKey::load(&new_pair.to_pem(), Some("2025-10"))?;
+        let user = User::new(1, "Tyr Chen", "tchen@acme.org");
+        let old_token = old_ek.sign(user.clone())?;
+
+        let dk = DecodingKey::load(&new_pair.public_key().to_pem(), Some("2025-10"))?;
+        assert!(dk.verify(&old_token).is_err());
+        let dk = DecodingKey(vec![
+            dk.0[0].clone(),
+            load_public_key(include_str!("../../fixtures/decoding.pem"), Some("2025-07"))?,
+        ]);
+        assert_eq!(dk.verify(&old_token)?.0, user);
+        assert_eq!(dk.verify(&new_ek.sign(user.clone())?)?.0, user);
+
+        let jwks = dk.jwks();
+        let kids: Vec<_> = jwks.keys.iter().map(|k| k.kid.as_str()).collect();
+        assert_eq!(kids, vec!["2025-10", "2025-07"]);
+        assert_eq!(jwks.keys[0].x.len(), 43);
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -1,3 +1,25 @@
+mod cidr;
+mod cookie;
+mod csv;
+mod dns;
+mod http;
 mod jwt;
+mod msgpack;
+mod password;
+mod patch;
+mod token;
+mod totp;
 
-pub use jwt::{DecodingKey, EncodingKey};
+pub use cidr::Cidr;
+pub use cookie::{clear_session_cookies, cookie_value, session_cookies, verify_csrf};
+pub use csv::csv_record;
+pub use dns::txt_records;
+pub use http::{HttpClient, HttpError};
+pub(crate) use http::check_public_url;
+pub use jwt::{DecodingKey, EncodingKey, TokenId, JWT_DURATION};
+pub use msgpack::{accepts_msgpack, has_msgpack_body, MsgPack, MSGPACK_CONTENT_TYPE};
+pub use patch::{is_merge_patch, nullable};
+pub use token::{constant_time_eq, generate_token, hex_encode};
+pub use totp::{generate_totp_secret, totp_provisioning_uri, verify_totp};
+#[cfg(test)]
+pub use totp::totp_code;
@@ -0,0 +1,115 @@
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::warn;
+
+use crate::AppError;
+
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+// what clients sent before application/msgpack was registered
+const LEGACY_CONTENT_TYPE: &str = "application/x-msgpack";
+
+// like Json, for bodies in MessagePack. Maps are written with their field names, so a client
+// reads the same shape as the json.
+#[derive(Debug, Clone, Default)]
+pub struct MsgPack<T>(pub T);
+
+fn is_msgpack(value: &str) -> bool {
+    let mime = value.split(';').next().unwrap_or_default().trim();
+    mime.eq_ignore_ascii_case(MSGPACK_CONTENT_TYPE) || mime.eq_ignore_ascii_case(LEGACY_CONTENT_TYPE)
+}
+
+pub fn has_msgpack_body(headers: &HeaderMap) -> bool {
+    headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).is_some_and(is_msgpack)
+}
+
+// listed in accept and not refused with q=0, json stays the default for everything else
+pub fn accepts_msgpack(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|range| {
+            let mut parts = range.split(';');
+            is_msgpack(parts.next().unwrap_or_default())
+                && !parts.any(|p| p.trim().strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0))
+        })
+}
+
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for MsgPack<T> {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !has_msgpack_body(req.headers()) {
+            let sent = req.headers().get(header::CONTENT_TYPE).map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned());
+            return Err(AppError::UnsupportedMediaType(sent.unwrap_or_else(|| "none".to_string())).into_response());
+        }
+        let bytes = Bytes::from_request(req, state).await.map_err(IntoResponse::into_response)?;
+        Self::from_bytes(&bytes).map_err(IntoResponse::into_response)
+    }
+}
+
+impl<T: DeserializeOwned> MsgPack<T> {
+    // one value and nothing after it, as serde_json reads a body
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AppError> {
+        let mut de = rmp_serde::Deserializer::new(bytes);
+        let value = T::deserialize(&mut de).map_err(|e| AppError::InvalidInput(format!("invalid msgpack body: {}", e)))?;
+        if !de.get_ref().is_empty() {
+            return Err(AppError::InvalidInput("invalid msgpack body: trailing bytes".to_string()));
+        }
+        Ok(MsgPack(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for MsgPack<T> {
+    fn into_response(self) -> Response {
+        match rmp_serde::to_vec_named(&self.0) {
+            Ok(body) => ([(header::CONTENT_TYPE, HeaderValue::from_static(MSGPACK_CONTENT_TYPE))], body).into_response(),
+            Err(e) => {
+                warn!("encode msgpack response failed: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn accepts_msgpack_should_read_the_accept_header() {
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_msgpack(&headers));
+        headers.insert(header::ACCEPT, "application/json, application/msgpack;q=0.9".parse().unwrap());
+        assert!(accepts_msgpack(&headers));
+        headers.insert(header::ACCEPT, "application/x-msgpack; q=0".parse().unwrap());
+        assert!(!accepts_msgpack(&headers));
+    }
+
+    #[tokio::test]
+    async fn msgpack_should_round_trip_a_body() {
+        let value = json!({ "content": "hello", "images": [], "thread_id": null, "seq": 7 });
+        let res = MsgPack(&value).into_response();
+        assert_eq!(res.headers()[header::CONTENT_TYPE], MSGPACK_CONTENT_TYPE);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+
+        let req = Request::builder()
+            .header(header::CONTENT_TYPE, "application/x-msgpack")
+            .body(Body::from(body))
+            .unwrap();
+        let MsgPack(read): MsgPack<Value> = MsgPack::from_request(req, &()).await.unwrap();
+        assert_eq!(read, value);
+
+        let req = Request::builder().header(header::CONTENT_TYPE, "application/json").body(Body::from("{}")).unwrap();
+        let ret = MsgPack::<Value>::from_request(req, &()).await;
+        assert_eq!(ret.unwrap_err().status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
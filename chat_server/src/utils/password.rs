@@ -0,0 +1,166 @@
+use crate::{config::PasswordPolicy, AppError};
+
+// the most used passwords from public breach lists, matched case insensitively and
+// ignoring trailing digits and symbols, so "Dragon2024!" counts as "dragon"
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456", "123456789", "12345678", "1234567890", "1234567", "12345", "1234", "111111", "000000",
+    "123123", "654321", "666666", "121212", "112233", "987654321", "password", "passw0rd", "p@ssword",
+    "qwerty", "qwertyuiop", "qwerty123", "asdfgh", "asdfghjkl", "zxcvbnm", "1q2w3e4r", "1qaz2wsx",
+    "qazwsx", "abc123", "abcdef", "abcd1234", "letmein", "welcome", "admin", "administrator", "root",
+    "login", "master", "monkey", "dragon", "football", "baseball", "soccer", "hockey", "superman",
+    "batman", "iloveyou", "sunshine", "princess", "shadow", "michael", "jennifer", "jordan", "charlie",
+    "freedom", "whatever", "trustno1", "starwars", "pokemon", "computer", "internet", "secret",
+    "changeme", "default", "guest", "test", "testing", "hello", "access", "flower", "cheese",
+    "summer", "winter", "spring", "autumn", "chocolate", "killer", "pepper", "ginger", "buster",
+    "tigger", "lovely", "loveme", "naruto", "michelle", "daniel", "ashley", "nicole", "mustang",
+    "maggie", "matrix", "liverpool", "chelsea", "arsenal", "samsung", "google", "chat",
+];
+
+// substrings of at least this many characters taken from the user's name or email count
+// as one guess instead of one per character
+const MIN_USER_INPUT_MATCH: usize = 3;
+
+impl PasswordPolicy {
+    // user_inputs are things an attacker knows about the user, e.g. their name and email
+    pub fn check(&self, password: &str, user_inputs: &[&str]) -> Result<(), AppError> {
+        let len = password.chars().count();
+        if len < self.min_length {
+            return Err(AppError::WeakPassword(format!(
+                "password must be at least {} characters",
+                self.min_length
+            )));
+        }
+        if char_classes(password) < self.min_classes {
+            return Err(AppError::WeakPassword(format!(
+                "password must use at least {} of lowercase letters, uppercase letters, digits and symbols",
+                self.min_classes
+            )));
+        }
+        if self.deny_common && is_common(password) {
+            return Err(AppError::WeakPassword("password is too common".to_string()));
+        }
+        if let Some(min) = self.min_guess_score
+            && guess_score(password, user_inputs) < min
+        {
+            return Err(AppError::WeakPassword("password is too easy to guess".to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn char_classes(password: &str) -> u8 {
+    let has = |f: fn(&char) -> bool| password.chars().any(|c| f(&c)) as u8;
+    has(|c| c.is_lowercase())
+        + has(|c| c.is_uppercase())
+        + has(|c| c.is_numeric())
+        + has(|c| !c.is_alphanumeric())
+}
+
+fn is_common(password: &str) -> bool {
+    let lower = password.to_lowercase();
+    let stripped = lower.trim_end_matches(|c: char| !c.is_alphabetic());
+    COMMON_PASSWORDS
+        .iter()
+        .any(|p| *p == lower || (stripped.len() >= 4 && *p == stripped))
+}
+
+// rough estimate of how many guesses it takes, scored like zxcvbn does: below 10^3 guesses
+// is 0, 10^6 is 1, 10^8 is 2, 10^10 is 3 and anything above is 4. It is a small heuristic, not
+// zxcvbn: the password is read left to right, and
+// - a word from COMMON_PASSWORDS or from the user's name or email costs one guess out of the list
+// - a character next to the one before (aaa, abc, 321) costs two guesses
+// - any other character costs the size of the character classes used, see pool_size
+// There are no dictionaries, keyboard patterns, dates or l33t substitutions.
+fn guess_score(password: &str, user_inputs: &[&str]) -> u8 {
+    let lower: Vec<char> = password.to_lowercase().chars().collect();
+    let mut known: Vec<Vec<char>> = COMMON_PASSWORDS
+        .iter()
+        .filter(|p| p.len() >= 4)
+        .map(|p| p.chars().collect())
+        .collect();
+    known.extend(
+        user_inputs
+            .iter()
+            .flat_map(|s| s.split(|c: char| !c.is_alphanumeric()))
+            .filter(|s| s.chars().count() >= MIN_USER_INPUT_MATCH)
+            .map(|s| s.to_lowercase().chars().collect()),
+    );
+
+    let per_char = (pool_size(password) as f64).log10();
+    let mut log_guesses = 0.0;
+    let mut i = 0;
+    while i < lower.len() {
+        if let Some(word) = known.iter().filter(|w| lower[i..].starts_with(w)).max_by_key(|w| w.len()) {
+            log_guesses += (COMMON_PASSWORDS.len() as f64).log10();
+            i += word.len();
+            continue;
+        }
+        let predictable = i > 0 && (lower[i - 1] as i64 - lower[i] as i64).abs() <= 1;
+        log_guesses += if predictable { 2f64.log10() } else { per_char };
+        i += 1;
+    }
+
+    match log_guesses {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+fn pool_size(password: &str) -> usize {
+    let has = |f: fn(&char) -> bool| password.chars().any(|c| f(&c));
+    let mut size = 0;
+    if has(|c| c.is_ascii_lowercase()) {
+        size += 26;
+    }
+    if has(|c| c.is_ascii_uppercase()) {
+        size += 26;
+    }
+    if has(|c| c.is_ascii_digit()) {
+        size += 10;
+    }
+    if has(|c| c.is_ascii_punctuation() || *c == ' ') {
+        size += 33;
+    }
+    if has(|c| !c.is_ascii()) {
+        size += 100;
+    }
+    size.max(10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_policy_should_check_length_classes_and_common() {
+        let policy = PasswordPolicy::default();
+        assert!(policy.check("hunter42", &[]).is_ok());
+        assert!(matches!(policy.check("hunt42", &[]), Err(AppError::WeakPassword(_))));
+        assert!(matches!(policy.check("huntertwo", &[]), Err(AppError::WeakPassword(_))));
+        assert!(matches!(policy.check("Password123!", &[]), Err(AppError::WeakPassword(_))));
+        assert!(matches!(policy.check("12345678", &[]), Err(AppError::WeakPassword(_))));
+
+        let relaxed = PasswordPolicy { min_classes: 1, deny_common: false, ..Default::default() };
+        assert!(relaxed.check("12345678", &[]).is_ok());
+    }
+
+    #[test]
+    fn guess_score_should_penalize_guessable_parts() {
+        assert_eq!(guess_score("12345678", &[]), 0);
+        assert_eq!(guess_score("aaaaaaaaaaaa", &[]), 1);
+        assert!(guess_score("Password1", &[]) <= 1);
+        assert_eq!(guess_score("correct horse battery staple", &[]), 4);
+        assert_eq!(guess_score("hunter42", &[]), 4);
+
+        // the user's own name is the first thing to be tried
+        assert_eq!(guess_score("tyrchen1", &["Tyr Chen", "tyr@acme.org"]), 1);
+        assert_eq!(guess_score("tyrchen1", &[]), 4);
+
+        let policy = PasswordPolicy { min_guess_score: Some(3), ..Default::default() };
+        assert!(policy.check("tyrchen1", &["Tyr Chen"]).is_err());
+        assert!(policy.check("Xq7#mP2v9!", &["Tyr Chen"]).is_ok());
+    }
+}
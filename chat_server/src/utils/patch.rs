@@ -0,0 +1,53 @@
+use axum::http::{header, HeaderMap};
+use serde::{Deserialize, Deserializer};
+
+// rfc 7396, null removes a field rather than leaving it as it is
+const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+
+pub fn is_merge_patch(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case(MERGE_PATCH_CONTENT_TYPE))
+}
+
+// for patch fields, with #[serde(default)]: None when missing, Some(None) when null
+pub fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Patch {
+        #[serde(default, deserialize_with = "nullable")]
+        name: Option<Option<String>>,
+    }
+
+    #[test]
+    fn nullable_should_tell_null_from_missing() {
+        let patch: Patch = serde_json::from_str("{}").unwrap();
+        assert_eq!(patch.name, None);
+        let patch: Patch = serde_json::from_str(r#"{"name": null}"#).unwrap();
+        assert_eq!(patch.name, Some(None));
+        let patch: Patch = serde_json::from_str(r#"{"name": "a"}"#).unwrap();
+        assert_eq!(patch.name, Some(Some("a".to_string())));
+    }
+
+    #[test]
+    fn is_merge_patch_should_check_the_content_type() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_merge_patch(&headers));
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        assert!(!is_merge_patch(&headers));
+        headers.insert(header::CONTENT_TYPE, "application/merge-patch+json; charset=utf-8".parse().unwrap());
+        assert!(is_merge_patch(&headers));
+    }
+}
@@ -0,0 +1,36 @@
+use std::fmt::Write;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+// url safe random token with `bytes` bytes of entropy, hex encoded
+pub fn generate_token(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buf);
+    hex_encode(&buf)
+}
+
+pub fn hex_encode(buf: &[u8]) -> String {
+    buf.iter().fold(String::with_capacity(buf.len() * 2), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+// for comparing secrets, takes as long wherever the first difference is
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_token_should_work() {
+        let t1 = generate_token(16);
+        let t2 = generate_token(16);
+        assert_eq!(t1.len(), 32);
+        assert!(t1.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(t1, t2);
+    }
+}
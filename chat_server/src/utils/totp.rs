@@ -0,0 +1,149 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+// RFC 6238 defaults, which is what authenticator apps expect
+const TOTP_STEP_SECS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// accept codes from one step before and after, to allow for clock drift
+const TOTP_SKEW_STEPS: i64 = 1;
+const SECRET_BYTES: usize = 20;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// base32 encoded, as used in provisioning uris
+pub fn generate_totp_secret() -> String {
+    let mut buf = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut buf);
+    base32_encode(&buf)
+}
+
+pub fn totp_provisioning_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        uri_encode(issuer),
+        uri_encode(account),
+        secret,
+        uri_encode(issuer),
+        TOTP_DIGITS,
+        TOTP_STEP_SECS
+    )
+}
+
+// returns the time step the code matched, callers reject steps at or before the last one
+// used so that a code cannot be replayed
+pub fn verify_totp(secret: &str, code: &str, unix_time: i64) -> Option<i64> {
+    let key = base32_decode(secret)?;
+    let code: u32 = code.trim().parse().ok()?;
+    let step = unix_time / TOTP_STEP_SECS;
+    (step - TOTP_SKEW_STEPS..=step + TOTP_SKEW_STEPS).find(|s| hotp(&key, *s as u64) == code)
+}
+
+// what an authenticator app would show at the given time
+#[cfg(test)]
+pub fn totp_code(secret: &str, unix_time: i64) -> String {
+    let key = base32_decode(secret).expect("secret should be base32");
+    format!("{:06}", hotp(&key, (unix_time / TOTP_STEP_SECS) as u64))
+}
+
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mac = hmac_sha1(key, &counter.to_be_bytes());
+    let offset = (mac[19] & 0x0f) as usize;
+    let bin = u32::from_be_bytes([mac[offset] & 0x7f, mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+    bin % 10u32.pow(TOTP_DIGITS)
+}
+
+// the crate's own HMAC pads keys to 40 bytes instead of the 64 byte block size, so its
+// output doesn't match what authenticator apps compute
+fn hmac_sha1(key: &[u8], input: &[u8]) -> [u8; 20] {
+    use hmac_sha1_compact::Hash;
+
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..20].copy_from_slice(&Hash::hash(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Hash::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(input);
+    let mut outer = Hash::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize()
+}
+
+fn base32_encode(buf: &[u8]) -> String {
+    let mut out = String::with_capacity(buf.len().div_ceil(5) * 8);
+    let (mut acc, mut bits) = (0u32, 0);
+    for b in buf {
+        acc = (acc << 8) | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((acc >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((acc << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let v = BASE32_ALPHABET.iter().position(|a| *a == c.to_ascii_uppercase())? as u32;
+        acc = (acc << 5) | v;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base32_should_roundtrip() {
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_decode("MZXW6YTBOI").unwrap(), b"foobar");
+        assert_eq!(generate_totp_secret().len(), 32);
+    }
+
+    #[test]
+    fn hmac_sha1_should_match_rfc2202() {
+        let mac = hmac_sha1(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(crate::utils::hex_encode(&mac), "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79");
+    }
+
+    #[test]
+    fn totp_should_match_rfc6238() {
+        // test vectors from RFC 6238, truncated to 6 digits
+        let secret = base32_encode(b"12345678901234567890");
+        assert_eq!(verify_totp(&secret, "287082", 59), Some(1));
+        assert_eq!(verify_totp(&secret, "081804", 1111111109), Some(37037036));
+        assert_eq!(verify_totp(&secret, "081804", 1111111109 + 60), None);
+        assert_eq!(verify_totp(&secret, "abc", 59), None);
+    }
+
+    #[test]
+    fn provisioning_uri_should_encode_account() {
+        let uri = totp_provisioning_uri("chat", "tchen+1@acme.org", "MZXW6YTBOI");
+        assert_eq!(
+            uri,
+            "otpauth://totp/chat:tchen%2B1%40acme.org?secret=MZXW6YTBOI&issuer=chat&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::BTreeMap;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{config::WebauthnConfig, AppError};
+
+// COSE algorithm id of ES256, the one algorithm every platform authenticator supports
+const COSE_ALG_ES256: i64 = -7;
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+pub const CEREMONY_TIMEOUT_MS: u64 = 120_000;
+
+// PublicKeyCredential.toJSON() of a navigator.credentials.create() result
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+// PublicKeyCredential.toJSON() of a navigator.credentials.get() result
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthenticationCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedCredential {
+    pub credential_id: Vec<u8>,
+    // SEC1 uncompressed point
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+// 32 random bytes, base64url encoded the way the client echoes it back
+pub fn generate_challenge() -> String {
+    let mut buf = [0u8; 32];
+    OsRng.fill_bytes(&mut buf);
+    b64url_encode(&buf)
+}
+
+pub fn b64url_encode(buf: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(buf)
+}
+
+pub fn b64url_decode(s: &str) -> Result<Vec<u8>, AppError> {
+    URL_SAFE_NO_PAD
+        .decode(s.trim_end_matches('='))
+        .map_err(|_| AppError::InvalidInput("invalid base64url value".to_string()))
+}
+
+// the challenge the browser signed, used to find the ceremony it belongs to
+pub fn client_challenge(client_data_json: &str) -> Result<String, AppError> {
+    let client_data: ClientData = serde_json::from_slice(&b64url_decode(client_data_json)?)
+        .map_err(|_| AppError::InvalidInput("invalid client data".to_string()))?;
+    Ok(client_data.challenge)
+}
+
+// options for navigator.credentials.create(), in their JSON form
+pub fn creation_options(config: &WebauthnConfig, challenge: &str, user_id: i64, email: &str, fullname: &str, exclude: &[Vec<u8>]) -> Value {
+    json!({
+        "challenge": challenge,
+        "rp": { "id": config.rp_id, "name": config.rp_name },
+        "user": {
+            "id": b64url_encode(&user_id.to_be_bytes()),
+            "name": email,
+            "displayName": fullname,
+        },
+        "pubKeyCredParams": [{ "type": "public-key", "alg": COSE_ALG_ES256 }],
+        "timeout": CEREMONY_TIMEOUT_MS,
+        "attestation": "none",
+        "authenticatorSelection": { "residentKey": "preferred", "userVerification": "preferred" },
+        "excludeCredentials": exclude
+            .iter()
+            .map(|id| json!({ "type": "public-key", "id": b64url_encode(id) }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+// options for navigator.credentials.get(), in their JSON form
+pub fn request_options(config: &WebauthnConfig, challenge: &str, allow: &[Vec<u8>]) -> Value {
+    json!({
+        "challenge": challenge,
+        "rpId": config.rp_id,
+        "timeout": CEREMONY_TIMEOUT_MS,
+        "userVerification": "preferred",
+        "allowCredentials": allow
+            .iter()
+            .map(|id| json!({ "type": "public-key", "id": b64url_encode(id) }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+// we ask for no attestation, so the attestation statement is not checked: the user is
+// already signed in when they register a passkey
+pub fn verify_registration(config: &WebauthnConfig, challenge: &str, credential: &RegistrationCredential) -> Result<VerifiedCredential, AppError> {
+    verify_client_data(config, "webauthn.create", challenge, &credential.response.client_data_json)?;
+    let attestation = b64url_decode(&credential.response.attestation_object)?;
+    let (attestation, _) = Cbor::parse(&attestation)?;
+    let auth_data = attestation
+        .get_text("authData")
+        .and_then(Cbor::as_bytes)
+        .ok_or_else(|| failed("attestation object has no authenticator data"))?;
+    let (flags, sign_count) = verify_auth_data(config, auth_data)?;
+    if flags & FLAG_ATTESTED_CREDENTIAL == 0 {
+        return Err(failed("no attested credential"));
+    }
+
+    // aaguid (16), credential id length (2), credential id, COSE key
+    let rest = &auth_data[37..];
+    if rest.len() < 18 {
+        return Err(failed("attested credential data is truncated"));
+    }
+    let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+    let credential_id = rest
+        .get(18..18 + id_len)
+        .ok_or_else(|| failed("attested credential data is truncated"))?
+        .to_vec();
+    let (cose_key, _) = Cbor::parse(&rest[18 + id_len..])?;
+    let public_key = cose_es256_key(&cose_key)?;
+    if b64url_decode(&credential.id)? != credential_id {
+        return Err(failed("credential id mismatch"));
+    }
+    Ok(VerifiedCredential {
+        credential_id,
+        public_key,
+        sign_count,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VerifiedAssertion {
+    pub sign_count: u32,
+    // the authenticator checked a pin or biometric, without it the passkey is only something
+    // the user has
+    pub user_verified: bool,
+}
+
+pub fn verify_authentication(config: &WebauthnConfig, challenge: &str, public_key: &[u8], sign_count: u32, credential: &AuthenticationCredential) -> Result<VerifiedAssertion, AppError> {
+    let client_data_json = b64url_decode(&credential.response.client_data_json)?;
+    verify_client_data(config, "webauthn.get", challenge, &credential.response.client_data_json)?;
+    let auth_data = b64url_decode(&credential.response.authenticator_data)?;
+    let (flags, new_count) = verify_auth_data(config, &auth_data)?;
+
+    let key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| failed("stored public key is invalid"))?;
+    let signature = Signature::from_der(&b64url_decode(&credential.response.signature)?)
+        .map_err(|_| failed("signature is malformed"))?;
+    let mut signed = auth_data.clone();
+    signed.extend_from_slice(&Sha256::digest(&client_data_json));
+    key.verify(&signed, &signature).map_err(|_| failed("signature mismatch"))?;
+
+    // authenticators that don't count always report 0, otherwise a counter that didn't move
+    // forward means the credential was cloned
+    if (new_count != 0 || sign_count != 0) && new_count <= sign_count {
+        return Err(failed("signature counter went backwards"));
+    }
+    Ok(VerifiedAssertion {
+        sign_count: new_count,
+        user_verified: flags & FLAG_USER_VERIFIED != 0,
+    })
+}
+
+fn verify_client_data(config: &WebauthnConfig, kind: &str, challenge: &str, client_data_json: &str) -> Result<(), AppError> {
+    let client_data: ClientData = serde_json::from_slice(&b64url_decode(client_data_json)?)
+        .map_err(|_| failed("invalid client data"))?;
+    if client_data.kind != kind {
+        return Err(failed("unexpected ceremony type"));
+    }
+    if client_data.challenge.trim_end_matches('=') != challenge {
+        return Err(failed("challenge mismatch"));
+    }
+    if client_data.origin != config.origin {
+        return Err(failed("origin mismatch"));
+    }
+    Ok(())
+}
+
+// returns the flags and the signature counter
+fn verify_auth_data(config: &WebauthnConfig, auth_data: &[u8]) -> Result<(u8, u32), AppError> {
+    if auth_data.len() < 37 {
+        return Err(failed("authenticator data is truncated"));
+    }
+    if auth_data[..32] != Sha256::digest(config.rp_id.as_bytes())[..] {
+        return Err(failed("relying party mismatch"));
+    }
+    let flags = auth_data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err(failed("user was not present"));
+    }
+    let sign_count = u32::from_be_bytes([auth_data[33], auth_data[34], auth_data[35], auth_data[36]]);
+    Ok((flags, sign_count))
+}
+
+fn cose_es256_key(key: &Cbor) -> Result<Vec<u8>, AppError> {
+    // kty 2 is EC2, crv 1 is P-256
+    let int = |label: i64| key.get_int(label).and_then(Cbor::as_int);
+    if int(1) != Some(2) || int(3) != Some(COSE_ALG_ES256) || int(-1) != Some(1) {
+        return Err(failed("only ES256 credentials are supported"));
+    }
+    let (Some(x), Some(y)) = (key.get_int(-2).and_then(Cbor::as_bytes), key.get_int(-3).and_then(Cbor::as_bytes)) else {
+        return Err(failed("public key is incomplete"));
+    };
+    let mut point = vec![0x04];
+    point.extend_from_slice(x);
+    point.extend_from_slice(y);
+    VerifyingKey::from_sec1_bytes(&point).map_err(|_| failed("public key is not on the curve"))?;
+    Ok(point)
+}
+
+fn failed(reason: &str) -> AppError {
+    AppError::PermissionDenied(format!("passkey verification failed: {}", reason))
+}
+
+// just enough CBOR for attestation objects and COSE keys: definite lengths only
+#[derive(Debug, Clone, PartialEq)]
+enum Cbor {
+    Int(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(BTreeMap<CborKey, Cbor>),
+    Simple(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum CborKey {
+    Int(i64),
+    Text(String),
+}
+
+impl Cbor {
+    // returns the value and the number of bytes it took
+    fn parse(buf: &[u8]) -> Result<(Cbor, usize), AppError> {
+        Self::parse_depth(buf, 0)
+    }
+
+    fn parse_depth(buf: &[u8], depth: usize) -> Result<(Cbor, usize), AppError> {
+        let malformed = || failed("malformed CBOR");
+        if depth > 16 {
+            return Err(malformed());
+        }
+        let head = *buf.first().ok_or_else(malformed)?;
+        let (major, info) = (head >> 5, head & 0x1f);
+        let (arg, mut pos) = match info {
+            0..=23 => (info as u64, 1),
+            24..=27 => {
+                let n = 1 << (info - 24);
+                let bytes = buf.get(1..1 + n).ok_or_else(malformed)?;
+                (bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64), 1 + n)
+            }
+            _ => return Err(malformed()),
+        };
+        let len = usize::try_from(arg).map_err(|_| malformed())?;
+        let value = match major {
+            0 => Cbor::Int(i64::try_from(arg).map_err(|_| malformed())?),
+            1 => Cbor::Int(-1 - i64::try_from(arg).map_err(|_| malformed())?),
+            2 | 3 => {
+                let bytes = buf.get(pos..pos.checked_add(len).ok_or_else(malformed)?).ok_or_else(malformed)?;
+                pos += len;
+                if major == 2 {
+                    Cbor::Bytes(bytes.to_vec())
+                } else {
+                    Cbor::Text(String::from_utf8(bytes.to_vec()).map_err(|_| malformed())?)
+                }
+            }
+            4 => {
+                let mut items = Vec::new();
+                for _ in 0..len {
+                    let (item, n) = Self::parse_depth(buf.get(pos..).ok_or_else(malformed)?, depth + 1)?;
+                    items.push(item);
+                    pos += n;
+                }
+                Cbor::Array(items)
+            }
+            5 => {
+                let mut map = BTreeMap::new();
+                for _ in 0..len {
+                    let (key, n) = Self::parse_depth(buf.get(pos..).ok_or_else(malformed)?, depth + 1)?;
+                    pos += n;
+                    let (value, n) = Self::parse_depth(buf.get(pos..).ok_or_else(malformed)?, depth + 1)?;
+                    pos += n;
+                    let key = match key {
+                        Cbor::Int(i) => CborKey::Int(i),
+                        Cbor::Text(s) => CborKey::Text(s),
+                        _ => return Err(malformed()),
+                    };
+                    map.insert(key, value);
+                }
+                Cbor::Map(map)
+            }
+            7 if info < 24 => Cbor::Simple(info),
+            _ => return Err(malformed()),
+        };
+        Ok((value, pos))
+    }
+
+    fn get_int(&self, key: i64) -> Option<&Cbor> {
+        match self {
+            Cbor::Map(map) => map.get(&CborKey::Int(key)),
+            _ => None,
+        }
+    }
+
+    fn get_text(&self, key: &str) -> Option<&Cbor> {
+        match self {
+            Cbor::Map(map) => map.get(&CborKey::Text(key.to_string())),
+            _ => None,
+        }
+    }
+
+    fn as_int(&self) -> Option<i64> {
+        match self {
+            Cbor::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Cbor::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+// a software authenticator, for tests of the ceremonies
+#[cfg(test)]
+pub(crate) mod test_authenticator {
+    use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+
+    use super::*;
+
+    pub(crate) struct Authenticator {
+        key: SigningKey,
+        pub(crate) credential_id: Vec<u8>,
+        pub(crate) counter: u32,
+        // whether assertions report a pin or biometric check
+        pub(crate) verify_user: bool,
+    }
+
+    impl Authenticator {
+        pub(crate) fn new(seed: u8) -> Self {
+            let key = SigningKey::from_bytes(&[seed; 32].into()).expect("seed should be a valid key");
+            Self {
+                key,
+                credential_id: vec![seed; 16],
+                counter: 0,
+                verify_user: true,
+            }
+        }
+
+        pub(crate) fn register(&mut self, config: &WebauthnConfig, challenge: &str) -> RegistrationCredential {
+            let point = self.key.verifying_key().to_encoded_point(false);
+            // {1: 2, 3: -7, -1: 1, -2: x, -3: y}
+            let mut cose = vec![0xa5, 0x01, 0x02, 0x03, 0x26, 0x20, 0x01, 0x21, 0x58, 0x20];
+            cose.extend_from_slice(point.x().expect("x"));
+            cose.extend_from_slice(&[0x22, 0x58, 0x20]);
+            cose.extend_from_slice(point.y().expect("y"));
+
+            let mut auth_data = self.auth_data(config, FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL);
+            auth_data.extend_from_slice(&[0u8; 16]);
+            auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            auth_data.extend_from_slice(&self.credential_id);
+            auth_data.extend_from_slice(&cose);
+
+            // {"fmt": "none", "attStmt": {}, "authData": auth_data}
+            let mut attestation = vec![0xa3, 0x63];
+            attestation.extend_from_slice(b"fmt");
+            attestation.push(0x64);
+            attestation.extend_from_slice(b"none");
+            attestation.push(0x67);
+            attestation.extend_from_slice(b"attStmt");
+            attestation.push(0xa0);
+            attestation.push(0x68);
+            attestation.extend_from_slice(b"authData");
+            attestation.extend_from_slice(&[0x59, (auth_data.len() >> 8) as u8, auth_data.len() as u8]);
+            attestation.extend_from_slice(&auth_data);
+
+            RegistrationCredential {
+                id: b64url_encode(&self.credential_id),
+                response: AttestationResponse {
+                    client_data_json: client_data(config, "webauthn.create", challenge),
+                    attestation_object: b64url_encode(&attestation),
+                },
+            }
+        }
+
+        pub(crate) fn authenticate(&mut self, config: &WebauthnConfig, challenge: &str) -> AuthenticationCredential {
+            self.counter += 1;
+            let flags = if self.verify_user { FLAG_USER_PRESENT | FLAG_USER_VERIFIED } else { FLAG_USER_PRESENT };
+            let auth_data = self.auth_data(config, flags);
+            let client_data_json = client_data(config, "webauthn.get", challenge);
+            let mut signed = auth_data.clone();
+            signed.extend_from_slice(&Sha256::digest(b64url_decode(&client_data_json).expect("client data")));
+            let signature: Signature = self.key.sign(&signed);
+            AuthenticationCredential {
+                id: b64url_encode(&self.credential_id),
+                response: AssertionResponse {
+                    client_data_json,
+                    authenticator_data: b64url_encode(&auth_data),
+                    signature: b64url_encode(signature.to_der().as_bytes()),
+                },
+            }
+        }
+
+        fn auth_data(&self, config: &WebauthnConfig, flags: u8) -> Vec<u8> {
+            let mut auth_data = Sha256::digest(config.rp_id.as_bytes()).to_vec();
+            auth_data.push(flags);
+            auth_data.extend_from_slice(&self.counter.to_be_bytes());
+            auth_data
+        }
+    }
+
+    fn client_data(config: &WebauthnConfig, kind: &str, challenge: &str) -> String {
+        let client_data = json!({ "type": kind, "challenge": challenge, "origin": config.origin });
+        b64url_encode(client_data.to_string().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_authenticator::Authenticator, *};
+
+    #[test]
+    fn registration_and_authentication_should_verify() {
+        let config = WebauthnConfig::default();
+        let mut authenticator = Authenticator::new(7);
+        let credential = authenticator.register(&config, "challenge-1");
+        assert_eq!(client_challenge(&credential.response.client_data_json).unwrap(), "challenge-1");
+        let verified = verify_registration(&config, "challenge-1", &credential).unwrap();
+        assert_eq!(verified.credential_id, authenticator.credential_id);
+        assert!(verify_registration(&config, "challenge-2", &credential).is_err());
+
+        let assertion = authenticator.authenticate(&config, "challenge-3");
+        let ret = verify_authentication(&config, "challenge-3", &verified.public_key, 0, &assertion).unwrap();
+        assert_eq!(ret, VerifiedAssertion { sign_count: 1, user_verified: true });
+        // replaying the same assertion doesn't move the counter forward
+        assert!(verify_authentication(&config, "challenge-3", &verified.public_key, 1, &assertion).is_err());
+        // presence alone is reported as such
+        authenticator.verify_user = false;
+        let assertion = authenticator.authenticate(&config, "challenge-4");
+        let ret = verify_authentication(&config, "challenge-4", &verified.public_key, 1, &assertion).unwrap();
+        assert_eq!(ret, VerifiedAssertion { sign_count: 2, user_verified: false });
+        // a different key cannot sign for this credential
+        let other = Authenticator::new(8).authenticate(&config, "challenge-3");
+        assert!(verify_authentication(&config, "challenge-3", &verified.public_key, 0, &other).is_err());
+    }
+
+    #[test]
+    fn cbor_should_reject_malformed_input() {
+        assert!(Cbor::parse(&[0x5f]).is_err());
+        assert!(Cbor::parse(&[0x58, 0x20, 0x01]).is_err());
+        assert_eq!(Cbor::parse(&[0x38, 0x18]).unwrap(), (Cbor::Int(-25), 2));
+    }
+}
@@ -0,0 +1,184 @@
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use futures::future::join_all;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    models::DueDelivery,
+    utils::{hex_encode, HttpError},
+    AppError, AppState, WebhookDelivery,
+};
+
+pub(crate) const SIGNATURE_HEADER: &str = "x-chat-signature";
+const EVENT_HEADER: &str = "x-chat-event";
+// the same on every attempt of a delivery, receivers use it to drop duplicates
+const DELIVERY_HEADER: &str = "x-chat-delivery";
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const DELIVERY_BATCH: usize = 20;
+// longer than an attempt with the http client's retries can take
+const CLAIM_LEASE_SECS: u64 = 120;
+// about a day and a half in all with the backoff below
+const MAX_ATTEMPTS: i32 = 12;
+const RETRY_BASE_SECS: i64 = 15;
+const RETRY_MAX_SECS: i64 = 6 * 60 * 60;
+// how long the log of done deliveries is kept, and how often it is trimmed
+const DELIVERY_RETENTION_DAYS: i64 = 30;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// hex encoded HMAC-SHA256 of the body, receivers recompute it with the webhook secret
+pub(crate) fn sign(secret: &str, body: &[u8]) -> String {
+    format!("sha256={}", hex_encode(&hmac_sha256::HMAC::mac(body, secret.as_bytes())))
+}
+
+// sends queued deliveries until stop is cancelled. Every instance runs one, each delivery is
+// claimed by one of them at a time.
+pub(crate) async fn deliver(state: AppState, stop: CancellationToken) {
+    let mut pruned_at: Option<Instant> = None;
+    loop {
+        if pruned_at.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+            match WebhookDelivery::prune(DELIVERY_RETENTION_DAYS, &state.pool).await {
+                Ok(0) => {}
+                Ok(n) => info!("pruned {} webhook deliveries", n),
+                Err(e) => warn!("prune webhook deliveries failed: {}", e),
+            }
+            pruned_at = Some(Instant::now());
+        }
+        let delay = match deliver_due(&state).await {
+            // more may be due
+            Ok(n) if n == DELIVERY_BATCH => Duration::ZERO,
+            Ok(_) => POLL_INTERVAL,
+            Err(e) => {
+                warn!("deliver webhooks failed: {}", e);
+                POLL_INTERVAL
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stop.cancelled() => return,
+        }
+    }
+}
+
+async fn deliver_due(state: &AppState) -> Result<usize, AppError> {
+    let due = WebhookDelivery::claim_due(DELIVERY_BATCH, CLAIM_LEASE_SECS, &state.pool).await?;
+    let n = due.len();
+    join_all(due.into_iter().map(|delivery| attempt(state, delivery))).await;
+    Ok(n)
+}
+
+async fn attempt(state: &AppState, delivery: DueDelivery) {
+    let (status, error) = match send(state, &delivery).await {
+        Ok(status) if (200..300).contains(&status) => (Some(status), None),
+        Ok(status) => (Some(status), Some(format!("responded with {}", status))),
+        Err(e) => (None, Some(e.detail())),
+    };
+    let retry_at = match &error {
+        Some(e) if delivery.attempts < MAX_ATTEMPTS => {
+            warn!("deliver {} to webhook {} failed, will retry: {}", delivery.id, delivery.webhook_id, e);
+            Some(Utc::now() + chrono::Duration::seconds(backoff_secs(delivery.attempts)))
+        }
+        Some(e) => {
+            warn!("deliver {} to webhook {} failed, giving up: {}", delivery.id, delivery.webhook_id, e);
+            None
+        }
+        None => None,
+    };
+    let ret = WebhookDelivery::record_attempt(delivery.id, status, error.as_deref(), retry_at, &state.pool).await;
+    // the claim runs out and the delivery is attempted again
+    if let Err(e) = ret {
+        warn!("record webhook delivery {} failed: {}", delivery.id, e);
+    }
+}
+
+async fn send(state: &AppState, delivery: &DueDelivery) -> Result<u16, HttpError> {
+    // jsonb doesn't keep the bytes it was given, the signature covers what is sent
+    let body = serde_json::to_vec(&delivery.payload.0).unwrap_or_default();
+    let request = state
+        .http
+        .post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, sign(&delivery.secret, &body))
+        .header(EVENT_HEADER, delivery.event.as_str())
+        .header(DELIVERY_HEADER, delivery.id)
+        .body(body);
+    let res = state.http.send_public(request).await?;
+    Ok(res.status().as_u16())
+}
+
+// doubles from RETRY_BASE_SECS after each failed attempt, up to RETRY_MAX_SECS
+fn backoff_secs(attempts: i32) -> i64 {
+    let exp = (attempts - 1).clamp(0, 30) as u32;
+    RETRY_BASE_SECS.saturating_mul(1 << exp).min(RETRY_MAX_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AppConfig, CreateMessage, CreateWebhook, ListWebhookDeliveries, Message, Webhook, WebhookDeliveryStatus,
+        WebhookEvent,
+    };
+    use anyhow::Result;
+    use axum::{body::Bytes, http::HeaderMap, routing::post, Router};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    #[test]
+    fn sign_should_match_hmac_sha256() {
+        // test case 2 of RFC 4231
+        let signature = sign("Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            signature,
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn backoff_should_double_up_to_max() {
+        assert_eq!(backoff_secs(1), RETRY_BASE_SECS);
+        assert_eq!(backoff_secs(3), RETRY_BASE_SECS * 4);
+        assert_eq!(backoff_secs(MAX_ATTEMPTS), RETRY_MAX_SECS);
+    }
+
+    #[tokio::test]
+    async fn deliver_due_should_post_signed_payloads() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        // the receiver of the test is local
+        config.outbound.allow_private_targets = true;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let (tx, mut rx) = mpsc::channel(1);
+        let receiver = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: Bytes| async move {
+                let _ = tx.send((headers, body)).await;
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/hook", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+        let input = CreateWebhook { url: "https://hooks.acme.org/hook".to_string(), events: vec![WebhookEvent::MessageCreated] };
+        let webhook = Webhook::create(1, &input, 1, &state.pool).await?;
+        // only public https urls can be registered
+        sqlx::query("UPDATE workspace_webhooks SET url = $2 WHERE id = $1")
+            .bind(webhook.id)
+            .bind(url)
+            .execute(&state.pool)
+            .await?;
+        let msg = Message::create(&CreateMessage::new("hello"), 1, 1, &state.pool).await?;
+        assert_eq!(deliver_due(&state).await?, 1);
+
+        let (headers, body) = rx.recv().await.expect("delivery");
+        let secret = webhook.secret.unwrap_or_default();
+        assert_eq!(headers[SIGNATURE_HEADER], sign(&secret, &body));
+        assert_eq!(headers[EVENT_HEADER], "message.created");
+        let payload: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(payload["data"]["id"], msg.id);
+
+        let log = WebhookDelivery::list(1, webhook.id as _, &ListWebhookDeliveries::default(), &state.pool).await?;
+        assert_eq!(log[0].status, WebhookDeliveryStatus::Succeeded);
+        assert_eq!(deliver_due(&state).await?, 0);
+        Ok(())
+    }
+}
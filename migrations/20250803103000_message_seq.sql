@@ -0,0 +1,26 @@
+-- per chat message sequence, bumped under the chat row lock on every insert
+ALTER TABLE chats
+    ADD COLUMN last_seq bigint NOT NULL DEFAULT 0;
+
+-- nullable until the messages already there are numbered
+ALTER TABLE messages
+    ADD COLUMN seq bigint;
+
+UPDATE messages m
+SET seq = s.seq
+FROM (
+    SELECT id, row_number() OVER (PARTITION BY chat_id ORDER BY created_at, id) AS seq
+    FROM messages
+) s
+WHERE m.id = s.id;
+
+UPDATE chats c
+SET last_seq = s.last_seq
+FROM (SELECT chat_id, max(seq) AS last_seq FROM messages GROUP BY chat_id) s
+WHERE c.id = s.chat_id;
+
+ALTER TABLE messages
+    ALTER COLUMN seq SET NOT NULL;
+
+-- create unique index for messages for chat_id and seq order by seq desc
+CREATE UNIQUE INDEX IF NOT EXISTS chat_id_seq_index ON messages(chat_id, seq DESC);
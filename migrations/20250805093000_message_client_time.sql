@@ -0,0 +1,3 @@
+-- time the client says the message was composed at, kept alongside server created_at
+ALTER TABLE messages
+    ADD COLUMN client_created_at timestamptz;
@@ -0,0 +1,9 @@
+-- trigram indexes so member search with ILIKE '%q%' does not scan the whole users table
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX IF NOT EXISTS users_fullname_trgm_index ON users USING gin (fullname gin_trgm_ops);
+
+CREATE INDEX IF NOT EXISTS users_email_trgm_index ON users USING gin (email gin_trgm_ops);
+
+-- create index for users for ws_id
+CREATE INDEX IF NOT EXISTS users_ws_id_index ON users(ws_id, id);
@@ -0,0 +1,24 @@
+-- chat creator, existing chats are owned by the super user
+ALTER TABLE chats
+    ADD COLUMN owner_id bigint NOT NULL DEFAULT 0 REFERENCES users(id);
+
+-- create chat member action type: join, leave, kick
+CREATE TYPE chat_member_action AS ENUM(
+  'join',
+  'leave',
+  'kick'
+);
+
+-- create chat member history table
+CREATE TABLE IF NOT EXISTS chat_member_history(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL REFERENCES chats(id),
+  user_id bigint NOT NULL REFERENCES users(id),
+  -- who performed the change, same as user_id for self join / leave
+  actor_id bigint NOT NULL REFERENCES users(id),
+  action chat_member_action NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for chat member history for chat_id and created_at order by created_at desc
+CREATE INDEX IF NOT EXISTS chat_member_history_chat_id_index ON chat_member_history(chat_id, created_at DESC);
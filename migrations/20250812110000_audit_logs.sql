@@ -0,0 +1,27 @@
+-- create audit action type
+CREATE TYPE audit_action AS ENUM(
+  'signin',
+  'chat_created',
+  'chat_deleted',
+  'member_added',
+  'member_removed',
+  'role_changed'
+);
+
+-- create audit log table
+CREATE TABLE IF NOT EXISTS audit_logs(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  actor_id bigint NOT NULL REFERENCES users(id),
+  action audit_action NOT NULL,
+  -- id of the chat / user the action was applied to, if any
+  target_id bigint,
+  details jsonb NOT NULL DEFAULT '{}',
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for audit logs for ws_id and created_at order by created_at desc
+CREATE INDEX IF NOT EXISTS audit_logs_ws_id_created_at_index ON audit_logs(ws_id, created_at DESC);
+
+-- create index for audit logs for actor_id
+CREATE INDEX IF NOT EXISTS audit_logs_actor_id_index ON audit_logs(actor_id, created_at DESC);
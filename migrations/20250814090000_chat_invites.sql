@@ -0,0 +1,15 @@
+-- create chat invite link table
+CREATE TABLE IF NOT EXISTS chat_invites(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL REFERENCES chats(id),
+  token varchar(64) NOT NULL UNIQUE,
+  created_by bigint NOT NULL REFERENCES users(id),
+  -- null means never expires / unlimited uses
+  expires_at timestamptz,
+  max_uses integer,
+  uses integer NOT NULL DEFAULT 0,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for chat invites for chat_id
+CREATE INDEX IF NOT EXISTS chat_invites_chat_id_index ON chat_invites(chat_id);
@@ -0,0 +1,15 @@
+-- max message length in characters, per workspace
+ALTER TABLE workspaces
+    ADD COLUMN max_message_length integer NOT NULL DEFAULT 65536;
+
+-- number of extra chunks stored in message_chunks, 0 for messages that fit in one row
+ALTER TABLE messages
+    ADD COLUMN chunks integer NOT NULL DEFAULT 0;
+
+-- create message chunk table, messages.content holds chunk 0
+CREATE TABLE IF NOT EXISTS message_chunks(
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  idx integer NOT NULL,
+  content text NOT NULL,
+  PRIMARY KEY (message_id, idx)
+);
@@ -0,0 +1,13 @@
+-- whether the channel shows up in the channel browser for non members
+ALTER TABLE chats
+    ADD COLUMN discoverable boolean NOT NULL DEFAULT false;
+
+UPDATE
+    chats
+SET
+    discoverable = true
+WHERE
+    type = 'public_channel';
+
+-- create index for chats for ws_id of discoverable channels
+CREATE INDEX IF NOT EXISTS chats_discoverable_index ON chats(ws_id) WHERE discoverable;
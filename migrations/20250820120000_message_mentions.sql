@@ -0,0 +1,10 @@
+-- users mentioned in a message with <@user_id>, read state is independent of the chat
+CREATE TABLE IF NOT EXISTS message_mentions(
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  read_at timestamptz,
+  PRIMARY KEY (message_id, user_id)
+);
+
+-- create index for message mentions for user_id and message_id order by message_id desc
+CREATE INDEX IF NOT EXISTS message_mentions_user_id_index ON message_mentions(user_id, message_id DESC);
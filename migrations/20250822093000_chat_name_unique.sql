@@ -0,0 +1,24 @@
+-- names that only differ in case get the chat id appended, on all but the oldest chat of
+-- each, so that the index below can be created
+UPDATE chats c
+SET name = left(c.name, 64 - length(d.suffix)) || d.suffix
+FROM (
+    SELECT id, ' (' || id || ')' AS suffix,
+        row_number() OVER (PARTITION BY ws_id, lower(name) ORDER BY id) AS n
+    FROM chats
+    WHERE name IS NOT NULL
+) d
+WHERE c.id = d.id AND d.n > 1;
+
+-- channel names are unique within a workspace, case insensitive
+CREATE UNIQUE INDEX IF NOT EXISTS chats_ws_id_name_index ON chats(ws_id, lower(name)) WHERE name IS NOT NULL;
+
+-- create message kind type: user, system
+CREATE TYPE message_kind AS ENUM(
+  'user',
+  'system'
+);
+
+-- system messages record chat events like renames in the timeline
+ALTER TABLE messages
+    ADD COLUMN kind message_kind NOT NULL DEFAULT 'user';
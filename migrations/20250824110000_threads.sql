@@ -0,0 +1,35 @@
+-- replies point at the root message of their thread
+ALTER TABLE messages
+    ADD COLUMN thread_id bigint REFERENCES messages(id);
+
+-- create index for messages for thread_id
+CREATE INDEX IF NOT EXISTS messages_thread_id_index ON messages(thread_id, seq) WHERE thread_id IS NOT NULL;
+
+-- create thread subscription table, unfollowing keeps the row with following = false
+-- so that the root author is not re-subscribed by later replies
+CREATE TABLE IF NOT EXISTS thread_subscriptions(
+  thread_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  following boolean NOT NULL DEFAULT true,
+  updated_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (thread_id, user_id)
+);
+
+-- create notification kind type
+CREATE TYPE notification_kind AS ENUM(
+  'thread_reply'
+);
+
+-- create notification table
+CREATE TABLE IF NOT EXISTS notifications(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  kind notification_kind NOT NULL,
+  chat_id bigint NOT NULL REFERENCES chats(id),
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  read_at timestamptz,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for notifications for user_id order by id desc
+CREATE INDEX IF NOT EXISTS notifications_user_id_index ON notifications(user_id, id DESC);
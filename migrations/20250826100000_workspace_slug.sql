@@ -0,0 +1,40 @@
+-- url safe identifier derived from the workspace name
+ALTER TABLE workspaces
+    ADD COLUMN slug varchar(64);
+
+UPDATE
+    workspaces
+SET
+    slug = trim(BOTH '-' FROM regexp_replace(lower(name), '[^a-z0-9]+', '-', 'g'));
+
+UPDATE
+    workspaces
+SET
+    slug = 'ws-' || id
+WHERE
+    slug = '';
+
+-- names that differ only in case or punctuation give the same slug, all but the oldest
+-- workspace get their id appended
+UPDATE
+    workspaces w
+SET
+    slug = left(w.slug, 64 - length(d.suffix)) || d.suffix
+FROM (
+    SELECT id, '-' || id AS suffix, row_number() OVER (PARTITION BY slug ORDER BY id) AS n
+    FROM workspaces
+) d
+WHERE
+    w.id = d.id AND d.n > 1;
+
+ALTER TABLE workspaces
+    ALTER COLUMN slug SET NOT NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS workspaces_slug_index ON workspaces(slug);
+
+-- previous slugs of renamed workspaces, lookups by them redirect to the current slug
+CREATE TABLE IF NOT EXISTS workspace_slug_aliases(
+  slug varchar(64) PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,11 @@
+-- create message reaction table
+CREATE TABLE IF NOT EXISTS message_reactions(
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  emoji varchar(64) NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (message_id, user_id, emoji)
+);
+
+-- create index for chat member history for user_id, used by the activity feed
+CREATE INDEX IF NOT EXISTS chat_member_history_user_id_index ON chat_member_history(user_id, created_at DESC);
@@ -0,0 +1,16 @@
+-- create refresh token table, tokens issued from one signin share a family
+CREATE TABLE IF NOT EXISTS refresh_tokens(
+  id bigserial PRIMARY KEY,
+  family_id varchar(64) NOT NULL,
+  user_id bigint NOT NULL REFERENCES users(id),
+  -- sha256 of the token, the token itself is never stored
+  token_hash bytea NOT NULL UNIQUE,
+  expires_at timestamptz NOT NULL,
+  -- set once the token has been exchanged for a new one
+  used_at timestamptz,
+  revoked_at timestamptz,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for refresh tokens for family_id
+CREATE INDEX IF NOT EXISTS refresh_tokens_family_id_index ON refresh_tokens(family_id);
@@ -0,0 +1,7 @@
+-- create revoked access token table, rows can be dropped once the token has expired
+CREATE TABLE IF NOT EXISTS revoked_tokens(
+  jti varchar(64) PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  expires_at timestamptz NOT NULL,
+  revoked_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,33 @@
+-- create workspace webhook table, security events are posted to every webhook of the workspace
+CREATE TABLE IF NOT EXISTS workspace_webhooks(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  url varchar(1024) NOT NULL,
+  -- shared secret used to sign the payloads
+  secret varchar(64) NOT NULL,
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for workspace webhooks for ws_id
+CREATE INDEX IF NOT EXISTS workspace_webhooks_ws_id_index ON workspace_webhooks(ws_id);
+
+-- create known devices table, a device is identified by the hash of its user agent
+CREATE TABLE IF NOT EXISTS user_devices(
+  user_id bigint NOT NULL REFERENCES users(id),
+  fingerprint bytea NOT NULL,
+  user_agent text NOT NULL,
+  first_seen_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_seen_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, fingerprint)
+);
+
+-- create failed signin table
+CREATE TABLE IF NOT EXISTS signin_failures(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for signin failures for user_id and created_at
+CREATE INDEX IF NOT EXISTS signin_failures_user_id_created_at_index ON signin_failures(user_id, created_at DESC);
@@ -0,0 +1,14 @@
+-- add session lifetime policy to workspaces, in seconds
+ALTER TABLE workspaces
+    ADD COLUMN session_idle_timeout integer NOT NULL DEFAULT 2592000,
+    ADD COLUMN session_max_age integer NOT NULL DEFAULT 2592000,
+    ADD COLUMN session_remember_me_max_age integer NOT NULL DEFAULT 7776000;
+
+-- track when the session a refresh token belongs to started
+ALTER TABLE refresh_tokens
+    ADD COLUMN session_started_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ADD COLUMN remember_me boolean NOT NULL DEFAULT FALSE;
+
+UPDATE refresh_tokens t SET session_started_at = (
+    SELECT MIN(created_at) FROM refresh_tokens f WHERE f.family_id = t.family_id
+);
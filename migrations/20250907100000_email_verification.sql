@@ -0,0 +1,29 @@
+-- add email verification to users, existing users are considered verified
+ALTER TABLE users ADD COLUMN email_verified_at timestamptz;
+UPDATE users SET email_verified_at = created_at;
+
+-- create email verification token table
+CREATE TABLE IF NOT EXISTS email_verifications(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  token_hash bytea NOT NULL UNIQUE,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for email verifications for user_id
+CREATE INDEX IF NOT EXISTS email_verifications_user_id_index ON email_verifications(user_id);
+
+-- create outgoing email table, a relay picks up unsent emails and delivers them
+CREATE TABLE IF NOT EXISTS outbound_emails(
+  id bigserial PRIMARY KEY,
+  from_email varchar(256) NOT NULL,
+  to_email varchar(256) NOT NULL,
+  subject varchar(256) NOT NULL,
+  body text NOT NULL,
+  sent_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for outbound emails that have not been sent
+CREATE INDEX IF NOT EXISTS outbound_emails_unsent_index ON outbound_emails(id) WHERE sent_at IS NULL;
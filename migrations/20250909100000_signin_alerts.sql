@@ -0,0 +1,33 @@
+-- force users to pick a new password before they can sign in again
+ALTER TABLE users ADD COLUMN password_reset_required boolean NOT NULL DEFAULT FALSE;
+
+-- create known signin countries table
+CREATE TABLE IF NOT EXISTS user_signin_countries(
+  user_id bigint NOT NULL REFERENCES users(id),
+  country varchar(8) NOT NULL,
+  first_seen_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_seen_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, country)
+);
+
+-- create signin alert table, the token backs the "this wasn't me" link
+CREATE TABLE IF NOT EXISTS signin_alerts(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  token_hash bytea NOT NULL UNIQUE,
+  details jsonb NOT NULL DEFAULT '{}',
+  denied_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create password reset token table
+CREATE TABLE IF NOT EXISTS password_resets(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  token_hash bytea NOT NULL UNIQUE,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for password resets for user_id
+CREATE INDEX IF NOT EXISTS password_resets_user_id_index ON password_resets(user_id);
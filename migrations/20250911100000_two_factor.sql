@@ -0,0 +1,31 @@
+-- create totp table, a secret is pending until the user confirms a code from it
+CREATE TABLE IF NOT EXISTS user_totp(
+  user_id bigint PRIMARY KEY REFERENCES users(id),
+  secret varchar(64) NOT NULL,
+  enabled_at timestamptz,
+  -- time step of the last accepted code, codes cannot be replayed
+  last_used_step bigint NOT NULL DEFAULT 0,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create recovery code table
+CREATE TABLE IF NOT EXISTS totp_recovery_codes(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  code_hash bytea NOT NULL,
+  used_at timestamptz
+);
+
+-- create index for recovery codes for user_id
+CREATE INDEX IF NOT EXISTS totp_recovery_codes_user_id_index ON totp_recovery_codes(user_id);
+
+-- create signin challenge table, issued after the password check when 2fa is enabled
+CREATE TABLE IF NOT EXISTS signin_challenges(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  token_hash bytea NOT NULL UNIQUE,
+  remember_me boolean NOT NULL DEFAULT FALSE,
+  attempts integer NOT NULL DEFAULT 0,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,15 @@
+-- add max pins per chat to workspaces
+ALTER TABLE workspaces ADD COLUMN max_pins_per_chat integer NOT NULL DEFAULT 50;
+
+-- create pinned message table, position orders the pinned bar from 0
+CREATE TABLE IF NOT EXISTS chat_pins(
+  chat_id bigint NOT NULL REFERENCES chats(id),
+  message_id bigint NOT NULL REFERENCES messages(id),
+  position integer NOT NULL,
+  pinned_by bigint NOT NULL REFERENCES users(id),
+  pinned_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, message_id)
+);
+
+-- create index for chat pins for chat_id and position
+CREATE INDEX IF NOT EXISTS chat_pins_chat_id_position_index ON chat_pins(chat_id, position);
@@ -0,0 +1,26 @@
+-- create passkey table, public keys are stored as uncompressed SEC1 points
+CREATE TABLE IF NOT EXISTS user_passkeys(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  credential_id bytea NOT NULL UNIQUE,
+  public_key bytea NOT NULL,
+  sign_count bigint NOT NULL DEFAULT 0,
+  name varchar(64) NOT NULL,
+  last_used_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for passkeys for user_id
+CREATE INDEX IF NOT EXISTS user_passkeys_user_id_index ON user_passkeys(user_id);
+
+-- create webauthn ceremony table, a ceremony is found by the challenge the client signed
+CREATE TABLE IF NOT EXISTS webauthn_ceremonies(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  challenge varchar(64) NOT NULL UNIQUE,
+  -- 'register' or 'signin'
+  kind varchar(16) NOT NULL,
+  remember_me boolean NOT NULL DEFAULT FALSE,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,37 @@
+-- create custom emoji table, reactions refer to them as :name:
+CREATE TABLE IF NOT EXISTS workspace_emoji(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  name varchar(32) NOT NULL,
+  image_url varchar(1024) NOT NULL,
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (ws_id, name)
+);
+
+-- skin tone 1 to 5 (U+1F3FB to U+1F3FF), stripped from the emoji so reactions aggregate
+ALTER TABLE message_reactions ADD COLUMN IF NOT EXISTS skin_tone smallint;
+
+-- same as normalize_emoji in the server: drop presentation selectors, split off a single skin tone
+CREATE FUNCTION pg_temp.normalize_emoji(emoji text) RETURNS text AS $$
+  SELECT CASE
+    WHEN array_length(regexp_split_to_array(e, '[\U0001F3FB-\U0001F3FF]'), 1) = 2
+      THEN regexp_replace(e, '[\U0001F3FB-\U0001F3FF]', '', 'g')
+    ELSE e
+  END
+  FROM (SELECT regexp_replace(emoji, '[\uFE0E\uFE0F]', '', 'g') AS e) AS s
+$$ LANGUAGE sql IMMUTABLE;
+
+-- keep the earliest of reactions that only differ in presentation or skin tone
+DELETE FROM message_reactions r
+USING message_reactions d
+WHERE r.message_id = d.message_id AND r.user_id = d.user_id
+  AND pg_temp.normalize_emoji(r.emoji) = pg_temp.normalize_emoji(d.emoji)
+  AND (r.created_at, r.emoji) > (d.created_at, d.emoji);
+
+UPDATE message_reactions SET
+  skin_tone = CASE
+    WHEN pg_temp.normalize_emoji(emoji) <> regexp_replace(emoji, '[\uFE0E\uFE0F]', '', 'g')
+      THEN ascii(substring(emoji FROM '[\U0001F3FB-\U0001F3FF]')) - x'1F3FA'::int
+  END,
+  emoji = pg_temp.normalize_emoji(emoji);
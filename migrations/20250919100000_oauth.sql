@@ -0,0 +1,29 @@
+-- users signed up through a social login have no password
+ALTER TABLE users ALTER COLUMN password_hash DROP NOT NULL;
+
+-- new users from a social login with an email at this domain join the workspace
+ALTER TABLE workspaces ADD COLUMN IF NOT EXISTS signup_domain varchar(255) UNIQUE;
+
+-- create oauth state table, one per authorization request, holds the PKCE verifier
+CREATE TABLE IF NOT EXISTS oauth_states(
+  id bigserial PRIMARY KEY,
+  state_hash bytea NOT NULL UNIQUE,
+  provider varchar(16) NOT NULL,
+  code_verifier varchar(128) NOT NULL,
+  remember_me boolean NOT NULL DEFAULT FALSE,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create user identity table, links provider accounts to users
+CREATE TABLE IF NOT EXISTS user_identities(
+  provider varchar(16) NOT NULL,
+  subject varchar(255) NOT NULL,
+  user_id bigint NOT NULL REFERENCES users(id),
+  email varchar(64) NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (provider, subject)
+);
+
+-- create index for user identities for user_id
+CREATE INDEX IF NOT EXISTS user_identities_user_id_index ON user_identities(user_id);
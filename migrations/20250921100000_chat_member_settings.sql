@@ -0,0 +1,15 @@
+-- create notification level type: all, mentions, none
+CREATE TYPE notification_level AS ENUM(
+  'all',
+  'mentions',
+  'none'
+);
+
+-- create per member chat settings, members without a row use the defaults
+CREATE TABLE IF NOT EXISTS chat_member_settings(
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  notification_level notification_level NOT NULL DEFAULT 'all',
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, user_id)
+);
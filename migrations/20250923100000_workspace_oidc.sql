@@ -0,0 +1,20 @@
+-- create workspace oidc table, the identity provider members sign in with
+CREATE TABLE IF NOT EXISTS workspace_oidc(
+  ws_id bigint PRIMARY KEY REFERENCES workspaces(id),
+  issuer varchar(255) NOT NULL,
+  client_id varchar(255) NOT NULL,
+  client_secret varchar(255) NOT NULL,
+  -- create users on their first signin, otherwise only existing members can sign in
+  jit_provisioning boolean NOT NULL DEFAULT TRUE,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- oidc signins are bound to a workspace and carry a nonce for the id token
+ALTER TABLE oauth_states
+  ADD COLUMN IF NOT EXISTS ws_id bigint REFERENCES workspaces(id),
+  ADD COLUMN IF NOT EXISTS nonce varchar(64);
+
+-- oidc identities are per workspace, e.g. oidc:42
+ALTER TABLE oauth_states ALTER COLUMN provider TYPE varchar(32);
+ALTER TABLE user_identities ALTER COLUMN provider TYPE varchar(32);
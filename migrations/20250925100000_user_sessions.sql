@@ -0,0 +1,25 @@
+-- create sessions table, a session is a refresh token family
+CREATE TABLE IF NOT EXISTS user_sessions(
+  id bigserial PRIMARY KEY,
+  family_id varchar(64) NOT NULL UNIQUE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  device_name varchar(64) NOT NULL,
+  user_agent text NOT NULL,
+  ip varchar(64),
+  -- latest access token, revoked along with the session
+  access_jti varchar(64),
+  access_expires_at timestamptz,
+  -- when the access token was last renewed
+  last_active_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for sessions for user_id
+CREATE INDEX IF NOT EXISTS user_sessions_user_id_index ON user_sessions(user_id);
+
+-- sessions started before devices were tracked
+INSERT INTO user_sessions (family_id, user_id, device_name, user_agent, last_active_at, created_at)
+SELECT family_id, user_id, 'Unknown device', '', MAX(created_at), MIN(session_started_at)
+FROM refresh_tokens
+GROUP BY family_id, user_id
+ON CONFLICT (family_id) DO NOTHING;
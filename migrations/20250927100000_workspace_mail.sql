@@ -0,0 +1,22 @@
+-- create workspace mail settings table, the smtp server and sender a workspace's emails go out with
+CREATE TABLE IF NOT EXISTS workspace_mail(
+  ws_id bigint PRIMARY KEY REFERENCES workspaces(id),
+  smtp_host varchar(255) NOT NULL,
+  smtp_port integer NOT NULL,
+  smtp_username varchar(255) NOT NULL,
+  smtp_password varchar(255) NOT NULL,
+  from_email varchar(254) NOT NULL,
+  from_name varchar(64),
+  -- sha256 of the token mailed to from_email through the smtp server
+  verification_token_hash bytea,
+  -- only verified settings are used, until then the instance mailer is
+  verified_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- the relay sends emails of a workspace through its smtp server
+ALTER TABLE outbound_emails ADD COLUMN IF NOT EXISTS ws_id bigint REFERENCES workspaces(id);
+
+-- room for a sender name, e.g. "Acme Chat" <noreply@acme.org>
+ALTER TABLE outbound_emails ALTER COLUMN from_email TYPE varchar(320);
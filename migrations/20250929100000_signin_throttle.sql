@@ -0,0 +1,11 @@
+-- failed signins are also counted for emails without an account, and per ip
+ALTER TABLE signin_failures
+  ALTER COLUMN user_id DROP NOT NULL,
+  ADD COLUMN IF NOT EXISTS email varchar(64),
+  ADD COLUMN IF NOT EXISTS ip varchar(64);
+
+UPDATE signin_failures f SET email = lower(u.email) FROM users u WHERE u.id = f.user_id;
+
+-- create index for signin failures for email and ip
+CREATE INDEX IF NOT EXISTS signin_failures_email_created_at_index ON signin_failures(email, created_at DESC);
+CREATE INDEX IF NOT EXISTS signin_failures_ip_created_at_index ON signin_failures(ip, created_at DESC);
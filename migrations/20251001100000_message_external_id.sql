@@ -0,0 +1,7 @@
+-- id of the message in the system it came from (email gateway, bridge...), the same message
+-- arriving through several paths is only stored once
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS external_id varchar(128);
+
+-- create unique index for external id per chat
+CREATE UNIQUE INDEX IF NOT EXISTS messages_chat_id_external_id_index ON messages(chat_id, external_id)
+  WHERE external_id IS NOT NULL;
@@ -0,0 +1,28 @@
+-- people on the other side of a bridge are shown through ghost users, with their remote
+-- name and avatar
+ALTER TABLE users ADD COLUMN IF NOT EXISTS avatar_url varchar(512);
+
+-- create bridge kind type
+CREATE TYPE bridge_kind AS ENUM(
+  'matrix',
+  'slack',
+  'email'
+);
+
+-- create bridge identity table, maps remote identities to the users their messages are sent as
+CREATE TABLE IF NOT EXISTS bridge_identities(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  bridge bridge_kind NOT NULL,
+  -- matrix user id, slack user id or email address
+  remote_id varchar(255) NOT NULL,
+  user_id bigint NOT NULL REFERENCES users(id),
+  -- whether the user was created for this identity, only ghosts follow the remote name and avatar
+  ghost boolean NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (ws_id, bridge, remote_id)
+);
+
+-- create index for bridge identities for user_id
+CREATE INDEX IF NOT EXISTS bridge_identities_user_id_index ON bridge_identities(user_id);
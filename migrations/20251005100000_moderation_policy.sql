@@ -0,0 +1,14 @@
+-- create moderation mode type: off, reject, mask
+CREATE TYPE moderation_mode AS ENUM(
+  'off',
+  'reject',
+  'mask'
+);
+
+-- what happens to messages using one of the workspace's blocked terms
+ALTER TABLE workspaces
+    ADD COLUMN moderation_mode moderation_mode NOT NULL DEFAULT 'off',
+    ADD COLUMN moderation_terms text[] NOT NULL DEFAULT '{}';
+
+-- the content as written for masked messages, only shown to moderators
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS original_content text;
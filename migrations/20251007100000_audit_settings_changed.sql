@@ -0,0 +1,2 @@
+-- admin changes to workspace settings and policies, details hold the before / after of each changed field
+ALTER TYPE audit_action ADD VALUE IF NOT EXISTS 'settings_changed';
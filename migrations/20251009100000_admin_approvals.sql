@@ -0,0 +1,42 @@
+-- create workspace role type: member, admin. The workspace owner is always an admin
+CREATE TYPE workspace_role AS ENUM(
+  'member',
+  'admin'
+);
+
+ALTER TABLE users
+    ADD COLUMN role workspace_role NOT NULL DEFAULT 'member',
+    -- deactivated users can't sign in and their sessions are revoked, their messages are kept
+    ADD COLUMN deactivated_at timestamptz;
+
+ALTER TABLE workspaces
+    -- destructive admin actions wait for a second admin to approve them
+    ADD COLUMN require_approval boolean NOT NULL DEFAULT FALSE,
+    ADD COLUMN deleted_at timestamptz;
+
+-- create approval status type: pending, approved, rejected
+CREATE TYPE approval_status AS ENUM(
+  'pending',
+  'approved',
+  'rejected'
+);
+
+-- create admin approvals table, pending requests past expires_at can no longer be approved
+CREATE TABLE IF NOT EXISTS admin_approvals(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  -- the destructive action to run once approved, e.g. {"kind": "delete_workspace"}
+  action jsonb NOT NULL,
+  requested_by bigint NOT NULL REFERENCES users(id),
+  status approval_status NOT NULL DEFAULT 'pending',
+  decided_by bigint REFERENCES users(id),
+  decided_at timestamptz,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS admin_approvals_ws_id_index ON admin_approvals(ws_id, created_at DESC);
+
+-- audit actions for approval requests and decisions
+ALTER TYPE audit_action ADD VALUE IF NOT EXISTS 'approval_requested';
+ALTER TYPE audit_action ADD VALUE IF NOT EXISTS 'approval_decided';
@@ -0,0 +1,2 @@
+-- admins copying the workspace, target_id is the new workspace
+ALTER TYPE audit_action ADD VALUE IF NOT EXISTS 'workspace_cloned';
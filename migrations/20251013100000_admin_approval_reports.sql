@@ -0,0 +1,2 @@
+-- what an approved destructive action deleted, the same report a dry run gives beforehand
+ALTER TABLE admin_approvals ADD COLUMN IF NOT EXISTS report jsonb;
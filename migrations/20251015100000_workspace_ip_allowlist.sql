@@ -0,0 +1,3 @@
+-- CIDR ranges the workspace may be used from, e.g. 10.0.0.0/8. Empty allows any address
+ALTER TABLE workspaces
+    ADD COLUMN ip_allowlist text[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,27 @@
+-- create auth event kind type
+CREATE TYPE auth_event_kind AS ENUM(
+  'signin',
+  'signin_failed',
+  'password_changed',
+  'token_refreshed',
+  'two_factor_enabled',
+  'two_factor_disabled',
+  'two_factor_failed',
+  'recovery_codes_regenerated'
+);
+
+-- create auth events table, what happened to a user's credentials and from where
+CREATE TABLE IF NOT EXISTS auth_events(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  user_id bigint NOT NULL REFERENCES users(id),
+  kind auth_event_kind NOT NULL,
+  ip text,
+  user_agent text NOT NULL,
+  details jsonb NOT NULL DEFAULT '{}',
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS auth_events_user_id_index ON auth_events(user_id, created_at DESC);
+
+CREATE INDEX IF NOT EXISTS auth_events_ws_id_index ON auth_events(ws_id, created_at DESC);
@@ -0,0 +1,13 @@
+-- create magic link table, each link signs its user in once without a password
+CREATE TABLE IF NOT EXISTS magic_links(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  token_hash bytea NOT NULL UNIQUE,
+  remember_me boolean NOT NULL DEFAULT FALSE,
+  expires_at timestamptz NOT NULL,
+  used_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for magic links for user_id
+CREATE INDEX IF NOT EXISTS magic_links_user_id_index ON magic_links(user_id, created_at DESC);
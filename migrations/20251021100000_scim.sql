@@ -0,0 +1,28 @@
+-- identity providers provision members through scim with a per workspace bearer token,
+-- only its hash is kept
+ALTER TABLE workspaces
+    ADD COLUMN scim_token_hash bytea UNIQUE,
+    ADD COLUMN scim_token_created_at timestamptz;
+
+-- the identity provider's own id for the member
+ALTER TABLE users ADD COLUMN scim_external_id text;
+
+-- create scim group table, every group is backed by a channel its members are added to
+CREATE TABLE IF NOT EXISTS scim_groups(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  chat_id bigint NOT NULL UNIQUE REFERENCES chats(id),
+  display_name varchar(64) NOT NULL,
+  external_id text,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS scim_groups_ws_id_index ON scim_groups(ws_id);
+
+-- create scim group member table, channel members who were not added by the group are not listed
+CREATE TABLE IF NOT EXISTS scim_group_members(
+  group_id bigint NOT NULL REFERENCES scim_groups(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  PRIMARY KEY (group_id, user_id)
+);
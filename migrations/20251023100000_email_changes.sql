@@ -0,0 +1,14 @@
+-- create email changes table, the new address only replaces the current one once confirmed
+CREATE TABLE IF NOT EXISTS email_changes(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  new_email varchar(64) NOT NULL,
+  token_hash bytea NOT NULL UNIQUE,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- a user has at most one pending change, a new request replaces it
+CREATE UNIQUE INDEX IF NOT EXISTS email_changes_user_id_index ON email_changes(user_id);
+
+ALTER TYPE auth_event_kind ADD VALUE IF NOT EXISTS 'email_changed';
@@ -0,0 +1,7 @@
+-- create event outbox table, events are written in the transaction that caused them and
+-- published by a relay afterwards, so a crash between commit and publish loses nothing
+CREATE TABLE IF NOT EXISTS event_outbox(
+  id bigserial PRIMARY KEY,
+  event jsonb NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,103 @@
+-- partition messages by month of created_at, so old months can be detached or dropped as a
+-- whole instead of deleted row by row. Primary and unique keys of a partitioned table have to
+-- include created_at, so foreign keys can no longer point at messages(id).
+
+ALTER TABLE message_chunks DROP CONSTRAINT IF EXISTS message_chunks_message_id_fkey;
+ALTER TABLE message_mentions DROP CONSTRAINT IF EXISTS message_mentions_message_id_fkey;
+ALTER TABLE message_reactions DROP CONSTRAINT IF EXISTS message_reactions_message_id_fkey;
+ALTER TABLE thread_subscriptions DROP CONSTRAINT IF EXISTS thread_subscriptions_thread_id_fkey;
+ALTER TABLE notifications DROP CONSTRAINT IF EXISTS notifications_message_id_fkey;
+ALTER TABLE chat_pins DROP CONSTRAINT IF EXISTS chat_pins_message_id_fkey;
+ALTER TABLE messages DROP CONSTRAINT IF EXISTS messages_thread_id_fkey;
+
+ALTER TABLE messages RENAME TO messages_unpartitioned;
+-- keep the id sequence when the old table is dropped
+ALTER SEQUENCE messages_id_seq OWNED BY NONE;
+
+CREATE TABLE messages(
+  id bigint NOT NULL DEFAULT nextval('messages_id_seq'),
+  chat_id bigint NOT NULL,
+  sender_id bigint NOT NULL,
+  content text NOT NULL,
+  images text[],
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  seq bigint NOT NULL,
+  client_created_at timestamptz,
+  chunks integer NOT NULL DEFAULT 0,
+  kind message_kind NOT NULL DEFAULT 'user',
+  thread_id bigint,
+  external_id varchar(128),
+  original_content text,
+  PRIMARY KEY (id, created_at)
+) PARTITION BY RANGE (created_at);
+
+ALTER SEQUENCE messages_id_seq OWNED BY messages.id;
+
+-- creates the partition holding the month of `day`, months start at midnight utc
+CREATE OR REPLACE FUNCTION create_messages_partition(day date) RETURNS text AS $$
+DECLARE
+  start_at date := date_trunc('month', day)::date;
+  name text := format('messages_%s', to_char(start_at, 'YYYY_MM'));
+BEGIN
+  EXECUTE format(
+    'CREATE TABLE IF NOT EXISTS %I PARTITION OF messages FOR VALUES FROM (%L) TO (%L)',
+    name,
+    start_at::timestamp AT TIME ZONE 'UTC',
+    (start_at + interval '1 month')::timestamp AT TIME ZONE 'UTC'
+  );
+  RETURN name;
+END;
+$$ LANGUAGE plpgsql;
+
+-- every month with messages, up to the next one
+SELECT create_messages_partition(m::date)
+FROM generate_series(
+  date_trunc('month', LEAST((SELECT min(created_at) FROM messages_unpartitioned), NOW()) AT TIME ZONE 'UTC'),
+  date_trunc('month', NOW() AT TIME ZONE 'UTC') + interval '1 month',
+  interval '1 month'
+) AS m;
+
+INSERT INTO messages (id, chat_id, sender_id, content, images, created_at, seq, client_created_at, chunks,
+  kind, thread_id, external_id, original_content)
+SELECT id, chat_id, sender_id, content, images, COALESCE(created_at, NOW()), seq, client_created_at, chunks,
+  kind, thread_id, external_id, original_content
+FROM messages_unpartitioned;
+
+DROP TABLE messages_unpartitioned;
+
+ALTER TABLE messages
+    ADD FOREIGN KEY (chat_id) REFERENCES chats(id),
+    ADD FOREIGN KEY (sender_id) REFERENCES users(id);
+
+-- create index for messages for chat_id and created_at order by created_at desc
+CREATE INDEX IF NOT EXISTS chat_id_created_at_index ON messages(chat_id, created_at DESC);
+
+-- create index for messages for sender_id
+CREATE INDEX IF NOT EXISTS sender_id_index ON messages(sender_id, created_at DESC);
+
+-- no longer unique, seq is still gap free per chat as it is bumped under the chat row lock
+CREATE INDEX IF NOT EXISTS chat_id_seq_index ON messages(chat_id, seq DESC);
+
+-- create index for messages for thread_id
+CREATE INDEX IF NOT EXISTS messages_thread_id_index ON messages(thread_id, seq) WHERE thread_id IS NOT NULL;
+
+-- no longer unique, duplicates are caught under the chat row lock when the message is created
+CREATE INDEX IF NOT EXISTS messages_chat_id_external_id_index ON messages(chat_id, external_id)
+  WHERE external_id IS NOT NULL;
+
+-- what the ON DELETE CASCADE foreign keys used to do. Dropped partitions don't fire it, their
+-- dependent rows are removed before the partition goes.
+CREATE OR REPLACE FUNCTION delete_message_dependents() RETURNS trigger AS $$
+BEGIN
+  DELETE FROM message_chunks WHERE message_id = OLD.id;
+  DELETE FROM message_mentions WHERE message_id = OLD.id;
+  DELETE FROM message_reactions WHERE message_id = OLD.id;
+  DELETE FROM thread_subscriptions WHERE thread_id = OLD.id;
+  DELETE FROM notifications WHERE message_id = OLD.id;
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER messages_delete_dependents
+  AFTER DELETE ON messages
+  FOR EACH ROW EXECUTE FUNCTION delete_message_dependents();
@@ -0,0 +1,63 @@
+-- denormalized for the chat list, so the sidebar is one query over chats. The last message is
+-- kept up to date by triggers on messages, thread replies don't count.
+ALTER TABLE chats
+    ADD COLUMN member_count integer GENERATED ALWAYS AS (cardinality(members)) STORED,
+    ADD COLUMN last_message_id bigint,
+    ADD COLUMN last_message_at timestamptz,
+    ADD COLUMN last_message_preview text,
+    ADD COLUMN last_sender_id bigint;
+
+-- unread is chats.last_seq minus this, seq being gap free per chat
+ALTER TABLE chat_member_settings
+    ADD COLUMN last_read_seq bigint NOT NULL DEFAULT 0;
+
+-- create index for chats for members, used with members @> ARRAY[user_id]
+CREATE INDEX IF NOT EXISTS chats_members_index ON chats USING gin(members);
+
+-- the newest top level message of a chat, null fields when there is none
+CREATE OR REPLACE FUNCTION refresh_chat_last_message(target bigint) RETURNS void AS $$
+BEGIN
+  UPDATE chats c
+  SET (last_message_id, last_message_at, last_message_preview, last_sender_id) = (
+    SELECT m.id, m.created_at, left(m.content, 140), m.sender_id
+    FROM (SELECT 1) AS one
+    LEFT JOIN LATERAL (
+      SELECT id, created_at, content, sender_id FROM messages
+      WHERE chat_id = target AND thread_id IS NULL
+      ORDER BY seq DESC LIMIT 1
+    ) m ON true
+  )
+  WHERE c.id = target;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION chat_last_message_inserted() RETURNS trigger AS $$
+BEGIN
+  IF NEW.thread_id IS NULL THEN
+    UPDATE chats
+    SET last_message_id = NEW.id, last_message_at = NEW.created_at,
+        last_message_preview = left(NEW.content, 140), last_sender_id = NEW.sender_id
+    WHERE id = NEW.chat_id;
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION chat_last_message_deleted() RETURNS trigger AS $$
+BEGIN
+  IF EXISTS(SELECT 1 FROM chats WHERE id = OLD.chat_id AND last_message_id = OLD.id) THEN
+    PERFORM refresh_chat_last_message(OLD.chat_id);
+  END IF;
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER chats_last_message_inserted
+  AFTER INSERT ON messages
+  FOR EACH ROW EXECUTE FUNCTION chat_last_message_inserted();
+
+CREATE TRIGGER chats_last_message_deleted
+  AFTER DELETE ON messages
+  FOR EACH ROW EXECUTE FUNCTION chat_last_message_deleted();
+
+SELECT refresh_chat_last_message(id) FROM chats;
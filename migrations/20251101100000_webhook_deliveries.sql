@@ -0,0 +1,37 @@
+-- events a webhook is sent, webhooks registered before had security events only
+CREATE TYPE webhook_event AS ENUM(
+  'security',
+  'message.created',
+  'chat.created',
+  'member.added'
+);
+
+ALTER TABLE workspace_webhooks ADD COLUMN IF NOT EXISTS events webhook_event[] NOT NULL DEFAULT '{security}';
+
+CREATE TYPE webhook_delivery_status AS ENUM(
+  'pending',
+  'succeeded',
+  'failed'
+);
+
+-- create webhook deliveries table, queued in the transaction that made the event and kept as the delivery log
+CREATE TABLE IF NOT EXISTS webhook_deliveries(
+  id bigserial PRIMARY KEY,
+  webhook_id bigint NOT NULL REFERENCES workspace_webhooks(id) ON DELETE CASCADE,
+  event webhook_event NOT NULL,
+  payload jsonb NOT NULL,
+  status webhook_delivery_status NOT NULL DEFAULT 'pending',
+  attempts int NOT NULL DEFAULT 0,
+  -- a claimed delivery is pushed forward, so it is retried if the worker dies mid attempt
+  next_attempt_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  response_status int,
+  last_error text,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  delivered_at timestamptz
+);
+
+-- create index for webhook deliveries that are due
+CREATE INDEX IF NOT EXISTS webhook_deliveries_due_index ON webhook_deliveries(next_attempt_at) WHERE status = 'pending';
+
+-- create index for webhook deliveries for webhook_id
+CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook_id_index ON webhook_deliveries(webhook_id, id DESC);
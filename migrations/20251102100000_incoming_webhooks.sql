@@ -0,0 +1,22 @@
+-- messages posted through an incoming webhook, sender is the user who created the webhook
+ALTER TYPE message_kind ADD VALUE IF NOT EXISTS 'integration';
+
+-- shown instead of the sender's name and avatar for integration messages
+ALTER TABLE messages
+    ADD COLUMN IF NOT EXISTS display_name varchar(64),
+    ADD COLUMN IF NOT EXISTS icon_url varchar(1024);
+
+-- create incoming webhook table, anyone with the url can post into the chat
+CREATE TABLE IF NOT EXISTS incoming_webhooks(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  name varchar(64) NOT NULL,
+  -- sha256 of the token in the url, the token itself is never stored
+  token_hash bytea NOT NULL UNIQUE,
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_used_at timestamptz
+);
+
+-- create index for incoming webhooks for chat_id
+CREATE INDEX IF NOT EXISTS incoming_webhooks_chat_id_index ON incoming_webhooks(chat_id);
@@ -0,0 +1,17 @@
+-- messages sent by a bot, sender is the bot user
+ALTER TYPE message_kind ADD VALUE IF NOT EXISTS 'bot';
+
+-- create bot table, a bot is a workspace user that signs in with its own token instead of a password
+CREATE TABLE IF NOT EXISTS bots(
+  user_id bigint PRIMARY KEY REFERENCES users(id),
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  -- sha256 of the token, the token itself is never stored
+  token_hash bytea NOT NULL UNIQUE,
+  -- what the token allows, a subset of what members get
+  scopes text[] NOT NULL,
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for bots for ws_id
+CREATE INDEX IF NOT EXISTS bots_ws_id_index ON bots(ws_id);
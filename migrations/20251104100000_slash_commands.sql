@@ -0,0 +1,13 @@
+-- create slash command table, /name in a message calls the url instead of sending the message
+CREATE TABLE IF NOT EXISTS slash_commands(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  name varchar(32) NOT NULL,
+  url varchar(1024) NOT NULL,
+  description varchar(256) NOT NULL DEFAULT '',
+  -- shared secret used to sign the calls
+  secret varchar(64) NOT NULL,
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (ws_id, name)
+);
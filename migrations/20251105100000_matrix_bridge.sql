@@ -0,0 +1,42 @@
+-- create matrix bridge table, the workspace's application service registration on a matrix homeserver
+CREATE TABLE IF NOT EXISTS matrix_bridges(
+  ws_id bigint PRIMARY KEY REFERENCES workspaces(id),
+  homeserver_url varchar(1024) NOT NULL,
+  -- the part after the colon in matrix user ids, e.g. example.org
+  server_name varchar(255) NOT NULL,
+  -- the bridge's own matrix user
+  sender_localpart varchar(64) NOT NULL,
+  -- members show up on matrix as @<prefix><user id>:<server name>
+  user_prefix varchar(64) NOT NULL,
+  -- sent to the homeserver with every request
+  as_token varchar(64) NOT NULL,
+  -- sha256 of the token the homeserver sends, it is never stored
+  hs_token_hash bytea NOT NULL UNIQUE,
+  enabled boolean NOT NULL DEFAULT TRUE,
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create matrix room table, chats mirrored to a matrix room
+CREATE TABLE IF NOT EXISTS matrix_rooms(
+  chat_id bigint PRIMARY KEY REFERENCES chats(id) ON DELETE CASCADE,
+  ws_id bigint NOT NULL REFERENCES matrix_bridges(ws_id) ON DELETE CASCADE,
+  room_id varchar(255) NOT NULL,
+  -- messages up to this seq have been sent to the room
+  synced_seq bigint NOT NULL,
+  -- claimed by an instance sending to the room, or waiting to retry after a failure
+  lease_until timestamptz,
+  last_error text,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (ws_id, room_id)
+);
+
+-- create matrix puppet table, members registered on the homeserver to send their messages as
+CREATE TABLE IF NOT EXISTS matrix_puppets(
+  user_id bigint PRIMARY KEY REFERENCES users(id),
+  mxid varchar(255) NOT NULL UNIQUE,
+  -- the name last set on the homeserver
+  display_name varchar(64) NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,14 @@
+-- replies to notification emails go to a signed address of the inbound gateway
+ALTER TABLE outbound_emails ADD COLUMN IF NOT EXISTS reply_to varchar(320);
+
+-- create email notification table, mentions and direct messages emailed if still unread after a delay
+CREATE TABLE IF NOT EXISTS email_notifications(
+  -- no foreign key, messages are partitioned; rows of deleted messages are dropped when due
+  message_id bigint NOT NULL,
+  user_id bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (message_id, user_id)
+);
+
+-- create index for email notifications by created_at, used to find the due ones
+CREATE INDEX IF NOT EXISTS email_notifications_created_at_index ON email_notifications(created_at);
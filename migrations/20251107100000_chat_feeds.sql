@@ -0,0 +1,13 @@
+-- create chat feed table, public channels whose feed anyone can read without a token
+CREATE TABLE IF NOT EXISTS chat_feeds(
+  chat_id bigint PRIMARY KEY REFERENCES chats(id) ON DELETE CASCADE,
+  enabled_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create feed key table, signs the feed tokens of a member; replacing it revokes them all
+CREATE TABLE IF NOT EXISTS feed_keys(
+  user_id bigint PRIMARY KEY REFERENCES users(id),
+  secret varchar(64) NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
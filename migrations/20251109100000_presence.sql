@@ -0,0 +1,10 @@
+-- users with an event stream open, by the instance it is connected to. Each instance refreshes
+-- its rows, those of an instance that went away without removing them expire.
+CREATE TABLE IF NOT EXISTS event_stream_presence(
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  instance_id text NOT NULL,
+  seen_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, instance_id)
+);
+
+CREATE INDEX IF NOT EXISTS event_stream_presence_seen_at_idx ON event_stream_presence(seen_at);
@@ -0,0 +1,13 @@
+-- events as they were published, for clients that poll rather than keep a stream open. Kept
+-- for events.log_retention_hours.
+CREATE TABLE IF NOT EXISTS event_log(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL,
+  -- none for workspace wide events
+  chat_id bigint,
+  event jsonb NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS event_log_ws_id_index ON event_log(ws_id, id);
+CREATE INDEX IF NOT EXISTS event_log_created_at_index ON event_log(created_at);
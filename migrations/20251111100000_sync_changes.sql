@@ -0,0 +1,72 @@
+-- what changed in chats, for clients catching up after being offline with /api/sync. Rows name
+-- what changed, the sync loads how it is now. Written by triggers so no change is missed, and
+-- given a seq by the outbox relay once committed, so a transaction committing late still comes
+-- after what clients have already synced. Kept for events.sync_retention_days.
+CREATE TABLE IF NOT EXISTS sync_changes(
+  id bigserial PRIMARY KEY,
+  -- null until the relay gets to it
+  seq bigint UNIQUE,
+  chat_id bigint NOT NULL,
+  -- message, members or read
+  kind text NOT NULL,
+  message_id bigint,
+  -- for members, everyone in the chat before or after, so users who left hear of it
+  members bigint[],
+  -- for read, whose read state it is
+  user_id bigint,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS sync_changes_pending_index ON sync_changes(id) WHERE seq IS NULL;
+CREATE INDEX IF NOT EXISTS sync_changes_created_at_index ON sync_changes(created_at);
+
+-- created, edited or deleted. Messages of dropped partitions are past retention and not logged.
+CREATE OR REPLACE FUNCTION sync_message_changed() RETURNS trigger AS $$
+DECLARE
+  m messages;
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    m := OLD;
+  ELSE
+    m := NEW;
+  END IF;
+  INSERT INTO sync_changes (chat_id, kind, message_id) VALUES (m.chat_id, 'message', m.id);
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION sync_chat_members_changed() RETURNS trigger AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO sync_changes (chat_id, kind, members) VALUES (NEW.id, 'members', NEW.members);
+  ELSIF TG_OP = 'DELETE' THEN
+    INSERT INTO sync_changes (chat_id, kind, members) VALUES (OLD.id, 'members', OLD.members);
+  ELSIF OLD.members IS DISTINCT FROM NEW.members THEN
+    INSERT INTO sync_changes (chat_id, kind, members)
+    VALUES (NEW.id, 'members', ARRAY(SELECT unnest(OLD.members) UNION SELECT unnest(NEW.members)));
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION sync_read_changed() RETURNS trigger AS $$
+BEGIN
+  IF (TG_OP = 'INSERT' AND NEW.last_read_seq > 0)
+    OR (TG_OP = 'UPDATE' AND OLD.last_read_seq <> NEW.last_read_seq) THEN
+    INSERT INTO sync_changes (chat_id, kind, user_id) VALUES (NEW.chat_id, 'read', NEW.user_id);
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER messages_sync_changed
+  AFTER INSERT OR UPDATE OR DELETE ON messages
+  FOR EACH ROW EXECUTE FUNCTION sync_message_changed();
+
+CREATE TRIGGER chats_sync_members_changed
+  AFTER INSERT OR UPDATE OF members OR DELETE ON chats
+  FOR EACH ROW EXECUTE FUNCTION sync_chat_members_changed();
+
+CREATE TRIGGER chat_member_settings_sync_read_changed
+  AFTER INSERT OR UPDATE OF last_read_seq ON chat_member_settings
+  FOR EACH ROW EXECUTE FUNCTION sync_read_changed();
@@ -0,0 +1,48 @@
+-- create oauth app table, third party apps a workspace admin registered, which users can let
+-- act for them with a token limited to the scopes they consent to
+CREATE TABLE IF NOT EXISTS oauth_apps(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  name varchar(64) NOT NULL,
+  client_id varchar(64) NOT NULL UNIQUE,
+  -- sha256 of the secret, none for public clients (mobile, single page apps) which rely on PKCE
+  client_secret_hash bytea,
+  -- codes are only sent to these, compared exactly
+  redirect_uris text[] NOT NULL,
+  -- the most the app may ask for, a subset of what members get
+  scopes text[] NOT NULL,
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for oauth apps for ws_id
+CREATE INDEX IF NOT EXISTS oauth_apps_ws_id_index ON oauth_apps(ws_id);
+
+-- create oauth code table, one per approved authorization request, single use and short lived
+CREATE TABLE IF NOT EXISTS oauth_codes(
+  code_hash bytea PRIMARY KEY,
+  app_id bigint NOT NULL REFERENCES oauth_apps(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  redirect_uri text NOT NULL,
+  scopes text[] NOT NULL,
+  -- S256 of the verifier the app must show to redeem the code
+  code_challenge varchar(128) NOT NULL,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create oauth grant table, what a user consented to an app doing and the app's refresh token
+-- for them. Deleting it revokes the app, access tokens already issued run out on their own.
+CREATE TABLE IF NOT EXISTS oauth_grants(
+  app_id bigint NOT NULL REFERENCES oauth_apps(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  scopes text[] NOT NULL,
+  -- sha256, replaced on every use, none until the app redeems a code
+  refresh_token_hash bytea UNIQUE,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (app_id, user_id)
+);
+
+-- create index for oauth grants for user_id
+CREATE INDEX IF NOT EXISTS oauth_grants_user_id_index ON oauth_grants(user_id);
@@ -0,0 +1,5 @@
+-- admins deactivating and reactivating members and making them reset their password through
+-- /api/admin, target_id is the member
+ALTER TYPE audit_action ADD VALUE IF NOT EXISTS 'user_deactivated';
+ALTER TYPE audit_action ADD VALUE IF NOT EXISTS 'user_reactivated';
+ALTER TYPE audit_action ADD VALUE IF NOT EXISTS 'password_reset_forced';
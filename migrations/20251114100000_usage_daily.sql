@@ -0,0 +1,17 @@
+-- what each workspace used per day (UTC), for billing and capacity planning. Written by the
+-- rollup job once the day is over, from messages and signins, and exported with
+-- /api/admin/usage.
+CREATE TABLE IF NOT EXISTS usage_daily(
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  day date NOT NULL,
+  -- members who signed in or sent a message that day
+  active_users bigint NOT NULL,
+  -- messages sent that day and still kept
+  messages bigint NOT NULL,
+  -- bytes of message content stored at the end of the day
+  storage_bytes bigint NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (ws_id, day)
+);
+
+CREATE INDEX IF NOT EXISTS usage_daily_day_index ON usage_daily(day);
@@ -0,0 +1,12 @@
+-- create oauth access token table, the access tokens issued to apps until they expire, so that
+-- revoking or deleting the app revokes them as well
+CREATE TABLE IF NOT EXISTS oauth_access_tokens(
+  jti varchar(64) PRIMARY KEY,
+  app_id bigint NOT NULL REFERENCES oauth_apps(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for oauth access tokens for app_id and user_id
+CREATE INDEX IF NOT EXISTS oauth_access_tokens_app_id_user_id_index ON oauth_access_tokens(app_id, user_id);
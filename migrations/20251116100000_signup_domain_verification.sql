@@ -0,0 +1,4 @@
+-- a signup domain takes effect once the workspace proves it owns it with a DNS TXT record
+ALTER TABLE workspaces
+    ADD COLUMN pending_signup_domain varchar(255),
+    ADD COLUMN signup_domain_token varchar(64);
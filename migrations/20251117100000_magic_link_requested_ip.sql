@@ -0,0 +1,6 @@
+-- the ip a magic link was requested from, for the per ip send limit
+ALTER TABLE magic_links
+    ADD COLUMN requested_ip varchar(64);
+
+-- create index for magic links for requested_ip
+CREATE INDEX IF NOT EXISTS magic_links_requested_ip_index ON magic_links(requested_ip, created_at DESC);
@@ -0,0 +1,12 @@
+-- whether the group added the member to its channel. Members who were in the channel before
+-- stay when the group drops them.
+ALTER TABLE scim_group_members
+    ADD COLUMN added_to_chat boolean NOT NULL DEFAULT FALSE;
+
+UPDATE scim_group_members m
+SET added_to_chat = TRUE
+FROM scim_groups g
+WHERE g.id = m.group_id AND EXISTS(
+    SELECT 1 FROM chat_member_history h
+    WHERE h.chat_id = g.chat_id AND h.user_id = m.user_id AND h.actor_id = 0 AND h.action = 'join'
+);
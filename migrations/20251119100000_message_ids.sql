@@ -0,0 +1,37 @@
+-- the primary key of the partitioned messages table has to include created_at, so it no longer
+-- keeps ids unique. Every message claims its id here when it is inserted.
+CREATE TABLE IF NOT EXISTS message_ids(
+  id bigint PRIMARY KEY
+);
+
+INSERT INTO message_ids (id) SELECT id FROM messages;
+
+CREATE OR REPLACE FUNCTION claim_message_id() RETURNS trigger AS $$
+BEGIN
+  INSERT INTO message_ids (id) VALUES (NEW.id);
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER messages_claim_id
+  BEFORE INSERT ON messages
+  FOR EACH ROW EXECUTE FUNCTION claim_message_id();
+
+-- pins of a deleted message go too, the ones after it move up
+CREATE OR REPLACE FUNCTION delete_message_dependents() RETURNS trigger AS $$
+BEGIN
+  DELETE FROM message_chunks WHERE message_id = OLD.id;
+  DELETE FROM message_mentions WHERE message_id = OLD.id;
+  DELETE FROM message_reactions WHERE message_id = OLD.id;
+  DELETE FROM thread_subscriptions WHERE thread_id = OLD.id;
+  DELETE FROM notifications WHERE message_id = OLD.id;
+  WITH unpinned AS (
+    DELETE FROM chat_pins WHERE message_id = OLD.id RETURNING chat_id, position
+  )
+  UPDATE chat_pins p SET position = p.position - 1
+  FROM unpinned u
+  WHERE p.chat_id = u.chat_id AND p.position > u.position;
+  DELETE FROM message_ids WHERE id = OLD.id;
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
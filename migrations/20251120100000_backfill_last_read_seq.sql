@@ -0,0 +1,13 @@
+-- last_read_seq was added as 0 for everyone, which showed members the whole history as unread.
+-- Members who have not read anything since start from the chat's newest message instead.
+UPDATE chat_member_settings s
+SET last_read_seq = c.last_seq
+FROM chats c
+WHERE c.id = s.chat_id AND s.last_read_seq = 0 AND c.last_seq > 0;
+
+-- members without settings count as having read nothing too
+INSERT INTO chat_member_settings (chat_id, user_id, last_read_seq)
+SELECT c.id, m, c.last_seq
+FROM chats c, unnest(c.members) AS m
+WHERE c.last_seq > 0
+ON CONFLICT (chat_id, user_id) DO NOTHING;
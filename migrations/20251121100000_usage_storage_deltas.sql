@@ -0,0 +1,66 @@
+-- bytes of message content added (positive) or removed (negative) per workspace and day (UTC),
+-- booked on the day of the change. The rollup adds a day's changes to the storage of the day
+-- before instead of summing up every message again.
+CREATE TABLE IF NOT EXISTS usage_storage_deltas(
+  ws_id bigint NOT NULL,
+  day date NOT NULL,
+  bytes bigint NOT NULL,
+  PRIMARY KEY (ws_id, day)
+);
+
+CREATE OR REPLACE FUNCTION book_storage_delta(target_chat bigint, delta bigint) RETURNS void AS $$
+BEGIN
+  IF delta <> 0 THEN
+    INSERT INTO usage_storage_deltas (ws_id, day, bytes)
+    SELECT ws_id, (now() AT TIME ZONE 'UTC')::date, delta FROM chats WHERE id = target_chat
+    ON CONFLICT (ws_id, day) DO UPDATE SET bytes = usage_storage_deltas.bytes + EXCLUDED.bytes;
+  END IF;
+END;
+$$ LANGUAGE plpgsql;
+
+-- a deleted message books its chunks too. Its trigger name sorts before
+-- messages_delete_dependents, so the chunks are still there.
+CREATE OR REPLACE FUNCTION messages_book_storage() RETURNS trigger AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    PERFORM book_storage_delta(NEW.chat_id, octet_length(NEW.content));
+  ELSIF TG_OP = 'DELETE' THEN
+    PERFORM book_storage_delta(OLD.chat_id, -(octet_length(OLD.content) + COALESCE(
+      (SELECT SUM(octet_length(content)) FROM message_chunks WHERE message_id = OLD.id), 0))::bigint);
+  ELSE
+    PERFORM book_storage_delta(NEW.chat_id, octet_length(NEW.content) - octet_length(OLD.content));
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- chunks of a message that is already gone were booked with it
+CREATE OR REPLACE FUNCTION message_chunks_book_storage() RETURNS trigger AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    PERFORM book_storage_delta(chat_id, octet_length(NEW.content)) FROM messages WHERE id = NEW.message_id;
+  ELSIF TG_OP = 'DELETE' THEN
+    PERFORM book_storage_delta(chat_id, -octet_length(OLD.content)) FROM messages WHERE id = OLD.message_id;
+  ELSE
+    PERFORM book_storage_delta(chat_id, octet_length(NEW.content) - octet_length(OLD.content))
+    FROM messages WHERE id = NEW.message_id;
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER messages_book_storage
+  AFTER INSERT OR DELETE OR UPDATE OF content ON messages
+  FOR EACH ROW EXECUTE FUNCTION messages_book_storage();
+
+CREATE TRIGGER message_chunks_book_storage
+  AFTER INSERT OR DELETE OR UPDATE OF content ON message_chunks
+  FOR EACH ROW EXECUTE FUNCTION message_chunks_book_storage();
+
+-- what is stored now, booked on the days the messages were sent
+INSERT INTO usage_storage_deltas (ws_id, day, bytes)
+SELECT c.ws_id, (m.created_at AT TIME ZONE 'UTC')::date,
+  SUM(octet_length(m.content) + COALESCE(
+    (SELECT SUM(octet_length(mc.content)) FROM message_chunks mc WHERE mc.message_id = m.id), 0))::bigint
+FROM messages m JOIN chats c ON c.id = m.chat_id
+GROUP BY 1, 2;
@@ -0,0 +1,6 @@
+-- room for any valid address, 254 characters at most per RFC 5321. Longer input is rejected
+-- before it gets here.
+ALTER TABLE users ALTER COLUMN email TYPE varchar(254);
+ALTER TABLE user_identities ALTER COLUMN email TYPE varchar(254);
+ALTER TABLE signin_failures ALTER COLUMN email TYPE varchar(254);
+ALTER TABLE email_changes ALTER COLUMN new_email TYPE varchar(254);
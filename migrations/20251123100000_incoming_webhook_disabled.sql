@@ -0,0 +1,4 @@
+-- set when the creator, who messages are posted as, left the chat or was deactivated. The
+-- webhook stays off, a chat admin creates a new one.
+ALTER TABLE incoming_webhooks
+    ADD COLUMN IF NOT EXISTS disabled_at timestamptz;
@@ -0,0 +1,5 @@
+-- the admin who asked for the email, test sends and mail settings checks are limited per admin
+ALTER TABLE outbound_emails ADD COLUMN IF NOT EXISTS requested_by bigint REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS outbound_emails_requested_by_index ON outbound_emails(requested_by, created_at)
+  WHERE requested_by IS NOT NULL;
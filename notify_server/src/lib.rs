@@ -1,4 +1,5 @@
 use axum::{response::{Html, IntoResponse}, routing::get, Router};
+use tokio_util::sync::CancellationToken;
 
 mod sse;
 
@@ -6,10 +7,12 @@ pub(crate) use sse::sse_handler;
 
 const INDEX_HTML: &str = include_str!("../index.html");
 
-pub fn get_router() -> Router {
+// event streams end, and new ones are refused, once `shutdown` is cancelled
+pub fn get_router(shutdown: CancellationToken) -> Router {
     Router::new()
         .route("/", get(index_handler))
         .route("/events", get(sse_handler))
+        .with_state(shutdown)
 }
 
 async fn index_handler() -> impl IntoResponse {
@@ -1,22 +1,29 @@
 use std::{convert::Infallible, time::Duration};
 
-use axum::response::{sse::Event, Sse};
+use axum::{extract::State, http::StatusCode, response::{sse::Event, Sse}};
 use axum_extra::{headers, TypedHeader};
 use futures::{stream, Stream};
 use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 use tracing::info;
 
 pub(crate) async fn sse_handler(
+    State(shutdown): State<CancellationToken>,
     TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    // clients reconnect, hopefully to an instance that isn't going away
+    if shutdown.is_cancelled() {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
     info!("`{}` connected", user_agent.as_str());
     let stream = stream::repeat_with(|| Event::default().data("hi!"))
     .map(Ok)
     .throttle(Duration::from_secs(2));
+    let stream = futures::StreamExt::take_until(stream, shutdown.cancelled_owned());
 
-    Sse::new(stream).keep_alive(
+    Ok(Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
         .interval(Duration::from_secs(2))
         .text("keep-alive-text"),
-    )
+    ))
 }